@@ -0,0 +1,243 @@
+//! Timings for the per-bar hot paths: `cargo bench`, optionally followed by a
+//! substring to run only matching benchmarks, e.g. `cargo bench -- eval`.
+//!
+//! Criterion isn't vendored, so this is a plain harness: each benchmark is
+//! warmed up, then run in doubling batches until a batch takes long enough
+//! to time, and reported as nanoseconds and heap allocations per iteration.
+//! Names carry a note on what is being measured so a regression points at
+//! its cause.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use candle_server::candle::Candle;
+use candle_server::expr::{evaluate_dense, evaluate_rpn, parse, to_rpn, Expr};
+use candle_server::mid::MidBars;
+use candle_server::protocol::{
+    BinanceBookTickerMessage, BinanceMessage, ResultData, ResultMessage, ServerMessage,
+};
+
+const TARGET: Duration = Duration::from_millis(500);
+
+const SYMBOLS: [&str; 20] = [
+    "btcusdt",
+    "ethusdt",
+    "bnbusdt",
+    "xrpusdt",
+    "adausdt",
+    "solusdt",
+    "dogeusdt",
+    "trxusdt",
+    "dotusdt",
+    "maticusdt",
+    "ltcusdt",
+    "shibusdt",
+    "avaxusdt",
+    "linkusdt",
+    "atomusdt",
+    "xlmusdt",
+    "uniusdt",
+    "etcusdt",
+    "filusdt",
+    "aptusdt",
+];
+
+const KLINE_FRAME: &str = r#"{"stream":"btcusdt@kline_1m","data":{"e":"kline","E":1672515782136,"s":"BTCUSDT","k":{"t":1672515780000,"T":1672515839999,"s":"BTCUSDT","i":"1m","f":100,"L":200,"o":"16569.10","c":"16570.40","h":"16572.00","l":"16568.90","v":"125.312","n":100,"x":false,"q":"2076441.90310","V":"61.230","Q":"1014564.48020","B":"0"}}}"#;
+
+const BOOK_TICKER_FRAME: &str = r#"{"stream":"btcusdt@bookTicker","data":{"e":"bookTicker","u":400900217,"E":1672515782136,"T":1672515782134,"s":"BTCUSDT","b":"16569.10","B":"31.21000000","a":"16569.20","A":"40.66000000"}}"#;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn bench(filter: &Option<String>, name: &str, mut f: impl FnMut()) {
+    if filter
+        .as_ref()
+        .is_some_and(|filter| !name.contains(filter.as_str()))
+    {
+        return;
+    }
+
+    let mut iterations: u64 = 1;
+    loop {
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        for _ in 0..iterations {
+            f();
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= TARGET {
+            let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+            println!(
+                "{:<60} {:>12.1} ns/iter {:>8.1} allocs/iter",
+                name,
+                elapsed.as_nanos() as f64 / iterations as f64,
+                allocations as f64 / iterations as f64
+            );
+            return;
+        }
+        iterations *= 2;
+    }
+}
+
+fn main() {
+    // `cargo bench` passes `--bench`; anything else is a name filter
+    let filter = std::env::args().skip(1).find(|arg| !arg.starts_with("--"));
+
+    let small = "btcusdt+ethusdt@1m";
+    let large = format!(
+        "({})/({})@1m",
+        SYMBOLS[..10].join("+"),
+        SYMBOLS[10..].join("-")
+    );
+
+    bench(
+        &filter,
+        "parse+to_rpn/2 symbols (interned symbol table)",
+        || {
+            black_box(to_rpn(&parse(black_box(small)).unwrap()).unwrap());
+        },
+    );
+    bench(
+        &filter,
+        "parse+to_rpn/20 symbols (interned symbol table)",
+        || {
+            black_box(to_rpn(&parse(black_box(&large)).unwrap()).unwrap());
+        },
+    );
+    bench(&filter, "Expr::parse/20 symbols (boxed tree)", || {
+        black_box(Expr::parse(black_box(&large)).unwrap());
+    });
+    bench(
+        &filter,
+        "Expr::parse+simplify/20 symbols (tree rebuilt)",
+        || {
+            black_box(
+                Expr::parse(black_box(&large))
+                    .unwrap()
+                    .0
+                    .simplify()
+                    .unwrap(),
+            );
+        },
+    );
+
+    let candles: HashMap<String, Candle> = SYMBOLS
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| {
+            let price = 10.0 + i as f64;
+            let candle = Candle::new(60_000, price, price + 1.0, price + 2.0, price - 1.0)
+                .with_volume(100.0, 100.0 * price);
+            (format!("{}@kline_1m", symbol), candle)
+        })
+        .collect();
+    let rpn = to_rpn(&parse(&large).unwrap()).unwrap();
+    bench(
+        &filter,
+        "evaluate_rpn/20 symbols (HashMap lookups, dense copy)",
+        || {
+            black_box(evaluate_rpn(black_box(&rpn), &candles).unwrap());
+        },
+    );
+    let dense: Vec<Option<Candle>> = rpn
+        .symbols
+        .iter()
+        .map(|name| candles.get(name).copied())
+        .collect();
+    let mut stack = Vec::new();
+    bench(
+        &filter,
+        "evaluate_dense/20 symbols (interned ids, reused stack)",
+        || {
+            black_box(evaluate_dense(black_box(&rpn.tokens), &dense, &mut stack).unwrap());
+        },
+    );
+    let (expr, interval) = Expr::parse(&large).unwrap();
+    let leg_streams: HashMap<&str, String> = expr
+        .symbols()
+        .map(|symbol| (symbol, interval.stream(symbol)))
+        .collect();
+    bench(
+        &filter,
+        "Expr::eval/20 symbols (two HashMap lookups per operand)",
+        || {
+            let candle = |symbol: &str| candles.get(leg_streams.get(symbol)?).copied();
+            black_box(black_box(&expr).eval(&candle).unwrap());
+        },
+    );
+
+    bench(
+        &filter,
+        "BinanceMessage deserialize/kline frame (borrowed fields)",
+        || {
+            black_box(serde_json::from_str::<BinanceMessage>(black_box(KLINE_FRAME)).unwrap());
+        },
+    );
+
+    bench(
+        &filter,
+        "BinanceBookTickerMessage deserialize+midpoint/book frame",
+        || {
+            let message =
+                serde_json::from_str::<BinanceBookTickerMessage>(black_box(BOOK_TICKER_FRAME))
+                    .unwrap();
+            let bid: f64 = message.data.b.parse().unwrap();
+            let ask: f64 = message.data.a.parse().unwrap();
+            black_box((bid + ask) / 2.0);
+        },
+    );
+    let mut bars = MidBars::new("1m").unwrap();
+    let mut time = 0;
+    bench(
+        &filter,
+        "MidBars::observe/book update every 10ms (bar boundary each 6000th)",
+        || {
+            time += 10;
+            black_box(bars.observe(time, black_box(16569.15)));
+        },
+    );
+
+    let result = evaluate_rpn(&rpn, &candles).unwrap();
+    let message = ServerMessage::Result(ResultMessage {
+        id: None,
+        stream: large.clone(),
+        data: ResultData::from(result),
+        closed: false,
+        out_of_order: false,
+        partial: false,
+        missing: Vec::new(),
+        snapshot: false,
+        latency_ms: None,
+        alias: None,
+    });
+    bench(
+        &filter,
+        "ResultMessage serialize/epoch time, rounded prices",
+        || {
+            black_box(serde_json::to_string(black_box(&message)).unwrap());
+        },
+    );
+}
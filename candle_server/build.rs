@@ -0,0 +1,22 @@
+use std::process::Command;
+
+// Commit the server is built from, for `GET_INFO`; "unknown" outside a git
+// checkout
+fn main() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|output| output.trim().to_string())
+    };
+    let hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=CANDLE_SERVER_GIT_HASH={}", hash);
+    if let Some(dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", dir);
+        println!("cargo:rerun-if-changed={}/refs", dir);
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,227 @@
+//! Load test: many clients with small expressions share the server with one
+//! subscription of hundreds of legs, all fed by an in-process fake upstream
+//! that ticks every stream every few milliseconds. Reports how evenly results
+//! reached the clients.
+//!
+//! Each expression is evaluated on a task of its own, spread over the
+//! runtime's workers. `WORKERS` picks how many, 0 for a current-thread
+//! runtime, where every evaluator shares one loop; `SHARDS` instead pins
+//! evaluators to that many shard threads by their key. `sweep` runs the
+//! single-loop baseline, then 1, 2, 4... workers and as many shards up to
+//! the number of cores, to show how throughput scales.
+//!
+//! `cargo run --release --example load_test -- [CLIENTS] [SECONDS] [TASK_BUDGET] [WORKERS|sweep] [SHARDS]`
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio::time::{interval, sleep, Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_async, connect_async};
+
+use candle_server::server::{Server, ServerConfig};
+
+const SYMBOLS: usize = 300;
+const WIDE_LEGS: usize = 250;
+const TICK: Duration = Duration::from_millis(20);
+
+fn symbol(i: usize) -> String {
+    format!("sym{}usdt", i)
+}
+
+fn kline(stream: &str, price: f64) -> String {
+    json!({
+        "stream": stream,
+        "data": {
+            "e": "kline", "E": 1, "s": "SYM",
+            "k": {
+                "t": 0, "T": 59_999, "s": "SYM", "i": "1m", "f": 1, "L": 2,
+                "o": price.to_string(), "c": price.to_string(),
+                "h": price.to_string(), "l": price.to_string(),
+                "v": "1", "n": 1, "x": false, "q": "1", "V": "1", "Q": "1", "B": "0"
+            }
+        }
+    })
+    .to_string()
+}
+
+// Answers SUBSCRIBE and sends a kline for every subscribed stream each tick
+async fn fake_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut write, mut read) = accept_async(socket).await.unwrap().split();
+                let streams = Arc::new(Mutex::new(HashSet::<String>::new()));
+                let subscribed = streams.clone();
+                tokio::spawn(async move {
+                    while let Some(Ok(Message::Text(text))) = read.next().await {
+                        let request: Value = serde_json::from_str(&text).unwrap();
+                        if request["method"] == "SUBSCRIBE" {
+                            let params = request["params"].as_array().unwrap();
+                            let mut streams = subscribed.lock().await;
+                            streams.extend(params.iter().map(|p| p.as_str().unwrap().to_string()));
+                        }
+                    }
+                });
+
+                let mut ticks = interval(TICK);
+                let mut price = 1.0;
+                loop {
+                    ticks.tick().await;
+                    price += 0.01;
+                    let streams: Vec<String> = streams.lock().await.iter().cloned().collect();
+                    for stream in streams {
+                        if write
+                            .send(Message::Text(kline(&stream, price)))
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    url
+}
+
+async fn client(addr: String, stream: String, until: Instant) -> usize {
+    let (mut socket, _) = connect_async(format!("ws://{}", addr)).await.unwrap();
+    let request = json!({"id": 1, "method": "SUBSCRIBE", "stream": stream});
+    socket
+        .send(Message::Text(request.to_string()))
+        .await
+        .unwrap();
+
+    let mut results = 0;
+    loop {
+        tokio::select! {
+            message = socket.next() => match message {
+                Some(Ok(Message::Text(_))) => results += 1,
+                Some(Ok(_)) => {}
+                _ => return results,
+            },
+            _ = sleep(until.saturating_duration_since(Instant::now())) => return results,
+        }
+    }
+}
+
+// Results per second over the run
+async fn run(clients: usize, seconds: u64, task_budget: usize, evaluator_shards: usize) -> f64 {
+    let config = ServerConfig {
+        upstream_url: fake_upstream().await,
+        task_budget,
+        evaluator_shards,
+        ..ServerConfig::default()
+    };
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { Server::from_config(config).serve_listener(listener).await });
+
+    let until = Instant::now() + Duration::from_secs(seconds);
+    let wide = (0..WIDE_LEGS).map(symbol).collect::<Vec<_>>().join("+") + "@1m";
+    let wide_client = tokio::spawn(client(addr.clone(), wide, until));
+    let small_clients: Vec<_> = (0..clients)
+        .map(|i| {
+            let stream = format!(
+                "{}-{}*2@1m",
+                symbol(i % SYMBOLS),
+                symbol((i * 7 + 1) % SYMBOLS)
+            );
+            tokio::spawn(client(addr.clone(), stream, until))
+        })
+        .collect();
+
+    let mut counts = Vec::new();
+    for handle in small_clients {
+        counts.push(handle.await.unwrap());
+    }
+    counts.sort_unstable();
+    let wide_results = wide_client.await.unwrap();
+
+    let total: usize = counts.iter().sum::<usize>() + wide_results;
+    println!("{} clients for {}s", clients, seconds);
+    println!(
+        "results per small client: min {} median {} max {}",
+        counts.first().unwrap_or(&0),
+        counts.get(counts.len() / 2).unwrap_or(&0),
+        counts.last().unwrap_or(&0)
+    );
+    println!("results for the {}-leg client: {}", WIDE_LEGS, wide_results);
+    let throughput = total as f64 / seconds as f64;
+    println!("throughput: {:.0} results/s", throughput);
+    throughput
+}
+
+fn runtime(workers: usize) -> tokio::runtime::Runtime {
+    let mut builder = match workers {
+        0 => tokio::runtime::Builder::new_current_thread(),
+        workers => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(workers);
+            builder
+        }
+    };
+    builder.enable_all().build().unwrap()
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let number = |i: usize, default: u64| args.get(i).map_or(default, |arg| arg.parse().unwrap());
+    let clients = number(0, 200) as usize;
+    let seconds = number(1, 5);
+    let task_budget = number(2, 64) as usize;
+    let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+
+    if args.get(3).map(String::as_str) != Some("sweep") {
+        let workers = number(3, cores as u64) as usize;
+        let shards = number(4, 0) as usize;
+        runtime(workers).block_on(run(clients, seconds, task_budget, shards));
+        return;
+    }
+    let counts: Vec<usize> = std::iter::successors(Some(1), |&count| Some(count * 2))
+        .take_while(|&count| count < cores)
+        .chain(std::iter::once(cores))
+        .collect();
+    // The baseline, then workers of one runtime, then shards beside a
+    // current-thread one
+    let setups = std::iter::once((0, 0))
+        .chain(counts.iter().map(|&workers| (workers, 0)))
+        .chain(counts.iter().map(|&shards| (0, shards)));
+    let mut rows = Vec::new();
+    for (workers, shards) in setups {
+        println!("\n== {} ==", label(workers, shards));
+        rows.push((
+            label(workers, shards),
+            runtime(workers).block_on(run(clients, seconds, task_budget, shards)),
+        ));
+    }
+    let baseline = rows[0].1.max(1.0);
+    println!();
+    for (label, throughput) in rows {
+        println!(
+            "{:>16}: {:>9.0} results/s  {:.2}x",
+            label,
+            throughput,
+            throughput / baseline
+        );
+    }
+}
+
+fn label(workers: usize, shards: usize) -> String {
+    match (workers, shards) {
+        (0, 0) => "current-thread".into(),
+        (0, 1) => "1 shard".into(),
+        (0, shards) => format!("{} shards", shards),
+        (1, _) => "1 worker".into(),
+        (workers, _) => format!("{} workers", workers),
+    }
+}
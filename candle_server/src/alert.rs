@@ -0,0 +1,254 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::ServerError;
+use crate::protocol::ResultData;
+
+/// Field of a closed bar a rule watches.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertField {
+    O,
+    H,
+    L,
+    #[default]
+    C,
+    V,
+}
+
+impl AlertField {
+    fn value(self, data: &ResultData) -> Option<f64> {
+        match self {
+            AlertField::O => data.o,
+            AlertField::H => data.h,
+            AlertField::L => data.l,
+            AlertField::C => data.c,
+            AlertField::V => data.v,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AlertField::O => "o",
+            AlertField::H => "h",
+            AlertField::L => "l",
+            AlertField::C => "c",
+            AlertField::V => "v",
+        }
+    }
+}
+
+/// Side of the threshold a rule fires on crossing into.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    #[serde(rename = ">")]
+    Above,
+    #[serde(rename = "<")]
+    Below,
+}
+
+/// What an `ALERT` request watches for, e.g. a close crossing above 50.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertRule {
+    #[serde(default)]
+    pub field: AlertField,
+    pub op: Comparison,
+    pub threshold: f64,
+    // How far back across the threshold the value must go before the rule
+    // can fire again, so a value hovering around it fires once
+    #[serde(default)]
+    pub hysteresis: f64,
+    // Seconds of bar time after firing in which crossings are ignored
+    #[serde(default)]
+    pub cooldown_secs: u64,
+}
+
+/// Whether an `ALERT` rule goes with the connection that set it or lives
+/// until deleted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertScope {
+    #[default]
+    Connection,
+    // Outlives its client, so its alerts need a webhook to go to
+    Server,
+}
+
+/// One alert rule, as a `list` event reports it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AlertInfo {
+    // Id of the `ALERT` request that set it
+    pub id: u32,
+    pub stream: String,
+    pub rule: AlertRule,
+    pub scope: AlertScope,
+}
+
+impl AlertRule {
+    pub fn validate(&self) -> Result<(), ServerError> {
+        if !self.threshold.is_finite() {
+            return Err(ServerError::InvalidMessage(
+                "alert threshold must be a finite number".into(),
+            ));
+        }
+        if !(self.hysteresis.is_finite() && self.hysteresis >= 0.0) {
+            return Err(ServerError::InvalidMessage(
+                "alert hysteresis must not be negative".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// What firing at `value` means, e.g. `c 50.5 crossed above 50`.
+    pub fn describe(&self, value: f64) -> String {
+        let side = match self.op {
+            Comparison::Above => "above",
+            Comparison::Below => "below",
+        };
+        format!(
+            "{} {} crossed {} {}",
+            self.field.name(),
+            value,
+            side,
+            self.threshold
+        )
+    }
+}
+
+/// A rule checked against an expression's closed bars in order. It fires on
+/// a crossing, not on every bar past the threshold: once fired, or first
+/// seen already past it, the value must come back by `hysteresis` first.
+#[derive(Debug, Clone)]
+pub struct Alert {
+    rule: AlertRule,
+    // A crossing would fire
+    armed: bool,
+    // Open time of the bar it last fired on
+    fired_at: Option<u64>,
+}
+
+impl Alert {
+    pub fn new(rule: AlertRule) -> Alert {
+        Alert {
+            rule,
+            armed: false,
+            fired_at: None,
+        }
+    }
+
+    pub fn rule(&self) -> &AlertRule {
+        &self.rule
+    }
+
+    /// The watched value of closed bar `data` if the rule fires on it.
+    pub fn check(&mut self, data: &ResultData) -> Option<f64> {
+        let rule = &self.rule;
+        let value = rule.field.value(data)?;
+        let (past, back) = match rule.op {
+            Comparison::Above => (
+                value > rule.threshold,
+                value <= rule.threshold - rule.hysteresis,
+            ),
+            Comparison::Below => (
+                value < rule.threshold,
+                value >= rule.threshold + rule.hysteresis,
+            ),
+        };
+        if back {
+            self.armed = true;
+        }
+        // A crossing within the cooldown is used up all the same
+        if !past || !std::mem::replace(&mut self.armed, false) {
+            return None;
+        }
+        let cooldown = rule.cooldown_secs.saturating_mul(1000);
+        if self
+            .fired_at
+            .is_some_and(|at| data.t < at.saturating_add(cooldown))
+        {
+            return None;
+        }
+        self.fired_at = Some(data.t);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alert, AlertField, AlertRule, Comparison};
+    use crate::candle::Candle;
+    use crate::protocol::ResultData;
+
+    fn rule(op: Comparison, threshold: f64, hysteresis: f64, cooldown_secs: u64) -> AlertRule {
+        AlertRule {
+            field: AlertField::C,
+            op,
+            threshold,
+            hysteresis,
+            cooldown_secs,
+        }
+    }
+
+    // Minutes of the bars the rule fired on, one bar a minute with these
+    // closes
+    fn fired(rule: AlertRule, closes: &[f64]) -> Vec<usize> {
+        let mut alert = Alert::new(rule);
+        closes
+            .iter()
+            .enumerate()
+            .filter(|&(minute, &close)| {
+                let data =
+                    ResultData::from(Candle::new(minute as u64 * 60_000, 1.0, close, 1.0, 1.0));
+                alert.check(&data).is_some()
+            })
+            .map(|(minute, _)| minute)
+            .collect()
+    }
+
+    #[test]
+    fn test_fires_on_crossings_only() {
+        let above = rule(Comparison::Above, 50.0, 0.0, 0);
+        assert_eq!(fired(above.clone(), &[49.0, 51.0, 52.0, 53.0]), [1]);
+        assert_eq!(fired(above.clone(), &[49.0, 51.0, 50.0, 51.0]), [1, 3]);
+        // Already above when first seen isn't a crossing
+        assert_eq!(fired(above, &[51.0, 52.0, 49.0, 51.0]), [3]);
+
+        let below = rule(Comparison::Below, 50.0, 0.0, 0);
+        assert_eq!(fired(below, &[51.0, 49.0, 48.0, 50.0, 49.0]), [1, 4]);
+    }
+
+    #[test]
+    fn test_hysteresis_needs_a_move_back_before_refiring() {
+        let above = rule(Comparison::Above, 50.0, 2.0, 0);
+        // Dipping to 49 is within the band, so 51 again doesn't fire
+        assert_eq!(fired(above.clone(), &[47.0, 51.0, 49.0, 51.0]), [1]);
+        assert_eq!(fired(above.clone(), &[47.0, 51.0, 48.0, 51.0]), [1, 3]);
+        // Starting within the band doesn't arm it either
+        assert_eq!(fired(above, &[49.0, 51.0, 47.0, 51.0]), [3]);
+
+        let below = rule(Comparison::Below, 50.0, 2.0, 0);
+        assert_eq!(fired(below, &[53.0, 49.0, 51.0, 49.0, 52.0, 49.0]), [1, 5]);
+    }
+
+    #[test]
+    fn test_cooldown_swallows_crossings() {
+        let above = rule(Comparison::Above, 50.0, 0.0, 180);
+        // Minute 3 is within three minutes of minute 1; that crossing is
+        // used up, so staying above doesn't fire at minute 4
+        assert_eq!(
+            fired(above, &[49.0, 51.0, 49.0, 51.0, 52.0, 49.0, 51.0]),
+            [1, 6]
+        );
+    }
+
+    #[test]
+    fn test_missing_values_are_skipped() {
+        let mut alert = Alert::new(rule(Comparison::Above, 50.0, 0.0, 0));
+        let mut data = ResultData::from(Candle::new(0, 1.0, 49.0, 1.0, 1.0));
+        assert_eq!(alert.check(&data), None);
+        data.c = None;
+        assert_eq!(alert.check(&data), None);
+        data.c = Some(51.0);
+        assert_eq!(alert.check(&data), Some(51.0));
+        assert_eq!(alert.rule().describe(51.0), "c 51 crossed above 50");
+    }
+}
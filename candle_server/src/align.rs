@@ -0,0 +1,290 @@
+use serde::{Deserialize, Serialize};
+
+use crate::candle::Candle;
+use crate::expr::stream_interval;
+use crate::expr::Interval;
+use crate::resample::Session;
+use crate::utils::interval_to_millis;
+
+/// How an expression whose legs are at different intervals lines them up,
+/// chosen per subscription.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Alignment {
+    // Finer legs are resampled up to the coarsest interval
+    #[default]
+    Resample,
+    // The latest bar of coarser legs is carried onto every bar of the
+    // finest interval
+    ForwardFill,
+}
+
+/// Puts the klines of every leg on one grid of bars before they are paired:
+/// the coarsest interval of the legs when resampling, the finest when
+/// forward-filling. Legs are addressed by their index in `streams`, as in
+/// `LegBook`.
+#[derive(Clone)]
+pub struct Aligner {
+    // The expression's trailing interval, the default of its legs
+    interval: Interval,
+    // Interval of the grid and the length of its bars
+    grid: String,
+    grid_length: u64,
+    legs: Vec<Leg>,
+    // Newest grid bar the filled legs were carried to
+    carried_to: Option<u64>,
+}
+
+#[derive(Clone)]
+enum Leg {
+    // Already at the grid's interval
+    Grid,
+    // Finer than the grid: the latest update of each of its bars within
+    // the grid bar `bucket`, oldest first
+    Resampled {
+        length: u64,
+        bucket: Option<u64>,
+        bars: Vec<Candle>,
+    },
+    // Coarser than the grid: its latest bar
+    Filled {
+        latest: Option<Candle>,
+    },
+}
+
+impl Aligner {
+    /// `streams` are the kline streams of the legs, e.g. `ethusdt@kline_1h`,
+    /// at intervals `Expr::parse` accepted together.
+    pub fn new(interval: &Interval, streams: &[String], alignment: Alignment) -> Aligner {
+        let intervals: Vec<&str> = streams
+            .iter()
+            .map(|stream| stream_interval(stream))
+            .collect();
+        let length = |interval: &str| interval_to_millis(interval, 0).unwrap_or(0);
+        let grid = match alignment {
+            Alignment::Resample => intervals.iter().max_by_key(|leg| length(leg)),
+            Alignment::ForwardFill => intervals.iter().min_by_key(|leg| length(leg)),
+        }
+        .map_or(interval.as_str(), |grid| grid)
+        .to_string();
+        let grid_length = length(&grid);
+
+        let legs = intervals
+            .iter()
+            .map(|&leg| match length(leg) {
+                _ if leg == grid => Leg::Grid,
+                leg_length if leg_length < grid_length => Leg::Resampled {
+                    length: leg_length,
+                    bucket: None,
+                    bars: Vec::new(),
+                },
+                _ => Leg::Filled { latest: None },
+            })
+            .collect();
+
+        Aligner {
+            interval: interval.clone(),
+            grid,
+            grid_length,
+            legs,
+            carried_to: None,
+        }
+    }
+
+    pub fn interval(&self) -> &Interval {
+        &self.interval
+    }
+
+    /// Interval of the bars the expression's results are for.
+    pub fn grid(&self) -> &str {
+        &self.grid
+    }
+
+    /// `leg`'s bar as a bar of the grid, or `None` for a bar of a resampled
+    /// leg whose grid bar already passed. A resampled bar is built from the
+    /// leg's bars seen since the subscription started, so the first one
+    /// may lack the earliest of them; it is closed once the leg's last bar
+    /// in it is. A filled leg's bar is closed, as it is final for the grid
+    /// bar it fills.
+    pub fn align(&mut self, leg: usize, candle: Candle) -> Option<Candle> {
+        match &mut self.legs[leg] {
+            Leg::Grid => Some(candle),
+            Leg::Resampled {
+                length,
+                bucket,
+                bars,
+            } => {
+                let start = bar_start(candle.t, &self.grid, self.grid_length);
+                match *bucket {
+                    Some(current) if start < current => return None,
+                    Some(current) if start == current => {}
+                    _ => {
+                        *bucket = Some(start);
+                        bars.clear();
+                    }
+                }
+                match bars.iter().position(|bar| bar.t >= candle.t) {
+                    Some(index) if bars[index].t == candle.t => bars[index] = candle,
+                    Some(index) => bars.insert(index, candle),
+                    None => bars.push(candle),
+                }
+
+                let (first, last) = (bars[0], bars[bars.len() - 1]);
+                let mut folded = Candle::new(start, first.o, last.c, first.h, first.l);
+                for bar in bars.iter() {
+                    folded.h = folded.h.max(bar.h);
+                    folded.l = folded.l.min(bar.l);
+                    folded.v += bar.v;
+                    folded.q += bar.q;
+                    folded.n += bar.n;
+                    folded.taker_v += bar.taker_v;
+                    folded.taker_q += bar.taker_q;
+                    folded.event_time = folded.event_time.max(bar.event_time);
+                }
+                folded.closed = last.closed && last.t + *length >= start + self.grid_length;
+                Some(folded)
+            }
+            Leg::Filled { latest } => {
+                *latest = Some(candle);
+                let t = self.carried_to.map_or(candle.t, |t| t.max(candle.t));
+                Some(carried(candle, t))
+            }
+        }
+    }
+
+    /// Calls `seed` with the latest bar of every filled leg carried to the
+    /// grid bar `t`, once per grid bar, so a new bar of the finest legs
+    /// pairs without waiting for the coarser ones.
+    pub fn carry(&mut self, t: u64, mut seed: impl FnMut(usize, Candle)) {
+        if self.carried_to.is_some_and(|carried_to| carried_to >= t) {
+            return;
+        }
+        self.carried_to = Some(t);
+        for (index, leg) in self.legs.iter().enumerate() {
+            if let Leg::Filled {
+                latest: Some(latest),
+            } = leg
+            {
+                seed(index, carried(*latest, t));
+            }
+        }
+    }
+}
+
+fn carried(candle: Candle, t: u64) -> Candle {
+    Candle {
+        t,
+        closed: true,
+        ..candle
+    }
+}
+
+// Start of the grid bar holding `t`; days and weeks as Binance starts
+// them, at UTC midnight and on Monday
+pub(crate) fn bar_start(t: u64, grid: &str, length: u64) -> u64 {
+    if grid.ends_with(['d', 'w']) {
+        Session::parse(None, None)
+            .and_then(|session| session.bucket_start(t, grid))
+            .unwrap_or(t)
+    } else {
+        t - t % length.max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Aligner, Alignment};
+    use crate::candle::Candle;
+    use crate::expr::Expr;
+
+    const MINUTE: u64 = 60_000;
+
+    fn aligner(input: &str, alignment: Alignment) -> Aligner {
+        let (expr, interval) = Expr::parse(input).unwrap();
+        Aligner::new(&interval, &expr.streams(&interval), alignment)
+    }
+
+    fn bar(t: u64, price: f64, closed: bool) -> Candle {
+        Candle::new(t, price, price, price, price)
+            .with_volume(1.0, price)
+            .with_closed(closed)
+    }
+
+    #[test]
+    fn test_finer_legs_are_resampled_to_the_coarsest() {
+        // Legs are btcusdt@kline_1m and ethusdt@kline_5m
+        let mut aligner = aligner("btcusdt/ethusdt@5m@1m", Alignment::Resample);
+        assert_eq!(aligner.grid(), "5m");
+        assert_eq!(aligner.align(1, bar(0, 3.0, false)).unwrap().t, 0);
+
+        aligner.align(0, bar(0, 10.0, true));
+        aligner.align(0, bar(MINUTE, 12.0, true));
+        let folded = aligner.align(0, bar(2 * MINUTE, 8.0, false)).unwrap();
+        assert_eq!(folded.t, 0);
+        assert_eq!(
+            (folded.o, folded.h, folded.l, folded.c),
+            (10.0, 12.0, 8.0, 8.0)
+        );
+        assert_eq!((folded.v, folded.q), (3.0, 30.0));
+        assert!(!folded.closed);
+
+        // An update replaces its bar rather than adding to it
+        let folded = aligner.align(0, bar(2 * MINUTE, 9.0, false)).unwrap();
+        assert_eq!((folded.c, folded.v), (9.0, 3.0));
+
+        // The grid bar closes with the leg's last bar in it
+        let folded = aligner.align(0, bar(4 * MINUTE, 11.0, true)).unwrap();
+        assert!(folded.closed);
+        assert_eq!(folded.c, 11.0);
+
+        let next = aligner.align(0, bar(5 * MINUTE, 7.0, false)).unwrap();
+        assert_eq!((next.t, next.o, next.v), (5 * MINUTE, 7.0, 1.0));
+        // Bars of a grid bar already passed are dropped
+        assert!(aligner.align(0, bar(3 * MINUTE, 1.0, true)).is_none());
+    }
+
+    #[test]
+    fn test_coarser_legs_are_forward_filled_to_the_finest() {
+        let mut aligner = aligner("btcusdt/ethusdt@5m@1m", Alignment::ForwardFill);
+        assert_eq!(aligner.grid(), "1m");
+        let mut seeded = Vec::new();
+        aligner.carry(0, |leg, candle| seeded.push((leg, candle.t)));
+        assert!(seeded.is_empty());
+
+        let filled = aligner.align(1, bar(0, 3.0, false)).unwrap();
+        assert_eq!(filled.t, 0);
+        assert!(filled.closed);
+        assert_eq!(aligner.align(0, bar(0, 10.0, false)).unwrap().t, 0);
+
+        // A new bar of the finest leg carries the coarse one along, once
+        for _ in 0..2 {
+            aligner.carry(2 * MINUTE, |leg, candle| seeded.push((leg, candle.t)));
+        }
+        assert_eq!(seeded, [(1, 2 * MINUTE)]);
+        // Its later updates fill the grid bar it was carried to
+        let filled = aligner.align(1, bar(0, 4.0, false)).unwrap();
+        assert_eq!((filled.t, filled.c), (2 * MINUTE, 4.0));
+        let filled = aligner.align(1, bar(5 * MINUTE, 5.0, false)).unwrap();
+        assert_eq!(filled.t, 5 * MINUTE);
+    }
+
+    #[test]
+    fn test_single_interval_passes_through() {
+        let mut aligner = aligner("btcusdt/ethusdt@1h", Alignment::ForwardFill);
+        assert_eq!(aligner.grid(), "1h");
+        let candle = aligner.align(1, bar(MINUTE, 3.0, false)).unwrap();
+        assert_eq!((candle.t, candle.closed), (MINUTE, false));
+    }
+
+    #[test]
+    fn test_daily_grid_starts_at_midnight() {
+        let mut aligner = aligner("btcusdt@1h/ethusdt@1d", Alignment::Resample);
+        assert_eq!(aligner.grid(), "1d");
+        let day = 86_400_000;
+        let folded = aligner
+            .align(0, bar(day + 23 * 60 * MINUTE, 1.0, true))
+            .unwrap();
+        assert_eq!(folded.t, day);
+        assert!(folded.closed);
+    }
+}
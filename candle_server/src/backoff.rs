@@ -0,0 +1,155 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackoffConfig {
+    pub initial_delay: Duration,
+    pub multiplier: f64,
+    pub max_delay: Duration,
+    // Each delay is randomly moved by up to this fraction in either direction
+    pub jitter: f64,
+    // `None` retries forever
+    pub max_attempts: Option<u32>,
+    // A connection that stays up this long resets the attempt counter
+    pub healthy_after: Duration,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        BackoffConfig {
+            initial_delay: Duration::from_secs(1),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(60),
+            jitter: 0.2,
+            max_attempts: None,
+            healthy_after: Duration::from_secs(30),
+        }
+    }
+}
+
+pub struct Backoff {
+    config: BackoffConfig,
+    attempt: u32,
+    rng: StdRng,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Backoff {
+        Self::with_rng(config, StdRng::from_entropy())
+    }
+
+    pub fn with_rng(config: BackoffConfig, rng: StdRng) -> Backoff {
+        Backoff {
+            config,
+            attempt: 0,
+            rng,
+        }
+    }
+
+    /// Number of attempts handed out since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn healthy_after(&self) -> Duration {
+        self.config.healthy_after
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay before the next attempt, or `None` once attempts are exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self
+            .config
+            .max_attempts
+            .is_some_and(|max| self.attempt >= max)
+        {
+            return None;
+        }
+
+        let exponent = self.attempt.min(i32::MAX as u32) as i32;
+        self.attempt += 1;
+
+        let max_delay = self.config.max_delay.as_secs_f64();
+        let base = (self.config.initial_delay.as_secs_f64()
+            * self.config.multiplier.powi(exponent))
+        .min(max_delay);
+        let spread = self.config.jitter * self.rng.gen_range(-1.0..=1.0);
+
+        Some(Duration::from_secs_f64(
+            (base * (1.0 + spread)).clamp(0.0, max_delay),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backoff, BackoffConfig};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio::time::Duration;
+
+    fn config(jitter: f64, max_attempts: Option<u32>) -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(100),
+            multiplier: 2.0,
+            max_delay: Duration::from_secs(1),
+            jitter,
+            max_attempts,
+            healthy_after: Duration::from_secs(30),
+        }
+    }
+
+    fn delays(backoff: &mut Backoff, count: usize) -> Vec<Duration> {
+        (0..count).map_while(|_| backoff.next_delay()).collect()
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially_up_to_max_delay() {
+        let mut backoff = Backoff::with_rng(config(0.0, None), StdRng::seed_from_u64(1));
+        let expected = [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis);
+        assert_eq!(delays(&mut backoff, 6), expected);
+    }
+
+    #[test]
+    fn test_backoff_jitter_is_deterministic_for_seed() {
+        let mut first = Backoff::with_rng(config(0.5, None), StdRng::seed_from_u64(42));
+        let mut second = Backoff::with_rng(config(0.5, None), StdRng::seed_from_u64(42));
+        assert_eq!(delays(&mut first, 8), delays(&mut second, 8));
+    }
+
+    #[test]
+    fn test_backoff_jitter_stays_within_bounds() {
+        let mut backoff = Backoff::with_rng(config(0.5, None), StdRng::seed_from_u64(7));
+        let bases = [100.0, 200.0, 400.0, 800.0, 1000.0];
+        for (delay, base) in delays(&mut backoff, 5).into_iter().zip(bases) {
+            let millis = delay.as_secs_f64() * 1000.0;
+            assert!(millis >= base * 0.5 - 1e-6, "{} below {}", millis, base);
+            assert!(
+                millis <= (base * 1.5).min(1000.0) + 1e-6,
+                "{} above {}",
+                millis,
+                base
+            );
+        }
+    }
+
+    #[test]
+    fn test_backoff_stops_after_max_attempts() {
+        let mut backoff = Backoff::with_rng(config(0.0, Some(3)), StdRng::seed_from_u64(1));
+        assert_eq!(delays(&mut backoff, 10).len(), 3);
+        assert_eq!(backoff.attempt(), 3);
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn test_backoff_reset_restarts_sequence() {
+        let mut backoff = Backoff::with_rng(config(0.0, Some(3)), StdRng::seed_from_u64(1));
+        delays(&mut backoff, 3);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+    }
+}
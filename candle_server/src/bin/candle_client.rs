@@ -0,0 +1,190 @@
+use futures::{SinkExt, StreamExt};
+use std::io::Write;
+
+use candle_server::emitter::{untagged, Version};
+use candle_server::encoding::open_envelope;
+use candle_server::error::ServerError;
+use candle_server::protocol::{Request, ResultData, ResultMessage, ServerMessage, TickerMessage};
+use candle_server::utils::format_rfc3339;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::Message;
+
+const USAGE: &str =
+    "usage: candle_client URL EXPR [--json] [--compress] [--count N] [--unsubscribe-after N]";
+
+// Status events after which the subscription delivers nothing more
+const FINAL_EVENTS: [&str; 2] = ["error", "closed"];
+
+#[tokio::main]
+async fn main() -> Result<(), ServerError> {
+    let mut positional = Vec::new();
+    let mut json = false;
+    // Large frames come gzip-compressed
+    let mut compress = false;
+    // Results to print before disconnecting
+    let mut count: Option<u64> = None;
+    // Results to print before unsubscribing and disconnecting
+    let mut unsubscribe_after: Option<u64> = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--compress" => compress = true,
+            "--count" => count = Some(positive(args.next())),
+            "--unsubscribe-after" => unsubscribe_after = Some(positive(args.next())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if arg.starts_with("--") => exit_with_usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let [url, stream] = <[String; 2]>::try_from(positional).unwrap_or_else(|_| exit_with_usage());
+
+    let mut url = url::Url::parse(&url)?;
+    if compress {
+        url.query_pairs_mut().append_pair("compress", "gzip");
+    }
+    let (mut socket, _) = connect_async(url).await?;
+    let subscribe = Request {
+        id: 1,
+        method: "SUBSCRIBE".into(),
+        version: Some(Version::V3.number()),
+        stream: stream.clone(),
+        ..Request::default()
+    };
+    socket
+        .send(Message::Text(serde_json::to_string(&subscribe)?))
+        .await?;
+
+    let mut stdout = std::io::stdout().lock();
+    let mut received = 0;
+    let mut failed = false;
+    while let Some(message) = socket.next().await {
+        let text = match message? {
+            Message::Text(text) => text,
+            Message::Binary(frame) => match open_envelope(&frame) {
+                Some(payload) => String::from_utf8_lossy(&payload?).into_owned(),
+                None => continue,
+            },
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        // Typed envelopes, read back as the messages they carry
+        let frame = untagged(serde_json::from_str(&text)?);
+        let line = match serde_json::from_value(frame)? {
+            ServerMessage::Result(result) if json => serde_json::to_string(&result)?,
+            ServerMessage::Result(result) => pretty(&result),
+            ServerMessage::Ticker(ticker) if json => serde_json::to_string(&ticker)?,
+            ServerMessage::Ticker(ticker) => pretty_ticker(&ticker),
+            ServerMessage::Status(status) => {
+                match status.code {
+                    Some(code) => eprintln!(
+                        "{}: {} {} ({})",
+                        status.stream,
+                        status.event,
+                        status.message,
+                        code.name()
+                    ),
+                    None => eprintln!("{}: {} {}", status.stream, status.event, status.message),
+                }
+                if FINAL_EVENTS.contains(&status.event.as_str()) {
+                    failed = status.code.is_some();
+                    break;
+                }
+                continue;
+            }
+        };
+        // A closed pipe, e.g. `| head`, just means the reader is done
+        if writeln!(stdout, "{}", line)
+            .and_then(|_| stdout.flush())
+            .is_err()
+        {
+            break;
+        }
+
+        received += 1;
+        if unsubscribe_after == Some(received) {
+            let unsubscribe = Request {
+                id: 2,
+                method: "UNSUBSCRIBE".into(),
+                version: Some(Version::V3.number()),
+                stream: stream.clone(),
+                ..Request::default()
+            };
+            socket
+                .send(Message::Text(serde_json::to_string(&unsubscribe)?))
+                .await?;
+            break;
+        }
+        if count == Some(received) {
+            break;
+        }
+    }
+
+    // The server may already be gone, so a failed close is not an error
+    let _ = socket.close(None).await;
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// One line per ticker, e.g.
+// `2024-01-01T00:00:00.123Z btcusdt@ticker24h last=42301.1 change=501.1 (1.199%) v=182034.1`
+fn pretty_ticker(ticker: &TickerMessage) -> String {
+    let stats = &ticker.ticker;
+    format!(
+        "{} {} last={} change={} ({}%) v={}",
+        format_rfc3339(stats.t),
+        ticker.stream,
+        stats.last_price,
+        stats.price_change,
+        stats.price_change_pct,
+        stats.volume
+    )
+}
+
+// One line per result, e.g.
+// `2024-01-01T00:00:00.000Z btcusdt@1m o=1 h=2 l=0.5 c=1.5 v=10 n=3 closed`
+fn pretty(result: &ResultMessage) -> String {
+    let ResultData { o, h, l, c, v, .. } = result.data;
+    let field = |name: &str, value: Option<f64>| match value {
+        Some(value) => format!(" {}={}", name, value),
+        None => format!(" {}=-", name),
+    };
+    let mut line = format!("{} {}", format_rfc3339(result.data.t), result.stream);
+    for (name, value) in [("o", o), ("h", h), ("l", l), ("c", c), ("v", v)] {
+        line.push_str(&field(name, value));
+    }
+    if let Some(n) = result.data.n {
+        line.push_str(&format!(" n={}", n));
+    }
+    if result.closed {
+        line.push_str(" closed");
+    }
+    if result.out_of_order {
+        line.push_str(" out_of_order");
+    }
+    if result.snapshot {
+        line.push_str(" snapshot");
+    }
+    if result.partial {
+        line.push_str(&format!(" partial missing={}", result.missing.join(",")));
+    }
+    line
+}
+
+fn positive(arg: Option<String>) -> u64 {
+    match arg.and_then(|arg| arg.parse().ok()) {
+        Some(value) if value > 0 => value,
+        _ => exit_with_usage(),
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
@@ -0,0 +1,515 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::error::ServerError;
+use crate::protocol::{BinanceKlineData, BinanceMiniTicker, Decimal};
+
+/// Volumes and trade counts add up across `+` and `-`, since both legs'
+/// trading happened; `*` and `/` keep the left operand's, as a product or
+/// ratio of volumes has no market meaning. A combined bar is closed once
+/// every operand's is. Highs and lows combine as `CompositeSemantics` says.
+/// A price is NaN where it's undefined, divided by a price within epsilon of
+/// zero, until `Division` settles what happens to the bar; results write it
+/// as null.
+///
+/// Deserializes from numbers or Binance-style decimal strings; volumes and
+/// the trade count may be omitted.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Candle {
+    pub t: u64, // start time
+    #[serde(deserialize_with = "decimal")]
+    pub o: f64, // open price
+    #[serde(deserialize_with = "decimal")]
+    pub c: f64, // close price
+    #[serde(deserialize_with = "decimal")]
+    pub h: f64, // high price
+    #[serde(deserialize_with = "decimal")]
+    pub l: f64, // low price
+    #[serde(default, deserialize_with = "decimal")]
+    pub v: f64, // base asset volume
+    #[serde(default, deserialize_with = "decimal")]
+    pub q: f64, // quote asset volume
+    #[serde(default)]
+    pub n: u64, // number of trades
+    #[serde(default, alias = "V", deserialize_with = "decimal")]
+    pub taker_v: f64, // taker buy base asset volume
+    #[serde(default, alias = "Q", deserialize_with = "decimal")]
+    pub taker_q: f64, // taker buy quote asset volume
+    #[serde(default, alias = "x")]
+    pub closed: bool, // the bar is final
+    #[serde(skip)]
+    pub event_time: u64, // Binance event time of the kline, 0 when unknown
+}
+
+/// How the high and low of a bar combining two legs are found. Neither is
+/// the true extreme of the combination over the bar, which only the legs'
+/// ticks could give: the legs needn't have peaked at the same moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompositeSemantics {
+    // The operator applied to the highs and to the lows, e.g. `a.h - b.h`;
+    // may miss the true extreme on either side
+    #[default]
+    Fieldwise,
+    // The largest and smallest results of the operator over each leg's high
+    // and low, e.g. `a.h - b.l`; always brackets the true extreme, if
+    // loosely. A divisor whose range takes in zero has no bound, so the
+    // high and low are undefined
+    Bound,
+}
+
+/// What happens to a bar with a price divided by (nearly) zero.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DivisionPolicy {
+    // The bar fails to evaluate, and is logged
+    #[default]
+    Error,
+    // The bar goes out with the undefined prices null
+    NullField,
+    // The bar is left out
+    SkipBar,
+}
+
+/// How prices divided by (nearly) zero are caught and what becomes of them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Division {
+    pub policy: DivisionPolicy,
+    // Divisors no further from zero than this count as zero, as dividing by
+    // a leftover of floating-point rounding gives absurd prices
+    pub epsilon: f64,
+}
+
+impl Default for Division {
+    fn default() -> Self {
+        Division {
+            policy: DivisionPolicy::default(),
+            epsilon: 1e-12,
+        }
+    }
+}
+
+impl Division {
+    /// `candle` as it goes out, `None` when the bar is skipped.
+    pub fn settle(&self, candle: Candle) -> Result<Option<Candle>, ServerError> {
+        match self.policy {
+            _ if !candle.undefined() => Ok(Some(candle)),
+            DivisionPolicy::Error => Err(ServerError::DivisionByZero),
+            DivisionPolicy::NullField => Ok(Some(candle)),
+            DivisionPolicy::SkipBar => Ok(None),
+        }
+    }
+}
+
+fn decimal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    match Decimal::deserialize(deserializer)? {
+        Decimal::Number(number) => Ok(number),
+        Decimal::Text(text) => text.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+impl TryFrom<&BinanceKlineData<'_>> for Candle {
+    type Error = ServerError;
+
+    /// Parses every decimal string, reporting all malformed fields at once.
+    fn try_from(kline: &BinanceKlineData<'_>) -> Result<Self, Self::Error> {
+        let mut malformed = Vec::new();
+        let mut field = |name: &str, text: &str| match text.parse::<f64>() {
+            Ok(value) => value,
+            Err(e) => {
+                malformed.push(format!("{} {:?} ({})", name, text, e));
+                0.0
+            }
+        };
+        let candle = Candle::new(
+            kline.t,
+            field("o", &kline.o),
+            field("c", &kline.c),
+            field("h", &kline.h),
+            field("l", &kline.l),
+        )
+        .with_volume(field("v", &kline.v), field("q", &kline.q))
+        .with_trades(kline.n)
+        .with_taker_volume(field("V", &kline.V), field("Q", &kline.Q))
+        .with_closed(kline.x);
+
+        if malformed.is_empty() {
+            Ok(candle)
+        } else {
+            Err(ServerError::MalformedKline {
+                symbol: kline.s.to_string(),
+                fields: malformed.join(", "),
+            })
+        }
+    }
+}
+
+impl TryFrom<&BinanceMiniTicker<'_>> for Candle {
+    type Error = ServerError;
+
+    /// A bar for the second of the ticker's event time, with the rolling
+    /// 24 hour open, high, low, close and volumes. It's never closed, as
+    /// the next second's update starts over from the same window.
+    fn try_from(ticker: &BinanceMiniTicker<'_>) -> Result<Self, Self::Error> {
+        let mut malformed = Vec::new();
+        let mut field = |name: &str, text: &str| match text.parse::<f64>() {
+            Ok(value) => value,
+            Err(e) => {
+                malformed.push(format!("{} {:?} ({})", name, text, e));
+                0.0
+            }
+        };
+        let candle = Candle::new(
+            ticker.E - ticker.E % 1_000,
+            field("o", &ticker.o),
+            field("c", &ticker.c),
+            field("h", &ticker.h),
+            field("l", &ticker.l),
+        )
+        .with_volume(field("v", &ticker.v), field("q", &ticker.q));
+
+        if malformed.is_empty() {
+            Ok(Candle {
+                event_time: ticker.E,
+                ..candle
+            })
+        } else {
+            Err(ServerError::MalformedKline {
+                symbol: ticker.s.to_string(),
+                fields: malformed.join(", "),
+            })
+        }
+    }
+}
+
+impl Candle {
+    pub fn new(t: u64, o: f64, c: f64, h: f64, l: f64) -> Self {
+        Self {
+            t,
+            o,
+            c,
+            h,
+            l,
+            ..Self::default()
+        }
+    }
+
+    pub fn with_volume(self, v: f64, q: f64) -> Self {
+        Self { v, q, ..self }
+    }
+
+    pub fn with_trades(self, n: u64) -> Self {
+        Self { n, ..self }
+    }
+
+    pub fn with_taker_volume(self, taker_v: f64, taker_q: f64) -> Self {
+        Self {
+            taker_v,
+            taker_q,
+            ..self
+        }
+    }
+
+    /// Applies `f` to every price, keeping `h` the larger of the two
+    /// extremes so decreasing maps like negation don't invert the range.
+    /// With either extreme undefined, which end it maps to isn't known, so
+    /// both are.
+    pub fn map_prices(&self, f: impl Fn(f64) -> f64) -> Self {
+        let (h, l) = match (f(self.h), f(self.l)) {
+            (h, l) if h.is_nan() || l.is_nan() => (f64::NAN, f64::NAN),
+            (h, l) => (h.max(l), h.min(l)),
+        };
+        Self {
+            o: f(self.o),
+            c: f(self.c),
+            h,
+            l,
+            ..*self
+        }
+    }
+
+    /// Widens the range to take in the open and close, so `l <= o, c <= h`
+    /// holds whatever the sign of the prices. Combining legs field by field
+    /// needn't keep it: `a.h - b.h` can come out below `a.c - b.c`, and the
+    /// product of two negative highs below that of their lows. An undefined
+    /// extreme stays undefined.
+    pub fn normalized(self) -> Self {
+        let prices = [self.o, self.c, self.h, self.l];
+        let extreme = |start: f64, f: fn(f64, f64) -> f64| {
+            if start.is_nan() {
+                start
+            } else {
+                prices.into_iter().fold(start, f)
+            }
+        };
+        Self {
+            h: extreme(self.h, f64::max),
+            l: extreme(self.l, f64::min),
+            ..self
+        }
+    }
+
+    /// Whether any price is undefined.
+    pub fn undefined(&self) -> bool {
+        [self.o, self.c, self.h, self.l]
+            .iter()
+            .any(|price| price.is_nan())
+    }
+
+    pub fn with_closed(self, closed: bool) -> Self {
+        Self { closed, ..self }
+    }
+
+    /// Widens the range of `self`, a result of `f` over `lhs` and `rhs`, to
+    /// the extremes of `f` over every pairing of their highs and lows. For
+    /// the four arithmetic operators these are the extremes over the legs'
+    /// whole ranges, as long as a divisor's range doesn't take in zero.
+    pub fn bounded(self, lhs: &Candle, rhs: &Candle, f: impl Fn(f64, f64) -> f64) -> Self {
+        let corners = [
+            f(lhs.h, rhs.h),
+            f(lhs.h, rhs.l),
+            f(lhs.l, rhs.h),
+            f(lhs.l, rhs.l),
+        ];
+        if corners.iter().any(|corner| corner.is_nan()) {
+            return Self {
+                h: f64::NAN,
+                l: f64::NAN,
+                ..self
+            };
+        }
+        Self {
+            h: corners.into_iter().fold(f64::NEG_INFINITY, f64::max),
+            l: corners.into_iter().fold(f64::INFINITY, f64::min),
+            ..self
+        }
+    }
+
+    /// Whether the range of the bar comes within `epsilon` of zero, so
+    /// dividing by it has no bound.
+    pub fn spans_zero(&self, epsilon: f64) -> bool {
+        self.l <= epsilon && self.h >= -epsilon
+    }
+
+    /// `self` and `other` as one bar, both starting when the earlier does,
+    /// if their starts are at most `tolerance` milliseconds apart.
+    pub fn paired(self, other: Self, tolerance: u64) -> Result<(Self, Self), ServerError> {
+        self.assert_timestamps(other, tolerance)?;
+        let t = self.t.min(other.t);
+        Ok((Self { t, ..self }, Self { t, ..other }))
+    }
+
+    fn assert_timestamps(&self, other: Self, tolerance: u64) -> Result<(), ServerError> {
+        if self.t.abs_diff(other.t) > tolerance {
+            return Err(ServerError::MismatchedTimestamps);
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&self, other: Self) -> Result<Self, ServerError> {
+        self.assert_timestamps(other, 0)?;
+
+        Ok(Self {
+            t: self.t,
+            o: self.o + other.o,
+            c: self.c + other.c,
+            h: self.h + other.h,
+            l: self.l + other.l,
+            v: self.v + other.v,
+            q: self.q + other.q,
+            n: self.n + other.n,
+            taker_v: self.taker_v + other.taker_v,
+            taker_q: self.taker_q + other.taker_q,
+            closed: self.closed && other.closed,
+            event_time: self.event_time.max(other.event_time),
+        })
+    }
+
+    pub fn sub(&self, other: Self) -> Result<Self, ServerError> {
+        self.assert_timestamps(other, 0)?;
+
+        Ok(Self {
+            t: self.t,
+            o: self.o - other.o,
+            c: self.c - other.c,
+            h: self.h - other.h,
+            l: self.l - other.l,
+            v: self.v + other.v,
+            q: self.q + other.q,
+            n: self.n + other.n,
+            taker_v: self.taker_v + other.taker_v,
+            taker_q: self.taker_q + other.taker_q,
+            closed: self.closed && other.closed,
+            event_time: self.event_time.max(other.event_time),
+        })
+    }
+
+    pub fn mul(&self, other: Self) -> Result<Self, ServerError> {
+        self.assert_timestamps(other, 0)?;
+
+        Ok(Self {
+            t: self.t,
+            o: self.o * other.o,
+            c: self.c * other.c,
+            h: self.h * other.h,
+            l: self.l * other.l,
+            closed: self.closed && other.closed,
+            event_time: self.event_time.max(other.event_time),
+            ..*self
+        })
+    }
+
+    /// Fails if any price of `other` is zero.
+    pub fn div(&self, other: Self) -> Result<Self, ServerError> {
+        let quotient = self.div_within(other, 0.0)?;
+        if quotient.undefined() {
+            Err(ServerError::DivisionByZero)
+        } else {
+            Ok(quotient)
+        }
+    }
+
+    /// Divides price by price, leaving those whose divisor is within
+    /// `epsilon` of zero undefined.
+    pub fn div_within(&self, other: Self, epsilon: f64) -> Result<Self, ServerError> {
+        self.assert_timestamps(other, 0)?;
+
+        Ok(Self {
+            t: self.t,
+            o: quotient(self.o, other.o, epsilon),
+            c: quotient(self.c, other.c, epsilon),
+            h: quotient(self.h, other.h, epsilon),
+            l: quotient(self.l, other.l, epsilon),
+            closed: self.closed && other.closed,
+            event_time: self.event_time.max(other.event_time),
+            ..*self
+        })
+    }
+}
+
+/// `dividend / divisor`, NaN for a divisor within `epsilon` of zero.
+pub fn quotient(dividend: f64, divisor: f64, epsilon: f64) -> f64 {
+    if divisor.abs() <= epsilon {
+        f64::NAN
+    } else {
+        dividend / divisor
+    }
+}
+
+#[cfg(test)]
+mod tests_candle_conversion {
+    use super::{BinanceKlineData, BinanceMiniTicker, Candle, ServerError};
+    use serde_json::json;
+
+    fn candle_from_kline(o: &str, q: &str) -> Result<Candle, ServerError> {
+        let json = json!({
+            "t": 1_704_067_200_000u64, "T": 1_704_067_259_999u64, "s": "BTCUSDT",
+            "i": "1m", "f": 100, "L": 200, "o": o, "c": "42301.10",
+            "h": "42310.00", "l": "42280.20", "v": "12.345", "n": 101, "x": false,
+            "q": q, "V": "5.120", "Q": "216563.01", "B": "0"
+        })
+        .to_string();
+        let kline: BinanceKlineData = serde_json::from_str(&json).unwrap();
+        Candle::try_from(&kline)
+    }
+
+    #[test]
+    fn test_candle_from_kline() {
+        let candle = candle_from_kline("42283.50", "521987.6543").unwrap();
+        assert_eq!(candle.t, 1_704_067_200_000);
+        assert_eq!((candle.o, candle.c), (42283.50, 42301.10));
+        assert_eq!((candle.h, candle.l), (42310.00, 42280.20));
+        assert_eq!((candle.v, candle.q, candle.n), (12.345, 521987.6543, 101));
+        assert_eq!((candle.taker_v, candle.taker_q), (5.120, 216563.01));
+        assert!(!candle.closed);
+    }
+
+    #[test]
+    fn test_candle_from_mini_ticker_buckets_to_the_second() {
+        let json = json!({
+            "e": "24hrMiniTicker", "E": 1_704_067_200_987u64, "s": "BTCUSDT",
+            "c": "42301.1", "o": "41800.0", "h": "42500.0", "l": "41650.5",
+            "v": "182034.1", "q": "7650283810.2"
+        })
+        .to_string();
+        let ticker: BinanceMiniTicker = serde_json::from_str(&json).unwrap();
+        let candle = Candle::try_from(&ticker).unwrap();
+        assert_eq!(
+            (candle.t, candle.event_time),
+            (1_704_067_200_000, 1_704_067_200_987)
+        );
+        assert_eq!((candle.o, candle.c), (41800.0, 42301.1));
+        assert_eq!((candle.h, candle.l), (42500.0, 41650.5));
+        assert_eq!((candle.v, candle.q, candle.n), (182034.1, 7650283810.2, 0));
+        assert!(!candle.closed);
+    }
+
+    #[test]
+    fn test_combined_candle_closes_with_every_operand() {
+        let open = Candle::new(0, 1.0, 1.0, 1.0, 1.0);
+        let closed = open.with_closed(true);
+        assert!(!closed.add(open).unwrap().closed);
+        assert!(closed.div(closed).unwrap().closed);
+    }
+
+    #[test]
+    fn test_paired_candles_start_with_the_earlier() {
+        let early = Candle::new(60_000, 1.0, 1.0, 1.0, 1.0);
+        let late = Candle { t: 60_500, ..early };
+        assert!(matches!(
+            early.add(late),
+            Err(ServerError::MismatchedTimestamps)
+        ));
+
+        // Exactly at the tolerance is still the same bar, either way round
+        let (lhs, rhs) = late.paired(early, 500).unwrap();
+        assert_eq!((lhs.t, rhs.t), (60_000, 60_000));
+        assert_eq!(lhs.add(rhs).unwrap().t, 60_000);
+        let just_over = Candle { t: 60_501, ..early };
+        assert!(early.paired(just_over, 500).is_err());
+        assert!(just_over.paired(early, 500).is_err());
+        assert!(early.paired(late, 0).is_err());
+    }
+
+    #[test]
+    fn test_candle_from_kline_names_malformed_field() {
+        let error = candle_from_kline("42283.50", "n/a").unwrap_err();
+        assert!(matches!(error, ServerError::MalformedKline { .. }));
+        assert_eq!(
+            error.to_string(),
+            r#"Malformed kline for BTCUSDT: q "n/a" (invalid float literal)"#
+        );
+    }
+
+    #[test]
+    fn test_candle_from_kline_collects_every_malformed_field() {
+        let error = candle_from_kline("", "1e").unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains(r#"o """#), "{}", message);
+        assert!(message.contains(r#"q "1e""#), "{}", message);
+    }
+
+    #[test]
+    fn test_candle_deserializes_numbers_and_strings() {
+        let numeric: Candle =
+            serde_json::from_value(json!({"t": 0, "o": 1.5, "c": 2, "h": 2.5, "l": 1})).unwrap();
+        let strings: Candle = serde_json::from_value(
+            json!({"t": 0, "o": "1.5", "c": "2", "h": "2.50", "l": "1.0", "V": "0.5"}),
+        )
+        .unwrap();
+        for candle in [numeric, strings] {
+            assert_eq!(
+                (candle.o, candle.c, candle.h, candle.l),
+                (1.5, 2.0, 2.5, 1.0)
+            );
+        }
+        assert_eq!(strings.taker_v, 0.5);
+        assert_eq!(numeric.v, 0.0);
+    }
+
+    #[test]
+    fn test_candle_deserialize_rejects_malformed_price() {
+        let result =
+            serde_json::from_value::<Candle>(json!({"t": 0, "o": "abc", "c": 1, "h": 1, "l": 1}));
+        assert!(result.is_err());
+    }
+}
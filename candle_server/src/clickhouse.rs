@@ -0,0 +1,507 @@
+use log::{info, warn};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+use url::Url;
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::error::ServerError;
+use crate::memory::MemoryAccount;
+use crate::sink::{SinkCounters, SinkHandle, SinkReceiver, SinkRecord, SINK_QUEUE_CAPACITY};
+
+// An INSERT taking longer than this counts as a failed one
+const INSERT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClickHouseSinkConfig {
+    // HTTP interface, `http://[user[:password]@]host[:port]`; plain `http`
+    // only, like the REST client
+    pub url: String,
+    // Table rows are inserted into, optionally `database.table`; see `row`
+    // for its columns
+    pub table: String,
+    // Rows sent in one INSERT, and longest a row waits for one
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    // Rows held while ClickHouse is down; beyond this the oldest are dropped
+    pub max_buffered_rows: usize,
+}
+
+impl Default for ClickHouseSinkConfig {
+    fn default() -> Self {
+        ClickHouseSinkConfig {
+            url: "http://127.0.0.1:8123".into(),
+            table: "candles".into(),
+            batch_size: 1000,
+            flush_interval: Duration::from_secs(1),
+            max_buffered_rows: 100_000,
+        }
+    }
+}
+
+/// One JSONEachRow line of a record, for a table with the columns
+/// `stream String, t UInt64` (open time, Unix millis), `o`, `h`, `l`, `c`
+/// and `v Nullable(Float64)`, `closed Bool`, `partial Bool` and
+/// `missing Array(String)`, the legs a partial bar lacks.
+pub fn row(record: &SinkRecord) -> String {
+    let message = &record.message;
+    let data = &message.data;
+    json!({
+        "stream": record.key,
+        "t": data.t,
+        "o": data.rounded(data.o),
+        "h": data.rounded(data.h),
+        "l": data.rounded(data.l),
+        "c": data.rounded(data.c),
+        "v": data.v,
+        "closed": message.closed,
+        "partial": message.partial,
+        "missing": message.missing,
+    })
+    .to_string()
+}
+
+/// Starts a task inserting every record into ClickHouse in batches. Failed
+/// inserts are retried with `backoff`, holding rows meanwhile up to
+/// `max_buffered_rows`.
+pub fn spawn(
+    config: ClickHouseSinkConfig,
+    backoff: BackoffConfig,
+    memory: MemoryAccount,
+) -> SinkHandle {
+    let (handle, rx) = SinkHandle::new("clickhouse", SINK_QUEUE_CAPACITY, memory.clone());
+    tokio::spawn(run(config, backoff, rx, handle.counters().clone(), memory));
+    handle
+}
+
+// Why an INSERT didn't go through
+enum Failure {
+    // ClickHouse couldn't be reached or answered 5xx; worth retrying
+    Transient(ServerError),
+    // Anything else, e.g. a missing table, which retrying won't fix
+    Rejected(ServerError),
+}
+
+async fn run(
+    config: ClickHouseSinkConfig,
+    backoff: BackoffConfig,
+    mut rx: SinkReceiver,
+    counters: Arc<SinkCounters>,
+    memory: MemoryAccount,
+) {
+    let mut backoff = Backoff::new(backoff);
+    // Rows waiting for an INSERT, charged to `memory` as they are held
+    let mut rows: VecDeque<String> = VecDeque::new();
+    let mut flush_at = Instant::now() + config.flush_interval;
+    // Set after a failed INSERT, which nothing is sent before
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        let due = match retry_at {
+            Some(at) => Instant::now() >= at,
+            None => rows.len() >= config.batch_size || Instant::now() >= flush_at,
+        };
+        if !due {
+            tokio::select! {
+                record = rx.recv() => {
+                    let Some(record) = record else {
+                        // Whatever is left gets one try
+                        if !rows.is_empty() {
+                            let batch = rows.len();
+                            let _ = insert(&config, &rows, batch).await;
+                        }
+                        return;
+                    };
+                    if rows.len() >= config.max_buffered_rows {
+                        if let Some(oldest) = rows.pop_front() {
+                            memory.release(oldest.len());
+                            counters.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    let row = row(&record);
+                    memory.charge(row.len());
+                    rows.push_back(row);
+                }
+                _ = sleep_until(retry_at.unwrap_or(flush_at)) => {}
+            }
+            continue;
+        }
+
+        flush_at = Instant::now() + config.flush_interval;
+        if rows.is_empty() {
+            continue;
+        }
+        let batch = rows.len().min(config.batch_size);
+        // Whether the batch is done with, delivered or not
+        let done = match insert(&config, &rows, batch).await {
+            Ok(()) => {
+                if !counters.connected.swap(true, Ordering::Relaxed) {
+                    info!("Inserting into ClickHouse at {}", config.url);
+                }
+                counters
+                    .published
+                    .fetch_add(batch as u64, Ordering::Relaxed);
+                backoff.reset();
+                retry_at = None;
+                true
+            }
+            Err(Failure::Rejected(e)) => {
+                warn!("ClickHouse rejected {} rows: {}", batch, e);
+                counters.failed.fetch_add(batch as u64, Ordering::Relaxed);
+                retry_at = None;
+                true
+            }
+            Err(Failure::Transient(e)) => {
+                counters.connected.store(false, Ordering::Relaxed);
+                match backoff.next_delay() {
+                    Some(delay) => {
+                        warn!(
+                            "ClickHouse insert attempt {} failed: {}",
+                            backoff.attempt(),
+                            e
+                        );
+                        retry_at = Some(Instant::now() + delay);
+                        false
+                    }
+                    None => {
+                        warn!(
+                            "Giving up on {} rows after {} ClickHouse attempts: {}",
+                            batch,
+                            backoff.attempt(),
+                            e
+                        );
+                        counters.failed.fetch_add(batch as u64, Ordering::Relaxed);
+                        backoff.reset();
+                        retry_at = None;
+                        true
+                    }
+                }
+            }
+        };
+        if done {
+            for row in rows.drain(..batch) {
+                memory.release(row.len());
+            }
+        }
+    }
+}
+
+// POSTs the first `batch` of `rows` to the HTTP interface as one INSERT
+async fn insert(
+    config: &ClickHouseSinkConfig,
+    rows: &VecDeque<String>,
+    batch: usize,
+) -> Result<(), Failure> {
+    let mut url = Url::parse(&config.url).map_err(|e| Failure::Rejected(e.into()))?;
+    if url.scheme() != "http" {
+        return Err(Failure::Rejected(ServerError::ClickHouse(format!(
+            "{} needs TLS, which this build lacks",
+            url.scheme()
+        ))));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| Failure::Rejected(ServerError::ClickHouse("URL without a host".into())))?
+        .to_string();
+    let port = url.port().unwrap_or(8123);
+    let mut credentials = String::new();
+    if !url.username().is_empty() {
+        credentials.push_str(&format!("X-ClickHouse-User: {}\r\n", url.username()));
+    }
+    if let Some(password) = url.password() {
+        credentials.push_str(&format!("X-ClickHouse-Key: {}\r\n", password));
+    }
+    url.query_pairs_mut().append_pair(
+        "query",
+        &format!("INSERT INTO {} FORMAT JSONEachRow", config.table),
+    );
+
+    let mut body = Vec::new();
+    for row in rows.iter().take(batch) {
+        body.extend_from_slice(row.as_bytes());
+        body.push(b'\n');
+    }
+    let request = format!(
+        "POST {}?{} HTTP/1.1\r\nHost: {}:{}\r\n{}Content-Type: application/x-ndjson\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        url.path(),
+        url.query().unwrap_or_default(),
+        host,
+        port,
+        credentials,
+        body.len()
+    );
+
+    let response = timeout(INSERT_TIMEOUT, async {
+        let mut stream = TcpStream::connect((host.as_str(), port)).await?;
+        stream.write_all(request.as_bytes()).await?;
+        stream.write_all(&body).await?;
+        // Read to the end, as the connection closes after one response
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    })
+    .await
+    .map_err(|_| Failure::Transient(ServerError::ClickHouse("insert timed out".into())))?
+    .map_err(|e| Failure::Transient(e.into()))?;
+
+    let status = std::str::from_utf8(&response)
+        .ok()
+        .and_then(|response| response.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(|| {
+            Failure::Transient(ServerError::ClickHouse("malformed HTTP response".into()))
+        })?;
+    // ClickHouse explains a failure in the body
+    let reason = || {
+        let head_end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map_or(response.len(), |end| end + 4);
+        let body = String::from_utf8_lossy(&response[head_end..]);
+        ServerError::ClickHouse(format!("HTTP status {}: {}", status, body.trim()))
+    };
+    match status {
+        200 => Ok(()),
+        500.. => Err(Failure::Transient(reason())),
+        _ => Err(Failure::Rejected(reason())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{row, spawn, ClickHouseSinkConfig};
+    use crate::backoff::BackoffConfig;
+    use crate::candle::Candle;
+    use crate::memory::MemoryAccount;
+    use crate::protocol::{ResultData, ResultMessage};
+    use crate::sink::SinkRecord;
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout, Duration};
+
+    #[derive(Debug)]
+    struct Insert {
+        target: String,
+        user: Option<String>,
+        rows: Vec<Value>,
+    }
+
+    // Fake HTTP interface answering the statuses of `script` in turn, then
+    // 200, and reporting every INSERT it accepts
+    async fn fake_clickhouse(script: &'static [u16]) -> (String, mpsc::UnboundedReceiver<Insert>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://default:secret@{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut statuses = script.iter();
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let head_end = loop {
+                    let mut chunk = [0; 1024];
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..read]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+                let header = |name: &str| {
+                    head.lines().find_map(|line| {
+                        let (key, value) = line.split_once(':')?;
+                        key.eq_ignore_ascii_case(name)
+                            .then(|| value.trim().to_string())
+                    })
+                };
+                let length: usize = header("content-length").unwrap().parse().unwrap();
+                while request.len() < head_end + length {
+                    let mut chunk = [0; 1024];
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..read]);
+                }
+
+                let status = statuses.next().copied().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                if status == 200 {
+                    let body = std::str::from_utf8(&request[head_end..]).unwrap();
+                    let _ = tx.send(Insert {
+                        target: head.split_whitespace().nth(1).unwrap().to_string(),
+                        user: header("x-clickhouse-user"),
+                        rows: body
+                            .lines()
+                            .map(|line| serde_json::from_str(line).unwrap())
+                            .collect(),
+                    });
+                }
+            }
+        });
+        (url, rx)
+    }
+
+    fn record(t: u64) -> Arc<SinkRecord> {
+        Arc::new(SinkRecord {
+            key: "btcusdt+ethusdt@1m".into(),
+            message: ResultMessage {
+                id: None,
+                stream: "btcusdt+ethusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 2.0, 3.0, 0.5)),
+                closed: true,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            },
+        })
+    }
+
+    async fn next_insert(rx: &mut mpsc::UnboundedReceiver<Insert>) -> Insert {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("nothing inserted")
+            .unwrap()
+    }
+
+    fn fast_backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            ..BackoffConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_row_columns() {
+        let mut record = (*record(60_000)).clone();
+        record.message.partial = true;
+        record.message.missing = vec!["ethusdt@kline_1m".into()];
+        record.message.data.c = None;
+        let row: Value = serde_json::from_str(&row(&record)).unwrap();
+        assert_eq!(
+            row,
+            json!({
+                "stream": "btcusdt+ethusdt@1m", "t": 60_000,
+                "o": 1.0, "h": 3.0, "l": 0.5, "c": null, "v": 0.0,
+                "closed": true, "partial": true, "missing": ["ethusdt@kline_1m"]
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_inserted_in_batches() {
+        let (url, mut inserts) = fake_clickhouse(&[]).await;
+        let config = ClickHouseSinkConfig {
+            url,
+            table: "market.candles".into(),
+            batch_size: 2,
+            flush_interval: Duration::from_millis(100),
+            ..ClickHouseSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+
+        for t in [0, 60_000, 120_000] {
+            sink.offer(record(t));
+        }
+        let insert = next_insert(&mut inserts).await;
+        assert_eq!(
+            insert.target,
+            "/?query=INSERT+INTO+market.candles+FORMAT+JSONEachRow"
+        );
+        assert_eq!(insert.user.as_deref(), Some("default"));
+        let times: Vec<&Value> = insert.rows.iter().map(|row| &row["t"]).collect();
+        assert_eq!(times, [0, 60_000]);
+        // The rest once the interval passes
+        let insert = next_insert(&mut inserts).await;
+        assert_eq!(insert.rows.len(), 1);
+        assert_eq!(insert.rows[0]["t"], 120_000);
+
+        // Counted once the response is read in full
+        timeout(Duration::from_secs(5), async {
+            while sink.stats().published < 3 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("rows not counted");
+        let stats = sink.stats();
+        assert_eq!((stats.published, stats.failed), (3, 0));
+        assert!(stats.connected);
+        assert_eq!(stats.buffered_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_transient_failures_are_retried() {
+        let (url, mut inserts) = fake_clickhouse(&[503, 503]).await;
+        let config = ClickHouseSinkConfig {
+            url,
+            batch_size: 1,
+            ..ClickHouseSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+
+        sink.offer(record(0));
+        let insert = next_insert(&mut inserts).await;
+        assert_eq!(insert.rows[0]["t"], 0);
+        assert_eq!(sink.stats().failed, 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_rows_are_not_retried() {
+        let (url, mut inserts) = fake_clickhouse(&[400]).await;
+        let config = ClickHouseSinkConfig {
+            url,
+            batch_size: 1,
+            ..ClickHouseSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+
+        sink.offer(record(0));
+        sink.offer(record(60_000));
+        let insert = next_insert(&mut inserts).await;
+        assert_eq!(insert.rows[0]["t"], 60_000);
+        assert_eq!(sink.stats().failed, 1);
+    }
+
+    #[tokio::test]
+    async fn test_oldest_rows_are_dropped_while_down() {
+        // Nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let config = ClickHouseSinkConfig {
+            url,
+            batch_size: 10,
+            flush_interval: Duration::from_millis(10),
+            max_buffered_rows: 2,
+            ..ClickHouseSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+
+        for t in 0..5 {
+            sink.offer(record(t * 60_000));
+            sleep(Duration::from_millis(5)).await;
+        }
+        timeout(Duration::from_secs(5), async {
+            while sink.stats().dropped < 3 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no rows dropped");
+        let stats = sink.stats();
+        assert_eq!((stats.published, stats.dropped), (0, 3));
+        assert!(!stats.connected);
+        assert!(stats.buffered_bytes > 0);
+    }
+}
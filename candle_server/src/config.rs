@@ -0,0 +1,1897 @@
+use log::LevelFilter;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use tokio::time::Duration;
+
+use crate::candle::DivisionPolicy;
+use crate::clickhouse::ClickHouseSinkConfig;
+use crate::encoding::OutputEncoder;
+use crate::error::ServerError;
+use crate::kafka::{KafkaSinkConfig, PartitionKey};
+use crate::mqtt::{MqttSinkConfig, QoS};
+use crate::postgres::PostgresSinkConfig;
+use crate::protocol::MAX_PRECISION;
+use crate::redis::RedisSinkConfig;
+use crate::server::{ServerConfig, SlowClientPolicy, TimestampPolicy};
+use crate::synthetic::SyntheticConfig;
+use crate::upstream::OrderingPolicy;
+
+/// Prefix of the environment variables overriding settings, e.g.
+/// `CANDLE_REDIS_URL` for `redis.url`.
+pub const ENV_PREFIX: &str = "CANDLE_";
+
+/// Command-line flags and the setting each one overrides.
+pub const FLAGS: &[(&str, &str)] = &[
+    ("--listen", "listen"),
+    ("--bind", "listen"),
+    ("--bind-unix", "listen_unix"),
+    ("--bind-unix-mode", "listen_unix_mode"),
+    ("--grpc-addr", "grpc_addr"),
+    ("--upstream", "upstream_url"),
+    ("--no-upstream-compression", "upstream_compression"),
+    ("--rest-url", "rest_url"),
+    ("--runtime", "runtime"),
+    ("--workers", "workers"),
+    ("--log-level", "log_level"),
+    ("--task-budget", "task_budget"),
+    ("--evaluator-shards", "evaluator_shards"),
+    ("--export-dir", "export_dir"),
+    ("--slow-client-timeout", "slow_client_timeout"),
+    ("--redis-url", "redis.url"),
+    ("--redis-prefix", "redis.prefix"),
+    ("--redis-latest-ttl", "redis.latest_ttl"),
+    ("--kafka-brokers", "kafka.brokers"),
+    ("--kafka-topic", "kafka.topic"),
+    ("--mqtt-url", "mqtt.url"),
+    ("--mqtt-prefix", "mqtt.prefix"),
+    ("--mqtt-qos", "mqtt.qos"),
+    ("--mqtt-retain", "mqtt.retain"),
+    ("--clickhouse-url", "clickhouse.url"),
+    ("--clickhouse-table", "clickhouse.table"),
+    ("--postgres-url", "postgres.url"),
+    ("--postgres-table", "postgres.table"),
+    ("--db-migrate", "postgres.migrate"),
+    ("--synthetic", "synthetic"),
+    ("--synthetic-seed", "synthetic.seed"),
+    ("--synthetic-tick", "synthetic.tick"),
+    ("--synthetic-volatility", "synthetic.volatility"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeFlavor {
+    CurrentThread,
+    MultiThread,
+}
+
+/// Everything `candle_server` runs with: the listener and runtime around a
+/// `ServerConfig`.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Every address is served the same; IPv6 ones are written `[::]:9000`
+    pub listen: Vec<SocketAddr>,
+    // Unix socket served besides `listen`; a stale file there is replaced
+    pub listen_unix: Option<PathBuf>,
+    // Permissions of `listen_unix`, e.g. 0o660; the umask's when unset
+    pub listen_unix_mode: Option<u32>,
+    // The gRPC `Candles` service, on a port of its own as it speaks HTTP/2
+    pub grpc_addr: Option<SocketAddr>,
+    pub runtime: RuntimeFlavor,
+    // Tokio's default is one worker per core, far more than a mostly idle
+    // I/O workload needs on a large machine
+    pub workers: Option<usize>,
+    // Least severe messages written to stderr, from `error` to `trace`
+    pub log_level: LevelFilter,
+    pub server: ServerConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            listen: vec![SocketAddr::from(([127, 0, 0, 1], 9000))],
+            listen_unix: None,
+            listen_unix_mode: None,
+            grpc_addr: None,
+            runtime: RuntimeFlavor::MultiThread,
+            workers: None,
+            log_level: LevelFilter::Info,
+            server: ServerConfig::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Defaults overridden by the TOML `file`, then by `flags` as
+    /// `(flag, value)` pairs, then by `CANDLE_*` variables looked up with
+    /// `env`. The result is validated as a whole.
+    pub fn load(
+        file: Option<&Path>,
+        flags: &[(String, String)],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Settings, ServerError> {
+        let text = match file {
+            Some(path) => Some(
+                std::fs::read_to_string(path)
+                    .map_err(|e| ServerError::Config(format!("{}: {}", path.display(), e)))?,
+            ),
+            None => None,
+        };
+        let file = file.zip(text.as_deref());
+        Self::from_layers(file, flags, env)
+    }
+
+    fn from_layers(
+        file: Option<(&Path, &str)>,
+        flags: &[(String, String)],
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Settings, ServerError> {
+        let mut settings = Settings::default();
+
+        if let Some((path, text)) = file {
+            let error = |line: usize, message: String| {
+                ServerError::Config(format!("{}:{}: {}", path.display(), line, message))
+            };
+            for entry in toml::parse(text).map_err(|(line, message)| error(line, message))? {
+                let key = KEYS
+                    .iter()
+                    .find(|key| key.name == entry.key)
+                    .ok_or_else(|| error(entry.line, format!("unknown key `{}`", entry.key)))?;
+                (key.set)(&mut settings, &entry.value)
+                    .map_err(|message| error(entry.line, format!("`{}`: {}", key.name, message)))?;
+            }
+        }
+
+        for (flag, value) in flags {
+            let (_, name) = FLAGS
+                .iter()
+                .find(|(known, _)| known == flag)
+                .ok_or_else(|| ServerError::Config(format!("unknown flag {}", flag)))?;
+            let key = KEYS.iter().find(|key| key.name == *name).unwrap();
+            (key.set)(&mut settings, &Value::String(value.clone()))
+                .map_err(|message| ServerError::Config(format!("{}: {}", flag, message)))?;
+        }
+
+        // Tables are turned on by setting any of their keys
+        for key in KEYS.iter().filter(|key| !TABLES.contains(&key.name)) {
+            let variable = env_variable(key.name);
+            if let Some(value) = env(&variable) {
+                (key.set)(&mut settings, &Value::String(value))
+                    .map_err(|message| ServerError::Config(format!("{}: {}", variable, message)))?;
+            }
+        }
+
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    // Checks spanning several keys; single values are checked as they're set
+    fn validate(&self) -> Result<(), ServerError> {
+        if self.runtime == RuntimeFlavor::CurrentThread && self.workers.is_some() {
+            return Err(ServerError::Config(
+                "`workers` requires runtime = \"multi-thread\"".into(),
+            ));
+        }
+        if self.listen.is_empty() && self.listen_unix.is_none() {
+            return Err(ServerError::Config(
+                "`listen` is empty and `listen_unix` is unset".into(),
+            ));
+        }
+        if self.listen_unix_mode.is_some() && self.listen_unix.is_none() {
+            return Err(ServerError::Config(
+                "`listen_unix_mode` requires `listen_unix`".into(),
+            ));
+        }
+        let backoff = &self.server.backoff;
+        if backoff.initial_delay > backoff.max_delay {
+            return Err(ServerError::Config(
+                "`backoff.initial_delay` exceeds `backoff.max_delay`".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The settings as a TOML file `load` reads back unchanged.
+    pub fn to_toml(&self) -> String {
+        let mut out = String::new();
+        let mut table = "";
+        for key in KEYS {
+            let Some(value) = (key.get)(self) else {
+                continue;
+            };
+            let (parent, name) = key.name.rsplit_once('.').unwrap_or(("", key.name));
+            let section = match value {
+                Value::Table => key.name,
+                _ => parent,
+            };
+            if section != table {
+                out.push_str(&format!("\n[{}]\n", section));
+                table = section;
+            }
+            if !matches!(value, Value::Table) {
+                out.push_str(&format!("{} = {}\n", name, value));
+            }
+        }
+        out
+    }
+}
+
+/// `CANDLE_*` variable overriding the setting `key`.
+pub fn env_variable(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// A TOML value. Flags and environment variables are read as strings that
+/// the setting parses as its own type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    // A `[table]` header; setting it turns on an optional section
+    Table,
+}
+
+impl Value {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Integer(_) => "integer",
+            Value::Float(_) => "float",
+            Value::Boolean(_) => "boolean",
+            Value::Table => "table",
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(text) => write!(f, "{:?}", text),
+            Value::Integer(integer) => write!(f, "{}", integer),
+            Value::Float(float) => write!(f, "{:?}", float),
+            Value::Boolean(boolean) => write!(f, "{}", boolean),
+            Value::Table => Ok(()),
+        }
+    }
+}
+
+// Optional sections, e.g. `[redis]`
+const TABLES: &[&str] = &[
+    "backoff",
+    "redis",
+    "kafka",
+    "mqtt",
+    "clickhouse",
+    "postgres",
+    "webhook",
+    "synthetic",
+];
+
+struct Key {
+    // Dotted for keys inside a table, e.g. `redis.url`
+    name: &'static str,
+    set: fn(&mut Settings, &Value) -> Result<(), String>,
+    // `None` leaves the key out of `to_toml`
+    get: fn(&Settings) -> Option<Value>,
+}
+
+// Every setting, in the order `to_toml` writes them; keys of a table follow
+// its header
+const KEYS: &[Key] = &[
+    Key {
+        name: "listen",
+        set: |s, v| {
+            // Empty for no TCP listener at all
+            s.listen = string(v)?
+                .split(',')
+                .filter(|addr| !addr.trim().is_empty())
+                .map(|addr| {
+                    let what = "socket addresses like \"0.0.0.0:9000, [::]:9000\"";
+                    parse(&Value::String(addr.trim().into()), what)
+                })
+                .collect::<Result<_, _>>()?;
+            Ok(())
+        },
+        get: |s| {
+            let addrs: Vec<_> = s.listen.iter().map(|addr| addr.to_string()).collect();
+            Some(Value::String(addrs.join(", ")))
+        },
+    },
+    Key {
+        name: "listen_unix",
+        set: |s, v| {
+            s.listen_unix = Some(string(v)?.into());
+            Ok(())
+        },
+        get: |s| {
+            s.listen_unix
+                .as_ref()
+                .map(|path| Value::String(path.display().to_string()))
+        },
+    },
+    Key {
+        name: "listen_unix_mode",
+        set: |s, v| {
+            let what = "octal permissions like \"660\"";
+            let mode = u32::from_str_radix(&string(v).map_err(|_| expected(what, v))?, 8)
+                .ok()
+                .filter(|&mode| mode <= 0o777)
+                .ok_or_else(|| expected(what, v))?;
+            s.listen_unix_mode = Some(mode);
+            Ok(())
+        },
+        get: |s| {
+            s.listen_unix_mode
+                .map(|mode| Value::String(format!("{:o}", mode)))
+        },
+    },
+    Key {
+        name: "grpc_addr",
+        set: |s, v| {
+            s.grpc_addr = Some(parse(v, "a socket address like \"0.0.0.0:9001\"")?);
+            Ok(())
+        },
+        get: |s| s.grpc_addr.map(|addr| Value::String(addr.to_string())),
+    },
+    Key {
+        name: "runtime",
+        set: |s, v| {
+            s.runtime = match string(v)?.as_str() {
+                "current-thread" => RuntimeFlavor::CurrentThread,
+                "multi-thread" => RuntimeFlavor::MultiThread,
+                _ => return Err(expected("\"current-thread\" or \"multi-thread\"", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(match s.runtime {
+                RuntimeFlavor::CurrentThread => "current-thread".into(),
+                RuntimeFlavor::MultiThread => "multi-thread".into(),
+            }))
+        },
+    },
+    Key {
+        name: "workers",
+        set: |s, v| {
+            s.workers = Some(positive(v)?);
+            Ok(())
+        },
+        get: |s| s.workers.map(|workers| Value::Integer(workers as i64)),
+    },
+    Key {
+        name: "log_level",
+        set: |s, v| {
+            s.log_level = string(v)?.parse().map_err(|_| {
+                expected(
+                    "one of \"off\", \"error\", \"warn\", \"info\", \"debug\" or \"trace\"",
+                    v,
+                )
+            })?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.log_level.as_str().to_lowercase())),
+    },
+    Key {
+        name: "upstream_url",
+        set: |s, v| {
+            s.server.upstream_url = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.upstream_url.clone())),
+    },
+    Key {
+        name: "upstream_compression",
+        set: |s, v| {
+            s.server.upstream_compression = boolean(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Boolean(s.server.upstream_compression)),
+    },
+    Key {
+        name: "rest_url",
+        set: |s, v| {
+            s.server.rest_url = Some(string(v)?).filter(|url| !url.is_empty());
+            Ok(())
+        },
+        get: |s| s.server.rest_url.clone().map(Value::String),
+    },
+    Key {
+        name: "rest_seed",
+        set: |s, v| {
+            s.server.rest_seed = boolean(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Boolean(s.server.rest_seed)),
+    },
+    Key {
+        name: "open_interest_poll",
+        set: |s, v| {
+            s.server.open_interest_poll = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.open_interest_poll)),
+    },
+    Key {
+        name: "ping_interval",
+        set: |s, v| {
+            s.server.ping_interval = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.ping_interval)),
+    },
+    Key {
+        name: "max_connection_age",
+        set: |s, v| {
+            s.server.max_connection_age = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.max_connection_age)),
+    },
+    Key {
+        name: "connection_stale_after",
+        set: |s, v| {
+            s.server.connection_stale_after = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.connection_stale_after)),
+    },
+    Key {
+        name: "stream_stale_after",
+        set: |s, v| {
+            s.server.stream_stale_after = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.stream_stale_after)),
+    },
+    Key {
+        name: "late_klines",
+        set: |s, v| {
+            s.server.ordering = match string(v)?.as_str() {
+                "drop" => OrderingPolicy::Drop,
+                "emit" => OrderingPolicy::EmitWithFlag,
+                _ => return Err(expected("\"drop\" or \"emit\"", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(match s.server.ordering {
+                OrderingPolicy::Drop => "drop".into(),
+                OrderingPolicy::EmitWithFlag => "emit".into(),
+            }))
+        },
+    },
+    Key {
+        name: "partial_bars_after",
+        set: |s, v| {
+            s.server.timestamp_policy = match never_or_duration(v)? {
+                None => TimestampPolicy::Skip,
+                Some(window) => TimestampPolicy::Partial { window },
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.timestamp_policy {
+                TimestampPolicy::Skip => Value::String("never".into()),
+                TimestampPolicy::Partial { window } => format_duration(window),
+            })
+        },
+    },
+    Key {
+        name: "division_by_zero",
+        set: |s, v| {
+            s.server.division.policy = match string(v)?.as_str() {
+                "error" => DivisionPolicy::Error,
+                "null_field" => DivisionPolicy::NullField,
+                "skip_bar" => DivisionPolicy::SkipBar,
+                _ => return Err(expected("\"error\", \"null_field\" or \"skip_bar\"", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(match s.server.division.policy {
+                DivisionPolicy::Error => "error".into(),
+                DivisionPolicy::NullField => "null_field".into(),
+                DivisionPolicy::SkipBar => "skip_bar".into(),
+            }))
+        },
+    },
+    Key {
+        name: "division_epsilon",
+        set: |s, v| {
+            s.server.division.epsilon = match float(v)? {
+                epsilon if epsilon >= 0.0 => epsilon,
+                _ => return Err("must not be negative".into()),
+            };
+            Ok(())
+        },
+        get: |s| Some(Value::Float(s.server.division.epsilon)),
+    },
+    Key {
+        name: "precision",
+        set: |s, v| {
+            s.server.precision = match v {
+                Value::String(text) if text == "full" => None,
+                _ => match integer(v)? {
+                    precision if (0..=MAX_PRECISION as i64).contains(&precision) => {
+                        Some(precision as u32)
+                    }
+                    _ => return Err(format!("must be between 0 and {}", MAX_PRECISION)),
+                },
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.precision {
+                Some(precision) => Value::Integer(precision as i64),
+                None => Value::String("full".into()),
+            })
+        },
+    },
+    Key {
+        name: "task_budget",
+        set: |s, v| {
+            s.server.task_budget = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.task_budget as i64)),
+    },
+    Key {
+        name: "result_channel_capacity",
+        set: |s, v| {
+            s.server.result_channel_capacity = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.result_channel_capacity as i64)),
+    },
+    Key {
+        name: "evaluator_linger",
+        set: |s, v| {
+            s.server.evaluator_linger = Some(duration(v)?);
+            Ok(())
+        },
+        get: |s| s.server.evaluator_linger.map(format_duration),
+    },
+    Key {
+        name: "evaluator_restarts",
+        set: |s, v| {
+            s.server.evaluator_restarts =
+                u32::try_from(integer(v)?).map_err(|_| expected("a count", v))?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.evaluator_restarts as i64)),
+    },
+    Key {
+        name: "evaluator_shards",
+        set: |s, v| {
+            s.server.evaluator_shards =
+                usize::try_from(integer(v)?).map_err(|_| expected("a count", v))?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.evaluator_shards as i64)),
+    },
+    Key {
+        name: "client_queue_capacity",
+        set: |s, v| {
+            s.server.client_queue_capacity = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.client_queue_capacity as i64)),
+    },
+    Key {
+        name: "max_message_size",
+        set: |s, v| {
+            s.server.max_message_size = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.max_message_size as i64)),
+    },
+    Key {
+        name: "max_frame_size",
+        set: |s, v| {
+            s.server.max_frame_size = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.max_frame_size as i64)),
+    },
+    Key {
+        name: "compress_above",
+        set: |s, v| {
+            s.server.compress_above = match v {
+                Value::String(text) if text == "never" => None,
+                _ => Some(positive(v)?),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.compress_above {
+                Some(above) => Value::Integer(above as i64),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "history_len",
+        set: |s, v| {
+            s.server.history_len =
+                usize::try_from(integer(v)?).map_err(|_| expected("a count", v))?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.history_len as i64)),
+    },
+    Key {
+        name: "export_dir",
+        set: |s, v| {
+            s.server.export_dir = Some(string(v)?.into());
+            Ok(())
+        },
+        get: |s| {
+            s.server
+                .export_dir
+                .as_ref()
+                .map(|path| Value::String(path.display().to_string()))
+        },
+    },
+    Key {
+        name: "slow_client_timeout",
+        set: |s, v| {
+            s.server.slow_clients = match never_or_duration(v)? {
+                None => SlowClientPolicy::KeepDropping,
+                Some(after) => SlowClientPolicy::Disconnect { after },
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.slow_clients {
+                SlowClientPolicy::KeepDropping => Value::String("never".into()),
+                SlowClientPolicy::Disconnect { after } => format_duration(after),
+            })
+        },
+    },
+    Key {
+        name: "sse_heartbeat",
+        set: |s, v| {
+            s.server.sse_heartbeat = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.sse_heartbeat)),
+    },
+    Key {
+        name: "subscription_ttl",
+        set: |s, v| {
+            s.server.subscription_ttl = never_or_duration(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.subscription_ttl {
+                Some(ttl) => format_duration(ttl),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "max_subscription_ttl",
+        set: |s, v| {
+            s.server.max_subscription_ttl = never_or_duration(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.max_subscription_ttl {
+                Some(ttl) => format_duration(ttl),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "max_subscriptions_per_client",
+        set: |s, v| {
+            s.server.max_subscriptions_per_client = match v {
+                Value::String(text) if text == "never" => None,
+                _ => Some(positive(v)?),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.max_subscriptions_per_client {
+                Some(max) => Value::Integer(max as i64),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "memory_budget",
+        set: |s, v| {
+            s.server.memory_budget = match v {
+                Value::String(text) if text == "never" => None,
+                _ => Some(positive(v)?),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.memory_budget {
+                Some(bytes) => Value::Integer(bytes as i64),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "drain_grace",
+        set: |s, v| {
+            s.server.drain_grace = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.drain_grace)),
+    },
+    Key {
+        name: "backoff",
+        set: |_, v| table(v),
+        get: |_| Some(Value::Table),
+    },
+    Key {
+        name: "backoff.initial_delay",
+        set: |s, v| {
+            s.server.backoff.initial_delay = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.backoff.initial_delay)),
+    },
+    Key {
+        name: "backoff.multiplier",
+        set: |s, v| {
+            s.server.backoff.multiplier = match float(v)? {
+                multiplier if multiplier >= 1.0 => multiplier,
+                _ => return Err("must be at least 1".into()),
+            };
+            Ok(())
+        },
+        get: |s| Some(Value::Float(s.server.backoff.multiplier)),
+    },
+    Key {
+        name: "backoff.max_delay",
+        set: |s, v| {
+            s.server.backoff.max_delay = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.backoff.max_delay)),
+    },
+    Key {
+        name: "backoff.jitter",
+        set: |s, v| {
+            s.server.backoff.jitter = match float(v)? {
+                jitter if (0.0..1.0).contains(&jitter) => jitter,
+                _ => return Err("must be at least 0 and below 1".into()),
+            };
+            Ok(())
+        },
+        get: |s| Some(Value::Float(s.server.backoff.jitter)),
+    },
+    Key {
+        name: "backoff.max_attempts",
+        set: |s, v| {
+            s.server.backoff.max_attempts = match v {
+                Value::String(text) if text == "never" => None,
+                _ => Some(u32::try_from(positive(v)?).map_err(|_| expected("a u32", v))?),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(match s.server.backoff.max_attempts {
+                Some(attempts) => Value::Integer(attempts as i64),
+                None => Value::String("never".into()),
+            })
+        },
+    },
+    Key {
+        name: "backoff.healthy_after",
+        set: |s, v| {
+            s.server.backoff.healthy_after = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.backoff.healthy_after)),
+    },
+    Key {
+        name: "redis",
+        set: |s, v| {
+            table(v)?;
+            redis(s);
+            Ok(())
+        },
+        get: |s| s.server.redis.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "redis.url",
+        set: |s, v| {
+            redis(s).url = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.redis.as_ref()?.url.clone())),
+    },
+    Key {
+        name: "redis.prefix",
+        set: |s, v| {
+            redis(s).channel_prefix = string(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(
+                s.server.redis.as_ref()?.channel_prefix.clone(),
+            ))
+        },
+    },
+    Key {
+        name: "redis.latest_ttl",
+        set: |s, v| {
+            redis(s).latest_ttl = Some(duration(v)?);
+            Ok(())
+        },
+        get: |s| s.server.redis.as_ref()?.latest_ttl.map(format_duration),
+    },
+    Key {
+        name: "kafka",
+        set: |s, v| {
+            table(v)?;
+            kafka(s);
+            Ok(())
+        },
+        get: |s| s.server.kafka.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "kafka.brokers",
+        set: |s, v| {
+            let brokers: Vec<String> = string(v)?
+                .split(',')
+                .map(|broker| broker.trim().to_string())
+                .filter(|broker| !broker.is_empty())
+                .collect();
+            if brokers.is_empty() {
+                return Err(expected("brokers like \"kafka-1:9092, kafka-2:9092\"", v));
+            }
+            kafka(s).brokers = brokers;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.kafka.as_ref()?.brokers.join(", "))),
+    },
+    Key {
+        name: "kafka.topic",
+        set: |s, v| {
+            kafka(s).topic = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.kafka.as_ref()?.topic.clone())),
+    },
+    Key {
+        name: "kafka.client_id",
+        set: |s, v| {
+            kafka(s).client_id = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.kafka.as_ref()?.client_id.clone())),
+    },
+    Key {
+        name: "kafka.partition_key",
+        set: |s, v| {
+            kafka(s).partition_key = match string(v)?.as_str() {
+                "expression" => PartitionKey::Expression,
+                "round_robin" => PartitionKey::RoundRobin,
+                _ => return Err(expected("\"expression\" or \"round_robin\"", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(
+                match s.server.kafka.as_ref()?.partition_key {
+                    PartitionKey::Expression => "expression".into(),
+                    PartitionKey::RoundRobin => "round_robin".into(),
+                },
+            ))
+        },
+    },
+    Key {
+        name: "kafka.format",
+        set: |s, v| {
+            kafka(s).format = match string(v)?.as_str() {
+                "json" => OutputEncoder::Json,
+                "cbor" => OutputEncoder::Cbor,
+                "protobuf" => OutputEncoder::Protobuf,
+                _ => return Err(expected("\"json\", \"cbor\" or \"protobuf\"", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::String(match s.server.kafka.as_ref()?.format {
+                OutputEncoder::Json => "json".into(),
+                OutputEncoder::Cbor => "cbor".into(),
+                OutputEncoder::Protobuf => "protobuf".into(),
+            }))
+        },
+    },
+    Key {
+        name: "kafka.batch_size",
+        set: |s, v| {
+            kafka(s).batch_size = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.kafka.as_ref()?.batch_size as i64)),
+    },
+    Key {
+        name: "kafka.flush_interval",
+        set: |s, v| {
+            kafka(s).flush_interval = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.kafka.as_ref()?.flush_interval)),
+    },
+    Key {
+        name: "kafka.max_buffered_records",
+        set: |s, v| {
+            kafka(s).max_buffered_records = positive(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(
+                s.server.kafka.as_ref()?.max_buffered_records as i64,
+            ))
+        },
+    },
+    Key {
+        name: "mqtt",
+        set: |s, v| {
+            table(v)?;
+            mqtt(s);
+            Ok(())
+        },
+        get: |s| s.server.mqtt.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "mqtt.url",
+        set: |s, v| {
+            mqtt(s).url = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.mqtt.as_ref()?.url.clone())),
+    },
+    Key {
+        name: "mqtt.client_id",
+        set: |s, v| {
+            mqtt(s).client_id = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.mqtt.as_ref()?.client_id.clone())),
+    },
+    Key {
+        name: "mqtt.prefix",
+        set: |s, v| {
+            mqtt(s).topic_prefix = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.mqtt.as_ref()?.topic_prefix.clone())),
+    },
+    Key {
+        name: "mqtt.qos",
+        set: |s, v| {
+            mqtt(s).qos = match integer(v) {
+                Ok(0) => QoS::AtMostOnce,
+                Ok(1) => QoS::AtLeastOnce,
+                _ => return Err(expected("0 or 1", v)),
+            };
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(match s.server.mqtt.as_ref()?.qos {
+                QoS::AtMostOnce => 0,
+                QoS::AtLeastOnce => 1,
+            }))
+        },
+    },
+    Key {
+        name: "mqtt.retain",
+        set: |s, v| {
+            mqtt(s).retain = boolean(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Boolean(s.server.mqtt.as_ref()?.retain)),
+    },
+    Key {
+        name: "mqtt.keep_alive",
+        set: |s, v| {
+            mqtt(s).keep_alive = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.mqtt.as_ref()?.keep_alive)),
+    },
+    Key {
+        name: "clickhouse",
+        set: |s, v| {
+            table(v)?;
+            clickhouse(s);
+            Ok(())
+        },
+        get: |s| s.server.clickhouse.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "clickhouse.url",
+        set: |s, v| {
+            clickhouse(s).url = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.clickhouse.as_ref()?.url.clone())),
+    },
+    Key {
+        name: "clickhouse.table",
+        set: |s, v| {
+            clickhouse(s).table = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.clickhouse.as_ref()?.table.clone())),
+    },
+    Key {
+        name: "clickhouse.batch_size",
+        set: |s, v| {
+            clickhouse(s).batch_size = positive(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(
+                s.server.clickhouse.as_ref()?.batch_size as i64,
+            ))
+        },
+    },
+    Key {
+        name: "clickhouse.flush_interval",
+        set: |s, v| {
+            clickhouse(s).flush_interval = duration(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(format_duration(
+                s.server.clickhouse.as_ref()?.flush_interval,
+            ))
+        },
+    },
+    Key {
+        name: "clickhouse.max_buffered_rows",
+        set: |s, v| {
+            clickhouse(s).max_buffered_rows = positive(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(
+                s.server.clickhouse.as_ref()?.max_buffered_rows as i64,
+            ))
+        },
+    },
+    Key {
+        name: "postgres",
+        set: |s, v| {
+            table(v)?;
+            postgres(s);
+            Ok(())
+        },
+        get: |s| s.server.postgres.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "postgres.url",
+        set: |s, v| {
+            postgres(s).url = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.postgres.as_ref()?.url.clone())),
+    },
+    Key {
+        name: "postgres.table",
+        set: |s, v| {
+            postgres(s).table = string(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::String(s.server.postgres.as_ref()?.table.clone())),
+    },
+    Key {
+        name: "postgres.batch_size",
+        set: |s, v| {
+            postgres(s).batch_size = positive(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(
+                s.server.postgres.as_ref()?.batch_size as i64,
+            ))
+        },
+    },
+    Key {
+        name: "postgres.flush_interval",
+        set: |s, v| {
+            postgres(s).flush_interval = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.postgres.as_ref()?.flush_interval)),
+    },
+    Key {
+        name: "postgres.max_buffered_rows",
+        set: |s, v| {
+            postgres(s).max_buffered_rows = positive(v)?;
+            Ok(())
+        },
+        get: |s| {
+            Some(Value::Integer(
+                s.server.postgres.as_ref()?.max_buffered_rows as i64,
+            ))
+        },
+    },
+    Key {
+        name: "postgres.migrate",
+        set: |s, v| {
+            postgres(s).migrate = boolean(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Boolean(s.server.postgres.as_ref()?.migrate)),
+    },
+    Key {
+        name: "postgres.hypertable",
+        set: |s, v| {
+            postgres(s).hypertable = boolean(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Boolean(s.server.postgres.as_ref()?.hypertable)),
+    },
+    Key {
+        name: "webhook",
+        set: |_, v| table(v),
+        get: |_| Some(Value::Table),
+    },
+    Key {
+        name: "webhook.secret",
+        set: |s, v| {
+            s.server.webhook.secret = Some(string(v)?);
+            Ok(())
+        },
+        get: |s| s.server.webhook.secret.clone().map(Value::String),
+    },
+    Key {
+        name: "webhook.max_attempts",
+        set: |s, v| {
+            s.server.webhook.max_attempts =
+                u32::try_from(positive(v)?).map_err(|_| expected("a u32", v))?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.webhook.max_attempts as i64)),
+    },
+    Key {
+        name: "webhook.failure_threshold",
+        set: |s, v| {
+            s.server.webhook.failure_threshold =
+                u32::try_from(positive(v)?).map_err(|_| expected("a u32", v))?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.webhook.failure_threshold as i64)),
+    },
+    Key {
+        name: "webhook.open_for",
+        set: |s, v| {
+            s.server.webhook.open_for = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.webhook.open_for)),
+    },
+    Key {
+        name: "webhook.queue_capacity",
+        set: |s, v| {
+            s.server.webhook.queue_capacity = positive(v)?;
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.webhook.queue_capacity as i64)),
+    },
+    Key {
+        name: "webhook.timeout",
+        set: |s, v| {
+            s.server.webhook.timeout = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.webhook.timeout)),
+    },
+    Key {
+        name: "synthetic",
+        set: |s, v| {
+            // `--synthetic` turns it on with `true`
+            if matches!(v, Value::Table) || boolean(v)? {
+                synthetic(s);
+            } else {
+                s.server.synthetic = None;
+            }
+            Ok(())
+        },
+        get: |s| s.server.synthetic.as_ref().map(|_| Value::Table),
+    },
+    Key {
+        name: "synthetic.seed",
+        set: |s, v| {
+            let seed = u64::try_from(integer(v)?).map_err(|_| "must not be negative")?;
+            synthetic(s).seed = Some(seed);
+            Ok(())
+        },
+        get: |s| Some(Value::Integer(s.server.synthetic.as_ref()?.seed? as i64)),
+    },
+    Key {
+        name: "synthetic.tick",
+        set: |s, v| {
+            synthetic(s).tick = duration(v)?;
+            Ok(())
+        },
+        get: |s| Some(format_duration(s.server.synthetic.as_ref()?.tick)),
+    },
+    Key {
+        name: "synthetic.volatility",
+        set: |s, v| {
+            synthetic(s).volatility = match float(v)? {
+                volatility if volatility > 0.0 => volatility,
+                _ => return Err("must be positive".into()),
+            };
+            Ok(())
+        },
+        get: |s| Some(Value::Float(s.server.synthetic.as_ref()?.volatility)),
+    },
+];
+
+// Redis options set so far, enabling the sink
+fn redis(settings: &mut Settings) -> &mut RedisSinkConfig {
+    settings
+        .server
+        .redis
+        .get_or_insert_with(RedisSinkConfig::default)
+}
+
+// Kafka options set so far, enabling the sink
+fn kafka(settings: &mut Settings) -> &mut KafkaSinkConfig {
+    settings
+        .server
+        .kafka
+        .get_or_insert_with(KafkaSinkConfig::default)
+}
+
+// MQTT options set so far, enabling the sink
+fn mqtt(settings: &mut Settings) -> &mut MqttSinkConfig {
+    settings
+        .server
+        .mqtt
+        .get_or_insert_with(MqttSinkConfig::default)
+}
+
+// ClickHouse options set so far, enabling the sink
+fn clickhouse(settings: &mut Settings) -> &mut ClickHouseSinkConfig {
+    settings
+        .server
+        .clickhouse
+        .get_or_insert_with(ClickHouseSinkConfig::default)
+}
+
+// PostgreSQL options set so far, enabling the sink
+fn postgres(settings: &mut Settings) -> &mut PostgresSinkConfig {
+    settings
+        .server
+        .postgres
+        .get_or_insert_with(PostgresSinkConfig::default)
+}
+
+// Synthetic options set so far, turning the mode on
+fn synthetic(settings: &mut Settings) -> &mut SyntheticConfig {
+    settings
+        .server
+        .synthetic
+        .get_or_insert_with(SyntheticConfig::default)
+}
+
+fn expected(what: &str, found: &Value) -> String {
+    match found {
+        Value::String(text) => format!("expected {}, found {:?}", what, text),
+        other => format!("expected {}, found {}", what, other.kind()),
+    }
+}
+
+fn table(value: &Value) -> Result<(), String> {
+    match value {
+        Value::Table => Ok(()),
+        other => Err(expected("a table", other)),
+    }
+}
+
+fn string(value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        other => Err(expected("a string", other)),
+    }
+}
+
+// A string naming a `T`, e.g. an address
+fn parse<T: std::str::FromStr>(value: &Value, what: &str) -> Result<T, String> {
+    match value {
+        Value::String(text) => text.parse().map_err(|_| expected(what, value)),
+        other => Err(expected(what, other)),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(integer) => Ok(*integer),
+        Value::String(text) => text.parse().map_err(|_| expected("an integer", value)),
+        other => Err(expected("an integer", other)),
+    }
+}
+
+fn positive(value: &Value) -> Result<usize, String> {
+    match integer(value)? {
+        integer if integer > 0 => Ok(integer as usize),
+        _ => Err("must be positive".into()),
+    }
+}
+
+fn float(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Float(float) => Ok(*float),
+        Value::Integer(integer) => Ok(*integer as f64),
+        Value::String(text) => text.parse().map_err(|_| expected("a number", value)),
+        other => Err(expected("a number", other)),
+    }
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(boolean) => Ok(*boolean),
+        Value::String(text) => text.parse().map_err(|_| expected("a boolean", value)),
+        other => Err(expected("a boolean", other)),
+    }
+}
+
+// Whole seconds, or a string with a unit: `250ms`, `30s`, `5m`, `23h`
+fn duration(value: &Value) -> Result<Duration, String> {
+    let what = "a duration like 30 or \"250ms\"";
+    let millis = match value {
+        Value::Integer(seconds) => u64::try_from(*seconds)
+            .ok()
+            .and_then(|seconds| seconds.checked_mul(1000)),
+        Value::String(text) => {
+            let digits = text.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+            let unit = match &text[digits.len()..] {
+                "ms" => Some(1),
+                "s" | "" => Some(1000),
+                "m" => Some(60_000),
+                "h" => Some(3_600_000),
+                _ => None,
+            };
+            digits
+                .parse::<u64>()
+                .ok()
+                .zip(unit)
+                .and_then(|(count, unit)| count.checked_mul(unit))
+        }
+        _ => None,
+    };
+    match millis {
+        Some(0) => Err("must be above zero".into()),
+        Some(millis) => Ok(Duration::from_millis(millis)),
+        None => Err(expected(what, value)),
+    }
+}
+
+// `"never"`, or a duration
+fn never_or_duration(value: &Value) -> Result<Option<Duration>, String> {
+    match value {
+        Value::String(text) if text == "never" => Ok(None),
+        _ => duration(value).map(Some),
+    }
+}
+
+// In the largest unit that keeps it whole
+fn format_duration(duration: Duration) -> Value {
+    let millis = duration.as_millis();
+    Value::String(match millis {
+        _ if millis.is_multiple_of(3_600_000) => format!("{}h", millis / 3_600_000),
+        _ if millis.is_multiple_of(60_000) => format!("{}m", millis / 60_000),
+        _ if millis.is_multiple_of(1000) => format!("{}s", millis / 1000),
+        _ => format!("{}ms", millis),
+    })
+}
+
+/// The subset of TOML settings need: `[table]` headers, dotted keys, and
+/// string, integer, float and boolean values. Arrays and inline tables are
+/// rejected rather than misread.
+mod toml {
+    use super::Value;
+    use std::collections::HashSet;
+
+    pub struct Entry {
+        // Full dotted key, including its table
+        pub key: String,
+        pub value: Value,
+        pub line: usize,
+    }
+
+    /// Entries in file order, with a `Value::Table` entry per header. Errors
+    /// carry their 1-based line.
+    pub fn parse(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+        let mut entries = Vec::new();
+        let mut seen = HashSet::new();
+        let mut table = String::new();
+        for (index, line) in text.lines().enumerate() {
+            let number = index + 1;
+            let mut cursor = Cursor { rest: line.trim() };
+            if cursor.rest.is_empty() || cursor.rest.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = if cursor.eat('[') {
+                if cursor.rest.starts_with('[') {
+                    return Err((number, "arrays of tables are not supported".into()));
+                }
+                table = cursor.key().map_err(|e| (number, e))?;
+                if !cursor.eat(']') {
+                    return Err((number, "expected `]`".into()));
+                }
+                (table.clone(), Value::Table)
+            } else {
+                let key = cursor.key().map_err(|e| (number, e))?;
+                if !cursor.eat('=') {
+                    return Err((number, format!("expected `=` after `{}`", key)));
+                }
+                let value = cursor.value().map_err(|e| (number, e))?;
+                if table.is_empty() {
+                    (key, value)
+                } else {
+                    (format!("{}.{}", table, key), value)
+                }
+            };
+            if !cursor.rest.is_empty() && !cursor.rest.starts_with('#') {
+                return Err((number, format!("unexpected `{}`", cursor.rest)));
+            }
+            if !seen.insert(key.clone()) {
+                return Err((number, format!("`{}` defined twice", key)));
+            }
+            entries.push(Entry {
+                key,
+                value,
+                line: number,
+            });
+        }
+        Ok(entries)
+    }
+
+    struct Cursor<'a> {
+        rest: &'a str,
+    }
+
+    impl Cursor<'_> {
+        // Consumes `c` and the whitespace after it if `c` is next
+        fn eat(&mut self, c: char) -> bool {
+            match self.rest.strip_prefix(c) {
+                Some(rest) => {
+                    self.rest = rest.trim_start();
+                    true
+                }
+                None => false,
+            }
+        }
+
+        // Bare or quoted parts joined by dots, e.g. `redis.url`
+        fn key(&mut self) -> Result<String, String> {
+            let mut parts = Vec::new();
+            loop {
+                let part = match self.rest.chars().next() {
+                    Some('"') | Some('\'') => self.string()?,
+                    _ => {
+                        let end = self
+                            .rest
+                            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+                            .unwrap_or(self.rest.len());
+                        if end == 0 {
+                            return Err("expected a key".into());
+                        }
+                        let (part, rest) = self.rest.split_at(end);
+                        self.rest = rest.trim_start();
+                        part.to_string()
+                    }
+                };
+                parts.push(part);
+                if !self.eat('.') {
+                    return Ok(parts.join("."));
+                }
+            }
+        }
+
+        fn value(&mut self) -> Result<Value, String> {
+            match self.rest.chars().next() {
+                Some('"') | Some('\'') => return self.string().map(Value::String),
+                Some('[') | Some('{') => {
+                    return Err("arrays and inline tables are not supported".into())
+                }
+                _ => {}
+            }
+            let end = self
+                .rest
+                .find(|c: char| c.is_whitespace() || c == '#')
+                .unwrap_or(self.rest.len());
+            let (token, rest) = self.rest.split_at(end);
+            self.rest = rest.trim_start();
+            match token {
+                "true" => return Ok(Value::Boolean(true)),
+                "false" => return Ok(Value::Boolean(false)),
+                _ => {}
+            }
+            let digits = token.replace('_', "");
+            if let Ok(integer) = digits.parse() {
+                return Ok(Value::Integer(integer));
+            }
+            let is_float = digits.contains(['.', 'e', 'E'])
+                && digits
+                    .trim_start_matches(['+', '-'])
+                    .starts_with(|c: char| c.is_ascii_digit());
+            match digits.parse() {
+                Ok(float) if is_float => Ok(Value::Float(float)),
+                _ => Err(format!("invalid value `{}`", token)),
+            }
+        }
+
+        // A basic `"..."` string with escapes, or a literal `'...'` one
+        fn string(&mut self) -> Result<String, String> {
+            let quote = self.rest.chars().next().unwrap();
+            let mut chars = self.rest[1..].char_indices();
+            let mut text = String::new();
+            while let Some((index, c)) = chars.next() {
+                if c == quote {
+                    self.rest = self.rest[index + 2..].trim_start();
+                    return Ok(text);
+                }
+                if c != '\\' || quote == '\'' {
+                    text.push(c);
+                    continue;
+                }
+                let escaped = match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('u') => {
+                        let hex: String = (0..4)
+                            .filter_map(|_| chars.next())
+                            .map(|(_, c)| c)
+                            .collect();
+                        u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .ok_or_else(|| format!("invalid escape `\\u{}`", hex))?
+                    }
+                    other => return Err(format!("invalid escape `\\{}`", other.unwrap_or(' '))),
+                };
+                text.push(escaped);
+            }
+            Err("unterminated string".into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Settings, Value};
+    use crate::candle::{Division, DivisionPolicy};
+    use crate::encoding::OutputEncoder;
+    use crate::kafka::PartitionKey;
+    use crate::server::SlowClientPolicy;
+    use crate::synthetic::SyntheticConfig;
+    use log::LevelFilter;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use tokio::time::Duration;
+
+    fn load(file: &str, flags: &[(&str, &str)], env: &[(&str, &str)]) -> Result<Settings, String> {
+        let flags: Vec<(String, String)> = flags
+            .iter()
+            .map(|(flag, value)| (flag.to_string(), value.to_string()))
+            .collect();
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Settings::from_layers(Some((Path::new("server.toml"), file)), &flags, |name| {
+            env.get(name).cloned()
+        })
+        .map_err(|e| e.to_string())
+    }
+
+    const FILE: &str = r#"
+# Local development
+listen = "0.0.0.0:9100"
+workers = 2
+slow_client_timeout = "90s"
+evaluator_linger = "500ms"
+compress_above = "never"
+drain_grace = "2m"
+max_subscription_ttl = "24h"
+max_subscriptions_per_client = 50
+memory_budget = 268435456
+history_len = 10080
+division_by_zero = "null_field"
+division_epsilon = 1e-9
+export_dir = "/var/lib/candles/exports"
+
+[backoff]
+max_delay = "5m"   # minutes
+jitter = 0.5
+
+[redis]
+url = 'redis://cache:6379/2'
+
+[kafka]
+brokers = "kafka-1:9092, kafka-2:9092"
+format = "cbor"
+
+[clickhouse]
+table = "market.candles"
+flush_interval = "5s"
+
+[postgres]
+url = "postgres://candles@timescale/market"
+migrate = true
+
+[webhook]
+secret = "hunter2"
+open_for = "5m"
+
+[synthetic]
+seed = 42
+tick = "250ms"
+"#;
+
+    #[test]
+    fn test_file_values_are_applied() {
+        let settings = load(FILE, &[], &[]).unwrap();
+        assert_eq!(settings.listen, ["0.0.0.0:9100".parse().unwrap()]);
+        assert_eq!(settings.workers, Some(2));
+        assert_eq!(
+            settings.server.slow_clients,
+            SlowClientPolicy::Disconnect {
+                after: Duration::from_secs(90)
+            }
+        );
+        assert_eq!(
+            settings.server.evaluator_linger,
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(settings.server.compress_above, None);
+        assert_eq!(settings.server.drain_grace, Duration::from_secs(120));
+        assert_eq!(settings.server.subscription_ttl, None);
+        assert_eq!(settings.server.max_subscriptions_per_client, Some(50));
+        assert_eq!(settings.server.memory_budget, Some(256 << 20));
+        assert_eq!(settings.server.history_len, 10_080);
+        assert_eq!(
+            settings.server.division,
+            Division {
+                policy: DivisionPolicy::NullField,
+                epsilon: 1e-9
+            }
+        );
+        assert_eq!(
+            settings.server.export_dir.as_deref(),
+            Some(Path::new("/var/lib/candles/exports"))
+        );
+        assert_eq!(
+            settings.server.max_subscription_ttl,
+            Some(Duration::from_secs(86_400))
+        );
+        assert_eq!(settings.server.backoff.max_delay, Duration::from_secs(300));
+        assert_eq!(settings.server.backoff.jitter, 0.5);
+        let redis = settings.server.redis.unwrap();
+        assert_eq!(redis.url, "redis://cache:6379/2");
+        assert_eq!(redis.channel_prefix, "candles:");
+        assert!(settings.server.mqtt.is_none());
+        let kafka = settings.server.kafka.unwrap();
+        assert_eq!(kafka.brokers, ["kafka-1:9092", "kafka-2:9092"]);
+        assert_eq!(
+            (kafka.topic.as_str(), kafka.partition_key, kafka.format),
+            ("candles", PartitionKey::Expression, OutputEncoder::Cbor)
+        );
+        let clickhouse = settings.server.clickhouse.unwrap();
+        assert_eq!(clickhouse.table, "market.candles");
+        assert_eq!(clickhouse.flush_interval, Duration::from_secs(5));
+        assert_eq!(clickhouse.batch_size, 1000);
+        let postgres = settings.server.postgres.unwrap();
+        assert_eq!(postgres.url, "postgres://candles@timescale/market");
+        assert!(postgres.migrate && postgres.hypertable);
+        assert_eq!(settings.server.webhook.secret.as_deref(), Some("hunter2"));
+        assert_eq!(settings.server.webhook.open_for, Duration::from_secs(300));
+        assert_eq!(settings.server.webhook.max_attempts, 3);
+        assert_eq!(
+            settings.server.synthetic,
+            Some(SyntheticConfig {
+                seed: Some(42),
+                tick: Duration::from_millis(250),
+                ..SyntheticConfig::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_flags_override_file_and_env_overrides_both() {
+        let settings = load(
+            FILE,
+            &[("--workers", "4"), ("--slow-client-timeout", "never")],
+            &[("CANDLE_WORKERS", "8"), ("CANDLE_MQTT_QOS", "1")],
+        )
+        .unwrap();
+        assert_eq!(settings.workers, Some(8));
+        assert_eq!(settings.server.slow_clients, SlowClientPolicy::KeepDropping);
+        assert!(settings.server.mqtt.is_some());
+
+        let settings = load(
+            "",
+            &[("--synthetic", "true"), ("--synthetic-volatility", "0.01")],
+            &[],
+        )
+        .unwrap();
+        let synthetic = settings.server.synthetic.unwrap();
+        assert_eq!((synthetic.seed, synthetic.volatility), (None, 0.01));
+
+        assert!(load("", &[], &[]).unwrap().server.upstream_compression);
+        let settings = load(
+            "upstream_compression = true",
+            &[("--no-upstream-compression", "false")],
+            &[],
+        )
+        .unwrap();
+        assert!(!settings.server.upstream_compression);
+    }
+
+    #[test]
+    fn test_errors_name_file_line_and_key() {
+        let error = |file: &str| load(file, &[], &[]).unwrap_err();
+        assert_eq!(
+            error("\n[redis]\nlatest_ttl = true"),
+            "Invalid configuration: server.toml:3: `redis.latest_ttl`: expected a duration \
+             like 30 or \"250ms\", found boolean"
+        );
+        assert_eq!(
+            error("workers = \"many\""),
+            "Invalid configuration: server.toml:1: `workers`: expected an integer, found \"many\""
+        );
+        assert_eq!(
+            error("workers = 0"),
+            "Invalid configuration: server.toml:1: `workers`: must be positive"
+        );
+        assert_eq!(
+            error("ping_interval = \"0s\""),
+            "Invalid configuration: server.toml:1: `ping_interval`: must be above zero"
+        );
+        assert_eq!(
+            error("listen = \"127.0.0.1:9000\"\nlisten_on = 1"),
+            "Invalid configuration: server.toml:2: unknown key `listen_on`"
+        );
+        assert_eq!(
+            error("workers = 2\nworkers = 3"),
+            "Invalid configuration: server.toml:2: `workers` defined twice"
+        );
+        assert_eq!(
+            error("upstream_url = \"wss://x"),
+            "Invalid configuration: server.toml:1: unterminated string"
+        );
+        assert_eq!(
+            error("runtime = \"current-thread\"\nworkers = 2"),
+            "Invalid configuration: `workers` requires runtime = \"multi-thread\""
+        );
+        assert_eq!(
+            load("", &[("--workers", "x")], &[]).unwrap_err(),
+            "Invalid configuration: --workers: expected an integer, found \"x\""
+        );
+        assert_eq!(
+            load("", &[], &[("CANDLE_MQTT_QOS", "2")]).unwrap_err(),
+            "Invalid configuration: CANDLE_MQTT_QOS: expected 0 or 1, found \"2\""
+        );
+        assert_eq!(
+            load("", &[("--kafka-brokers", " , ")], &[]).unwrap_err(),
+            "Invalid configuration: --kafka-brokers: expected brokers like \
+             \"kafka-1:9092, kafka-2:9092\", found \" , \""
+        );
+    }
+
+    #[test]
+    fn test_listen_addresses() {
+        let settings = load("", &[("--bind", "127.0.0.1:9000, [::1]:9001")], &[]).unwrap();
+        assert_eq!(
+            settings.listen,
+            [
+                "127.0.0.1:9000".parse().unwrap(),
+                "[::1]:9001".parse().unwrap()
+            ]
+        );
+        assert!(settings
+            .to_toml()
+            .starts_with("listen = \"127.0.0.1:9000, [::1]:9001\"\n"));
+        let settings = load(
+            "listen = \"\"\nlisten_unix = \"/run/candles.sock\"\nlisten_unix_mode = \"660\"",
+            &[],
+            &[],
+        )
+        .unwrap();
+        assert!(settings.listen.is_empty());
+        assert_eq!(settings.listen_unix_mode, Some(0o660));
+        assert!(settings.to_toml().starts_with(
+            "listen = \"\"\nlisten_unix = \"/run/candles.sock\"\nlisten_unix_mode = \"660\"\n"
+        ));
+        assert_eq!(
+            load("listen = \"\"", &[], &[]).unwrap_err(),
+            "Invalid configuration: `listen` is empty and `listen_unix` is unset"
+        );
+        assert_eq!(
+            load("listen = \"127.0.0.1:9000, ::1\"", &[], &[]).unwrap_err(),
+            "Invalid configuration: server.toml:1: `listen`: expected socket addresses like \
+             \"0.0.0.0:9000, [::]:9000\", found \"::1\""
+        );
+    }
+
+    #[test]
+    fn test_evaluator_shards() {
+        assert_eq!(load("", &[], &[]).unwrap().server.evaluator_shards, 0);
+        let settings = load("evaluator_shards = 4", &[], &[]).unwrap();
+        assert_eq!(settings.server.evaluator_shards, 4);
+        let settings = load("", &[("--evaluator-shards", "2")], &[]).unwrap();
+        assert_eq!(settings.server.evaluator_shards, 2);
+        assert!(load("evaluator_shards = -1", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(load("", &[], &[]).unwrap().log_level, LevelFilter::Info);
+        let settings = load("log_level = \"warn\"", &[("--log-level", "debug")], &[]).unwrap();
+        assert_eq!(settings.log_level, LevelFilter::Debug);
+        assert!(settings.to_toml().contains("\nlog_level = \"debug\"\n"));
+        let settings = load("", &[], &[("CANDLE_LOG_LEVEL", "off")]).unwrap();
+        assert_eq!(settings.log_level, LevelFilter::Off);
+        assert!(load("log_level = \"loud\"", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_grpc_address() {
+        assert_eq!(load("", &[], &[]).unwrap().grpc_addr, None);
+        let settings = load("", &[("--grpc-addr", "0.0.0.0:9001")], &[]).unwrap();
+        assert_eq!(settings.grpc_addr, Some("0.0.0.0:9001".parse().unwrap()));
+        assert!(settings
+            .to_toml()
+            .contains("\ngrpc_addr = \"0.0.0.0:9001\"\n"));
+        let settings = load("", &[], &[("CANDLE_GRPC_ADDR", "[::1]:9001")]).unwrap();
+        assert_eq!(settings.grpc_addr, Some("[::1]:9001".parse().unwrap()));
+        assert!(load("grpc_addr = \"9001\"", &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_printed_config_loads_back_unchanged() {
+        let settings = load(FILE, &[("--mqtt-retain", "true")], &[]).unwrap();
+        let printed = settings.to_toml();
+        assert!(printed.contains("\n[redis]\nurl = \"redis://cache:6379/2\"\n"));
+        assert!(printed.contains("\n[mqtt]\n"));
+        assert!(printed.contains("max_connection_age = \"23h\"\n"));
+        assert!(printed.contains("compress_above = \"never\"\n"));
+
+        let reloaded = load(&printed, &[], &[]).unwrap();
+        assert_eq!(reloaded.to_toml(), printed);
+    }
+
+    #[test]
+    fn test_toml_values() {
+        let values: Vec<Value> = super::toml::parse(
+            "a = \"tab\\there \\u00e9\"\nb = -1_000\nc = 2.5e3\nd = false\n\"e.f\" = 'C:\\x'",
+        )
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.value)
+        .collect();
+        assert_eq!(
+            values,
+            [
+                Value::String("tab\there é".into()),
+                Value::Integer(-1000),
+                Value::Float(2500.0),
+                Value::Boolean(false),
+                Value::String("C:\\x".into()),
+            ]
+        );
+        assert!(super::toml::parse("a = [1, 2]").is_err());
+        assert!(super::toml::parse("[[a]]").is_err());
+        assert!(super::toml::parse("a = 1 2").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::VecDeque;
+
+use crate::error::ServerError;
+use crate::utils::interval_to_millis;
+
+// Longest window a subscription may ask for, in bars
+pub const MAX_WINDOW: usize = 10_000;
+
+/// Pearson correlation of two legs' close-to-close returns over their last
+/// `window` closed bars, each a change over the previous close's magnitude
+/// so a fall reads as one when the leg is negative. Kept as running sums, so a bar updates it in O(1);
+/// they're summed afresh once per window so rounding doesn't build up. A
+/// bar that doesn't directly follow the last one starts the window over.
+pub struct RollingCorrelation {
+    interval: String,
+    window: usize,
+    // Returns of both legs in the window, oldest first
+    returns: VecDeque<(f64, f64)>,
+    sums: Sums,
+    // Returns added since the sums were last summed afresh
+    since_resum: usize,
+    // Start time and closes of the last bar
+    last: Option<(u64, f64, f64)>,
+}
+
+#[derive(Default)]
+struct Sums {
+    x: f64,
+    y: f64,
+    xx: f64,
+    yy: f64,
+    xy: f64,
+}
+
+impl Sums {
+    fn add(&mut self, (x, y): (f64, f64), sign: f64) {
+        self.x += sign * x;
+        self.y += sign * y;
+        self.xx += sign * x * x;
+        self.yy += sign * y * y;
+        self.xy += sign * x * y;
+    }
+}
+
+impl RollingCorrelation {
+    pub fn new(interval: &str, window: usize) -> Result<RollingCorrelation, ServerError> {
+        interval_to_millis(interval, 0)?;
+        if !(2..=MAX_WINDOW).contains(&window) {
+            return Err(ServerError::InvalidMessage(format!(
+                "correlation window must be 2 to {} bars",
+                MAX_WINDOW
+            )));
+        }
+        Ok(RollingCorrelation {
+            interval: interval.to_string(),
+            window,
+            returns: VecDeque::with_capacity(window),
+            sums: Sums::default(),
+            since_resum: 0,
+            last: None,
+        })
+    }
+
+    /// Forgets every bar, e.g. after a gap in the data.
+    pub fn reset(&mut self) {
+        self.returns.clear();
+        self.sums = Sums::default();
+        self.since_resum = 0;
+        self.last = None;
+    }
+
+    /// Folds in the closes `x` and `y` of the legs' closed bar at `t` and
+    /// returns the correlation, `None` while the window warms up. Bars at or
+    /// before the last one change nothing.
+    pub fn push(&mut self, t: u64, x: f64, y: f64) -> Option<f64> {
+        match self.last {
+            Some((last_t, _, _)) if t <= last_t => return self.value(),
+            Some((last_t, last_x, last_y)) => {
+                let next = last_t + interval_to_millis(&self.interval, last_t).unwrap_or(0);
+                if t != next || last_x == 0.0 || last_y == 0.0 {
+                    self.reset();
+                } else {
+                    self.add(((x - last_x) / last_x.abs(), (y - last_y) / last_y.abs()));
+                }
+            }
+            None => {}
+        }
+        self.last = Some((t, x, y));
+        self.value()
+    }
+
+    fn add(&mut self, returns: (f64, f64)) {
+        if self.returns.len() == self.window {
+            let oldest = self.returns.pop_front().unwrap();
+            self.sums.add(oldest, -1.0);
+        }
+        self.returns.push_back(returns);
+        self.sums.add(returns, 1.0);
+        self.since_resum += 1;
+        if self.since_resum == self.window {
+            self.sums = Sums::default();
+            for &returns in &self.returns {
+                self.sums.add(returns, 1.0);
+            }
+            self.since_resum = 0;
+        }
+    }
+
+    /// Correlation over a full window; `None` before one, or when either
+    /// leg's returns didn't vary over it.
+    pub fn value(&self) -> Option<f64> {
+        if self.returns.len() < self.window {
+            return None;
+        }
+        let n = self.window as f64;
+        let Sums { x, y, xx, yy, xy } = self.sums;
+        let var_x = n * xx - x * x;
+        let var_y = n * yy - y * y;
+        if var_x <= 0.0 || var_y <= 0.0 {
+            return None;
+        }
+        Some(((n * xy - x * y) / (var_x * var_y).sqrt()).clamp(-1.0, 1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RollingCorrelation;
+
+    const MINUTE: u64 = 60_000;
+
+    // Closes of two legs that mostly move together
+    const CLOSES: [(f64, f64); 12] = [
+        (100.0, 50.0),
+        (101.0, 50.4),
+        (100.5, 50.1),
+        (102.0, 51.2),
+        (103.5, 51.5),
+        (103.0, 51.6),
+        (101.8, 50.7),
+        (102.2, 51.3),
+        (104.0, 52.0),
+        (103.1, 51.2),
+        (103.9, 51.9),
+        (105.0, 52.1),
+    ];
+
+    // Correlation of the returns of `closes`, summed over all of them
+    fn offline(closes: &[(f64, f64)]) -> f64 {
+        let returns: Vec<(f64, f64)> = closes
+            .windows(2)
+            .map(|pair| {
+                (
+                    (pair[1].0 - pair[0].0) / pair[0].0.abs(),
+                    (pair[1].1 - pair[0].1) / pair[0].1.abs(),
+                )
+            })
+            .collect();
+        let n = returns.len() as f64;
+        let mean_x = returns.iter().map(|r| r.0).sum::<f64>() / n;
+        let mean_y = returns.iter().map(|r| r.1).sum::<f64>() / n;
+        let cov: f64 = returns
+            .iter()
+            .map(|r| (r.0 - mean_x) * (r.1 - mean_y))
+            .sum();
+        let var_x: f64 = returns.iter().map(|r| (r.0 - mean_x).powi(2)).sum();
+        let var_y: f64 = returns.iter().map(|r| (r.1 - mean_y).powi(2)).sum();
+        cov / (var_x * var_y).sqrt()
+    }
+
+    #[test]
+    fn test_matches_offline_computation() {
+        let window = 5;
+        let mut correlation = RollingCorrelation::new("1m", window).unwrap();
+        for (i, &(x, y)) in CLOSES.iter().enumerate() {
+            let value = correlation.push(i as u64 * MINUTE, x, y);
+            // A window of returns takes one bar more than it has returns
+            if i < window {
+                assert_eq!(value, None, "bar {}", i);
+                continue;
+            }
+            let expected = offline(&CLOSES[i - window..=i]);
+            assert!(
+                (value.unwrap() - expected).abs() < 1e-9,
+                "bar {}: {:?} against {}",
+                i,
+                value,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_legs_moving_alike_and_opposite() {
+        let mut alike = RollingCorrelation::new("1m", 3).unwrap();
+        let mut opposite = RollingCorrelation::new("1m", 3).unwrap();
+        for (i, x) in [10.0, 11.0, 10.5, 12.0].into_iter().enumerate() {
+            let t = i as u64 * MINUTE;
+            alike.push(t, x, x * 2.0);
+            opposite.push(t, x, 100.0 / x);
+        }
+        assert!((alike.value().unwrap() - 1.0).abs() < 1e-9);
+        assert!(opposite.value().unwrap() < -0.99);
+    }
+
+    #[test]
+    fn test_negative_leg_falls_as_its_mirror_rises() {
+        let mut mirrored = RollingCorrelation::new("1m", 3).unwrap();
+        let mut negative = RollingCorrelation::new("1m", 3).unwrap();
+        for (i, x) in [10.0, 11.0, 10.5, 12.0].into_iter().enumerate() {
+            let t = i as u64 * MINUTE;
+            mirrored.push(t, x, -x);
+            negative.push(t, -x, -x * 2.0);
+        }
+        assert!((mirrored.value().unwrap() + 1.0).abs() < 1e-9);
+        assert!((negative.value().unwrap() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gap_starts_the_window_over() {
+        let mut correlation = RollingCorrelation::new("1m", 3).unwrap();
+        for (i, &(x, y)) in CLOSES[..4].iter().enumerate() {
+            correlation.push(i as u64 * MINUTE, x, y);
+        }
+        assert!(correlation.value().is_some());
+
+        // Bar 5 is missing
+        assert_eq!(correlation.push(5 * MINUTE, 103.0, 51.6), None);
+        for (i, &(x, y)) in CLOSES[6..9].iter().enumerate() {
+            correlation.push((6 + i as u64) * MINUTE, x, y);
+        }
+        let expected = offline(&[(103.0, 51.6), CLOSES[6], CLOSES[7], CLOSES[8]]);
+        assert!((correlation.value().unwrap() - expected).abs() < 1e-9);
+
+        correlation.reset();
+        assert_eq!(correlation.value(), None);
+    }
+
+    #[test]
+    fn test_repeated_bar_changes_nothing() {
+        let mut correlation = RollingCorrelation::new("1m", 2).unwrap();
+        correlation.push(0, 10.0, 20.0);
+        correlation.push(MINUTE, 11.0, 21.0);
+        let value = correlation.push(2 * MINUTE, 10.0, 22.0);
+        assert!(value.is_some());
+        assert_eq!(correlation.push(2 * MINUTE, 50.0, 1.0), value);
+    }
+
+    #[test]
+    fn test_flat_leg_has_no_correlation() {
+        let mut correlation = RollingCorrelation::new("1m", 2).unwrap();
+        for (i, x) in [10.0, 11.0, 12.0].into_iter().enumerate() {
+            correlation.push(i as u64 * MINUTE, x, 5.0);
+        }
+        assert_eq!(correlation.value(), None);
+    }
+
+    #[test]
+    fn test_invalid_window() {
+        assert!(RollingCorrelation::new("1m", 1).is_err());
+        assert!(RollingCorrelation::new("1m", super::MAX_WINDOW + 1).is_err());
+        assert!(RollingCorrelation::new("5x", 10).is_err());
+    }
+}
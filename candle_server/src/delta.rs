@@ -0,0 +1,156 @@
+use serde_json::{Map, Value};
+
+/// Cuts the results of a `delta` subscription down to what changed. The
+/// first result for a bar carries all of its data; later updates of the same
+/// bar carry `t`, the fields whose value changed since the result before,
+/// and `"delta": true`. A field that is no longer written comes as `null`.
+/// Fields are compared as written, after rounding, so merging each delta
+/// into the data before it gives exactly what a full result would have.
+/// Closed and partial bars always carry all of their data.
+#[derive(Debug, Default)]
+pub struct Delta {
+    // Data of the last result sent, in full
+    sent: Map<String, Value>,
+}
+
+impl Delta {
+    pub fn new() -> Delta {
+        Delta::default()
+    }
+
+    /// Replaces the `data` of `message`, a result as written, with its
+    /// changes when it updates the same bar as the last one. `full` results
+    /// are left whole.
+    pub fn apply(&mut self, message: &mut Value, full: bool) {
+        let Some(Value::Object(data)) = message.get_mut("data") else {
+            return;
+        };
+        if full || data.get("t") != self.sent.get("t") {
+            self.sent.clone_from(data);
+            return;
+        }
+
+        let mut changes = Map::new();
+        for (field, value) in data.iter() {
+            if field == "t" || self.sent.get(field) != Some(value) {
+                changes.insert(field.clone(), value.clone());
+            }
+        }
+        for field in self.sent.keys() {
+            if !data.contains_key(field) {
+                changes.insert(field.clone(), Value::Null);
+            }
+        }
+        self.sent = std::mem::replace(data, changes);
+        message["delta"] = Value::Bool(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::Delta;
+    use crate::candle::Candle;
+    use crate::protocol::{ResultData, ResultMessage, ServerMessage};
+
+    fn result(t: u64, c: f64, closed: bool) -> Value {
+        let data = ResultData::from(Candle::new(t, 10.0, c, c.max(10.0), c.min(10.0)));
+        serde_json::to_value(ServerMessage::Result(ResultMessage {
+            id: None,
+            stream: "btcusdt@1m".to_string(),
+            alias: Some("btc".to_string()),
+            data,
+            closed,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+        }))
+        .unwrap()
+    }
+
+    // `data` merged into `state` the way a client would
+    fn merge(state: &mut Value, message: &Value) {
+        if message.get("delta").is_none() {
+            *state = message["data"].clone();
+            return;
+        }
+        for (field, value) in message["data"].as_object().unwrap() {
+            match value {
+                Value::Null => state.as_object_mut().unwrap().remove(field),
+                value => state
+                    .as_object_mut()
+                    .unwrap()
+                    .insert(field.clone(), value.clone()),
+            };
+        }
+    }
+
+    #[test]
+    fn test_merged_deltas_match_full_results() {
+        let series = [
+            result(0, 10.0, false),
+            result(0, 11.0, false),
+            result(0, 11.0, false),
+            result(0, 9.5, false),
+            result(0, 10.0, true),
+            result(60_000, 10.0, false),
+            result(60_000, 12.0, false),
+        ];
+        let mut delta = Delta::new();
+        let mut state = Value::Null;
+        for full in &series {
+            let mut message = full.clone();
+            delta.apply(&mut message, full["closed"] == true);
+            merge(&mut state, &message);
+            assert_eq!(state, full["data"]);
+        }
+    }
+
+    #[test]
+    fn test_update_carries_only_changes() {
+        let mut delta = Delta::new();
+        let mut first = result(0, 10.0, false);
+        delta.apply(&mut first, false);
+        assert_eq!(first, result(0, 10.0, false));
+
+        let mut update = result(0, 11.0, false);
+        delta.apply(&mut update, false);
+        assert_eq!(update["delta"], true);
+        assert_eq!(update["alias"], "btc");
+        assert_eq!(
+            update["data"],
+            json!({"t": 0, "c": 11.0, "h": 11.0, "dir": "up"})
+        );
+
+        let mut same = result(0, 11.0, false);
+        delta.apply(&mut same, false);
+        assert_eq!(same["data"], json!({"t": 0}));
+    }
+
+    #[test]
+    fn test_closed_and_new_bars_are_full() {
+        let mut delta = Delta::new();
+        delta.apply(&mut result(0, 10.0, false), false);
+        let mut closed = result(0, 10.5, true);
+        delta.apply(&mut closed, true);
+        assert_eq!(closed, result(0, 10.5, true));
+
+        let mut next = result(60_000, 10.5, false);
+        delta.apply(&mut next, false);
+        assert!(next.get("delta").is_none());
+        assert_eq!(next["data"]["o"], 10.0);
+    }
+
+    #[test]
+    fn test_fields_no_longer_written_are_null() {
+        let mut delta = Delta::new();
+        delta.apply(&mut result(0, 10.0, false), false);
+        let mut missing = result(0, 10.0, false);
+        missing["data"].as_object_mut().unwrap().remove("dir");
+        delta.apply(&mut missing, false);
+        assert_eq!(missing["data"], json!({"t": 0, "dir": null}));
+    }
+}
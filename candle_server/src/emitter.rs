@@ -0,0 +1,437 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::encoding::OutputEncoder;
+use crate::error::ServerError;
+use crate::protobuf;
+use crate::protocol::{ServerMessage, StatusMessage};
+
+/// Protocol versions the server speaks, oldest first.
+pub const VERSIONS: [u32; 3] = [1, 2, 3];
+
+/// Shape of the frames a request gets, by the `version` it asked for.
+/// Version 1 frames are exactly what clients got before versions existed,
+/// without `v`. Version 2 frames carry `"v": 2` and a `type` naming the
+/// kind of frame, `result`, `status` or `ticker`, ahead of the same fields.
+/// Version 3 frames are `{"v": 3, "type", "stream", "id", "data"}`, typed
+/// `candle`, `ticker`, `ack`, `error`, `alert` or `status`, with every
+/// other field in `data`; a result's candle and a ticker's statistics are
+/// lifted into it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Version {
+    #[default]
+    V1,
+    V2,
+    V3,
+}
+
+impl Version {
+    /// Version numbered `version`, 1 when a request names none.
+    pub fn new(version: Option<u32>) -> Result<Version, ServerError> {
+        match version.unwrap_or(1) {
+            1 => Ok(Version::V1),
+            2 => Ok(Version::V2),
+            3 => Ok(Version::V3),
+            other => Err(ServerError::UnsupportedVersion(other)),
+        }
+    }
+
+    pub fn number(&self) -> u32 {
+        match self {
+            Version::V1 => 1,
+            Version::V2 => 2,
+            Version::V3 => 3,
+        }
+    }
+}
+
+/// Writes the frames of one version in one encoding; every frame the server
+/// sends a client about its requests goes through here.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Emitter {
+    pub encoder: OutputEncoder,
+    pub version: Version,
+}
+
+// A version 2 frame: its tags, then the message's own fields
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
+// A version 3 frame: what it is and what it's about, then the rest
+#[derive(Serialize)]
+struct Multiplexed {
+    v: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    stream: Value,
+    id: Value,
+    data: Map<String, Value>,
+}
+
+/// Fields of a result besides its candle, kept next to the candle's in the
+/// `data` of a version 3 frame.
+pub const RESULT_FLAGS: [&str; 8] = [
+    "alias",
+    "closed",
+    "out_of_order",
+    "partial",
+    "missing",
+    "snapshot",
+    "latency_ms",
+    "delta",
+];
+
+// Statuses acknowledging a request that changed something
+const ACKS: [&str; 6] = [
+    "subscribed",
+    "defined",
+    "renewed",
+    "exporting",
+    "alert_set",
+    "alert_deleted",
+];
+
+fn kind(message: &ServerMessage) -> &'static str {
+    match message {
+        ServerMessage::Result(_) => "result",
+        ServerMessage::Status(_) => "status",
+        ServerMessage::Ticker(_) => "ticker",
+    }
+}
+
+// `message`, of version 2 `kind`, as a version 3 frame
+fn multiplexed<T: Serialize>(message: &T, kind: &'static str) -> Result<Multiplexed, ServerError> {
+    let mut data: Map<String, Value> = serde_json::from_value(serde_json::to_value(message)?)?;
+    let stream = data.remove("stream").unwrap_or_default();
+    let id = data.remove("id").unwrap_or_default();
+    let (kind, nested) = match kind {
+        "result" => ("candle", Some("data")),
+        "ticker" => ("ticker", Some("ticker")),
+        _ => match data.get("event").and_then(Value::as_str) {
+            Some("error") => ("error", None),
+            Some("alert") => ("alert", None),
+            Some(event) if ACKS.contains(&event) => ("ack", None),
+            _ => ("status", None),
+        },
+    };
+    if let Some(Value::Object(fields)) = nested.and_then(|nested| data.remove(nested)) {
+        data.extend(fields);
+    }
+    Ok(Multiplexed {
+        v: Version::V3.number(),
+        kind,
+        stream,
+        id,
+        data,
+    })
+}
+
+/// A version 3 frame back in the version 1 shape, for clients reading it as
+/// a `ServerMessage`; any other frame is returned as it is.
+pub fn untagged(frame: Value) -> Value {
+    let Value::Object(mut frame) = frame else {
+        return frame;
+    };
+    if frame.get("v") != Some(&Value::from(Version::V3.number())) {
+        return Value::Object(frame);
+    }
+    let Some(Value::Object(mut data)) = frame.remove("data") else {
+        return Value::Object(frame);
+    };
+    let mut message = Map::new();
+    if let Some(stream) = frame.remove("stream") {
+        message.insert("stream".into(), stream);
+    }
+    if let Some(id) = frame.remove("id").filter(|id| !id.is_null()) {
+        message.insert("id".into(), id);
+    }
+    let nested = match frame.get("type").and_then(Value::as_str) {
+        Some("candle") => "data",
+        Some("ticker") => "ticker",
+        _ => {
+            message.extend(data);
+            return Value::Object(message);
+        }
+    };
+    for flag in RESULT_FLAGS {
+        if let Some(value) = data.remove(flag) {
+            message.insert(flag.into(), value);
+        }
+    }
+    message.insert(nested.into(), Value::Object(data));
+    Value::Object(message)
+}
+
+impl Emitter {
+    pub fn new(encoder: OutputEncoder, version: Version) -> Emitter {
+        Emitter { encoder, version }
+    }
+
+    /// Appends `message` to `buffer` as a frame of this version.
+    pub fn encode(&self, message: &ServerMessage, buffer: &mut Vec<u8>) -> Result<(), ServerError> {
+        match self.encoder {
+            // Typed by the `ServerFrame` member they're written as, in any
+            // version
+            OutputEncoder::Protobuf => protobuf::write_message(message, buffer),
+            _ => self.tagged(message, kind(message), buffer),
+        }
+    }
+
+    pub fn encode_status(
+        &self,
+        status: &StatusMessage,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ServerError> {
+        match self.encoder {
+            OutputEncoder::Protobuf => protobuf::write_status(status, buffer),
+            _ => self.tagged(status, "status", buffer),
+        }
+    }
+
+    /// Like `encode`, with `edit` applied to the message's JSON tree before
+    /// it's tagged; protobuf frames have none, so they fail.
+    pub fn encode_edited(
+        &self,
+        message: &ServerMessage,
+        edit: impl FnOnce(&mut Value),
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ServerError> {
+        let mut value = serde_json::to_value(message)?;
+        edit(&mut value);
+        self.tagged(&value, kind(message), buffer)
+    }
+
+    fn tagged<T: Serialize>(
+        &self,
+        message: &T,
+        kind: &'static str,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ServerError> {
+        match self.version {
+            Version::V1 => self.encoder.encode(message, buffer),
+            Version::V2 => self.encoder.encode(
+                &Envelope {
+                    v: self.version.number(),
+                    kind,
+                    message,
+                },
+                buffer,
+            ),
+            Version::V3 => self.encoder.encode(&multiplexed(message, kind)?, buffer),
+        }
+    }
+
+    /// Frame carrying one message produced by `encode`.
+    pub fn frame(&self, encoded: &[u8]) -> Message {
+        self.encoder.frame(encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{untagged, Emitter, Version};
+    use crate::candle::Candle;
+    use crate::encoding::OutputEncoder;
+    use crate::error::{ErrorCode, ServerError};
+    use crate::protocol::{
+        ResultData, ResultMessage, ServerMessage, StatusMessage, TickerMessage, TickerStats,
+    };
+
+    fn result() -> ServerMessage {
+        let candle = Candle::new(60_000, 1.5, 2.0, 2.5, 1.0)
+            .with_volume(3.0, 6.0)
+            .with_trades(4)
+            .with_closed(true);
+        ServerMessage::Result(ResultMessage {
+            id: None,
+            stream: "btcusdt@1m".into(),
+            alias: Some("btc".into()),
+            data: ResultData::from(candle),
+            closed: true,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+        })
+    }
+
+    fn status() -> ServerMessage {
+        ServerMessage::Status(StatusMessage {
+            id: Some(7),
+            ..StatusMessage::error(
+                "btcusdt@1x".into(),
+                &ServerError::InvalidInterval("1x".into()),
+            )
+        })
+    }
+
+    fn ticker() -> ServerMessage {
+        ServerMessage::Ticker(TickerMessage {
+            stream: "btcusdt@ticker24h".into(),
+            alias: None,
+            ticker: TickerStats {
+                t: 1,
+                last_price: 7.0,
+                ..TickerStats::default()
+            },
+        })
+    }
+
+    fn event(id: Option<u32>, event: &str, message: &str) -> ServerMessage {
+        ServerMessage::Status(StatusMessage {
+            id,
+            stream: "btcusdt@1m".into(),
+            event: event.into(),
+            message: message.into(),
+            ..StatusMessage::default()
+        })
+    }
+
+    fn emitted(version: Version, message: &ServerMessage) -> String {
+        let mut buffer = Vec::new();
+        Emitter::new(OutputEncoder::Json, version)
+            .encode(message, &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn test_version_1_shapes() {
+        assert_eq!(
+            emitted(Version::V1, &result()),
+            r#"{"stream":"btcusdt@1m","alias":"btc","data":{"t":60000,"o":1.5,"c":2.0,"h":2.5,"l":1.0,"v":3.0,"q":6.0,"n":4,"V":0.0,"Q":0.0,"dir":"up"},"closed":true}"#
+        );
+        assert_eq!(
+            emitted(Version::V1, &status()),
+            format!(
+                r#"{{"id":7,"stream":"btcusdt@1x","event":"error","message":"Unknown interval 1x","code":{}}}"#,
+                ErrorCode::InvalidInterval.value()
+            )
+        );
+        assert_eq!(
+            emitted(Version::V1, &ticker()),
+            r#"{"stream":"btcusdt@ticker24h","ticker":{"t":1,"last_price":7.0,"price_change":0.0,"price_change_pct":0.0,"weighted_avg_price":0.0,"open":0.0,"high":0.0,"low":0.0,"volume":0.0,"quote_volume":0.0,"trades":0}}"#
+        );
+    }
+
+    #[test]
+    fn test_version_2_shapes() {
+        assert_eq!(
+            emitted(Version::V2, &result()),
+            r#"{"v":2,"type":"result","stream":"btcusdt@1m","alias":"btc","data":{"t":60000,"o":1.5,"c":2.0,"h":2.5,"l":1.0,"v":3.0,"q":6.0,"n":4,"V":0.0,"Q":0.0,"dir":"up"},"closed":true}"#
+        );
+        assert_eq!(
+            emitted(Version::V2, &status()),
+            format!(
+                r#"{{"v":2,"type":"status","id":7,"stream":"btcusdt@1x","event":"error","message":"Unknown interval 1x","code":{}}}"#,
+                ErrorCode::InvalidInterval.value()
+            )
+        );
+        assert!(emitted(Version::V2, &ticker()).starts_with(
+            r#"{"v":2,"type":"ticker","stream":"btcusdt@ticker24h","ticker":{"t":1,"#
+        ));
+    }
+
+    #[test]
+    fn test_version_3_shapes() {
+        assert_eq!(
+            emitted(Version::V3, &result()),
+            r#"{"v":3,"type":"candle","stream":"btcusdt@1m","id":null,"data":{"Q":0.0,"V":0.0,"alias":"btc","c":2.0,"closed":true,"dir":"up","h":2.5,"l":1.0,"n":4,"o":1.5,"q":6.0,"t":60000,"v":3.0}}"#
+        );
+        assert_eq!(
+            emitted(Version::V3, &status()),
+            format!(
+                r#"{{"v":3,"type":"error","stream":"btcusdt@1x","id":7,"data":{{"code":{},"event":"error","message":"Unknown interval 1x"}}}}"#,
+                ErrorCode::InvalidInterval.value()
+            )
+        );
+        assert_eq!(
+            emitted(Version::V3, &event(Some(1), "subscribed", "Subscribed")),
+            r#"{"v":3,"type":"ack","stream":"btcusdt@1m","id":1,"data":{"event":"subscribed","message":"Subscribed"}}"#
+        );
+        assert_eq!(
+            emitted(
+                Version::V3,
+                &event(Some(6), "alert", "h crossed above 50000")
+            ),
+            r#"{"v":3,"type":"alert","stream":"btcusdt@1m","id":6,"data":{"event":"alert","message":"h crossed above 50000"}}"#
+        );
+        assert_eq!(
+            emitted(Version::V3, &event(None, "stale", "No klines for 60s")),
+            r#"{"v":3,"type":"status","stream":"btcusdt@1m","id":null,"data":{"event":"stale","message":"No klines for 60s"}}"#
+        );
+        assert_eq!(
+            emitted(Version::V3, &ticker()),
+            r#"{"v":3,"type":"ticker","stream":"btcusdt@ticker24h","id":null,"data":{"high":0.0,"last_price":7.0,"low":0.0,"open":0.0,"price_change":0.0,"price_change_pct":0.0,"quote_volume":0.0,"t":1,"trades":0,"volume":0.0,"weighted_avg_price":0.0}}"#
+        );
+    }
+
+    #[test]
+    fn test_untagged_reads_back_version_1() {
+        for message in [result(), status(), ticker(), event(None, "stale", "")] {
+            let frame: Value = serde_json::from_str(&emitted(Version::V3, &message)).unwrap();
+            let plain: Value = serde_json::from_str(&emitted(Version::V1, &message)).unwrap();
+            assert_eq!(untagged(frame), plain);
+            assert_eq!(untagged(plain.clone()), plain);
+        }
+    }
+
+    #[test]
+    fn test_edited_messages_are_tagged_too() {
+        let mut buffer = Vec::new();
+        Emitter::new(OutputEncoder::Json, Version::V2)
+            .encode_edited(
+                &result(),
+                |value| value["data"] = serde_json::json!({"t": 60000}),
+                &mut buffer,
+            )
+            .unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(value["v"], 2);
+        assert_eq!(value["type"], "result");
+        assert_eq!(value["data"], serde_json::json!({"t": 60000}));
+
+        // Flags a delta adds sit next to the candle's changes
+        buffer.clear();
+        Emitter::new(OutputEncoder::Json, Version::V3)
+            .encode_edited(
+                &result(),
+                |value| {
+                    value["data"] = json!({"t": 60000, "c": 2.5});
+                    value["delta"] = json!(true);
+                },
+                &mut buffer,
+            )
+            .unwrap();
+        let value: Value = serde_json::from_slice(&buffer).unwrap();
+        assert_eq!(value["type"], "candle");
+        assert_eq!(
+            value["data"],
+            json!({"t": 60000, "c": 2.5, "alias": "btc", "closed": true, "delta": true})
+        );
+    }
+
+    #[test]
+    fn test_versions() {
+        assert_eq!(Version::new(None).unwrap(), Version::V1);
+        assert_eq!(Version::new(Some(2)).unwrap().number(), 2);
+        assert_eq!(Version::new(Some(3)).unwrap(), Version::V3);
+        let unknown = Version::new(Some(4)).unwrap_err();
+        assert_eq!(
+            unknown.to_string(),
+            "Unsupported protocol version 4, supported versions are 1, 2, 3"
+        );
+        assert_eq!(unknown.code(), ErrorCode::ParseError);
+        assert!(Version::new(Some(0)).is_err());
+    }
+}
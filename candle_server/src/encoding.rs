@@ -0,0 +1,558 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::error::ServerError;
+use crate::gzip;
+
+/// Wire encoding of the frames sent for one subscription.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputEncoder {
+    // Text frames
+    #[default]
+    Json,
+    // Binary frames, RFC 8949
+    Cbor,
+    // Binary frames of length-delimited messages of `proto/candles.proto`
+    Protobuf,
+}
+
+/// Every encoding, in the order the server prefers them.
+pub const ENCODERS: [OutputEncoder; 3] = [
+    OutputEncoder::Json,
+    OutputEncoder::Cbor,
+    OutputEncoder::Protobuf,
+];
+
+impl OutputEncoder {
+    /// WebSocket subprotocol a client offers to get every frame of its
+    /// connection in this encoding.
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            OutputEncoder::Json => "candles.json",
+            OutputEncoder::Cbor => "candles.cbor",
+            OutputEncoder::Protobuf => "candles.protobuf",
+        }
+    }
+
+    /// First encoding with a subprotocol among `offered`, the comma
+    /// separated `Sec-WebSocket-Protocol` values in the client's order of
+    /// preference.
+    pub fn negotiate<'a>(offered: impl IntoIterator<Item = &'a str>) -> Option<OutputEncoder> {
+        offered
+            .into_iter()
+            .flat_map(|value| value.split(','))
+            .find_map(|name| {
+                ENCODERS
+                    .into_iter()
+                    .find(|encoder| encoder.subprotocol() == name.trim())
+            })
+    }
+
+    /// Appends `message` to `buffer` in this encoding. Protobuf messages
+    /// are typed, so `Emitter` writes those instead.
+    pub fn encode<T: Serialize>(
+        &self,
+        message: &T,
+        buffer: &mut Vec<u8>,
+    ) -> Result<(), ServerError> {
+        match self {
+            OutputEncoder::Json => serde_json::to_writer(buffer, message)?,
+            // Encoded from the JSON tree, so both encodings carry the same
+            // fields with the same presentation
+            OutputEncoder::Cbor => cbor::write_value(&serde_json::to_value(message)?, buffer),
+            OutputEncoder::Protobuf => {
+                return Err(ServerError::InvalidMessage(
+                    "protobuf frames carry server messages only".into(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Frame carrying one message produced by `encode`.
+    pub fn frame(&self, encoded: &[u8]) -> Message {
+        match self {
+            OutputEncoder::Json => Message::Text(String::from_utf8_lossy(encoded).into_owned()),
+            OutputEncoder::Cbor | OutputEncoder::Protobuf => Message::Binary(encoded.to_vec()),
+        }
+    }
+}
+
+/// Messages collected to go out as a single array frame.
+pub struct Batch {
+    encoder: OutputEncoder,
+    items: Vec<u8>,
+    len: usize,
+}
+
+impl Batch {
+    pub fn new(encoder: OutputEncoder) -> Batch {
+        Batch {
+            encoder,
+            items: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds one message produced by `encode`.
+    pub fn push(&mut self, encoded: &[u8]) {
+        if self.encoder == OutputEncoder::Json && !self.is_empty() {
+            self.items.push(b',');
+        }
+        self.items.extend_from_slice(encoded);
+        self.len += 1;
+    }
+
+    /// Frame holding every message added since the last one, in order.
+    pub fn take_frame(&mut self) -> Message {
+        let len = std::mem::take(&mut self.len);
+        let items = std::mem::take(&mut self.items);
+        match self.encoder {
+            OutputEncoder::Json => {
+                let mut text = String::with_capacity(items.len() + 2);
+                text.push('[');
+                text.push_str(&String::from_utf8_lossy(&items));
+                text.push(']');
+                Message::Text(text)
+            }
+            OutputEncoder::Cbor => {
+                let mut bytes = Vec::with_capacity(items.len() + 9);
+                cbor::write_head(cbor::ARRAY, len as u64, &mut bytes);
+                bytes.extend_from_slice(&items);
+                Message::Binary(bytes)
+            }
+            // Length-delimited messages just follow each other
+            OutputEncoder::Protobuf => Message::Binary(items),
+        }
+    }
+}
+
+/// First line of a compressed frame, followed by the gzip stream of the
+/// frame's payload. Compressed frames are binary whatever the encoding;
+/// neither a JSON text frame, a CBOR map nor a protobuf `ServerFrame`
+/// starts like this.
+pub const GZIP_ENVELOPE: &[u8] = b"{\"encoding\":\"gzip\"}\n";
+
+/// Compresses the frames of one client of at least `above` bytes; smaller
+/// ones aren't worth the CPU and go out as they are.
+#[derive(Debug, Clone)]
+pub struct FrameCompression {
+    pub above: usize,
+    pub counters: Arc<CompressionCounters>,
+}
+
+impl FrameCompression {
+    /// `message` as a gzip envelope if it's large enough.
+    pub fn apply(&self, message: Message) -> Message {
+        let payload = match &message {
+            Message::Text(text) => text.as_bytes(),
+            Message::Binary(bytes) => bytes.as_slice(),
+            _ => return message,
+        };
+        if payload.len() < self.above {
+            return message;
+        }
+        let mut envelope = GZIP_ENVELOPE.to_vec();
+        gzip::compress(payload, &mut envelope);
+        self.counters.record(payload.len(), envelope.len());
+        Message::Binary(envelope)
+    }
+}
+
+/// Payload of a frame sent as a gzip envelope; `None` for any other frame.
+pub fn open_envelope(frame: &[u8]) -> Option<Result<Vec<u8>, ServerError>> {
+    frame.strip_prefix(GZIP_ENVELOPE).map(gzip::decompress)
+}
+
+/// Frames compressed since the server started, counted without locks.
+#[derive(Debug, Default)]
+pub struct CompressionCounters {
+    frames: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    pub frames: u64,
+    // Payload bytes before compression and envelope bytes sent for them
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl CompressionCounters {
+    pub fn record(&self, bytes_in: usize, bytes_out: usize) {
+        self.frames.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes_in as u64, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(bytes_out as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            frames: self.frames.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl CompressionStats {
+    /// Payload bytes per byte sent, `None` before the first frame.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.bytes_out > 0).then(|| self.bytes_in as f64 / self.bytes_out as f64)
+    }
+}
+
+// The subset of CBOR a JSON tree needs: definite-length items only
+mod cbor {
+    use serde_json::Value;
+
+    pub const UNSIGNED: u8 = 0;
+    pub const NEGATIVE: u8 = 1;
+    pub const TEXT: u8 = 3;
+    pub const ARRAY: u8 = 4;
+    pub const MAP: u8 = 5;
+
+    const FALSE: u8 = 0xf4;
+    const TRUE: u8 = 0xf5;
+    const NULL: u8 = 0xf6;
+    const FLOAT64: u8 = 0xfb;
+
+    /// Major type and argument, in the shortest form that holds it.
+    pub fn write_head(major: u8, argument: u64, out: &mut Vec<u8>) {
+        let major = major << 5;
+        match argument {
+            0..=23 => out.push(major | argument as u8),
+            24..=0xff => out.extend_from_slice(&[major | 24, argument as u8]),
+            0x100..=0xffff => {
+                out.push(major | 25);
+                out.extend_from_slice(&(argument as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(major | 26);
+                out.extend_from_slice(&(argument as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(major | 27);
+                out.extend_from_slice(&argument.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn write_value(value: &Value, out: &mut Vec<u8>) {
+        match value {
+            Value::Null => out.push(NULL),
+            Value::Bool(false) => out.push(FALSE),
+            Value::Bool(true) => out.push(TRUE),
+            Value::Number(number) => {
+                if let Some(unsigned) = number.as_u64() {
+                    write_head(UNSIGNED, unsigned, out);
+                } else if let Some(signed) = number.as_i64() {
+                    // -1 - n for n >= 0
+                    write_head(NEGATIVE, !(signed as u64), out);
+                } else {
+                    out.push(FLOAT64);
+                    out.extend_from_slice(&number.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+                }
+            }
+            Value::String(text) => {
+                write_head(TEXT, text.len() as u64, out);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Value::Array(items) => {
+                write_head(ARRAY, items.len() as u64, out);
+                for item in items {
+                    write_value(item, out);
+                }
+            }
+            Value::Object(fields) => {
+                write_head(MAP, fields.len() as u64, out);
+                for (key, field) in fields {
+                    write_head(TEXT, key.len() as u64, out);
+                    out.extend_from_slice(key.as_bytes());
+                    write_value(field, out);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_envelope, Batch, CompressionCounters, FrameCompression, OutputEncoder};
+    use crate::candle::Candle;
+    use crate::protobuf;
+    use crate::protocol::{OutputFormat, ResultData, ResultMessage, ServerMessage, StatusMessage};
+    use serde::Serialize;
+    use serde_json::{json, Map, Value};
+    use tokio_tungstenite::tungstenite::Message;
+
+    // Reads back what `cbor::write_value` produces
+    fn decode(bytes: &mut &[u8]) -> Value {
+        let (head, rest) = bytes.split_first().unwrap();
+        *bytes = rest;
+        let (major, info) = (head >> 5, head & 0x1f);
+        let argument = |bytes: &mut &[u8]| -> u64 {
+            let width = match info {
+                0..=23 => return info as u64,
+                24..=27 => 1 << (info - 24),
+                _ => panic!("unexpected additional info {}", info),
+            };
+            let (value, rest) = bytes.split_at(width);
+            *bytes = rest;
+            value.iter().fold(0, |acc, byte| acc << 8 | *byte as u64)
+        };
+        match (major, *head) {
+            (7, 0xf4) => json!(false),
+            (7, 0xf5) => json!(true),
+            (7, 0xf6) => Value::Null,
+            (7, 0xfb) => {
+                let (value, rest) = bytes.split_at(8);
+                *bytes = rest;
+                json!(f64::from_be_bytes(value.try_into().unwrap()))
+            }
+            (0, _) => json!(argument(bytes)),
+            (1, _) => json!(-1 - argument(bytes) as i64),
+            (3, _) => {
+                let len = argument(bytes) as usize;
+                let (text, rest) = bytes.split_at(len);
+                *bytes = rest;
+                json!(std::str::from_utf8(text).unwrap())
+            }
+            (4, _) => {
+                let len = argument(bytes);
+                Value::Array((0..len).map(|_| decode(bytes)).collect())
+            }
+            (5, _) => {
+                let len = argument(bytes);
+                let mut fields = Map::new();
+                for _ in 0..len {
+                    let key = decode(bytes).as_str().unwrap().to_string();
+                    fields.insert(key, decode(bytes));
+                }
+                Value::Object(fields)
+            }
+            _ => panic!("unexpected head {:#x}", head),
+        }
+    }
+
+    fn round_trip<T: Serialize>(message: &T) -> Value {
+        let mut buffer = Vec::new();
+        OutputEncoder::Cbor.encode(message, &mut buffer).unwrap();
+        let mut bytes = buffer.as_slice();
+        let value = decode(&mut bytes);
+        assert!(bytes.is_empty());
+        value
+    }
+
+    fn result(t: u64, format: OutputFormat) -> ResultMessage {
+        let candle = Candle::new(t, 42_000.5, 42_100.25, 42_200.0, 41_900.125)
+            .with_volume(12.5, 525_000.0)
+            .with_trades(1_234);
+        let mut data = ResultData::from(candle);
+        data.format = format;
+        ResultMessage {
+            id: None,
+            stream: "btcusdt/ethusdt@1m".into(),
+            data,
+            closed: true,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+            alias: None,
+        }
+    }
+
+    #[test]
+    fn test_cbor_matches_json() {
+        for format in [
+            OutputFormat::default(),
+            OutputFormat {
+                precision: Some(2),
+                string_prices: true,
+                ..OutputFormat::default()
+            },
+        ] {
+            let message = result(1_709_210_096_789, format);
+            assert_eq!(
+                round_trip(&message),
+                serde_json::to_value(&message).unwrap()
+            );
+        }
+
+        let missing = ResultMessage {
+            id: None,
+            data: ResultData::missing(0),
+            partial: true,
+            missing: vec!["ethusdt@kline_1m".into()],
+            snapshot: false,
+            latency_ms: None,
+            alias: None,
+            ..result(0, OutputFormat::default())
+        };
+        assert_eq!(
+            round_trip(&missing),
+            serde_json::to_value(&missing).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cbor_lengths_and_signs() {
+        let status = StatusMessage {
+            stream: "x".repeat(70_000),
+            event: "gap".into(),
+            message: "é".repeat(200),
+            ..StatusMessage::default()
+        };
+        assert_eq!(round_trip(&status), serde_json::to_value(&status).unwrap());
+
+        let numbers = json!([
+            0,
+            23,
+            24,
+            255,
+            256,
+            65_536,
+            u64::MAX,
+            -1,
+            -500,
+            i64::MIN,
+            -0.5
+        ]);
+        assert_eq!(round_trip(&numbers), numbers);
+    }
+
+    #[test]
+    fn test_json_batch_is_an_array() {
+        let mut batch = Batch::new(OutputEncoder::Json);
+        for t in [0, 60_000] {
+            let mut buffer = Vec::new();
+            OutputEncoder::Json
+                .encode(&json!({ "t": t }), &mut buffer)
+                .unwrap();
+            batch.push(&buffer);
+        }
+
+        assert_eq!(
+            batch.take_frame(),
+            Message::Text(r#"[{"t":0},{"t":60000}]"#.into())
+        );
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_cbor_batch_is_an_array() {
+        let mut batch = Batch::new(OutputEncoder::Cbor);
+        let messages = [
+            result(0, OutputFormat::default()),
+            result(60_000, OutputFormat::default()),
+        ];
+        for message in &messages {
+            let mut buffer = Vec::new();
+            OutputEncoder::Cbor.encode(message, &mut buffer).unwrap();
+            batch.push(&buffer);
+        }
+
+        let Message::Binary(bytes) = batch.take_frame() else {
+            panic!("expected a binary frame");
+        };
+        assert_eq!(
+            decode(&mut bytes.as_slice()),
+            serde_json::to_value(&messages).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_protobuf_batch_is_frames_back_to_back() {
+        let mut batch = Batch::new(OutputEncoder::Protobuf);
+        let mut all = Vec::new();
+        for t in [0, 60_000] {
+            let mut buffer = Vec::new();
+            let message = ServerMessage::Result(result(t, OutputFormat::default()));
+            protobuf::write_message(&message, &mut buffer).unwrap();
+            batch.push(&buffer);
+            all.extend_from_slice(&buffer);
+        }
+
+        assert_eq!(batch.take_frame(), Message::Binary(all));
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_negotiate_takes_first_known_subprotocol() {
+        assert_eq!(
+            OutputEncoder::negotiate(["chat, candles.cbor", "candles.json"]),
+            Some(OutputEncoder::Cbor)
+        );
+        assert_eq!(
+            OutputEncoder::negotiate(["candles.json,candles.cbor"]),
+            Some(OutputEncoder::Json)
+        );
+        assert_eq!(
+            OutputEncoder::negotiate(["candles.msgpack", "candles.protobuf"]),
+            Some(OutputEncoder::Protobuf)
+        );
+        assert_eq!(OutputEncoder::negotiate(["chat", "candles.msgpack"]), None);
+        assert_eq!(OutputEncoder::negotiate([]), None);
+    }
+
+    fn compression(above: usize) -> FrameCompression {
+        FrameCompression {
+            above,
+            counters: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_frames_below_threshold_go_out_raw() {
+        let compression = compression(64);
+        let small = Message::Text("x".repeat(63));
+        assert_eq!(compression.apply(small.clone()), small);
+        assert_eq!(
+            compression.apply(Message::Ping(vec![0; 100])),
+            Message::Ping(vec![0; 100])
+        );
+        assert_eq!(compression.counters.stats().frames, 0);
+        assert_eq!(compression.counters.stats().ratio(), None);
+    }
+
+    #[test]
+    fn test_large_frames_go_out_as_envelopes() {
+        let compression = compression(64);
+        let messages: Vec<ResultMessage> = (0..50)
+            .map(|i| result(i * 60_000, OutputFormat::default()))
+            .collect();
+        let mut buffer = Vec::new();
+        OutputEncoder::Json.encode(&messages, &mut buffer).unwrap();
+        let text = String::from_utf8(buffer).unwrap();
+
+        let Message::Binary(frame) = compression.apply(Message::Text(text.clone())) else {
+            panic!("expected a binary frame");
+        };
+        assert!(frame.starts_with(br#"{"encoding":"gzip"}"#));
+        assert_eq!(open_envelope(&frame).unwrap().unwrap(), text.as_bytes());
+
+        let stats = compression.counters.stats();
+        assert_eq!(stats.frames, 1);
+        assert_eq!(stats.bytes_in, text.len() as u64);
+        assert_eq!(stats.bytes_out, frame.len() as u64);
+        assert!(stats.ratio().unwrap() > 4.0, "{:?}", stats);
+
+        // Anything else isn't an envelope
+        assert!(open_envelope(text.as_bytes()).is_none());
+        let counters = CompressionCounters::default();
+        counters.record(100, 25);
+        assert_eq!(counters.stats().ratio(), Some(4.0));
+    }
+}
@@ -0,0 +1,647 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Debug, Error)]
+pub enum ServerError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    WebSocket(Box<tungstenite::Error>),
+
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    UrlParse(#[from] url::ParseError),
+
+    #[error(transparent)]
+    ParseFloatError(#[from] std::num::ParseFloatError),
+
+    #[error("Key not found: {0}")]
+    KeyNotFound(String),
+
+    #[error("Can not connect to WebSocket")]
+    WebSocketConnect,
+
+    #[error("Can not accept WebSocket connection")]
+    WebSocketAccept,
+
+    #[error("Сonnection time expired")]
+    WebSocketTimeout,
+
+    #[error("Can not write to WebSocket for {streams}")]
+    WebSocketWrite { streams: String },
+
+    #[error("Client too slow to keep up with its results")]
+    SlowClient,
+
+    #[error("Division by zero")]
+    DivisionByZero,
+
+    #[error("Operation on mismatched timestamps")]
+    MismatchedTimestamps,
+
+    #[error("Empty expression")]
+    EmptyExpression,
+
+    #[error("Missing interval suffix, e.g. @1m")]
+    MissingIntervalSuffix,
+
+    #[error("Unbalanced parenthesis at position {position}")]
+    UnbalancedParentheses { position: usize },
+
+    #[error("Invalid character {ch:?} at position {position}")]
+    InvalidCharacter { ch: char, position: usize },
+
+    #[error("Empty operand at position {position}")]
+    EmptyOperand { position: usize },
+
+    #[error("Operator at position {position} is missing an operand")]
+    DanglingOperator { position: usize },
+
+    #[error("Missing operator at position {position}")]
+    MissingOperator { position: usize },
+
+    #[error(
+        "Misplaced interval at position {position}, one goes right after a symbol or at the end"
+    )]
+    MisplacedInterval { position: usize },
+
+    #[error("Invalid number {0}")]
+    InvalidNumber(String),
+
+    #[error("Expression nested too deeply")]
+    NestingTooDeep,
+
+    #[error("Expression references no symbol")]
+    NoSymbol,
+
+    #[error("Invalid message: {0}")]
+    InvalidMessage(String),
+
+    #[error(
+        "No supported subprotocol in {0:?}, offer candles.json, candles.cbor or candles.protobuf"
+    )]
+    UnsupportedSubprotocol(String),
+
+    #[error(
+        "Unsupported protocol version {0}, supported versions are {}",
+        supported_versions()
+    )]
+    UnsupportedVersion(u32),
+
+    #[error("No result for {0} yet, it needs a running subscription")]
+    NoResult(String),
+
+    #[error("No closed bars of {0} kept in the range asked for")]
+    NoHistory(String),
+
+    #[error("Invalid definition: {0}")]
+    InvalidDefinition(String),
+
+    #[error("{0} is already defined as another expression")]
+    NameTaken(String),
+
+    #[error("Alias {0} is already used by another subscription")]
+    AliasTaken(String),
+
+    #[error("Unknown interval {0}")]
+    InvalidInterval(String),
+
+    #[error("Invalid session {0}")]
+    InvalidSession(String),
+
+    #[error("Malformed kline for {symbol}: {fields}")]
+    MalformedKline { symbol: String, fields: String },
+
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+
+    #[error("Redis error: {0}")]
+    Redis(String),
+
+    #[error("Kafka error: {0}")]
+    Kafka(String),
+
+    #[error("MQTT error: {0}")]
+    Mqtt(String),
+
+    #[error("ClickHouse error: {0}")]
+    ClickHouse(String),
+
+    #[error("PostgreSQL error: {0}")]
+    Postgres(String),
+
+    #[error("Webhook error: {0}")]
+    Webhook(String),
+
+    #[error("gRPC error: {0}")]
+    Grpc(String),
+
+    #[error("REST error: {0}")]
+    Rest(String),
+
+    #[error("Evaluator panicked: {0}")]
+    EvaluatorPanicked(String),
+
+    #[error("{0} is only streamed by futures markets, the upstream is spot")]
+    FuturesOnly(String),
+
+    #[error("Server is draining, subscribe on another instance")]
+    Draining,
+
+    #[error("Limit {limit} of {max} exceeded")]
+    LimitExceeded { limit: &'static str, max: usize },
+}
+
+fn supported_versions() -> String {
+    let versions: Vec<String> = crate::emitter::VERSIONS
+        .iter()
+        .map(u32::to_string)
+        .collect();
+    versions.join(", ")
+}
+
+impl From<tungstenite::Error> for ServerError {
+    fn from(e: tungstenite::Error) -> Self {
+        ServerError::WebSocket(Box::new(e))
+    }
+}
+
+/// Kind of an error as sent to clients in the `code` of error frames, so
+/// they can branch without parsing the message. Codes are part of the
+/// protocol: one never changes meaning and a retired one is never reused.
+///
+/// `1xxx` are problems with the request, `2xxx` with the upstream, `3xxx`
+/// with evaluating an expression, `4xxx` with the client's connection and
+/// `5xxx` with the server as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    // A fault of the server's own; the request may succeed later
+    Internal = 1,
+    // The request isn't valid JSON or lacks required fields
+    ParseError = 1000,
+    // Retired: sent for every expression that didn't parse before the
+    // specific codes from 1007 on
+    InvalidStream = 1001,
+    InvalidInterval = 1002,
+    InvalidSession = 1003,
+    // Unsubscribing from a stream the client isn't subscribed to
+    NotSubscribed = 1004,
+    // Reserved for symbols the upstream doesn't list
+    UnknownSymbol = 1005,
+    // Reserved for clients sending requests too fast
+    RateLimited = 1006,
+    // Nothing before the `@`
+    EmptyExpression = 1007,
+    // No `@interval` after the expression
+    MissingInterval = 1008,
+    UnbalancedParentheses = 1009,
+    InvalidCharacter = 1010,
+    // Nothing between two operators or inside `()`
+    EmptyOperand = 1011,
+    // An operator at either end of the expression or a group
+    DanglingOperator = 1012,
+    // Two operands or groups next to each other
+    MissingOperator = 1013,
+    InvalidNumber = 1014,
+    NestingTooDeep = 1015,
+    // The expression is all constants
+    NoSymbol = 1016,
+    // `GET_LAST` on an expression no client is subscribed to, or before
+    // its first result
+    NoResult = 1017,
+    // A `DEFINE` with a bad name, a cycle or too deep a nesting of names
+    InvalidDefinition = 1018,
+    // A `DEFINE` changing the expression of a name already defined, or a
+    // SUBSCRIBE reusing an alias of the same connection
+    NameTaken = 1019,
+    // An `@interval` after a group, a constant, another interval or where
+    // an operand belongs
+    MisplacedInterval = 1020,
+    // A stream only futures markets have, e.g. a premium index, on a spot
+    // upstream
+    FuturesOnly = 1021,
+    // A request over one of the server's limits, named in the message
+    LimitExceeded = 1022,
+    // `EXPORT` of an expression without a running evaluator, or whose
+    // evaluator kept no closed bar in the range
+    NoHistory = 1023,
+    // The upstream can't be reached or gave up on the stream
+    UpstreamUnavailable = 2000,
+    // The upstream sent a kline that doesn't parse
+    UpstreamMalformed = 2001,
+    // Division by zero or bars that don't line up
+    EvaluationFailed = 3000,
+    // Disconnected for not keeping up with its results
+    SlowClient = 4000,
+    // The server is shutting down and takes no new subscriptions
+    Draining = 5000,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 30] = [
+        ErrorCode::Internal,
+        ErrorCode::ParseError,
+        ErrorCode::InvalidStream,
+        ErrorCode::InvalidInterval,
+        ErrorCode::InvalidSession,
+        ErrorCode::NotSubscribed,
+        ErrorCode::UnknownSymbol,
+        ErrorCode::RateLimited,
+        ErrorCode::EmptyExpression,
+        ErrorCode::MissingInterval,
+        ErrorCode::UnbalancedParentheses,
+        ErrorCode::InvalidCharacter,
+        ErrorCode::EmptyOperand,
+        ErrorCode::DanglingOperator,
+        ErrorCode::MissingOperator,
+        ErrorCode::InvalidNumber,
+        ErrorCode::NestingTooDeep,
+        ErrorCode::NoSymbol,
+        ErrorCode::NoResult,
+        ErrorCode::InvalidDefinition,
+        ErrorCode::NameTaken,
+        ErrorCode::MisplacedInterval,
+        ErrorCode::FuturesOnly,
+        ErrorCode::LimitExceeded,
+        ErrorCode::NoHistory,
+        ErrorCode::UpstreamUnavailable,
+        ErrorCode::UpstreamMalformed,
+        ErrorCode::EvaluationFailed,
+        ErrorCode::SlowClient,
+        ErrorCode::Draining,
+    ];
+
+    pub fn value(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_value(value: u16) -> Option<ErrorCode> {
+        Self::ALL.into_iter().find(|code| code.value() == value)
+    }
+
+    /// Name for logs and documentation, e.g. `PARSE_ERROR`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Internal => "INTERNAL",
+            ErrorCode::ParseError => "PARSE_ERROR",
+            ErrorCode::InvalidStream => "INVALID_STREAM",
+            ErrorCode::InvalidInterval => "INVALID_INTERVAL",
+            ErrorCode::InvalidSession => "INVALID_SESSION",
+            ErrorCode::NotSubscribed => "NOT_SUBSCRIBED",
+            ErrorCode::UnknownSymbol => "UNKNOWN_SYMBOL",
+            ErrorCode::RateLimited => "RATE_LIMITED",
+            ErrorCode::EmptyExpression => "EMPTY_EXPRESSION",
+            ErrorCode::MissingInterval => "MISSING_INTERVAL",
+            ErrorCode::UnbalancedParentheses => "UNBALANCED_PARENTHESES",
+            ErrorCode::InvalidCharacter => "INVALID_CHARACTER",
+            ErrorCode::EmptyOperand => "EMPTY_OPERAND",
+            ErrorCode::DanglingOperator => "DANGLING_OPERATOR",
+            ErrorCode::MissingOperator => "MISSING_OPERATOR",
+            ErrorCode::InvalidNumber => "INVALID_NUMBER",
+            ErrorCode::NestingTooDeep => "NESTING_TOO_DEEP",
+            ErrorCode::NoSymbol => "NO_SYMBOL",
+            ErrorCode::NoResult => "NO_RESULT",
+            ErrorCode::InvalidDefinition => "INVALID_DEFINITION",
+            ErrorCode::NameTaken => "NAME_TAKEN",
+            ErrorCode::MisplacedInterval => "MISPLACED_INTERVAL",
+            ErrorCode::FuturesOnly => "FUTURES_ONLY",
+            ErrorCode::LimitExceeded => "LIMIT_EXCEEDED",
+            ErrorCode::NoHistory => "NO_HISTORY",
+            ErrorCode::UpstreamUnavailable => "UPSTREAM_UNAVAILABLE",
+            ErrorCode::UpstreamMalformed => "UPSTREAM_MALFORMED",
+            ErrorCode::EvaluationFailed => "EVALUATION_FAILED",
+            ErrorCode::SlowClient => "SLOW_CLIENT",
+            ErrorCode::Draining => "DRAINING",
+        }
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.value())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u16::deserialize(deserializer)?;
+        ErrorCode::from_value(value)
+            .ok_or_else(|| serde::de::Error::custom(format!("unknown error code {}", value)))
+    }
+}
+
+impl ServerError {
+    /// Code sent to clients for this error. The match has no wildcard, so a
+    /// new variant doesn't compile until it's given one.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServerError::Serde(_)
+            | ServerError::InvalidMessage(_)
+            | ServerError::UnsupportedSubprotocol(_)
+            | ServerError::UnsupportedVersion(_) => ErrorCode::ParseError,
+            ServerError::EmptyExpression => ErrorCode::EmptyExpression,
+            ServerError::MissingIntervalSuffix => ErrorCode::MissingInterval,
+            ServerError::UnbalancedParentheses { .. } => ErrorCode::UnbalancedParentheses,
+            ServerError::InvalidCharacter { .. } => ErrorCode::InvalidCharacter,
+            ServerError::EmptyOperand { .. } => ErrorCode::EmptyOperand,
+            ServerError::DanglingOperator { .. } => ErrorCode::DanglingOperator,
+            ServerError::MissingOperator { .. } => ErrorCode::MissingOperator,
+            ServerError::MisplacedInterval { .. } => ErrorCode::MisplacedInterval,
+            ServerError::FuturesOnly(_) => ErrorCode::FuturesOnly,
+            ServerError::InvalidNumber(_) => ErrorCode::InvalidNumber,
+            ServerError::NestingTooDeep => ErrorCode::NestingTooDeep,
+            ServerError::NoSymbol => ErrorCode::NoSymbol,
+            ServerError::NoResult(_) => ErrorCode::NoResult,
+            ServerError::NoHistory(_) => ErrorCode::NoHistory,
+            ServerError::InvalidDefinition(_) => ErrorCode::InvalidDefinition,
+            ServerError::NameTaken(_) | ServerError::AliasTaken(_) => ErrorCode::NameTaken,
+            ServerError::InvalidInterval(_) => ErrorCode::InvalidInterval,
+            ServerError::InvalidSession(_) => ErrorCode::InvalidSession,
+            ServerError::KeyNotFound(_) => ErrorCode::NotSubscribed,
+            ServerError::WebSocketConnect
+            | ServerError::WebSocketTimeout
+            | ServerError::Rest(_) => ErrorCode::UpstreamUnavailable,
+            ServerError::MalformedKline { .. } | ServerError::ParseFloatError(_) => {
+                ErrorCode::UpstreamMalformed
+            }
+            ServerError::DivisionByZero | ServerError::MismatchedTimestamps => {
+                ErrorCode::EvaluationFailed
+            }
+            ServerError::SlowClient => ErrorCode::SlowClient,
+            ServerError::Draining => ErrorCode::Draining,
+            ServerError::LimitExceeded { .. } => ErrorCode::LimitExceeded,
+            ServerError::Io(_)
+            | ServerError::WebSocket(_)
+            | ServerError::UrlParse(_)
+            | ServerError::WebSocketAccept
+            | ServerError::WebSocketWrite { .. }
+            | ServerError::Config(_)
+            | ServerError::Redis(_)
+            | ServerError::Kafka(_)
+            | ServerError::Mqtt(_)
+            | ServerError::ClickHouse(_)
+            | ServerError::Postgres(_)
+            | ServerError::Webhook(_)
+            | ServerError::Grpc(_)
+            | ServerError::EvaluatorPanicked(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorCode, ServerError};
+    use std::collections::HashSet;
+
+    // One of every variant with the code it maps to
+    fn every_error() -> Vec<(ServerError, ErrorCode)> {
+        use ErrorCode::*;
+        vec![
+            (ServerError::Io(std::io::Error::other("io")), Internal),
+            (
+                ServerError::WebSocket(Box::new(
+                    tokio_tungstenite::tungstenite::Error::ConnectionClosed,
+                )),
+                Internal,
+            ),
+            (
+                ServerError::Serde(serde_json::from_str::<u8>("x").unwrap_err()),
+                ParseError,
+            ),
+            (ServerError::UrlParse(url::ParseError::EmptyHost), Internal),
+            (
+                ServerError::ParseFloatError("x".parse::<f64>().unwrap_err()),
+                UpstreamMalformed,
+            ),
+            (ServerError::KeyNotFound(String::new()), NotSubscribed),
+            (ServerError::WebSocketConnect, UpstreamUnavailable),
+            (ServerError::WebSocketAccept, Internal),
+            (ServerError::WebSocketTimeout, UpstreamUnavailable),
+            (
+                ServerError::WebSocketWrite {
+                    streams: String::new(),
+                },
+                Internal,
+            ),
+            (ServerError::SlowClient, SlowClient),
+            (ServerError::DivisionByZero, EvaluationFailed),
+            (ServerError::MismatchedTimestamps, EvaluationFailed),
+            (ServerError::EmptyExpression, EmptyExpression),
+            (ServerError::MissingIntervalSuffix, MissingInterval),
+            (
+                ServerError::UnbalancedParentheses { position: 0 },
+                UnbalancedParentheses,
+            ),
+            (
+                ServerError::InvalidCharacter {
+                    ch: '%',
+                    position: 0,
+                },
+                InvalidCharacter,
+            ),
+            (ServerError::EmptyOperand { position: 0 }, EmptyOperand),
+            (
+                ServerError::DanglingOperator { position: 0 },
+                DanglingOperator,
+            ),
+            (
+                ServerError::MissingOperator { position: 0 },
+                MissingOperator,
+            ),
+            (ServerError::InvalidNumber(String::new()), InvalidNumber),
+            (ServerError::NestingTooDeep, NestingTooDeep),
+            (ServerError::NoSymbol, NoSymbol),
+            (ServerError::InvalidMessage(String::new()), ParseError),
+            (ServerError::InvalidInterval(String::new()), InvalidInterval),
+            (ServerError::InvalidSession(String::new()), InvalidSession),
+            (
+                ServerError::MalformedKline {
+                    symbol: String::new(),
+                    fields: String::new(),
+                },
+                UpstreamMalformed,
+            ),
+            (ServerError::Config(String::new()), Internal),
+            (ServerError::Redis(String::new()), Internal),
+            (ServerError::Kafka(String::new()), Internal),
+            (ServerError::Mqtt(String::new()), Internal),
+            (
+                ServerError::UnsupportedSubprotocol(String::new()),
+                ParseError,
+            ),
+            (ServerError::NoResult(String::new()), NoResult),
+            (ServerError::Rest(String::new()), UpstreamUnavailable),
+            (
+                ServerError::InvalidDefinition(String::new()),
+                InvalidDefinition,
+            ),
+            (ServerError::NameTaken(String::new()), NameTaken),
+            (ServerError::AliasTaken(String::new()), NameTaken),
+            (
+                ServerError::MisplacedInterval { position: 0 },
+                MisplacedInterval,
+            ),
+            (ServerError::EvaluatorPanicked(String::new()), Internal),
+            (ServerError::FuturesOnly(String::new()), FuturesOnly),
+            (ServerError::UnsupportedVersion(0), ParseError),
+            (ServerError::Draining, Draining),
+            (
+                ServerError::LimitExceeded {
+                    limit: "max_subscriptions_per_client",
+                    max: 0,
+                },
+                LimitExceeded,
+            ),
+            (ServerError::NoHistory(String::new()), NoHistory),
+            (ServerError::ClickHouse(String::new()), Internal),
+            (ServerError::Postgres(String::new()), Internal),
+            (ServerError::Webhook(String::new()), Internal),
+            (ServerError::Grpc(String::new()), Internal),
+        ]
+    }
+
+    // Exhaustive, so a new variant doesn't compile here, and once given an
+    // index the test fails until `every_error` lists it too
+    fn variant_index(error: &ServerError) -> usize {
+        match error {
+            ServerError::Io(_) => 0,
+            ServerError::WebSocket(_) => 1,
+            ServerError::Serde(_) => 2,
+            ServerError::UrlParse(_) => 3,
+            ServerError::ParseFloatError(_) => 4,
+            ServerError::KeyNotFound(_) => 5,
+            ServerError::WebSocketConnect => 6,
+            ServerError::WebSocketAccept => 7,
+            ServerError::WebSocketTimeout => 8,
+            ServerError::WebSocketWrite { .. } => 9,
+            ServerError::SlowClient => 10,
+            ServerError::DivisionByZero => 11,
+            ServerError::MismatchedTimestamps => 12,
+            ServerError::EmptyExpression => 13,
+            ServerError::MissingIntervalSuffix => 14,
+            ServerError::UnbalancedParentheses { .. } => 15,
+            ServerError::InvalidCharacter { .. } => 16,
+            ServerError::EmptyOperand { .. } => 17,
+            ServerError::DanglingOperator { .. } => 18,
+            ServerError::MissingOperator { .. } => 19,
+            ServerError::InvalidNumber(_) => 20,
+            ServerError::NestingTooDeep => 21,
+            ServerError::NoSymbol => 22,
+            ServerError::InvalidMessage(_) => 23,
+            ServerError::InvalidInterval(_) => 24,
+            ServerError::InvalidSession(_) => 25,
+            ServerError::MalformedKline { .. } => 26,
+            ServerError::Config(_) => 27,
+            ServerError::Redis(_) => 28,
+            ServerError::Kafka(_) => 29,
+            ServerError::Mqtt(_) => 30,
+            ServerError::UnsupportedSubprotocol(_) => 31,
+            ServerError::NoResult(_) => 32,
+            ServerError::Rest(_) => 33,
+            ServerError::InvalidDefinition(_) => 34,
+            ServerError::NameTaken(_) => 35,
+            ServerError::AliasTaken(_) => 36,
+            ServerError::MisplacedInterval { .. } => 37,
+            ServerError::EvaluatorPanicked(_) => 38,
+            ServerError::FuturesOnly(_) => 39,
+            ServerError::UnsupportedVersion(_) => 40,
+            ServerError::Draining => 41,
+            ServerError::LimitExceeded { .. } => 42,
+            ServerError::NoHistory(_) => 43,
+            ServerError::ClickHouse(_) => 44,
+            ServerError::Postgres(_) => 45,
+            ServerError::Webhook(_) => 46,
+            ServerError::Grpc(_) => 47,
+        }
+    }
+
+    #[test]
+    fn test_every_variant_has_a_code() {
+        let errors = every_error();
+        let indexes: HashSet<usize> = errors
+            .iter()
+            .map(|(error, _)| variant_index(error))
+            .collect();
+        assert_eq!(indexes, (0..errors.len()).collect());
+        for (error, code) in &errors {
+            assert_eq!(error.code(), *code, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_messages_carry_context() {
+        let write = ServerError::WebSocketWrite {
+            streams: "SUBSCRIBE btcusdt@kline_1m".into(),
+        };
+        assert_eq!(
+            write.to_string(),
+            "Can not write to WebSocket for SUBSCRIBE btcusdt@kline_1m"
+        );
+        assert_eq!(
+            ServerError::InvalidMessage("missing stream parameter".into()).to_string(),
+            "Invalid message: missing stream parameter"
+        );
+        assert_eq!(
+            ServerError::InvalidCharacter {
+                ch: '%',
+                position: 7
+            }
+            .to_string(),
+            "Invalid character '%' at position 7"
+        );
+    }
+
+    // Clients depend on these values; never change one
+    #[test]
+    fn test_codes_are_stable() {
+        let values: Vec<(&str, u16)> = ErrorCode::ALL
+            .iter()
+            .map(|code| (code.name(), code.value()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("INTERNAL", 1),
+                ("PARSE_ERROR", 1000),
+                ("INVALID_STREAM", 1001),
+                ("INVALID_INTERVAL", 1002),
+                ("INVALID_SESSION", 1003),
+                ("NOT_SUBSCRIBED", 1004),
+                ("UNKNOWN_SYMBOL", 1005),
+                ("RATE_LIMITED", 1006),
+                ("EMPTY_EXPRESSION", 1007),
+                ("MISSING_INTERVAL", 1008),
+                ("UNBALANCED_PARENTHESES", 1009),
+                ("INVALID_CHARACTER", 1010),
+                ("EMPTY_OPERAND", 1011),
+                ("DANGLING_OPERATOR", 1012),
+                ("MISSING_OPERATOR", 1013),
+                ("INVALID_NUMBER", 1014),
+                ("NESTING_TOO_DEEP", 1015),
+                ("NO_SYMBOL", 1016),
+                ("NO_RESULT", 1017),
+                ("INVALID_DEFINITION", 1018),
+                ("NAME_TAKEN", 1019),
+                ("MISPLACED_INTERVAL", 1020),
+                ("FUTURES_ONLY", 1021),
+                ("LIMIT_EXCEEDED", 1022),
+                ("NO_HISTORY", 1023),
+                ("UPSTREAM_UNAVAILABLE", 2000),
+                ("UPSTREAM_MALFORMED", 2001),
+                ("EVALUATION_FAILED", 3000),
+                ("SLOW_CLIENT", 4000),
+                ("DRAINING", 5000),
+            ]
+        );
+        for code in ErrorCode::ALL {
+            let json = serde_json::to_string(&code).unwrap();
+            assert_eq!(json, code.value().to_string());
+            assert_eq!(serde_json::from_str::<ErrorCode>(&json).unwrap(), code);
+        }
+        assert!(serde_json::from_str::<ErrorCode>("999").is_err());
+    }
+}
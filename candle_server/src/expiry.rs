@@ -0,0 +1,92 @@
+use std::collections::{BTreeSet, HashMap};
+use tokio::time::Instant;
+
+/// When each subscription of a connection with a `ttl_secs` expires, in one
+/// queue ordered by deadline, so the connection's own task sleeps until the
+/// earliest one rather than every subscription keeping a timer task.
+#[derive(Debug, Default)]
+pub struct Expiries {
+    // Every deadline with its subscription's key, earliest first
+    queue: BTreeSet<(Instant, String)>,
+    // Deadline of each key in `queue`
+    deadlines: HashMap<String, Instant>,
+}
+
+impl Expiries {
+    pub fn new() -> Expiries {
+        Expiries::default()
+    }
+
+    /// Makes `key` expire at `at`, replacing any deadline it had.
+    pub fn set(&mut self, key: &str, at: Instant) {
+        self.remove(key);
+        self.deadlines.insert(key.to_string(), at);
+        self.queue.insert((at, key.to_string()));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(at) = self.deadlines.remove(key) {
+            self.queue.remove(&(at, key.to_string()));
+        }
+    }
+
+    /// Earliest deadline, `None` when nothing expires.
+    pub fn next(&self) -> Option<Instant> {
+        self.queue.first().map(|(at, _)| *at)
+    }
+
+    /// Takes the keys whose deadline is `now` or earlier, earliest first.
+    pub fn expired(&mut self, now: Instant) -> Vec<String> {
+        let mut expired = Vec::new();
+        while let Some((at, _)) = self.queue.first() {
+            if *at > now {
+                break;
+            }
+            let (_, key) = self.queue.pop_first().unwrap();
+            self.deadlines.remove(&key);
+            expired.push(key);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Expiries;
+    use tokio::time::{Duration, Instant};
+
+    #[test]
+    fn test_keys_expire_in_deadline_order() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut expiries = Expiries::new();
+        expiries.set("b", at(20));
+        expiries.set("a", at(10));
+        expiries.set("c", at(30));
+        assert_eq!(expiries.next(), Some(at(10)));
+
+        assert!(expiries.expired(at(5)).is_empty());
+        assert_eq!(expiries.expired(at(20)), ["a", "b"]);
+        assert_eq!(expiries.next(), Some(at(30)));
+        assert_eq!(expiries.expired(at(60)), ["c"]);
+        assert_eq!(expiries.next(), None);
+    }
+
+    #[test]
+    fn test_set_replaces_and_remove_drops() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut expiries = Expiries::new();
+        expiries.set("a", at(10));
+        expiries.set("b", at(15));
+        // Renewed past `b`
+        expiries.set("a", at(40));
+        assert_eq!(expiries.expired(at(20)), ["b"]);
+        assert_eq!(expiries.next(), Some(at(40)));
+
+        expiries.remove("a");
+        expiries.remove("missing");
+        assert_eq!(expiries.next(), None);
+        assert!(expiries.expired(at(60)).is_empty());
+    }
+}
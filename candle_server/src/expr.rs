@@ -0,0 +1,2848 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::candle::{self, Candle, CompositeSemantics};
+use crate::error::ServerError;
+use crate::utils::interval_to_millis;
+
+/// Bar interval of a stream expression, the part after `@`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interval(String);
+
+impl Interval {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Interval of `symbol`: its own for a leg written like `ethusdt@1h`,
+    /// otherwise this one.
+    pub fn of<'a>(&'a self, symbol: &'a str) -> &'a str {
+        symbol
+            .split_once('@')
+            .map_or(&self.0, |(_, interval)| interval)
+    }
+
+    /// Upstream stream of `symbol` at its interval: a kline stream, the
+    /// open interest of an `oi:` operand, the premium index klines of a
+    /// `premium:` one or the mid-price bars of a `mid:` one.
+    pub fn stream(&self, symbol: &str) -> String {
+        let name = symbol.split_once('@').map_or(symbol, |(name, _)| name);
+        if let Some(name) = name.strip_prefix(OPEN_INTEREST_PREFIX) {
+            format!("{}@openInterest_{}", name, self.of(symbol))
+        } else if let Some(name) = name.strip_prefix(PREMIUM_INDEX_PREFIX) {
+            format!("{}@premiumIndexKline_{}", name, self.of(symbol))
+        } else if let Some(name) = name.strip_prefix(MID_PREFIX) {
+            format!("{}@midKline_{}", name, self.of(symbol))
+        } else {
+            kline_stream(name, self.of(symbol))
+        }
+    }
+}
+
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Marks an operand standing for a symbol's futures open interest, e.g.
+/// `oi:btcusdt`, rather than its price.
+pub const OPEN_INTEREST_PREFIX: &str = "oi:";
+
+/// Marks an operand standing for a symbol's premium index, e.g.
+/// `premium:btcusdt`, the relative gap of its perpetual over the index price.
+pub const PREMIUM_INDEX_PREFIX: &str = "premium:";
+
+/// Marks an operand standing for the midpoint of a symbol's best bid and
+/// ask, e.g. `mid:btcusdt`, built into bars from its book ticker.
+pub const MID_PREFIX: &str = "mid:";
+
+const OPERAND_PREFIXES: [&str; 3] = [OPEN_INTEREST_PREFIX, PREMIUM_INDEX_PREFIX, MID_PREFIX];
+
+/// Contract types of Binance's continuous contracts, named like
+/// `btcusdt_perpetual` after the pair they track.
+pub const CONTRACT_TYPES: [&str; 3] = ["perpetual", "current_quarter", "next_quarter"];
+
+/// Pseudo-interval of expressions over `@miniTicker` streams, e.g.
+/// `btcusdt-ethusdt@ticker`. Each result is for the second of the update,
+/// and its open, high, low and close are over the rolling 24 hours before
+/// it rather than a bar.
+pub const TICKER_INTERVAL: &str = "ticker";
+
+/// Pseudo-interval of a symbol's 24 hour statistics, e.g.
+/// `btcusdt@ticker24h`, relayed without evaluating anything.
+pub const TICKER_STATS_INTERVAL: &str = "ticker24h";
+
+/// Kline stream of `name` at `interval`: a continuous contract's for a
+/// name ending in a contract type, otherwise the symbol's own, or its mini
+/// ticker or ticker at the `ticker` and `ticker24h` pseudo-intervals.
+pub fn kline_stream(name: &str, interval: &str) -> String {
+    if interval == TICKER_INTERVAL {
+        format!("{}@miniTicker", name)
+    } else if interval == TICKER_STATS_INTERVAL {
+        format!("{}@ticker", name)
+    } else if is_continuous(name) {
+        format!("{}@continuousKline_{}", name, interval)
+    } else {
+        format!("{}@kline_{}", name, interval)
+    }
+}
+
+// Whether `name` is a pair and a contract type, e.g. `btcusdt_perpetual`
+fn is_continuous(name: &str) -> bool {
+    CONTRACT_TYPES.iter().any(|contract| {
+        name.strip_suffix(contract)
+            .and_then(|pair| pair.strip_suffix('_'))
+            .is_some_and(|pair| !pair.is_empty())
+    })
+}
+
+/// Interval of an upstream stream such as `btcusdt@kline_1m`, which is
+/// `ticker` for a mini ticker and empty for a name it can't be read from.
+pub fn stream_interval(stream: &str) -> &str {
+    match stream.rsplit_once('@') {
+        Some((_, "miniTicker")) => TICKER_INTERVAL,
+        Some((_, "ticker")) => TICKER_STATS_INTERVAL,
+        Some((_, kind)) => kind.split_once('_').map_or("", |(_, interval)| interval),
+        None => "",
+    }
+}
+
+/// Whether `stream` is of klines, which REST has the bars of, rather than
+/// open interest or tickers.
+pub fn is_kline_stream(stream: &str) -> bool {
+    ["@kline_", "@continuousKline_", "@premiumIndexKline_"]
+        .iter()
+        .any(|kind| stream.contains(kind))
+}
+
+/// Whether `stream` only exists on futures markets: continuous contract
+/// and premium index klines, and those of delivery contracts.
+pub fn is_futures_only(stream: &str) -> bool {
+    let name = stream.split_once('@').map_or(stream, |(name, _)| name);
+    stream.contains("@continuousKline_")
+        || stream.contains("@premiumIndexKline_")
+        || is_delivery(name)
+}
+
+// Whether `name` is a pair and a delivery date, e.g. `btcusdt_240927`
+fn is_delivery(name: &str) -> bool {
+    name.rsplit_once('_').is_some_and(|(pair, date)| {
+        !pair.is_empty() && date.len() == 6 && date.bytes().all(|b| b.is_ascii_digit())
+    })
+}
+
+// Byte offset of an underscore in symbol `word` that doesn't join two of
+// its parts, as in `btcusdt_` or `btc__usdt`
+fn stray_underscore(word: &str) -> Option<usize> {
+    let bytes = word.as_bytes();
+    (0..bytes.len())
+        .find(|&i| bytes[i] == b'_' && (i == 0 || i + 1 == bytes.len() || bytes[i + 1] == b'_'))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinOp {
+    fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            BinOp::Add => lhs + rhs,
+            BinOp::Sub => lhs - rhs,
+            BinOp::Mul => lhs * rhs,
+            BinOp::Div => lhs / rhs,
+        }
+    }
+}
+
+impl std::fmt::Display for BinOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            BinOp::Add => '+',
+            BinOp::Sub => '-',
+            BinOp::Mul => '*',
+            BinOp::Div => '/',
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Symbol(String),
+    Const(f64),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+// Deep enough for any real spread, shallow enough that hostile input can't
+// exhaust the stack
+pub const MAX_DEPTH: usize = 64;
+// Definitions may build on other definitions this many levels deep
+const MAX_DEFINITION_DEPTH: usize = 8;
+// Assets Binance quotes pairs in, which a name can't end in so it never
+// stands for a pair, e.g. `btcusdt` or `ethbtc`
+const QUOTE_ASSETS: [&str; 11] = [
+    "usdt", "usdc", "usd", "dai", "btc", "eth", "bnb", "eur", "try", "brl", "jpy",
+];
+
+impl Expr {
+    /// Parses `expression@interval`, e.g. `(btcusdt-ethusdt)*2@1m`. Operands
+    /// are symbols, ASCII letters and digits with parts joined by single
+    /// underscores as in `btcusdt_240927`, or plain decimal constants; `-`
+    /// may be unary, and the
+    /// expression must reference at least one symbol. A symbol may have its
+    /// own interval, e.g. `btcusdt/ethusdt@1h@1m`, which the trailing one is
+    /// the default for; legs at different intervals can't use months.
+    /// Symbols are lowercased, as upstream stream names are, while their
+    /// intervals keep their case, as `1M` isn't `1m`. ASCII whitespace may
+    /// surround tokens and the trailing interval; it ends a symbol, so
+    /// `btc usdt` misses an operator before `usdt`.
+    /// Positions in errors count characters from the start of `input`.
+    pub fn parse(input: &str) -> Result<(Expr, Interval), ServerError> {
+        let divider_index = input.rfind('@').ok_or(ServerError::MissingIntervalSuffix)?;
+        let interval = input[(divider_index + 1)..].trim_ascii();
+        if interval.is_empty() {
+            return Err(ServerError::MissingIntervalSuffix);
+        }
+        if !interval.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(ServerError::InvalidInterval(interval.to_string()));
+        }
+
+        let mut expr = Self::parse_expression(&input[..divider_index])?;
+        let interval = Interval(interval.to_string());
+        // A leg at the default interval is written the same without it
+        expr.drop_interval(&interval);
+
+        // Statistics are of a single symbol
+        let relayed = expr.intervals(&interval).contains(&TICKER_STATS_INTERVAL);
+        if relayed && !matches!(&expr, Expr::Symbol(symbol) if !symbol.contains(':')) {
+            return Err(ServerError::InvalidInterval(input.to_string()));
+        }
+        // Mini tickers are only for plain symbols, which they don't mix with
+        for symbol in expr.symbols() {
+            let name = symbol.split_once('@').map_or(symbol, |(name, _)| name);
+            let plain = !name.contains(':') && !is_continuous(name);
+            if interval.of(symbol) == TICKER_INTERVAL && !plain {
+                return Err(ServerError::InvalidInterval(format!(
+                    "{}@{}",
+                    name, TICKER_INTERVAL
+                )));
+            }
+        }
+        let intervals = expr.intervals(&interval);
+        if intervals.len() > 1 {
+            for leg in intervals {
+                if leg.ends_with('M') || interval_to_millis(leg, 0).is_err() {
+                    return Err(ServerError::InvalidInterval(leg.to_string()));
+                }
+            }
+        }
+        Ok((expr, interval))
+    }
+
+    fn drop_interval(&mut self, interval: &Interval) {
+        match self {
+            Expr::Symbol(symbol) => {
+                if let Some((name, leg)) = symbol.split_once('@') {
+                    if leg == interval.as_str() {
+                        *symbol = name.to_string();
+                    }
+                }
+            }
+            Expr::Const(_) => {}
+            Expr::Unary(_, operand) => operand.drop_interval(interval),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.drop_interval(interval);
+                rhs.drop_interval(interval);
+            }
+        }
+    }
+
+    /// Intervals the legs are at, sorted, each listed once.
+    pub fn intervals<'a>(&'a self, interval: &'a Interval) -> Vec<&'a str> {
+        let mut intervals: Vec<&str> = self.symbols().map(|symbol| interval.of(symbol)).collect();
+        intervals.sort_unstable();
+        intervals.dedup();
+        intervals
+    }
+
+    // The part of a stream before the `@`, or a definition's expression
+    fn parse_expression(input: &str) -> Result<Expr, ServerError> {
+        let mut parser = Parser {
+            input,
+            pos: 0,
+            depth: 0,
+        };
+        let expr = parser.sum()?;
+        if let Some(c) = parser.peek() {
+            return Err(parser.unexpected(c));
+        }
+        if expr.symbols().next().is_none() {
+            return Err(ServerError::NoSymbol);
+        }
+        Ok(expr)
+    }
+
+    /// Symbols in the order they appear, repeats included.
+    pub fn symbols(&self) -> impl Iterator<Item = &str> {
+        let mut symbols = Vec::new();
+        self.collect_symbols(&mut symbols);
+        symbols.into_iter()
+    }
+
+    /// Upstream kline streams of the symbols at `interval`, sorted, each
+    /// listed once however often its symbol appears.
+    pub fn streams(&self, interval: &Interval) -> Vec<String> {
+        let mut streams: Vec<String> = self
+            .symbols()
+            .map(|symbol| interval.stream(symbol))
+            .collect();
+        streams.sort();
+        streams.dedup();
+        streams
+    }
+
+    fn collect_symbols<'a>(&'a self, symbols: &mut Vec<&'a str>) {
+        match self {
+            Expr::Symbol(symbol) => symbols.push(symbol),
+            Expr::Const(_) => {}
+            Expr::Unary(_, operand) => operand.collect_symbols(symbols),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_symbols(symbols);
+                rhs.collect_symbols(symbols);
+            }
+        }
+    }
+
+    /// Folds constant subtrees, drops `+0`, `-0`, `*1` and `/1`, and gathers
+    /// the constants of a `+` chain into its last operand. Legs keep their
+    /// order and grouping: a negative factor swaps the high and low of what
+    /// it scales, and every step widens the bar to take in its open and
+    /// close, so moving a factor across a product changes the result.
+    /// Division by a constant zero is rejected here rather than failing on
+    /// every bar.
+    pub fn simplify(&self) -> Result<Expr, ServerError> {
+        Ok(match self {
+            Expr::Symbol(_) | Expr::Const(_) => self.clone(),
+            Expr::Unary(UnaryOp::Neg, operand) => match operand.simplify()? {
+                Expr::Const(value) => Expr::Const(-value),
+                Expr::Unary(UnaryOp::Neg, inner) => *inner,
+                other => Expr::Unary(UnaryOp::Neg, Box::new(other)),
+            },
+            Expr::Binary(BinOp::Add, lhs, rhs) => Expr::sum(lhs.simplify()?, rhs.simplify()?),
+            Expr::Binary(BinOp::Mul, lhs, rhs) => Expr::product(lhs.simplify()?, rhs.simplify()?),
+            Expr::Binary(BinOp::Sub, lhs, rhs) => match (lhs.simplify()?, rhs.simplify()?) {
+                (Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(lhs - rhs),
+                (lhs, Expr::Const(0.0)) => lhs,
+                (Expr::Const(0.0), rhs) => Expr::Unary(UnaryOp::Neg, Box::new(rhs)).simplify()?,
+                (lhs, rhs) => Expr::Binary(BinOp::Sub, Box::new(lhs), Box::new(rhs)),
+            },
+            Expr::Binary(BinOp::Div, lhs, rhs) => match (lhs.simplify()?, rhs.simplify()?) {
+                (_, Expr::Const(0.0)) => return Err(ServerError::DivisionByZero),
+                (Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(lhs / rhs),
+                (lhs, Expr::Const(1.0)) => lhs,
+                (lhs, rhs) => Expr::Binary(BinOp::Div, Box::new(lhs), Box::new(rhs)),
+            },
+        })
+    }
+
+    // Rebuilds a simplified `+` chain with its operands in order and its
+    // constants folded into one last operand. Sums of bars are already as
+    // wide as their opens and closes, so regrouping them is safe.
+    fn sum(lhs: Expr, rhs: Expr) -> Expr {
+        let mut operands = Vec::new();
+        lhs.flatten(BinOp::Add, &mut operands);
+        rhs.flatten(BinOp::Add, &mut operands);
+
+        let mut folded = 0.0;
+        operands.retain(|operand| match operand {
+            Expr::Const(value) => {
+                folded += value;
+                false
+            }
+            _ => true,
+        });
+        if operands.is_empty() || folded != 0.0 {
+            operands.push(Expr::Const(folded));
+        }
+
+        let mut operands = operands.into_iter();
+        let first = operands.next().unwrap();
+        operands.fold(first, |acc, operand| {
+            Expr::Binary(BinOp::Add, Box::new(acc), Box::new(operand))
+        })
+    }
+
+    // Multiplies two simplified operands in their order. A constant folds
+    // into a factor of the operand it scales, as scaling twice is scaling
+    // once by the product, but never moves past a leg.
+    fn product(lhs: Expr, rhs: Expr) -> Expr {
+        match (lhs, rhs) {
+            (Expr::Const(lhs), Expr::Const(rhs)) => Expr::Const(lhs * rhs),
+            (Expr::Const(1.0), operand) | (operand, Expr::Const(1.0)) => operand,
+            (Expr::Const(factor), operand) => match operand.split_factor() {
+                Ok((inner, operand)) => Expr::product(Expr::Const(factor * inner), operand),
+                Err(operand) => {
+                    Expr::Binary(BinOp::Mul, Box::new(Expr::Const(factor)), Box::new(operand))
+                }
+            },
+            (operand, Expr::Const(factor)) => match operand.split_factor() {
+                Ok((inner, operand)) => Expr::product(operand, Expr::Const(inner * factor)),
+                Err(operand) => {
+                    Expr::Binary(BinOp::Mul, Box::new(operand), Box::new(Expr::Const(factor)))
+                }
+            },
+            (lhs, rhs) => Expr::Binary(BinOp::Mul, Box::new(lhs), Box::new(rhs)),
+        }
+    }
+
+    // `c*x` or `x*c` as `(c, x)`, or the expression back if it's neither
+    fn split_factor(self) -> Result<(f64, Expr), Expr> {
+        match self {
+            Expr::Binary(BinOp::Mul, lhs, rhs) => match (*lhs, *rhs) {
+                (Expr::Const(factor), operand) | (operand, Expr::Const(factor)) => {
+                    Ok((factor, operand))
+                }
+                (lhs, rhs) => Err(Expr::Binary(BinOp::Mul, Box::new(lhs), Box::new(rhs))),
+            },
+            other => Err(other),
+        }
+    }
+
+    fn flatten(self, op: BinOp, operands: &mut Vec<Expr>) {
+        match self {
+            Expr::Binary(inner, lhs, rhs) if inner == op => {
+                lhs.flatten(op, operands);
+                rhs.flatten(op, operands);
+            }
+            other => operands.push(other),
+        }
+    }
+
+    /// Evaluates the expression, looking every symbol's candle up with
+    /// `candle`. Fails on dividing by zero.
+    pub fn eval(&self, candle: &impl Fn(&str) -> Option<Candle>) -> Result<Candle, ServerError> {
+        let result = self.eval_as(CompositeSemantics::Fieldwise, 0.0, 0, candle)?;
+        if result.undefined() {
+            Err(ServerError::DivisionByZero)
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Like `eval`, combining the highs and lows of legs as `semantics`
+    /// says. Prices divided by a divisor within `epsilon` of zero are left
+    /// undefined rather than failing the bar. Legs whose bars start at most
+    /// `tolerance` milliseconds apart combine, into a bar starting with the
+    /// earliest.
+    pub fn eval_as(
+        &self,
+        semantics: CompositeSemantics,
+        epsilon: f64,
+        tolerance: u64,
+        candle: &impl Fn(&str) -> Option<Candle>,
+    ) -> Result<Candle, ServerError> {
+        match self.value(semantics, epsilon, tolerance, candle)? {
+            Value::Series(result) => Ok(result),
+            Value::Scalar(_) => Err(ServerError::NoSymbol),
+        }
+    }
+
+    fn value(
+        &self,
+        semantics: CompositeSemantics,
+        epsilon: f64,
+        tolerance: u64,
+        candle: &impl Fn(&str) -> Option<Candle>,
+    ) -> Result<Value, ServerError> {
+        match self {
+            Expr::Symbol(symbol) => candle(symbol)
+                .map(Value::Series)
+                .ok_or_else(|| ServerError::KeyNotFound(symbol.clone())),
+            Expr::Const(value) => Ok(Value::Scalar(*value)),
+            Expr::Unary(UnaryOp::Neg, operand) => Ok(
+                match operand.value(semantics, epsilon, tolerance, candle)? {
+                    Value::Scalar(value) => Value::Scalar(-value),
+                    Value::Series(series) => Value::Series(series.map_prices(|p| -p)),
+                },
+            ),
+            Expr::Binary(op, lhs, rhs) => Value::apply(
+                *op,
+                lhs.value(semantics, epsilon, tolerance, candle)?,
+                rhs.value(semantics, epsilon, tolerance, candle)?,
+                semantics,
+                epsilon,
+                tolerance,
+            ),
+        }
+    }
+}
+
+impl Expr {
+    fn precedence(&self) -> usize {
+        match self {
+            Expr::Symbol(_) | Expr::Const(_) => usize::MAX,
+            Expr::Unary(_, _) => 3,
+            Expr::Binary(BinOp::Add | BinOp::Sub, _, _) => 1,
+            Expr::Binary(_, _, _) => 2,
+        }
+    }
+}
+
+/// Prints the expression with spaces around binary operators and only the
+/// parentheses needed to parse back into the same tree, e.g.
+/// `-btcusdt * (ethusdt - 2)`.
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let operand = |f: &mut std::fmt::Formatter, expr: &Expr, parens: bool| {
+            if parens {
+                write!(f, "({})", expr)
+            } else {
+                write!(f, "{}", expr)
+            }
+        };
+
+        match self {
+            Expr::Symbol(symbol) => write!(f, "{}", symbol),
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Unary(UnaryOp::Neg, inner) => {
+                write!(f, "-")?;
+                operand(f, inner, inner.precedence() < self.precedence())
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                // Operators are left-associative, so a right operand of equal
+                // precedence keeps its parentheses: a-(b-c) != a-b-c.
+                operand(f, lhs, lhs.precedence() < self.precedence())?;
+                write!(f, " {} ", op)?;
+                operand(f, rhs, rhs.precedence() <= self.precedence())
+            }
+        }
+    }
+}
+
+// Intermediate result: constants stay scalars until combined with a candle
+enum Value {
+    Scalar(f64),
+    Series(Candle),
+}
+
+impl Value {
+    fn apply(
+        op: BinOp,
+        lhs: Value,
+        rhs: Value,
+        semantics: CompositeSemantics,
+        epsilon: f64,
+        tolerance: u64,
+    ) -> Result<Value, ServerError> {
+        let bound = semantics == CompositeSemantics::Bound;
+        // A divisor whose range comes near zero has no bound
+        let unbounded = |candle: Candle| Candle {
+            h: f64::NAN,
+            l: f64::NAN,
+            ..candle
+        };
+        let series = match (lhs, rhs) {
+            (Value::Series(lhs), Value::Series(rhs)) => {
+                let (lhs, rhs) = lhs.paired(rhs, tolerance)?;
+                let series = match op {
+                    BinOp::Add => lhs.add(rhs),
+                    BinOp::Sub => lhs.sub(rhs),
+                    BinOp::Mul => lhs.mul(rhs),
+                    BinOp::Div => lhs.div_within(rhs, epsilon),
+                }?;
+                if !bound {
+                    series
+                } else if op == BinOp::Div && rhs.spans_zero(epsilon) {
+                    unbounded(series)
+                } else {
+                    series.bounded(&lhs, &rhs, |a, b| op.apply(a, b))
+                }
+            }
+            (Value::Series(lhs), Value::Scalar(k)) => match op {
+                BinOp::Add => lhs.map_prices(|p| p + k),
+                BinOp::Sub => lhs.map_prices(|p| p - k),
+                BinOp::Mul => lhs.map_prices(|p| p * k),
+                BinOp::Div => lhs.map_prices(|p| candle::quotient(p, k, epsilon)),
+            },
+            (Value::Scalar(k), Value::Series(rhs)) => match op {
+                BinOp::Add => rhs.map_prices(|p| k + p),
+                BinOp::Sub => rhs.map_prices(|p| k - p),
+                BinOp::Mul => rhs.map_prices(|p| k * p),
+                BinOp::Div if bound && rhs.spans_zero(epsilon) => {
+                    unbounded(rhs.map_prices(|p| candle::quotient(k, p, epsilon)))
+                }
+                BinOp::Div => rhs.map_prices(|p| candle::quotient(k, p, epsilon)),
+            },
+            (Value::Scalar(lhs), Value::Scalar(rhs)) => {
+                return Ok(Value::Scalar(match op {
+                    BinOp::Add => lhs + rhs,
+                    BinOp::Sub => lhs - rhs,
+                    BinOp::Mul => lhs * rhs,
+                    BinOp::Div if rhs == 0.0 => return Err(ServerError::DivisionByZero),
+                    BinOp::Div => lhs / rhs,
+                }))
+            }
+        };
+
+        Ok(Value::Series(series.normalized()))
+    }
+}
+
+// Recursive descent over `sum := product (('+' | '-') product)*`,
+// `product := unary (('*' | '/') unary)*`, `unary := '-' unary | atom`
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_ascii_start().len();
+        self.input[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self, c: char) {
+        self.pos += c.len_utf8();
+    }
+
+    // Character position of byte offset `at`, for error messages
+    fn position(&self, at: usize) -> usize {
+        self.input[..at].chars().count()
+    }
+
+    // Error for `c` at the current position, where an operator or the end
+    // of the expression was expected
+    fn unexpected(&self, c: char) -> ServerError {
+        let position = self.position(self.pos);
+        match c {
+            ')' => ServerError::UnbalancedParentheses { position },
+            // Only a symbol takes an interval, and only one
+            '@' => ServerError::MisplacedInterval { position },
+            '(' | '.' | '_' => ServerError::MissingOperator { position },
+            c if c.is_ascii_alphanumeric() => ServerError::MissingOperator { position },
+            ch => ServerError::InvalidCharacter { ch, position },
+        }
+    }
+
+    // Error for the current position, where an operand was expected but
+    // `next` can't start one
+    fn missing_operand(&self, next: Option<char>) -> ServerError {
+        let position = self.position(self.pos);
+        let before = self.input[..self.pos].trim_ascii_end();
+        // Only ever `(` or an operator, as nothing else precedes an operand
+        let previous = before.chars().next_back();
+        let previous_position = || self.position(before.len()) - 1;
+        match (previous, next) {
+            (None, None) => ServerError::EmptyExpression,
+            (Some('('), None) => ServerError::UnbalancedParentheses {
+                position: previous_position(),
+            },
+            (Some(_), None) => ServerError::DanglingOperator {
+                position: previous_position(),
+            },
+            (None, Some(')')) => ServerError::UnbalancedParentheses { position },
+            (Some('('), Some(')')) => ServerError::EmptyOperand { position },
+            (Some(_), Some(')')) => ServerError::DanglingOperator {
+                position: previous_position(),
+            },
+            (None | Some('('), Some('+' | '*' | '/')) => ServerError::DanglingOperator { position },
+            (Some(_), Some('+' | '*' | '/')) => ServerError::EmptyOperand { position },
+            (_, Some('@')) => ServerError::MisplacedInterval { position },
+            (_, Some(ch)) => ServerError::InvalidCharacter { ch, position },
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expr, ServerError> {
+        let mut lhs = self.product()?;
+        loop {
+            let (op, c) = match self.peek() {
+                Some(c @ '+') => (BinOp::Add, c),
+                Some(c @ '-') => (BinOp::Sub, c),
+                _ => return Ok(lhs),
+            };
+            self.bump(c);
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expr, ServerError> {
+        let mut lhs = self.unary()?;
+        loop {
+            let (op, c) = match self.peek() {
+                Some(c @ '*') => (BinOp::Mul, c),
+                Some(c @ '/') => (BinOp::Div, c),
+                _ => return Ok(lhs),
+            };
+            self.bump(c);
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ServerError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(ServerError::NestingTooDeep);
+        }
+
+        let expr = match self.peek() {
+            Some('-') => {
+                self.bump('-');
+                Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))
+            }
+            Some('(') => {
+                let open = self.position(self.pos);
+                self.bump('(');
+                let inner = self.sum()?;
+                match self.peek() {
+                    Some(')') => self.bump(')'),
+                    Some(c) => return Err(self.unexpected(c)),
+                    None => return Err(ServerError::UnbalancedParentheses { position: open }),
+                }
+                inner
+            }
+            Some(_) => self.operand()?,
+            None => return Err(self.missing_operand(None)),
+        };
+
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn operand(&mut self) -> Result<Expr, ServerError> {
+        let rest = &self.input[self.pos..];
+        let prefix = OPERAND_PREFIXES
+            .into_iter()
+            .find(|prefix| {
+                rest.get(..prefix.len())
+                    .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+            })
+            .unwrap_or("");
+        self.pos += prefix.len();
+        let rest = &self.input[self.pos..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+            .unwrap_or(rest.len());
+        let word = &rest[..len];
+        let numeric = word.chars().all(|c| c.is_ascii_digit() || c == '.');
+        if !prefix.is_empty() && numeric {
+            // Only a symbol has an open interest, a premium index or a book
+            return Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: self.position(self.pos) - 1,
+            });
+        }
+        if word.is_empty() {
+            return Err(self.missing_operand(rest.chars().next()));
+        }
+        let start = self.pos;
+        self.pos += len;
+
+        if numeric {
+            word.parse()
+                .map(Expr::Const)
+                .map_err(|_| ServerError::InvalidNumber(word.to_string()))
+        } else if let Some(at) = word.find('.') {
+            Err(ServerError::InvalidCharacter {
+                ch: '.',
+                position: self.position(start + at),
+            })
+        } else if let Some(at) = stray_underscore(word) {
+            Err(ServerError::InvalidCharacter {
+                ch: '_',
+                position: self.position(start + at),
+            })
+        } else {
+            let word = word.to_ascii_lowercase();
+            match self.leg_interval() {
+                Some(interval) => Ok(Expr::Symbol(format!("{}{}@{}", prefix, word, interval?))),
+                None => Ok(Expr::Symbol(format!("{}{}", prefix, word))),
+            }
+        }
+    }
+
+    // A symbol's own `@interval` right after it, if it has one
+    fn leg_interval(&mut self) -> Option<Result<&str, ServerError>> {
+        let rest = self.input[self.pos..].strip_prefix('@')?;
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        if len == 0 {
+            return Some(Err(ServerError::InvalidCharacter {
+                ch: '@',
+                position: self.position(self.pos),
+            }));
+        }
+        let interval = &self.input[self.pos + 1..self.pos + 1 + len];
+        self.pos += 1 + len;
+        Some(Ok(interval))
+    }
+}
+
+// Token-level form of an expression, for callers that work on the RPN
+// directly; the server evaluates `Expr` trees. Operands are indices into the
+// `SymbolTable` of the same `Tokens`, so tokens are `Copy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Token {
+    Operator(Operator),
+    Operand(usize),
+    LeftParenthesis,
+    RightParenthesis,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+}
+
+impl TryFrom<char> for Operator {
+    type Error = ServerError;
+
+    fn try_from(value: char) -> Result<Self, Self::Error> {
+        match value {
+            '+' => Ok(Operator::Plus),
+            '-' => Ok(Operator::Minus),
+            '*' => Ok(Operator::Multiply),
+            '/' => Ok(Operator::Divide),
+            // The caller knows where `value` was and should say so
+            ch => Err(ServerError::InvalidCharacter { ch, position: 0 }),
+        }
+    }
+}
+
+impl std::fmt::Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let symbol = match self {
+            Operator::Plus => '+',
+            Operator::Minus => '-',
+            Operator::Multiply => '*',
+            Operator::Divide => '/',
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Stream names of an expression's operands, each stored once; operand
+/// tokens refer to them by index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolTable(Vec<String>);
+
+impl SymbolTable {
+    pub fn intern(&mut self, name: &str) -> usize {
+        match self.0.iter().position(|known| known == name) {
+            Some(id) => id,
+            None => {
+                self.0.push(name.to_string());
+                self.0.len() - 1
+            }
+        }
+    }
+
+    pub fn name(&self, id: usize) -> &str {
+        &self.0[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+}
+
+/// A token sequence, infix or RPN, with the table its operands index into.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tokens {
+    pub tokens: Vec<Token>,
+    pub symbols: SymbolTable,
+}
+
+impl std::fmt::Display for Tokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for token in &self.tokens {
+            match token {
+                Token::Operator(op) => write!(f, "{}", op)?,
+                Token::Operand(id) => write!(f, "{}", self.symbols.name(*id))?,
+                Token::LeftParenthesis => write!(f, "(")?,
+                Token::RightParenthesis => write!(f, ")")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Splits `expression@interval` into infix tokens, skipping ASCII whitespace
+/// between them as `Expr::parse` does. Symbols are ASCII letters and digits,
+/// their parts joined by single underscores as in `btcusdt_240927`.
+/// Positions in errors count characters.
+pub fn parse(input: &str) -> Result<Tokens, ServerError> {
+    let mut parsed = Tokens::default();
+    let mut current_operand = String::new();
+    let divider_index = input.rfind('@').ok_or(ServerError::MissingIntervalSuffix)?;
+    let default_interval = input[(divider_index + 1)..].trim_ascii();
+    // Whitespace ended the last operand, so another can't start before an
+    // operator, as in `btc usdt`
+    let mut after_gap = false;
+    // Position the operand being read starts at
+    let mut start = 0;
+
+    let push_operand = |parsed: &mut Tokens, operand: &mut String, start: usize| {
+        if operand.is_empty() {
+            return Ok(());
+        }
+        let symbol = operand
+            .split_once('@')
+            .map_or(&**operand, |(symbol, _)| symbol);
+        if let Some(at) = stray_underscore(symbol) {
+            return Err(ServerError::InvalidCharacter {
+                ch: '_',
+                position: start + at,
+            });
+        }
+        // A symbol may have its own interval, e.g. `ethusdt@1h`
+        *operand = match operand.split_once('@') {
+            Some((symbol, interval)) => kline_stream(symbol, interval),
+            None => kline_stream(operand, default_interval),
+        };
+        let id = parsed.symbols.intern(operand);
+        parsed.tokens.push(Token::Operand(id));
+        operand.clear();
+        Ok(())
+    };
+
+    for (position, c) in input[..divider_index].chars().enumerate() {
+        if c.is_ascii_whitespace() {
+            after_gap |= !current_operand.is_empty();
+            push_operand(&mut parsed, &mut current_operand, start)?;
+            continue;
+        }
+        match c {
+            '+' | '-' | '*' | '/' => {
+                push_operand(&mut parsed, &mut current_operand, start)?;
+                parsed.tokens.push(Token::Operator(c.try_into()?));
+            }
+            '(' => parsed.tokens.push(Token::LeftParenthesis),
+            ')' => {
+                push_operand(&mut parsed, &mut current_operand, start)?;
+                parsed.tokens.push(Token::RightParenthesis);
+            }
+            '@' if current_operand.is_empty() || current_operand.contains('@') => {
+                return Err(ServerError::MisplacedInterval { position });
+            }
+            _ if after_gap && (c.is_ascii_alphanumeric() || c == '_') => {
+                return Err(ServerError::MissingOperator { position });
+            }
+            _ => {
+                if c.is_ascii_alphanumeric() || c == '@' || c == '_' {
+                    if current_operand.is_empty() {
+                        start = position;
+                    }
+                    current_operand.push(c);
+                } else {
+                    return Err(ServerError::InvalidCharacter { ch: c, position });
+                }
+            }
+        }
+        after_gap = false;
+    }
+    push_operand(&mut parsed, &mut current_operand, start)?;
+
+    Ok(parsed)
+}
+
+/// Reorders infix `tokens` into RPN. Positions in errors are token indices.
+pub fn to_rpn(tokens: &Tokens) -> Result<Tokens, ServerError> {
+    let mut rpn = Vec::with_capacity(tokens.tokens.len());
+    let mut stack: Vec<Token> = Vec::new();
+    // Indices of the parentheses still open
+    let mut open_brackets = Vec::new();
+
+    let precedence = |t: &Token| match t {
+        Token::Operator(op) => match op {
+            Operator::Plus | Operator::Minus => 1,
+            Operator::Multiply | Operator::Divide => 2,
+        },
+        Token::LeftParenthesis => 0,
+        _ => usize::MAX,
+    };
+
+    for (position, &token) in tokens.tokens.iter().enumerate() {
+        match token {
+            Token::Operator(_) => {
+                while let Some(last) = stack.last() {
+                    if precedence(&token) <= precedence(last) {
+                        rpn.push(stack.pop().unwrap());
+                    } else {
+                        break;
+                    }
+                }
+                stack.push(token);
+            }
+            Token::LeftParenthesis => {
+                stack.push(token);
+                open_brackets.push(position);
+            }
+            Token::RightParenthesis => {
+                if open_brackets.pop().is_none() {
+                    return Err(ServerError::UnbalancedParentheses { position });
+                }
+                while let Some(top) = stack.pop() {
+                    if matches!(top, Token::LeftParenthesis) {
+                        break;
+                    }
+                    rpn.push(top);
+                }
+            }
+            Token::Operand(_) => rpn.push(token),
+        }
+    }
+
+    if let Some(&position) = open_brackets.last() {
+        return Err(ServerError::UnbalancedParentheses { position });
+    }
+
+    while let Some(op) = stack.pop() {
+        rpn.push(op);
+    }
+
+    Ok(Tokens {
+        tokens: rpn,
+        symbols: tokens.symbols.clone(),
+    })
+}
+
+/// Evaluates an RPN expression, looking operands up by their stream name.
+pub fn evaluate_rpn(
+    rpn: &Tokens,
+    candles: &HashMap<String, Candle>,
+) -> Result<Candle, ServerError> {
+    if let Some(missing) = rpn.symbols.iter().find(|name| !candles.contains_key(*name)) {
+        return Err(ServerError::KeyNotFound(missing.to_string()));
+    }
+    let dense: Vec<Option<Candle>> = rpn
+        .symbols
+        .iter()
+        .map(|name| candles.get(name).copied())
+        .collect();
+    evaluate_dense(&rpn.tokens, &dense, &mut Vec::new())
+}
+
+/// Evaluates an RPN expression against candles indexed by symbol id. `stack`
+/// is scratch space, cleared first, so a caller evaluating every bar can
+/// reuse one and avoid allocating. Positions in errors are token indices.
+pub fn evaluate_dense(
+    rpn: &[Token],
+    candles: &[Option<Candle>],
+    stack: &mut Vec<Candle>,
+) -> Result<Candle, ServerError> {
+    stack.clear();
+
+    for (position, token) in rpn.iter().enumerate() {
+        match token {
+            Token::Operand(id) => {
+                let candle = candles
+                    .get(*id)
+                    .copied()
+                    .flatten()
+                    .ok_or_else(|| ServerError::KeyNotFound(format!("leg {}", id)))?;
+                stack.push(candle);
+            }
+            Token::Operator(op) => {
+                let dangling = || ServerError::DanglingOperator { position };
+                let rhs = stack.pop().ok_or_else(dangling)?;
+                let lhs = stack.pop().ok_or_else(dangling)?;
+                let result = match op {
+                    Operator::Plus => lhs.add(rhs),
+                    Operator::Minus => lhs.sub(rhs),
+                    Operator::Multiply => lhs.mul(rhs),
+                    Operator::Divide => lhs.div(rhs),
+                }?;
+                stack.push(result);
+            }
+            // Parentheses never survive `to_rpn`
+            _ => return Err(ServerError::UnbalancedParentheses { position }),
+        }
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(result), true) => Ok(result),
+        (Some(_), false) => Err(ServerError::MissingOperator {
+            position: rpn.len(),
+        }),
+        (None, _) => Err(ServerError::EmptyExpression),
+    }
+}
+
+// Expression tree used only to build canonical keys: chains of `+` are
+// flattened so their operands can be sorted, and the two operands of a `*`
+// are sorted. Products aren't flattened, as regrouping one can change its
+// result, e.g. `(btcusdt*ethusdt)*-2` != `btcusdt*(ethusdt*-2)`.
+enum KeyNode {
+    Leaf(String),
+    Neg(Box<KeyNode>),
+    Chain(BinOp, Vec<KeyNode>),
+}
+
+impl KeyNode {
+    fn from_expr(expr: &Expr) -> KeyNode {
+        match expr {
+            Expr::Symbol(symbol) => KeyNode::Leaf(symbol.clone()),
+            Expr::Const(value) => KeyNode::Leaf(value.to_string()),
+            Expr::Unary(UnaryOp::Neg, operand) => {
+                KeyNode::Neg(Box::new(KeyNode::from_expr(operand)))
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                KeyNode::join(*op, KeyNode::from_expr(lhs), KeyNode::from_expr(rhs))
+            }
+        }
+    }
+
+    fn precedence(&self) -> usize {
+        match self {
+            KeyNode::Leaf(_) => usize::MAX,
+            KeyNode::Neg(_) => 3,
+            KeyNode::Chain(BinOp::Add | BinOp::Sub, _) => 1,
+            KeyNode::Chain(_, _) => 2,
+        }
+    }
+
+    fn join(op: BinOp, lhs: KeyNode, rhs: KeyNode) -> KeyNode {
+        let mut operands = Vec::new();
+        for node in [lhs, rhs] {
+            match node {
+                KeyNode::Chain(BinOp::Add, children) if op == BinOp::Add => {
+                    operands.extend(children)
+                }
+                other => operands.push(other),
+            }
+        }
+        KeyNode::Chain(op, operands)
+    }
+
+    fn render(&self) -> String {
+        match self {
+            KeyNode::Leaf(leaf) => leaf.clone(),
+            KeyNode::Neg(operand) if operand.precedence() < self.precedence() => {
+                format!("-({})", operand.render())
+            }
+            KeyNode::Neg(operand) => format!("-{}", operand.render()),
+            KeyNode::Chain(op, children) => {
+                let mut parts = children
+                    .iter()
+                    .enumerate()
+                    .map(|(i, child)| {
+                        // The right operand of `-` and `/` needs parentheses
+                        // even at equal precedence: a-(b-c) != a-b-c. So
+                        // does any of `+` and `*`, whose operands are
+                        // sorted: a `-` in a `+` would otherwise key
+                        // (a-b)+c like (c+a)-b, a different grouping.
+                        let strict = matches!(op, BinOp::Add | BinOp::Mul)
+                            || (i > 0 && matches!(op, BinOp::Sub | BinOp::Div));
+                        let needs_parens = child.precedence() < self.precedence()
+                            || (strict && child.precedence() == self.precedence());
+                        if needs_parens {
+                            format!("({})", child.render())
+                        } else {
+                            child.render()
+                        }
+                    })
+                    .collect::<Vec<String>>();
+
+                if matches!(op, BinOp::Add | BinOp::Mul) {
+                    parts.sort();
+                }
+                parts.join(&op.to_string())
+            }
+        }
+    }
+}
+
+/// Builds the key a stream expression is registered under, so equivalent
+/// expressions share one subscription: the expression is simplified, symbols
+/// are lowercased and operands of `+` and `*` are sorted, e.g.
+/// `ETHUSDT+btcusdt*1@1m` -> `btcusdt+ethusdt@1m`.
+pub fn canonical_key(input: &str) -> Result<String, ServerError> {
+    let (expr, interval) = Expr::parse(input)?;
+    Ok(format!(
+        "{}@{}",
+        KeyNode::from_expr(&expr.simplify()?).render(),
+        interval
+    ))
+}
+
+/// Named expressions set with `DEFINE`, which stream expressions can use
+/// like symbols, e.g. `l1index-btcusdt@1m`. A name keeps the expression it
+/// was first defined as, so subscriptions using it never change meaning,
+/// and can't be that of a market symbol, which it would stand in for.
+#[derive(Clone, Debug, Default)]
+pub struct Definitions(BTreeMap<String, Expr>);
+
+impl Definitions {
+    /// Defines `name`, case-insensitively, as `expr`, which has no interval
+    /// and may use earlier definitions. Repeating a definition unchanged is
+    /// a no-op; changing one is refused.
+    pub fn define(&mut self, name: &str, expr: &str) -> Result<(), ServerError> {
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(ServerError::InvalidDefinition(format!(
+                "name {:?} is not a letter followed by letters and digits",
+                name
+            )));
+        }
+        let name = name.to_lowercase();
+        if is_pair(&name) {
+            return Err(ServerError::InvalidDefinition(format!(
+                "name {} reads as a market symbol",
+                name
+            )));
+        }
+        let expr = Expr::parse_expression(expr)?;
+        if let Some(defined) = self.0.get(&name) {
+            return if *defined == expr {
+                Ok(())
+            } else {
+                Err(ServerError::NameTaken(name))
+            };
+        }
+
+        self.0.insert(name.clone(), expr);
+        // Refused if it closes a cycle through names defined before it
+        let resolved = self.resolve(&Expr::Symbol(name.clone()), &mut Vec::new());
+        if resolved.is_err() {
+            self.0.remove(&name);
+        }
+        resolved.map(|_| ())
+    }
+
+    /// Names and their expressions, sorted by name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Expr)> {
+        self.0.iter().map(|(name, expr)| (name.as_str(), expr))
+    }
+
+    /// `stream` with every defined name replaced by its expression, or
+    /// unchanged when it uses none.
+    pub fn expand(&self, stream: &str) -> Result<String, ServerError> {
+        let (expr, interval) = Expr::parse(stream)?;
+        let resolved = self.resolve(&expr, &mut Vec::new())?;
+        Ok(if resolved == expr {
+            stream.to_string()
+        } else {
+            format!("{}@{}", resolved, interval)
+        })
+    }
+
+    // `expanding` holds the names whose expressions enclose `expr`
+    fn resolve(&self, expr: &Expr, expanding: &mut Vec<String>) -> Result<Expr, ServerError> {
+        Ok(match expr {
+            Expr::Symbol(symbol) => {
+                let name = symbol.clone();
+                let Some(defined) = self.0.get(&name) else {
+                    return Ok(expr.clone());
+                };
+                if expanding.contains(&name) {
+                    expanding.push(name);
+                    return Err(ServerError::InvalidDefinition(format!(
+                        "cycle {}",
+                        expanding.join(" -> ")
+                    )));
+                }
+                if expanding.len() == MAX_DEFINITION_DEPTH {
+                    return Err(ServerError::InvalidDefinition(format!(
+                        "{} nests definitions more than {} deep",
+                        expanding[0], MAX_DEFINITION_DEPTH
+                    )));
+                }
+                expanding.push(name);
+                let resolved = self.resolve(defined, expanding)?;
+                expanding.pop();
+                resolved
+            }
+            Expr::Const(_) => expr.clone(),
+            Expr::Unary(op, operand) => {
+                Expr::Unary(*op, Box::new(self.resolve(operand, expanding)?))
+            }
+            Expr::Binary(op, lhs, rhs) => Expr::Binary(
+                *op,
+                Box::new(self.resolve(lhs, expanding)?),
+                Box::new(self.resolve(rhs, expanding)?),
+            ),
+        })
+    }
+}
+
+// Whether lowercase `name` is a base asset followed by a quote asset
+fn is_pair(name: &str) -> bool {
+    QUOTE_ASSETS.iter().any(|quote| {
+        name.strip_suffix(quote)
+            .is_some_and(|base| !base.is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests_definitions {
+    use super::{canonical_key, Definitions};
+    use crate::error::ServerError;
+
+    #[test]
+    fn test_names_expand_in_streams() {
+        let mut definitions = Definitions::default();
+        definitions
+            .define("L1index", "btcusdt*0.6+ethusdt*0.4")
+            .unwrap();
+        assert_eq!(
+            definitions.expand("l1index-btcusdt@1m").unwrap(),
+            "btcusdt * 0.6 + ethusdt * 0.4 - btcusdt@1m"
+        );
+        assert_eq!(
+            definitions.expand("l1index*2@5m").unwrap(),
+            "(btcusdt * 0.6 + ethusdt * 0.4) * 2@5m"
+        );
+        // Streams without names are left as spelled
+        assert_eq!(
+            definitions.expand("ETHUSDT+btcusdt@1m").unwrap(),
+            "ETHUSDT+btcusdt@1m"
+        );
+        assert_eq!(
+            canonical_key(&definitions.expand("L1INDEX@1m").unwrap()).unwrap(),
+            canonical_key("ethusdt*0.4+btcusdt*0.6@1m").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_definitions_build_on_each_other() {
+        let mut definitions = Definitions::default();
+        definitions.define("majors", "btcusdt+ethusdt").unwrap();
+        definitions.define("spread", "majors-solusdt").unwrap();
+        assert_eq!(
+            definitions.expand("spread@1m").unwrap(),
+            "btcusdt + ethusdt - solusdt@1m"
+        );
+        let names: Vec<&str> = definitions.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["majors", "spread"]);
+    }
+
+    #[test]
+    fn test_redefinition_is_refused() {
+        let mut definitions = Definitions::default();
+        definitions.define("pair", "btcusdt/ethusdt").unwrap();
+        // The same expression again, however spelled, is fine
+        definitions.define("PAIR", "btcusdt / ethusdt").unwrap();
+        assert!(matches!(
+            definitions.define("pair", "ethusdt/btcusdt"),
+            Err(ServerError::NameTaken(name)) if name == "pair"
+        ));
+        assert_eq!(
+            definitions.expand("pair@1m").unwrap(),
+            "btcusdt / ethusdt@1m"
+        );
+    }
+
+    #[test]
+    fn test_cycles_and_deep_nesting_are_refused() {
+        let mut definitions = Definitions::default();
+        definitions.define("a", "b+btcusdt").unwrap();
+        let error = definitions.define("b", "a*2").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid definition: cycle b -> a -> b");
+        assert!(definitions.define("c", "c+ethusdt").is_err());
+        assert_eq!(definitions.iter().count(), 1);
+
+        let mut definitions = Definitions::default();
+        definitions.define("level0", "btcusdt").unwrap();
+        for level in 1..8 {
+            let expr = format!("level{}+ethusdt", level - 1);
+            definitions
+                .define(&format!("level{}", level), &expr)
+                .unwrap();
+        }
+        assert!(matches!(
+            definitions.define("level8", "level7+ethusdt"),
+            Err(ServerError::InvalidDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        let mut definitions = Definitions::default();
+        assert!(definitions.define("9lives", "btcusdt").is_err());
+        assert!(definitions.define("l1-index", "btcusdt").is_err());
+        assert!(definitions.define("idx", "btcusdt@").is_err());
+        assert!(matches!(
+            definitions.define("idx", "2*3"),
+            Err(ServerError::NoSymbol)
+        ));
+    }
+
+    #[test]
+    fn test_names_of_market_symbols_are_refused() {
+        let mut definitions = Definitions::default();
+        for name in ["btcusdt", "BTCUSDT", "ethbtc", "solfdusd", "btceur"] {
+            assert!(
+                matches!(
+                    definitions.define(name, "ethusdt*1000"),
+                    Err(ServerError::InvalidDefinition(_))
+                ),
+                "{}",
+                name
+            );
+        }
+        assert_eq!(definitions.expand("btcusdt@1m").unwrap(), "btcusdt@1m");
+        // A quote asset alone is no pair
+        definitions.define("usdt", "usdcusdt").unwrap();
+        definitions.define("majors", "btcusdt+ethusdt").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests_parse {
+    use super::{kline_stream, Expr, ServerError};
+
+    fn streams(input: &str) -> Vec<String> {
+        let (expr, interval) = Expr::parse(input).unwrap();
+        expr.symbols()
+            .map(|symbol| interval.stream(symbol))
+            .collect()
+    }
+
+    #[test]
+    fn test_symbols_single_token() {
+        assert_eq!(streams("btcusdt@1m"), vec!["btcusdt@kline_1m"]);
+    }
+
+    #[test]
+    fn test_symbols_multiple_tokens() {
+        let expected = vec!["btcusdt@kline_1h", "ethusdt@kline_1h"];
+        assert_eq!(streams("btcusdt+ethusdt@1h"), expected);
+    }
+
+    #[test]
+    fn test_symbols_with_operations() {
+        let expected = vec!["btcusdt@kline_1d", "ethusdt@kline_1d", "bnbusdt@kline_1d"];
+        assert_eq!(streams("(btcusdt-ethusdt)*bnbusdt@1d"), expected);
+    }
+
+    #[test]
+    fn test_symbols_skip_constants() {
+        let expected = vec!["btcusdt@kline_1m", "btcusdt@kline_1m"];
+        assert_eq!(streams("-btcusdt*2+btcusdt/0.5@1m"), expected);
+    }
+
+    #[test]
+    fn test_legs_with_own_intervals() {
+        assert_eq!(
+            streams("btcusdt@1m/ethusdt@1h"),
+            vec!["btcusdt@kline_1m", "ethusdt@kline_1h"]
+        );
+        assert_eq!(
+            streams("(btcusdt - ethusdt@4h) * 2@15m"),
+            vec!["btcusdt@kline_15m", "ethusdt@kline_4h"]
+        );
+
+        // A leg at the default interval is the same as one without it
+        let (expr, interval) = Expr::parse("btcusdt@1m+ethusdt@1h@1m").unwrap();
+        assert_eq!(expr.to_string(), "btcusdt + ethusdt@1h");
+        assert_eq!(expr.intervals(&interval), ["1h", "1m"]);
+        let (expr, interval) = Expr::parse("btcusdt@1h@1m").unwrap();
+        assert_eq!(expr.intervals(&interval), ["1h"]);
+
+        // Months vary in length, so they only work for every leg at once
+        assert!(Expr::parse("btcusdt@1M@1M").is_ok());
+        assert!(matches!(
+            Expr::parse("btcusdt@1M/ethusdt@1d"),
+            Err(ServerError::InvalidInterval(interval)) if interval == "1M"
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt@1x/ethusdt@1d"),
+            Err(ServerError::InvalidInterval(interval)) if interval == "1x"
+        ));
+        assert!(matches!(
+            Expr::parse("2@1h*btcusdt@1m"),
+            Err(ServerError::MisplacedInterval { position: 1 })
+        ));
+    }
+
+    #[test]
+    fn test_continuous_contract_operands() {
+        assert_eq!(
+            streams("btcusdt_current_quarter-btcusdt_perpetual@1h"),
+            [
+                "btcusdt_current_quarter@continuousKline_1h",
+                "btcusdt_perpetual@continuousKline_1h"
+            ]
+        );
+        // Mixed with a regular symbol, and only known contract types count
+        assert_eq!(
+            streams("btcusdt_perpetual/btcusdt_next_quarter/ethusdt/btc_foo@1m"),
+            [
+                "btcusdt_perpetual@continuousKline_1m",
+                "btcusdt_next_quarter@continuousKline_1m",
+                "ethusdt@kline_1m",
+                "btc_foo@kline_1m"
+            ]
+        );
+        assert_eq!(kline_stream("_perpetual", "1m"), "_perpetual@kline_1m");
+    }
+
+    #[test]
+    fn test_open_interest_operands() {
+        assert_eq!(
+            streams("oi:btcusdt/btcusdt@5m"),
+            vec!["btcusdt@openInterest_5m", "btcusdt@kline_5m"]
+        );
+        assert_eq!(
+            streams("OI:ethusdt@1h - ethusdt@1m"),
+            vec!["ethusdt@openInterest_1h", "ethusdt@kline_1m"]
+        );
+        let (expr, _) = Expr::parse("oi:btcusdt / btcusdt@5m").unwrap();
+        assert_eq!(expr.to_string(), "oi:btcusdt / btcusdt");
+        assert_eq!(
+            super::canonical_key("OI:BTCUSDT/btcusdt@5m").unwrap(),
+            "oi:btcusdt/btcusdt@5m"
+        );
+
+        assert!(matches!(
+            Expr::parse("oi:2*btcusdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: 2
+            })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt*oi:@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: 10
+            })
+        ));
+        assert!(matches!(
+            Expr::parse("ab:btcusdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn test_ticker_stats_pseudo_interval() {
+        assert_eq!(streams("btcusdt@ticker24h"), vec!["btcusdt@ticker"]);
+        assert_eq!(super::stream_interval("btcusdt@ticker"), "ticker24h");
+        assert!(!super::is_kline_stream("btcusdt@ticker"));
+        assert!(super::is_kline_stream("btcusdt@premiumIndexKline_1m"));
+
+        // Relayed for one plain symbol, with nothing to evaluate
+        for input in [
+            "btcusdt-ethusdt@ticker24h",
+            "2*btcusdt@ticker24h",
+            "oi:btcusdt@ticker24h",
+            "btcusdt@ticker24h-ethusdt@1m",
+        ] {
+            assert!(
+                matches!(Expr::parse(input), Err(ServerError::InvalidInterval(_))),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_ticker_pseudo_interval() {
+        assert_eq!(
+            streams("btcusdt-ethusdt@ticker"),
+            vec!["btcusdt@miniTicker", "ethusdt@miniTicker"]
+        );
+        assert_eq!(super::stream_interval("btcusdt@miniTicker"), "ticker");
+        assert_eq!(super::stream_interval("btcusdt@openInterest_5m"), "5m");
+        assert_eq!(
+            super::stream_interval("btcusdt_perpetual@continuousKline_1h"),
+            "1h"
+        );
+
+        // Only over plain symbols, and not mixed with klines
+        for input in [
+            "oi:btcusdt@ticker",
+            "premium:btcusdt@ticker",
+            "btcusdt_perpetual@ticker",
+            "btcusdt@ticker-ethusdt@1m",
+            "btcusdt-ethusdt@ticker@1m",
+        ] {
+            assert!(
+                matches!(Expr::parse(input), Err(ServerError::InvalidInterval(_))),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_mid_price_operands() {
+        assert_eq!(
+            streams("btcusdt-mid:btcusdt@1m"),
+            vec!["btcusdt@kline_1m", "btcusdt@midKline_1m"]
+        );
+        assert_eq!(
+            super::canonical_key("MID:BTCUSDT-btcusdt@1m").unwrap(),
+            "mid:btcusdt-btcusdt@1m"
+        );
+        assert_eq!(super::stream_interval("btcusdt@midKline_1m"), "1m");
+        assert!(!super::is_kline_stream("btcusdt@midKline_1m"));
+        assert!(matches!(
+            Expr::parse("mid:1@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: 3
+            })
+        ));
+    }
+
+    #[test]
+    fn test_premium_index_operands() {
+        assert_eq!(
+            streams("premium:btcusdt*10000@1m"),
+            vec!["btcusdt@premiumIndexKline_1m"]
+        );
+        assert_eq!(
+            streams("Premium:ethusdt@1h - oi:ethusdt@1m"),
+            vec!["ethusdt@premiumIndexKline_1h", "ethusdt@openInterest_1m"]
+        );
+        assert_eq!(
+            super::canonical_key("PREMIUM:BTCUSDT*10000@1m").unwrap(),
+            "10000*premium:btcusdt@1m"
+        );
+        assert!(matches!(
+            Expr::parse("premium:0.5*btcusdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: ':',
+                position: 7
+            })
+        ));
+
+        assert!(super::is_futures_only("btcusdt@premiumIndexKline_1m"));
+        assert!(super::is_futures_only(
+            "btcusdt_perpetual@continuousKline_1m"
+        ));
+        assert!(super::is_futures_only("btcusdt_240927@kline_1m"));
+        assert!(!super::is_futures_only("btcusdt_24092@kline_1m"));
+        assert!(!super::is_futures_only("btcusdt@kline_1m"));
+        assert!(!super::is_futures_only("btcusdt@openInterest_1m"));
+    }
+
+    #[test]
+    fn test_misplaced_intervals() {
+        let misplaced = |input: &str| match Expr::parse(input) {
+            Err(ServerError::MisplacedInterval { position }) => Some(position),
+            _ => None,
+        };
+        // A leg takes one interval, the trailing one is the default
+        assert_eq!(misplaced("btcusdt@1m@5m+ethusdt@1h"), Some(10));
+        assert_eq!(misplaced("btcusdt@1m+ethusdt@5m@1h@1d"), Some(21));
+        // A group or the expression before the end takes none
+        assert_eq!(misplaced("(btcusdt+ethusdt)@1m*2@5m"), Some(17));
+        assert_eq!(misplaced("((btcusdt)@1m)@5m"), Some(10));
+        assert_eq!(misplaced("(btcusdt+@1m)@5m"), Some(9));
+        assert!(Expr::parse("(btcusdt@1m+ethusdt)@5m").is_ok());
+        // Nor does nothing
+        assert_eq!(misplaced("@btcusdt@1m"), Some(0));
+        assert_eq!(misplaced("@1m@5m"), Some(0));
+        assert!(matches!(
+            Expr::parse("@1m"),
+            Err(ServerError::EmptyExpression)
+        ));
+    }
+
+    #[test]
+    fn test_basket_streams() {
+        let (expr, interval) = Expr::parse(
+            "(btcusdt*0.25 + ethusdt*0.15 + solusdt*0.1 + bnbusdt*0.1 + xrpusdt*0.08 \
+             + adausdt*0.07 + dogeusdt*0.06 + avaxusdt*0.05 + dotusdt*0.05 \
+             + linkusdt*0.04 + ltcusdt*0.03 + btcusdt*0.02)@1m",
+        )
+        .unwrap();
+        assert_eq!(expr.symbols().count(), 12);
+        assert_eq!(
+            expr.streams(&interval),
+            [
+                "adausdt@kline_1m",
+                "avaxusdt@kline_1m",
+                "bnbusdt@kline_1m",
+                "btcusdt@kline_1m",
+                "dogeusdt@kline_1m",
+                "dotusdt@kline_1m",
+                "ethusdt@kline_1m",
+                "linkusdt@kline_1m",
+                "ltcusdt@kline_1m",
+                "solusdt@kline_1m",
+                "xrpusdt@kline_1m",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_empty_operand() {
+        assert!(matches!(
+            Expr::parse("btcusdt++ethusdt@1m"),
+            Err(ServerError::EmptyOperand { position: 8 })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt+@1m"),
+            Err(ServerError::DanglingOperator { position: 7 })
+        ));
+        assert!(matches!(
+            Expr::parse("*btcusdt@1m"),
+            Err(ServerError::DanglingOperator { position: 0 })
+        ));
+        assert!(matches!(
+            Expr::parse("(btcusdt- )@1m"),
+            Err(ServerError::DanglingOperator { position: 8 })
+        ));
+        assert!(matches!(
+            Expr::parse("()@1m"),
+            Err(ServerError::EmptyOperand { position: 1 })
+        ));
+        assert!(matches!(
+            Expr::parse(" @1m"),
+            Err(ServerError::EmptyExpression)
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt ethusdt@1m"),
+            Err(ServerError::MissingOperator { position: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_symbols_with_underscores() {
+        assert_eq!(
+            streams("BTCUSDT_240927 - btcusdt_perpetual@1h"),
+            vec![
+                "btcusdt_240927@kline_1h",
+                "btcusdt_perpetual@continuousKline_1h"
+            ]
+        );
+        assert_eq!(
+            super::canonical_key("BTCUSDT_240927-btcusdt@1m").unwrap(),
+            "btcusdt_240927-btcusdt@1m"
+        );
+
+        for (input, ch, at) in [
+            ("_btcusdt@1m", '_', 0),
+            ("btcusdt_ + 1@1m", '_', 7),
+            ("ethusdt*btc__usdt@1m", '_', 11),
+            ("btcusdt+ethusdt_@1h@1m", '_', 15),
+            // Symbols are ASCII
+            ("btcusdté@1m", 'é', 7),
+            ("(ethusdt-bitcoin€)@1m", '€', 16),
+        ] {
+            assert!(
+                matches!(
+                    Expr::parse(input),
+                    Err(ServerError::InvalidCharacter { ch: c, position }) if c == ch && position == at
+                ),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_skips_ascii_whitespace() {
+        let plain = Expr::parse("(btcusdt-ethusdt)*2/bnbusdt@1h@1m").unwrap();
+        for input in [
+            "( btcusdt - ethusdt ) * 2 / bnbusdt@1h @ 1m",
+            "\t(btcusdt\t-\tethusdt)*2/bnbusdt@1h@1m\n",
+            "(btcusdt -\n  ethusdt) * 2\r\n/ bnbusdt@1h\n@1m\r\n",
+        ] {
+            assert_eq!(Expr::parse(input).unwrap(), plain, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_whitespace_within_tokens() {
+        for (input, at) in [("btc usdt@1m", 4), ("btc\tusdt + ethusdt@1m", 4)] {
+            assert!(
+                matches!(Expr::parse(input), Err(ServerError::MissingOperator { position }) if position == at),
+                "{:?}",
+                input
+            );
+        }
+        // Only ASCII whitespace is skipped
+        assert!(matches!(
+            Expr::parse("btcusdt\u{a0}+ethusdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: '\u{a0}',
+                position: 7
+            })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt@1 m"),
+            Err(ServerError::InvalidInterval(interval)) if interval == "1 m"
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt @ \n"),
+            Err(ServerError::MissingIntervalSuffix)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests_rpn {
+    use super::{
+        evaluate_dense, evaluate_rpn, parse, to_rpn, Candle, Operator, ServerError, Token,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_accepts_continuous_contracts() {
+        let tokens = parse("btcusdt_current_quarter-btcusdt_perpetual@1h").unwrap();
+        assert_eq!(
+            tokens.symbols.iter().collect::<Vec<_>>(),
+            vec![
+                "btcusdt_current_quarter@continuousKline_1h",
+                "btcusdt_perpetual@continuousKline_1h"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_interns_repeated_symbols() {
+        let tokens = parse("btcusdt*btcusdt-ethusdt@1m").unwrap();
+        assert_eq!(
+            tokens.symbols.iter().collect::<Vec<_>>(),
+            vec!["btcusdt@kline_1m", "ethusdt@kline_1m"]
+        );
+        assert_eq!(
+            tokens.tokens,
+            vec![
+                Token::Operand(0),
+                Token::Operator(Operator::Multiply),
+                Token::Operand(0),
+                Token::Operator(Operator::Minus),
+                Token::Operand(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_legs_with_own_intervals() {
+        let tokens = parse("btcusdt@1m/ethusdt@1h").unwrap();
+        assert_eq!(
+            tokens.symbols.iter().collect::<Vec<_>>(),
+            vec!["btcusdt@kline_1m", "ethusdt@kline_1h"]
+        );
+
+        for (input, at) in [
+            ("btcusdt@1m@5m+ethusdt@1h", 10),
+            ("(btcusdt+ethusdt)@1m*2@5m", 17),
+            ("@btcusdt@1m", 0),
+        ] {
+            assert!(
+                matches!(parse(input), Err(ServerError::MisplacedInterval { position }) if position == at),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_accepts_delivery_contracts() {
+        let tokens = parse("btcusdt_240927-btcusdt@1m").unwrap();
+        assert_eq!(
+            tokens.symbols.iter().collect::<Vec<_>>(),
+            vec!["btcusdt_240927@kline_1m", "btcusdt@kline_1m"]
+        );
+
+        for (input, ch, at) in [
+            ("_btcusdt@1m", '_', 0),
+            ("btcusdt_ + 1@1m", '_', 7),
+            ("ethusdt*btc__usdt@1m", '_', 11),
+            ("btcusdt+ethusdt_@1h@1m", '_', 15),
+            ("btcusdté@1m", 'é', 7),
+        ] {
+            assert!(
+                matches!(
+                    parse(input),
+                    Err(ServerError::InvalidCharacter { ch: c, position }) if c == ch && position == at
+                ),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_skips_ascii_whitespace() {
+        let plain = parse("(btcusdt+ethusdt@1h)*adausdt@1m").unwrap();
+        let spaced = parse("\t( btcusdt +\n ethusdt@1h ) * adausdt @ 1m\r\n").unwrap();
+        assert_eq!(spaced, plain);
+
+        assert!(matches!(
+            parse("btc usdt+ethusdt@1m"),
+            Err(ServerError::MissingOperator { position: 4 })
+        ));
+        assert!(matches!(
+            parse("btcusdt @1h+ethusdt@1m"),
+            Err(ServerError::MisplacedInterval { position: 8 })
+        ));
+        assert!(matches!(
+            parse("btcusdt\u{a0}+ethusdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: '\u{a0}',
+                position: 7
+            })
+        ));
+    }
+
+    #[test]
+    fn test_to_rpn_reorders_ids() {
+        let rpn = to_rpn(&parse("(btcusdt+ethusdt)*adausdt@1m").unwrap()).unwrap();
+        assert_eq!(
+            rpn.tokens,
+            vec![
+                Token::Operand(0),
+                Token::Operand(1),
+                Token::Operator(Operator::Plus),
+                Token::Operand(2),
+                Token::Operator(Operator::Multiply),
+            ]
+        );
+        assert_eq!(
+            rpn.to_string(),
+            "btcusdt@kline_1methusdt@kline_1m+adausdt@kline_1m*"
+        );
+    }
+
+    #[test]
+    fn test_to_rpn_mismatched_parentheses() {
+        assert!(matches!(
+            to_rpn(&parse("(btcusdt+ethusdt*adausdt@1m").unwrap()),
+            Err(ServerError::UnbalancedParentheses { position: 0 })
+        ));
+        assert!(matches!(
+            to_rpn(&parse("btcusdt+ethusdt)*adausdt@1m").unwrap()),
+            Err(ServerError::UnbalancedParentheses { position: 3 })
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_dense_reuses_stack() {
+        let rpn = to_rpn(&parse("btcusdt-ethusdt*btcusdt@1m").unwrap()).unwrap();
+        let candles = [
+            Some(Candle::new(0, 10.0, 10.0, 10.0, 10.0)),
+            Some(Candle::new(0, 2.0, 2.0, 2.0, 2.0)),
+        ];
+        let mut stack = Vec::new();
+
+        for _ in 0..3 {
+            let result = evaluate_dense(&rpn.tokens, &candles, &mut stack).unwrap();
+            assert_eq!(result.c, -10.0);
+        }
+        assert!(stack.is_empty());
+        assert!(matches!(
+            evaluate_dense(&rpn.tokens, &candles[..1], &mut stack),
+            Err(ServerError::KeyNotFound(leg)) if leg == "leg 1"
+        ));
+        assert!(matches!(
+            evaluate_dense(&rpn.tokens, &[candles[0], None], &mut stack),
+            Err(ServerError::KeyNotFound(leg)) if leg == "leg 1"
+        ));
+        assert!(matches!(
+            evaluate_dense(&rpn.tokens[1..], &candles, &mut stack),
+            Err(ServerError::DanglingOperator { position: 3 })
+        ));
+        assert!(matches!(
+            evaluate_dense(&rpn.tokens[..2], &candles, &mut stack),
+            Err(ServerError::MissingOperator { position: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_evaluate_rpn_looks_up_stream_names() {
+        let rpn = to_rpn(&parse("btcusdt/ethusdt@1m").unwrap()).unwrap();
+        let candles = HashMap::from([
+            (
+                "btcusdt@kline_1m".to_string(),
+                Candle::new(0, 10.0, 10.0, 10.0, 10.0),
+            ),
+            (
+                "ethusdt@kline_1m".to_string(),
+                Candle::new(0, 2.0, 2.0, 2.0, 2.0),
+            ),
+        ]);
+        assert_eq!(evaluate_rpn(&rpn, &candles).unwrap().o, 5.0);
+    }
+}
+
+#[cfg(test)]
+mod tests_expr {
+    use super::{BinOp, Expr, ServerError, UnaryOp};
+
+    fn sym(symbol: &str) -> Expr {
+        Expr::Symbol(symbol.into())
+    }
+
+    fn bin(op: BinOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    fn neg(operand: Expr) -> Expr {
+        Expr::Unary(UnaryOp::Neg, Box::new(operand))
+    }
+
+    fn parsed(input: &str) -> Expr {
+        Expr::parse(input).unwrap().0
+    }
+
+    #[test]
+    fn test_parse_simple_expression() {
+        assert_eq!(
+            parsed("btcusdt+ethusdt@1m"),
+            bin(BinOp::Add, sym("btcusdt"), sym("ethusdt"))
+        );
+    }
+
+    #[test]
+    fn test_parse_returns_interval() {
+        let (_, interval) = Expr::parse("btcusdt@1M").unwrap();
+        assert_eq!(interval.as_str(), "1M");
+        assert!(matches!(
+            Expr::parse("btcusdt+ethusdt"),
+            Err(ServerError::MissingIntervalSuffix)
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt@"),
+            Err(ServerError::MissingIntervalSuffix)
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt@@1h"),
+            Err(ServerError::InvalidCharacter {
+                ch: '@',
+                position: 7
+            })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt@1m!"),
+            Err(ServerError::InvalidInterval(interval)) if interval == "1m!"
+        ));
+    }
+
+    #[test]
+    fn test_parse_expression_with_parentheses() {
+        assert_eq!(
+            parsed("(btcusdt+ethusdt)*adausdt@1m"),
+            bin(
+                BinOp::Mul,
+                bin(BinOp::Add, sym("btcusdt"), sym("ethusdt")),
+                sym("adausdt")
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_mismatched_parentheses() {
+        assert!(matches!(
+            Expr::parse("(btcusdt+ethusdt*adausdt@1m"),
+            Err(ServerError::UnbalancedParentheses { position: 0 })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt+ethusdt)*adausdt@1m"),
+            Err(ServerError::UnbalancedParentheses { position: 15 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_operator_precedence() {
+        assert_eq!(
+            parsed("btcusdt+ethusdt*adausdt@1h"),
+            bin(
+                BinOp::Add,
+                sym("btcusdt"),
+                bin(BinOp::Mul, sym("ethusdt"), sym("adausdt"))
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_complex_expression() {
+        assert_eq!(
+            parsed("btcusdt+ethusdt*(bnbusdt-trxusdt)@1h"),
+            bin(
+                BinOp::Add,
+                sym("btcusdt"),
+                bin(
+                    BinOp::Mul,
+                    sym("ethusdt"),
+                    bin(BinOp::Sub, sym("bnbusdt"), sym("trxusdt"))
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_with_no_parentheses() {
+        assert_eq!(
+            parsed("btcusdt+ethusdt*bnbusdt/trxusdt@1M"),
+            bin(
+                BinOp::Add,
+                sym("btcusdt"),
+                bin(
+                    BinOp::Div,
+                    bin(BinOp::Mul, sym("ethusdt"), sym("bnbusdt")),
+                    sym("trxusdt")
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_with_all_operators() {
+        assert_eq!(
+            parsed("btcusdt+ethusdt-bnbusdt*trxusdt/bchusdt@1M"),
+            bin(
+                BinOp::Sub,
+                bin(BinOp::Add, sym("btcusdt"), sym("ethusdt")),
+                bin(
+                    BinOp::Div,
+                    bin(BinOp::Mul, sym("bnbusdt"), sym("trxusdt")),
+                    sym("bchusdt")
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_with_multiple_parentheses() {
+        assert_eq!(
+            parsed("(btcusdt+(ethusdt-(bnbusdt*(trxusdt/bchusdt))))@1M"),
+            bin(
+                BinOp::Add,
+                sym("btcusdt"),
+                bin(
+                    BinOp::Sub,
+                    sym("ethusdt"),
+                    bin(
+                        BinOp::Mul,
+                        sym("bnbusdt"),
+                        bin(BinOp::Div, sym("trxusdt"), sym("bchusdt"))
+                    )
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_constants_and_unary_minus() {
+        assert_eq!(
+            parsed("-btcusdt*2.5@1m"),
+            bin(BinOp::Mul, neg(sym("btcusdt")), Expr::Const(2.5))
+        );
+        assert_eq!(
+            parsed("ethusdt - -(btcusdt) @1m"),
+            bin(BinOp::Sub, sym("ethusdt"), neg(sym("btcusdt")))
+        );
+    }
+
+    #[test]
+    fn test_parse_symbols_starting_with_digits() {
+        assert_eq!(
+            parsed("1000shibusdt/1inchusdt@1m"),
+            bin(BinOp::Div, sym("1000shibusdt"), sym("1inchusdt"))
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_operands() {
+        assert!(matches!(Expr::parse("2*3@1m"), Err(ServerError::NoSymbol)));
+        assert!(matches!(
+            Expr::parse("btcusdt*1.2.3@1m"),
+            Err(ServerError::InvalidNumber(number)) if number == "1.2.3"
+        ));
+        assert!(matches!(
+            Expr::parse("btc.usdt@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: '.',
+                position: 3
+            })
+        ));
+        assert!(matches!(
+            Expr::parse("btcusdt%2@1m"),
+            Err(ServerError::InvalidCharacter {
+                ch: '%',
+                position: 7
+            })
+        ));
+    }
+
+    #[test]
+    fn test_parse_rejects_deep_nesting() {
+        let deep = format!("{}btcusdt{}@1m", "(".repeat(1000), ")".repeat(1000));
+        assert!(matches!(
+            Expr::parse(&deep),
+            Err(ServerError::NestingTooDeep)
+        ));
+        let negated = format!("{}btcusdt@1m", "-".repeat(1000));
+        assert!(matches!(
+            Expr::parse(&negated),
+            Err(ServerError::NestingTooDeep)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests_print {
+    use super::{canonical_key, parse, to_rpn, BinOp, Expr, ServerError, UnaryOp};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn printed(input: &str) -> String {
+        Expr::parse(input).unwrap().0.to_string()
+    }
+
+    fn round_trips(expr: &Expr) -> bool {
+        matches!(Expr::parse(&format!("{}@1m", expr)), Ok((parsed, _)) if parsed == *expr)
+    }
+
+    #[test]
+    fn test_print_uses_minimal_parentheses() {
+        assert_eq!(printed("btcusdt+ethusdt@1m"), "btcusdt + ethusdt");
+        assert_eq!(
+            printed("((btcusdt)+(ethusdt*bnbusdt))@1m"),
+            "btcusdt + ethusdt * bnbusdt"
+        );
+        assert_eq!(
+            printed("(btcusdt+ethusdt)*bnbusdt@1m"),
+            "(btcusdt + ethusdt) * bnbusdt"
+        );
+        assert_eq!(
+            printed("(btcusdt-ethusdt)-bnbusdt@1m"),
+            "btcusdt - ethusdt - bnbusdt"
+        );
+        assert_eq!(
+            printed("btcusdt-(ethusdt-bnbusdt)@1m"),
+            "btcusdt - (ethusdt - bnbusdt)"
+        );
+        assert_eq!(
+            printed("btcusdt/(ethusdt*bnbusdt)@1m"),
+            "btcusdt / (ethusdt * bnbusdt)"
+        );
+    }
+
+    #[test]
+    fn test_print_constants_and_negation() {
+        assert_eq!(printed("-btcusdt*2.50@1m"), "-btcusdt * 2.5");
+        assert_eq!(printed("-(btcusdt*2)@1m"), "-(btcusdt * 2)");
+        assert_eq!(printed("ethusdt--btcusdt@1m"), "ethusdt - -btcusdt");
+        assert_eq!(printed("1000/btcusdt@1m"), "1000 / btcusdt");
+    }
+
+    const SYMBOLS: [&str; 4] = ["btcusdt", "ethusdt", "1inchusdt", "bnbusdt"];
+
+    fn random_expr(rng: &mut StdRng, depth: u32) -> Expr {
+        if depth == 0 || rng.gen_bool(0.3) {
+            return match rng.gen_range(0..4) {
+                0 => Expr::Const([0.0, 1.0, 2.5, 1e21][rng.gen_range(0..4)]),
+                1 => Expr::Const(rng.gen_range(0.0..1000.0)),
+                _ => Expr::Symbol(SYMBOLS[rng.gen_range(0..SYMBOLS.len())].into()),
+            };
+        }
+
+        if rng.gen_bool(0.2) {
+            return Expr::Unary(UnaryOp::Neg, Box::new(random_expr(rng, depth - 1)));
+        }
+        let op = [BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div][rng.gen_range(0..4)];
+        Expr::Binary(
+            op,
+            Box::new(random_expr(rng, depth - 1)),
+            Box::new(random_expr(rng, depth - 1)),
+        )
+    }
+
+    #[test]
+    fn test_print_parse_round_trip() {
+        let mut rng = StdRng::seed_from_u64(614);
+        for _ in 0..5_000 {
+            let expr = random_expr(&mut rng, 6);
+            if expr.symbols().next().is_some() {
+                assert!(round_trips(&expr), "{:?} printed as {}", expr, expr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_print_round_trip_regressions() {
+        // Right operands at equal precedence and negated left operands
+        for input in [
+            "btcusdt-(ethusdt+bnbusdt)@1m",
+            "btcusdt/(ethusdt/bnbusdt)@1m",
+            "btcusdt*(ethusdt*bnbusdt)@1m",
+            "(-btcusdt)*ethusdt@1m",
+            "-(-btcusdt)@1m",
+            "0.1+btcusdt@1m",
+        ] {
+            assert!(round_trips(&Expr::parse(input).unwrap().0), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_arbitrary_input_never_panics() {
+        // Used to panic on the missing `@`
+        assert!(matches!(
+            parse("btcusdt+ethusdt"),
+            Err(ServerError::MissingIntervalSuffix)
+        ));
+
+        const ALPHABET: &[u8] = b"btcusdt1M0.5+-*/()@ ";
+        let mut rng = StdRng::seed_from_u64(614);
+        for _ in 0..20_000 {
+            let len = rng.gen_range(0..24);
+            let input: String = (0..len)
+                .map(|_| {
+                    if rng.gen_bool(0.8) {
+                        ALPHABET[rng.gen_range(0..ALPHABET.len())] as char
+                    } else {
+                        rng.gen_range(0u8..128) as char
+                    }
+                })
+                .collect();
+
+            let _ = parse(&input).and_then(|tokens| to_rpn(&tokens));
+            let _ = Expr::parse(&input).and_then(|(expr, _)| expr.simplify());
+            let _ = canonical_key(&input);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_simplify {
+    use super::{canonical_key, Expr, ServerError};
+
+    fn simplified(input: &str) -> Expr {
+        Expr::parse(input).unwrap().0.simplify().unwrap()
+    }
+
+    fn parsed(input: &str) -> Expr {
+        Expr::parse(input).unwrap().0
+    }
+
+    #[test]
+    fn test_simplify_folds_constants() {
+        assert_eq!(simplified("btcusdt*2*3@1m"), parsed("btcusdt*6@1m"));
+        assert_eq!(simplified("2*(3*btcusdt)@1m"), parsed("6*btcusdt@1m"));
+        assert_eq!(simplified("2*btcusdt*0.5@1m"), parsed("btcusdt@1m"));
+        assert_eq!(simplified("btcusdt/(4-2)@1m"), parsed("btcusdt/2@1m"));
+        assert_eq!(
+            simplified("-(2*3)*btcusdt@1m"),
+            parsed("-6*btcusdt@1m").simplify().unwrap()
+        );
+        assert_eq!(
+            simplified("(btcusdt+1)+(ethusdt+2)@1m"),
+            parsed("btcusdt+ethusdt+3@1m")
+        );
+    }
+
+    #[test]
+    fn test_simplify_drops_identities() {
+        for input in [
+            "(btcusdt+0)@1m",
+            "0+btcusdt@1m",
+            "btcusdt-0@1m",
+            "btcusdt*1@1m",
+            "1*btcusdt@1m",
+            "btcusdt/1@1m",
+            "btcusdt*(3-2)@1m",
+            "--btcusdt@1m",
+        ] {
+            assert_eq!(simplified(input), parsed("btcusdt@1m"), "{}", input);
+        }
+        assert_eq!(simplified("0-btcusdt@1m"), parsed("-btcusdt@1m"));
+    }
+
+    #[test]
+    fn test_simplify_keeps_operand_order() {
+        assert_eq!(
+            simplified("ethusdt+btcusdt@1m"),
+            parsed("ethusdt+btcusdt@1m")
+        );
+        assert_eq!(
+            simplified("bnbusdt*(ethusdt*btcusdt)@1m"),
+            parsed("bnbusdt*(ethusdt*btcusdt)@1m")
+        );
+        assert_eq!(
+            simplified("--bnbusdt*ethusdt*-2@1m"),
+            parsed("bnbusdt*ethusdt*-2@1m").simplify().unwrap()
+        );
+        assert_eq!(
+            simplified("ethusdt-btcusdt@1m"),
+            parsed("ethusdt-btcusdt@1m")
+        );
+    }
+
+    #[test]
+    fn test_simplify_keeps_non_identity_constants() {
+        assert_eq!(simplified("btcusdt*0@1m"), parsed("btcusdt*0@1m"));
+        assert_eq!(simplified("1/btcusdt@1m"), parsed("1/btcusdt@1m"));
+    }
+
+    #[test]
+    fn test_simplify_rejects_division_by_constant_zero() {
+        let (expr, _) = Expr::parse("btcusdt/(1-1)@1m").unwrap();
+        assert!(matches!(expr.simplify(), Err(ServerError::DivisionByZero)));
+        assert!(matches!(
+            canonical_key("btcusdt/0@1m"),
+            Err(ServerError::DivisionByZero)
+        ));
+        assert!(matches!(
+            canonical_key("(btcusdt+ethusdt)/(0*2)@1m"),
+            Err(ServerError::DivisionByZero)
+        ));
+    }
+
+    #[test]
+    fn test_canonical_key_uses_simplified_tree() {
+        assert_eq!(
+            canonical_key("btcusdt*2*3@1m").unwrap(),
+            canonical_key("6*btcusdt@1m").unwrap()
+        );
+        assert_eq!(canonical_key("(ETHUSDT+0)@1m").unwrap(), "ethusdt@1m");
+    }
+
+    #[test]
+    fn test_canonical_key_keeps_product_grouping() {
+        assert_eq!(
+            canonical_key("ethusdt*btcusdt@1m").unwrap(),
+            canonical_key("btcusdt*ethusdt@1m").unwrap()
+        );
+        assert_eq!(
+            canonical_key("-2*(ethusdt*btcusdt)@1m").unwrap(),
+            canonical_key("btcusdt*ethusdt*-2@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("btcusdt*ethusdt*-2@1m").unwrap(),
+            canonical_key("btcusdt*(ethusdt*-2)@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("(btcusdt*ethusdt)*bnbusdt@1m").unwrap(),
+            canonical_key("btcusdt*(ethusdt*bnbusdt)@1m").unwrap()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests_evaluate {
+    use super::{Candle, CompositeSemantics, Expr, ServerError};
+    use crate::candle::{Division, DivisionPolicy};
+    use crate::protocol::ResultData;
+    use std::collections::HashMap;
+
+    fn evaluate(input: &str, legs: &[(&str, Candle)]) -> Result<Candle, ServerError> {
+        evaluate_as(CompositeSemantics::Fieldwise, input, legs)
+    }
+
+    fn evaluate_as(
+        semantics: CompositeSemantics,
+        input: &str,
+        legs: &[(&str, Candle)],
+    ) -> Result<Candle, ServerError> {
+        let legs: HashMap<&str, Candle> = legs.iter().copied().collect();
+        let result = Expr::parse(input)
+            .unwrap()
+            .0
+            .eval_as(semantics, 0.0, 0, &|symbol| legs.get(symbol).copied())?;
+        Division::default()
+            .settle(result)
+            .map(|result| result.unwrap())
+    }
+
+    #[test]
+    fn test_evaluate_spread() {
+        let result = evaluate(
+            "btcusdt-ethusdt*bnbusdt@1m",
+            &[
+                ("btcusdt", Candle::new(0, 100.0, 110.0, 120.0, 90.0)),
+                ("ethusdt", Candle::new(0, 10.0, 11.0, 12.0, 9.0)),
+                ("bnbusdt", Candle::new(0, 2.0, 2.0, 2.0, 2.0)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(result.o, 80.0);
+        assert_eq!(result.c, 88.0);
+        assert_eq!(result.h, 96.0);
+        assert_eq!(result.l, 72.0);
+    }
+
+    #[test]
+    fn test_evaluate_missing_leg() {
+        let result = evaluate(
+            "btcusdt+ethusdt@1m",
+            &[("btcusdt", Candle::new(0, 1.0, 1.0, 1.0, 1.0))],
+        );
+        assert!(matches!(result, Err(ServerError::KeyNotFound(symbol)) if symbol == "ethusdt"));
+    }
+
+    #[test]
+    fn test_evaluate_mismatched_timestamps() {
+        let result = evaluate(
+            "btcusdt/ethusdt@1m",
+            &[
+                ("btcusdt", Candle::new(0, 1.0, 1.0, 1.0, 1.0)),
+                ("ethusdt", Candle::new(60_000, 1.0, 1.0, 1.0, 1.0)),
+            ],
+        );
+        assert!(matches!(result, Err(ServerError::MismatchedTimestamps)));
+    }
+
+    #[test]
+    fn test_evaluate_trade_counts() {
+        let btc = Candle::new(0, 100.0, 100.0, 100.0, 100.0).with_trades(7);
+        let eth = Candle::new(0, 10.0, 10.0, 10.0, 10.0).with_trades(5);
+        let trades = |input: &str| {
+            evaluate(input, &[("btcusdt", btc), ("ethusdt", eth)])
+                .unwrap()
+                .n
+        };
+
+        assert_eq!(trades("btcusdt+ethusdt@1m"), 12);
+        assert_eq!(trades("btcusdt-ethusdt@1m"), 12);
+        assert_eq!(trades("btcusdt*ethusdt@1m"), 7);
+        assert_eq!(trades("ethusdt/btcusdt@1m"), 5);
+        assert_eq!(trades("btcusdt*2@1m"), 7);
+    }
+
+    #[test]
+    fn test_evaluate_volumes() {
+        let btc = Candle::new(0, 100.0, 100.0, 100.0, 100.0).with_volume(2.0, 200.0);
+        let eth = Candle::new(0, 10.0, 10.0, 10.0, 10.0).with_volume(30.0, 300.0);
+        let volumes = |input: &str| {
+            let result = evaluate(input, &[("btcusdt", btc), ("ethusdt", eth)]).unwrap();
+            (result.v, result.q)
+        };
+
+        assert_eq!(volumes("btcusdt+ethusdt@1m"), (32.0, 500.0));
+        assert_eq!(volumes("btcusdt-ethusdt@1m"), (32.0, 500.0));
+        assert_eq!(volumes("btcusdt*ethusdt@1m"), (2.0, 200.0));
+        assert_eq!(volumes("ethusdt/btcusdt@1m"), (30.0, 300.0));
+        assert_eq!(volumes("(btcusdt+ethusdt)/ethusdt@1m"), (32.0, 500.0));
+    }
+
+    #[test]
+    fn test_evaluate_constants() {
+        let btc = Candle::new(0, 100.0, 110.0, 120.0, 80.0);
+        let prices = |input: &str| {
+            let result = evaluate(input, &[("btcusdt", btc)]).unwrap();
+            (result.o, result.c, result.h, result.l)
+        };
+
+        assert_eq!(prices("btcusdt*2@1m"), (200.0, 220.0, 240.0, 160.0));
+        assert_eq!(prices("btcusdt-100@1m"), (0.0, 10.0, 20.0, -20.0));
+        assert_eq!(prices("(1+1)*btcusdt/4@1m"), (50.0, 55.0, 60.0, 40.0));
+    }
+
+    #[test]
+    fn test_evaluate_decreasing_operations_swap_high_and_low() {
+        let btc = Candle::new(0, 100.0, 125.0, 200.0, 50.0);
+        let prices = |input: &str| {
+            let result = evaluate(input, &[("btcusdt", btc)]).unwrap();
+            (result.o, result.c, result.h, result.l)
+        };
+
+        assert_eq!(prices("-btcusdt@1m"), (-100.0, -125.0, -50.0, -200.0));
+        assert_eq!(prices("1000/btcusdt@1m"), (10.0, 8.0, 20.0, 5.0));
+        assert_eq!(prices("btcusdt*-1@1m"), (-100.0, -125.0, -50.0, -200.0));
+    }
+
+    #[test]
+    fn test_evaluate_simplified_keeps_negative_factor_in_place() {
+        let legs = [
+            ("bnbusdt", Candle::new(0, 2.0, 3.0, 4.0, 1.0)),
+            ("ethusdt", Candle::new(0, 5.0, 6.0, 8.0, 4.0)),
+        ];
+        let lookup = |symbol: &str| legs.iter().find(|(s, _)| *s == symbol).map(|(_, c)| *c);
+        let (expr, _) = Expr::parse("--bnbusdt*ethusdt*-2@1m").unwrap();
+        let original = expr.eval(&lookup).unwrap();
+        let simplified = expr.simplify().unwrap().eval(&lookup).unwrap();
+
+        // The product spans 4..32 before -2 flips it; scaling the bnbusdt
+        // leg first would give -16..-36
+        assert_eq!((original.h, original.l), (-8.0, -64.0));
+        assert_eq!((simplified.h, simplified.l), (original.h, original.l));
+    }
+
+    #[test]
+    fn test_evaluate_simplified_tree_matches_original() {
+        let legs = [
+            ("btcusdt", Candle::new(0, 100.0, 110.0, 120.0, 90.0)),
+            ("ethusdt", Candle::new(0, 10.0, 11.0, 12.0, 9.0)),
+        ];
+        let lookup = |symbol: &str| legs.iter().find(|(s, _)| *s == symbol).map(|(_, c)| *c);
+        let (expr, _) = Expr::parse("(ethusdt*2*1+0)-(btcusdt/4)@1m").unwrap();
+        let original = expr.eval(&lookup).unwrap();
+        let simplified = expr.simplify().unwrap().eval(&lookup).unwrap();
+
+        assert_eq!(
+            (original.o, original.c, original.h, original.l),
+            (simplified.o, simplified.c, simplified.h, simplified.l)
+        );
+    }
+
+    #[test]
+    fn test_evaluate_bound_high_and_low() {
+        let legs = [
+            // Two positive legs, a negative one and one crossing zero
+            ("aaa", Candle::new(0, 6.0, 8.0, 10.0, 4.0)),
+            ("bbb", Candle::new(0, 3.0, 4.0, 5.0, 2.0)),
+            ("nnn", Candle::new(0, -3.0, -4.0, -2.0, -6.0)),
+            ("mmm", Candle::new(0, 1.0, 2.0, 3.0, -1.0)),
+        ];
+        let range = |semantics, input: &str| {
+            let result = evaluate_as(semantics, &format!("{}@1m", input), &legs).unwrap();
+            (result.h, result.l)
+        };
+        let bound = |input: &str| range(CompositeSemantics::Bound, input);
+        let fieldwise = |input: &str| range(CompositeSemantics::Fieldwise, input);
+
+        // h is the largest of a.h op b.h, a.h op b.l, a.l op b.h and
+        // a.l op b.l, l the smallest
+        assert_eq!(bound("aaa+bbb"), (15.0, 6.0));
+        assert_eq!(bound("aaa-bbb"), (10.0 - 2.0, 4.0 - 5.0));
+        assert_eq!(bound("aaa*bbb"), (50.0, 8.0));
+        assert_eq!(bound("aaa/bbb"), (10.0 / 2.0, 4.0 / 5.0));
+        assert_eq!(bound("nnn-aaa"), (-2.0 - 4.0, -6.0 - 10.0));
+        assert_eq!(bound("aaa*nnn"), (4.0 * -2.0, 10.0 * -6.0));
+        assert_eq!(bound("aaa/nnn"), (4.0 / -6.0, 10.0 / -2.0));
+        assert_eq!(bound("nnn/bbb"), (-2.0 / 5.0, -6.0 / 2.0));
+        assert_eq!(bound("nnn*nnn"), (36.0, 4.0));
+        assert_eq!(bound("aaa*mmm"), (30.0, -10.0));
+        // Nested results bound in turn
+        assert_eq!(bound("(aaa-bbb)*nnn"), (6.0, -48.0));
+
+        // Addition is the same either way, the rest needn't be
+        assert_eq!(fieldwise("aaa+bbb"), (15.0, 6.0));
+        assert_eq!(fieldwise("aaa-bbb"), (5.0, 2.0));
+        assert_eq!(fieldwise("aaa/bbb"), (2.0, 2.0));
+        // Widened to the open of -18 and the close of -32
+        assert_eq!(fieldwise("aaa*nnn"), (-18.0, -32.0));
+
+        // A divisor crossing zero has no bound
+        for input in ["aaa/mmm@1m", "1/mmm@1m"] {
+            assert!(evaluate(input, &legs).is_ok());
+            let result = evaluate_as(CompositeSemantics::Bound, input, &legs);
+            assert!(matches!(result, Err(ServerError::DivisionByZero)));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_division_policies() {
+        let btc = Candle::new(0, 10.0, 20.0, 30.0, 5.0);
+        // An exact zero in the open, one below epsilon in the low
+        let zero_open = Candle::new(0, 0.0, 2.0, 4.0, 1.0);
+        let tiny_low = Candle::new(0, 1.0, 2.0, 4.0, 1e-13);
+        let settle = |policy, epsilon, input: &str, eth: Candle| {
+            let legs = [("btcusdt", btc), ("ethusdt", eth)];
+            let lookup = |symbol: &str| legs.iter().find(|(s, _)| *s == symbol).map(|(_, c)| *c);
+            let (expr, _) = Expr::parse(&format!("{}@1m", input)).unwrap();
+            let result = expr
+                .eval_as(CompositeSemantics::Fieldwise, epsilon, 0, &lookup)
+                .unwrap();
+            Division { policy, epsilon }
+                .settle(result)
+                .map(|result| result.map(ResultData::from).map(|d| (d.o, d.c, d.h, d.l)))
+        };
+        let (error, null_field, skip_bar) = (
+            DivisionPolicy::Error,
+            DivisionPolicy::NullField,
+            DivisionPolicy::SkipBar,
+        );
+        let spread = "btcusdt/ethusdt";
+
+        for eth in [zero_open, tiny_low] {
+            assert!(matches!(
+                settle(error, 1e-12, spread, eth),
+                Err(ServerError::DivisionByZero)
+            ));
+            assert_eq!(settle(skip_bar, 1e-12, spread, eth).unwrap(), None);
+        }
+        // Only the fields divided by zero are null
+        assert_eq!(
+            settle(null_field, 1e-12, spread, zero_open).unwrap(),
+            Some((None, Some(10.0), Some(10.0), Some(5.0)))
+        );
+        assert_eq!(
+            settle(null_field, 1e-12, spread, tiny_low).unwrap(),
+            Some((Some(10.0), Some(10.0), Some(10.0), None))
+        );
+        // A constant divided by a leg, and what's made of the result
+        assert_eq!(
+            settle(null_field, 1e-12, "1/ethusdt+btcusdt", zero_open).unwrap(),
+            Some((None, Some(20.5), Some(31.0), Some(5.25)))
+        );
+        // Which end of the range an undefined low maps to isn't known
+        assert_eq!(
+            settle(null_field, 1e-12, "-(btcusdt/ethusdt)", tiny_low).unwrap(),
+            Some((Some(-10.0), Some(-10.0), None, None))
+        );
+        // Without an epsilon, only an exact zero is caught
+        let absurd = settle(error, 0.0, spread, tiny_low).unwrap().unwrap();
+        assert_eq!(absurd.2, Some(5.0 / 1e-13));
+        assert!(settle(error, 0.0, spread, zero_open).is_err());
+    }
+
+    #[test]
+    fn test_evaluate_constant_division_by_zero() {
+        let btc = Candle::new(0, 100.0, 100.0, 100.0, 0.0);
+        let result = evaluate("btcusdt/0@1m", &[("btcusdt", btc)]);
+        assert!(matches!(result, Err(ServerError::DivisionByZero)));
+        let result = evaluate("1/btcusdt@1m", &[("btcusdt", btc)]);
+        assert!(matches!(result, Err(ServerError::DivisionByZero)));
+    }
+}
+
+#[cfg(test)]
+mod tests_canonical {
+    use super::{canonical_key, ServerError};
+
+    #[test]
+    fn test_canonical_key_commutative_operands_dedupe() {
+        assert_eq!(
+            canonical_key("btcusdt+ethusdt@1m").unwrap(),
+            canonical_key("ethusdt+btcusdt@1m").unwrap()
+        );
+        assert_eq!(
+            canonical_key("btcusdt*ethusdt*bnbusdt@1h").unwrap(),
+            canonical_key("bnbusdt*(ethusdt*btcusdt)@1h").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_normalizes_case() {
+        assert_eq!(
+            canonical_key("BTCUSDT-EthUsdt@1m").unwrap(),
+            "btcusdt-ethusdt@1m"
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_ignores_whitespace() {
+        for input in ["btcusdt + ethusdt @1m", "\tethusdt\t+\tbtcusdt@ 1m\n"] {
+            assert_eq!(canonical_key(input).unwrap(), "btcusdt+ethusdt@1m");
+        }
+    }
+
+    #[test]
+    fn test_canonical_key_keeps_interval_case() {
+        assert_ne!(
+            canonical_key("btcusdt@1m").unwrap(),
+            canonical_key("btcusdt@1M").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_leg_intervals() {
+        assert_eq!(
+            canonical_key("ETHUSDT@1h + btcusdt@1m").unwrap(),
+            "btcusdt+ethusdt@1h@1m"
+        );
+        assert_eq!(
+            canonical_key("btcusdt@1m+ethusdt@1h@1m").unwrap(),
+            canonical_key("btcusdt+ethusdt@1h@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("btcusdt+ethusdt@1h@1m").unwrap(),
+            canonical_key("btcusdt@1h+ethusdt@1h").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_drops_redundant_parentheses() {
+        assert_eq!(
+            canonical_key("((btcusdt)+(ethusdt*bnbusdt))@1m").unwrap(),
+            "bnbusdt*ethusdt+btcusdt@1m"
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_non_commutative_operands_do_not_collide() {
+        assert_ne!(
+            canonical_key("btcusdt-ethusdt@1m").unwrap(),
+            canonical_key("ethusdt-btcusdt@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("btcusdt/ethusdt@1m").unwrap(),
+            canonical_key("ethusdt/btcusdt@1m").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_same_symbols_do_not_collide() {
+        assert_ne!(
+            canonical_key("btcusdt+ethusdt@1m").unwrap(),
+            canonical_key("btcusdt*ethusdt@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("(btcusdt+ethusdt)*bnbusdt@1m").unwrap(),
+            canonical_key("btcusdt+ethusdt*bnbusdt@1m").unwrap()
+        );
+        assert_ne!(
+            canonical_key("btcusdt-(ethusdt-bnbusdt)@1m").unwrap(),
+            canonical_key("btcusdt-ethusdt-bnbusdt@1m").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_keeps_differences_within_sums() {
+        assert_ne!(
+            canonical_key("(btcusdt-ethusdt)+bnbusdt@1m").unwrap(),
+            canonical_key("(bnbusdt+btcusdt)-ethusdt@1m").unwrap()
+        );
+        assert_eq!(
+            canonical_key("bnbusdt+(btcusdt-ethusdt)@1m").unwrap(),
+            "(btcusdt-ethusdt)+bnbusdt@1m"
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_constants_and_negation() {
+        assert_eq!(canonical_key("BTCUSDT*2@1m").unwrap(), "2*btcusdt@1m");
+        assert_eq!(
+            canonical_key("2*btcusdt@1m").unwrap(),
+            canonical_key("btcusdt*2@1m").unwrap()
+        );
+        assert_eq!(
+            canonical_key("-(ethusdt+btcusdt)@1m").unwrap(),
+            "-(btcusdt+ethusdt)@1m"
+        );
+        assert_ne!(
+            canonical_key("-btcusdt*ethusdt@1m").unwrap(),
+            canonical_key("-(btcusdt*ethusdt)@1m").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_canonical_key_invalid_stream() {
+        assert!(matches!(
+            canonical_key("btcusdt+ethusdt"),
+            Err(ServerError::MissingIntervalSuffix)
+        ));
+        assert!(matches!(
+            canonical_key("(btcusdt+ethusdt@1m"),
+            Err(ServerError::UnbalancedParentheses { position: 0 })
+        ));
+    }
+}
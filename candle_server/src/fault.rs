@@ -0,0 +1,295 @@
+//! Failures injected on purpose, so tests can drive reconnects, gaps and
+//! broken clients deterministically instead of scripting a mock server for
+//! each. Components consult the `FaultInjector` of their `ServerConfig`;
+//! without the `test-util` feature it's empty and every check is a no-op.
+
+use futures::Sink;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+#[cfg(any(test, feature = "test-util"))]
+use futures::SinkExt;
+#[cfg(any(test, feature = "test-util"))]
+use std::pin::Pin;
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::{Arc, Mutex};
+#[cfg(any(test, feature = "test-util"))]
+use std::task::{Context, Poll};
+#[cfg(any(test, feature = "test-util"))]
+use tokio::time::{sleep, Duration};
+
+/// What reading an upstream connection yields.
+pub type UpstreamMessage = Option<Result<Message, tungstenite::Error>>;
+
+/// Faults armed by a test, shared by every clone.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    faults: Arc<Faults>,
+}
+
+#[cfg(not(any(test, feature = "test-util")))]
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {}
+
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+struct Faults {
+    connect_failures: AtomicUsize,
+    frame_delay: Mutex<Duration>,
+    corrupt_frames: AtomicUsize,
+    // Stream frames the upstream connection reads before it's closed
+    close_after: Mutex<Option<usize>>,
+    hang_up: AtomicBool,
+    client_write_failures: AtomicUsize,
+}
+
+// Takes one from `count` unless it's used up
+#[cfg(any(test, feature = "test-util"))]
+fn take(count: &AtomicUsize) -> bool {
+    count
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl FaultInjector {
+    /// The next `count` upstream connection attempts fail.
+    pub fn fail_connects(&self, count: usize) {
+        self.faults.connect_failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Every upstream frame is held back by `delay` before it's handled;
+    /// zero stops it.
+    pub fn delay_frames(&self, delay: Duration) {
+        *self.faults.frame_delay.lock().unwrap() = delay;
+    }
+
+    /// The next `count` stream frames arrive cut in half.
+    pub fn corrupt_frames(&self, count: usize) {
+        self.faults.corrupt_frames.store(count, Ordering::SeqCst);
+    }
+
+    /// The upstream connection is closed once it has read `frames` more
+    /// stream frames, as if Binance hung up.
+    pub fn close_upstream_after(&self, frames: usize) {
+        *self.faults.close_after.lock().unwrap() = (frames > 0).then_some(frames);
+        self.faults.hang_up.store(frames == 0, Ordering::SeqCst);
+    }
+
+    /// The next `count` writes to WebSocket clients fail.
+    pub fn fail_client_writes(&self, count: usize) {
+        self.faults
+            .client_write_failures
+            .store(count, Ordering::SeqCst);
+    }
+
+    pub(crate) fn fail_connect(&self) -> bool {
+        take(&self.faults.connect_failures)
+    }
+
+    /// `message` as the faults armed make it arrive.
+    pub(crate) async fn upstream_frame(&self, mut message: UpstreamMessage) -> UpstreamMessage {
+        let delay = *self.faults.frame_delay.lock().unwrap();
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+        // Replies to requests are left alone, so counts are of stream frames
+        let Some(Ok(Message::Text(text))) = &mut message else {
+            return message;
+        };
+        if !text.contains(r#""stream""#) {
+            return message;
+        }
+        if take(&self.faults.corrupt_frames) {
+            let mut end = text.len() / 2;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text.truncate(end);
+        }
+        let mut close_after = self.faults.close_after.lock().unwrap();
+        if let Some(frames) = close_after.as_mut() {
+            *frames -= 1;
+            if *frames == 0 {
+                *close_after = None;
+                self.faults.hang_up.store(true, Ordering::SeqCst);
+            }
+        }
+        message
+    }
+
+    /// Whether the upstream connection is to be closed now.
+    pub(crate) fn hang_up(&self) -> bool {
+        self.faults.hang_up.swap(false, Ordering::SeqCst)
+    }
+
+    /// `sink` failing the writes `fail_client_writes` asks for.
+    pub(crate) fn client_sink<S>(
+        &self,
+        sink: S,
+    ) -> impl Sink<Message, Error = tungstenite::Error> + Unpin
+    where
+        S: Sink<Message, Error = tungstenite::Error> + Unpin,
+    {
+        FailingSink {
+            sink,
+            faults: self.faults.clone(),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+struct FailingSink<S> {
+    sink: S,
+    faults: Arc<Faults>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl<S> Sink<Message> for FailingSink<S>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    type Error = tungstenite::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_ready_unpin(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: Message) -> Result<(), Self::Error> {
+        if take(&self.faults.client_write_failures) {
+            return Err(tungstenite::Error::Io(std::io::Error::other(
+                "injected write failure",
+            )));
+        }
+        self.sink.start_send_unpin(message)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.sink.poll_close_unpin(cx)
+    }
+}
+
+#[cfg(not(any(test, feature = "test-util")))]
+impl FaultInjector {
+    pub(crate) fn fail_connect(&self) -> bool {
+        false
+    }
+
+    pub(crate) async fn upstream_frame(&self, message: UpstreamMessage) -> UpstreamMessage {
+        message
+    }
+
+    pub(crate) fn hang_up(&self) -> bool {
+        false
+    }
+
+    pub(crate) fn client_sink<S>(
+        &self,
+        sink: S,
+    ) -> impl Sink<Message, Error = tungstenite::Error> + Unpin
+    where
+        S: Sink<Message, Error = tungstenite::Error> + Unpin,
+    {
+        sink
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultInjector, UpstreamMessage};
+    use futures::SinkExt;
+    use tokio::time::{Duration, Instant};
+    use tokio_tungstenite::tungstenite::Message;
+
+    const FRAME: &str = r#"{"stream":"btcusdt@kline_1m","data":{}}"#;
+    const REPLY: &str = r#"{"result":null,"id":1}"#;
+
+    fn text(message: UpstreamMessage) -> String {
+        match message {
+            Some(Ok(Message::Text(text))) => text,
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    async fn arrive(faults: &FaultInjector, frame: &str) -> String {
+        text(
+            faults
+                .upstream_frame(Some(Ok(Message::Text(frame.into()))))
+                .await,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_nothing_happens_unarmed() {
+        let faults = FaultInjector::default();
+        assert!(!faults.fail_connect());
+        assert_eq!(arrive(&faults, FRAME).await, FRAME);
+        assert!(!faults.hang_up());
+    }
+
+    #[tokio::test]
+    async fn test_counted_faults_are_used_up() {
+        let faults = FaultInjector::default();
+        faults.fail_connects(2);
+        assert_eq!(
+            [
+                faults.fail_connect(),
+                faults.fail_connect(),
+                faults.fail_connect()
+            ],
+            [true, true, false]
+        );
+
+        // Replies don't count
+        faults.corrupt_frames(1);
+        assert_eq!(arrive(&faults, REPLY).await, REPLY);
+        assert_eq!(arrive(&faults, FRAME).await, &FRAME[..FRAME.len() / 2]);
+        assert_eq!(arrive(&faults, FRAME).await, FRAME);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_hangs_up_after_frames() {
+        let faults = FaultInjector::default();
+        faults.close_upstream_after(2);
+        arrive(&faults, FRAME).await;
+        arrive(&faults, REPLY).await;
+        assert!(!faults.hang_up());
+        arrive(&faults, FRAME).await;
+        assert!(faults.hang_up());
+        // Once
+        arrive(&faults, FRAME).await;
+        assert!(!faults.hang_up());
+    }
+
+    #[tokio::test]
+    async fn test_frames_are_delayed() {
+        let faults = FaultInjector::default();
+        faults.delay_frames(Duration::from_millis(50));
+        let start = Instant::now();
+        arrive(&faults, FRAME).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_client_writes_fail() {
+        let faults = FaultInjector::default();
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = written.clone();
+        let sink = Box::pin(futures::sink::unfold((), move |_, message: Message| {
+            recorded.lock().unwrap().push(message);
+            futures::future::ready(Ok(()))
+        }));
+        let mut sink = faults.client_sink(sink);
+
+        faults.fail_client_writes(1);
+        assert!(sink.send(Message::Text("a".into())).await.is_err());
+        assert!(sink.send(Message::Text("b".into())).await.is_ok());
+        assert_eq!(*written.lock().unwrap(), [Message::Text("b".into())]);
+    }
+}
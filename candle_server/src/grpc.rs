@@ -0,0 +1,963 @@
+//! The `Candles` service of `proto/candles.proto` over cleartext HTTP/2,
+//! which gRPC clients speak to a plain `host:port` without upgrading.
+//! Subscriptions go through `Server::subscribe`, so they share the registry
+//! and evaluators of WebSocket clients. Only what gRPC needs of HTTP/2 is
+//! spoken: there's no server push, priorities are ignored and request
+//! messages must be uncompressed.
+
+use log::{debug, warn};
+use std::collections::{HashMap, VecDeque};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::error::{ErrorCode, ServerError};
+use crate::expr::canonical_key;
+use crate::hpack;
+use crate::protobuf::{self, FieldValue};
+use crate::protocol::ServerMessage;
+use crate::server::Server;
+
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+pub(crate) const DATA: u8 = 0x0;
+pub(crate) const HEADERS: u8 = 0x1;
+pub(crate) const RST_STREAM: u8 = 0x3;
+pub(crate) const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+pub(crate) const PING: u8 = 0x6;
+pub(crate) const GOAWAY: u8 = 0x7;
+pub(crate) const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+pub(crate) const END_STREAM: u8 = 0x1;
+pub(crate) const ACK: u8 = 0x1;
+pub(crate) const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY: u8 = 0x20;
+
+pub(crate) const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// Error codes of RST_STREAM and GOAWAY
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+
+/// Frame payloads both ends accept until told otherwise, and the most this
+/// end ever accepts.
+pub(crate) const MAX_FRAME: usize = 16_384;
+/// Flow-control window of a new connection and stream.
+pub(crate) const INITIAL_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// Calls open at once on one connection
+const MAX_CALLS: usize = 100;
+// Request bytes of one call; an `ExpressionRequest` is tiny
+const MAX_REQUEST: usize = 64 * 1024;
+// Header block of one call, HEADERS and CONTINUATION frames together
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+// Updates of one call waiting on the client's flow control, the oldest
+// dropped beyond it, as a lagging WebSocket subscriber skips results
+const MAX_QUEUED: usize = 1024;
+// Updates in flight from the subscriptions to the connection
+const EVENT_QUEUE: usize = 256;
+
+const SUBSCRIBE: &str = "/candles.Candles/Subscribe";
+const UNSUBSCRIBE: &str = "/candles.Candles/Unsubscribe";
+
+/// gRPC status codes sent in `grpc-status`.
+pub mod status {
+    pub const OK: u32 = 0;
+    pub const INVALID_ARGUMENT: u32 = 3;
+    pub const NOT_FOUND: u32 = 5;
+    pub const RESOURCE_EXHAUSTED: u32 = 8;
+    pub const UNIMPLEMENTED: u32 = 12;
+    pub const INTERNAL: u32 = 13;
+    pub const UNAVAILABLE: u32 = 14;
+}
+
+/// One HTTP/2 frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub kind: u8,
+    pub flags: u8,
+    pub stream: u32,
+    pub payload: Vec<u8>,
+}
+
+/// Reads one frame, refusing a payload over `max` bytes before reading
+/// it.
+pub(crate) async fn read_frame(
+    read: &mut (impl AsyncReadExt + Unpin),
+    max: usize,
+) -> Result<Frame, ServerError> {
+    let mut head = [0; 9];
+    read.read_exact(&mut head).await?;
+    let len = u32::from_be_bytes([0, head[0], head[1], head[2]]) as usize;
+    if len > max {
+        return Err(ServerError::Grpc(format!(
+            "frame of {} bytes over the {} allowed",
+            len, max
+        )));
+    }
+    let mut payload = vec![0; len];
+    read.read_exact(&mut payload).await?;
+    Ok(Frame {
+        kind: head[3],
+        flags: head[4],
+        stream: u32::from_be_bytes(head[5..9].try_into().unwrap()) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+pub(crate) fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.push(kind);
+    out.push(flags);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// A gRPC message as DATA frames carry it: an uncompressed flag, then its
+/// length.
+pub(crate) fn length_prefixed(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len() + 5);
+    out.push(0);
+    out.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    out.extend_from_slice(message);
+    out
+}
+
+/// `grpc-message` text, with everything but printable ASCII other than `%`
+/// percent-encoded.
+pub(crate) fn percent_encode(message: &str) -> String {
+    let mut out = String::with_capacity(message.len());
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+// `grpc-status` and `grpc-message` a call ends with
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Status {
+    code: u32,
+    message: String,
+}
+
+impl Status {
+    fn new(code: u32, message: impl Into<String>) -> Status {
+        Status {
+            code,
+            message: message.into(),
+        }
+    }
+
+    // Requests that can't succeed as sent are the client's to fix, while
+    // the upstream or a draining server may do better later
+    fn from_error(e: &ServerError) -> Status {
+        let code = match e.code() {
+            ErrorCode::LimitExceeded => status::RESOURCE_EXHAUSTED,
+            ErrorCode::Draining => status::UNAVAILABLE,
+            ErrorCode::Internal => status::INTERNAL,
+            code if code.value() < 2000 => status::INVALID_ARGUMENT,
+            code if code.value() < 3000 => status::UNAVAILABLE,
+            _ => status::INTERNAL,
+        };
+        Status::new(code, e.to_string())
+    }
+}
+
+// What a `Subscribe` call hands the connection
+enum Event {
+    Update(u32, Vec<u8>),
+    End(u32, Status),
+}
+
+struct ExpressionRequest {
+    stream: String,
+    precision: Option<u32>,
+}
+
+impl ExpressionRequest {
+    fn decode(bytes: &[u8]) -> Result<ExpressionRequest, ServerError> {
+        let mut request = ExpressionRequest {
+            stream: String::new(),
+            precision: None,
+        };
+        for (field, value) in protobuf::read_fields(bytes)? {
+            match (field, value) {
+                (1, FieldValue::Bytes(stream)) => {
+                    request.stream = String::from_utf8(stream.to_vec())
+                        .map_err(|_| ServerError::InvalidMessage("stream is not UTF-8".into()))?;
+                }
+                (2, FieldValue::Varint(precision)) => {
+                    request.precision = Some(precision.min(u64::from(u32::MAX)) as u32)
+                }
+                // Unknown fields are skipped, as protobuf readers do
+                _ => {}
+            }
+        }
+        Ok(request)
+    }
+
+    // What `Unsubscribe` matches calls by, so a respelled expression still
+    // ends its call
+    fn key(&self) -> String {
+        canonical_key(&self.stream).unwrap_or_else(|_| self.stream.clone())
+    }
+}
+
+// A fault of the peer's that ends the whole connection with a GOAWAY
+struct ConnectionError {
+    code: u32,
+    message: String,
+}
+
+impl ConnectionError {
+    fn new(code: u32, message: impl Into<String>) -> ConnectionError {
+        ConnectionError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ServerError> for ConnectionError {
+    fn from(e: ServerError) -> Self {
+        ConnectionError::new(PROTOCOL_ERROR, e.to_string())
+    }
+}
+
+// One request stream of the connection
+struct Call {
+    path: String,
+    request: Vec<u8>,
+    // The request arrived whole, so any more DATA is the client's error
+    dispatched: bool,
+    // Bytes the client still takes on this stream
+    window: i64,
+    headers_sent: bool,
+    // Length-prefixed updates waiting on flow control, the first perhaps
+    // partly sent
+    queued: VecDeque<Vec<u8>>,
+    // Sent once the queue drains
+    trailers: Option<Status>,
+    // `Subscribe` calls only, with the key `Unsubscribe` finds them by
+    subscription: Option<(String, JoinHandle<()>)>,
+}
+
+impl Call {
+    fn cancel(&mut self) {
+        if let Some((_, task)) = self.subscription.take() {
+            task.abort();
+        }
+    }
+}
+
+struct Connection {
+    server: Server,
+    write: OwnedWriteHalf,
+    // Frames of a handler, written together once it's done
+    out: Vec<u8>,
+    decoder: hpack::Decoder,
+    calls: HashMap<u32, Call>,
+    // Header block of a stream still arriving in CONTINUATION frames
+    continuation: Option<(u32, u8, Vec<u8>)>,
+    last_stream: u32,
+    // Bytes the client still takes on the connection as a whole
+    window: i64,
+    // What the client's SETTINGS say new streams start with
+    initial_window: i64,
+    max_frame: usize,
+    events: mpsc::Sender<Event>,
+    // Set on a GOAWAY from the client
+    closing: bool,
+}
+
+/// Serves gRPC calls on `socket` until the client goes away or `shutdown`
+/// is set, when open calls end with `UNAVAILABLE`.
+pub async fn serve(
+    server: Server,
+    socket: TcpStream,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), ServerError> {
+    let (read, write) = socket.into_split();
+    let (frames_tx, mut frames) = mpsc::channel(16);
+    let reader = tokio::spawn(read_frames(read, frames_tx));
+    let (events_tx, mut events) = mpsc::channel(EVENT_QUEUE);
+    let mut connection = Connection {
+        server,
+        write,
+        out: Vec::new(),
+        decoder: hpack::Decoder::new(),
+        calls: HashMap::new(),
+        continuation: None,
+        last_stream: 0,
+        window: INITIAL_WINDOW,
+        initial_window: INITIAL_WINDOW,
+        max_frame: MAX_FRAME,
+        events: events_tx,
+        closing: false,
+    };
+
+    let mut settings = Vec::new();
+    for (id, value) in [
+        (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_CALLS as u32),
+        (SETTINGS_MAX_FRAME_SIZE, MAX_FRAME as u32),
+    ] {
+        settings.extend_from_slice(&id.to_be_bytes());
+        settings.extend_from_slice(&value.to_be_bytes());
+    }
+    write_frame(&mut connection.out, SETTINGS, 0, 0, &settings);
+    // Its `Ref` isn't `Send`, so it's dropped before any arm runs
+    let stopped = async move {
+        let _ = shutdown.wait_for(|&down| down).await;
+    };
+    tokio::pin!(stopped);
+    let served = async {
+        connection.send().await?;
+        loop {
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(Ok(frame)) => connection.on_frame(frame).await?,
+                    Some(Err(e)) => return Err(e),
+                    None => return Ok(()),
+                },
+                Some(event) = events.recv() => connection.on_event(event),
+                _ = &mut stopped => {
+                    connection.end_calls(Status::new(status::UNAVAILABLE, "Server is shutting down"));
+                    return Err(ConnectionError::new(NO_ERROR, "shutting down"));
+                }
+            }
+            connection.send().await?;
+            if connection.closing && connection.calls.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    connection.end_calls(Status::new(status::UNAVAILABLE, "Connection closed"));
+
+    match served {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let mut payload = connection.last_stream.to_be_bytes().to_vec();
+            payload.extend_from_slice(&e.code.to_be_bytes());
+            write_frame(&mut connection.out, GOAWAY, 0, 0, &payload);
+            // The client may be gone already
+            let _ = connection.send().await;
+            match e.code {
+                NO_ERROR => Ok(()),
+                _ => Err(ServerError::Grpc(e.message)),
+            }
+        }
+    }
+}
+
+// Checks the preface, then hands on frames until the client closes the
+// connection or sends one that's too large
+async fn read_frames(
+    mut read: OwnedReadHalf,
+    frames: mpsc::Sender<Result<Frame, ConnectionError>>,
+) {
+    let mut preface = [0; PREFACE.len()];
+    if let Err(e) = read.read_exact(&mut preface).await {
+        debug!("gRPC client left before its preface: {}", e);
+        return;
+    }
+    if preface != PREFACE {
+        let _ = frames
+            .send(Err(ConnectionError::new(
+                PROTOCOL_ERROR,
+                "not an HTTP/2 preface",
+            )))
+            .await;
+        return;
+    }
+    loop {
+        let frame = match read_frame(&mut read, MAX_FRAME).await {
+            Ok(frame) => Ok(frame),
+            Err(ServerError::Io(_)) => return,
+            Err(e) => Err(ConnectionError::new(FRAME_SIZE_ERROR, e.to_string())),
+        };
+        let failed = frame.is_err();
+        if frames.send(frame).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+// Payload of a DATA or HEADERS frame without its padding
+fn unpadded(frame: &Frame) -> Result<&[u8], ConnectionError> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&pad, rest) = frame
+        .payload
+        .split_first()
+        .ok_or_else(|| ConnectionError::new(PROTOCOL_ERROR, "padded frame without padding"))?;
+    rest.len()
+        .checked_sub(usize::from(pad))
+        .map(|len| &rest[..len])
+        .ok_or_else(|| ConnectionError::new(PROTOCOL_ERROR, "padding longer than the frame"))
+}
+
+impl Connection {
+    async fn send(&mut self) -> Result<(), ConnectionError> {
+        if !self.out.is_empty() {
+            let out = std::mem::take(&mut self.out);
+            self.write
+                .write_all(&out)
+                .await
+                .map_err(|e| ConnectionError::new(NO_ERROR, e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    async fn on_frame(&mut self, frame: Frame) -> Result<(), ConnectionError> {
+        if let Some((stream, _, _)) = &self.continuation {
+            if frame.kind != CONTINUATION || frame.stream != *stream {
+                return Err(ConnectionError::new(
+                    PROTOCOL_ERROR,
+                    "expected CONTINUATION",
+                ));
+            }
+        }
+        match frame.kind {
+            DATA => self.on_data(&frame)?,
+            HEADERS => {
+                let mut block = unpadded(&frame)?;
+                if frame.flags & PRIORITY != 0 {
+                    block = block.get(5..).ok_or_else(|| {
+                        ConnectionError::new(PROTOCOL_ERROR, "HEADERS too short for a priority")
+                    })?;
+                }
+                self.continuation = Some((frame.stream, frame.flags, block.to_vec()));
+                if frame.flags & END_HEADERS != 0 {
+                    self.on_headers()?;
+                }
+            }
+            CONTINUATION => {
+                let Some((_, _, block)) = &mut self.continuation else {
+                    return Err(ConnectionError::new(PROTOCOL_ERROR, "stray CONTINUATION"));
+                };
+                block.extend_from_slice(&frame.payload);
+                if block.len() > MAX_HEADER_BLOCK {
+                    return Err(ConnectionError::new(
+                        PROTOCOL_ERROR,
+                        format!("header block over {} bytes", MAX_HEADER_BLOCK),
+                    ));
+                }
+                if frame.flags & END_HEADERS != 0 {
+                    self.on_headers()?;
+                }
+            }
+            RST_STREAM => {
+                if let Some(mut call) = self.calls.remove(&frame.stream) {
+                    call.cancel();
+                }
+            }
+            SETTINGS if frame.flags & ACK == 0 => {
+                self.on_settings(&frame.payload)?;
+                write_frame(&mut self.out, SETTINGS, ACK, 0, &[]);
+            }
+            PING if frame.flags & ACK == 0 => {
+                write_frame(&mut self.out, PING, ACK, 0, &frame.payload);
+            }
+            GOAWAY => self.closing = true,
+            WINDOW_UPDATE => {
+                let increment = frame
+                    .payload
+                    .get(..4)
+                    .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()) & 0x7fff_ffff)
+                    .filter(|&increment| increment > 0)
+                    .ok_or_else(|| ConnectionError::new(PROTOCOL_ERROR, "invalid WINDOW_UPDATE"))?;
+                let window = match frame.stream {
+                    0 => &mut self.window,
+                    stream => match self.calls.get_mut(&stream) {
+                        Some(call) => &mut call.window,
+                        None => return Ok(()),
+                    },
+                };
+                *window += i64::from(increment);
+                if *window > MAX_WINDOW {
+                    return Err(ConnectionError::new(
+                        FLOW_CONTROL_ERROR,
+                        "window over 2^31-1",
+                    ));
+                }
+                self.flush_all();
+            }
+            PUSH_PROMISE => {
+                return Err(ConnectionError::new(
+                    PROTOCOL_ERROR,
+                    "PUSH_PROMISE from a client",
+                ))
+            }
+            // PRIORITY, acknowledgements and unknown types
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn on_settings(&mut self, payload: &[u8]) -> Result<(), ConnectionError> {
+        if !payload.len().is_multiple_of(6) {
+            return Err(ConnectionError::new(
+                FRAME_SIZE_ERROR,
+                "SETTINGS not in 6 byte entries",
+            ));
+        }
+        for entry in payload.chunks(6) {
+            let id = u16::from_be_bytes([entry[0], entry[1]]);
+            let value = u32::from_be_bytes(entry[2..6].try_into().unwrap());
+            match id {
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let value = i64::from(value);
+                    if value > MAX_WINDOW {
+                        return Err(ConnectionError::new(
+                            FLOW_CONTROL_ERROR,
+                            "initial window over 2^31-1",
+                        ));
+                    }
+                    // Open streams' windows move by the difference
+                    let delta = value - self.initial_window;
+                    self.initial_window = value;
+                    for call in self.calls.values_mut() {
+                        call.window += delta;
+                    }
+                }
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME as u32..1 << 24).contains(&value) {
+                        return Err(ConnectionError::new(
+                            PROTOCOL_ERROR,
+                            "invalid max frame size",
+                        ));
+                    }
+                    self.max_frame = value as usize;
+                }
+                _ => {}
+            }
+        }
+        self.flush_all();
+        Ok(())
+    }
+
+    fn on_data(&mut self, frame: &Frame) -> Result<(), ConnectionError> {
+        if frame.stream == 0 {
+            return Err(ConnectionError::new(PROTOCOL_ERROR, "DATA on stream 0"));
+        }
+        let data = unpadded(frame)?;
+        // Requests are read whole as they come, so the window is given back
+        // right away
+        if !frame.payload.is_empty() {
+            let increment = (frame.payload.len() as u32).to_be_bytes();
+            write_frame(&mut self.out, WINDOW_UPDATE, 0, 0, &increment);
+        }
+        let Some(call) = self.calls.get_mut(&frame.stream) else {
+            return Ok(());
+        };
+        if call.dispatched {
+            return Err(ConnectionError::new(
+                PROTOCOL_ERROR,
+                "DATA after the end of the stream",
+            ));
+        }
+        if call.request.len() + data.len() > MAX_REQUEST {
+            call.dispatched = true;
+            call.trailers = Some(Status::new(
+                status::RESOURCE_EXHAUSTED,
+                format!("Request over {} bytes", MAX_REQUEST),
+            ));
+            write_frame(
+                &mut self.out,
+                RST_STREAM,
+                0,
+                frame.stream,
+                &NO_ERROR.to_be_bytes(),
+            );
+            self.flush(frame.stream);
+            return Ok(());
+        }
+        call.request.extend_from_slice(data);
+        if frame.flags & END_STREAM != 0 {
+            self.dispatch(frame.stream);
+        } else if !frame.payload.is_empty() {
+            let increment = (frame.payload.len() as u32).to_be_bytes();
+            write_frame(&mut self.out, WINDOW_UPDATE, 0, frame.stream, &increment);
+        }
+        Ok(())
+    }
+
+    fn on_headers(&mut self) -> Result<(), ConnectionError> {
+        let (stream, flags, block) = self.continuation.take().unwrap();
+        // Decoded even for a refused stream, as the table must stay in step
+        let headers = self
+            .decoder
+            .decode(&block)
+            .map_err(|e| ConnectionError::new(COMPRESSION_ERROR, e.to_string()))?;
+        if self.calls.contains_key(&stream) {
+            // Trailers of the request, which gRPC doesn't use
+            return Ok(());
+        }
+        if stream % 2 == 0 || stream <= self.last_stream {
+            return Err(ConnectionError::new(
+                PROTOCOL_ERROR,
+                format!("HEADERS opening stream {}", stream),
+            ));
+        }
+        self.last_stream = stream;
+        if self.closing || self.calls.len() >= MAX_CALLS {
+            write_frame(
+                &mut self.out,
+                RST_STREAM,
+                0,
+                stream,
+                &REFUSED_STREAM.to_be_bytes(),
+            );
+            return Ok(());
+        }
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(known, _)| known == name)
+                .map(|(_, value)| value.as_str())
+        };
+        let grpc = header("content-type").is_some_and(|kind| kind.starts_with("application/grpc"));
+        let mut call = Call {
+            path: header(":path").unwrap_or_default().to_string(),
+            request: Vec::new(),
+            dispatched: false,
+            window: self.initial_window,
+            headers_sent: false,
+            queued: VecDeque::new(),
+            trailers: None,
+            subscription: None,
+        };
+        if header(":method") != Some("POST") || !grpc {
+            call.dispatched = true;
+            call.trailers = Some(Status::new(
+                status::UNIMPLEMENTED,
+                "Only gRPC calls are served",
+            ));
+        }
+        let ended = call.trailers.is_some();
+        self.calls.insert(stream, call);
+        if ended {
+            self.flush(stream);
+        } else if flags & END_STREAM != 0 {
+            self.dispatch(stream);
+        }
+        Ok(())
+    }
+
+    // Starts the call once its request is in
+    fn dispatch(&mut self, stream: u32) {
+        let call = self.calls.get_mut(&stream).unwrap();
+        call.dispatched = true;
+        let request = match Self::request(&call.request) {
+            Ok(request) => request,
+            Err(status) => {
+                call.trailers = Some(status);
+                self.flush(stream);
+                return;
+            }
+        };
+        match call.path.as_str() {
+            SUBSCRIBE => {
+                let task = tokio::spawn(subscribe(
+                    self.server.clone(),
+                    stream,
+                    request.stream.clone(),
+                    request.precision,
+                    self.events.clone(),
+                ));
+                call.subscription = Some((request.key(), task));
+            }
+            UNSUBSCRIBE => {
+                let key = request.key();
+                let mut ended = Vec::new();
+                for (&id, other) in self.calls.iter_mut() {
+                    if other
+                        .subscription
+                        .as_ref()
+                        .is_some_and(|(of, _)| *of == key)
+                    {
+                        other.cancel();
+                        other.trailers = Some(Status::new(status::OK, ""));
+                        ended.push(id);
+                    }
+                }
+                let call = self.calls.get_mut(&stream).unwrap();
+                if ended.is_empty() {
+                    call.trailers = Some(Status::new(
+                        status::NOT_FOUND,
+                        format!("Not subscribed to {}", request.stream),
+                    ));
+                } else {
+                    // An empty `UnsubscribeReply`
+                    call.queued.push_back(length_prefixed(&[]));
+                    call.trailers = Some(Status::new(status::OK, ""));
+                }
+                ended.push(stream);
+                for id in ended {
+                    self.flush(id);
+                }
+                return;
+            }
+            path => {
+                call.trailers = Some(Status::new(
+                    status::UNIMPLEMENTED,
+                    format!("Unknown method {}", path),
+                ));
+            }
+        }
+        self.flush(stream);
+    }
+
+    // The one message of a unary request
+    fn request(bytes: &[u8]) -> Result<ExpressionRequest, Status> {
+        let invalid = |message: &str| Status::new(status::INVALID_ARGUMENT, message);
+        let (head, message) = match bytes.len() {
+            5.. => bytes.split_at(5),
+            _ => return Err(invalid("Request has no message")),
+        };
+        if head[0] != 0 {
+            return Err(Status::new(
+                status::UNIMPLEMENTED,
+                "Compressed requests are not supported",
+            ));
+        }
+        if u32::from_be_bytes(head[1..5].try_into().unwrap()) as usize != message.len() {
+            return Err(invalid("Request message length does not match its data"));
+        }
+        ExpressionRequest::decode(message).map_err(|e| Status::from_error(&e))
+    }
+
+    fn on_event(&mut self, event: Event) {
+        let stream = stream_of(&event);
+        match event {
+            Event::Update(stream, update) => {
+                let Some(call) = self.calls.get_mut(&stream) else {
+                    return;
+                };
+                if call.queued.len() >= MAX_QUEUED {
+                    warn!("gRPC call {} lagging, dropped its oldest update", call.path);
+                    call.queued.pop_front();
+                }
+                call.queued.push_back(length_prefixed(&update));
+            }
+            Event::End(stream, status) => {
+                let Some(call) = self.calls.get_mut(&stream) else {
+                    return;
+                };
+                call.subscription = None;
+                call.trailers.get_or_insert(status);
+            }
+        }
+        self.flush(stream);
+    }
+
+    // Ends every call with `status`, e.g. as the server shuts down
+    fn end_calls(&mut self, status: Status) {
+        let streams: Vec<u32> = self.calls.keys().copied().collect();
+        for stream in streams {
+            let call = self.calls.get_mut(&stream).unwrap();
+            call.cancel();
+            call.queued.clear();
+            call.trailers = Some(status.clone());
+            self.flush(stream);
+        }
+    }
+
+    fn flush_all(&mut self) {
+        let mut streams: Vec<u32> = self.calls.keys().copied().collect();
+        streams.sort_unstable();
+        for stream in streams {
+            self.flush(stream);
+        }
+    }
+
+    // Sends what flow control lets through of a call's updates, then its
+    // trailers once nothing's left
+    fn flush(&mut self, stream: u32) {
+        let Some(call) = self.calls.get_mut(&stream) else {
+            return;
+        };
+        if !call.headers_sent {
+            match (&call.trailers, call.queued.is_empty()) {
+                (None, true) => return,
+                // Trailers-only, for a call failing before any update
+                (Some(status), true) => {
+                    let code = status.code.to_string();
+                    let message = percent_encode(&status.message);
+                    let mut block = Vec::new();
+                    hpack::encode(
+                        &[
+                            (":status", "200"),
+                            ("content-type", "application/grpc"),
+                            ("grpc-status", &code),
+                            ("grpc-message", &message),
+                        ],
+                        &mut block,
+                    );
+                    write_frame(
+                        &mut self.out,
+                        HEADERS,
+                        END_HEADERS | END_STREAM,
+                        stream,
+                        &block,
+                    );
+                    self.calls.remove(&stream);
+                    return;
+                }
+                _ => {
+                    let mut block = Vec::new();
+                    hpack::encode(
+                        &[(":status", "200"), ("content-type", "application/grpc")],
+                        &mut block,
+                    );
+                    write_frame(&mut self.out, HEADERS, END_HEADERS, stream, &block);
+                    call.headers_sent = true;
+                }
+            }
+        }
+
+        while let Some(front) = call.queued.front_mut() {
+            let room = self.window.min(call.window).min(self.max_frame as i64);
+            if room <= 0 {
+                return;
+            }
+            let len = front.len().min(room as usize);
+            write_frame(&mut self.out, DATA, 0, stream, &front[..len]);
+            self.window -= len as i64;
+            call.window -= len as i64;
+            front.drain(..len);
+            if front.is_empty() {
+                call.queued.pop_front();
+            }
+        }
+        if let Some(status) = &call.trailers {
+            let code = status.code.to_string();
+            let message = percent_encode(&status.message);
+            let mut block = Vec::new();
+            hpack::encode(
+                &[("grpc-status", &code), ("grpc-message", &message)],
+                &mut block,
+            );
+            write_frame(
+                &mut self.out,
+                HEADERS,
+                END_HEADERS | END_STREAM,
+                stream,
+                &block,
+            );
+            self.calls.remove(&stream);
+        }
+    }
+}
+
+fn stream_of(event: &Event) -> u32 {
+    match event {
+        Event::Update(stream, _) | Event::End(stream, _) => *stream,
+    }
+}
+
+// Forwards the results of one `Subscribe` call, with prices rounded to the
+// request's precision, until the client cancels or the subscription ends
+async fn subscribe(
+    server: Server,
+    stream: u32,
+    expression: String,
+    precision: Option<u32>,
+    events: mpsc::Sender<Event>,
+) {
+    let mut subscription = match server.subscribe(&expression).await {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            let _ = events
+                .send(Event::End(stream, Status::from_error(&e)))
+                .await;
+            return;
+        }
+    };
+    while let Some(message) = subscription.recv().await {
+        // Statuses and tickers have no place in a `CandleUpdate` stream
+        let ServerMessage::Result(mut result) = message else {
+            continue;
+        };
+        if precision.is_some() {
+            result.data.format.precision = precision;
+        }
+        let mut update = Vec::new();
+        protobuf::write_candle_update(&result, &mut update);
+        if events.send(Event::Update(stream, update)).await.is_err() {
+            return;
+        }
+    }
+    let status = Status::new(
+        status::UNAVAILABLE,
+        format!("Subscription to {} ended", expression),
+    );
+    let _ = events.send(Event::End(stream, status)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{length_prefixed, percent_encode, ExpressionRequest, Status};
+    use crate::error::ServerError;
+    use crate::grpc::status;
+
+    #[test]
+    fn test_decodes_expression_request() {
+        // stream = "btcusdt@1m", precision = 2, then an unknown field 9
+        let mut bytes = vec![0x0a, 10];
+        bytes.extend_from_slice(b"btcusdt@1m");
+        bytes.extend_from_slice(&[0x10, 2, 0x48, 1]);
+        let request = ExpressionRequest::decode(&bytes).unwrap();
+        assert_eq!(
+            (request.stream.as_str(), request.precision),
+            ("btcusdt@1m", Some(2))
+        );
+        assert_eq!(request.key(), "btcusdt@1m");
+
+        assert!(ExpressionRequest::decode(&[0x0a, 10, b'b']).is_err());
+    }
+
+    #[test]
+    fn test_length_prefixed_message() {
+        assert_eq!(length_prefixed(&[7, 8]), [0, 0, 0, 0, 2, 7, 8]);
+    }
+
+    #[test]
+    fn test_errors_map_to_status_codes() {
+        let code = |e: ServerError| Status::from_error(&e).code;
+        assert_eq!(code(ServerError::EmptyExpression), status::INVALID_ARGUMENT);
+        assert_eq!(code(ServerError::WebSocketConnect), status::UNAVAILABLE);
+        assert_eq!(code(ServerError::Draining), status::UNAVAILABLE);
+        assert_eq!(code(ServerError::DivisionByZero), status::INTERNAL);
+        assert_eq!(
+            code(ServerError::LimitExceeded {
+                limit: "max_subscriptions",
+                max: 1
+            }),
+            status::RESOURCE_EXHAUSTED
+        );
+    }
+
+    #[test]
+    fn test_percent_encodes_grpc_message() {
+        assert_eq!(percent_encode("50% off: ü"), "50%25 off: %C3%BC");
+    }
+}
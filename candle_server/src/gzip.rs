@@ -0,0 +1,607 @@
+use crate::error::ServerError;
+
+// The subset of gzip (RFC 1952) large frames need: DEFLATE (RFC 1951) with
+// the fixed Huffman codes, which keeps the encoder small and still shrinks
+// repetitive JSON several times over. Decompression takes any gzip stream,
+// and the raw DEFLATE of permessage-deflate messages from the upstream.
+
+// Bytes back a match may reach, and the longest match
+const WINDOW: usize = 32 * 1024;
+const MAX_MATCH: usize = 258;
+const MIN_MATCH: usize = 3;
+// Earlier positions with the same hash tried per match
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// Order the code length code lengths of a dynamic block come in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Appends `data` to `out` as a gzip stream.
+pub fn compress(data: &[u8], out: &mut Vec<u8>) {
+    // No file name or time, unknown OS
+    out.extend_from_slice(&[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff]);
+    let mut bits = BitWriter {
+        out,
+        bits: 0,
+        len: 0,
+    };
+    // A single final block with the fixed codes
+    bits.write(1, 1);
+    bits.write(1, 2);
+    fixed_block(data, &[], &mut bits);
+    bits.flush();
+
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+}
+
+/// Appends `data` to `out` as raw DEFLATE ending in a sync flush, the
+/// empty stored block `00 00 ff ff`, as permessage-deflate (RFC 7692)
+/// sends a message. Matches may reach back into `history`, what came
+/// before on the same stream.
+pub fn deflate_sync(data: &[u8], history: &[u8], out: &mut Vec<u8>) {
+    let mut bits = BitWriter {
+        out,
+        bits: 0,
+        len: 0,
+    };
+    bits.write(0, 1);
+    bits.write(1, 2);
+    fixed_block(data, history, &mut bits);
+    bits.write(0, 3);
+    bits.flush();
+    out.extend_from_slice(&[0, 0, 0xff, 0xff]);
+}
+
+// Symbols of `data` and the end of block, after a block header
+fn fixed_block(data: &[u8], history: &[u8], bits: &mut BitWriter) {
+    let history = &history[history.len().saturating_sub(WINDOW)..];
+    let window = [history, data].concat();
+    let data = &window[..];
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let insert = |head: &mut [usize], prev: &mut [usize], at: usize| {
+        if at + MIN_MATCH <= data.len() {
+            let hash = hash(&data[at..at + MIN_MATCH]);
+            prev[at] = head[hash];
+            head[hash] = at;
+        }
+    };
+    for at in 0..history.len() {
+        insert(&mut head, &mut prev, at);
+    }
+    let mut at = history.len();
+    while at < data.len() {
+        let (length, distance) = longest_match(data, at, &head, &prev);
+        if length >= MIN_MATCH {
+            bits.length(length);
+            bits.distance(distance);
+            for skipped in at..at + length {
+                insert(&mut head, &mut prev, skipped);
+            }
+            at += length;
+        } else {
+            bits.literal(data[at] as u16);
+            insert(&mut head, &mut prev, at);
+            at += 1;
+        }
+    }
+    bits.literal(256);
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from(bytes[0]) | u32::from(bytes[1]) << 8 | u32::from(bytes[2]) << 16;
+    (word.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+// Longest earlier match of the bytes at `at` within the window, as length
+// and distance
+fn longest_match(data: &[u8], at: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if at + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let limit = (data.len() - at).min(MAX_MATCH);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(&data[at..at + MIN_MATCH])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || at - candidate > WINDOW {
+            break;
+        }
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[at..at + limit])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best.0 {
+            best = (length, at - candidate);
+            if length == limit {
+                break;
+            }
+        }
+        candidate = prev[candidate];
+    }
+    best
+}
+
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+
+impl BitWriter<'_> {
+    // `len` low bits of `value`, least significant first
+    fn write(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first
+    fn code(&mut self, code: u32, len: u32) {
+        self.write(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn literal(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.code(0x30 + symbol, 8),
+            144..=255 => self.code(0x190 + symbol - 144, 9),
+            256..=279 => self.code(symbol - 256, 7),
+            _ => self.code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let index = LENGTH_BASE
+            .iter()
+            .rposition(|&base| base as usize <= length)
+            .unwrap();
+        self.literal(257 + index as u16);
+        let extra = (length - LENGTH_BASE[index] as usize) as u32;
+        self.write(extra, u32::from(LENGTH_EXTRA[index]));
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let index = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| base as usize <= distance)
+            .unwrap();
+        self.code(index as u32, 5);
+        let extra = (distance - DISTANCE_BASE[index] as usize) as u32;
+        self.write(extra, u32::from(DISTANCE_EXTRA[index]));
+    }
+
+    fn flush(&mut self) {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.bits = 0;
+        self.len = 0;
+    }
+}
+
+fn corrupt(what: &str) -> ServerError {
+    ServerError::InvalidMessage(format!("corrupt gzip stream: {}", what))
+}
+
+/// Contents of the gzip stream `data`, checked against its CRC and size.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ServerError> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    if data.len() < 18 || data[..3] != [0x1f, 0x8b, 8] {
+        return Err(corrupt("not gzip"));
+    }
+    let flags = data[3];
+    let mut at = 10;
+    if flags & FEXTRA != 0 {
+        let len = data.get(at..at + 2).ok_or_else(|| corrupt("truncated"))?;
+        at += 2 + u16::from_le_bytes([len[0], len[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(at..)
+                .and_then(|rest| rest.iter().position(|&byte| byte == 0))
+                .ok_or_else(|| corrupt("truncated"))?;
+            at += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        at += 2;
+    }
+    if at + 8 > data.len() {
+        return Err(corrupt("truncated"));
+    }
+
+    let trailer = &data[data.len() - 8..];
+    let mut reader = BitReader {
+        data: &data[at..data.len() - 8],
+        at: 0,
+        bits: 0,
+        len: 0,
+    };
+    let mut out = Vec::new();
+    inflate(&mut reader, &mut out, false, usize::MAX)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc32(&out) != crc || out.len() as u32 != size {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    at: usize,
+    bits: u32,
+    len: u32,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, len: u32) -> Result<u32, ServerError> {
+        while self.len < len {
+            let byte = *self.data.get(self.at).ok_or_else(|| corrupt("truncated"))?;
+            self.bits |= u32::from(byte) << self.len;
+            self.at += 1;
+            self.len += 8;
+        }
+        let value = self.bits & ((1u64 << len) - 1) as u32;
+        self.bits = self.bits.checked_shr(len).unwrap_or(0);
+        self.len -= len;
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        self.bits = 0;
+        self.len = 0;
+    }
+}
+
+// Canonical Huffman code as symbol counts per code length and the symbols
+// in code order
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16, ServerError> {
+        // First code and index of each length, walked one bit at a time
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= reader.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("bad code"))
+    }
+}
+
+/// What the raw DEFLATE `data` holds up to a final block or, as a
+/// permessage-deflate message ends in a sync flush, the end of `data`.
+/// Matches may reach back into `history`; more than `limit` bytes are an
+/// error.
+pub fn inflate_sync(data: &[u8], history: &[u8], limit: usize) -> Result<Vec<u8>, ServerError> {
+    let history = &history[history.len().saturating_sub(WINDOW)..];
+    let mut reader = BitReader {
+        data,
+        at: 0,
+        bits: 0,
+        len: 0,
+    };
+    let mut out = history.to_vec();
+    inflate(
+        &mut reader,
+        &mut out,
+        true,
+        history.len().saturating_add(limit),
+    )?;
+    Ok(out.split_off(history.len()))
+}
+
+// Blocks of `reader` appended to `out`, stopping at a final one or, when
+// `sync`, where the data ends between blocks
+fn inflate(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    sync: bool,
+    limit: usize,
+) -> Result<(), ServerError> {
+    loop {
+        // What's left of the last byte is too short for another block
+        if sync && reader.at == reader.data.len() {
+            return Ok(());
+        }
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader
+                    .data
+                    .get(reader.at..reader.at + 4)
+                    .ok_or_else(|| corrupt("truncated"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("bad stored block"));
+                }
+                let start = reader.at + 4;
+                let stored = reader
+                    .data
+                    .get(start..start + len as usize)
+                    .ok_or_else(|| corrupt("truncated"))?;
+                out.extend_from_slice(stored);
+                reader.at = start + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths);
+                let distances = Huffman::new(&[5; 30]);
+                inflate_block(reader, &literals, &distances, out, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                inflate_block(reader, &literals, &distances, out, limit)?;
+            }
+            _ => return Err(corrupt("bad block type")),
+        }
+        if out.len() > limit {
+            return Err(corrupt("too large"));
+        }
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman), ServerError> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let code_length_count = reader.bits(4)? as usize + 4;
+    let mut code_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let code_lengths = Huffman::new(&code_lengths);
+
+    let mut lengths = Vec::with_capacity(literal_count + distance_count);
+    while lengths.len() < literal_count + distance_count {
+        let (value, repeat) = match code_lengths.decode(reader)? {
+            symbol @ 0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| corrupt("bad code lengths"))?;
+                (previous, 3 + reader.bits(2)?)
+            }
+            17 => (0, 3 + reader.bits(3)?),
+            _ => (0, 11 + reader.bits(7)?),
+        };
+        lengths.extend(std::iter::repeat_n(value, repeat as usize));
+    }
+    if lengths.len() > literal_count + distance_count {
+        return Err(corrupt("bad code lengths"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literal_count]),
+        Huffman::new(&lengths[literal_count..]),
+    ))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    literals: &Huffman,
+    distances: &Huffman,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> Result<(), ServerError> {
+    loop {
+        let symbol = literals.decode(reader)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let index = symbol - 257;
+                let base = *LENGTH_BASE
+                    .get(index)
+                    .ok_or_else(|| corrupt("bad length"))?;
+                let length = base as usize + reader.bits(u32::from(LENGTH_EXTRA[index]))? as usize;
+                let index = distances.decode(reader)? as usize;
+                let base = *DISTANCE_BASE
+                    .get(index)
+                    .ok_or_else(|| corrupt("bad distance"))?;
+                let distance =
+                    base as usize + reader.bits(u32::from(DISTANCE_EXTRA[index]))? as usize;
+                if distance > out.len() {
+                    return Err(corrupt("distance too far back"));
+                }
+                if out.len() + length > limit {
+                    return Err(corrupt("too large"));
+                }
+                // Byte by byte, as a match may overlap what it copies
+                let start = out.len() - distance;
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            }
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compress, crc32, decompress, deflate_sync, inflate_sync};
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        compress(data, &mut compressed);
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        compressed
+    }
+
+    #[test]
+    fn test_round_trips() {
+        round_trip(b"");
+        round_trip(b"a");
+        round_trip(b"abcabcabcabcabcabc");
+        round_trip(&[0u8; 1000]);
+        let bytes: Vec<u8> = (0..70_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        round_trip(&bytes);
+    }
+
+    #[test]
+    fn test_repetitive_json_shrinks() {
+        let frame: String = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"stream":"btcusdt@1m","data":{{"t":{},"o":42301.5}}}}"#,
+                    i * 60_000
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let compressed = round_trip(frame.as_bytes());
+        assert!(compressed.len() * 4 < frame.len(), "{}", compressed.len());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    // Nine `{"t":..,"c":..}` objects through zlib at level 9, which picks a
+    // dynamic Huffman block
+    #[test]
+    fn test_decompresses_other_encoders() {
+        let gzipped = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x55, 0xce, 0xc1, 0x0d,
+            0x80, 0x30, 0x08, 0x85, 0xe1, 0x5d, 0x38, 0x37, 0x06, 0xe8, 0x2b, 0xa9, 0x5d, 0xc7,
+            0x15, 0xbc, 0x35, 0xee, 0xae, 0x3d, 0x80, 0xe5, 0x46, 0xbe, 0x10, 0x7e, 0x26, 0xdd,
+            0x34, 0xb8, 0xd0, 0x45, 0x03, 0xca, 0xcc, 0x47, 0x7b, 0xe6, 0x22, 0xfb, 0xe6, 0x9f,
+            0xc5, 0x59, 0x34, 0x39, 0xc2, 0x7b, 0xf2, 0xd3, 0x5d, 0xb1, 0xbb, 0x98, 0x7b, 0xe5,
+            0xdd, 0xb5, 0x85, 0xa7, 0x6e, 0x8d, 0x7d, 0xa4, 0x2e, 0xe2, 0x3e, 0x52, 0xd7, 0xd6,
+            0x3f, 0x2f, 0xa9, 0x0a, 0x35, 0x31, 0xd2, 0x00, 0x00, 0x00,
+        ];
+        let expected: String = (0..9)
+            .map(|i| format!(r#"{{"t":{},"c":{}.5}}"#, i * 60_000, 42_000 + i * i % 97))
+            .collect();
+        assert_eq!(decompress(&gzipped).unwrap(), expected.as_bytes());
+    }
+
+    #[test]
+    fn test_rejects_corrupt_streams() {
+        let mut compressed = Vec::new();
+        compress(b"some frame worth sending", &mut compressed);
+        assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+        let crc = compressed.len() - 8;
+        compressed[crc] ^= 1;
+        assert!(decompress(&compressed).is_err());
+        assert!(decompress(b"not gzip at all, not at all").is_err());
+    }
+
+    #[test]
+    fn test_sync_flushed_messages_match_into_history() {
+        let first = br#"{"stream":"btcusdt@kline_1m","data":{"c":"42000.5"}}"#;
+        let second = br#"{"stream":"btcusdt@kline_1m","data":{"c":"42001.5"}}"#;
+        let mut alone = Vec::new();
+        deflate_sync(second, &[], &mut alone);
+        let mut following = Vec::new();
+        deflate_sync(second, first, &mut following);
+        assert!(following.len() * 3 < alone.len(), "{}", following.len());
+        assert!(following.ends_with(&[0, 0, 0xff, 0xff]));
+
+        assert_eq!(inflate_sync(&alone, &[], 1024).unwrap(), second);
+        assert_eq!(inflate_sync(&following, first, 1024).unwrap(), second);
+        assert!(inflate_sync(&following, &[], 1024).is_err());
+        assert!(inflate_sync(&following, first, 16).is_err());
+    }
+
+    // The same two messages through zlib with a sync flush after each and
+    // its trailing `00 00 ff ff` cut, as a permessage-deflate server sends
+    // them; the second is mostly a match into the first
+    #[test]
+    fn test_inflates_other_encoders_messages() {
+        let first = [
+            0xaa, 0x56, 0x2a, 0x2e, 0x29, 0x4a, 0x4d, 0xcc, 0x55, 0xb2, 0x52, 0x4a, 0x2a, 0x49,
+            0x2e, 0x2d, 0x4e, 0x29, 0x71, 0xc8, 0xce, 0xc9, 0xcc, 0x4b, 0x8d, 0x37, 0xcc, 0x55,
+            0xd2, 0x51, 0x4a, 0x49, 0x2c, 0x49, 0x54, 0xb2, 0xaa, 0x56, 0x4a, 0x06, 0x4a, 0x9b,
+            0x18, 0x19, 0x18, 0x18, 0xe8, 0x99, 0x2a, 0xd5, 0xd6, 0x02, 0x00, 0x00, 0x00, 0xff,
+            0xff,
+        ];
+        let second = [
+            0xaa, 0x26, 0x49, 0x8f, 0x21, 0x58, 0x0f, 0x00, 0x00, 0x00, 0xff, 0xff,
+        ];
+        let history = inflate_sync(&first, &[], 1024).unwrap();
+        assert_eq!(
+            history,
+            br#"{"stream":"btcusdt@kline_1m","data":{"c":"42000.5"}}"#
+        );
+        assert_eq!(
+            inflate_sync(&second, &history, 1024).unwrap(),
+            br#"{"stream":"btcusdt@kline_1m","data":{"c":"42001.5"}}"#
+        );
+    }
+}
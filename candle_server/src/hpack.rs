@@ -0,0 +1,447 @@
+//! HPACK (RFC 7541), the header compression of the gRPC front-end's
+//! HTTP/2 connections. Header blocks are decoded in full, dynamic table and
+//! Huffman-coded strings included, as gRPC clients use both. Encoding
+//! sticks to the static table and literals that are never added to the
+//! dynamic table, which every decoder takes.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+use crate::error::ServerError;
+
+/// Dynamic table size both ends start with, and the most a peer may ask
+/// for, as the SETTINGS sent leave it at the default.
+pub const TABLE_SIZE: usize = 4096;
+// Decoded names and values of one block together, HTTP/2's
+// MAX_HEADER_LIST_SIZE, which the SETTINGS sent leave unlimited
+const MAX_HEADER_LIST: usize = 16 * 1024;
+// Each table entry counts this much on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// Bit length of the Huffman code of each byte, then of EOS. The code is
+// canonical, so the lengths are all it takes to rebuild it.
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+const EOS: u16 = 256;
+const LONGEST_CODE: usize = 30;
+
+/// Decoder of one connection's header blocks, which share its dynamic
+/// table, so every block must go through it in the order received.
+#[derive(Debug)]
+pub struct Decoder {
+    // Newest first, as indexes count from it
+    table: VecDeque<(String, String)>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: TABLE_SIZE,
+        }
+    }
+
+    /// Headers of one whole block, HEADERS and any CONTINUATION payloads
+    /// joined, in order.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<(String, String)>, ServerError> {
+        let mut headers = Vec::new();
+        let mut list_size = 0;
+        while let Some(&first) = block.first() {
+            let header = if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                self.entry(index)?
+            } else if first & 0x40 != 0 {
+                let header = self.literal(&mut block, 6)?;
+                self.insert(header.clone());
+                header
+            } else if first & 0x20 != 0 {
+                let size = integer(&mut block, 5)?;
+                if size > TABLE_SIZE {
+                    return Err(error(format!("table size {} over {}", size, TABLE_SIZE)));
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // Without indexing or never indexed, which only matters to
+                // intermediaries
+                self.literal(&mut block, 4)?
+            };
+            list_size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+            if list_size > MAX_HEADER_LIST {
+                return Err(error(format!("header list over {} bytes", MAX_HEADER_LIST)));
+            }
+            headers.push(header);
+        }
+        Ok(headers)
+    }
+
+    // Static entries come first, from 1, then the dynamic ones
+    fn entry(&self, index: usize) -> Result<(String, String), ServerError> {
+        match index {
+            0 => Err(error("index 0".into())),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.into(), value.into()))
+            }
+            _ => self
+                .table
+                .get(index - 62)
+                .cloned()
+                .ok_or_else(|| error(format!("index {} past the table", index))),
+        }
+    }
+
+    // A literal header whose name is indexed after a `prefix` bit long
+    // pattern, or follows as a string when the index is 0
+    fn literal(&self, block: &mut &[u8], prefix: u32) -> Result<(String, String), ServerError> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.entry(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, header: (String, String)) {
+        let size = header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // One larger than the whole table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(header);
+        }
+    }
+
+    // Drops the oldest entries until `room` more bytes fit
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            let Some((name, value)) = self.table.pop_back() else {
+                break;
+            };
+            self.size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Appends `headers` as one block: fields the static table has whole are
+/// indexed, the rest are literals never added to the dynamic table, with
+/// the name indexed where the static table has it.
+pub fn encode(headers: &[(&str, &str)], out: &mut Vec<u8>) {
+    for &(name, value) in headers {
+        if let Some(index) = STATIC_TABLE
+            .iter()
+            .position(|&entry| entry == (name, value))
+        {
+            put_integer(out, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|&(known, _)| known == name) {
+            Some(index) => put_integer(out, 0x10, 4, index + 1),
+            None => {
+                out.push(0x10);
+                put_string(out, name);
+            }
+        }
+        put_string(out, value);
+    }
+}
+
+fn error(message: String) -> ServerError {
+    ServerError::Grpc(format!("HPACK: {}", message))
+}
+
+// An integer with a `prefix` bit long start, the rest of whose first byte
+// is taken up by the representation's flags
+fn integer(block: &mut &[u8], prefix: u32) -> Result<usize, ServerError> {
+    let truncated = || error("truncated integer".into());
+    let (&first, rest) = block.split_first().ok_or_else(truncated)?;
+    *block = rest;
+    let max = (1 << prefix) - 1;
+    let mut value = usize::from(first) & max;
+    if value < max {
+        return Ok(value);
+    }
+    for shift in (0..).step_by(7) {
+        // No length, index or size gets anywhere near this
+        if shift > 21 {
+            return Err(error("integer too large".into()));
+        }
+        let (&byte, rest) = block.split_first().ok_or_else(truncated)?;
+        *block = rest;
+        value += usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok(value)
+}
+
+fn put_integer(out: &mut Vec<u8>, flags: u8, prefix: u32, mut value: usize) {
+    let max = (1 << prefix) - 1;
+    if value < max {
+        out.push(flags | value as u8);
+        return;
+    }
+    out.push(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn string(block: &mut &[u8]) -> Result<String, ServerError> {
+    let huffman = block.first().is_some_and(|&first| first & 0x80 != 0);
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(error("truncated string".into()));
+    }
+    let (bytes, rest) = block.split_at(len);
+    *block = rest;
+    let bytes = if huffman {
+        huffman_decode(bytes)?
+    } else {
+        bytes.to_vec()
+    };
+    String::from_utf8(bytes).map_err(|_| error("string is not UTF-8".into()))
+}
+
+// Written as is; headers sent are short enough that Huffman coding them
+// saves next to nothing
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    put_integer(out, 0, 7, value.len());
+    out.extend_from_slice(value.as_bytes());
+}
+
+// Symbols ordered by code, and how many codes there are of each length
+struct Canonical {
+    symbols: Vec<u16>,
+    counts: [u32; LONGEST_CODE + 1],
+}
+
+fn canonical() -> &'static Canonical {
+    static CANONICAL: OnceLock<Canonical> = OnceLock::new();
+    CANONICAL.get_or_init(|| {
+        let mut symbols: Vec<u16> = (0..=EOS).collect();
+        symbols.sort_by_key(|&symbol| (HUFFMAN_LENGTHS[usize::from(symbol)], symbol));
+        let mut counts = [0; LONGEST_CODE + 1];
+        for &len in &HUFFMAN_LENGTHS {
+            counts[usize::from(len)] += 1;
+        }
+        Canonical { symbols, counts }
+    })
+}
+
+// Decodes a bit at a time, keeping the first code of the current length and
+// the index of its symbol. Padding must be the start of EOS, fewer than
+// eight one bits.
+fn huffman_decode(bytes: &[u8]) -> Result<Vec<u8>, ServerError> {
+    let Canonical { symbols, counts } = canonical();
+    let mut out = Vec::with_capacity(bytes.len() * 8 / 5);
+    let (mut code, mut first, mut index, mut len) = (0u32, 0u32, 0usize, 0usize);
+    // The bits of the code being read, for checking the padding
+    let mut bits = 0u32;
+    for byte in bytes {
+        for shift in (0..8).rev() {
+            let bit = u32::from(byte >> shift & 1);
+            code |= bit;
+            bits = bits << 1 | bit;
+            len += 1;
+            let count = counts[len];
+            if code < first + count {
+                let symbol = symbols[index + (code - first) as usize];
+                if symbol == EOS {
+                    return Err(error("EOS inside a string".into()));
+                }
+                out.push(symbol as u8);
+                (code, first, index, len, bits) = (0, 0, 0, 0, 0);
+                continue;
+            }
+            if len == LONGEST_CODE {
+                return Err(error("invalid Huffman code".into()));
+            }
+            index += count as usize;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+    }
+    if len > 7 || bits != (1 << len) - 1 {
+        return Err(error("invalid Huffman padding".into()));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, huffman_decode, Decoder};
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len())
+            .step_by(2)
+            .map(|at| u8::from_str_radix(&text[at..at + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.into(), value.into()))
+            .collect()
+    }
+
+    // RFC 7541 C.4: requests with Huffman-coded strings, the later ones
+    // indexing what the earlier ones added to the table
+    #[test]
+    fn test_decodes_huffman_requests_sharing_a_table() {
+        let mut decoder = Decoder::new();
+        let first = decoder
+            .decode(&hex("828684418cf1e3c2e5f23a6ba0ab90f4ff"))
+            .unwrap();
+        assert_eq!(
+            first,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+            ])
+        );
+
+        let second = decoder.decode(&hex("828684be5886a8eb10649cbf")).unwrap();
+        assert_eq!(
+            second,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                (":authority", "www.example.com"),
+                ("cache-control", "no-cache"),
+            ])
+        );
+
+        let third = decoder
+            .decode(&hex("828785bf408825a849e95ba97d7f8925a849e95bb8e8b4bf"))
+            .unwrap();
+        assert_eq!(
+            third,
+            headers(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                (":authority", "www.example.com"),
+                ("custom-key", "custom-value"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_encoded_headers_decode_back() {
+        let sent = [
+            (":status", "200"),
+            ("content-type", "application/grpc"),
+            ("grpc-status", "3"),
+            ("grpc-message", "Invalid character '!' at position 7"),
+        ];
+        let mut block = Vec::new();
+        encode(&sent, &mut block);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::new().decode(&block).unwrap(), headers(&sent));
+    }
+
+    #[test]
+    fn test_rejects_malformed_blocks() {
+        let mut decoder = Decoder::new();
+        // Index 0, an index past the table, a truncated string
+        for block in ["80", "be", "0003616263"] {
+            assert!(decoder.decode(&hex(block)).is_err(), "{}", block);
+        }
+        // A table larger than SETTINGS allow
+        assert!(decoder.decode(&hex("3fe21f")).is_err());
+        // Padding of zeros rather than the start of EOS
+        assert!(huffman_decode(&hex("f1e3c2e5f23a6ba0ab90f400")).is_err());
+    }
+}
@@ -0,0 +1,1041 @@
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+use crate::error::ServerError;
+use crate::protocol::ResultData;
+use crate::utils::{interval_to_millis, MILLIS_PER_DAY};
+
+// Longest window an indicator may ask for, in bars
+pub const MAX_WINDOW: usize = 10_000;
+// Binance trades every day of the year
+const MILLIS_PER_YEAR: f64 = 365.0 * MILLIS_PER_DAY as f64;
+
+/// An indicator a client asks to have computed over its results, e.g.
+/// `{"type":"volatility","window":30,"annualize":true}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IndicatorSpec {
+    // Standard deviation of the log returns of the last `window` closes,
+    // those of the magnitude for negative closes; a close of zero or across
+    // it has no return and is counted as `volatility_skipped`. Scaled
+    // to a year of bars when `annualize` is set
+    Volatility {
+        window: usize,
+        #[serde(default)]
+        annualize: bool,
+    },
+    // Mean of the last `period` closes, and bands `stddev` of their
+    // standard deviations above and below it
+    Bollinger {
+        period: usize,
+        #[serde(default = "default_band_width")]
+        stddev: f64,
+    },
+    // Wilder's relative strength index of the closes' changes
+    Rsi {
+        period: usize,
+    },
+    // Wilder's average true range of the bars
+    Atr {
+        period: usize,
+    },
+    // Difference of the `fast` and `slow` EMAs of the closes, its `signal`
+    // EMA, and how far apart the two are
+    Macd {
+        fast: usize,
+        slow: usize,
+        signal: usize,
+    },
+}
+
+fn default_band_width() -> f64 {
+    2.0
+}
+
+impl IndicatorSpec {
+    fn name(&self) -> &'static str {
+        match self {
+            IndicatorSpec::Volatility { .. } => "volatility",
+            IndicatorSpec::Bollinger { .. } => "bollinger",
+            IndicatorSpec::Rsi { .. } => "rsi",
+            IndicatorSpec::Atr { .. } => "atr",
+            IndicatorSpec::Macd { .. } => "macd",
+        }
+    }
+}
+
+/// Every field indicators add to results.
+pub const FIELDS: [&str; 10] = [
+    "volatility",
+    "volatility_skipped",
+    "bb_mid",
+    "bb_upper",
+    "bb_lower",
+    "rsi",
+    "atr",
+    "macd",
+    "macd_signal",
+    "macd_hist",
+];
+
+/// Mean and variance of the last `window` values, updated with Welford's
+/// algorithm as values enter and leave, so each update is O(1) and stays
+/// accurate however long it runs.
+pub struct RollingStats {
+    window: usize,
+    values: VecDeque<f64>,
+    mean: f64,
+    // Sum of squared distances from the mean
+    m2: f64,
+}
+
+impl RollingStats {
+    pub fn new(window: usize) -> RollingStats {
+        RollingStats {
+            window,
+            values: VecDeque::with_capacity(window),
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        if self.values.len() == self.window {
+            let oldest = self.values.pop_front().unwrap();
+            if self.values.is_empty() {
+                self.mean = 0.0;
+                self.m2 = 0.0;
+            } else {
+                let delta = oldest - self.mean;
+                self.mean -= delta / self.values.len() as f64;
+                self.m2 -= delta * (oldest - self.mean);
+            }
+        }
+        self.values.push_back(value);
+        let delta = value - self.mean;
+        self.mean += delta / self.values.len() as f64;
+        self.m2 += delta * (value - self.mean);
+    }
+
+    /// Whether the window has filled.
+    pub fn is_full(&self) -> bool {
+        self.values.len() == self.window
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    /// Variance of the values as the whole population, over `n`.
+    pub fn population_variance(&self) -> f64 {
+        match self.values.len() {
+            0 => 0.0,
+            n => self.m2.max(0.0) / n as f64,
+        }
+    }
+
+    /// Variance of the values as a sample, over `n - 1`.
+    pub fn sample_variance(&self) -> f64 {
+        match self.values.len() {
+            0 | 1 => 0.0,
+            n => self.m2.max(0.0) / (n - 1) as f64,
+        }
+    }
+}
+
+/// Exponential moving average over `period` values, seeded with the plain
+/// average of the first `period` of them.
+pub struct Ema {
+    period: usize,
+    // Values folded in, up to `period`; until then `value` is their sum
+    count: usize,
+    value: f64,
+}
+
+impl Ema {
+    pub fn new(period: usize) -> Ema {
+        Ema {
+            period,
+            count: 0,
+            value: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        if self.count < self.period {
+            self.value += value;
+            self.count += 1;
+            if self.count == self.period {
+                self.value /= self.period as f64;
+            }
+        } else {
+            let alpha = 2.0 / (self.period as f64 + 1.0);
+            self.value += alpha * (value - self.value);
+        }
+    }
+
+    /// `None` until seeded.
+    pub fn value(&self) -> Option<f64> {
+        (self.count == self.period).then_some(self.value)
+    }
+}
+
+enum Indicator {
+    Volatility {
+        returns: RollingStats,
+        // Applied to the standard deviation, 1 unless annualized
+        scale: f64,
+        previous: Option<f64>,
+        // Returns left out for a close that was zero or crossed it
+        skipped: u64,
+    },
+    Bollinger {
+        closes: RollingStats,
+        width: f64,
+    },
+    Rsi {
+        period: usize,
+        previous: Option<f64>,
+        // Changes folded in, up to `period`; until then the averages are
+        // sums of the first ones
+        changes: usize,
+        gain: f64,
+        loss: f64,
+    },
+    Atr {
+        period: usize,
+        previous: Option<f64>,
+        // True ranges folded in, up to `period`; until then `atr` is their sum
+        ranges: usize,
+        atr: f64,
+    },
+    Macd {
+        fast: Ema,
+        slow: Ema,
+        signal: Ema,
+        // Closes folded in, up to the warm-up of `slow` and `signal` bars
+        closes: usize,
+    },
+}
+
+impl Indicator {
+    fn new(spec: IndicatorSpec, interval: &str) -> Result<Indicator, ServerError> {
+        Ok(match spec {
+            IndicatorSpec::Volatility { window, annualize } => {
+                check_window(spec, window, 2)?;
+                let scale = if annualize {
+                    (MILLIS_PER_YEAR / interval_to_millis(interval, 0)? as f64).sqrt()
+                } else {
+                    1.0
+                };
+                Indicator::Volatility {
+                    returns: RollingStats::new(window),
+                    scale,
+                    previous: None,
+                    skipped: 0,
+                }
+            }
+            IndicatorSpec::Bollinger { period, stddev } => {
+                check_window(spec, period, 1)?;
+                if !(stddev.is_finite() && stddev >= 0.0) {
+                    return Err(ServerError::InvalidMessage(
+                        "bollinger stddev must be a number from 0".into(),
+                    ));
+                }
+                Indicator::Bollinger {
+                    closes: RollingStats::new(period),
+                    width: stddev,
+                }
+            }
+            IndicatorSpec::Rsi { period } => {
+                check_window(spec, period, 1)?;
+                Indicator::Rsi {
+                    period,
+                    previous: None,
+                    changes: 0,
+                    gain: 0.0,
+                    loss: 0.0,
+                }
+            }
+            IndicatorSpec::Atr { period } => {
+                check_window(spec, period, 1)?;
+                Indicator::Atr {
+                    period,
+                    previous: None,
+                    ranges: 0,
+                    atr: 0.0,
+                }
+            }
+            IndicatorSpec::Macd { fast, slow, signal } => {
+                check_window(spec, fast, 1)?;
+                check_window(spec, slow, 1)?;
+                check_window(spec, signal, 1)?;
+                if fast >= slow {
+                    return Err(ServerError::InvalidMessage(
+                        "macd fast period must be shorter than slow".into(),
+                    ));
+                }
+                Indicator::Macd {
+                    fast: Ema::new(fast),
+                    slow: Ema::new(slow),
+                    signal: Ema::new(signal),
+                    closes: 0,
+                }
+            }
+        })
+    }
+
+    // Back to the state of a new subscription
+    fn reset(&mut self) {
+        match self {
+            Indicator::Volatility {
+                returns, previous, ..
+            } => {
+                *returns = RollingStats::new(returns.window);
+                *previous = None;
+            }
+            Indicator::Bollinger { closes, .. } => *closes = RollingStats::new(closes.window),
+            Indicator::Rsi {
+                previous,
+                changes,
+                gain,
+                loss,
+                ..
+            } => {
+                *previous = None;
+                *changes = 0;
+                *gain = 0.0;
+                *loss = 0.0;
+            }
+            Indicator::Atr {
+                previous,
+                ranges,
+                atr,
+                ..
+            } => {
+                *previous = None;
+                *ranges = 0;
+                *atr = 0.0;
+            }
+            Indicator::Macd {
+                fast,
+                slow,
+                signal,
+                closes,
+            } => {
+                *fast = Ema::new(fast.period);
+                *slow = Ema::new(slow.period);
+                *signal = Ema::new(signal.period);
+                *closes = 0;
+            }
+        }
+    }
+
+    fn push(&mut self, bar: &ResultData, close: f64) {
+        match self {
+            Indicator::Volatility {
+                returns,
+                previous,
+                skipped,
+                ..
+            } => {
+                match *previous {
+                    // Of the magnitude for a negative series
+                    Some(previous) if close / previous > 0.0 => {
+                        returns.push((close / previous).ln())
+                    }
+                    Some(_) => *skipped += 1,
+                    None => {}
+                }
+                *previous = Some(close);
+            }
+            Indicator::Bollinger { closes, .. } => closes.push(close),
+            // Seeded with the plain average of the first `period` changes,
+            // then smoothed by 1/period per change
+            Indicator::Rsi {
+                period,
+                previous,
+                changes,
+                gain,
+                loss,
+            } => {
+                if let Some(previous) = previous.replace(close) {
+                    let change = close - previous;
+                    let length = *period as f64;
+                    if *changes < *period {
+                        *gain += change.max(0.0);
+                        *loss += (-change).max(0.0);
+                        *changes += 1;
+                        if *changes == *period {
+                            *gain /= length;
+                            *loss /= length;
+                        }
+                    } else {
+                        *gain = (*gain * (length - 1.0) + change.max(0.0)) / length;
+                        *loss = (*loss * (length - 1.0) + (-change).max(0.0)) / length;
+                    }
+                }
+            }
+            // The high and low of a composite bar needn't bound it, e.g. of
+            // `a-b`, so its range is taken over all four prices
+            Indicator::Atr {
+                period,
+                previous,
+                ranges,
+                atr,
+            } => {
+                let prices = [bar.o, bar.h, bar.l, Some(close)];
+                let high = prices.iter().flatten().copied().fold(close, f64::max);
+                let low = prices.iter().flatten().copied().fold(close, f64::min);
+                let range = match previous.replace(close) {
+                    Some(previous) => (high - low)
+                        .max((high - previous).abs())
+                        .max((low - previous).abs()),
+                    None => high - low,
+                };
+                let length = *period as f64;
+                if *ranges < *period {
+                    *atr += range;
+                    *ranges += 1;
+                    if *ranges == *period {
+                        *atr /= length;
+                    }
+                } else {
+                    *atr = (*atr * (length - 1.0) + range) / length;
+                }
+            }
+            Indicator::Macd {
+                fast,
+                slow,
+                signal,
+                closes,
+            } => {
+                fast.push(close);
+                slow.push(close);
+                if let (Some(fast), Some(slow)) = (fast.value(), slow.value()) {
+                    signal.push(fast - slow);
+                }
+                *closes = (*closes + 1).min(slow.period + signal.period);
+            }
+        }
+    }
+
+    fn write(&self, data: &mut ResultData) {
+        match self {
+            Indicator::Volatility {
+                returns,
+                scale,
+                skipped,
+                ..
+            } => {
+                let volatility = returns
+                    .is_full()
+                    .then(|| returns.sample_variance().sqrt() * scale);
+                data.indicators.push(("volatility", volatility));
+                if *skipped > 0 {
+                    data.indicators
+                        .push(("volatility_skipped", Some(*skipped as f64)));
+                }
+            }
+            // Bands are as wide as the population's deviation, as usual
+            Indicator::Bollinger { closes, width } => {
+                let full = closes.is_full();
+                let mid = closes.mean();
+                let band = width * closes.population_variance().sqrt();
+                data.indicators.push(("bb_mid", full.then_some(mid)));
+                data.indicators
+                    .push(("bb_upper", full.then_some(mid + band)));
+                data.indicators
+                    .push(("bb_lower", full.then_some(mid - band)));
+            }
+            // A flat window is neither overbought nor oversold
+            Indicator::Rsi {
+                period,
+                changes,
+                gain,
+                loss,
+                ..
+            } => {
+                let rsi = (changes == period).then(|| match (*gain, *loss) {
+                    (gain, loss) if loss > 0.0 => 100.0 - 100.0 / (1.0 + gain / loss),
+                    (gain, _) if gain > 0.0 => 100.0,
+                    _ => 50.0,
+                });
+                data.indicators.push(("rsi", rsi));
+            }
+            Indicator::Atr {
+                period,
+                ranges,
+                atr,
+                ..
+            } => data
+                .indicators
+                .push(("atr", (ranges == period).then_some(*atr))),
+            // All three wait out `slow + signal` bars, a bar past the
+            // signal's seed
+            Indicator::Macd {
+                fast,
+                slow,
+                signal,
+                closes,
+            } => {
+                let warm = *closes == slow.period + signal.period;
+                let macd = fast
+                    .value()
+                    .zip(slow.value())
+                    .map(|(fast, slow)| fast - slow)
+                    .filter(|_| warm);
+                let line = signal.value().filter(|_| warm);
+                data.indicators.push(("macd", macd));
+                data.indicators.push(("macd_signal", line));
+                data.indicators
+                    .push(("macd_hist", macd.zip(line).map(|(macd, line)| macd - line)));
+            }
+        }
+    }
+}
+
+fn check_window(spec: IndicatorSpec, window: usize, min: usize) -> Result<(), ServerError> {
+    if (min..=MAX_WINDOW).contains(&window) {
+        return Ok(());
+    }
+    Err(ServerError::InvalidMessage(format!(
+        "{} window must be {} to {} bars",
+        spec.name(),
+        min,
+        MAX_WINDOW
+    )))
+}
+
+/// The indicators of one client's subscription, computed over the closes
+/// of its closed bars. Results of a bar still open carry the values as of
+/// the last closed one; until an indicator's window fills they're null.
+pub struct Indicators {
+    indicators: Vec<Indicator>,
+    // Newest closed bar folded in, so a repeat or a late bar isn't again
+    last_closed: Option<u64>,
+}
+
+impl Indicators {
+    /// `interval` is the one of the bars the results are for.
+    pub fn new(specs: &[IndicatorSpec], interval: &str) -> Result<Indicators, ServerError> {
+        let mut indicators = Vec::with_capacity(specs.len());
+        for (i, spec) in specs.iter().enumerate() {
+            // Each writes fields named after its type
+            if specs[..i].iter().any(|other| other.name() == spec.name()) {
+                return Err(ServerError::InvalidMessage(format!(
+                    "indicator {} given twice",
+                    spec.name()
+                )));
+            }
+            indicators.push(Indicator::new(*spec, interval)?);
+        }
+        Ok(Indicators {
+            indicators,
+            last_closed: None,
+        })
+    }
+
+    /// Starts every indicator over, as bars missed in a gap would otherwise
+    /// pass for a single change.
+    pub fn reset(&mut self) {
+        for indicator in &mut self.indicators {
+            indicator.reset();
+        }
+    }
+
+    /// Folds in `data` if it's a newer `closed` bar, then adds every
+    /// indicator's fields to it.
+    pub fn update(&mut self, data: &mut ResultData, closed: bool) {
+        if let Some(close) = data.c.filter(|_| closed) {
+            if self.last_closed.is_none_or(|t| data.t > t) {
+                self.last_closed = Some(data.t);
+                for indicator in &mut self.indicators {
+                    indicator.push(data, close);
+                }
+            }
+        }
+        for indicator in &self.indicators {
+            indicator.write(data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ema, IndicatorSpec, Indicators, RollingStats};
+    use crate::protocol::ResultData;
+
+    // Closes of a fixture series
+    const CLOSES: [f64; 10] = [10.0, 10.5, 10.2, 10.8, 11.0, 10.6, 10.9, 11.4, 11.1, 11.5];
+
+    fn bar(t: u64, c: f64) -> ResultData {
+        ResultData {
+            c: Some(c),
+            ..ResultData::missing(t)
+        }
+    }
+
+    fn volatility(data: &ResultData) -> Option<f64> {
+        data.indicators
+            .iter()
+            .find(|(name, _)| *name == "volatility")
+            .and_then(|&(_, value)| value)
+    }
+
+    // Sample standard deviation of the log returns of `closes`
+    fn offline(closes: &[f64]) -> f64 {
+        let returns: Vec<f64> = closes.windows(2).map(|w| (w[1] / w[0]).ln()).collect();
+        let n = returns.len() as f64;
+        let mean = returns.iter().sum::<f64>() / n;
+        (returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+    }
+
+    fn spec(window: usize, annualize: bool) -> IndicatorSpec {
+        IndicatorSpec::Volatility { window, annualize }
+    }
+
+    #[test]
+    fn test_rolling_stats_match_the_window() {
+        let mut stats = RollingStats::new(3);
+        for value in [1.0, 2.0, 4.0, 8.0, 16.0] {
+            stats.push(value);
+        }
+        // Over 4, 8 and 16
+        assert!((stats.mean() - 28.0 / 3.0).abs() < 1e-12);
+        assert!((stats.sample_variance() - 37.333333333333336).abs() < 1e-9);
+        assert!(stats.is_full());
+    }
+
+    #[test]
+    fn test_volatility_matches_offline_computation() {
+        let window = 4;
+        let mut indicators = Indicators::new(&[spec(window, false)], "1m").unwrap();
+        for (i, &close) in CLOSES.iter().enumerate() {
+            let mut data = bar(i as u64 * 60_000, close);
+            indicators.update(&mut data, true);
+            // A window of returns takes a bar more than it has returns
+            if i < window {
+                assert_eq!(data.indicators, [("volatility", None)], "bar {}", i);
+                continue;
+            }
+            let expected = offline(&CLOSES[i - window..=i]);
+            assert!((volatility(&data).unwrap() - expected).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_annualized_by_the_interval() {
+        for (interval, bars_per_year) in [("1m", 525_600.0_f64), ("1h", 8_760.0)] {
+            let mut plain = Indicators::new(&[spec(3, false)], interval).unwrap();
+            let mut annual = Indicators::new(&[spec(3, true)], interval).unwrap();
+            let (mut a, mut b) = (bar(0, 0.0), bar(0, 0.0));
+            for (i, &close) in CLOSES[..4].iter().enumerate() {
+                a = bar(i as u64, close);
+                b = bar(i as u64, close);
+                plain.update(&mut a, true);
+                annual.update(&mut b, true);
+            }
+            let factor = volatility(&b).unwrap() / volatility(&a).unwrap();
+            assert!((factor - bars_per_year.sqrt()).abs() < 1e-9, "{}", interval);
+        }
+    }
+
+    #[test]
+    fn test_only_closed_bars_move_it() {
+        let mut indicators = Indicators::new(&[spec(2, false)], "1m").unwrap();
+        for (i, &close) in CLOSES[..3].iter().enumerate() {
+            indicators.update(&mut bar(i as u64, close), true);
+        }
+        let mut closed = bar(2, CLOSES[2]);
+        indicators.update(&mut closed, true);
+        let mut open = bar(3, 1_000.0);
+        indicators.update(&mut open, false);
+        // Neither the repeat nor the open bar changed it
+        assert_eq!(volatility(&open), volatility(&closed));
+        assert!((volatility(&open).unwrap() - offline(&CLOSES[..3])).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_closes_crossing_zero_are_skipped_and_counted() {
+        let mut indicators = Indicators::new(&[spec(2, false)], "1m").unwrap();
+        let mut data = bar(0, 0.0);
+        for (t, close) in [2.0, 3.0, -1.0, 2.0, 2.5, 2.0].into_iter().enumerate() {
+            data = bar(t as u64, close);
+            indicators.update(&mut data, true);
+        }
+        // Into and out of -1
+        assert!(data.indicators.contains(&("volatility_skipped", Some(2.0))));
+        assert!((volatility(&data).unwrap() - offline(&[2.0, 2.5, 2.0])).abs() < 1e-12);
+    }
+
+    // Mean and 2-deviation bands of 5 closes, as Python's `statistics` has
+    // them, from bar 4 on
+    const BANDS: [(f64, f64, f64); 6] = [
+        (10.5, 11.237563556583432, 9.762436443416568),
+        (10.62, 11.162586398650022, 10.07741360134998),
+        (10.7, 11.265685424949238, 10.13431457505076),
+        (10.94, 11.470659966456864, 10.409340033543135),
+        (11.0, 11.521536192416212, 10.478463807583788),
+        (11.1, 11.757267069006199, 10.4427329309938),
+    ];
+
+    fn field(data: &ResultData, name: &str) -> Option<f64> {
+        data.indicators
+            .iter()
+            .find(|(field, _)| *field == name)
+            .and_then(|&(_, value)| value)
+    }
+
+    #[test]
+    fn test_bollinger_matches_reference() {
+        let bollinger = IndicatorSpec::Bollinger {
+            period: 5,
+            stddev: 2.0,
+        };
+        let mut indicators = Indicators::new(&[bollinger], "1m").unwrap();
+        for (i, &close) in CLOSES.iter().enumerate() {
+            let mut data = bar(i as u64, close);
+            indicators.update(&mut data, true);
+            if i < 4 {
+                assert_eq!(
+                    data.indicators,
+                    [("bb_mid", None), ("bb_upper", None), ("bb_lower", None)]
+                );
+                continue;
+            }
+            let (mid, upper, lower) = BANDS[i - 4];
+            assert!(
+                (field(&data, "bb_mid").unwrap() - mid).abs() < 1e-9,
+                "bar {}",
+                i
+            );
+            assert!((field(&data, "bb_upper").unwrap() - upper).abs() < 1e-9);
+            assert!((field(&data, "bb_lower").unwrap() - lower).abs() < 1e-9);
+
+            // An open bar after it carries the same bands
+            let mut open = bar(i as u64 + 1, 50.0);
+            indicators.update(&mut open, false);
+            assert_eq!(open.indicators, data.indicators);
+        }
+    }
+
+    #[test]
+    fn test_bollinger_default_width() {
+        let spec: IndicatorSpec =
+            serde_json::from_str(r#"{"type":"bollinger","period":20}"#).unwrap();
+        assert_eq!(
+            spec,
+            IndicatorSpec::Bollinger {
+                period: 20,
+                stddev: 2.0
+            }
+        );
+        let volatility = IndicatorSpec::Volatility {
+            window: 3,
+            annualize: false,
+        };
+        // Both at once write their own fields
+        let mut indicators = Indicators::new(&[volatility, spec], "1m").unwrap();
+        let mut data = bar(0, 1.0);
+        indicators.update(&mut data, true);
+        assert_eq!(data.indicators.len(), 4);
+    }
+
+    // StockCharts' published 14-bar RSI example, with the RSI it lists from
+    // bar 14 on. Its sheet rounds the averages, so it's off by up to 0.07
+    const RSI_CLOSES: [f64; 33] = [
+        44.34, 44.09, 44.15, 43.61, 44.33, 44.83, 45.10, 45.42, 45.84, 46.08, 45.89, 46.03, 45.61,
+        46.28, 46.28, 46.00, 46.03, 46.41, 46.22, 45.64, 46.21, 46.25, 45.71, 46.45, 45.78, 45.35,
+        44.03, 44.18, 44.22, 44.57, 43.42, 42.66, 43.13,
+    ];
+    const PUBLISHED_RSI: [f64; 19] = [
+        70.53, 66.32, 66.55, 69.41, 66.36, 57.97, 62.93, 63.26, 56.06, 62.38, 54.71, 50.42, 39.99,
+        41.46, 41.87, 45.46, 37.30, 33.08, 37.77,
+    ];
+    // The same without rounding, to 2 places
+    const EXACT_RSI: [f64; 19] = [
+        70.46, 66.25, 66.48, 69.35, 66.29, 57.92, 62.88, 63.21, 56.01, 62.34, 54.67, 50.39, 40.02,
+        41.49, 41.9, 45.5, 37.32, 33.09, 37.79,
+    ];
+
+    #[test]
+    fn test_rsi_matches_published_example() {
+        let mut indicators = Indicators::new(&[IndicatorSpec::Rsi { period: 14 }], "1d").unwrap();
+        for (i, &close) in RSI_CLOSES.iter().enumerate() {
+            let mut data = bar(i as u64, close);
+            indicators.update(&mut data, true);
+            let rsi = field(&data, "rsi");
+            if i < 14 {
+                assert_eq!(rsi, None, "bar {}", i);
+                continue;
+            }
+            let rsi = rsi.unwrap();
+            assert!(
+                (rsi - PUBLISHED_RSI[i - 14]).abs() < 0.1,
+                "bar {}: {}",
+                i,
+                rsi
+            );
+            assert!(
+                (rsi - EXACT_RSI[i - 14]).abs() < 0.005,
+                "bar {}: {}",
+                i,
+                rsi
+            );
+        }
+    }
+
+    #[test]
+    fn test_rsi_of_one_way_and_flat_series() {
+        let rsi = |closes: &[f64]| {
+            let mut indicators =
+                Indicators::new(&[IndicatorSpec::Rsi { period: 3 }], "1m").unwrap();
+            let mut data = bar(0, 0.0);
+            for (t, &close) in closes.iter().enumerate() {
+                data = bar(t as u64, close);
+                indicators.update(&mut data, true);
+            }
+            field(&data, "rsi")
+        };
+        assert_eq!(rsi(&[1.0, 2.0, 3.0, 4.0]), Some(100.0));
+        assert_eq!(rsi(&[4.0, 3.0, 2.0, 1.0]), Some(0.0));
+        assert_eq!(rsi(&[2.0, 2.0, 2.0, 2.0]), Some(50.0));
+        assert_eq!(rsi(&[1.0, 2.0, 3.0]), None);
+    }
+
+    #[test]
+    fn test_reset_starts_over() {
+        let specs = [
+            IndicatorSpec::Rsi { period: 2 },
+            IndicatorSpec::Bollinger {
+                period: 2,
+                stddev: 2.0,
+            },
+        ];
+        let mut indicators = Indicators::new(&specs, "1m").unwrap();
+        for (t, close) in [1.0, 2.0, 3.0].into_iter().enumerate() {
+            indicators.update(&mut bar(t as u64, close), true);
+        }
+        indicators.reset();
+        let mut data = bar(10, 4.0);
+        indicators.update(&mut data, true);
+        assert!(data.indicators.iter().all(|(_, value)| value.is_none()));
+
+        // The change across the gap isn't counted
+        let mut data = bar(11, 3.0);
+        indicators.update(&mut data, true);
+        assert_eq!(field(&data, "rsi"), None);
+        assert_eq!(field(&data, "bb_mid"), Some(3.5));
+    }
+
+    // Bars of two legs as (o, h, l, c); the spread between them has highs
+    // below its open and lows above it
+    const ATR_LEGS: [[(f64, f64, f64, f64); 2]; 8] = [
+        [(100.0, 102.0, 99.0, 101.0), (50.0, 51.0, 49.0, 50.5)],
+        [(101.0, 104.0, 100.0, 103.0), (50.5, 52.0, 50.0, 51.0)],
+        [(103.0, 103.0, 98.0, 99.0), (51.0, 51.5, 48.0, 48.5)],
+        [(99.0, 101.0, 97.0, 100.0), (48.5, 49.0, 47.0, 48.0)],
+        [(100.0, 106.0, 100.0, 105.0), (48.0, 50.0, 47.5, 49.5)],
+        [(105.0, 107.0, 103.0, 104.0), (49.5, 50.0, 49.0, 49.2)],
+        [(104.0, 105.0, 101.0, 102.0), (49.2, 49.5, 48.0, 48.1)],
+        [(102.0, 104.0, 100.0, 103.0), (48.1, 49.0, 47.9, 48.8)],
+    ];
+    // 3-bar ATR of `btcusdt-ethusdt` over them, from a reference computation
+    const ATR: [f64; 6] = [
+        1.6666666666666667,
+        1.777777777777778,
+        2.5185185185185186,
+        2.6790123456790127,
+        2.6193415637860085,
+        2.7128943758573385,
+    ];
+
+    #[test]
+    fn test_atr_of_a_composed_expression() {
+        use crate::candle::Candle;
+        use crate::expr::Expr;
+
+        let (expr, _) = Expr::parse("btcusdt-ethusdt@1m").unwrap();
+        let mut indicators = Indicators::new(&[IndicatorSpec::Atr { period: 3 }], "1m").unwrap();
+        for (i, legs) in ATR_LEGS.iter().enumerate() {
+            let t = i as u64 * 60_000;
+            let leg = |symbol: &str| {
+                let (o, h, l, c) = legs[usize::from(symbol == "ethusdt")];
+                Some(Candle::new(t, o, c, h, l).with_closed(true))
+            };
+            let candle = expr.eval(&leg).unwrap();
+            let mut data = ResultData::from(candle);
+            indicators.update(&mut data, candle.closed);
+            let atr = field(&data, "atr");
+            if i < 2 {
+                assert_eq!(atr, None, "bar {}", i);
+                continue;
+            }
+            assert!((atr.unwrap() - ATR[i - 2]).abs() < 1e-12, "bar {}", i);
+        }
+    }
+
+    // MACD 5/10/4 of `RSI_CLOSES` from a reference computation, from the
+    // 14th close on, as (macd, signal, histogram)
+    const MACD: [(f64, f64, f64); 20] = [
+        (0.4609942578982995, 0.5439972734979535, -0.08300301559965395),
+        (0.43484026272746235, 0.500334469189757, -0.06549420646229465),
+        (
+            0.35179624943867793,
+            0.4409191812893254,
+            -0.08912293185064746,
+        ),
+        (
+            0.2897239846485178,
+            0.38044110263300235,
+            -0.09071711798448456,
+        ),
+        (
+            0.29588311387518473,
+            0.3466179071298753,
+            -0.05073479325469055,
+        ),
+        (
+            0.2525224501882022,
+            0.30897972435320603,
+            -0.056457274165003846,
+        ),
+        (
+            0.12568800028693516,
+            0.2356630347266977,
+            -0.10997503443976253,
+        ),
+        (
+            0.13525175486884677,
+            0.19549852278355734,
+            -0.060246767914710564,
+        ),
+        (
+            0.13833187828511484,
+            0.17263186498418034,
+            -0.034299986699065504,
+        ),
+        (
+            0.049810013585798174,
+            0.12350312442482747,
+            -0.0736931108390293,
+        ),
+        (
+            0.11062778353249314,
+            0.11835298806789374,
+            -0.007725204535400598,
+        ),
+        (
+            0.03558124692568754,
+            0.08524429161101126,
+            -0.04966304468532372,
+        ),
+        (
+            -0.07266118194624482,
+            0.022082102188108823,
+            -0.09474328413435365,
+        ),
+        (
+            -0.327298798788739,
+            -0.11767025820263032,
+            -0.2096285405861087,
+        ),
+        (-0.4236284807762303, -0.24005354723207029, -0.18357493354416),
+        (
+            -0.44443688423751837,
+            -0.32180688203424956,
+            -0.1226300022032688,
+        ),
+        (
+            -0.3758210507171569,
+            -0.3434125495074125,
+            -0.03240850120974437,
+        ),
+        (
+            -0.4898596232989476,
+            -0.4019913790240266,
+            -0.08786824427492101,
+        ),
+        (
+            -0.6375255342042294,
+            -0.49620504109610775,
+            -0.14132049310812167,
+        ),
+        (-0.6082205441401882, -0.54101124231374, -0.06720930182644824),
+    ];
+
+    #[test]
+    fn test_macd_matches_reference() {
+        let macd = IndicatorSpec::Macd {
+            fast: 5,
+            slow: 10,
+            signal: 4,
+        };
+        let mut indicators = Indicators::new(&[macd], "1d").unwrap();
+        for (i, &close) in RSI_CLOSES.iter().enumerate() {
+            let mut data = bar(i as u64, close);
+            indicators.update(&mut data, true);
+            if i < 13 {
+                assert!(
+                    data.indicators.iter().all(|(_, value)| value.is_none()),
+                    "bar {}",
+                    i
+                );
+                continue;
+            }
+            let (macd, signal, hist) = MACD[i - 13];
+            assert!(
+                (field(&data, "macd").unwrap() - macd).abs() < 1e-12,
+                "bar {}",
+                i
+            );
+            assert!((field(&data, "macd_signal").unwrap() - signal).abs() < 1e-12);
+            assert!((field(&data, "macd_hist").unwrap() - hist).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_ema_is_seeded_with_the_average() {
+        let mut ema = Ema::new(3);
+        ema.push(1.0);
+        ema.push(2.0);
+        assert_eq!(ema.value(), None);
+        ema.push(6.0);
+        assert_eq!(ema.value(), Some(3.0));
+        ema.push(5.0);
+        assert_eq!(ema.value(), Some(4.0));
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        assert!(Indicators::new(&[spec(1, false)], "1m").is_err());
+        assert!(Indicators::new(&[spec(super::MAX_WINDOW + 1, false)], "1m").is_err());
+        assert!(Indicators::new(&[spec(5, true)], "ticker").is_err());
+        assert!(Indicators::new(&[spec(5, false), spec(6, false)], "1m").is_err());
+        let bollinger = |period, stddev| IndicatorSpec::Bollinger { period, stddev };
+        assert!(Indicators::new(&[bollinger(0, 2.0)], "1m").is_err());
+        assert!(Indicators::new(&[bollinger(20, -1.0)], "1m").is_err());
+        assert!(Indicators::new(&[IndicatorSpec::Atr { period: 0 }], "1m").is_err());
+        let macd = |fast, slow| IndicatorSpec::Macd {
+            fast,
+            slow,
+            signal: 9,
+        };
+        assert!(Indicators::new(&[macd(26, 12)], "1m").is_err());
+        assert!(Indicators::new(&[macd(0, 12)], "1m").is_err());
+    }
+
+    #[test]
+    fn test_spec_wire_format() {
+        let spec: IndicatorSpec =
+            serde_json::from_str(r#"{"type":"volatility","window":30,"annualize":true}"#).unwrap();
+        assert_eq!(
+            spec,
+            IndicatorSpec::Volatility {
+                window: 30,
+                annualize: true
+            }
+        );
+    }
+}
@@ -0,0 +1,1058 @@
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, timeout, Duration, Instant};
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::encoding::OutputEncoder;
+use crate::error::ServerError;
+use crate::memory::MemoryAccount;
+use crate::protobuf;
+use crate::protocol::ServerMessage;
+use crate::sink::{
+    FlushRequest, SinkCounters, SinkHandle, SinkReceiver, SinkRecord, SINK_QUEUE_CAPACITY,
+};
+
+// A broker taking longer than this to answer counts as a failed connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// How long the leader waits for its replicas before answering a produce
+const REPLICATION_TIMEOUT_MS: i32 = 5_000;
+// Answers bigger than this are a broken connection rather than a reply
+const MAX_RESPONSE: usize = 16 << 20;
+
+// API keys and the versions spoken, the oldest Kafka 4 still accepts for
+// a produce
+const PRODUCE: i16 = 0;
+const PRODUCE_VERSION: i16 = 3;
+const METADATA: i16 = 3;
+const METADATA_VERSION: i16 = 1;
+// Every in-sync replica has the records before they count as delivered
+const ACKS_ALL: i16 = -1;
+
+/// Which partition of the topic a result goes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PartitionKey {
+    // Keyed by canonical expression and partitioned as Kafka's own clients
+    // would, so each series stays in order within one partition
+    #[default]
+    Expression,
+    // Unkeyed, spread over the partitions in turn
+    RoundRobin,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaSinkConfig {
+    // Brokers, `host:port`, asked for the topic's partition leaders; plain
+    // TCP only, without SASL
+    pub brokers: Vec<String>,
+    pub topic: String,
+    pub client_id: String,
+    pub partition_key: PartitionKey,
+    // Record values are results as clients of this encoding get them
+    pub format: OutputEncoder,
+    // Records sent in one produce request, and longest one waits for it
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    // Records held while Kafka is down; beyond this the oldest are dropped
+    pub max_buffered_records: usize,
+}
+
+impl Default for KafkaSinkConfig {
+    fn default() -> Self {
+        KafkaSinkConfig {
+            brokers: vec!["127.0.0.1:9092".into()],
+            topic: "candles".into(),
+            client_id: "candle_server".into(),
+            partition_key: PartitionKey::default(),
+            format: OutputEncoder::default(),
+            batch_size: 1000,
+            flush_interval: Duration::from_millis(100),
+            max_buffered_records: 100_000,
+        }
+    }
+}
+
+/// Starts a task producing every record to a Kafka topic in batches. Failed
+/// produces are retried with `backoff`, holding records meanwhile up to
+/// `max_buffered_records`; `SinkHandle::flush` sends what's held at once.
+pub fn spawn(config: KafkaSinkConfig, backoff: BackoffConfig, memory: MemoryAccount) -> SinkHandle {
+    let (handle, rx) = SinkHandle::new("kafka", SINK_QUEUE_CAPACITY, memory.clone());
+    let (handle, flushes) = handle.with_flush();
+    let counters = handle.counters().clone();
+    tokio::spawn(run(config, backoff, rx, flushes, counters, memory));
+    handle
+}
+
+/// Partition of `key` among `partitions`, as Kafka's default partitioner
+/// picks it.
+pub fn partition(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+// MurmurHash2 as Kafka's clients hash keys
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= u32::from(byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+// CRC-32C, which record batches are checked with
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// A record waiting to be produced
+struct Pending {
+    key: String,
+    value: Vec<u8>,
+    // Unix millis it was taken from the sink's queue
+    timestamp: i64,
+}
+
+impl Pending {
+    fn new(config: &KafkaSinkConfig, record: &SinkRecord) -> Result<Pending, ServerError> {
+        let mut value = Vec::new();
+        match config.format {
+            OutputEncoder::Protobuf => {
+                let message = ServerMessage::Result(record.message.clone());
+                protobuf::write_message(&message, &mut value)?;
+            }
+            encoder => encoder.encode(&record.message, &mut value)?,
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as i64);
+        Ok(Pending {
+            key: record.key.clone(),
+            value,
+            timestamp,
+        })
+    }
+
+    fn size(&self) -> usize {
+        self.key.len() + self.value.len()
+    }
+}
+
+async fn run(
+    config: KafkaSinkConfig,
+    backoff: BackoffConfig,
+    mut rx: SinkReceiver,
+    mut flushes: mpsc::Receiver<FlushRequest>,
+    counters: Arc<SinkCounters>,
+    memory: MemoryAccount,
+) {
+    let mut producer = Producer::new(config.clone());
+    let mut backoff = Backoff::new(backoff);
+    let mut sink = Buffer {
+        pending: VecDeque::new(),
+        config: &config,
+        counters: &counters,
+        memory: &memory,
+    };
+    // Set once a record is held, for when it has waited `flush_interval`
+    let mut flush_at: Option<Instant> = None;
+    // Set after a failed produce, which nothing is sent before
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        let due = match retry_at {
+            Some(at) => Instant::now() >= at,
+            None => {
+                sink.pending.len() >= config.batch_size
+                    || flush_at.is_some_and(|at| Instant::now() >= at)
+            }
+        };
+        if !due {
+            let wake = retry_at.or(flush_at);
+            tokio::select! {
+                record = rx.recv() => {
+                    let Some(record) = record else {
+                        // Whatever is left gets one try
+                        producer.flush(&mut sink).await;
+                        return;
+                    };
+                    sink.push(&record);
+                    flush_at.get_or_insert(Instant::now() + config.flush_interval);
+                }
+                Some(flush) = flushes.recv() => {
+                    while let Some(record) = rx.try_recv() {
+                        sink.push(&record);
+                    }
+                    producer.flush(&mut sink).await;
+                    let _ = flush.send(());
+                    if sink.pending.is_empty() {
+                        flush_at = None;
+                    }
+                }
+                _ = sleep_until(wake.unwrap_or_else(Instant::now)), if wake.is_some() => {}
+            }
+            continue;
+        }
+
+        match producer.send(&mut sink).await {
+            Ok(()) => {
+                if !counters.connected.swap(true, Ordering::Relaxed) {
+                    info!("Producing to Kafka topic {}", config.topic);
+                }
+                backoff.reset();
+                retry_at = None;
+            }
+            Err(e) => {
+                counters.connected.store(false, Ordering::Relaxed);
+                match backoff.next_delay() {
+                    Some(delay) => {
+                        warn!("Kafka produce attempt {} failed: {}", backoff.attempt(), e);
+                        retry_at = Some(Instant::now() + delay);
+                    }
+                    None => {
+                        warn!(
+                            "Giving up on {} records after {} Kafka attempts: {}",
+                            sink.pending.len(),
+                            backoff.attempt(),
+                            e
+                        );
+                        sink.give_up();
+                        backoff.reset();
+                        retry_at = None;
+                    }
+                }
+            }
+        }
+        // Those left beyond a batch are overdue already
+        flush_at = if sink.pending.is_empty() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+    }
+}
+
+// Records held by the sink task, charged to its memory account
+struct Buffer<'a> {
+    pending: VecDeque<Pending>,
+    config: &'a KafkaSinkConfig,
+    counters: &'a SinkCounters,
+    memory: &'a MemoryAccount,
+}
+
+impl Buffer<'_> {
+    fn push(&mut self, record: &SinkRecord) {
+        let pending = match Pending::new(self.config, record) {
+            Ok(pending) => pending,
+            Err(e) => {
+                warn!("Can not write {} for Kafka: {}", record.key, e);
+                self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        if self.pending.len() >= self.config.max_buffered_records {
+            if let Some(oldest) = self.pending.pop_front() {
+                self.memory.release(oldest.size());
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.memory.charge(pending.size());
+        self.pending.push_back(pending);
+    }
+
+    // Counts what's held as failed and lets go of it
+    fn give_up(&mut self) {
+        for pending in self.pending.drain(..) {
+            self.memory.release(pending.size());
+            self.counters.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// What became of a partition's records in one produce
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Delivered,
+    // Worth sending again once the partition's leader is looked up again
+    Retry,
+    // Refused with this error code, which sending again won't change
+    Rejected(i16),
+}
+
+// Errors after which the partition's leader may be another broker, or is
+// only briefly unavailable
+fn retriable(code: i16) -> bool {
+    // Unknown topic or partition, leader not available, not the leader,
+    // request timed out, broker not available, network error, not enough
+    // replicas (before and after the append), storage error
+    matches!(code, 3 | 5 | 6 | 7 | 8 | 13 | 19 | 20 | 56)
+}
+
+// The topic's partitions, each with its leader's node id, and the address
+// of each broker
+#[derive(Debug, Default, PartialEq)]
+struct Metadata {
+    brokers: HashMap<i32, String>,
+    leaders: Vec<i32>,
+}
+
+struct Producer {
+    config: KafkaSinkConfig,
+    // Dropped after a failure, so leaders are looked up again
+    metadata: Option<Metadata>,
+    connections: HashMap<i32, KafkaConnection>,
+    // Partition of the next unkeyed record
+    next_partition: usize,
+}
+
+impl Producer {
+    fn new(config: KafkaSinkConfig) -> Producer {
+        Producer {
+            config,
+            metadata: None,
+            connections: HashMap::new(),
+            next_partition: 0,
+        }
+    }
+
+    // Sends what's held a batch at a time, stopping at the first failure
+    async fn flush(&mut self, sink: &mut Buffer<'_>) {
+        while !sink.pending.is_empty() {
+            if let Err(e) = self.send(sink).await {
+                warn!(
+                    "Kafka flush left {} records unsent: {}",
+                    sink.pending.len(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+
+    // Produces the first batch held. Delivered and rejected records are
+    // counted and let go of, while those worth retrying stay at the front,
+    // in order, and make it fail
+    async fn send(&mut self, sink: &mut Buffer<'_>) -> Result<(), ServerError> {
+        if sink.pending.is_empty() {
+            return Ok(());
+        }
+        if self.metadata.is_none() {
+            self.metadata = Some(self.fetch_metadata().await?);
+        }
+        let metadata = self.metadata.as_ref().unwrap();
+        let partitions = metadata.leaders.len();
+        let batch = sink.pending.len().min(self.config.batch_size);
+        let assigned: Vec<usize> = sink
+            .pending
+            .iter()
+            .take(batch)
+            .map(|pending| match self.config.partition_key {
+                PartitionKey::Expression => partition(pending.key.as_bytes(), partitions),
+                PartitionKey::RoundRobin => {
+                    self.next_partition = (self.next_partition + 1) % partitions;
+                    self.next_partition
+                }
+            })
+            .collect();
+        // Indexes of the batch's records by leader, then partition
+        let mut by_leader: BTreeMap<i32, BTreeMap<usize, Vec<usize>>> = BTreeMap::new();
+        for (index, &partition) in assigned.iter().enumerate() {
+            by_leader
+                .entry(metadata.leaders[partition])
+                .or_default()
+                .entry(partition)
+                .or_default()
+                .push(index);
+        }
+
+        let mut outcomes = vec![Outcome::Retry; partitions];
+        let mut failure = None;
+        for (leader, records) in &by_leader {
+            let Some(address) = metadata.brokers.get(leader).cloned() else {
+                // No leader, e.g. during an election
+                continue;
+            };
+            let request = produce_request(&self.config.topic, records, &sink.pending);
+            let answered = match self.connections.get_mut(leader) {
+                Some(connection) => connection.request(PRODUCE, PRODUCE_VERSION, &request).await,
+                None => match KafkaConnection::connect(&address, &self.config.client_id).await {
+                    Ok(connection) => {
+                        let connection = self.connections.entry(*leader).or_insert(connection);
+                        connection.request(PRODUCE, PRODUCE_VERSION, &request).await
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            let codes = match answered.and_then(|response| produce_errors(&response)) {
+                Ok(codes) => codes,
+                Err(e) => {
+                    self.connections.remove(leader);
+                    failure = Some(e);
+                    continue;
+                }
+            };
+            for &partition in records.keys() {
+                outcomes[partition] = match codes.get(&(partition as i32)) {
+                    Some(0) => Outcome::Delivered,
+                    Some(&code) if !retriable(code) => Outcome::Rejected(code),
+                    _ => Outcome::Retry,
+                };
+            }
+        }
+
+        let mut retried = VecDeque::new();
+        for (pending, &partition) in sink.pending.drain(..batch).zip(&assigned) {
+            match outcomes[partition] {
+                Outcome::Retry => {
+                    retried.push_back(pending);
+                    continue;
+                }
+                Outcome::Delivered => sink.counters.published.fetch_add(1, Ordering::Relaxed),
+                Outcome::Rejected(_) => sink.counters.failed.fetch_add(1, Ordering::Relaxed),
+            };
+            sink.memory.release(pending.size());
+        }
+        for (partition, outcome) in outcomes.iter().enumerate() {
+            if let Outcome::Rejected(code) = outcome {
+                warn!(
+                    "Kafka rejected records for {}-{} with error code {}",
+                    self.config.topic, partition, code
+                );
+            }
+        }
+        if retried.is_empty() {
+            return Ok(());
+        }
+        let count = retried.len();
+        retried.append(&mut sink.pending);
+        sink.pending = retried;
+        self.metadata = None;
+        Err(failure.unwrap_or_else(|| {
+            ServerError::Kafka(format!("{} records found no partition leader", count))
+        }))
+    }
+
+    // Asks the brokers in turn for the topic's partition leaders
+    async fn fetch_metadata(&mut self) -> Result<Metadata, ServerError> {
+        let mut request = Vec::new();
+        put_i32(&mut request, 1);
+        put_string(&mut request, &self.config.topic);
+        let mut failure = ServerError::Kafka("no brokers configured".into());
+        for broker in &self.config.brokers {
+            let answered = match KafkaConnection::connect(broker, &self.config.client_id).await {
+                Ok(mut connection) => {
+                    connection
+                        .request(METADATA, METADATA_VERSION, &request)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match answered.and_then(|response| metadata(&response, &self.config.topic)) {
+                Ok(metadata) => return Ok(metadata),
+                Err(e) => failure = e,
+            }
+        }
+        Err(failure)
+    }
+}
+
+// A Produce request of the records at `records` of `pending`, by partition
+fn produce_request(
+    topic: &str,
+    records: &BTreeMap<usize, Vec<usize>>,
+    pending: &VecDeque<Pending>,
+) -> Vec<u8> {
+    let mut request = Vec::new();
+    // No transactional id
+    put_i16(&mut request, -1);
+    put_i16(&mut request, ACKS_ALL);
+    put_i32(&mut request, REPLICATION_TIMEOUT_MS);
+    put_i32(&mut request, 1);
+    put_string(&mut request, topic);
+    put_i32(&mut request, records.len() as i32);
+    for (&partition, indexes) in records {
+        put_i32(&mut request, partition as i32);
+        let batch = record_batch(indexes.iter().map(|&index| &pending[index]));
+        put_i32(&mut request, batch.len() as i32);
+        request.extend_from_slice(&batch);
+    }
+    request
+}
+
+// A record batch of magic 2, uncompressed and outside any transaction
+fn record_batch<'a>(records: impl Iterator<Item = &'a Pending>) -> Vec<u8> {
+    let records: Vec<&Pending> = records.collect();
+    let base = records.first().map_or(0, |record| record.timestamp);
+    let newest = records.iter().map(|record| record.timestamp).max();
+
+    // From the attributes on, what the CRC covers
+    let mut checked = Vec::new();
+    put_i16(&mut checked, 0);
+    put_i32(&mut checked, records.len() as i32 - 1);
+    put_i64(&mut checked, base);
+    put_i64(&mut checked, newest.unwrap_or(base));
+    // No producer id, epoch or sequence
+    put_i64(&mut checked, -1);
+    put_i16(&mut checked, -1);
+    put_i32(&mut checked, -1);
+    put_i32(&mut checked, records.len() as i32);
+    for (offset, record) in records.iter().enumerate() {
+        let mut body = vec![0];
+        put_varint(&mut body, record.timestamp - base);
+        put_varint(&mut body, offset as i64);
+        put_varint(&mut body, record.key.len() as i64);
+        body.extend_from_slice(record.key.as_bytes());
+        put_varint(&mut body, record.value.len() as i64);
+        body.extend_from_slice(&record.value);
+        // No headers
+        put_varint(&mut body, 0);
+        put_varint(&mut checked, body.len() as i64);
+        checked.extend_from_slice(&body);
+    }
+
+    let mut batch = Vec::with_capacity(checked.len() + 21);
+    // Base offset, assigned by the broker
+    put_i64(&mut batch, 0);
+    put_i32(&mut batch, checked.len() as i32 + 9);
+    // Partition leader epoch, also the broker's
+    put_i32(&mut batch, -1);
+    batch.push(2);
+    batch.extend_from_slice(&crc32c(&checked).to_be_bytes());
+    batch.extend_from_slice(&checked);
+    batch
+}
+
+// Error code of each partition in a Produce response
+fn produce_errors(response: &[u8]) -> Result<HashMap<i32, i16>, ServerError> {
+    let mut reader = Reader(response);
+    let mut codes = HashMap::new();
+    for _ in 0..reader.count()? {
+        reader.string()?;
+        for _ in 0..reader.count()? {
+            let partition = reader.i32()?;
+            codes.insert(partition, reader.i16()?);
+            // Base offset and log append time
+            reader.bytes(16)?;
+        }
+    }
+    Ok(codes)
+}
+
+// Partition leaders of `topic` in a Metadata response
+fn metadata(response: &[u8], topic: &str) -> Result<Metadata, ServerError> {
+    let mut reader = Reader(response);
+    let mut metadata = Metadata::default();
+    for _ in 0..reader.count()? {
+        let node = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        // Rack
+        reader.string()?;
+        metadata.brokers.insert(node, format!("{}:{}", host, port));
+    }
+    // Controller
+    reader.i32()?;
+    for _ in 0..reader.count()? {
+        let code = reader.i16()?;
+        let name = reader.string()?;
+        // Internal
+        reader.bytes(1)?;
+        let mut leaders = BTreeMap::new();
+        for _ in 0..reader.count()? {
+            // Errors of single partitions show in their leader
+            reader.i16()?;
+            let partition = reader.i32()?;
+            leaders.insert(partition, reader.i32()?);
+            for _ in 0..2 {
+                // Replicas, then in-sync replicas
+                let count = reader.count()?;
+                reader.bytes(count * 4)?;
+            }
+        }
+        if name != topic {
+            continue;
+        }
+        if code != 0 {
+            return Err(ServerError::Kafka(format!(
+                "topic {} unavailable with error code {}",
+                topic, code
+            )));
+        }
+        // Partitions are numbered from 0 without gaps
+        if leaders.is_empty() || leaders.keys().copied().ne(0..leaders.len() as i32) {
+            return Err(ServerError::Kafka(format!(
+                "topic {} has partitions {:?}",
+                topic,
+                leaders.keys().collect::<Vec<_>>()
+            )));
+        }
+        metadata.leaders = leaders.into_values().collect();
+        return Ok(metadata);
+    }
+    Err(ServerError::Kafka(format!(
+        "topic {} not in metadata",
+        topic
+    )))
+}
+
+struct KafkaConnection {
+    stream: TcpStream,
+    client_id: String,
+    correlation_id: i32,
+}
+
+impl KafkaConnection {
+    async fn connect(address: &str, client_id: &str) -> Result<KafkaConnection, ServerError> {
+        let stream = timeout(REQUEST_TIMEOUT, TcpStream::connect(address))
+            .await
+            .map_err(|_| ServerError::Kafka(format!("connecting to {} timed out", address)))??;
+        Ok(KafkaConnection {
+            stream,
+            client_id: client_id.to_string(),
+            correlation_id: 0,
+        })
+    }
+
+    /// Sends one request and returns the body of its response.
+    async fn request(
+        &mut self,
+        api_key: i16,
+        version: i16,
+        body: &[u8],
+    ) -> Result<Vec<u8>, ServerError> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = vec![0; 4];
+        put_i16(&mut request, api_key);
+        put_i16(&mut request, version);
+        put_i32(&mut request, self.correlation_id);
+        put_string(&mut request, &self.client_id);
+        request.extend_from_slice(body);
+        let size = (request.len() - 4) as i32;
+        request[..4].copy_from_slice(&size.to_be_bytes());
+
+        timeout(REQUEST_TIMEOUT, async {
+            self.stream.write_all(&request).await?;
+            let size = self.stream.read_i32().await?;
+            let size = usize::try_from(size)
+                .ok()
+                .filter(|&size| (4..=MAX_RESPONSE).contains(&size))
+                .ok_or_else(|| ServerError::Kafka(format!("response of {} bytes", size)))?;
+            let mut response = vec![0; size];
+            self.stream.read_exact(&mut response).await?;
+            let correlation_id = i32::from_be_bytes(response[..4].try_into().unwrap());
+            if correlation_id != self.correlation_id {
+                return Err(ServerError::Kafka(format!(
+                    "response to request {} while waiting for {}",
+                    correlation_id, self.correlation_id
+                )));
+            }
+            response.drain(..4);
+            Ok(response)
+        })
+        .await
+        .map_err(|_| ServerError::Kafka("request timed out".into()))?
+    }
+}
+
+fn put_i16(out: &mut Vec<u8>, value: i16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i32(out: &mut Vec<u8>, value: i32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_i64(out: &mut Vec<u8>, value: i64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, value: &str) {
+    put_i16(out, value.len() as i16);
+    out.extend_from_slice(value.as_bytes());
+}
+
+// Zigzag varint, as record fields are written
+fn put_varint(out: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Reads responses, failing on one cut short
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ServerError> {
+        if self.0.len() < len {
+            return Err(ServerError::Kafka("truncated response".into()));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn i16(&mut self) -> Result<i16, ServerError> {
+        Ok(i16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, ServerError> {
+        Ok(i32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    // Length of an array, where null counts as empty
+    fn count(&mut self) -> Result<usize, ServerError> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    // A string, where null reads as empty
+    fn string(&mut self) -> Result<String, ServerError> {
+        let len = self.i16()?.max(0) as usize;
+        Ok(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32c, murmur2, partition, spawn, KafkaSinkConfig};
+    use crate::backoff::BackoffConfig;
+    use crate::candle::Candle;
+    use crate::memory::MemoryAccount;
+    use crate::protocol::{ResultData, ResultMessage};
+    use crate::sink::SinkRecord;
+    use serde_json::Value;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    // `(partition, [(key, value)])` of each record batch produced
+    type Produced = (i32, Vec<(String, Vec<u8>)>);
+
+    // Fake broker leading every one of `partitions`, answering its first
+    // `failures` produces with "not the leader" and reporting each batch
+    async fn fake_kafka(
+        partitions: i32,
+        failures: usize,
+    ) -> (String, mpsc::UnboundedReceiver<Produced>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let failures = Arc::new(AtomicUsize::new(failures));
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let broker = (address.port() as i32, partitions, failures.clone());
+                tokio::spawn(serve(socket, broker, tx.clone()));
+            }
+        });
+        (address.to_string(), rx)
+    }
+
+    async fn serve(
+        mut socket: TcpStream,
+        (port, partitions, failures): (i32, i32, Arc<AtomicUsize>),
+        tx: mpsc::UnboundedSender<Produced>,
+    ) {
+        while let Ok(size) = socket.read_i32().await {
+            let mut request = vec![0; size as usize];
+            socket.read_exact(&mut request).await.unwrap();
+            let mut request = Cursor(&request);
+            let api_key = request.i16();
+            request.i16();
+            let correlation_id = request.i32();
+            request.string();
+
+            let mut response = correlation_id.to_be_bytes().to_vec();
+            match api_key {
+                super::METADATA => {
+                    let topic = (request.i32(), request.string()).1;
+                    put(&mut response, &[&1i32.to_be_bytes(), &1i32.to_be_bytes()]);
+                    put_str(&mut response, "127.0.0.1");
+                    put(
+                        &mut response,
+                        &[&port.to_be_bytes(), &(-1i16).to_be_bytes()],
+                    );
+                    put(&mut response, &[&1i32.to_be_bytes(), &1i32.to_be_bytes()]);
+                    put(&mut response, &[&0i16.to_be_bytes()]);
+                    put_str(&mut response, &topic);
+                    put(&mut response, &[&[0], &partitions.to_be_bytes()]);
+                    for partition in 0..partitions {
+                        put(
+                            &mut response,
+                            &[
+                                &0i16.to_be_bytes(),
+                                &partition.to_be_bytes(),
+                                &1i32.to_be_bytes(),
+                                &1i32.to_be_bytes(),
+                                &1i32.to_be_bytes(),
+                                &1i32.to_be_bytes(),
+                                &1i32.to_be_bytes(),
+                            ],
+                        );
+                    }
+                }
+                super::PRODUCE => {
+                    let failed = failures
+                        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                        .is_ok();
+                    let code: i16 = if failed { 6 } else { 0 };
+                    assert_eq!((request.i16(), request.i16()), (-1, super::ACKS_ALL));
+                    request.i32();
+                    assert_eq!(request.i32(), 1);
+                    let topic = request.string();
+                    put(&mut response, &[&1i32.to_be_bytes()]);
+                    put_str(&mut response, &topic);
+                    let count = request.i32();
+                    put(&mut response, &[&count.to_be_bytes()]);
+                    for _ in 0..count {
+                        let partition = request.i32();
+                        let len = request.i32() as usize;
+                        let records = read_batch(request.bytes(len));
+                        if !failed {
+                            let _ = tx.send((partition, records));
+                        }
+                        put(
+                            &mut response,
+                            &[
+                                &partition.to_be_bytes(),
+                                &code.to_be_bytes(),
+                                &0i64.to_be_bytes(),
+                                &(-1i64).to_be_bytes(),
+                            ],
+                        );
+                    }
+                    put(&mut response, &[&0i32.to_be_bytes()]);
+                }
+                other => panic!("unexpected API key {}", other),
+            }
+            let mut framed = (response.len() as i32).to_be_bytes().to_vec();
+            framed.extend_from_slice(&response);
+            if socket.write_all(&framed).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Keys and values of a record batch, checking its CRC
+    fn read_batch(batch: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut batch = Cursor(batch);
+        batch.bytes(8);
+        let len = batch.i32() as usize;
+        assert_eq!(batch.0.len(), len);
+        batch.i32();
+        assert_eq!(batch.bytes(1), [2]);
+        let crc = batch.i32() as u32;
+        assert_eq!(crc32c(batch.0), crc);
+        batch.bytes(2 + 4 + 8 + 8 + 8 + 2 + 4);
+        let mut records = Vec::new();
+        for _ in 0..batch.i32() {
+            batch.varint();
+            batch.bytes(1);
+            batch.varint();
+            batch.varint();
+            let key = batch.varint() as usize;
+            let key = String::from_utf8(batch.bytes(key).to_vec()).unwrap();
+            let value = batch.varint() as usize;
+            records.push((key, batch.bytes(value).to_vec()));
+            assert_eq!(batch.varint(), 0);
+        }
+        records
+    }
+
+    struct Cursor<'a>(&'a [u8]);
+
+    impl<'a> Cursor<'a> {
+        fn bytes(&mut self, len: usize) -> &'a [u8] {
+            let (bytes, rest) = self.0.split_at(len);
+            self.0 = rest;
+            bytes
+        }
+
+        fn i16(&mut self) -> i16 {
+            i16::from_be_bytes(self.bytes(2).try_into().unwrap())
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_be_bytes(self.bytes(4).try_into().unwrap())
+        }
+
+        fn string(&mut self) -> String {
+            let len = self.i16().max(0) as usize;
+            String::from_utf8(self.bytes(len).to_vec()).unwrap()
+        }
+
+        fn varint(&mut self) -> i64 {
+            let (mut value, mut shift) = (0u64, 0);
+            loop {
+                let byte = self.bytes(1)[0];
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    return (value >> 1) as i64 ^ -((value & 1) as i64);
+                }
+                shift += 7;
+            }
+        }
+    }
+
+    fn put(out: &mut Vec<u8>, fields: &[&[u8]]) {
+        for field in fields {
+            out.extend_from_slice(field);
+        }
+    }
+
+    fn put_str(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as i16).to_be_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn record(key: &str, t: u64) -> Arc<SinkRecord> {
+        Arc::new(SinkRecord {
+            key: key.into(),
+            message: ResultMessage {
+                id: None,
+                stream: key.into(),
+                data: ResultData::from(Candle::new(t, 1.0, 2.0, 2.0, 1.0)),
+                closed: false,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            },
+        })
+    }
+
+    async fn next_batch(rx: &mut mpsc::UnboundedReceiver<Produced>) -> Produced {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no batch produced")
+            .unwrap()
+    }
+
+    fn fast_backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            ..BackoffConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_keys_hash_as_kafka_clients_do() {
+        for (key, hash) in [
+            ("21", -973932308),
+            ("foobar", -790332482),
+            ("a-little-bit-long-string", -985981536),
+            ("a-little-bit-longer-string", -1486304829),
+            (
+                "lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            ("abc", 479470107),
+        ] {
+            assert_eq!(murmur2(key.as_bytes()) as i32, hash, "{}", key);
+        }
+        assert_eq!(
+            partition(b"foobar", 7),
+            (-790332482i32 & 0x7fff_ffff) as usize % 7
+        );
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[tokio::test]
+    async fn test_records_go_to_the_partition_of_their_key() {
+        let (broker, mut batches) = fake_kafka(4, 0).await;
+        let config = KafkaSinkConfig {
+            brokers: vec![broker],
+            // Only a flush sends them
+            flush_interval: Duration::from_secs(60),
+            ..KafkaSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+        let keys = [
+            "btcusdt@1m",
+            "ethusdt@1m",
+            "btcusdt+ethusdt@1m",
+            "solusdt@1m",
+        ];
+        for (t, key) in keys.iter().enumerate() {
+            sink.offer(record(key, t as u64 * 60_000));
+        }
+        timeout(Duration::from_secs(5), sink.flush())
+            .await
+            .expect("flush never finished");
+
+        let mut produced = Vec::new();
+        while let Ok((partition, records)) = batches.try_recv() {
+            for (key, value) in records {
+                assert_eq!(partition as usize, super::partition(key.as_bytes(), 4));
+                let value: Value = serde_json::from_slice(&value).unwrap();
+                assert_eq!(value["stream"], key.as_str());
+                produced.push(key);
+            }
+        }
+        produced.sort();
+        let mut expected = keys.to_vec();
+        expected.sort();
+        assert_eq!(produced, expected);
+        assert_eq!(sink.stats().published, 4);
+    }
+
+    #[tokio::test]
+    async fn test_records_are_produced_again_after_a_retriable_error() {
+        let (broker, mut batches) = fake_kafka(1, 2).await;
+        let config = KafkaSinkConfig {
+            brokers: vec![broker],
+            flush_interval: Duration::from_millis(10),
+            ..KafkaSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+        sink.offer(record("btcusdt@1m", 0));
+        sink.offer(record("btcusdt@1m", 60_000));
+
+        let (partition, records) = next_batch(&mut batches).await;
+        assert_eq!(partition, 0);
+        let times: Vec<Value> = records
+            .iter()
+            .map(|(_, value)| serde_json::from_slice::<Value>(value).unwrap()["data"]["t"].clone())
+            .collect();
+        assert_eq!(times, [0, 60_000]);
+        // Counted once the broker's answer is read
+        timeout(Duration::from_secs(5), sink.flush()).await.unwrap();
+        let stats = sink.stats();
+        assert_eq!((stats.published, stats.failed, stats.dropped), (2, 0, 0));
+    }
+}
@@ -0,0 +1,95 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Upper bounds in milliseconds of the latency buckets; a last bucket
+/// holds everything slower.
+pub const LATENCY_BUCKETS_MS: [u64; 9] = [10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000];
+
+/// How long results took from Binance's event to being emitted, counted
+/// without locks by every client that asked for `latency`.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+    // Results emitted before their event time by our clock
+    skewed: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    // Results per bucket of `LATENCY_BUCKETS_MS`, then those above them all
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_ms: u64,
+    pub skewed: u64,
+}
+
+impl LatencyHistogram {
+    /// Counts a result emitted at `emitted` for an event at `event_time`,
+    /// both unix millis, and returns its latency. Clock skew can put the
+    /// event after the emission; that counts as 0 and as skewed.
+    pub fn record(&self, emitted: u64, event_time: u64) -> u64 {
+        let latency = match emitted.checked_sub(event_time) {
+            Some(latency) => latency,
+            None => {
+                self.skewed.fetch_add(1, Ordering::Relaxed);
+                0
+            }
+        };
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| latency <= bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms.fetch_add(latency, Ordering::Relaxed);
+        latency
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        LatencyStats {
+            count: buckets.iter().sum(),
+            buckets,
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+            skewed: self.skewed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LatencyHistogram, LATENCY_BUCKETS_MS};
+
+    #[test]
+    fn test_latencies_fall_into_buckets() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.record(1_000, 995), 5);
+        assert_eq!(histogram.record(1_000, 990), 10);
+        assert_eq!(histogram.record(1_000, 989), 11);
+        assert_eq!(histogram.record(60_000, 0), 60_000);
+
+        let stats = histogram.stats();
+        let mut buckets = vec![0; LATENCY_BUCKETS_MS.len() + 1];
+        buckets[0] = 2;
+        buckets[1] = 1;
+        buckets[LATENCY_BUCKETS_MS.len()] = 1;
+        assert_eq!(stats.buckets, buckets);
+        assert_eq!(stats.count, 4);
+        assert_eq!(stats.sum_ms, 60_026);
+        assert_eq!(stats.skewed, 0);
+    }
+
+    #[test]
+    fn test_skewed_latency_is_clamped_and_counted() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.record(1_000, 1_250), 0);
+
+        let stats = histogram.stats();
+        assert_eq!(stats.buckets[0], 1);
+        assert_eq!(stats.sum_ms, 0);
+        assert_eq!(stats.skewed, 1);
+    }
+}
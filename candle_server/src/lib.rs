@@ -0,0 +1,49 @@
+pub mod alert;
+pub mod align;
+pub mod backoff;
+pub mod candle;
+pub mod clickhouse;
+pub mod config;
+pub mod correlation;
+pub mod delta;
+pub mod emitter;
+pub mod encoding;
+pub mod error;
+pub mod expiry;
+pub mod expr;
+pub mod fault;
+pub mod grpc;
+pub mod gzip;
+pub mod hpack;
+pub mod indicators;
+pub mod kafka;
+pub mod latency;
+pub mod lifecycle;
+pub mod memory;
+pub mod mid;
+pub mod mqtt;
+pub mod open_interest;
+pub mod pairing;
+pub mod parquet;
+pub mod permessage;
+pub mod postgres;
+pub mod probe;
+pub mod protobuf;
+pub mod protocol;
+pub mod queue;
+pub mod rebase;
+pub mod redis;
+pub mod resample;
+pub mod rest;
+pub mod schema;
+pub mod scram;
+pub mod server;
+pub mod shards;
+pub mod sink;
+pub mod sse;
+pub mod synthetic;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod upstream;
+pub mod utils;
+pub mod webhook;
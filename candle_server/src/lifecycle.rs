@@ -0,0 +1,552 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use crate::candle::Candle;
+use crate::error::{ErrorCode, ServerError};
+use crate::protocol::{EvaluatorStats, ResultData, ResultMessage, ServerMessage, StatusMessage};
+use crate::rebase::Bases;
+use crate::utils::now_millis;
+
+/// Why a subscription stopped delivering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // The client asked to stop
+    Unsubscribed,
+    // Its upstream streams couldn't be subscribed or were given up on
+    UpstreamFailed,
+    // Its evaluator panicked more often than it may be restarted
+    EvaluatorFailed,
+    // Its `ttl_secs` passed without a RENEW
+    TtlExpired,
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::Unsubscribed => "unsubscribed",
+            CloseReason::UpstreamFailed => "upstream_failed",
+            CloseReason::EvaluatorFailed => "evaluator_failed",
+            CloseReason::TtlExpired => "ttl_expired",
+        }
+    }
+}
+
+/// Where a subscription is. Clients get a status event named after each
+/// state it enters, e.g. `reconnecting` with its `attempt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscriptionState {
+    // Subscribing its streams upstream
+    Connecting,
+    // Starting from `bars` bars the upstream had cached, ahead of live ones
+    Backfilling { bars: u64 },
+    // Evaluating live klines
+    Subscribed,
+    // Waiting to retry the upstream; `attempt` counts retries from 1
+    Reconnecting { attempt: u32 },
+    Closed { reason: CloseReason },
+}
+
+impl SubscriptionState {
+    pub fn event(&self) -> &'static str {
+        match self {
+            SubscriptionState::Connecting => "connecting",
+            SubscriptionState::Backfilling { .. } => "backfilling",
+            SubscriptionState::Subscribed => "subscribed",
+            SubscriptionState::Reconnecting { .. } => "reconnecting",
+            SubscriptionState::Closed { .. } => "closed",
+        }
+    }
+
+    /// Status event announcing this state on `stream`.
+    pub fn status(&self, stream: &str) -> StatusMessage {
+        let mut status = StatusMessage {
+            stream: stream.to_string(),
+            event: self.event().into(),
+            ..StatusMessage::default()
+        };
+        status.message = match *self {
+            SubscriptionState::Connecting => "Subscribing upstream streams".into(),
+            SubscriptionState::Backfilling { bars } => {
+                status.bars = Some(bars);
+                format!("Starting from {} cached bars", bars)
+            }
+            SubscriptionState::Subscribed => "Receiving live klines".into(),
+            SubscriptionState::Reconnecting { attempt } => {
+                status.attempt = Some(attempt);
+                format!("Reconnecting upstream, attempt {}", attempt)
+            }
+            SubscriptionState::Closed { reason } => {
+                status.reason = Some(reason.as_str().into());
+                match reason {
+                    CloseReason::Unsubscribed => "Unsubscribed".into(),
+                    CloseReason::UpstreamFailed => {
+                        status.code = Some(ErrorCode::UpstreamUnavailable);
+                        "Upstream connection lost, resubscribe to retry".into()
+                    }
+                    CloseReason::EvaluatorFailed => {
+                        status.code = Some(ErrorCode::Internal);
+                        "Evaluator failed, resubscribe to retry".into()
+                    }
+                    CloseReason::TtlExpired => "Expired, RENEW within its ttl to keep one".into(),
+                }
+            }
+        };
+        status
+    }
+}
+
+// What a subscription has handled so far, bumped by its evaluator and by
+// the feeds of its clients. Every counter only goes up
+#[derive(Default)]
+struct Counters {
+    // Upstream streams of the legs, and the klines each has received
+    legs: Vec<(String, AtomicU64)>,
+    // Results sent, one per evaluation however many clients share it
+    results: AtomicU64,
+    // Bytes encoded for clients, summed over all of them
+    bytes_sent: AtomicU64,
+    // Results clients skipped after falling behind the channel
+    dropped: AtomicU64,
+    // Unix millis of the newest kline and result, 0 before the first
+    last_kline_at: AtomicU64,
+    last_result_at: AtomicU64,
+}
+
+/// A subscription's state, shared by the tasks moving it along, which
+/// broadcast every change to its clients.
+#[derive(Clone)]
+pub struct Lifecycle {
+    // Expression the statuses are about
+    stream: String,
+    state: Arc<Mutex<SubscriptionState>>,
+    // Newest result sent, for `GET_LAST`
+    latest: Arc<Mutex<Option<ResultMessage>>>,
+    // Closed bars sent, oldest first, at most `keep` of them, for `EXPORT`
+    history: Arc<Mutex<VecDeque<ResultData>>>,
+    keep: usize,
+    counters: Arc<Counters>,
+    // Set for `rebase` subscriptions; outlives restarts of the evaluator
+    bases: Option<Arc<Mutex<Bases>>>,
+    tx: broadcast::Sender<ServerMessage>,
+}
+
+impl Lifecycle {
+    /// Starts out `Connecting`, without telling anyone. `legs` are the
+    /// upstream streams its klines are counted by.
+    pub fn new(stream: &str, legs: &[String], tx: broadcast::Sender<ServerMessage>) -> Lifecycle {
+        let counters = Counters {
+            legs: legs
+                .iter()
+                .map(|leg| (leg.clone(), AtomicU64::new(0)))
+                .collect(),
+            ..Counters::default()
+        };
+        Lifecycle {
+            stream: stream.to_string(),
+            state: Arc::new(Mutex::new(SubscriptionState::Connecting)),
+            latest: Arc::default(),
+            history: Arc::default(),
+            keep: 0,
+            counters: Arc::new(counters),
+            bases: None,
+            tx,
+        }
+    }
+
+    /// Keeps the newest `keep` closed bars sent, see `history`.
+    pub fn keeping(self, keep: usize) -> Lifecycle {
+        Lifecycle { keep, ..self }
+    }
+
+    /// Takes the legs' prices as a percentage of their base from now on,
+    /// see `rebase`.
+    pub fn rebasing(self) -> Lifecycle {
+        let legs = self.counters.legs.len();
+        Lifecycle {
+            bases: Some(Arc::new(Mutex::new(Bases::new(legs)))),
+            ..self
+        }
+    }
+
+    /// `candle` of leg `index` as the expression takes it. Unchanged unless
+    /// `rebasing`; then rebased, and `None` until the leg's first closed
+    /// bar, whose close becomes its base and is told to clients.
+    pub fn rebase(&self, index: usize, candle: Candle) -> Option<Candle> {
+        let Some(bases) = &self.bases else {
+            return Some(candle);
+        };
+        let mut bases = bases.lock().unwrap();
+        if bases.record(index, &candle) {
+            let _ = self.tx.send(ServerMessage::Status(self.rebased(&bases)));
+        }
+        bases.apply(index, candle)
+    }
+
+    // `rebased` event with every base known so far
+    fn rebased(&self, bases: &Bases) -> StatusMessage {
+        let bases = self
+            .counters
+            .legs
+            .iter()
+            .zip(bases.get())
+            .filter_map(|((leg, _), base)| Some((leg.clone(), (*base)?)))
+            .collect::<BTreeMap<_, _>>();
+        StatusMessage {
+            stream: self.stream.clone(),
+            event: "rebased".into(),
+            message: format!(
+                "Rebased {} of {} legs to 100",
+                bases.len(),
+                self.counters.legs.len()
+            ),
+            bases: Some(bases),
+            ..StatusMessage::default()
+        }
+    }
+
+    pub fn stream(&self) -> &str {
+        &self.stream
+    }
+
+    pub fn state(&self) -> SubscriptionState {
+        *self.state.lock().unwrap()
+    }
+
+    /// Moves to `next`, telling clients unless it's already there.
+    pub fn enter(&self, next: SubscriptionState) {
+        self.transition(next, None);
+    }
+
+    /// Like `enter`, with the error that caused the move in the status.
+    pub fn enter_after(&self, next: SubscriptionState, error: &ServerError) {
+        self.transition(next, Some(error));
+    }
+
+    fn transition(&self, next: SubscriptionState, error: Option<&ServerError>) {
+        let mut state = self.state.lock().unwrap();
+        if *state == next {
+            return;
+        }
+        *state = next;
+
+        let mut status = next.status(&self.stream);
+        if let Some(error) = error {
+            status.message = format!("{}: {}", status.message, error);
+            status.code = Some(error.code());
+        }
+        // Sent under the lock, so `join` sees each change exactly once
+        let _ = self.tx.send(ServerMessage::Status(status));
+    }
+
+    /// Results, and statuses that aren't state changes, e.g. a gap.
+    pub fn send(&self, message: ServerMessage) {
+        let mut latest = self.latest.lock().unwrap();
+        if let ServerMessage::Result(result) = &message {
+            self.counters.results.fetch_add(1, Ordering::Relaxed);
+            self.counters
+                .last_result_at
+                .store(now_millis(), Ordering::Relaxed);
+            // A bar that arrived behind the newest one doesn't replace it
+            if !result.out_of_order {
+                *latest = Some(result.clone());
+            }
+            if result.closed && self.keep > 0 {
+                let mut history = self.history.lock().unwrap();
+                // A bar closed again after a late kline is kept once
+                if history.back().is_none_or(|last| last.t < result.data.t) {
+                    if history.len() == self.keep {
+                        history.pop_front();
+                    }
+                    history.push_back(result.data.clone());
+                }
+            }
+        }
+        // Sent under the lock, so `join` takes its snapshot either before or
+        // after this message, never both. No receivers just means every
+        // client is between subscriptions
+        let _ = self.tx.send(message);
+    }
+
+    /// Number of results sent since the subscription started.
+    pub fn results(&self) -> u64 {
+        self.counters.results.load(Ordering::Relaxed)
+    }
+
+    /// Counts a kline received on leg `index`.
+    pub fn kline(&self, index: usize) {
+        if let Some((_, klines)) = self.counters.legs.get(index) {
+            klines.fetch_add(1, Ordering::Relaxed);
+        }
+        self.counters
+            .last_kline_at
+            .store(now_millis(), Ordering::Relaxed);
+    }
+
+    /// Everything counted so far; `subscribers` and `buffered_bytes` are
+    /// left at 0 for the caller, which knows them.
+    pub fn stats(&self) -> EvaluatorStats {
+        let counters = &self.counters;
+        let at = |millis: &AtomicU64| Some(millis.load(Ordering::Relaxed)).filter(|&t| t > 0);
+        EvaluatorStats {
+            subscribers: 0,
+            klines: counters
+                .legs
+                .iter()
+                .map(|(leg, klines)| (leg.clone(), klines.load(Ordering::Relaxed)))
+                .collect(),
+            results: counters.results.load(Ordering::Relaxed),
+            bytes_sent: counters.bytes_sent.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            buffered_bytes: 0,
+            last_kline_at: at(&counters.last_kline_at),
+            last_result_at: at(&counters.last_result_at),
+        }
+    }
+
+    /// Newest result sent, whether its bar is still open or `closed`.
+    pub fn latest(&self) -> Option<ResultMessage> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Closed bars kept, oldest first, of those opening in `from..to`.
+    pub fn history(&self, from: Option<u64>, to: Option<u64>) -> Vec<ResultData> {
+        let from = from.unwrap_or(0);
+        let to = to.unwrap_or(u64::MAX);
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|candle| (from..to).contains(&candle.t))
+            .cloned()
+            .collect()
+    }
+
+    /// Feed for a new client: the current state and the newest result as a
+    /// snapshot, then everything after them, taken together so the client
+    /// neither misses nor repeats a change or a result.
+    pub fn join(&self) -> Feed {
+        let state = self.state.lock().unwrap();
+        let latest = self.latest.lock().unwrap();
+        // Held too, so bases recorded after it come as events
+        let bases = self.bases.as_ref().map(|bases| bases.lock().unwrap());
+        let rebased = bases
+            .as_deref()
+            .filter(|bases| !bases.is_empty())
+            .map(|bases| ServerMessage::Status(self.rebased(bases)));
+        let snapshot = latest.clone().map(|mut result| {
+            result.snapshot = true;
+            ServerMessage::Result(result)
+        });
+        Feed {
+            joined: [
+                Some(ServerMessage::Status(state.status(&self.stream))),
+                rebased,
+                snapshot,
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            rx: self.tx.subscribe(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+/// What one client of a subscription receives: the state it joined in and
+/// the snapshot, then every message broadcast since.
+pub struct Feed {
+    joined: VecDeque<ServerMessage>,
+    rx: broadcast::Receiver<ServerMessage>,
+    counters: Arc<Counters>,
+}
+
+impl Feed {
+    /// Results skipped by falling behind are counted as dropped.
+    pub async fn recv(&mut self) -> Result<ServerMessage, broadcast::error::RecvError> {
+        let received = match self.joined.pop_front() {
+            Some(joined) => Ok(joined),
+            None => self.rx.recv().await,
+        };
+        if let Err(broadcast::error::RecvError::Lagged(skipped)) = received {
+            self.counters.dropped.fetch_add(skipped, Ordering::Relaxed);
+        }
+        received
+    }
+
+    /// Counts `bytes` encoded for this feed's client.
+    pub fn sent(&self, bytes: usize) {
+        self.counters
+            .bytes_sent
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CloseReason, Feed, Lifecycle, SubscriptionState};
+    use crate::candle::Candle;
+    use crate::error::{ErrorCode, ServerError};
+    use crate::protocol::{ResultData, ResultMessage, ServerMessage};
+    use tokio::sync::broadcast;
+
+    fn next_message(feed: &mut Feed) -> Option<ServerMessage> {
+        match feed.joined.pop_front() {
+            Some(joined) => Some(joined),
+            None => feed.rx.try_recv().ok(),
+        }
+    }
+
+    fn next_status(feed: &mut Feed) -> Option<serde_json::Value> {
+        match next_message(feed) {
+            Some(ServerMessage::Status(status)) => Some(serde_json::to_value(status).unwrap()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_only_changes_are_sent() {
+        let (tx, _) = broadcast::channel(16);
+        let lifecycle = Lifecycle::new("btcusdt@1m", &[], tx);
+        let mut feed = lifecycle.join();
+        assert_eq!(next_status(&mut feed).unwrap()["event"], "connecting");
+
+        lifecycle.enter(SubscriptionState::Connecting);
+        lifecycle.enter(SubscriptionState::Reconnecting { attempt: 1 });
+        lifecycle.enter(SubscriptionState::Reconnecting { attempt: 1 });
+        lifecycle.enter(SubscriptionState::Subscribed);
+
+        let reconnecting = next_status(&mut feed).unwrap();
+        assert_eq!(reconnecting["event"], "reconnecting");
+        assert_eq!(reconnecting["attempt"], 1);
+        assert_eq!(next_status(&mut feed).unwrap()["event"], "subscribed");
+        assert!(next_status(&mut feed).is_none());
+        assert_eq!(lifecycle.state(), SubscriptionState::Subscribed);
+    }
+
+    #[test]
+    fn test_failures_carry_a_code() {
+        let (tx, _) = broadcast::channel(16);
+        let lifecycle = Lifecycle::new("btcusdt@1m", &[], tx);
+        let mut feed = lifecycle.join();
+        next_status(&mut feed);
+
+        lifecycle.enter_after(
+            SubscriptionState::Reconnecting { attempt: 2 },
+            &ServerError::WebSocketConnect,
+        );
+        let status = next_status(&mut feed).unwrap();
+        assert_eq!(
+            status["message"],
+            "Reconnecting upstream, attempt 2: Can not connect to WebSocket"
+        );
+        assert_eq!(status["code"], ErrorCode::UpstreamUnavailable.value());
+
+        lifecycle.enter(SubscriptionState::Closed {
+            reason: CloseReason::UpstreamFailed,
+        });
+        let status = next_status(&mut feed).unwrap();
+        assert_eq!(status["event"], "closed");
+        assert_eq!(status["reason"], "upstream_failed");
+        assert_eq!(status["code"], ErrorCode::UpstreamUnavailable.value());
+    }
+
+    #[test]
+    fn test_latest_skips_late_bars() {
+        let (tx, _) = broadcast::channel(16);
+        let lifecycle = Lifecycle::new("btcusdt@1m", &[], tx);
+        assert!(lifecycle.latest().is_none());
+
+        let result = |t: u64, closed: bool, out_of_order: bool| {
+            ServerMessage::Result(ResultMessage {
+                id: None,
+                stream: "btcusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 1.0, 1.0, 1.0)),
+                closed,
+                out_of_order,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            })
+        };
+        lifecycle.send(result(60_000, true, false));
+        lifecycle.send(result(120_000, false, false));
+        lifecycle.send(result(0, true, true));
+
+        let latest = lifecycle.latest().unwrap();
+        assert_eq!(latest.data.t, 120_000);
+        assert!(!latest.closed);
+    }
+
+    #[test]
+    fn test_history_keeps_newest_closed_bars() {
+        let (tx, _) = broadcast::channel(16);
+        let lifecycle = Lifecycle::new("btcusdt@1m", &[], tx).keeping(3);
+        let result = |t: u64, closed: bool| {
+            ServerMessage::Result(ResultMessage {
+                id: None,
+                stream: "btcusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 1.0, 1.0, 1.0)),
+                closed,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            })
+        };
+        for minute in 0..5 {
+            lifecycle.send(result(minute * 60_000, false));
+            lifecycle.send(result(minute * 60_000, true));
+        }
+        // Closed again, after a late kline
+        lifecycle.send(result(240_000, true));
+
+        let times = |candles: Vec<ResultData>| -> Vec<u64> {
+            candles.into_iter().map(|candle| candle.t).collect()
+        };
+        assert_eq!(
+            times(lifecycle.history(None, None)),
+            [120_000, 180_000, 240_000]
+        );
+        assert_eq!(
+            times(lifecycle.history(Some(150_000), Some(240_000))),
+            [180_000]
+        );
+    }
+
+    #[test]
+    fn test_join_starts_with_snapshot() {
+        let (tx, _) = broadcast::channel(16);
+        let lifecycle = Lifecycle::new("btcusdt@1m", &[], tx);
+        lifecycle.enter(SubscriptionState::Subscribed);
+        let result = |t: u64| {
+            ServerMessage::Result(ResultMessage {
+                id: None,
+                stream: "btcusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 1.0, 1.0, 1.0)),
+                closed: false,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            })
+        };
+        lifecycle.send(result(60_000));
+
+        let mut feed = lifecycle.join();
+        lifecycle.send(result(120_000));
+        assert_eq!(next_status(&mut feed).unwrap()["event"], "subscribed");
+        let mut received = Vec::new();
+        while let Some(ServerMessage::Result(result)) = next_message(&mut feed) {
+            received.push((result.data.t, result.snapshot));
+        }
+        assert_eq!(received, [(60_000, true), (120_000, false)]);
+    }
+}
@@ -1,250 +1,330 @@
-use futures::stream::{SplitSink, SplitStream};
-use futures::{SinkExt, StreamExt};
-use log::{error, info};
-use std::collections::{HashMap, VecDeque};
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, RwLock};
-use tokio::time::{timeout, Duration};
-use tokio_tungstenite::tungstenite::{Message, WebSocket};
-use tokio_tungstenite::{accept_async, connect_async, MaybeTlsStream, WebSocketStream};
-use url::Url;
-
-mod utils;
-use utils::*;
-
-struct ServerState {
-    connections: RwLock<HashMap<String, SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>>>,
-}
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-struct Server {
-    state: Arc<ServerState>,
-}
+use candle_server::config::{self, RuntimeFlavor, Settings};
+use candle_server::error::ServerError;
+use candle_server::protocol::ServerMessage;
+use candle_server::server::Server;
+use candle_server::utils::format_rfc3339;
+use log::{info, warn, Log, Metadata, Record};
+use tokio::sync::mpsc;
+
+const USAGE: &str = "usage: candle_server [ADDR...] [--bind ADDR]... [--bind-unix PATH] \
+[--bind-unix-mode 660] [--grpc-addr ADDR] [--config FILE] [--print-config] \
+[--upstream URL] [--no-upstream-compression] [--rest-url URL] [--runtime current-thread|multi-thread] \
+[--workers N] [--log-level LEVEL] [--task-budget N] [--evaluator-shards N] [--slow-client-timeout SECS|never] [--export-dir DIR] \
+[--redis-url URL] [--redis-prefix PREFIX] \
+[--redis-latest-ttl SECS] [--kafka-brokers HOST:PORT,...] [--kafka-topic TOPIC] \
+[--mqtt-url URL] [--mqtt-prefix PREFIX] [--mqtt-qos 0|1] \
+[--mqtt-retain] [--clickhouse-url URL] [--clickhouse-table TABLE] \
+[--postgres-url URL] [--postgres-table TABLE] [--db-migrate] [--synthetic [--synthetic-seed N] [--synthetic-tick SECS] \
+[--synthetic-volatility X]] [--subscribe EXPR... --stdout]
+
+Settings come from FILE, then flags, then CANDLE_* environment variables, \
+e.g. CANDLE_REDIS_URL for `redis.url`. On SIGHUP they are read again and \
+the ones that can change while running are applied. On SIGUSR1 the server \
+drains: it takes no new clients or subscriptions and shuts down once its \
+clients have left or `drain_grace` passed.";
+
+fn main() -> Result<(), ServerError> {
+    let mut file: Option<PathBuf> = None;
+    let mut print_config = false;
+    // `(flag, value)` overrides of the file's settings
+    let mut flags = Vec::new();
+    // Every address given, which together replace the file's `listen`
+    let mut listen = Vec::new();
+    // Expressions streamed to stdout instead of serving clients
+    let mut subscribe = Vec::new();
+    let mut stdout = false;
 
-impl Server {
-    pub fn new() -> Server {
-        Server {
-            state: Arc::new(ServerState {
-                connections: RwLock::default(),
-            }),
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => file = Some(args.next().unwrap_or_else(|| exit_with_usage()).into()),
+            "--print-config" => print_config = true,
+            "--mqtt-retain" | "--db-migrate" | "--synthetic" => flags.push((arg, "true".into())),
+            "--no-upstream-compression" => flags.push((arg, "false".into())),
+            "--subscribe" => subscribe.push(args.next().unwrap_or_else(|| exit_with_usage())),
+            "--stdout" => stdout = true,
+            "--listen" | "--bind" => listen.push(args.next().unwrap_or_else(|| exit_with_usage())),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ if config::FLAGS.iter().any(|(flag, _)| *flag == arg) => {
+                let value = args.next().unwrap_or_else(|| exit_with_usage());
+                flags.push((arg, value));
+            }
+            _ if arg.starts_with('-') => exit_with_usage(),
+            _ => listen.push(arg),
         }
     }
-
-    async fn connect_websocket() -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, ServerError>
-    {
-        timeout(
-            Duration::from_secs(5),
-            connect_async("wss://fstream.binance.com/stream"),
-        )
-        .await
-        .map_err(|_| ServerError::WebSocketTimeout)?
-        .map_err(|_| ServerError::WebSocketConnect)
-        .map(|(ws, _)| ws)
+    if !listen.is_empty() {
+        flags.push(("--listen".into(), listen.join(",")));
     }
 
-    async fn send_subscription(
-        write: &mut SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
-        subscription: &BinanceSubscription,
-    ) -> Result<(), ServerError> {
-        let subscribe_message = Message::text(serde_json::to_string(&subscription)?);
-        write
-            .send(subscribe_message)
-            .await
-            .map_err(|_| ServerError::WebSocketWrite)
+    if stdout == subscribe.is_empty() {
+        exit_with_usage();
     }
 
-    async fn subscribe_to_binance(
-        state: Arc<ServerState>,
-        req: &Request,
-    ) -> Result<(), ServerError> {
-        // if req.method != "SUBSCRIBE" ...
-
-        info!("Subscribing to stream: {}", &req.stream);
-
-        let mut state_lock = state.connections.write().await;
-        if state_lock.contains_key(&req.stream) {
-            info!("Stream {} is already subscribed", &req.stream);
-            return Ok(());
+    let settings = match Settings::load(file.as_deref(), &flags, |name| std::env::var(name).ok()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
         }
+    };
+    if print_config {
+        print!("{}", settings.to_toml());
+        return Ok(());
+    }
+    // Only ever set once
+    let _ = log::set_logger(&StderrLogger);
+    log::set_max_level(settings.log_level);
 
-        let ws_socket = Self::connect_websocket().await?;
-        let (mut write, read) = ws_socket.split();
-
-        let subscription = BinanceSubscription {
-            id: req.id,
-            method: req.method.clone(),
-            params: parse_streams(&req.stream),
-        };
-
-        Self::send_subscription(&mut write, &subscription).await?;
-        state_lock.insert(req.stream.clone(), read);
-        info!("Stream {} subscribed successfully", &req.stream);
-
-        Ok(())
+    let mut builder = match settings.runtime {
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+        RuntimeFlavor::MultiThread => tokio::runtime::Builder::new_multi_thread(),
+    };
+    if let Some(workers) = settings.workers {
+        builder.worker_threads(workers);
     }
+    let runtime = builder.enable_all().build()?;
 
-    async fn handle_socket(socket: TcpStream) -> Result<Request, ServerError> {
-        info!("Handling new WebSocket connection...");
+    // Sinks start with the server, so it's built inside the runtime
+    runtime.block_on(async {
+        let server = Server::from_config(settings.server.clone());
+        #[cfg(unix)]
+        tokio::spawn(drain_on_user_signal(server.clone()));
+        #[cfg(unix)]
+        tokio::spawn(reload_on_hangup(
+            server.clone(),
+            settings.clone(),
+            file,
+            flags,
+        ));
+        if stdout {
+            stream_to_stdout(server, subscribe).await
+        } else {
+            serve(&server, &settings).await
+        }
+    })
+}
 
-        let websocket = match accept_async(socket).await {
-            Ok(ws) => ws,
-            Err(e) => {
-                error!("Error accepting WebSocket connection: {:?}", e);
-                return Err(ServerError::WebSocketAccept);
+// Serves the TCP addresses, the Unix socket and gRPC together, until one
+// fails or the process is told to stop, which closes every client first
+async fn serve(server: &Server, settings: &Settings) -> Result<(), ServerError> {
+    let listeners = async {
+        let grpc = async {
+            match settings.grpc_addr {
+                Some(addr) => server.serve_grpc(addr).await,
+                None => Ok(()),
             }
         };
+        let tcp = async { tokio::try_join!(server.serve(&settings.listen), grpc).map(|_| ()) };
+        match &settings.listen_unix {
+            #[cfg(unix)]
+            Some(path) => {
+                let unix = server.serve_unix(path, settings.listen_unix_mode);
+                tokio::try_join!(tcp, unix).map(|_| ())
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(ServerError::Config(
+                "`listen_unix` requires a Unix platform".into(),
+            )),
+            None => tcp.await,
+        }
+    };
+    tokio::select! {
+        // Listeners also stop once a drain ends, with clients still closing
+        served = listeners => {
+            served?;
+            server.shutdown().await;
+            Ok(())
+        }
+        _ = terminated() => {
+            server.shutdown().await;
+            Ok(())
+        }
+    }
+}
 
-        let (_, mut read) = websocket.split();
-
-        while let Some(message_result) = read.next().await {
-            match message_result {
-                Ok(Message::Text(text)) => {
-                    match serde_json::from_str(&text) {
-                        Ok(request) => {
-                            info!("Received valid request: {:?}", request);
-                            return Ok(request);
-                        }
-                        Err(e) => {
-                            error!("Error parsing request: {:?}", e);
-                            return Err(ServerError::Serde(e));
-                        }
-                    };
-                }
-                Ok(Message::Close(_)) => {
-                    info!("Received close message, ending connection");
-                    break;
-                }
-                Ok(other) => {
-                    info!("Received unsupported message type: {:?}", other);
-                }
-                Err(e) => {
-                    error!("Error reading message: {:?}", e);
-                    return Err(ServerError::InvalidMessage(e.to_string()));
-                }
+// Ctrl-C, or SIGTERM on Unix
+async fn terminated() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        if let Ok(mut terms) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = terms.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
             }
+            return;
         }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// Drains the server on SIGUSR1, ahead of a rolling deploy
+#[cfg(unix)]
+async fn drain_on_user_signal(server: Server) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-        error!("Connection closed without valid request");
-        Err(ServerError::InvalidMessage(
-            "Connection closed without valid request".into(),
-        ))
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Draining on SIGUSR1 disabled: {}", e);
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let deadline = server.drain();
+        info!("Draining, shutting down by {}", format_rfc3339(deadline));
     }
+}
 
-    async fn close_connection(&self, key: String) -> Result<(), ServerError> {
-        let mut state_lock = self.state.connections.write().await;
+// Reloads the settings on every SIGHUP, keeping the running ones when the
+// new ones don't load
+#[cfg(unix)]
+async fn reload_on_hangup(
+    server: Server,
+    running: Settings,
+    file: Option<PathBuf>,
+    flags: Vec<(String, String)>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
 
-        if let Some(_stream) = state_lock.remove(&key) {
-            info!("Connection with key '{}' successfully closed.", key);
-            Ok(())
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!("Configuration reload disabled: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let settings =
+            match Settings::load(file.as_deref(), &flags, |name| std::env::var(name).ok()) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    warn!("Keeping the running configuration: {}", e);
+                    continue;
+                }
+            };
+        log::set_max_level(settings.log_level);
+        let mut restart = server.reload(&settings.server);
+        if settings.listen != running.listen {
+            restart.insert(0, "listen");
+        }
+        if (&settings.listen_unix, settings.listen_unix_mode)
+            != (&running.listen_unix, running.listen_unix_mode)
+        {
+            restart.insert(0, "listen_unix");
+        }
+        if settings.grpc_addr != running.grpc_addr {
+            restart.insert(0, "grpc_addr");
+        }
+        if (settings.runtime, settings.workers) != (running.runtime, running.workers) {
+            restart.insert(0, "runtime");
+        }
+        if restart.is_empty() {
+            info!("Reloaded configuration");
         } else {
-            error!(
-                "Attempted to close connection with non-existing key '{}'.",
-                key
+            warn!(
+                "Reloaded configuration; requires restart: {}",
+                restart.join(", ")
             );
-            Err(ServerError::KeyNotFound)
         }
     }
+}
 
-    pub async fn serve(&self, addr: &SocketAddr) -> Result<(), ServerError> {
-        let try_socket = TcpListener::bind(addr).await?;
-        loop {
-            let (socket, _) = try_socket.accept().await?;
-            let state = self.state.clone();
-            tokio::spawn(async move {
-                let state2 = state.clone();
-                match Self::handle_socket(socket).await {
-                    Ok(request) => match Self::subscribe_to_binance(state, &request).await {
-                        Ok(_) => {
-                            tokio::spawn(async move {
-                                match Self::process_binance_stream(state2, &request).await {
-                                    Err(e) => println!("Error processing Binance stream: {}", e),
-                                    _ => {}
-                                }
-                            });
-                        }
-                        Err(e) => println!("Error connecting to Binance: {}", e),
-                    },
-                    Err(e) => println!("Error handling connection: {}", e),
+// Writes every result of `streams` to stdout as one JSON line, and status
+// events to stderr, until the process is told to stop or an upstream
+// failure, then shuts the server down so the sinks send what they hold
+async fn stream_to_stdout(server: Server, streams: Vec<String>) -> Result<(), ServerError> {
+    let streamed = tokio::select! {
+        streamed = write_streams(&server, streams) => streamed,
+        _ = terminated() => Ok(()),
+    };
+    server.shutdown().await;
+    streamed
+}
+
+async fn write_streams(server: &Server, streams: Vec<String>) -> Result<(), ServerError> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for stream in streams {
+        let mut subscription = server.subscribe(&stream).await?;
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while let Some(message) = subscription.recv().await {
+                if tx.send(Some(message)).is_err() {
+                    return;
                 }
-            });
-        }
+            }
+            // Only ends once the upstream gave up on the stream
+            let _ = tx.send(None);
+        });
     }
 
-    async fn process_binance_stream(
-        state: Arc<ServerState>,
-        req: &Request,
-    ) -> Result<(), ServerError> {
-        let rpn_tokens = to_rpn(&parse(&req.stream)?[..])?;
-        let mut candle_stack: Vec<Arc<Mutex<Candle>>> = Vec::new();
-
-        loop {
-            for token in rpn_tokens {
-                match token {
-                    Token::Operand(symbol) => {
-                        if let Some(ref mut connection) =
-                            state.connections.read().await.get(&symbol)
-                        {
-                            while let Some(message) = connection.next().await {
-                                match message {
-                                    Ok(data) => {
-                                        let parsed_data: BinanceMessage =
-                                            serde_json::from_str(&data.to_string())?;
-                                        let kline = parsed_data.data.k;
-
-                                        let candle = Candle::new(
-                                            kline.t,
-                                            kline.o.parse()?,
-                                            kline.c.parse()?,
-                                            kline.h.parse()?,
-                                            kline.l.parse()?,
-                                        );
-                                        candle_stack.push(candle);
-                                    }
-                                    Err(e) => return Err(ServerError::WebsocketError(e)),
-                                }
-                            }
-                        }
-                    }
-                    Token::Operator(op) => {
-                        let rhs = candle_stack.pop().unwrap();
-                        let lhs = candle_stack.pop().unwrap();
-                        let result = match op {
-                            Operator::Plus => lhs.lock().await.add(*rhs.lock().await),
-                            Operator::Minus => lhs.lock().await.sub(*rhs.lock().await),
-                            Operator::Multiply => lhs.lock().await.mul(*rhs.lock().await),
-                            Operator::Divide => lhs.lock().await.div(*rhs.lock().await),
-                            Operator::NotOperator => return Err(ServerError::ParsingStream),
-                        }?;
-                        candle_stack.push(Candle::new(
-                            result.t, result.o, result.c, result.h, result.l,
-                        ));
-                    }
-                    _ => return Err(ServerError::ParsingStream),
+    let mut stdout = std::io::stdout().lock();
+    loop {
+        let message = rx
+            .recv()
+            .await
+            .flatten()
+            .ok_or(ServerError::WebSocketConnect)?;
+        match message {
+            ServerMessage::Result(result) => {
+                let line = serde_json::to_string(&result)?;
+                // A closed pipe, e.g. `| head`, just means the reader is done
+                if writeln!(stdout, "{}", line)
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    return Ok(());
+                }
+            }
+            ServerMessage::Status(status) => eprintln!("{}", serde_json::to_string(&status)?),
+            ServerMessage::Ticker(ticker) => {
+                if writeln!(stdout, "{}", serde_json::to_string(&ticker)?)
+                    .and_then(|_| stdout.flush())
+                    .is_err()
+                {
+                    return Ok(());
                 }
             }
+        }
+    }
+}
 
-            let result_candle = candle_stack.pop().unwrap();
-            let result_candle = result_candle.lock().await;
-
-            let result_message = ResultMessage {
-                stream: req.stream.clone(),
-                data: ResultData {
-                    t: result_candle.t,
-                    o: result_candle.o,
-                    c: result_candle.c,
-                    h: result_candle.h,
-                    l: result_candle.l,
-                },
-            };
+// Writes each message to stderr as one line, prefixed with its time and level
+struct StderrLogger;
 
-            let result_message = serde_json::to_string(&result_message)?;
-            println!("{}", result_message);
-            break;
-        }
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
 
-        Ok(())
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64);
+        eprintln!(
+            "{} {:<5} {}: {}",
+            format_rfc3339(now),
+            record.level(),
+            record.target(),
+            record.args()
+        );
     }
+
+    fn flush(&self) {}
 }
 
-fn main() {}
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
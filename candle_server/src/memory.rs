@@ -0,0 +1,218 @@
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Approximate bytes held by every buffer of the server, against an
+/// optional budget. Each buffer charges its own `MemoryAccount`, so a new
+/// buffer type is accounted for by taking one rather than by remembering to
+/// report to some central place.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    // `usize::MAX` when unlimited
+    limit: AtomicUsize,
+    used: AtomicUsize,
+    // Accounts alive, to tell the largest ones apart
+    accounts: AtomicUsize,
+    // Bytes given up over budget since the server started
+    shed: AtomicU64,
+}
+
+/// Where the buffered bytes stand, as `STATS` reports them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub used: usize,
+    pub budget: Option<usize>,
+    pub shed: u64,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget::new(None)
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> MemoryBudget {
+        MemoryBudget {
+            limit: AtomicUsize::new(limit.unwrap_or(usize::MAX)),
+            used: AtomicUsize::new(0),
+            accounts: AtomicUsize::new(0),
+            shed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit
+            .store(limit.unwrap_or(usize::MAX), Ordering::Relaxed);
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.used() > self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let limit = self.limit.load(Ordering::Relaxed);
+        MemoryStats {
+            used: self.used(),
+            budget: (limit != usize::MAX).then_some(limit),
+            shed: self.shed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes one buffer holds, counted into its `MemoryBudget` as well. Clones
+/// share the count, e.g. between the two ends of a channel.
+#[derive(Debug, Clone)]
+pub struct MemoryAccount {
+    inner: Arc<Account>,
+}
+
+#[derive(Debug)]
+struct Account {
+    budget: Arc<MemoryBudget>,
+    // What the buffer is, for the log
+    name: String,
+    bytes: AtomicUsize,
+    // Set from the first shed until the account is back within its share,
+    // so an episode is logged once rather than per message
+    shedding: AtomicBool,
+    shed: AtomicU64,
+}
+
+impl Default for MemoryAccount {
+    /// An account of a budget of its own, without a limit.
+    fn default() -> Self {
+        MemoryAccount::new(&Arc::default(), "unbudgeted")
+    }
+}
+
+impl MemoryAccount {
+    pub fn new(budget: &Arc<MemoryBudget>, name: impl Into<String>) -> MemoryAccount {
+        budget.accounts.fetch_add(1, Ordering::Relaxed);
+        MemoryAccount {
+            inner: Arc::new(Account {
+                budget: budget.clone(),
+                name: name.into(),
+                bytes: AtomicUsize::new(0),
+                shedding: AtomicBool::new(false),
+                shed: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn charge(&self, bytes: usize) {
+        self.inner.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.inner.budget.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.inner.bytes.fetch_sub(bytes, Ordering::Relaxed);
+        self.inner.budget.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.inner.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Whether this buffer should give up what it can: the budget is spent
+    /// and it holds at least an even share of it, so the largest consumers
+    /// shed and small ones are left alone. Those can only grow to their
+    /// share, so the total stays about at the budget.
+    pub fn must_shed(&self) -> bool {
+        let budget = &self.inner.budget;
+        let share =
+            budget.limit.load(Ordering::Relaxed) / budget.accounts.load(Ordering::Relaxed).max(1);
+        let over = budget.is_over() && self.bytes() >= share;
+        if !over && self.inner.shedding.swap(false, Ordering::Relaxed) {
+            info!(
+                "{} back within the memory budget, shed {} bytes",
+                self.inner.name,
+                self.inner.shed.swap(0, Ordering::Relaxed)
+            );
+        }
+        over
+    }
+
+    /// Records `bytes` given up over budget.
+    pub fn shed(&self, bytes: usize) {
+        let budget = &self.inner.budget;
+        budget.shed.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.inner.shed.fetch_add(bytes as u64, Ordering::Relaxed);
+        if !self.inner.shedding.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Memory budget spent ({} of {} bytes), shedding from {} holding {} bytes",
+                budget.used(),
+                budget.limit.load(Ordering::Relaxed),
+                self.inner.name,
+                self.bytes()
+            );
+        }
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        let bytes = *self.bytes.get_mut();
+        self.budget.used.fetch_sub(bytes, Ordering::Relaxed);
+        self.budget.accounts.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryAccount, MemoryBudget, MemoryStats};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_accounts_add_up_and_release_on_drop() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let a = MemoryAccount::new(&budget, "a");
+        let b = MemoryAccount::new(&budget, "b");
+        a.charge(30);
+        b.charge(50);
+        a.release(10);
+        assert_eq!((a.bytes(), b.bytes(), budget.used()), (20, 50, 70));
+
+        drop(b);
+        assert_eq!(budget.used(), 20);
+        assert_eq!(
+            budget.stats(),
+            MemoryStats {
+                used: 20,
+                budget: Some(100),
+                shed: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_largest_accounts_shed_first() {
+        let budget = Arc::new(MemoryBudget::new(Some(100)));
+        let large = MemoryAccount::new(&budget, "large");
+        let small = MemoryAccount::new(&budget, "small");
+        large.charge(90);
+        small.charge(10);
+        assert!(!large.must_shed());
+
+        small.charge(10);
+        assert!(large.must_shed());
+        assert!(!small.must_shed());
+
+        large.shed(40);
+        large.release(40);
+        assert!(!large.must_shed());
+        assert_eq!(budget.stats().shed, 40);
+    }
+
+    #[test]
+    fn test_unlimited_budget_never_sheds() {
+        let account = MemoryAccount::default();
+        account.charge(usize::MAX / 2);
+        assert!(!account.must_shed());
+        assert_eq!(MemoryBudget::default().stats().budget, None);
+    }
+}
@@ -0,0 +1,124 @@
+use crate::align::bar_start;
+use crate::candle::Candle;
+use crate::error::ServerError;
+use crate::utils::interval_to_millis;
+
+// Kind of the upstream streams of `mid:` operands, e.g. `btcusdt@midKline_1m`,
+// built from the symbol's `@bookTicker`
+const STREAM_KIND: &str = "@midKline_";
+
+/// Symbol and interval of a mid-price stream, `None` for any other.
+pub fn parse_stream(stream: &str) -> Option<(&str, &str)> {
+    stream.split_once(STREAM_KIND)
+}
+
+pub fn is_stream(stream: &str) -> bool {
+    parse_stream(stream).is_some()
+}
+
+/// The book ticker stream a mid-price stream of `symbol` is built from.
+pub fn book_ticker_stream(symbol: &str) -> String {
+    format!("{}@bookTicker", symbol)
+}
+
+/// Turns best bid/ask midpoints into bars of one interval. The midpoint
+/// holds from one book update to the next, so a bar opens at the close of
+/// the one before it; bars no update landed in are skipped. Folding in an
+/// update within the open bar doesn't allocate.
+pub struct MidBars {
+    interval: String,
+    bar: Option<Candle>,
+    // When the open bar ends, so most updates skip working out their bar
+    end: u64,
+}
+
+impl MidBars {
+    pub fn new(interval: &str) -> Result<MidBars, ServerError> {
+        interval_to_millis(interval, 0)?;
+        Ok(MidBars {
+            interval: interval.to_string(),
+            bar: None,
+            end: 0,
+        })
+    }
+
+    /// Folds in the midpoint `price` at `time` and returns the bar it
+    /// closed, if any, and the open one. An update older than the open bar
+    /// changes neither and returns `None`.
+    pub fn observe(&mut self, time: u64, price: f64) -> Option<(Option<Candle>, Candle)> {
+        let mut closed = None;
+        let mut bar = match self.bar {
+            Some(bar) if time < bar.t => return None,
+            Some(bar) if time < self.end => bar,
+            previous => {
+                let length = interval_to_millis(&self.interval, time).unwrap_or(1);
+                let start = bar_start(time, &self.interval, length);
+                self.end = start + interval_to_millis(&self.interval, start).unwrap_or(length);
+                let open = previous.map_or(price, |bar| bar.c);
+                closed = previous.map(|bar| bar.with_closed(true));
+                Candle::new(start, open, open, open, open)
+            }
+        };
+        bar.c = price;
+        bar.h = bar.h.max(price);
+        bar.l = bar.l.min(price);
+        bar.event_time = time;
+        self.bar = Some(bar);
+        Some((closed, bar))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{book_ticker_stream, parse_stream, MidBars};
+
+    const MINUTE: u64 = 60_000;
+
+    #[test]
+    fn test_streams() {
+        assert_eq!(parse_stream("btcusdt@midKline_1m"), Some(("btcusdt", "1m")));
+        assert_eq!(parse_stream("btcusdt@kline_1m"), None);
+        assert_eq!(book_ticker_stream("btcusdt"), "btcusdt@bookTicker");
+    }
+
+    #[test]
+    fn test_updates_within_a_bar_update_it() {
+        let mut bars = MidBars::new("1m").unwrap();
+        let (closed, bar) = bars.observe(10_000, 100.0).unwrap();
+        assert!(closed.is_none());
+        assert_eq!((bar.t, bar.o, bar.c), (0, 100.0, 100.0));
+
+        let (closed, bar) = bars.observe(40_000, 99.5).unwrap();
+        assert!(closed.is_none());
+        assert_eq!((bar.o, bar.h, bar.l, bar.c), (100.0, 100.0, 99.5, 99.5));
+        assert_eq!(bar.event_time, 40_000);
+        assert!(!bar.closed);
+    }
+
+    #[test]
+    fn test_a_new_bar_closes_the_last_and_opens_at_its_close() {
+        let mut bars = MidBars::new("1m").unwrap();
+        bars.observe(10_000, 100.0);
+        bars.observe(50_000, 101.0);
+
+        let (closed, open) = bars.observe(3 * MINUTE + 5_000, 102.0).unwrap();
+        let closed = closed.unwrap();
+        assert_eq!((closed.t, closed.c), (0, 101.0));
+        assert!(closed.closed);
+        // The bars in between had no update
+        assert_eq!(open.t, 3 * MINUTE);
+        assert_eq!(
+            (open.o, open.h, open.l, open.c),
+            (101.0, 102.0, 101.0, 102.0)
+        );
+        assert!(!open.closed);
+
+        // Nothing older than the open bar changes it
+        assert!(bars.observe(30_000, 1.0).is_none());
+    }
+
+    #[test]
+    fn test_invalid_interval() {
+        assert!(MidBars::new("5x").is_err());
+    }
+}
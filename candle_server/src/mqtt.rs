@@ -0,0 +1,439 @@
+use log::{error, info, warn};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+use url::Url;
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::error::ServerError;
+use crate::memory::MemoryAccount;
+use crate::sink::{SinkCounters, SinkHandle, SinkReceiver, SinkRecord, SINK_QUEUE_CAPACITY};
+
+// A broker taking longer than this to answer counts as a failed connection
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// MQTT 3.1.1 control packet types, shifted into the fixed header
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+/// Delivery guarantee of published candles. Exactly-once isn't offered: a
+/// newer candle supersedes a duplicate anyway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QoS {
+    #[default]
+    AtMostOnce,
+    AtLeastOnce,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttSinkConfig {
+    // `mqtt://[user[:password]@]host[:port]`
+    pub url: String,
+    pub client_id: String,
+    // First level of every topic; see `topic`
+    pub topic_prefix: String,
+    pub qos: QoS,
+    // Have the broker keep the latest candle of every topic for dashboards
+    // that subscribe later
+    pub retain: bool,
+    pub keep_alive: Duration,
+}
+
+impl Default for MqttSinkConfig {
+    fn default() -> Self {
+        MqttSinkConfig {
+            url: "mqtt://127.0.0.1:1883".into(),
+            client_id: "candle_server".into(),
+            topic_prefix: "candles".into(),
+            qos: QoS::default(),
+            retain: false,
+            keep_alive: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Topic of an expression: the prefix, the expression and the interval as
+/// three levels, e.g. `btcusdt-ethusdt@1m` -> `candles/btcusdt-ethusdt/1m`.
+/// MQTT gives `/` and `+` special meaning, so in the expression division is
+/// written `|` and addition `&`.
+pub fn topic(prefix: &str, key: &str) -> String {
+    let (expression, interval) = key.rsplit_once('@').unwrap_or((key, ""));
+    let expression: String = expression
+        .chars()
+        .map(|c| match c {
+            '/' => '|',
+            '+' => '&',
+            '#' => '_',
+            c => c,
+        })
+        .collect();
+    format!("{}/{}/{}", prefix, expression, interval)
+}
+
+/// Starts a task publishing every record to an MQTT broker, reconnecting
+/// with `backoff` on its own; records arriving while it is down are dropped.
+pub fn spawn(config: MqttSinkConfig, backoff: BackoffConfig, memory: MemoryAccount) -> SinkHandle {
+    let (handle, rx) = SinkHandle::new("mqtt", SINK_QUEUE_CAPACITY, memory);
+    tokio::spawn(run(config, backoff, rx, handle.counters().clone()));
+    handle
+}
+
+async fn run(
+    config: MqttSinkConfig,
+    backoff: BackoffConfig,
+    mut rx: SinkReceiver,
+    counters: Arc<SinkCounters>,
+) {
+    let mut backoff = Backoff::new(backoff);
+    loop {
+        let mut connection = match MqttConnection::connect(&config).await {
+            Ok(connection) => {
+                info!("Connected to MQTT broker at {}", config.url);
+                counters.connected.store(true, Ordering::Relaxed);
+                backoff.reset();
+                connection
+            }
+            Err(e) => {
+                let Some(delay) = backoff.next_delay() else {
+                    error!(
+                        "Giving up on MQTT after {} attempts: {}",
+                        backoff.attempt(),
+                        e
+                    );
+                    return;
+                };
+                warn!(
+                    "MQTT connection attempt {} failed: {}",
+                    backoff.attempt(),
+                    e
+                );
+                // Whatever arrives meanwhile can't be delivered
+                sleep(delay).await;
+                while rx.try_recv().is_some() {
+                    counters.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+        };
+
+        loop {
+            let published = tokio::select! {
+                record = rx.recv() => match record {
+                    Some(record) => connection.publish(&config, &record).await.map(|_| true),
+                    None => return,
+                },
+                // The broker drops clients silent past their keep-alive
+                _ = sleep(config.keep_alive) => connection.ping().await.map(|_| false),
+            };
+            match published {
+                Ok(true) => {
+                    counters.published.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("Error talking to MQTT broker: {}", e);
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    counters.connected.store(false, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+struct MqttConnection {
+    stream: TcpStream,
+    // Identifier of the last QoS 1 publish, never 0
+    packet_id: u16,
+}
+
+impl MqttConnection {
+    async fn connect(config: &MqttSinkConfig) -> Result<MqttConnection, ServerError> {
+        let url = Url::parse(&config.url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| ServerError::Mqtt("URL without a host".into()))?;
+        let stream = timeout(
+            REPLY_TIMEOUT,
+            TcpStream::connect((host, url.port().unwrap_or(1883))),
+        )
+        .await
+        .map_err(|_| ServerError::Mqtt("connection timed out".into()))??;
+        let mut connection = MqttConnection {
+            stream,
+            packet_id: 0,
+        };
+
+        // Clean session, with a username and password when the URL has them
+        let mut flags = 0x02;
+        let mut body = Vec::new();
+        write_string(&mut body, "MQTT");
+        body.push(4);
+        let flags_at = body.len();
+        body.push(0);
+        let keep_alive = config.keep_alive.as_secs().clamp(1, u16::MAX as u64) as u16;
+        body.extend_from_slice(&keep_alive.to_be_bytes());
+        write_string(&mut body, &config.client_id);
+        if !url.username().is_empty() {
+            flags |= 0x80;
+            write_string(&mut body, url.username());
+        }
+        if let Some(password) = url.password() {
+            flags |= 0x40;
+            write_string(&mut body, password);
+        }
+        body[flags_at] = flags;
+
+        connection.send(CONNECT, &body).await?;
+        let (kind, reply) = connection.read_packet().await?;
+        match (kind, reply.as_slice()) {
+            (CONNACK, [_, 0]) => Ok(connection),
+            (CONNACK, [_, code]) => Err(ServerError::Mqtt(format!(
+                "connection refused with code {}",
+                code
+            ))),
+            _ => Err(ServerError::Mqtt("expected CONNACK".into())),
+        }
+    }
+
+    /// Publishes one record, waiting for the broker's acknowledgement at
+    /// QoS 1.
+    async fn publish(
+        &mut self,
+        config: &MqttSinkConfig,
+        record: &SinkRecord,
+    ) -> Result<(), ServerError> {
+        let payload = serde_json::to_vec(&record.message)?;
+        let mut body = Vec::with_capacity(payload.len() + 64);
+        write_string(&mut body, &topic(&config.topic_prefix, &record.key));
+        let mut flags = config.retain as u8;
+        if config.qos == QoS::AtLeastOnce {
+            flags |= 0x02;
+            self.packet_id = self.packet_id.checked_add(1).unwrap_or(1);
+            body.extend_from_slice(&self.packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(&payload);
+        self.send(PUBLISH | flags, &body).await?;
+
+        if config.qos == QoS::AtLeastOnce {
+            let (kind, reply) = self.read_packet().await?;
+            if kind != PUBACK || reply != self.packet_id.to_be_bytes() {
+                return Err(ServerError::Mqtt("expected PUBACK".into()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<(), ServerError> {
+        self.send(PINGREQ, &[]).await?;
+        match self.read_packet().await? {
+            (PINGRESP, _) => Ok(()),
+            _ => Err(ServerError::Mqtt("expected PINGRESP".into())),
+        }
+    }
+
+    async fn send(&mut self, header: u8, body: &[u8]) -> Result<(), ServerError> {
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        // Remaining length, seven bits per byte
+        let mut remaining = body.len();
+        loop {
+            let byte = (remaining % 128) as u8;
+            remaining /= 128;
+            if remaining == 0 {
+                packet.push(byte);
+                break;
+            }
+            packet.push(byte | 0x80);
+        }
+        packet.extend_from_slice(body);
+        self.stream.write_all(&packet).await?;
+        Ok(())
+    }
+
+    // Packet type and body of the next packet from the broker
+    async fn read_packet(&mut self) -> Result<(u8, Vec<u8>), ServerError> {
+        timeout(REPLY_TIMEOUT, async {
+            let header = self.stream.read_u8().await?;
+            let mut remaining = 0usize;
+            for shift in (0..28).step_by(7) {
+                let byte = self.stream.read_u8().await?;
+                remaining |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+            }
+            let mut body = vec![0; remaining];
+            self.stream.read_exact(&mut body).await?;
+            Ok((header & 0xf0, body))
+        })
+        .await
+        .map_err(|_| ServerError::Mqtt("broker stopped answering".into()))?
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn, topic, MqttSinkConfig, QoS};
+    use crate::backoff::BackoffConfig;
+    use crate::candle::Candle;
+    use crate::memory::MemoryAccount;
+    use crate::protocol::{ResultData, ResultMessage};
+    use crate::sink::SinkRecord;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    #[derive(Debug)]
+    struct Published {
+        topic: String,
+        payload: Value,
+        qos: u8,
+        retain: bool,
+    }
+
+    async fn read_packet(socket: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let header = socket.read_u8().await.ok()?;
+        let mut remaining = 0usize;
+        for shift in (0..28).step_by(7) {
+            let byte = socket.read_u8().await.ok()?;
+            remaining |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        let mut body = vec![0; remaining];
+        socket.read_exact(&mut body).await.ok()?;
+        Some((header, body))
+    }
+
+    // Fake broker accepting every client and reporting what it publishes
+    async fn fake_broker() -> (String, mpsc::UnboundedReceiver<Published>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("mqtt://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    while let Some((header, body)) = read_packet(&mut socket).await {
+                        match header >> 4 {
+                            1 => socket.write_all(&[0x20, 2, 0, 0]).await.unwrap(),
+                            3 => {
+                                let qos = (header >> 1) & 0x03;
+                                let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                                let topic = String::from_utf8(body[2..2 + len].to_vec()).unwrap();
+                                let mut payload = &body[2 + len..];
+                                if qos > 0 {
+                                    let id = &payload[..2];
+                                    socket.write_all(&[0x40, 2, id[0], id[1]]).await.unwrap();
+                                    payload = &payload[2..];
+                                }
+                                let _ = tx.send(Published {
+                                    topic,
+                                    payload: serde_json::from_slice(payload).unwrap(),
+                                    qos,
+                                    retain: header & 0x01 == 1,
+                                });
+                            }
+                            12 => socket.write_all(&[0xd0, 0]).await.unwrap(),
+                            _ => {}
+                        }
+                    }
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    fn record(t: u64) -> Arc<SinkRecord> {
+        Arc::new(SinkRecord {
+            key: "btcusdt/ethusdt@1m".into(),
+            message: ResultMessage {
+                id: None,
+                stream: "btcusdt/ethusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 2.0, 2.0, 1.0)),
+                closed: false,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            },
+        })
+    }
+
+    async fn next_published(rx: &mut mpsc::UnboundedReceiver<Published>) -> Published {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("nothing published")
+            .unwrap()
+    }
+
+    #[test]
+    fn test_topic_levels_and_sanitization() {
+        assert_eq!(
+            topic("candles", "btcusdt-ethusdt@1m"),
+            "candles/btcusdt-ethusdt/1m"
+        );
+        assert_eq!(
+            topic("candles", "(btcusdt+ethusdt)/bnbusdt@15m"),
+            "candles/(btcusdt&ethusdt)|bnbusdt/15m"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_results_are_published_with_qos_and_retain() {
+        let (url, mut published) = fake_broker().await;
+        let config = MqttSinkConfig {
+            url,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            ..MqttSinkConfig::default()
+        };
+        let sink = spawn(config, BackoffConfig::default(), MemoryAccount::default());
+
+        for t in [0, 60_000] {
+            sink.offer(record(t));
+        }
+        for t in [0, 60_000] {
+            let message = next_published(&mut published).await;
+            assert_eq!(message.topic, "candles/btcusdt|ethusdt/1m");
+            assert_eq!(message.payload["data"]["t"], t);
+            assert_eq!((message.qos, message.retain), (1, true));
+        }
+        assert!(sink.stats().connected);
+    }
+
+    #[tokio::test]
+    async fn test_idle_connection_is_kept_alive() {
+        let (url, mut published) = fake_broker().await;
+        let config = MqttSinkConfig {
+            url,
+            keep_alive: Duration::from_millis(20),
+            ..MqttSinkConfig::default()
+        };
+        let sink = spawn(config, BackoffConfig::default(), MemoryAccount::default());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        sink.offer(record(0));
+        let message = next_published(&mut published).await;
+        assert_eq!((message.qos, message.retain), (0, false));
+        assert_eq!(sink.stats().failed, 0);
+    }
+}
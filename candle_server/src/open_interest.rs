@@ -0,0 +1,144 @@
+use crate::align::bar_start;
+use crate::candle::Candle;
+use crate::error::ServerError;
+use crate::utils::interval_to_millis;
+
+// Kind of the upstream streams of `oi:` operands, e.g. `btcusdt@openInterest_5m`
+const STREAM_KIND: &str = "@openInterest_";
+
+/// Symbol and interval of an open-interest stream, `None` for any other.
+pub fn parse_stream(stream: &str) -> Option<(&str, &str)> {
+    stream.split_once(STREAM_KIND)
+}
+
+pub fn is_stream(stream: &str) -> bool {
+    parse_stream(stream).is_some()
+}
+
+/// Turns polled open interest into bars of one interval. The value holds
+/// from one poll to the next, so a bar opens at the close of the one before
+/// it and bars no poll landed in repeat that close.
+pub struct OpenInterestBars {
+    interval: String,
+    bar: Option<Candle>,
+}
+
+impl OpenInterestBars {
+    pub fn new(interval: &str) -> Result<OpenInterestBars, ServerError> {
+        interval_to_millis(interval, 0)?;
+        Ok(OpenInterestBars {
+            interval: interval.to_string(),
+            bar: None,
+        })
+    }
+
+    /// Folds in `value` measured at `time` and returns the bars it changed,
+    /// oldest first: any it closed, then the open one. A measurement older
+    /// than the open bar changes none.
+    pub fn observe(&mut self, time: u64, value: f64) -> Vec<Candle> {
+        let start = self.start(time);
+        let mut bar = match self.bar {
+            Some(bar) if start < bar.t => return Vec::new(),
+            Some(bar) => bar,
+            None => Candle::new(start, value, value, value, value),
+        };
+
+        let mut changed = Vec::new();
+        while bar.t < start {
+            bar.closed = true;
+            changed.push(bar);
+            let next = bar.t + interval_to_millis(&self.interval, bar.t).unwrap_or(u64::MAX);
+            bar = Candle::new(next.min(start), bar.c, bar.c, bar.c, bar.c);
+            bar.event_time = time;
+        }
+        bar.c = value;
+        bar.h = bar.h.max(value);
+        bar.l = bar.l.min(value);
+        bar.event_time = time;
+        self.bar = Some(bar);
+        changed.push(bar);
+        changed
+    }
+
+    fn start(&self, time: u64) -> u64 {
+        let length = interval_to_millis(&self.interval, time).unwrap_or(1);
+        bar_start(time, &self.interval, length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_stream, OpenInterestBars};
+
+    const MINUTE: u64 = 60_000;
+
+    #[test]
+    fn test_streams() {
+        assert_eq!(
+            parse_stream("btcusdt@openInterest_5m"),
+            Some(("btcusdt", "5m"))
+        );
+        assert_eq!(parse_stream("btcusdt@kline_5m"), None);
+    }
+
+    #[test]
+    fn test_polls_within_a_bar_update_it() {
+        let mut bars = OpenInterestBars::new("1m").unwrap();
+        let bar = bars.observe(10_000, 100.0);
+        assert_eq!(bar.len(), 1);
+        assert_eq!((bar[0].t, bar[0].o, bar[0].c), (0, 100.0, 100.0));
+
+        let bar = bars.observe(40_000, 90.0)[0];
+        assert_eq!((bar.o, bar.h, bar.l, bar.c), (100.0, 100.0, 90.0, 90.0));
+        assert_eq!(bar.event_time, 40_000);
+        assert!(!bar.closed);
+    }
+
+    #[test]
+    fn test_a_new_bar_closes_the_last_and_opens_at_its_close() {
+        let mut bars = OpenInterestBars::new("1m").unwrap();
+        bars.observe(10_000, 100.0);
+        bars.observe(50_000, 110.0);
+
+        let changed = bars.observe(MINUTE + 5_000, 120.0);
+        assert_eq!(changed.len(), 2);
+        assert_eq!((changed[0].t, changed[0].c), (0, 110.0));
+        assert!(changed[0].closed);
+        let open = changed[1];
+        assert_eq!(open.t, MINUTE);
+        assert_eq!(
+            (open.o, open.h, open.l, open.c),
+            (110.0, 120.0, 110.0, 120.0)
+        );
+        assert!(!open.closed);
+
+        // Nothing older than the open bar changes it
+        assert!(bars.observe(30_000, 1.0).is_empty());
+    }
+
+    #[test]
+    fn test_bars_without_a_poll_repeat_the_last_value() {
+        let mut bars = OpenInterestBars::new("1m").unwrap();
+        bars.observe(0, 100.0);
+
+        let changed = bars.observe(3 * MINUTE, 130.0);
+        let opens: Vec<(u64, f64, f64, bool)> = changed
+            .iter()
+            .map(|bar| (bar.t, bar.o, bar.c, bar.closed))
+            .collect();
+        assert_eq!(
+            opens,
+            [
+                (0, 100.0, 100.0, true),
+                (MINUTE, 100.0, 100.0, true),
+                (2 * MINUTE, 100.0, 100.0, true),
+                (3 * MINUTE, 100.0, 130.0, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_interval() {
+        assert!(OpenInterestBars::new("5x").is_err());
+    }
+}
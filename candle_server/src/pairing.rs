@@ -0,0 +1,572 @@
+use std::collections::{BTreeMap, HashMap};
+use tokio::time::{Duration, Instant};
+
+use crate::candle::{Candle, CompositeSemantics};
+use crate::error::ServerError;
+use crate::expr::{Expr, Interval};
+use crate::memory::MemoryAccount;
+use crate::protocol::TakerFlow;
+
+/// Latest candle of every leg of one expression, and the bars still waiting
+/// on some of its legs. Legs are addressed by their index in `streams`, so
+/// once the pending buffers have warmed up, recording and evaluating a bar
+/// doesn't allocate.
+pub struct LegBook {
+    streams: Vec<String>,
+    // Leg of every symbol in the expression
+    legs_by_symbol: HashMap<String, usize>,
+    latest: Vec<Option<Candle>>,
+    // Newest bar among the latest candles, and how many legs are at it, so
+    // a bar completes without scanning every leg on each arrival
+    newest: Option<u64>,
+    at_newest: usize,
+    pending: Vec<PendingBar>,
+    // `reported` buffers of expired or completed bars, for reuse
+    spare: Vec<Vec<bool>>,
+    last_complete: Option<u64>,
+    // Charged for the pending bars
+    memory: MemoryAccount,
+    // How results combine the legs' highs and lows
+    semantics: CompositeSemantics,
+    // Divisors no further from zero leave the prices they divide undefined
+    epsilon: f64,
+    // Bars of legs starting at most this many milliseconds apart are one
+    tolerance: u64,
+}
+
+// A bar not yet reported by every leg, held until its pairing window expires
+struct PendingBar {
+    t: u64,
+    deadline: Instant,
+    reported: Vec<bool>,
+}
+
+impl LegBook {
+    /// `streams` are the upstream kline streams of `expr`'s symbols at
+    /// `interval`, each listed once.
+    pub fn new(expr: &Expr, interval: &Interval, streams: Vec<String>) -> LegBook {
+        let legs_by_symbol = expr
+            .symbols()
+            .filter_map(|symbol| {
+                let stream = interval.stream(symbol);
+                let leg = streams.iter().position(|known| *known == stream)?;
+                Some((symbol.to_string(), leg))
+            })
+            .collect();
+
+        LegBook {
+            latest: vec![None; streams.len()],
+            streams,
+            legs_by_symbol,
+            newest: None,
+            at_newest: 0,
+            pending: Vec::new(),
+            spare: Vec::new(),
+            last_complete: None,
+            memory: MemoryAccount::default(),
+            semantics: CompositeSemantics::default(),
+            epsilon: 0.0,
+            tolerance: 0,
+        }
+    }
+
+    /// Charges the bars held for the pairing window to `memory`. While it
+    /// must shed, a new bar missing legs isn't held, as if there were no
+    /// window.
+    pub fn with_memory(mut self, memory: MemoryAccount) -> LegBook {
+        self.memory = memory;
+        self
+    }
+
+    /// Combines the legs' highs and lows as `semantics` says in `eval`.
+    pub fn with_semantics(mut self, semantics: CompositeSemantics) -> LegBook {
+        self.semantics = semantics;
+        self
+    }
+
+    /// Treats divisors within `epsilon` of zero as zero in `eval`.
+    pub fn with_epsilon(mut self, epsilon: f64) -> LegBook {
+        self.epsilon = epsilon;
+        self
+    }
+
+    /// Pairs bars of legs starting at most `tolerance` milliseconds apart,
+    /// as from venues whose bars don't open on the same millisecond. The bar
+    /// they make starts with the earliest. It should stay under half a bar,
+    /// so a leg's next or previous bar is never taken for the same one.
+    pub fn with_tolerance(mut self, tolerance: u64) -> LegBook {
+        self.tolerance = tolerance;
+        self
+    }
+
+    // Approximate bytes of one pending bar
+    fn pending_size(&self) -> usize {
+        std::mem::size_of::<PendingBar>() + self.streams.len()
+    }
+
+    pub fn streams(&self) -> &[String] {
+        &self.streams
+    }
+
+    /// Sets a leg's latest candle without pairing it, e.g. from a cache.
+    pub fn seed(&mut self, leg: usize, candle: Candle) {
+        self.set_latest(leg, candle);
+    }
+
+    // Whether a leg's bar starting at `t` is the newest bar, or within the
+    // tolerance before it
+    fn at_newest_bar(&self, t: u64) -> bool {
+        self.newest
+            .is_some_and(|newest| t <= newest && t + self.tolerance >= newest)
+    }
+
+    fn set_latest(&mut self, leg: usize, candle: Candle) {
+        let was_newest = self.latest[leg].is_some_and(|c| self.at_newest_bar(c.t));
+        self.latest[leg] = Some(candle);
+        match self.newest {
+            Some(_) if self.at_newest_bar(candle.t) => self.at_newest += usize::from(!was_newest),
+            Some(newest) if candle.t < newest => {
+                self.at_newest -= usize::from(was_newest);
+                // Only a seed moves a leg back; once no leg is at the newest
+                // bar, find the one that now is
+                if self.at_newest == 0 {
+                    self.newest = self.latest.iter().flatten().map(|c| c.t).max();
+                    self.count_at_newest();
+                }
+            }
+            _ => {
+                self.newest = Some(candle.t);
+                // Legs within the tolerance before it are at it too
+                match self.tolerance {
+                    0 => self.at_newest = 1,
+                    _ => self.count_at_newest(),
+                }
+            }
+        }
+    }
+
+    fn count_at_newest(&mut self) {
+        self.at_newest = self
+            .latest
+            .iter()
+            .flatten()
+            .filter(|latest| self.at_newest_bar(latest.t))
+            .count();
+    }
+
+    // Pending bar a leg's bar starting at `t` belongs to: the nearest within
+    // the tolerance, the earlier of two as near
+    fn pending_at(&self, t: u64) -> Option<usize> {
+        self.pending
+            .iter()
+            .enumerate()
+            .filter(|(_, bar)| bar.t.abs_diff(t) <= self.tolerance)
+            .min_by_key(|(_, bar)| (bar.t.abs_diff(t), bar.t))
+            .map(|(index, _)| index)
+    }
+
+    /// Newest bar among the legs' latest candles.
+    pub fn newest(&self) -> Option<u64> {
+        self.newest
+    }
+
+    /// Latest candle of the first leg, when every leg's latest is the same
+    /// bar, so it can be evaluated before any leg reports another.
+    pub fn aligned(&self) -> Option<Candle> {
+        let first = self.latest.first().copied().flatten()?;
+        (self.at_newest == self.latest.len()).then_some(first)
+    }
+
+    /// Candles of every leg, with `candle` in place of `leg`'s latest.
+    fn legs_with(&self, leg: usize, candle: Candle) -> impl Iterator<Item = Option<Candle>> + '_ {
+        self.latest
+            .iter()
+            .enumerate()
+            .map(move |(i, latest)| if i == leg { Some(candle) } else { *latest })
+    }
+
+    /// Records a bar from `leg` and returns whether every leg has now
+    /// reported `candle.t`, or a bar within the tolerance of it. Late bars
+    /// are paired with the latest values but never replace them. With a
+    /// `window`, a new bar still missing legs is held until the window
+    /// passes; see `expire`.
+    pub fn record(
+        &mut self,
+        leg: usize,
+        candle: Candle,
+        late: bool,
+        window: Option<Duration>,
+    ) -> bool {
+        let complete = if late {
+            self.legs_with(leg, candle)
+                .try_fold((candle.t, candle.t), |(first, last), leg| {
+                    leg.map(|leg| (first.min(leg.t), last.max(leg.t)))
+                })
+                .is_some_and(|(first, last)| last - first <= self.tolerance)
+        } else {
+            self.set_latest(leg, candle);
+            self.at_newest_bar(candle.t) && self.at_newest == self.latest.len()
+        };
+        let pending = self.pending_at(candle.t);
+        if !complete {
+            let waiting = !late
+                && self
+                    .last_complete
+                    .is_none_or(|t| candle.t > t + self.tolerance);
+            if let (Some(window), true) = (window, waiting) {
+                let size = self.pending_size();
+                if pending.is_none() && self.memory.must_shed() {
+                    self.memory.shed(size);
+                    return false;
+                }
+                let index = pending.unwrap_or_else(|| {
+                    self.memory.charge(size);
+                    let mut reported = self.spare.pop().unwrap_or_default();
+                    reported.clear();
+                    reported.resize(self.streams.len(), false);
+                    self.pending.push(PendingBar {
+                        t: candle.t,
+                        deadline: Instant::now() + window,
+                        reported,
+                    });
+                    self.pending.len() - 1
+                });
+                let bar = &mut self.pending[index];
+                bar.t = bar.t.min(candle.t);
+                bar.reported[leg] = true;
+            }
+            return false;
+        }
+
+        if !late {
+            if let Some(index) = pending {
+                let bar = self.pending.swap_remove(index);
+                self.memory.release(self.pending_size());
+                self.spare.push(bar.reported);
+            }
+            self.last_complete = self.last_complete.max(Some(candle.t));
+        }
+        true
+    }
+
+    /// Evaluates `expr` over the latest candles, with `candle` standing in
+    /// for `leg`'s. Prices divided by zero are left undefined.
+    pub fn eval(&self, expr: &Expr, leg: usize, candle: Candle) -> Result<Candle, ServerError> {
+        expr.eval_as(self.semantics, self.epsilon, self.tolerance, &|symbol| {
+            let &i = self.legs_by_symbol.get(symbol)?;
+            if i == leg {
+                Some(candle)
+            } else {
+                self.latest[i]
+            }
+        })
+    }
+
+    /// Taker flow of every leg by stream, with `candle` standing in for
+    /// `leg`'s.
+    pub fn flow(&self, leg: usize, candle: Candle) -> BTreeMap<String, TakerFlow> {
+        self.streams
+            .iter()
+            .zip(self.legs_with(leg, candle))
+            .filter_map(|(stream, leg)| Some((stream.clone(), TakerFlow::from(&leg?))))
+            .collect()
+    }
+
+    /// Closes of the first two legs, with `candle` standing in for `leg`'s.
+    pub fn closes(&self, leg: usize, candle: Candle) -> [Option<f64>; 2] {
+        let mut closes = self.legs_with(leg, candle).map(|leg| leg.map(|leg| leg.c));
+        [closes.next().flatten(), closes.next().flatten()]
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.iter().map(|bar| bar.deadline).min()
+    }
+
+    /// Drops the bars whose window passed by `now`, oldest first, calling
+    /// `report` with each one's start time and the streams that never
+    /// reported it.
+    pub fn expire(&mut self, now: Instant, mut report: impl FnMut(u64, Vec<String>)) {
+        self.pending.sort_unstable_by_key(|bar| bar.t);
+        let mut index = 0;
+        while index < self.pending.len() {
+            if self.pending[index].deadline > now {
+                index += 1;
+                continue;
+            }
+            let bar = self.pending.remove(index);
+            self.memory.release(self.pending_size());
+            let missing = self
+                .streams
+                .iter()
+                .zip(&bar.reported)
+                .filter(|(_, reported)| !**reported)
+                .map(|(stream, _)| stream.clone())
+                .collect();
+            report(bar.t, missing);
+            self.spare.push(bar.reported);
+        }
+    }
+}
+
+impl Drop for LegBook {
+    // An evaluator restarted after a panic starts a new book on the same
+    // account
+    fn drop(&mut self) {
+        self.memory
+            .release(self.pending.len() * self.pending_size());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LegBook;
+    use crate::candle::Candle;
+    use crate::expr::Expr;
+    use crate::memory::{MemoryAccount, MemoryBudget};
+    use std::sync::Arc;
+    use tokio::time::{Duration, Instant};
+
+    fn book(input: &str) -> (Expr, LegBook) {
+        let (expr, interval) = Expr::parse(input).unwrap();
+        let streams = expr.streams(&interval);
+        let book = LegBook::new(&expr, &interval, streams);
+        (expr, book)
+    }
+
+    fn bar(t: u64, price: f64) -> Candle {
+        Candle::new(t, price, price, price, price)
+    }
+
+    #[test]
+    fn test_bar_completes_once_every_leg_reports() {
+        let (expr, mut book) = book("ethusdt/btcusdt@1m");
+        assert_eq!(book.streams(), ["btcusdt@kline_1m", "ethusdt@kline_1m"]);
+
+        assert!(!book.record(0, bar(0, 10.0), false, None));
+        assert!(book.record(1, bar(0, 5.0), false, None));
+        assert_eq!(book.eval(&expr, 1, bar(0, 5.0)).unwrap().c, 0.5);
+        // A later bar on one leg doesn't pair with the other's older bar
+        assert!(!book.record(1, bar(60_000, 6.0), false, None));
+    }
+
+    #[test]
+    fn test_late_bar_pairs_without_replacing_latest() {
+        let (expr, mut book) = book("ethusdt/btcusdt@1m");
+        book.record(0, bar(60_000, 10.0), false, None);
+        book.record(1, bar(60_000, 5.0), false, None);
+
+        assert!(!book.record(1, bar(0, 4.0), true, None));
+        book.seed(0, bar(0, 8.0));
+        assert!(book.record(1, bar(0, 4.0), true, None));
+        assert_eq!(book.eval(&expr, 1, bar(0, 4.0)).unwrap().c, 0.5);
+        assert_eq!(book.flow(1, bar(0, 4.0)).len(), 2);
+        assert_eq!(book.closes(1, bar(0, 4.0)), [Some(8.0), Some(4.0)]);
+    }
+
+    #[test]
+    fn test_expired_bar_lists_missing_legs() {
+        let (_, mut book) = book("btcusdt+ethusdt+bnbusdt@1m");
+        let window = Some(Duration::from_millis(10));
+        book.record(0, bar(0, 1.0), false, window);
+        book.record(2, bar(0, 1.0), false, window);
+        book.record(2, bar(60_000, 1.0), false, window);
+        assert!(book.next_deadline().is_some());
+
+        let mut expired = Vec::new();
+        book.expire(Instant::now() + Duration::from_secs(1), |t, missing| {
+            expired.push((t, missing))
+        });
+        assert_eq!(
+            expired,
+            vec![
+                (0, vec!["btcusdt@kline_1m".to_string()]),
+                (
+                    60_000,
+                    vec![
+                        "bnbusdt@kline_1m".to_string(),
+                        "btcusdt@kline_1m".to_string()
+                    ]
+                ),
+            ]
+        );
+        assert!(book.next_deadline().is_none());
+    }
+
+    const BASKET: &str = "(btcusdt*0.25 + ethusdt*0.15 + solusdt*0.1 + bnbusdt*0.1 \
+        + xrpusdt*0.08 + adausdt*0.07 + dogeusdt*0.06 + avaxusdt*0.05 + dotusdt*0.05 \
+        + linkusdt*0.04 + ltcusdt*0.03 + trxusdt*0.02)@1m";
+
+    #[test]
+    fn test_basket_completes_once_every_leg_reports() {
+        let (expr, mut book) = book(BASKET);
+        assert_eq!(book.streams().len(), 12);
+
+        for leg in 0..11 {
+            assert!(!book.record(leg, bar(0, 1.0), false, None));
+        }
+        // A repeated update of a leg already at the bar doesn't count twice
+        assert!(!book.record(3, bar(0, 2.0), false, None));
+        assert!(book.record(11, bar(0, 1.0), false, None));
+        assert_eq!(book.aligned().unwrap().t, 0);
+        let c = book.eval(&expr, 11, bar(0, 1.0)).unwrap().c;
+        assert!((c - 1.25).abs() < 1e-9);
+
+        // The next bar needs every leg again
+        for leg in 0..11 {
+            assert!(!book.record(leg, bar(60_000, 1.0), false, None));
+        }
+        assert!(book.aligned().is_none());
+        assert!(book.record(11, bar(60_000, 1.0), false, None));
+    }
+
+    #[test]
+    fn test_silent_basket_leg() {
+        // Skipped: the bar never completes
+        let (_, mut skipping) = book(BASKET);
+        for leg in 1..12 {
+            assert!(!skipping.record(leg, bar(0, 1.0), false, None));
+        }
+        assert!(skipping.next_deadline().is_none());
+
+        // Partial: the bar expires listing the silent leg
+        let (_, mut partial) = book(BASKET);
+        let window = Some(Duration::from_millis(10));
+        for leg in 1..12 {
+            assert!(!partial.record(leg, bar(0, 1.0), false, window));
+        }
+        let mut expired = Vec::new();
+        partial.expire(Instant::now() + Duration::from_secs(1), |t, missing| {
+            expired.push((t, missing))
+        });
+        assert_eq!(expired, vec![(0, vec!["adausdt@kline_1m".to_string()])]);
+    }
+
+    #[test]
+    fn test_completed_bar_is_no_longer_pending() {
+        let (_, mut book) = book("btcusdt+ethusdt@1m");
+        let window = Some(Duration::from_millis(10));
+        book.record(0, bar(0, 1.0), false, window);
+        assert!(book.record(1, bar(0, 1.0), false, window));
+        assert!(book.next_deadline().is_none());
+    }
+
+    // A book pairing bars starting up to 500ms apart
+    fn tolerant(input: &str) -> (Expr, LegBook) {
+        let (expr, book) = book(input);
+        (expr, book.with_tolerance(500))
+    }
+
+    #[test]
+    fn test_bars_within_tolerance_pair_as_the_earliest() {
+        // Without a tolerance, only the same start pairs
+        let (_, mut strict) = book("ethusdt/btcusdt@1m");
+        strict.record(0, bar(1, 10.0), false, None);
+        assert!(!strict.record(1, bar(0, 5.0), false, None));
+
+        let (expr, mut book) = tolerant("ethusdt/btcusdt@1m");
+        assert!(!book.record(0, bar(500, 10.0), false, None));
+        // Exactly at the tolerance, and earlier than the other leg's
+        assert!(book.record(1, bar(0, 5.0), false, None));
+        let result = book.eval(&expr, 1, bar(0, 5.0)).unwrap();
+        assert_eq!((result.t, result.c), (0, 0.5));
+        assert_eq!(book.aligned().unwrap().t, 500);
+
+        // One past it, they're different bars
+        assert!(!book.record(0, bar(60_000, 10.0), false, None));
+        assert!(!book.record(1, bar(60_501, 5.0), false, None));
+        assert!(book.eval(&expr, 1, bar(60_501, 5.0)).is_err());
+    }
+
+    #[test]
+    fn test_late_bar_pairs_within_tolerance() {
+        let (_, mut book) = tolerant("ethusdt/btcusdt@1m");
+        book.seed(0, bar(300, 10.0));
+        book.record(1, bar(60_000, 5.0), false, None);
+        assert!(book.record(1, bar(0, 4.0), true, None));
+        assert!(!book.record(1, bar(801, 4.0), true, None));
+    }
+
+    #[test]
+    fn test_leg_joins_the_nearest_pending_bar() {
+        let window = Some(Duration::from_millis(10));
+        let (_, mut book) = tolerant("btcusdt+ethusdt+bnbusdt@1m");
+        book.record(0, bar(0, 1.0), false, window);
+        book.record(2, bar(700, 1.0), false, window);
+        // Within the tolerance of both, nearer the second
+        book.record(1, bar(400, 1.0), false, window);
+        let mut expired = Vec::new();
+        book.expire(Instant::now() + Duration::from_secs(1), |t, missing| {
+            expired.push((t, missing))
+        });
+        assert_eq!(
+            expired,
+            vec![
+                (
+                    0,
+                    vec![
+                        "btcusdt@kline_1m".to_string(),
+                        "ethusdt@kline_1m".to_string()
+                    ]
+                ),
+                // Starting with its earliest leg
+                (400, vec!["bnbusdt@kline_1m".to_string()]),
+            ]
+        );
+
+        // As near to both, the earlier is taken
+        let (_, mut book) = tolerant("btcusdt+ethusdt+bnbusdt@1m");
+        book.record(0, bar(0, 1.0), false, window);
+        book.record(2, bar(800, 1.0), false, window);
+        book.record(1, bar(400, 1.0), false, window);
+        let mut expired = Vec::new();
+        book.expire(Instant::now() + Duration::from_secs(1), |t, missing| {
+            expired.push((t, missing.len()))
+        });
+        assert_eq!(expired, vec![(0, 1), (800, 2)]);
+    }
+
+    #[test]
+    fn test_tolerance_does_not_cover_a_missing_bar() {
+        let window = Some(Duration::from_millis(10));
+        let (expr, mut book) = tolerant("btcusdt+ethusdt@1m");
+        assert!(!book.record(0, bar(0, 1.0), false, window));
+        assert!(book.record(1, bar(300, 2.0), false, window));
+        // ethusdt skips the bar opening around 60s
+        assert!(!book.record(0, bar(60_000, 1.0), false, window));
+        assert!(!book.record(0, bar(120_000, 1.0), false, window));
+        assert!(book.record(1, bar(120_300, 2.0), false, window));
+        assert_eq!(book.eval(&expr, 1, bar(120_300, 2.0)).unwrap().t, 120_000);
+
+        let mut expired = Vec::new();
+        book.expire(Instant::now() + Duration::from_secs(1), |t, missing| {
+            expired.push((t, missing))
+        });
+        assert_eq!(
+            expired,
+            vec![(60_000, vec!["ethusdt@kline_1m".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_pending_bars_are_charged_and_shed_over_budget() {
+        let budget = Arc::new(MemoryBudget::new(None));
+        let (_, book) = book("btcusdt+ethusdt@1m");
+        let mut book = book.with_memory(MemoryAccount::new(&budget, "evaluator"));
+        let window = Some(Duration::from_millis(10));
+        book.record(0, bar(0, 1.0), false, window);
+        let held = budget.used();
+        assert!(held > 0);
+        book.record(1, bar(0, 1.0), false, window);
+        assert_eq!(budget.used(), 0);
+
+        // Spent: a bar missing legs is no longer held for them
+        book.record(0, bar(60_000, 1.0), false, window);
+        budget.set_limit(Some(held - 1));
+        book.record(0, bar(120_000, 1.0), false, window);
+        assert_eq!(budget.used(), held);
+        assert_eq!(budget.stats().shed, held as u64);
+
+        drop(book);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,641 @@
+use std::io::{self, Write};
+
+use crate::protocol::ResultData;
+
+// The subset of Parquet an export needs: one uncompressed, PLAIN encoded
+// data page per column chunk, with the file metadata in Thrift's compact
+// protocol. Columns are `stream` (utf8), `t` (timestamp, millis) and the
+// optional float64 prices and volume `o`, `h`, `l`, `c` and `v`.
+
+/// Rows per row group unless an export asks for another size.
+pub const ROW_GROUP_ROWS: usize = 65_536;
+
+const MAGIC: &[u8] = b"PAR1";
+
+// Physical types, repetitions, encodings and converted types
+const INT64: i32 = 2;
+const DOUBLE: i32 = 5;
+const BYTE_ARRAY: i32 = 6;
+const REQUIRED: i32 = 0;
+const OPTIONAL: i32 = 1;
+const PLAIN: i32 = 0;
+const RLE: i32 = 3;
+const UTF8: i32 = 0;
+const TIMESTAMP_MILLIS: i32 = 9;
+
+// Compact protocol field types
+const TRUE: u8 = 1;
+const I32: u8 = 5;
+const I64: u8 = 6;
+const BINARY: u8 = 8;
+const LIST: u8 = 9;
+const STRUCT: u8 = 12;
+
+const PRICES: [&str; 5] = ["o", "h", "l", "c", "v"];
+
+/// What an export wrote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Written {
+    pub rows: usize,
+    pub row_groups: usize,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Column {
+    Stream,
+    Time,
+    Price(usize),
+}
+
+impl Column {
+    const ALL: [Column; 7] = [
+        Column::Stream,
+        Column::Time,
+        Column::Price(0),
+        Column::Price(1),
+        Column::Price(2),
+        Column::Price(3),
+        Column::Price(4),
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Column::Stream => "stream",
+            Column::Time => "t",
+            Column::Price(index) => PRICES[index],
+        }
+    }
+
+    fn kind(self) -> i32 {
+        match self {
+            Column::Stream => BYTE_ARRAY,
+            Column::Time => INT64,
+            Column::Price(_) => DOUBLE,
+        }
+    }
+
+    fn price(self, candle: &ResultData) -> Option<f64> {
+        match self {
+            Column::Price(0) => candle.o,
+            Column::Price(1) => candle.h,
+            Column::Price(2) => candle.l,
+            Column::Price(3) => candle.c,
+            _ => candle.v,
+        }
+    }
+}
+
+// Where a column chunk was written, for the footer
+struct Chunk {
+    column: Column,
+    offset: u64,
+    size: u64,
+    values: usize,
+}
+
+/// Writes `candles` of `stream` to `out` as a Parquet file, `row_group`
+/// rows to a row group.
+pub fn write_candles<W: Write>(
+    out: W,
+    stream: &str,
+    candles: &[ResultData],
+    row_group: usize,
+) -> io::Result<Written> {
+    let mut out = Counting {
+        inner: out,
+        written: 0,
+    };
+    out.write_all(MAGIC)?;
+    let mut groups = Vec::new();
+    for rows in candles.chunks(row_group.max(1)) {
+        let mut chunks = Vec::new();
+        for column in Column::ALL {
+            let page = page(column, stream, rows);
+            let mut header = Compact::default();
+            header.i32(1, 0); // DATA_PAGE
+            header.i32(2, page.len() as i32);
+            header.i32(3, page.len() as i32);
+            header.begin(5);
+            header.i32(1, rows.len() as i32);
+            header.i32(2, PLAIN);
+            header.i32(3, RLE);
+            header.i32(4, RLE);
+            header.stop();
+            header.stop();
+
+            let offset = out.written;
+            out.write_all(&header.buf)?;
+            out.write_all(&page)?;
+            chunks.push(Chunk {
+                column,
+                offset,
+                size: out.written - offset,
+                values: rows.len(),
+            });
+        }
+        groups.push((rows.len(), chunks));
+    }
+
+    let footer = footer(candles.len(), &groups);
+    out.write_all(&footer)?;
+    out.write_all(&(footer.len() as u32).to_le_bytes())?;
+    out.write_all(MAGIC)?;
+    out.flush()?;
+    Ok(Written {
+        rows: candles.len(),
+        row_groups: groups.len(),
+        bytes: out.written,
+    })
+}
+
+// PLAIN values of `rows`, after the definition levels of an optional column
+fn page(column: Column, stream: &str, rows: &[ResultData]) -> Vec<u8> {
+    let mut page = Vec::new();
+    match column {
+        Column::Stream => {
+            for _ in rows {
+                page.extend_from_slice(&(stream.len() as u32).to_le_bytes());
+                page.extend_from_slice(stream.as_bytes());
+            }
+        }
+        Column::Time => {
+            for row in rows {
+                page.extend_from_slice(&(row.t as i64).to_le_bytes());
+            }
+        }
+        Column::Price(_) => {
+            let prices: Vec<Option<f64>> = rows.iter().map(|row| column.price(row)).collect();
+            let levels = levels(&prices);
+            page.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+            page.extend_from_slice(&levels);
+            for price in prices.into_iter().flatten() {
+                page.extend_from_slice(&price.to_le_bytes());
+            }
+        }
+    }
+    page
+}
+
+// Definition levels, 1 for a value and 0 for a null, as RLE runs of the
+// hybrid encoding with a bit width of 1
+fn levels(prices: &[Option<f64>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at < prices.len() {
+        let present = prices[at].is_some();
+        let run = prices[at..]
+            .iter()
+            .take_while(|price| price.is_some() == present)
+            .count();
+        varint(&mut out, (run as u64) << 1);
+        out.push(present as u8);
+        at += run;
+    }
+    out
+}
+
+fn footer(rows: usize, groups: &[(usize, Vec<Chunk>)]) -> Vec<u8> {
+    let mut meta = Compact::default();
+    meta.i32(1, 1);
+    meta.list(2, STRUCT, Column::ALL.len() + 1);
+    meta.element();
+    meta.binary(4, b"schema");
+    meta.i32(5, Column::ALL.len() as i32);
+    meta.stop();
+    for column in Column::ALL {
+        meta.element();
+        meta.i32(1, column.kind());
+        match column {
+            Column::Stream => {
+                meta.i32(3, REQUIRED);
+                meta.binary(4, column.name().as_bytes());
+                meta.i32(6, UTF8);
+                meta.begin(10);
+                meta.begin(1); // STRING
+                meta.stop();
+                meta.stop();
+            }
+            Column::Time => {
+                meta.i32(3, REQUIRED);
+                meta.binary(4, column.name().as_bytes());
+                meta.i32(6, TIMESTAMP_MILLIS);
+                meta.begin(10);
+                meta.begin(8); // TIMESTAMP
+                meta.boolean(1, true); // isAdjustedToUTC
+                meta.begin(2);
+                meta.begin(1); // MILLIS
+                meta.stop();
+                meta.stop();
+                meta.stop();
+                meta.stop();
+            }
+            Column::Price(_) => {
+                meta.i32(3, OPTIONAL);
+                meta.binary(4, column.name().as_bytes());
+            }
+        }
+        meta.stop();
+    }
+    meta.i64(3, rows as i64);
+    meta.list(4, STRUCT, groups.len());
+    for (rows, chunks) in groups {
+        meta.element();
+        meta.list(1, STRUCT, chunks.len());
+        for chunk in chunks {
+            meta.element();
+            meta.i64(2, chunk.offset as i64);
+            meta.begin(3);
+            meta.i32(1, chunk.column.kind());
+            meta.list(2, I32, 2);
+            meta.varint(PLAIN as i64);
+            meta.varint(RLE as i64);
+            meta.list(3, BINARY, 1);
+            meta.bytes(chunk.column.name().as_bytes());
+            meta.i32(4, 0); // UNCOMPRESSED
+            meta.i64(5, chunk.values as i64);
+            meta.i64(6, chunk.size as i64);
+            meta.i64(7, chunk.size as i64);
+            meta.i64(9, chunk.offset as i64);
+            meta.stop();
+            meta.stop();
+        }
+        meta.i64(2, chunks.iter().map(|chunk| chunk.size as i64).sum());
+        meta.i64(3, *rows as i64);
+        meta.stop();
+    }
+    meta.binary(6, b"candle_server");
+    meta.stop();
+    meta.buf
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+// Writer of Thrift's compact protocol. Field ids are written as deltas from
+// the previous field of the same struct, so nested structs keep their own
+#[derive(Default)]
+struct Compact {
+    buf: Vec<u8>,
+    last: i16,
+    outer: Vec<i16>,
+}
+
+impl Compact {
+    fn field(&mut self, id: i16, kind: u8) {
+        match id - self.last {
+            delta @ 1..=15 => self.buf.push((delta as u8) << 4 | kind),
+            _ => {
+                self.buf.push(kind);
+                self.varint(id as i64);
+            }
+        }
+        self.last = id;
+    }
+
+    // A zigzag varint, as every integer is written
+    fn varint(&mut self, value: i64) {
+        varint(&mut self.buf, ((value << 1) ^ (value >> 63)) as u64);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        varint(&mut self.buf, bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn i32(&mut self, id: i16, value: i32) {
+        self.field(id, I32);
+        self.varint(value as i64);
+    }
+
+    fn i64(&mut self, id: i16, value: i64) {
+        self.field(id, I64);
+        self.varint(value);
+    }
+
+    fn boolean(&mut self, id: i16, value: bool) {
+        self.field(id, if value { TRUE } else { TRUE + 1 });
+    }
+
+    fn binary(&mut self, id: i16, bytes: &[u8]) {
+        self.field(id, BINARY);
+        self.bytes(bytes);
+    }
+
+    fn list(&mut self, id: i16, kind: u8, len: usize) {
+        self.field(id, LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | kind);
+        } else {
+            self.buf.push(0xf0 | kind);
+            varint(&mut self.buf, len as u64);
+        }
+    }
+
+    // A struct field, ended by `stop`
+    fn begin(&mut self, id: i16) {
+        self.field(id, STRUCT);
+        self.element();
+    }
+
+    // A struct element of a list, ended by `stop` too
+    fn element(&mut self) {
+        self.outer.push(self.last);
+        self.last = 0;
+    }
+
+    fn stop(&mut self) {
+        self.buf.push(0);
+        self.last = self.outer.pop().unwrap_or(0);
+    }
+}
+
+// Counts the bytes written, for the offsets of column chunks
+struct Counting<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_candles, Written};
+    use crate::protocol::ResultData;
+    use std::collections::BTreeMap;
+
+    // Just enough of a compact protocol reader to find the column chunks
+    #[derive(Debug)]
+    enum Thrift {
+        Int(i64),
+        Bytes(Vec<u8>),
+        Bool(bool),
+        List(Vec<Thrift>),
+        Struct(BTreeMap<i16, Thrift>),
+    }
+
+    impl Thrift {
+        fn get(&self, id: i16) -> &Thrift {
+            match self {
+                Thrift::Struct(fields) => &fields[&id],
+                other => panic!("expected a struct, got {:?}", other),
+            }
+        }
+
+        fn int(&self) -> i64 {
+            match self {
+                Thrift::Int(value) => *value,
+                other => panic!("expected an integer, got {:?}", other),
+            }
+        }
+
+        fn text(&self) -> &str {
+            match self {
+                Thrift::Bytes(bytes) => std::str::from_utf8(bytes).unwrap(),
+                other => panic!("expected a string, got {:?}", other),
+            }
+        }
+
+        fn list(&self) -> &[Thrift] {
+            match self {
+                Thrift::List(items) => items,
+                other => panic!("expected a list, got {:?}", other),
+            }
+        }
+    }
+
+    fn varint(bytes: &[u8], at: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*at];
+            *at += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    fn zigzag(bytes: &[u8], at: &mut usize) -> i64 {
+        let value = varint(bytes, at);
+        (value >> 1) as i64 ^ -((value & 1) as i64)
+    }
+
+    fn value(bytes: &[u8], at: &mut usize, kind: u8) -> Thrift {
+        match kind {
+            1 | 2 => Thrift::Bool(kind == 1),
+            5 | 6 => Thrift::Int(zigzag(bytes, at)),
+            8 => {
+                let len = varint(bytes, at) as usize;
+                *at += len;
+                Thrift::Bytes(bytes[*at - len..*at].to_vec())
+            }
+            9 => {
+                let header = bytes[*at];
+                *at += 1;
+                let len = match header >> 4 {
+                    15 => varint(bytes, at) as usize,
+                    len => len as usize,
+                };
+                Thrift::List((0..len).map(|_| value(bytes, at, header & 0x0f)).collect())
+            }
+            12 => {
+                let mut fields = BTreeMap::new();
+                let mut last = 0;
+                loop {
+                    let header = bytes[*at];
+                    *at += 1;
+                    if header == 0 {
+                        return Thrift::Struct(fields);
+                    }
+                    let id = match header >> 4 {
+                        0 => zigzag(bytes, at) as i16,
+                        delta => last + delta as i16,
+                    };
+                    last = id;
+                    fields.insert(id, value(bytes, at, header & 0x0f));
+                }
+            }
+            other => panic!("unexpected type {}", other),
+        }
+    }
+
+    // Metadata of `file`, and its rows as (stream, t, [o, h, l, c, v])
+    type Row = (String, i64, Vec<Option<f64>>);
+
+    fn read(file: &[u8]) -> (Thrift, Vec<Row>) {
+        assert_eq!(&file[..4], b"PAR1");
+        assert_eq!(&file[file.len() - 4..], b"PAR1");
+        let len = u32::from_le_bytes(file[file.len() - 8..file.len() - 4].try_into().unwrap());
+        let mut at = file.len() - 8 - len as usize;
+        let meta = value(file, &mut at, 12);
+
+        let mut rows = Vec::new();
+        for group in meta.get(4).list() {
+            let count = group.get(3).int() as usize;
+            let mut columns: Vec<Vec<Option<Vec<u8>>>> = Vec::new();
+            for chunk in group.get(1).list() {
+                let column = chunk.get(3);
+                let mut at = column.get(9).int() as usize;
+                let header = value(file, &mut at, 12);
+                assert_eq!(header.get(5).get(1).int() as usize, count);
+                let size = header.get(3).int() as usize;
+                let page = &file[at..at + size];
+                let width = if column.get(1).int() == 6 { 0 } else { 8 };
+                columns.push(values(page, count, width, column.get(1).int() == 5));
+            }
+            for row in 0..count {
+                let stream = columns[0][row].clone().unwrap();
+                let t = columns[1][row].clone().unwrap();
+                rows.push((
+                    String::from_utf8(stream).unwrap(),
+                    i64::from_le_bytes(t.try_into().unwrap()),
+                    columns[2..]
+                        .iter()
+                        .map(|column| {
+                            column[row]
+                                .clone()
+                                .map(|bytes| f64::from_le_bytes(bytes.try_into().unwrap()))
+                        })
+                        .collect(),
+                ));
+            }
+        }
+        (meta, rows)
+    }
+
+    // `count` PLAIN values of `width` bytes, or length-prefixed when 0,
+    // after RLE definition levels when `optional`
+    fn values(page: &[u8], count: usize, width: usize, optional: bool) -> Vec<Option<Vec<u8>>> {
+        let mut at = 0;
+        let mut present = vec![true; count];
+        if optional {
+            let len = u32::from_le_bytes(page[..4].try_into().unwrap()) as usize;
+            at = 4;
+            let mut row = 0;
+            while at < 4 + len {
+                let run = (varint(page, &mut at) >> 1) as usize;
+                let value = page[at] == 1;
+                at += 1;
+                present[row..row + run].fill(value);
+                row += run;
+            }
+        }
+        present
+            .into_iter()
+            .map(|present| {
+                present.then(|| {
+                    let width = match width {
+                        0 => {
+                            at += 4;
+                            u32::from_le_bytes(page[at - 4..at].try_into().unwrap()) as usize
+                        }
+                        width => width,
+                    };
+                    at += width;
+                    page[at - width..at].to_vec()
+                })
+            })
+            .collect()
+    }
+
+    fn candle(t: u64, close: f64) -> ResultData {
+        ResultData {
+            o: Some(close - 1.0),
+            h: Some(close + 2.0),
+            l: Some(close - 2.0),
+            c: Some(close),
+            v: Some(10.0),
+            ..ResultData::missing(t)
+        }
+    }
+
+    #[test]
+    fn test_rows_read_back() {
+        let mut candles: Vec<ResultData> = (0..5)
+            .map(|i| candle(i * 60_000, 100.0 + i as f64))
+            .collect();
+        // A bar a leg was missing from
+        candles[3] = ResultData::missing(180_000);
+        let mut file = Vec::new();
+        let written = write_candles(&mut file, "btcusdt/ethusdt@1m", &candles, 2).unwrap();
+        assert_eq!(
+            written,
+            Written {
+                rows: 5,
+                row_groups: 3,
+                bytes: file.len() as u64,
+            }
+        );
+
+        let (meta, rows) = read(&file);
+        assert_eq!(meta.get(3).int(), 5);
+        let sizes: Vec<i64> = meta.get(4).list().iter().map(|g| g.get(3).int()).collect();
+        assert_eq!(sizes, [2, 2, 1]);
+        let names: Vec<&str> = meta.get(2).list()[1..]
+            .iter()
+            .map(|element| element.get(4).text())
+            .collect();
+        assert_eq!(names, ["stream", "t", "o", "h", "l", "c", "v"]);
+        // `t` is a UTC timestamp in milliseconds
+        let time = &meta.get(2).list()[2];
+        assert!(matches!(
+            time.get(10).get(8).get(1),
+            super::tests::Thrift::Bool(true)
+        ));
+
+        assert_eq!(rows.len(), 5);
+        assert_eq!(
+            rows[1],
+            (
+                "btcusdt/ethusdt@1m".to_string(),
+                60_000,
+                vec![
+                    Some(100.0),
+                    Some(103.0),
+                    Some(99.0),
+                    Some(101.0),
+                    Some(10.0)
+                ]
+            )
+        );
+        assert_eq!(rows[3].1, 180_000);
+        assert_eq!(rows[3].2, [None; 5]);
+        assert_eq!(rows[4].2[3], Some(104.0));
+    }
+
+    #[test]
+    fn test_long_runs_and_lists() {
+        // More columns chunks than fit a short list header, and level runs
+        // past a single varint byte
+        let candles: Vec<ResultData> = (0..200).map(|i| candle(i * 60_000, 1.0)).collect();
+        let mut file = Vec::new();
+        write_candles(&mut file, "btcusdt@1m", &candles, 10).unwrap();
+        let (meta, rows) = read(&file);
+        assert_eq!(meta.get(4).list().len(), 20);
+        assert_eq!(rows.len(), 200);
+        assert_eq!(rows[199].1, 199 * 60_000);
+
+        let mut file = Vec::new();
+        write_candles(&mut file, "btcusdt@1m", &candles, 200).unwrap();
+        let (_, rows) = read(&file);
+        assert!(rows.iter().all(|row| row.2[4] == Some(10.0)));
+    }
+}
@@ -0,0 +1,570 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::gzip;
+
+// permessage-deflate (RFC 7692) beneath tungstenite, which has no
+// extensions and refuses frames with RSV1 set. `Inflating` sits under a
+// client: it sees the handshake response go by and, when the server took
+// the offer, inflates compressed messages back into plain frames before
+// tungstenite reads them. `Deflating` does the opposite under a server, for
+// the fake Binance of the tests.
+
+/// Header a client offers the extension in, and a server accepts it with.
+pub const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+// Longest handshake head accepted, status line and headers together
+const MAX_HEAD: usize = 16 * 1024;
+// Bytes one message may take, compressed or inflated, and one frame may
+// carry; tungstenite's own limits
+const MAX_MESSAGE: usize = 64 << 20;
+const MAX_FRAME: usize = 16 << 20;
+// Bytes back the next message may match into
+const WINDOW: usize = 32 * 1024;
+const SYNC_FLUSH: [u8; 4] = [0, 0, 0xff, 0xff];
+
+const FIN: u8 = 0x80;
+const RSV1: u8 = 0x40;
+const CONTINUATION: u8 = 0;
+
+/// Bytes read off the socket and handed on once inflated, over every
+/// connection they're shared by.
+#[derive(Debug, Default)]
+pub struct WireCounters {
+    received: AtomicU64,
+    decoded: AtomicU64,
+}
+
+impl WireCounters {
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn decoded(&self) -> u64 {
+        self.decoded.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether `value`, a `Sec-WebSocket-Extensions` header, names
+/// permessage-deflate, and if so whether its parameters include
+/// `server_no_context_takeover`.
+pub fn accepted(value: &str) -> Option<bool> {
+    value.split(',').find_map(|extension| {
+        let mut params = extension.split(';').map(str::trim);
+        (params.next()? == PERMESSAGE_DEFLATE)
+            .then(|| params.any(|param| param == "server_no_context_takeover"))
+    })
+}
+
+// What the handshake agreed on, once it's over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Handshake,
+    Plain,
+    // Each message starts afresh when the server doesn't take its context
+    // over
+    Compressed { takeover: bool },
+}
+
+// A whole frame at the start of `bytes`: its first byte, where the payload
+// starts and where the frame ends. One longer than `MAX_FRAME` is refused
+// as soon as its header is in, rather than waited for
+fn frame(bytes: &[u8]) -> io::Result<Option<(u8, usize, usize)>> {
+    let Some((first, start, len)) = header(bytes) else {
+        return Ok(None);
+    };
+    if len > MAX_FRAME as u64 {
+        return Err(invalid(format!("frame of {} bytes", len)));
+    }
+    let end = start + len as usize;
+    Ok((bytes.len() >= end).then_some((first, start, end)))
+}
+
+// The first byte, where the payload starts and how long it is, of the frame
+// header at the start of `bytes`
+fn header(bytes: &[u8]) -> Option<(u8, usize, u64)> {
+    let (&first, &second) = (bytes.first()?, bytes.get(1)?);
+    let (mut start, len): (usize, u64) = match second & 0x7f {
+        126 => (
+            4,
+            u16::from_be_bytes(bytes.get(2..4)?.try_into().ok()?) as u64,
+        ),
+        127 => (10, u64::from_be_bytes(bytes.get(2..10)?.try_into().ok()?)),
+        len => (2, u64::from(len)),
+    };
+    if second & 0x80 != 0 {
+        start += 4;
+    }
+    Some((first, start, len))
+}
+
+// Unmasked frame starting with the byte `first`, carrying `payload`
+fn write_frame(first: u8, payload: &[u8], out: &mut Vec<u8>) {
+    out.push(first);
+    match payload.len() {
+        len @ 0..=125 => out.push(len as u8),
+        len @ 126..=0xffff => {
+            out.push(126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(payload);
+}
+
+// Length of the head at the start of `bytes`, blank line included
+fn head_len(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|at| at + 4)
+}
+
+// The phase a handshake `head` leads to
+fn negotiated(head: &[u8]) -> Phase {
+    let head = String::from_utf8_lossy(head);
+    let agreed = head.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case(EXTENSIONS_HEADER)
+            .then(|| accepted(value))?
+    });
+    match agreed {
+        Some(no_takeover) => Phase::Compressed {
+            takeover: !no_takeover,
+        },
+        None => Phase::Plain,
+    }
+}
+
+fn invalid(what: impl ToString) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// Client stream inflating what a permessage-deflate server sends. It only
+/// looks for the extension in the handshake response when `offered`, as a
+/// server may not accept what wasn't offered.
+pub struct Inflating<S> {
+    inner: S,
+    phase: Phase,
+    counters: Arc<WireCounters>,
+    // Read and not yet a whole frame, or the whole head
+    raw: Vec<u8>,
+    // Ready for the reader, from `ready_at` on
+    ready: Vec<u8>,
+    ready_at: usize,
+    // Opcode and payload so far of a compressed message in fragments
+    message: Option<(u8, Vec<u8>)>,
+    // Messages inflated before, for the next to match into
+    history: Vec<u8>,
+}
+
+impl<S> Inflating<S> {
+    pub fn new(inner: S, offered: bool, counters: Arc<WireCounters>) -> Inflating<S> {
+        Inflating {
+            inner,
+            phase: if offered {
+                Phase::Handshake
+            } else {
+                Phase::Plain
+            },
+            counters,
+            raw: Vec::new(),
+            ready: Vec::new(),
+            ready_at: 0,
+            message: None,
+            history: Vec::new(),
+        }
+    }
+
+    /// Whether the server accepted permessage-deflate; false until the
+    /// handshake is over.
+    pub fn compressed(&self) -> bool {
+        matches!(self.phase, Phase::Compressed { .. })
+    }
+
+    // Moves what's whole in `raw` to `ready`
+    fn decode(&mut self) -> io::Result<()> {
+        if self.phase == Phase::Handshake {
+            let Some(len) = head_len(&self.raw) else {
+                if self.raw.len() > MAX_HEAD {
+                    return Err(invalid("handshake response too long"));
+                }
+                return Ok(());
+            };
+            self.phase = negotiated(&self.raw[..len]);
+            self.ready.extend(self.raw.drain(..len));
+        }
+        let Phase::Compressed { takeover } = self.phase else {
+            self.ready.append(&mut self.raw);
+            return Ok(());
+        };
+        let mut at = 0;
+        while let Some((first, start, end)) = frame(&self.raw[at..])? {
+            let (frame, payload) = (&self.raw[at..at + end], &self.raw[at + start..at + end]);
+            let opcode = first & 0x0f;
+            let fin = first & FIN != 0;
+            match &mut self.message {
+                // Control frames may come between fragments
+                _ if opcode & 0x08 != 0 => self.ready.extend_from_slice(frame),
+                Some((_, compressed)) if opcode == CONTINUATION => {
+                    if compressed.len() + payload.len() > MAX_MESSAGE {
+                        return Err(invalid("compressed message too long"));
+                    }
+                    compressed.extend_from_slice(payload)
+                }
+                None if first & RSV1 != 0 && opcode != CONTINUATION => {
+                    self.message = Some((opcode, payload.to_vec()))
+                }
+                Some(_) => return Err(invalid("data frame inside a compressed message")),
+                None => self.ready.extend_from_slice(frame),
+            }
+            if fin && opcode & 0x08 == 0 {
+                if let Some((opcode, mut compressed)) = self.message.take() {
+                    compressed.extend_from_slice(&SYNC_FLUSH);
+                    let history = if takeover { &self.history[..] } else { &[] };
+                    let message =
+                        gzip::inflate_sync(&compressed, history, MAX_MESSAGE).map_err(invalid)?;
+                    write_frame(FIN | opcode, &message, &mut self.ready);
+                    if takeover {
+                        self.history.extend_from_slice(&message);
+                        let excess = self.history.len().saturating_sub(WINDOW);
+                        self.history.drain(..excess);
+                    }
+                }
+            }
+            at += end;
+        }
+        self.raw.drain(..at);
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Inflating<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if this.ready_at < this.ready.len() {
+                let len = buf.remaining().min(this.ready.len() - this.ready_at);
+                buf.put_slice(&this.ready[this.ready_at..this.ready_at + len]);
+                this.ready_at += len;
+                if this.ready_at == this.ready.len() {
+                    this.ready.clear();
+                    this.ready_at = 0;
+                }
+                this.counters
+                    .decoded
+                    .fetch_add(len as u64, Ordering::Relaxed);
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0; 8 * 1024];
+            let mut read = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // A frame cut short is tungstenite's to report
+                if this.raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                this.ready.append(&mut this.raw);
+                continue;
+            }
+            this.counters
+                .received
+                .fetch_add(read.filled().len() as u64, Ordering::Relaxed);
+            this.raw.extend_from_slice(read.filled());
+            this.decode()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Inflating<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Server stream compressing the messages it sends once its handshake
+/// response has accepted permessage-deflate. Fragmented messages and
+/// control frames go out as they are.
+pub struct Deflating<S> {
+    inner: S,
+    phase: Phase,
+    // Written and not yet a whole frame, or the whole head
+    raw: Vec<u8>,
+    // To write to `inner`, from `pending_at` on
+    pending: Vec<u8>,
+    pending_at: usize,
+    history: Vec<u8>,
+}
+
+impl<S> Deflating<S> {
+    pub fn new(inner: S) -> Deflating<S> {
+        Deflating {
+            inner,
+            phase: Phase::Handshake,
+            raw: Vec::new(),
+            pending: Vec::new(),
+            pending_at: 0,
+            history: Vec::new(),
+        }
+    }
+
+    // Moves what's whole in `raw` to `pending`
+    fn encode(&mut self) -> io::Result<()> {
+        if self.phase == Phase::Handshake {
+            let Some(len) = head_len(&self.raw) else {
+                return Ok(());
+            };
+            self.phase = negotiated(&self.raw[..len]);
+            self.pending.extend(self.raw.drain(..len));
+        }
+        let Phase::Compressed { takeover } = self.phase else {
+            self.pending.append(&mut self.raw);
+            return Ok(());
+        };
+        let mut at = 0;
+        while let Some((first, start, end)) = frame(&self.raw[at..])? {
+            let (frame, payload) = (&self.raw[at..at + end], &self.raw[at + start..at + end]);
+            let opcode = first & 0x0f;
+            if first & FIN == 0 || opcode == CONTINUATION || opcode & 0x08 != 0 {
+                self.pending.extend_from_slice(frame);
+            } else {
+                let mut compressed = Vec::new();
+                gzip::deflate_sync(payload, &self.history, &mut compressed);
+                compressed.truncate(compressed.len() - SYNC_FLUSH.len());
+                write_frame(FIN | RSV1 | opcode, &compressed, &mut self.pending);
+                if takeover {
+                    self.history.extend_from_slice(payload);
+                    let excess = self.history.len().saturating_sub(WINDOW);
+                    self.history.drain(..excess);
+                }
+            }
+            at += end;
+        }
+        self.raw.drain(..at);
+        Ok(())
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        while self.pending_at < self.pending.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_at..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending_at += written;
+        }
+        self.pending.clear();
+        self.pending_at = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Deflating<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Deflating<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(this.poll_pending(cx))?;
+        this.raw.extend_from_slice(buf);
+        this.encode()?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepted, write_frame, Deflating, Inflating, WireCounters, FIN, MAX_FRAME, RSV1};
+    use crate::gzip;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{timeout, Duration};
+
+    const ACCEPTED: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+sec-websocket-extensions: permessage-deflate; server_max_window_bits=15\r\n\r\n";
+    const DECLINED: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n";
+    const TEXT: u8 = 1;
+    const PING: u8 = 9;
+
+    fn plain(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame(first, payload, &mut frame);
+        frame
+    }
+
+    // `payload` deflated after `history`, without the sync flush's tail
+    fn deflated(payload: &[u8], history: &[u8]) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        gzip::deflate_sync(payload, history, &mut compressed);
+        compressed.truncate(compressed.len() - 4);
+        compressed
+    }
+
+    async fn inflate(bytes: &[u8], offered: bool) -> (Vec<u8>, bool, Arc<WireCounters>) {
+        let counters = Arc::new(WireCounters::default());
+        let mut stream = Inflating::new(bytes, offered, counters.clone());
+        let mut out = Vec::new();
+        stream.read_to_end(&mut out).await.unwrap();
+        (out, stream.compressed(), counters)
+    }
+
+    #[test]
+    fn test_accepted_extensions() {
+        assert_eq!(accepted("permessage-deflate"), Some(false));
+        assert_eq!(
+            accepted("x-webkit-deflate-frame, permessage-deflate; server_no_context_takeover"),
+            Some(true)
+        );
+        assert_eq!(accepted("permessage-deflater"), None);
+        assert_eq!(accepted(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_compressed_messages_are_inflated_into_plain_frames() {
+        let first = br#"{"stream":"btcusdt@kline_1m","data":{"c":"42000.5"}}"#;
+        let second = br#"{"stream":"btcusdt@kline_1m","data":{"c":"42001.5"}}"#;
+        let third = deflated(b"fragmented", &[first, &second[..]].concat());
+        let (head, tail) = third.split_at(4);
+        let mut wire = ACCEPTED.to_vec();
+        wire.extend(plain(FIN | RSV1 | TEXT, &deflated(first, b"")));
+        wire.extend(plain(FIN | RSV1 | TEXT, &deflated(second, first)));
+        // Fragments, with a ping between them, and a message sent as is
+        wire.extend(plain(RSV1 | TEXT, head));
+        wire.extend(plain(FIN | PING, b"ping"));
+        wire.extend(plain(FIN, tail));
+        wire.extend(plain(FIN | TEXT, b"uncompressed"));
+
+        let (out, compressed, counters) = inflate(&wire, true).await;
+        let mut expected = ACCEPTED.to_vec();
+        expected.extend(plain(FIN | TEXT, first));
+        expected.extend(plain(FIN | TEXT, second));
+        expected.extend(plain(FIN | PING, b"ping"));
+        expected.extend(plain(FIN | TEXT, b"fragmented"));
+        expected.extend(plain(FIN | TEXT, b"uncompressed"));
+        assert_eq!(out, expected);
+        assert!(compressed);
+        assert_eq!(counters.received(), wire.len() as u64);
+        assert_eq!(counters.decoded(), expected.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_frames_pass_through_unless_accepted() {
+        let mut wire = DECLINED.to_vec();
+        wire.extend(plain(FIN | TEXT, b"plain"));
+        let (out, compressed, _) = inflate(&wire, true).await;
+        assert_eq!((out, compressed), (wire, false));
+
+        // Accepted without being offered is left to tungstenite to refuse
+        let mut wire = ACCEPTED.to_vec();
+        wire.extend(plain(FIN | RSV1 | TEXT, &deflated(b"plain", b"")));
+        let (out, compressed, _) = inflate(&wire, false).await;
+        assert_eq!((out, compressed), (wire, false));
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_messages_are_errors() {
+        let mut wire = ACCEPTED.to_vec();
+        wire.extend(plain(FIN | RSV1 | TEXT, b"\xff\xff\xff"));
+        let counters = Arc::new(WireCounters::default());
+        let mut stream = Inflating::new(&wire[..], true, counters);
+        assert!(stream.read_to_end(&mut Vec::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_are_errors() {
+        // Refused from the header alone, with the connection still open
+        let (mut server, client) = tokio::io::duplex(1024);
+        let mut stream = Inflating::new(client, true, Arc::new(WireCounters::default()));
+        server.write_all(ACCEPTED).await.unwrap();
+        server.write_all(&[FIN | RSV1 | TEXT, 127]).await.unwrap();
+        server
+            .write_all(&(MAX_FRAME as u64 + 1).to_be_bytes())
+            .await
+            .unwrap();
+        let read = timeout(Duration::from_secs(5), stream.read_to_end(&mut Vec::new()))
+            .await
+            .expect("oversized frame waited for");
+        assert!(read.is_err());
+
+        // One at the limit is read, though it fails to inflate
+        let mut wire = ACCEPTED.to_vec();
+        wire.extend(plain(FIN | RSV1 | TEXT, &vec![0xff; MAX_FRAME]));
+        let mut stream = Inflating::new(&wire[..], true, Arc::new(WireCounters::default()));
+        let error = stream.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert!(!error.to_string().starts_with("frame of"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_deflated_frames_inflate_back() {
+        let messages: Vec<String> = (0..20)
+            .map(|i| format!(r#"{{"stream":"btcusdt@kline_1m","data":{{"t":{}}}}}"#, i))
+            .collect();
+        let mut sent = ACCEPTED.to_vec();
+        for message in &messages {
+            sent.extend(plain(FIN | TEXT, message.as_bytes()));
+        }
+        sent.extend(plain(FIN | PING, b""));
+
+        let mut wire = Vec::new();
+        let mut stream = Deflating::new(&mut wire);
+        // Split mid-frame, as writes may be
+        for chunk in sent.chunks(37) {
+            stream.write_all(chunk).await.unwrap();
+        }
+        stream.flush().await.unwrap();
+        assert!(wire.len() * 2 < sent.len(), "{}", wire.len());
+
+        let (out, compressed, _) = inflate(&wire, true).await;
+        assert_eq!(out, sent);
+        assert!(compressed);
+    }
+}
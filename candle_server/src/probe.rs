@@ -0,0 +1,60 @@
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::error::ServerError;
+use crate::sse;
+
+// Route load balancers poll on the WebSocket listener
+const ROUTE: &[u8] = b"GET /ready";
+
+/// Whether the request waiting on `socket` is the readiness probe, without
+/// consuming any of it.
+pub async fn is_probe_request(socket: &TcpStream) -> bool {
+    sse::requests_route(socket, ROUTE).await
+}
+
+/// Answers the readiness probe: 200 while the server takes new clients, 503
+/// once it's draining, so it's routed no more of them.
+pub async fn serve<S>(socket: S, ready: bool) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if ready {
+        respond(socket, "200 OK", r#"{"ready":true}"#).await
+    } else {
+        respond(socket, "503 Service Unavailable", r#"{"ready":false}"#).await
+    }
+}
+
+/// Turns away any other request of a client connecting while the server
+/// drains, with a 503 rather than a dropped connection.
+pub async fn refuse<S>(socket: S) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let body = r#"{"ready":false,"error":"server draining"}"#;
+    respond(socket, "503 Service Unavailable", body).await
+}
+
+// Reads the request head, so closing doesn't reset the connection before
+// the client reads the answer, then answers `status` with a JSON `body`
+async fn respond<S>(socket: S, status: &str, body: &str) -> Result<(), ServerError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut socket = BufReader::new(socket);
+    sse::read_target(&mut socket).await?;
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let socket = socket.get_mut();
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
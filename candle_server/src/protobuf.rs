@@ -0,0 +1,667 @@
+//! Writes server messages as the `ServerFrame`s of `proto/candles.proto`,
+//! for clients asking for the `protobuf` format, and the `CandleUpdate`s
+//! of the gRPC front-end, whose requests it reads. Only the wire types the
+//! contract uses are needed: varints, 64-bit doubles and length-delimited
+//! fields.
+
+use serde_json::{Map, Value};
+
+use crate::error::ServerError;
+use crate::protocol::{ResultMessage, ServerMessage, StatusMessage, TickerMessage};
+
+const VARINT: u32 = 0;
+const FIXED64: u32 = 1;
+const LEN: u32 = 2;
+const FIXED32: u32 = 5;
+
+// `ServerFrame` fields
+const CANDLE: u32 = 1;
+const ACK: u32 = 2;
+const ERROR: u32 = 3;
+const STATUS: u32 = 4;
+const TICKER: u32 = 5;
+
+/// Appends `message` as one length-prefixed `ServerFrame`.
+pub fn write_message(message: &ServerMessage, out: &mut Vec<u8>) -> Result<(), ServerError> {
+    match message {
+        ServerMessage::Result(result) => delimited(out, |frame| {
+            nested(frame, CANDLE, |candle| write_result(result, candle))
+        }),
+        ServerMessage::Status(status) => return write_status(status, out),
+        ServerMessage::Ticker(ticker) => delimited(out, |frame| {
+            nested(frame, TICKER, |update| write_ticker(ticker, update))
+        }),
+    }
+    Ok(())
+}
+
+/// Appends `result` as a bare `CandleUpdate`, the message of a gRPC
+/// `Subscribe` stream.
+pub fn write_candle_update(result: &ResultMessage, out: &mut Vec<u8>) {
+    write_result(result, out);
+}
+
+/// A field of a message read by `read_fields`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// The number and value of every field of one message, in the order
+/// written. Fails on a truncated message or a group, which proto3 has none
+/// of.
+pub fn read_fields(mut bytes: &[u8]) -> Result<Vec<(u32, FieldValue<'_>)>, ServerError> {
+    let truncated = || ServerError::InvalidMessage("truncated protobuf message".into());
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let key = read_varint(&mut bytes).ok_or_else(truncated)?;
+        let field = u32::try_from(key >> 3).map_err(|_| truncated())?;
+        let value = match key as u32 & 7 {
+            VARINT => FieldValue::Varint(read_varint(&mut bytes).ok_or_else(truncated)?),
+            FIXED64 => FieldValue::Fixed64(u64::from_le_bytes(
+                take(&mut bytes, 8)
+                    .ok_or_else(truncated)?
+                    .try_into()
+                    .unwrap(),
+            )),
+            LEN => {
+                let len = read_varint(&mut bytes).ok_or_else(truncated)?;
+                let len = usize::try_from(len).map_err(|_| truncated())?;
+                FieldValue::Bytes(take(&mut bytes, len).ok_or_else(truncated)?)
+            }
+            FIXED32 => FieldValue::Fixed32(u32::from_le_bytes(
+                take(&mut bytes, 4)
+                    .ok_or_else(truncated)?
+                    .try_into()
+                    .unwrap(),
+            )),
+            wire_type => {
+                return Err(ServerError::InvalidMessage(format!(
+                    "protobuf wire type {} of field {}",
+                    wire_type, field
+                )))
+            }
+        };
+        fields.push((field, value));
+    }
+    Ok(fields)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let value = bytes.get(..len)?;
+    *bytes = &bytes[len..];
+    Some(value)
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Appends `status` as one length-prefixed `ServerFrame`.
+pub fn write_status(status: &StatusMessage, out: &mut Vec<u8>) -> Result<(), ServerError> {
+    let details = status_details(status)?;
+    delimited(out, |frame| match status.event.as_str() {
+        "subscribed" => nested(frame, ACK, |ack| {
+            string(ack, 1, &status.stream);
+            optional(ack, 2, status.id.map(u64::from), put_uint);
+            optional(ack, 3, status.alias.as_deref(), put_string);
+            string(ack, 4, &status.message);
+        }),
+        "error" => nested(frame, ERROR, |error| {
+            string(error, 1, &status.stream);
+            optional(error, 2, status.id.map(u64::from), put_uint);
+            optional(error, 3, status.alias.as_deref(), put_string);
+            string(error, 4, &status.message);
+            uint(error, 5, status.code.map_or(0, |code| code.value().into()));
+        }),
+        _ => nested(frame, STATUS, |other| {
+            string(other, 1, &status.stream);
+            string(other, 2, &status.event);
+            string(other, 3, &status.message);
+            optional(other, 4, status.id.map(u64::from), put_uint);
+            optional(other, 5, status.alias.as_deref(), put_string);
+            optional(
+                other,
+                6,
+                status.code.map(|code| code.value().into()),
+                put_uint,
+            );
+            optional(other, 7, status.attempt.map(u64::from), put_uint);
+            optional(other, 8, status.bars, put_uint);
+            optional(other, 9, status.from, put_uint);
+            optional(other, 10, status.to, put_uint);
+            optional(other, 11, status.reason.as_deref(), put_string);
+            optional(other, 12, status.deadline, put_uint);
+            for subscription in status.subscriptions.iter().flatten() {
+                put_string(other, 13, subscription);
+            }
+            for (name, expr) in status.definitions.iter().flatten() {
+                nested(other, 14, |entry| {
+                    put_string(entry, 1, name);
+                    put_string(entry, 2, expr);
+                });
+            }
+            for (stream, base) in status.bases.iter().flatten() {
+                nested(other, 15, |entry| {
+                    put_string(entry, 1, stream);
+                    put_double(entry, 2, *base);
+                });
+            }
+            optional(other, 16, details.as_deref(), put_string);
+        }),
+    });
+    Ok(())
+}
+
+// The `StreamStatus` fields with no protobuf counterpart, as a JSON object
+fn status_details(status: &StatusMessage) -> Result<Option<String>, ServerError> {
+    let mut details = Map::new();
+    if let Some(evaluators) = &status.evaluators {
+        details.insert("evaluators".into(), serde_json::to_value(evaluators)?);
+    }
+    if let Some(schema) = &status.schema {
+        details.insert("schema".into(), schema.clone());
+    }
+    if let Some(info) = &status.info {
+        details.insert("info".into(), serde_json::to_value(info)?);
+    }
+    if let Some(candle) = &status.candle {
+        details.insert("candle".into(), serde_json::to_value(candle)?);
+    }
+    if let Some(alerts) = &status.alerts {
+        details.insert("alerts".into(), serde_json::to_value(alerts)?);
+    }
+    Ok((!details.is_empty()).then(|| Value::Object(details).to_string()))
+}
+
+fn write_result(result: &ResultMessage, out: &mut Vec<u8>) {
+    let data = &result.data;
+    string(out, 1, &result.stream);
+    uint(out, 2, data.t);
+    optional(out, 3, data.rounded(data.o), put_double);
+    optional(out, 4, data.rounded(data.c), put_double);
+    optional(out, 5, data.rounded(data.h), put_double);
+    optional(out, 6, data.rounded(data.l), put_double);
+    optional(out, 7, data.v, put_double);
+    optional(out, 8, data.q, put_double);
+    optional(out, 9, data.n, put_uint);
+    optional(out, 10, data.taker_v, put_double);
+    optional(out, 11, data.taker_q, put_double);
+    for (leg, flow) in &data.flow {
+        nested(out, 12, |entry| {
+            put_string(entry, 1, leg);
+            nested(entry, 2, |flow_out| {
+                double(flow_out, 1, flow.v);
+                double(flow_out, 2, flow.taker_v);
+            });
+        });
+    }
+    boolean(out, 13, result.closed);
+    boolean(out, 14, result.out_of_order);
+    boolean(out, 15, result.partial);
+    for leg in &result.missing {
+        put_string(out, 16, leg);
+    }
+    optional(out, 17, result.alias.as_deref(), put_string);
+    boolean(out, 18, result.snapshot);
+    optional(out, 19, result.latency_ms, put_uint);
+    optional(out, 20, result.id.map(u64::from), put_uint);
+    optional(out, 21, data.corr, put_double);
+    for &(name, value) in &data.indicators {
+        if let Some(value) = value {
+            nested(out, 22, |entry| {
+                put_string(entry, 1, name);
+                put_double(entry, 2, value);
+            });
+        }
+    }
+}
+
+fn write_ticker(ticker: &TickerMessage, out: &mut Vec<u8>) {
+    let stats = &ticker.ticker;
+    string(out, 1, &ticker.stream);
+    optional(out, 2, ticker.alias.as_deref(), put_string);
+    uint(out, 3, stats.t);
+    double(out, 4, stats.last_price);
+    double(out, 5, stats.price_change);
+    double(out, 6, stats.price_change_pct);
+    double(out, 7, stats.weighted_avg_price);
+    double(out, 8, stats.open);
+    double(out, 9, stats.high);
+    double(out, 10, stats.low);
+    double(out, 11, stats.volume);
+    double(out, 12, stats.quote_volume);
+    uint(out, 13, stats.trades);
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn key(out: &mut Vec<u8>, field: u32, wire_type: u32) {
+    varint(out, u64::from(field << 3 | wire_type));
+}
+
+// Written whatever the value, for `optional` fields and map entries
+fn put_uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    key(out, field, VARINT);
+    varint(out, value);
+}
+
+fn put_double(out: &mut Vec<u8>, field: u32, value: f64) {
+    key(out, field, FIXED64);
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_string(out: &mut Vec<u8>, field: u32, value: &str) {
+    key(out, field, LEN);
+    varint(out, value.len() as u64);
+    out.extend_from_slice(value.as_bytes());
+}
+
+fn optional<T>(out: &mut Vec<u8>, field: u32, value: Option<T>, put: fn(&mut Vec<u8>, u32, T)) {
+    if let Some(value) = value {
+        put(out, field, value);
+    }
+}
+
+// Other scalars of proto3 are left out at their default
+fn uint(out: &mut Vec<u8>, field: u32, value: u64) {
+    if value != 0 {
+        put_uint(out, field, value);
+    }
+}
+
+fn boolean(out: &mut Vec<u8>, field: u32, value: bool) {
+    uint(out, field, u64::from(value));
+}
+
+fn double(out: &mut Vec<u8>, field: u32, value: f64) {
+    if value != 0.0 {
+        put_double(out, field, value);
+    }
+}
+
+fn string(out: &mut Vec<u8>, field: u32, value: &str) {
+    if !value.is_empty() {
+        put_string(out, field, value);
+    }
+}
+
+// A message field, map entry or `ServerFrame` member
+fn nested(out: &mut Vec<u8>, field: u32, write: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    write(&mut body);
+    key(out, field, LEN);
+    varint(out, body.len() as u64);
+    out.extend_from_slice(&body);
+}
+
+fn delimited(out: &mut Vec<u8>, write: impl FnOnce(&mut Vec<u8>)) {
+    let mut body = Vec::new();
+    write(&mut body);
+    varint(out, body.len() as u64);
+    out.extend_from_slice(&body);
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+
+    use super::{
+        read_fields, write_candle_update, write_message, FieldValue, ACK, CANDLE, ERROR, STATUS,
+        TICKER,
+    };
+    use crate::candle::Candle;
+    use crate::error::ErrorCode;
+    use crate::protocol::{
+        EvaluatorStats, OutputFormat, ResultData, ResultMessage, ServerMessage, StatusMessage,
+        TakerFlow, TickerMessage, TickerStats,
+    };
+
+    #[derive(Debug, Clone)]
+    enum Field {
+        Varint(u64),
+        Double(f64),
+        Bytes(Vec<u8>),
+    }
+
+    // Fields of one message by number, as any protobuf reader sees them
+    type Fields = HashMap<u32, Vec<Field>>;
+
+    fn read_varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = bytes.split_first().unwrap();
+            *bytes = rest;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn read_bytes(bytes: &mut &[u8]) -> Vec<u8> {
+        let len = read_varint(bytes) as usize;
+        let (value, rest) = bytes.split_at(len);
+        *bytes = rest;
+        value.to_vec()
+    }
+
+    fn decode(mut bytes: &[u8]) -> Fields {
+        let mut fields = Fields::new();
+        while !bytes.is_empty() {
+            let key = read_varint(&mut bytes);
+            let field = match key & 7 {
+                0 => Field::Varint(read_varint(&mut bytes)),
+                1 => {
+                    let (value, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    Field::Double(f64::from_le_bytes(value.try_into().unwrap()))
+                }
+                2 => Field::Bytes(read_bytes(&mut bytes)),
+                other => panic!("unexpected wire type {}", other),
+            };
+            fields.entry((key >> 3) as u32).or_default().push(field);
+        }
+        fields
+    }
+
+    // The member and its fields of each `ServerFrame` in `bytes`
+    fn frames(mut bytes: &[u8]) -> Vec<(u32, Fields)> {
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            let frame = decode(&read_bytes(&mut bytes));
+            assert_eq!(frame.len(), 1, "one member per frame");
+            let (&member, values) = frame.iter().next().unwrap();
+            let [Field::Bytes(body)] = &values[..] else {
+                panic!("member {} is not a message", member);
+            };
+            frames.push((member, decode(body)));
+        }
+        frames
+    }
+
+    fn frame(message: &ServerMessage) -> (u32, Fields) {
+        let mut bytes = Vec::new();
+        write_message(message, &mut bytes).unwrap();
+        let mut frames = frames(&bytes);
+        assert_eq!(frames.len(), 1);
+        frames.pop().unwrap()
+    }
+
+    // Each field as JSON, `null` when absent
+    fn uint(fields: &Fields, field: u32) -> Value {
+        match fields.get(&field).map(|values| &values[..]) {
+            Some([Field::Varint(value)]) => json!(value),
+            None => Value::Null,
+            other => panic!("field {} is {:?}", field, other),
+        }
+    }
+
+    fn double(fields: &Fields, field: u32) -> Value {
+        match fields.get(&field).map(|values| &values[..]) {
+            Some([Field::Double(value)]) => json!(value),
+            None => Value::Null,
+            other => panic!("field {} is {:?}", field, other),
+        }
+    }
+
+    fn texts(fields: &Fields, field: u32) -> Vec<String> {
+        fields
+            .get(&field)
+            .into_iter()
+            .flatten()
+            .map(|value| match value {
+                Field::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+                other => panic!("field {} is {:?}", field, other),
+            })
+            .collect()
+    }
+
+    fn text(fields: &Fields, field: u32) -> Value {
+        match &texts(fields, field)[..] {
+            [value] => json!(value),
+            [] => Value::Null,
+            other => panic!("field {} is {:?}", field, other),
+        }
+    }
+
+    fn entries(fields: &Fields, field: u32) -> Vec<Fields> {
+        fields
+            .get(&field)
+            .into_iter()
+            .flatten()
+            .map(|value| match value {
+                Field::Bytes(bytes) => decode(bytes),
+                other => panic!("field {} is {:?}", field, other),
+            })
+            .collect()
+    }
+
+    fn result(format: OutputFormat) -> ResultMessage {
+        let candle = Candle::new(60_000, 42_000.123, 42_100.456, 42_200.0, 41_900.5)
+            .with_volume(12.5, 525_000.0)
+            .with_taker_volume(5.0, 210_000.0)
+            .with_trades(1_234)
+            .with_closed(true);
+        let mut data = ResultData::from(candle);
+        data.format = format;
+        data.flow = BTreeMap::from([
+            ("btcusdt@kline_1m".to_string(), TakerFlow::from(&candle)),
+            (
+                "ethusdt@kline_1m".to_string(),
+                TakerFlow {
+                    v: 0.0,
+                    taker_v: 0.0,
+                },
+            ),
+        ]);
+        data.corr = Some(-0.25);
+        data.indicators = vec![("sma", Some(42_000.0)), ("rsi", None)];
+        ResultMessage {
+            id: Some(3),
+            stream: "btcusdt/ethusdt@1m".into(),
+            alias: Some("ratio".into()),
+            data,
+            closed: true,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: true,
+            latency_ms: Some(0),
+        }
+    }
+
+    #[test]
+    fn test_candle_update_matches_json() {
+        let message = result(OutputFormat {
+            precision: Some(2),
+            ..OutputFormat::default()
+        });
+        let json = serde_json::to_value(&message).unwrap();
+        let (member, candle) = frame(&ServerMessage::Result(message));
+        assert_eq!(member, CANDLE);
+
+        assert_eq!(text(&candle, 1), json["stream"]);
+        assert_eq!(uint(&candle, 2), json["data"]["t"]);
+        let prices = [(3, "o"), (4, "c"), (5, "h"), (6, "l"), (7, "v"), (8, "q")];
+        for (field, name) in prices.into_iter().chain([(10, "V"), (11, "Q")]) {
+            assert_eq!(double(&candle, field), json["data"][name], "{}", name);
+        }
+        assert_eq!(uint(&candle, 9), json["data"]["n"]);
+        assert_eq!(uint(&candle, 13), json!(1));
+        assert_eq!(text(&candle, 17), json["alias"]);
+        assert_eq!(uint(&candle, 18), json!(1));
+        // Set, so written though it's zero
+        assert_eq!(uint(&candle, 19), json!(0));
+        assert_eq!(uint(&candle, 20), json["id"]);
+        assert_eq!(double(&candle, 21), json["data"]["corr"]);
+
+        // Flows give the JSON's buy ratios; a leg without volume has none
+        let flows = entries(&candle, 12);
+        assert_eq!(flows.len(), 2);
+        for entry in &flows {
+            let leg = text(entry, 1);
+            let flow = &entries(entry, 2)[0];
+            let ratio = match (double(flow, 1), double(flow, 2)) {
+                (Value::Null, _) => Value::Null,
+                (v, taker_v) => json!(taker_v.as_f64().unwrap() / v.as_f64().unwrap()),
+            };
+            assert_eq!(ratio, json["data"]["buy_ratio"][leg.as_str().unwrap()]);
+        }
+
+        // Only indicators with a value
+        let indicators = entries(&candle, 22);
+        assert_eq!(indicators.len(), 1);
+        assert_eq!(text(&indicators[0], 1), "sma");
+        assert_eq!(double(&indicators[0], 2), json["data"]["sma"]);
+        assert_eq!(json["data"]["rsi"], Value::Null);
+    }
+
+    #[test]
+    fn test_read_fields_of_candle_update() {
+        let mut bytes = Vec::new();
+        write_candle_update(&result(OutputFormat::default()), &mut bytes);
+        let fields = read_fields(&bytes).unwrap();
+        assert_eq!(
+            fields[..2],
+            [
+                (1, FieldValue::Bytes(b"btcusdt/ethusdt@1m")),
+                (2, FieldValue::Varint(60_000))
+            ]
+        );
+        assert_eq!(fields[2], (3, FieldValue::Fixed64(42_000.123f64.to_bits())));
+        assert_eq!(
+            read_fields(&[0x0d, 1, 0, 0, 0]).unwrap()[0].1,
+            FieldValue::Fixed32(1)
+        );
+
+        // Cut inside a field, or a group's wire type
+        assert!(read_fields(&bytes[..bytes.len() - 1]).is_err());
+        assert!(read_fields(&[0x0a, 5, b'a']).is_err());
+        assert!(read_fields(&[0x0b]).is_err());
+    }
+
+    #[test]
+    fn test_missing_legs_leave_prices_unset() {
+        let message = ResultMessage {
+            data: ResultData::missing(0),
+            partial: true,
+            missing: vec!["ethusdt@kline_1m".into()],
+            ..result(OutputFormat::default())
+        };
+        let (_, candle) = frame(&ServerMessage::Result(message));
+        for field in [2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 21] {
+            assert!(!candle.contains_key(&field), "field {} is set", field);
+        }
+        assert_eq!(uint(&candle, 15), json!(1));
+        assert_eq!(texts(&candle, 16), ["ethusdt@kline_1m"]);
+    }
+
+    #[test]
+    fn test_statuses_by_event() {
+        let status = |event: &str| StatusMessage {
+            id: Some(7),
+            stream: "btcusdt@1m".into(),
+            event: event.into(),
+            message: format!("{} message", event),
+            ..StatusMessage::default()
+        };
+
+        let (member, ack) = frame(&ServerMessage::Status(status("subscribed")));
+        assert_eq!(member, ACK);
+        assert_eq!(
+            (text(&ack, 1), uint(&ack, 2), text(&ack, 4)),
+            (json!("btcusdt@1m"), json!(7), json!("subscribed message"))
+        );
+
+        let error = StatusMessage {
+            code: Some(ErrorCode::InvalidCharacter),
+            ..status("error")
+        };
+        let (member, fields) = frame(&ServerMessage::Status(error));
+        assert_eq!(member, ERROR);
+        assert_eq!(uint(&fields, 5), json!(ErrorCode::InvalidCharacter.value()));
+
+        let gap = StatusMessage {
+            from: Some(0),
+            to: Some(180_000),
+            ..status("gap_detected")
+        };
+        let json = serde_json::to_value(&gap).unwrap();
+        let (member, fields) = frame(&ServerMessage::Status(gap));
+        assert_eq!(member, STATUS);
+        assert_eq!(text(&fields, 2), json["event"]);
+        assert_eq!(
+            (uint(&fields, 9), uint(&fields, 10)),
+            (json!(0), json!(180_000))
+        );
+
+        // Payloads without fields of their own come as JSON
+        let list = StatusMessage {
+            subscriptions: Some(vec!["btcusdt@1m".into(), String::new()]),
+            definitions: Some(BTreeMap::from([("x".into(), "btcusdt@1m".into())])),
+            evaluators: Some(BTreeMap::from([(
+                "btcusdt@1m".into(),
+                EvaluatorStats {
+                    subscribers: 2,
+                    ..EvaluatorStats::default()
+                },
+            )])),
+            ..status("list")
+        };
+        let json = serde_json::to_value(&list).unwrap();
+        let (_, fields) = frame(&ServerMessage::Status(list));
+        assert_eq!(texts(&fields, 13), ["btcusdt@1m", ""]);
+        let definition = &entries(&fields, 14)[0];
+        assert_eq!(
+            (text(definition, 1), text(definition, 2)),
+            (json!("x"), json!("btcusdt@1m"))
+        );
+        let details: Value = serde_json::from_str(text(&fields, 16).as_str().unwrap()).unwrap();
+        assert_eq!(details, json!({"evaluators": json["evaluators"]}));
+    }
+
+    #[test]
+    fn test_ticker_update() {
+        let message = TickerMessage {
+            stream: "btcusdt@ticker24h".into(),
+            alias: None,
+            ticker: TickerStats {
+                t: 5,
+                last_price: 42_000.0,
+                price_change: -10.5,
+                trades: 9,
+                ..TickerStats::default()
+            },
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        let (member, ticker) = frame(&ServerMessage::Ticker(message));
+        assert_eq!(member, TICKER);
+        assert_eq!(uint(&ticker, 3), json["ticker"]["t"]);
+        assert_eq!(double(&ticker, 4), json["ticker"]["last_price"]);
+        assert_eq!(double(&ticker, 5), json["ticker"]["price_change"]);
+        assert_eq!(uint(&ticker, 13), json["ticker"]["trades"]);
+        // Zero, so left out
+        assert_eq!(double(&ticker, 8), Value::Null);
+    }
+}
@@ -0,0 +1,1418 @@
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::alert::{AlertInfo, AlertRule, AlertScope};
+use crate::align::Alignment;
+use crate::candle::{Candle, CompositeSemantics};
+use crate::encoding::OutputEncoder;
+use crate::error::{ErrorCode, ServerError};
+use crate::indicators::{self, IndicatorSpec};
+use crate::utils::{format_rfc3339, parse_rfc3339};
+
+// Upstream payloads mirror Binance's field names, including the ones we don't
+// use yet. Text fields borrow from the frame, so reading a kline only
+// allocates for the rare escaped string.
+#[derive(Debug, Deserialize)]
+pub struct BinanceMessage<'a> {
+    #[serde(borrow)]
+    pub stream: Cow<'a, str>,
+    #[serde(borrow)]
+    pub data: BinanceData<'a>,
+}
+
+#[allow(dead_code, non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BinanceData<'a> {
+    #[serde(borrow)]
+    pub e: Cow<'a, str>,
+    pub E: u64,
+    // Continuous contract klines carry no symbol, only the pair and its
+    // contract type, e.g. `BTCUSDT` and `PERPETUAL`
+    #[serde(borrow, default)]
+    pub s: Cow<'a, str>,
+    #[serde(borrow, default)]
+    pub ps: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    pub ct: Option<Cow<'a, str>>,
+    #[serde(borrow)]
+    pub k: BinanceKlineData<'a>,
+}
+
+#[allow(dead_code, non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BinanceKlineData<'a> {
+    pub t: u64,
+    pub T: u64,
+    #[serde(borrow, default)]
+    pub s: Cow<'a, str>,
+    #[serde(borrow)]
+    pub i: Cow<'a, str>,
+    pub f: u64,
+    pub L: u64,
+    #[serde(borrow)]
+    pub o: Cow<'a, str>, // Open price
+    #[serde(borrow)]
+    pub c: Cow<'a, str>, // Close price
+    #[serde(borrow)]
+    pub h: Cow<'a, str>, // High price
+    #[serde(borrow)]
+    pub l: Cow<'a, str>, // Low price
+    #[serde(borrow)]
+    pub v: Cow<'a, str>,
+    pub n: u64,
+    pub x: bool,
+    #[serde(borrow)]
+    pub q: Cow<'a, str>,
+    #[serde(borrow)]
+    pub V: Cow<'a, str>,
+    #[serde(borrow)]
+    pub Q: Cow<'a, str>,
+    #[serde(borrow)]
+    pub B: Cow<'a, str>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BinanceMiniTickerMessage<'a> {
+    #[serde(borrow)]
+    pub stream: Cow<'a, str>,
+    #[serde(borrow)]
+    pub data: BinanceMiniTicker<'a>,
+}
+
+// A `@miniTicker` update, about once a second. Prices and volumes are over
+// the rolling 24 hours before `E`, not a bar.
+#[allow(dead_code, non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BinanceMiniTicker<'a> {
+    #[serde(borrow)]
+    pub e: Cow<'a, str>,
+    pub E: u64,
+    #[serde(borrow)]
+    pub s: Cow<'a, str>,
+    #[serde(borrow)]
+    pub c: Cow<'a, str>, // Close price
+    #[serde(borrow)]
+    pub o: Cow<'a, str>, // Open price
+    #[serde(borrow)]
+    pub h: Cow<'a, str>, // High price
+    #[serde(borrow)]
+    pub l: Cow<'a, str>, // Low price
+    #[serde(borrow)]
+    pub v: Cow<'a, str>,
+    #[serde(borrow)]
+    pub q: Cow<'a, str>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BinanceBookTickerMessage<'a> {
+    #[serde(borrow)]
+    pub stream: Cow<'a, str>,
+    #[serde(borrow)]
+    pub data: BinanceBookTicker<'a>,
+}
+
+// A `@bookTicker` update: the best bid and ask. Spot's carry no event time.
+#[allow(dead_code, non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BinanceBookTicker<'a> {
+    pub u: u64,
+    #[serde(default)]
+    pub E: u64,
+    #[serde(borrow)]
+    pub s: Cow<'a, str>,
+    #[serde(borrow)]
+    pub b: Cow<'a, str>, // Best bid price
+    #[serde(borrow)]
+    pub B: Cow<'a, str>, // Best bid quantity
+    #[serde(borrow)]
+    pub a: Cow<'a, str>, // Best ask price
+    #[serde(borrow)]
+    pub A: Cow<'a, str>, // Best ask quantity
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BinanceTickerMessage<'a> {
+    #[serde(borrow)]
+    pub stream: Cow<'a, str>,
+    #[serde(borrow)]
+    pub data: BinanceTicker<'a>,
+}
+
+// A `@ticker` update: statistics of the rolling 24 hours before `E`
+#[allow(dead_code, non_snake_case)]
+#[derive(Debug, Deserialize)]
+pub struct BinanceTicker<'a> {
+    #[serde(borrow)]
+    pub e: Cow<'a, str>,
+    pub E: u64,
+    #[serde(borrow)]
+    pub s: Cow<'a, str>,
+    #[serde(borrow)]
+    pub p: Cow<'a, str>, // Price change
+    #[serde(borrow)]
+    pub P: Cow<'a, str>, // Price change percent
+    #[serde(borrow)]
+    pub w: Cow<'a, str>, // Weighted average price
+    #[serde(borrow)]
+    pub c: Cow<'a, str>, // Last price
+    #[serde(borrow)]
+    pub Q: Cow<'a, str>, // Last quantity
+    #[serde(borrow)]
+    pub o: Cow<'a, str>, // Open price
+    #[serde(borrow)]
+    pub h: Cow<'a, str>, // High price
+    #[serde(borrow)]
+    pub l: Cow<'a, str>, // Low price
+    #[serde(borrow)]
+    pub v: Cow<'a, str>,
+    #[serde(borrow)]
+    pub q: Cow<'a, str>,
+    pub O: u64,
+    pub C: u64,
+    pub F: i64,
+    pub L: i64,
+    pub n: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResultMessage {
+    // Request id of the `GET_LAST` this answers; live results have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub stream: String,
+    // Name the client gave the subscription in its SUBSCRIBE request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub data: ResultData,
+    // Every leg's bar is final, so no further update for it will follow
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub closed: bool,
+    // Built from a bar that arrived behind the newest one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub out_of_order: bool,
+    // Some legs never reported this bar; they are listed in `missing`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+    // The newest result of a running evaluator, sent to a client as it
+    // joins ahead of live ones
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub snapshot: bool,
+    // Milliseconds from the newest leg's Binance event to sending this, for
+    // clients that asked for `latency`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+}
+
+/// How timestamps in results are written, chosen per subscription.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimeFormat {
+    #[default]
+    EpochMillis,
+    // RFC 3339 in UTC with millisecond precision
+    Iso8601,
+}
+
+/// Per-subscription presentation of results; never affects evaluation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub time_format: TimeFormat,
+    // Decimal places prices are rounded to, `None` to keep full precision
+    pub precision: Option<u32>,
+    // Write prices and volumes as JSON strings, like Binance's kline payloads
+    pub string_prices: bool,
+    // Frames carrying the messages
+    pub encoder: OutputEncoder,
+}
+
+// Largest precision a request may ask for; f64 holds ~16 significant digits
+pub const MAX_PRECISION: u32 = 16;
+
+// Rounds at `precision` decimal places by way of the decimal text, so the
+// nearest f64 is picked and 0.1 + 0.2 serializes as 0.3
+fn round_price(price: f64, precision: Option<u32>) -> f64 {
+    match precision {
+        Some(precision) => format!("{:.*}", precision as usize, price)
+            .parse()
+            .unwrap_or(price),
+        None => price,
+    }
+}
+
+// Prices are `None` when a leg they depend on is missing, or when they
+// were divided by zero and `DivisionPolicy::NullField` kept the bar
+#[derive(Debug, Clone)]
+pub struct ResultData {
+    pub t: u64,               // kline start time
+    pub o: Option<f64>,       // open price: 26884.70 + 1806.09
+    pub c: Option<f64>,       // close price: 26886.20 + 1806.14
+    pub h: Option<f64>,       // high price: 26892.50 + 1806.33
+    pub l: Option<f64>,       // low price: 26877.80 + 1805.67
+    pub v: Option<f64>,       // base asset volume
+    pub q: Option<f64>,       // quote asset volume
+    pub n: Option<u64>,       // number of trades
+    pub taker_v: Option<f64>, // taker buy base asset volume
+    pub taker_q: Option<f64>, // taker buy quote asset volume
+    // Per-leg volumes behind `buy_ratio`, keyed by kline stream
+    pub flow: BTreeMap<String, TakerFlow>,
+    // Correlation of the two legs' returns, for subscriptions asking for it
+    // once their window has filled
+    pub corr: Option<f64>,
+    // Fields of the client's indicators, null until their window fills
+    pub indicators: Vec<(&'static str, Option<f64>)>,
+    // Newest Binance event time among the legs, 0 when unknown
+    pub event_time: u64,
+    pub format: OutputFormat,
+}
+
+/// One leg's base volume and the part of it bought by takers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TakerFlow {
+    pub v: f64,
+    pub taker_v: f64,
+}
+
+impl TakerFlow {
+    /// Share of the volume bought by takers, `None` for a bar with no volume.
+    pub fn buy_ratio(&self) -> Option<f64> {
+        (self.v > 0.0).then(|| self.taker_v / self.v)
+    }
+}
+
+impl From<&Candle> for TakerFlow {
+    fn from(candle: &Candle) -> Self {
+        Self {
+            v: candle.v,
+            taker_v: candle.taker_v,
+        }
+    }
+}
+
+impl ResultData {
+    pub fn missing(t: u64) -> Self {
+        Self {
+            t,
+            o: None,
+            c: None,
+            h: None,
+            l: None,
+            v: None,
+            q: None,
+            n: None,
+            taker_v: None,
+            taker_q: None,
+            flow: BTreeMap::new(),
+            corr: None,
+            indicators: Vec::new(),
+            event_time: 0,
+            format: OutputFormat::default(),
+        }
+    }
+}
+
+// Undefined prices, NaN in a `Candle`, are null
+fn defined(price: f64) -> Option<f64> {
+    (!price.is_nan()).then_some(price)
+}
+
+impl From<Candle> for ResultData {
+    fn from(candle: Candle) -> Self {
+        Self {
+            t: candle.t,
+            o: defined(candle.o),
+            c: defined(candle.c),
+            h: defined(candle.h),
+            l: defined(candle.l),
+            v: Some(candle.v),
+            q: Some(candle.q),
+            n: Some(candle.n),
+            taker_v: Some(candle.taker_v),
+            taker_q: Some(candle.taker_q),
+            flow: BTreeMap::new(),
+            corr: None,
+            indicators: Vec::new(),
+            event_time: candle.event_time,
+            format: OutputFormat::default(),
+        }
+    }
+}
+
+// A decimal field as written to clients, or as read from Binance-style input
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum Decimal {
+    Number(f64),
+    Text(String),
+}
+
+impl ResultData {
+    /// `price` rounded to the precision of the output format.
+    pub fn rounded(&self, price: Option<f64>) -> Option<f64> {
+        price.map(|price| round_price(price, self.format.precision))
+    }
+
+    fn price(&self, price: Option<f64>) -> Option<Decimal> {
+        let price = price?;
+        Some(match (self.format.string_prices, self.format.precision) {
+            (true, Some(precision)) => Decimal::Text(format!("{:.*}", precision as usize, price)),
+            (true, None) => Decimal::Text(price.to_string()),
+            (false, precision) => Decimal::Number(round_price(price, precision)),
+        })
+    }
+
+    /// `up`, `down` or `flat` from the open to the close as written, so a
+    /// move the precision rounds away reads `flat`.
+    pub fn direction(&self) -> Option<&'static str> {
+        let open = round_price(self.o?, self.format.precision);
+        let close = round_price(self.c?, self.format.precision);
+        Some(match close.partial_cmp(&open)? {
+            std::cmp::Ordering::Greater => "up",
+            std::cmp::Ordering::Less => "down",
+            std::cmp::Ordering::Equal => "flat",
+        })
+    }
+
+    fn volume(&self, volume: Option<f64>) -> Option<Decimal> {
+        let volume = volume?;
+        Some(if self.format.string_prices {
+            Decimal::Text(volume.to_string())
+        } else {
+            Decimal::Number(volume)
+        })
+    }
+}
+
+impl Serialize for ResultData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut data = serializer.serialize_struct("ResultData", 12)?;
+        match self.format.time_format {
+            TimeFormat::EpochMillis => data.serialize_field("t", &self.t)?,
+            TimeFormat::Iso8601 => data.serialize_field("t", &format_rfc3339(self.t))?,
+        }
+        data.serialize_field("o", &self.price(self.o))?;
+        data.serialize_field("c", &self.price(self.c))?;
+        data.serialize_field("h", &self.price(self.h))?;
+        data.serialize_field("l", &self.price(self.l))?;
+        data.serialize_field("v", &self.volume(self.v))?;
+        data.serialize_field("q", &self.volume(self.q))?;
+        data.serialize_field("n", &self.n)?;
+        data.serialize_field("V", &self.volume(self.taker_v))?;
+        data.serialize_field("Q", &self.volume(self.taker_q))?;
+        match self.direction() {
+            Some(direction) => data.serialize_field("dir", direction)?,
+            None => data.skip_field("dir")?,
+        }
+        // Ratios don't compose across operators, so each leg reports its own
+        if self.flow.is_empty() {
+            data.skip_field("buy_ratio")?;
+        } else {
+            let buy_ratio: BTreeMap<&String, Option<f64>> = self
+                .flow
+                .iter()
+                .map(|(leg, flow)| (leg, flow.buy_ratio()))
+                .collect();
+            data.serialize_field("buy_ratio", &buy_ratio)?;
+        }
+        match self.corr {
+            Some(corr) => data.serialize_field("corr", &corr)?,
+            None => data.skip_field("corr")?,
+        }
+        for &(name, value) in &self.indicators {
+            data.serialize_field(name, &value)?;
+        }
+        data.end()
+    }
+}
+
+// `ResultData` as written to clients, whatever its output format
+#[derive(Deserialize)]
+struct ResultDataWire {
+    t: Timestamp,
+    o: Option<Decimal>,
+    c: Option<Decimal>,
+    h: Option<Decimal>,
+    l: Option<Decimal>,
+    v: Option<Decimal>,
+    q: Option<Decimal>,
+    n: Option<u64>,
+    #[serde(rename = "V")]
+    taker_v: Option<Decimal>,
+    #[serde(rename = "Q")]
+    taker_q: Option<Decimal>,
+    #[serde(default)]
+    buy_ratio: BTreeMap<String, Option<f64>>,
+    #[serde(default)]
+    corr: Option<f64>,
+    // Indicator fields among the rest
+    #[serde(flatten)]
+    rest: BTreeMap<String, serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Timestamp {
+    EpochMillis(u64),
+    Iso8601(String),
+}
+
+impl Decimal {
+    fn value(&self) -> Option<f64> {
+        match self {
+            Decimal::Number(value) => Some(*value),
+            Decimal::Text(text) => text.parse().ok(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ResultData {
+    /// Reads results the way clients receive them. Only each leg's buy
+    /// ratio survives the wire, so `flow` holds it as a share of unit volume.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let wire = ResultDataWire::deserialize(deserializer)?;
+        let (t, time_format) = match wire.t {
+            Timestamp::EpochMillis(t) => (t, TimeFormat::EpochMillis),
+            Timestamp::Iso8601(text) => (
+                parse_rfc3339(&text)
+                    .ok_or_else(|| D::Error::custom(format!("invalid timestamp {}", text)))?,
+                TimeFormat::Iso8601,
+            ),
+        };
+        let decimals = [
+            &wire.o,
+            &wire.c,
+            &wire.h,
+            &wire.l,
+            &wire.v,
+            &wire.q,
+            &wire.taker_v,
+            &wire.taker_q,
+        ];
+        let string_prices = decimals
+            .iter()
+            .any(|decimal| matches!(decimal, Some(Decimal::Text(_))));
+        let value = |decimal: Option<Decimal>| match decimal {
+            Some(decimal) => decimal
+                .value()
+                .map(Some)
+                .ok_or_else(|| D::Error::custom("invalid decimal")),
+            None => Ok(None),
+        };
+        let flow = wire
+            .buy_ratio
+            .into_iter()
+            .map(|(leg, ratio)| {
+                let flow = match ratio {
+                    Some(ratio) => TakerFlow {
+                        v: 1.0,
+                        taker_v: ratio,
+                    },
+                    None => TakerFlow::default(),
+                };
+                (leg, flow)
+            })
+            .collect();
+
+        Ok(Self {
+            t,
+            o: value(wire.o)?,
+            c: value(wire.c)?,
+            h: value(wire.h)?,
+            l: value(wire.l)?,
+            v: value(wire.v)?,
+            q: value(wire.q)?,
+            n: wire.n,
+            taker_v: value(wire.taker_v)?,
+            taker_q: value(wire.taker_q)?,
+            flow,
+            corr: wire.corr,
+            indicators: indicators::FIELDS
+                .into_iter()
+                .filter_map(|name| match wire.rest.get(name)? {
+                    serde_json::Value::Null => Some((name, None)),
+                    value => Some((name, Some(value.as_f64()?))),
+                })
+                .collect(),
+            event_time: 0,
+            format: OutputFormat {
+                time_format,
+                string_prices,
+                ..OutputFormat::default()
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatusMessage {
+    // Request id of the subscription, for WebSocket clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u32>,
+    pub stream: String,
+    // Name the client gave the subscription in its SUBSCRIBE request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub event: String,
+    pub message: String,
+    // Set on `error` events and failures of the upstream
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    // Upstream retry a `reconnecting` event announces, counting from 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempt: Option<u32>,
+    // Cached bars a `backfilling` event starts from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bars: Option<u64>,
+    // Bars opening in `from..to` a `gap_detected` event reports missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<u64>,
+    // Why a `closed` event's subscription stopped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    // Canonical keys of the client's subscriptions, on a `list` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subscriptions: Option<Vec<String>>,
+    // Every name set with `DEFINE` and its expression, on a `list` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub definitions: Option<BTreeMap<String, String>>,
+    // Counters of every evaluator by canonical key, on a `list` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub evaluators: Option<BTreeMap<String, EvaluatorStats>>,
+    // Close each leg's prices are a percentage of, by upstream stream, on a
+    // `rebased` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bases: Option<BTreeMap<String, f64>>,
+    // Schemas of every frame, on a `schema` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schema: Option<serde_json::Value>,
+    // The server's build, limits and load, on an `info` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<Box<ServerInfo>>,
+    // Unix millis a `draining` event's server shuts down by, or a
+    // `renewed` subscription expires at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<u64>,
+    // Closed bar an `alert` event's rule fired on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candle: Option<Box<ResultData>>,
+    // The client's alert rules and the server's, on a `list` event
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<Vec<AlertInfo>>,
+}
+
+/// One evaluator shared by the clients of an expression.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EvaluatorStats {
+    pub subscribers: usize,
+    // Klines received per upstream stream
+    pub klines: BTreeMap<String, u64>,
+    // Results evaluated, each sent to every subscriber
+    pub results: u64,
+    // Bytes encoded for its subscribers, summed over them
+    pub bytes_sent: u64,
+    // Results subscribers skipped after falling behind
+    pub dropped: u64,
+    // Approximate bytes it holds, bars waiting on legs
+    pub buffered_bytes: usize,
+    // Unix millis of the newest kline and result, unset before the first
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_kline_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_result_at: Option<u64>,
+}
+
+/// What `GET_INFO` tells a client about the server. It carries no secrets:
+/// URLs are given without their credentials and query.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    // Crate version and the commit it was built from
+    pub version: String,
+    pub git_hash: String,
+    pub uptime_ms: u64,
+    pub protocol_versions: Vec<u32>,
+    pub limits: ServerLimits,
+    pub features: ServerFeatures,
+    // Set once draining, with the Unix millis it shuts down by
+    pub draining: bool,
+    pub drain_deadline: Option<u64>,
+    // WebSocket clients connected
+    pub clients: usize,
+    // Subscriptions of every client, and the evaluators they share
+    pub subscriptions: usize,
+    pub evaluators: usize,
+    // Streams subscribed or polled upstream
+    pub upstream_streams: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerLimits {
+    // Bytes of a request, whole and per frame
+    pub max_message_size: usize,
+    pub max_frame_size: usize,
+    // Messages buffered for a client before updates are dropped
+    pub client_queue_capacity: usize,
+    // Subscriptions of one connection at once; null when unlimited
+    pub max_subscriptions_per_client: Option<usize>,
+    // Bytes buffered over the server before the largest buffers shed; null
+    // when unlimited
+    pub memory_budget: Option<usize>,
+    // Nesting of operators and parentheses in an expression
+    pub max_expression_depth: usize,
+    pub max_precision: u32,
+    // Bars an indicator or correlation window may span
+    pub max_indicator_window: usize,
+    pub max_correlation_window: usize,
+    // Frames of at least this many bytes are compressed for clients asking;
+    // null when none are
+    pub compress_above: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerFeatures {
+    // Upstream stream URL, e.g. `wss://fstream.binance.com/stream`, or
+    // `synthetic` when streams are made up
+    pub upstream: String,
+    // Binance REST API, when set
+    pub rest: Option<String>,
+    // New legs start from their open bar fetched over REST
+    pub backfill: bool,
+    // `oi:` operands, polled over REST
+    pub open_interest: bool,
+    // Outputs getting every result, e.g. `redis`
+    pub sinks: Vec<String>,
+    pub formats: Vec<String>,
+    pub compression: Vec<String>,
+    // Seed of the synthetic streams, to run them again exactly
+    pub synthetic_seed: Option<u64>,
+}
+
+impl StatusMessage {
+    /// `error` event telling a client its request on `stream` failed.
+    pub fn error(stream: String, error: &ServerError) -> Self {
+        StatusMessage {
+            stream,
+            event: "error".into(),
+            message: error.to_string(),
+            code: Some(error.code()),
+            ..StatusMessage::default()
+        }
+    }
+}
+
+/// The 24 hour statistics of a symbol, relayed as they are for a
+/// `<symbol>@ticker24h` subscription.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TickerStats {
+    pub t: u64, // Binance event time
+    pub last_price: f64,
+    pub price_change: f64,
+    pub price_change_pct: f64,
+    pub weighted_avg_price: f64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub volume: f64,
+    pub quote_volume: f64,
+    pub trades: u64,
+}
+
+impl TryFrom<&BinanceTicker<'_>> for TickerStats {
+    type Error = ServerError;
+
+    /// Parses every decimal string, reporting all malformed fields at once.
+    fn try_from(ticker: &BinanceTicker<'_>) -> Result<Self, Self::Error> {
+        let mut malformed = Vec::new();
+        let mut field = |name: &str, text: &str| match text.parse::<f64>() {
+            Ok(value) => value,
+            Err(e) => {
+                malformed.push(format!("{} {:?} ({})", name, text, e));
+                0.0
+            }
+        };
+        let stats = TickerStats {
+            t: ticker.E,
+            last_price: field("c", &ticker.c),
+            price_change: field("p", &ticker.p),
+            price_change_pct: field("P", &ticker.P),
+            weighted_avg_price: field("w", &ticker.w),
+            open: field("o", &ticker.o),
+            high: field("h", &ticker.h),
+            low: field("l", &ticker.l),
+            volume: field("v", &ticker.v),
+            quote_volume: field("q", &ticker.q),
+            trades: ticker.n,
+        };
+        if malformed.is_empty() {
+            Ok(stats)
+        } else {
+            Err(ServerError::MalformedKline {
+                symbol: ticker.s.to_string(),
+                fields: malformed.join(", "),
+            })
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TickerMessage {
+    pub stream: String,
+    // Name the client gave the subscription in its SUBSCRIBE request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub ticker: TickerStats,
+}
+
+// Everything the server pushes to a client for one of its subscriptions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ServerMessage {
+    Result(ResultMessage),
+    Status(StatusMessage),
+    Ticker(TickerMessage),
+}
+
+impl ServerMessage {
+    pub fn set_stream(&mut self, stream: String) {
+        match self {
+            ServerMessage::Result(result) => result.stream = stream,
+            ServerMessage::Status(status) => status.stream = stream,
+            ServerMessage::Ticker(ticker) => ticker.stream = stream,
+        }
+    }
+
+    /// Tags a status with the request id of the subscription it's about.
+    pub fn set_id(&mut self, id: u32) {
+        if let ServerMessage::Status(status) = self {
+            status.id = Some(id);
+        }
+    }
+
+    pub fn set_alias(&mut self, alias: Option<String>) {
+        match self {
+            ServerMessage::Result(result) => result.alias = alias,
+            ServerMessage::Status(status) => status.alias = alias,
+            ServerMessage::Ticker(ticker) => ticker.alias = alias,
+        }
+    }
+
+    pub fn set_format(&mut self, format: OutputFormat) {
+        if let ServerMessage::Result(result) = self {
+            result.data.format = format;
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Request {
+    pub id: u32,
+    pub method: String,
+    // Protocol version of the frames the request gets, 1 when unset; see
+    // `Version`
+    #[serde(default)]
+    pub version: Option<u32>,
+    // Empty for methods not about a stream, e.g. `DEFINE`
+    #[serde(default)]
+    pub stream: String,
+    // Name and expression a `DEFINE` sets, e.g. `l1index` and
+    // `btcusdt*0.6+ethusdt*0.4`
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub expr: Option<String>,
+    // Client's own name for the subscription, echoed in its results and
+    // statuses and usable to UNSUBSCRIBE; unique within a connection
+    #[serde(default)]
+    pub alias: Option<String>,
+    // How legs at different intervals are lined up; part of what the
+    // subscription is, so an UNSUBSCRIBE by stream repeats it
+    #[serde(default)]
+    pub align: Alignment,
+    #[serde(default)]
+    pub time_format: TimeFormat,
+    // Overrides the server's default price precision
+    #[serde(default)]
+    pub precision: Option<u32>,
+    #[serde(default)]
+    pub string_prices: bool,
+    // Daily or weekly interval to aggregate results into, e.g. `1d`
+    #[serde(default)]
+    pub resample: Option<String>,
+    // `-05:00` or a zone name; resampled buckets roll at `session_start`
+    // (`HH:MM`) in this zone instead of at UTC midnight
+    #[serde(default)]
+    pub session_offset: Option<String>,
+    #[serde(default)]
+    pub session_start: Option<String>,
+    // Results produced within this many milliseconds are sent together as
+    // one JSON array; a closed bar is sent right away with those before it
+    #[serde(default)]
+    pub batch_ms: Option<u64>,
+    // `json` text frames, or `cbor` or `protobuf` binary frames; the
+    // connection's subprotocol, if one was negotiated, otherwise `json`
+    #[serde(default)]
+    pub format: Option<OutputEncoder>,
+    // Leaves out status events without an error code, for clients that
+    // only want results
+    #[serde(default)]
+    pub quiet: bool,
+    // Only results for closed bars are sent
+    #[serde(default)]
+    pub closed_only: bool,
+    // Which updates of the open bar are sent
+    #[serde(default)]
+    pub emit: Emit,
+    // How much of each result is sent
+    #[serde(default)]
+    pub mode: ResultMode,
+    // Results carry `latency_ms`
+    #[serde(default)]
+    pub latency: bool,
+    // Leg prices are taken as a percentage of the magnitude of the close of
+    // the leg's first closed bar; part of what the subscription is, like
+    // `align`
+    #[serde(default)]
+    pub rebase: bool,
+    // How the highs and lows of legs combine; also part of what the
+    // subscription is
+    #[serde(default)]
+    pub composite_semantics: CompositeSemantics,
+    // Legs' bars starting at most this many milliseconds apart are paired
+    // as one bar, starting with the earliest; under half a bar, and part of
+    // what the subscription is too
+    #[serde(default)]
+    pub align_tolerance_ms: u64,
+    // Seconds the subscription lives without a RENEW before it's closed;
+    // the server's default when unset, capped at its maximum. On a RENEW,
+    // replaces the subscription's ttl
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+    // Results of a two-leg expression carry the legs' rolling correlation
+    // as `corr`; also part of what the subscription is
+    #[serde(default)]
+    pub correlation: Option<CorrelationOptions>,
+    // File an `EXPORT` writes, relative to the server's export directory,
+    // the bars it takes, by open time, and the rows of its row groups
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default)]
+    pub from: Option<u64>,
+    #[serde(default)]
+    pub to: Option<u64>,
+    #[serde(default)]
+    pub row_group_rows: Option<usize>,
+    // Computed over the expression's closed bars, before any `resample`,
+    // and added to the results as fields named after them
+    #[serde(default)]
+    pub indicators: Vec<IndicatorSpec>,
+    // Closed bars are POSTed here too, signed when the server has a
+    // `webhook.secret`; failed deliveries never hold up the stream. An
+    // `ALERT` sends its alerts there
+    #[serde(default)]
+    pub webhook: Option<WebhookTarget>,
+    // What an `ALERT` watches the closed bars of `stream`, or of the
+    // subscription `alias` names, for; its id is the request's
+    #[serde(default)]
+    pub rule: Option<AlertRule>,
+    #[serde(default)]
+    pub scope: AlertScope,
+}
+
+/// Which updates of a bar still open a client is sent; closed bars always are.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Emit {
+    #[default]
+    All,
+    // Only an update that changes some field as written
+    OnChange,
+}
+
+/// How much of each result a client is sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultMode {
+    #[default]
+    Full,
+    // Updates of the open bar carry only what changed; see `Delta`
+    Delta,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrelationOptions {
+    // Closed bars whose returns are correlated, e.g. 50
+    pub window: usize,
+}
+
+/// Endpoint a subscription's closed bars are POSTed to as JSON results.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WebhookTarget {
+    // Plain `http` only
+    pub url: String,
+    // Sent with every POST, e.g. an `Authorization` the endpoint wants
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Request {
+    /// SUBSCRIBE request spelled as a URL query, e.g.
+    /// `stream=btcusdt%2Bethusdt%401m&closed_only=true`; `None` without a
+    /// `stream` parameter. Options take the names of the JSON fields.
+    pub fn from_query(query: &str) -> Result<Option<Request>, ServerError> {
+        let mut fields = serde_json::Map::new();
+        for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            // Query values are all text; the expression always stays so
+            let value = match value.as_ref() {
+                _ if name == "stream" => serde_json::Value::from(value.as_ref()),
+                "true" => serde_json::Value::Bool(true),
+                "false" => serde_json::Value::Bool(false),
+                text => match text.parse::<u64>() {
+                    Ok(number) => serde_json::Value::from(number),
+                    Err(_) => serde_json::Value::from(text),
+                },
+            };
+            fields.insert(name.into_owned(), value);
+        }
+        if !fields.contains_key("stream") {
+            return Ok(None);
+        }
+        fields.insert("id".into(), 0.into());
+        fields.insert("method".into(), "SUBSCRIBE".into());
+        Ok(Some(serde_json::from_value(fields.into())?))
+    }
+}
+
+#[derive(Serialize)]
+pub struct BinanceSubscription {
+    pub id: u32,
+    pub method: String,
+    pub params: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests_time_format {
+    use super::{format_rfc3339, OutputFormat, ResultData, TimeFormat};
+    use crate::utils::parse_rfc3339;
+    use serde_json::{json, Value};
+
+    fn result_data(t: u64, time_format: TimeFormat) -> Value {
+        let data = ResultData {
+            format: OutputFormat {
+                time_format,
+                ..OutputFormat::default()
+            },
+            ..ResultData::missing(t)
+        };
+        serde_json::to_value(data).unwrap()
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_rfc3339(1_704_067_200_000),
+            "2024-01-01T00:00:00.000Z"
+        );
+        assert_eq!(
+            format_rfc3339(1_709_210_096_789),
+            "2024-02-29T12:34:56.789Z"
+        );
+    }
+
+    #[test]
+    fn test_result_data_epoch_millis_by_default() {
+        let data = serde_json::to_value(ResultData::missing(1_709_210_096_789)).unwrap();
+        assert_eq!(
+            data,
+            json!({
+                "t": 1_709_210_096_789u64,
+                "o": null, "c": null, "h": null, "l": null,
+                "v": null, "q": null, "n": null, "V": null, "Q": null
+            })
+        );
+    }
+
+    #[test]
+    fn test_result_data_round_trips_both_formats() {
+        for t in [0, 1_704_067_200_000, 1_709_210_096_789, 4_102_444_799_999] {
+            let epoch = result_data(t, TimeFormat::EpochMillis);
+            assert_eq!(epoch["t"].as_u64(), Some(t));
+
+            let iso = result_data(t, TimeFormat::Iso8601);
+            assert_eq!(parse_rfc3339(iso["t"].as_str().unwrap()), Some(t));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_buy_ratio {
+    use super::{Candle, ResultData, TakerFlow};
+    use serde_json::json;
+
+    #[test]
+    fn test_buy_ratio_guards_zero_volume() {
+        let flow = TakerFlow {
+            v: 12.5,
+            taker_v: 5.0,
+        };
+        assert_eq!(flow.buy_ratio(), Some(0.4));
+        assert_eq!(TakerFlow::default().buy_ratio(), None);
+    }
+
+    #[test]
+    fn test_buy_ratio_serialized_per_leg() {
+        let btc = Candle::new(0, 1.0, 1.0, 1.0, 1.0).with_volume(10.0, 420_000.0);
+        let btc = btc.with_taker_volume(6.0, 252_000.0);
+        let eth = Candle::new(0, 1.0, 1.0, 1.0, 1.0);
+        let mut data = ResultData::from(btc);
+        data.flow
+            .insert("btcusdt@kline_1m".into(), TakerFlow::from(&btc));
+        data.flow
+            .insert("ethusdt@kline_1m".into(), TakerFlow::from(&eth));
+
+        let data = serde_json::to_value(data).unwrap();
+        assert_eq!(data["V"], 6.0);
+        assert_eq!(data["Q"], 252_000.0);
+        assert_eq!(
+            data["buy_ratio"],
+            json!({"btcusdt@kline_1m": 0.6, "ethusdt@kline_1m": null})
+        );
+    }
+
+    #[test]
+    fn test_buy_ratio_omitted_without_legs() {
+        let data = serde_json::to_value(ResultData::missing(0)).unwrap();
+        assert!(data.get("buy_ratio").is_none());
+    }
+}
+
+#[cfg(test)]
+mod tests_precision {
+    use super::{Candle, OutputFormat, ResultData};
+
+    fn serialized(price: f64, precision: Option<u32>) -> String {
+        let mut data = ResultData::from(Candle::new(0, price, price, price, price));
+        data.format = OutputFormat {
+            precision,
+            ..OutputFormat::default()
+        };
+        let json = serde_json::to_value(data).unwrap();
+        json["c"].to_string()
+    }
+
+    #[test]
+    fn test_precision_removes_float_artifacts() {
+        assert_eq!(serialized(0.1 + 0.2, None), "0.30000000000000004");
+        assert_eq!(serialized(0.1 + 0.2, Some(8)), "0.3");
+        assert_eq!(serialized(26884.70 + 1806.09, Some(8)), "28690.79");
+        assert_eq!(serialized(1806.14 * 1.0000000000000002, Some(2)), "1806.14");
+    }
+
+    #[test]
+    fn test_precision_rounds_to_places() {
+        assert_eq!(serialized(14.885_276, Some(2)), "14.89");
+        assert_eq!(serialized(-0.004, Some(2)), "-0.0");
+        assert_eq!(serialized(42_301.5, Some(0)), "42302.0");
+        assert_eq!(serialized(1e-9, Some(8)), "0.0");
+    }
+
+    #[test]
+    fn test_precision_leaves_computation_alone() {
+        let mut data = ResultData::from(Candle::new(0, 0.1 + 0.2, 0.0, 0.0, 0.0));
+        data.format.precision = Some(1);
+        serde_json::to_string(&data).unwrap();
+        assert_eq!(data.o, Some(0.1 + 0.2));
+    }
+
+    #[test]
+    fn test_direction_compares_rounded_prices() {
+        let direction = |o: f64, c: f64, precision: Option<u32>| {
+            let mut data = ResultData::from(Candle::new(0, o, c, o.max(c), o.min(c)));
+            data.format.precision = precision;
+            serde_json::to_value(data).unwrap()["dir"].clone()
+        };
+        assert_eq!(direction(1.0, 2.0, None), "up");
+        assert_eq!(direction(2.0, 1.0, None), "down");
+        assert_eq!(direction(0.1 + 0.2, 0.3, None), "down");
+        assert_eq!(direction(0.1 + 0.2, 0.3, Some(8)), "flat");
+        assert_eq!(direction(1.004, 1.001, Some(2)), "flat");
+        assert!(serde_json::to_value(ResultData::missing(0)).unwrap()["dir"].is_null());
+    }
+}
+
+#[cfg(test)]
+mod tests_string_prices {
+    use super::{Candle, OutputFormat, ResultData};
+
+    fn serialized(string_prices: bool, precision: Option<u32>) -> String {
+        let candle = Candle::new(1_704_067_200_000, 26884.7, 0.1 + 0.2, 26892.5, 26877.8)
+            .with_volume(12.5, 336_058.75)
+            .with_trades(42)
+            .with_taker_volume(5.0, 134_423.5);
+        let mut data = ResultData::from(candle);
+        data.format = OutputFormat {
+            precision,
+            string_prices,
+            ..OutputFormat::default()
+        };
+        serde_json::to_string(&data).unwrap()
+    }
+
+    #[test]
+    fn test_numeric_prices() {
+        assert_eq!(
+            serialized(false, Some(2)),
+            r#"{"t":1704067200000,"o":26884.7,"c":0.3,"h":26892.5,"l":26877.8,"v":12.5,"q":336058.75,"n":42,"V":5.0,"Q":134423.5,"dir":"down"}"#
+        );
+    }
+
+    #[test]
+    fn test_string_prices_with_precision() {
+        assert_eq!(
+            serialized(true, Some(2)),
+            r#"{"t":1704067200000,"o":"26884.70","c":"0.30","h":"26892.50","l":"26877.80","v":"12.5","q":"336058.75","n":42,"V":"5","Q":"134423.5","dir":"down"}"#
+        );
+    }
+
+    #[test]
+    fn test_string_prices_without_precision() {
+        assert_eq!(
+            serialized(true, None),
+            r#"{"t":1704067200000,"o":"26884.7","c":"0.30000000000000004","h":"26892.5","l":"26877.8","v":"12.5","q":"336058.75","n":42,"V":"5","Q":"134423.5","dir":"down"}"#
+        );
+    }
+
+    #[test]
+    fn test_string_prices_keep_nulls() {
+        let mut data = ResultData::missing(0);
+        data.format.string_prices = true;
+        assert_eq!(
+            serde_json::to_string(&data).unwrap(),
+            r#"{"t":0,"o":null,"c":null,"h":null,"l":null,"v":null,"q":null,"n":null,"V":null,"Q":null}"#
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests_binance_message {
+    use super::{
+        BinanceBookTickerMessage, BinanceMessage, BinanceMiniTickerMessage, BinanceTickerMessage,
+        TickerStats,
+    };
+    use std::borrow::Cow;
+
+    #[test]
+    fn test_kline_fields_borrow_from_frame() {
+        let frame = r#"{"stream":"btcusdt@kline_1m","data":{"e":"kline","E":1,"s":"BTCUSDT","k":{
+            "t":0,"T":59999,"s":"BTCUSDT","i":"1m","f":1,"L":2,"o":"1.5","c":"2.5","h":"3.0",
+            "l":"1.0","v":"10","n":2,"x":false,"q":"20","V":"4","Q":"8","B":"0"}}}"#;
+        let message: BinanceMessage = serde_json::from_str(frame).unwrap();
+
+        assert!(matches!(message.stream, Cow::Borrowed("btcusdt@kline_1m")));
+        assert!(matches!(message.data.k.o, Cow::Borrowed("1.5")));
+        assert!(matches!(message.data.k.Q, Cow::Borrowed("8")));
+    }
+
+    #[test]
+    fn test_escaped_fields_are_owned() {
+        let frame = r#"{"stream":"btcusdt\u0040kline_1m","data":{"e":"kline","E":1,"s":"BTCUSDT",
+            "k":{"t":0,"T":59999,"s":"BTCUSDT","i":"1m","f":1,"L":2,"o":"1.5","c":"2.5","h":"3.0",
+            "l":"1.0","v":"10","n":2,"x":false,"q":"20","V":"4","Q":"8","B":"0"}}}"#;
+        let message: BinanceMessage = serde_json::from_str(frame).unwrap();
+
+        assert!(matches!(message.stream, Cow::Owned(ref stream) if stream == "btcusdt@kline_1m"));
+    }
+
+    #[test]
+    fn test_book_ticker_borrows_from_frame() {
+        let frame = r#"{"stream":"btcusdt@bookTicker","data":{"e":"bookTicker","u":400900217,
+            "E":1568014460893,"T":1568014460891,"s":"BTCUSDT","b":"25.35190000",
+            "B":"31.21000000","a":"25.36520000","A":"40.66000000"}}"#;
+        let message: BinanceBookTickerMessage = serde_json::from_str(frame).unwrap();
+        assert_eq!(message.data.E, 1_568_014_460_893);
+        assert!(matches!(message.data.b, Cow::Borrowed("25.35190000")));
+        assert!(matches!(message.data.a, Cow::Borrowed("25.36520000")));
+
+        // Spot's lack the event type and time
+        let spot = r#"{"stream":"btcusdt@bookTicker","data":{"u":400900217,"s":"BTCUSDT",
+            "b":"25.35190000","B":"31.21000000","a":"25.36520000","A":"40.66000000"}}"#;
+        let message: BinanceBookTickerMessage = serde_json::from_str(spot).unwrap();
+        assert_eq!(message.data.E, 0);
+    }
+
+    #[test]
+    fn test_ticker_stats_from_frame() {
+        let frame = r#"{"stream":"btcusdt@ticker","data":{"e":"24hrTicker","E":1704067200123,
+            "s":"BTCUSDT","p":"501.1","P":"1.199","w":"42025.3","c":"42301.1","Q":"0.010",
+            "o":"41800.0","h":"42500.0","l":"41650.5","v":"182034.1","q":"7650283810.2",
+            "O":1703980800000,"C":1704067200122,"F":100,"L":2200,"n":2101}}"#;
+        let message: BinanceTickerMessage = serde_json::from_str(frame).unwrap();
+        let stats = TickerStats::try_from(&message.data).unwrap();
+
+        assert_eq!(stats.t, 1_704_067_200_123);
+        assert_eq!((stats.last_price, stats.open), (42301.1, 41800.0));
+        assert_eq!((stats.price_change, stats.price_change_pct), (501.1, 1.199));
+        assert_eq!(stats.weighted_avg_price, 42025.3);
+        assert_eq!((stats.volume, stats.trades), (182034.1, 2101));
+        // A mini ticker lacks the statistics, so it never reads as one
+        let mini = frame.replace(r#""p":"501.1","P":"1.199","w":"42025.3","#, "");
+        assert!(serde_json::from_str::<BinanceTickerMessage>(&mini).is_err());
+        assert!(serde_json::from_str::<BinanceMiniTickerMessage>(&mini).is_ok());
+    }
+
+    #[test]
+    fn test_mini_ticker_borrows_from_frame() {
+        let frame = r#"{"stream":"btcusdt@miniTicker","data":{"e":"24hrMiniTicker",
+            "E":1704067200123,"s":"BTCUSDT","c":"42301.1","o":"41800.0","h":"42500.0",
+            "l":"41650.5","v":"182034.1","q":"7650283810.2"}}"#;
+        let message: BinanceMiniTickerMessage = serde_json::from_str(frame).unwrap();
+
+        assert_eq!(message.data.E, 1_704_067_200_123);
+        assert!(matches!(message.data.c, Cow::Borrowed("42301.1")));
+        // Not a kline, so the kline reader passes it on
+        assert!(serde_json::from_str::<BinanceMessage>(frame).is_err());
+    }
+
+    #[test]
+    fn test_premium_index_kline_names_its_index() {
+        let frame = r#"{"stream":"btcusdt@premiumIndexKline_1m","data":{
+            "e":"premium_index_kline","E":1,"s":"BTCUSDT","k":{"t":0,"T":59999,
+            "s":"PBTCUSDT","i":"1m","f":1,"L":2,"o":"-0.00010","c":"0.00025","h":"0.00030",
+            "l":"-0.00012","v":"0","n":0,"x":false,"q":"0","V":"0","Q":"0","B":"0"}}}"#;
+        let message: BinanceMessage = serde_json::from_str(frame).unwrap();
+
+        assert_eq!(message.data.s, "BTCUSDT");
+        assert_eq!(message.data.k.s, "PBTCUSDT");
+        assert!(matches!(message.data.k.c, Cow::Borrowed("0.00025")));
+    }
+
+    #[test]
+    fn test_continuous_kline_has_pair_and_contract_type() {
+        let frame = r#"{"stream":"btcusdt_perpetual@continuousKline_1m","data":{
+            "e":"continuous_kline","E":1,"ps":"BTCUSDT","ct":"PERPETUAL","k":{"t":0,"T":59999,
+            "i":"1m","f":1,"L":2,"o":"1.5","c":"2.5","h":"3.0","l":"1.0","v":"10","n":2,
+            "x":false,"q":"20","V":"4","Q":"8","B":"0"}}}"#;
+        let message: BinanceMessage = serde_json::from_str(frame).unwrap();
+
+        assert_eq!(message.data.e, "continuous_kline");
+        assert_eq!(message.data.ps.as_deref(), Some("BTCUSDT"));
+        assert_eq!(message.data.ct.as_deref(), Some("PERPETUAL"));
+        assert!(message.data.s.is_empty() && message.data.k.s.is_empty());
+        assert!(matches!(message.data.k.c, Cow::Borrowed("2.5")));
+    }
+}
+
+#[cfg(test)]
+mod tests_deserialize {
+    use super::{
+        Candle, OutputFormat, ResultData, ResultMessage, ServerMessage, TakerFlow, TickerMessage,
+        TickerStats, TimeFormat,
+    };
+    use serde_json::json;
+
+    fn result(format: OutputFormat) -> ResultMessage {
+        let candle = Candle::new(1_709_210_096_789, 1.5, 1.125, 2.25, 0.75)
+            .with_volume(10.0, 420_000.0)
+            .with_taker_volume(6.0, 252_000.0);
+        let mut data = ResultData::from(candle);
+        data.flow
+            .insert("btcusdt@kline_1m".into(), TakerFlow::from(&candle));
+        data.corr = Some(0.25);
+        data.indicators = vec![("volatility", None), ("volatility_skipped", Some(2.0))];
+        data.format = format;
+        ResultMessage {
+            id: None,
+            stream: "btcusdt@1m".into(),
+            data,
+            closed: true,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+            alias: None,
+        }
+    }
+
+    #[test]
+    fn test_result_round_trips_every_format() {
+        for (time_format, string_prices) in [
+            (TimeFormat::EpochMillis, false),
+            (TimeFormat::Iso8601, false),
+            (TimeFormat::EpochMillis, true),
+        ] {
+            let sent = serde_json::to_value(result(OutputFormat {
+                time_format,
+                string_prices,
+                ..OutputFormat::default()
+            }))
+            .unwrap();
+            let received: ResultMessage = serde_json::from_value(sent.clone()).unwrap();
+            assert_eq!(received.data.t, 1_709_210_096_789);
+            assert_eq!(received.data.c, Some(1.125));
+            assert!(received.closed);
+            assert_eq!(serde_json::to_value(received).unwrap(), sent);
+        }
+    }
+
+    #[test]
+    fn test_server_message_tells_results_from_statuses() {
+        let status =
+            json!({"stream": "btcusdt@1m", "event": "error", "message": "Empty expression"});
+        match serde_json::from_value(status).unwrap() {
+            ServerMessage::Status(status) => assert_eq!(status.event, "error"),
+            other => panic!("expected a status, got {:?}", other),
+        }
+
+        let result = serde_json::to_value(result(OutputFormat::default())).unwrap();
+        assert!(matches!(
+            serde_json::from_value(result).unwrap(),
+            ServerMessage::Result(_)
+        ));
+
+        let ticker = TickerMessage {
+            stream: "btcusdt@ticker24h".into(),
+            alias: None,
+            ticker: TickerStats::default(),
+        };
+        let ticker = serde_json::to_value(ServerMessage::Ticker(ticker)).unwrap();
+        assert!(matches!(
+            serde_json::from_value(ticker).unwrap(),
+            ServerMessage::Ticker(_)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests_query {
+    use super::{Request, TimeFormat};
+    use crate::encoding::OutputEncoder;
+
+    #[test]
+    fn test_query_options_are_typed() {
+        let request = Request::from_query(
+            "stream=btcusdt%2Bethusdt%401m&closed_only=true&precision=2&time_format=iso8601&format=cbor",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(request.method, "SUBSCRIBE");
+        assert_eq!(request.stream, "btcusdt+ethusdt@1m");
+        assert!(request.closed_only);
+        assert_eq!(request.precision, Some(2));
+        assert_eq!(request.time_format, TimeFormat::Iso8601);
+        assert_eq!(request.format, Some(OutputEncoder::Cbor));
+    }
+
+    #[test]
+    fn test_query_without_stream_is_no_request() {
+        assert!(Request::from_query("").unwrap().is_none());
+        assert!(Request::from_query("quiet=true").unwrap().is_none());
+        assert!(Request::from_query("stream=btcusdt@1m&precision=high").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::emitter::Version;
+use crate::memory::MemoryAccount;
+
+/// How readily a queued message is given up when a client's queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    // Superseded by the next update of the same bar
+    Update,
+    // Closed bars, status frames and control messages
+    Keep,
+}
+
+/// Outgoing messages of one client, bounded so a client that stops reading
+/// can't make the server buffer without limit. When full, the oldest
+/// `Update` is dropped to make room; with none queued, a new update is
+/// dropped itself and anything else replaces the oldest message. Queued
+/// bytes are charged to a memory account, and while that account must shed
+/// the queue gives up messages the same way, updates first, until it no
+/// longer has to.
+pub struct ClientQueue {
+    capacity: usize,
+    memory: MemoryAccount,
+    state: Mutex<QueueState>,
+    ready: Notify,
+    dropped_updates: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Queued>,
+    // Messages dropped per stream since the last `take_lagging`
+    lagging: BTreeMap<String, u64>,
+    // When the queue last filled up without draining to half since
+    full_since: Option<Instant>,
+    // A close frame is queued, and nothing may follow it
+    closing: bool,
+    closed: bool,
+    // Protocol version of the client's latest request
+    version: Version,
+}
+
+struct Queued {
+    message: Message,
+    stream: Option<String>,
+    priority: Priority,
+    // Bytes charged for it
+    size: usize,
+}
+
+impl ClientQueue {
+    pub fn new(capacity: usize) -> ClientQueue {
+        ClientQueue {
+            capacity: capacity.max(1),
+            memory: MemoryAccount::default(),
+            state: Mutex::new(QueueState::default()),
+            ready: Notify::new(),
+            dropped_updates: AtomicU64::new(0),
+        }
+    }
+
+    /// Charges what's queued to `memory` rather than an account of its own.
+    pub fn with_memory(mut self, memory: MemoryAccount) -> ClientQueue {
+        self.memory = memory;
+        self
+    }
+
+    /// Queues a message, attributed to `stream` if it belongs to a
+    /// subscription. Returns `false` once the queue is closed or ends in a
+    /// close frame.
+    pub fn push(&self, message: Message, stream: Option<&str>, priority: Priority) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.closing {
+            return false;
+        }
+        state.closing = matches!(message, Message::Close(_));
+
+        let size = std::mem::size_of::<Queued>() + message.len() + stream.map_or(0, str::len);
+        let mut incoming = Some(Queued {
+            message,
+            stream: stream.map(str::to_string),
+            priority,
+            size,
+        });
+        if state.messages.len() >= self.capacity {
+            self.make_room(&mut state, &mut incoming);
+        } else {
+            // Over budget, messages go the same way until the account is
+            // back within its share
+            while incoming.is_some() && self.memory.must_shed() {
+                let shed = self.make_room(&mut state, &mut incoming);
+                if shed == 0 {
+                    break;
+                }
+                self.memory.shed(shed);
+            }
+        }
+        if let Some(queued) = incoming {
+            self.memory.charge(queued.size);
+            state.messages.push_back(queued);
+        }
+        if state.messages.len() >= self.capacity && state.full_since.is_none() {
+            state.full_since = Some(Instant::now());
+        }
+        drop(state);
+
+        self.ready.notify_one();
+        true
+    }
+
+    // Drops the oldest update, or `incoming` when it's an update and none is
+    // queued, or else the oldest message. Returns the bytes dropped
+    fn make_room(&self, state: &mut QueueState, incoming: &mut Option<Queued>) -> usize {
+        let oldest_update = state
+            .messages
+            .iter()
+            .position(|queued| queued.priority == Priority::Update);
+        let priority = incoming.as_ref().map(|queued| queued.priority);
+        let dropped = match (oldest_update, priority) {
+            (Some(index), _) => state.messages.remove(index),
+            // Nothing but kept messages queued: an update gives way to
+            // them, anything else replaces the oldest
+            (None, Some(Priority::Update)) => return self.drop_queued(state, incoming.take()),
+            (None, _) => state.messages.pop_front(),
+        };
+        if let Some(queued) = &dropped {
+            self.memory.release(queued.size);
+        }
+        self.drop_queued(state, dropped)
+    }
+
+    fn drop_queued(&self, state: &mut QueueState, dropped: Option<Queued>) -> usize {
+        let Some(dropped) = dropped else {
+            return 0;
+        };
+        self.dropped_updates.fetch_add(1, Ordering::Relaxed);
+        if let Some(stream) = dropped.stream {
+            *state.lagging.entry(stream).or_default() += 1;
+        }
+        dropped.size
+    }
+
+    /// Waits for the next message, or `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return None;
+                }
+                if let Some(queued) = state.messages.pop_front() {
+                    self.memory.release(queued.size);
+                    // A writer sending one message as the next one arrives
+                    // isn't catching up, so the queue counts as full until
+                    // half of it has drained
+                    if state.messages.len() <= self.capacity / 2 {
+                        state.full_since = None;
+                    }
+                    return Some(queued.message);
+                }
+            }
+            self.ready.notified().await;
+        }
+    }
+
+    /// Drops everything queued; later pushes are refused.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        let queued = state.messages.drain(..).map(|queued| queued.size).sum();
+        self.memory.release(queued);
+        drop(state);
+        self.ready.notify_one();
+    }
+
+    /// Whether a close frame was queued.
+    pub fn is_closing(&self) -> bool {
+        self.state.lock().unwrap().closing
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Since when the queue has been full, if it is.
+    pub fn full_since(&self) -> Option<Instant> {
+        self.state.lock().unwrap().full_since
+    }
+
+    /// Messages dropped over the client's lifetime.
+    pub fn dropped_updates(&self) -> u64 {
+        self.dropped_updates.load(Ordering::Relaxed)
+    }
+
+    /// Version of frames about the connection rather than one request: the
+    /// one the client last asked for.
+    pub fn version(&self) -> Version {
+        self.state.lock().unwrap().version
+    }
+
+    pub fn set_version(&self, version: Version) {
+        self.state.lock().unwrap().version = version;
+    }
+
+    /// Streams that lost messages since the last call, with how many.
+    pub fn take_lagging(&self) -> BTreeMap<String, u64> {
+        std::mem::take(&mut self.state.lock().unwrap().lagging)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ClientQueue, Priority, Queued};
+    use crate::memory::{MemoryAccount, MemoryBudget};
+    use std::sync::Arc;
+    use tokio::time::{timeout, Duration};
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn text(message: Option<Message>) -> String {
+        match message {
+            Some(Message::Text(text)) => text,
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_oldest_update() {
+        let queue = ClientQueue::new(3);
+        queue.push(Message::Text("a1".into()), Some("a"), Priority::Update);
+        queue.push(Message::Text("closed".into()), Some("a"), Priority::Keep);
+        queue.push(Message::Text("b1".into()), Some("b"), Priority::Update);
+        queue.push(Message::Text("a2".into()), Some("a"), Priority::Update);
+        queue.push(Message::Text("b2".into()), Some("b"), Priority::Update);
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.dropped_updates(), 2);
+        assert_eq!(text(queue.pop().await), "closed");
+        assert_eq!(text(queue.pop().await), "a2");
+        assert_eq!(text(queue.pop().await), "b2");
+        let lagging = queue.take_lagging();
+        assert_eq!(lagging.get("a"), Some(&1));
+        assert_eq!(lagging.get("b"), Some(&1));
+        assert!(queue.take_lagging().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_of_kept_messages_drops_oldest() {
+        let queue = ClientQueue::new(2);
+        for name in ["first", "second", "third"] {
+            queue.push(Message::Text(name.into()), None, Priority::Keep);
+        }
+
+        assert_eq!(queue.dropped_updates(), 1);
+        assert!(queue.take_lagging().is_empty());
+        assert_eq!(text(queue.pop().await), "second");
+    }
+
+    #[tokio::test]
+    async fn test_stalled_consumer_stays_bounded() {
+        let queue = ClientQueue::new(16);
+        for i in 0..10_000 {
+            let priority = match i % 60 {
+                59 => Priority::Keep,
+                _ => Priority::Update,
+            };
+            queue.push(Message::Text(i.to_string()), Some("s"), priority);
+            assert!(queue.len() <= 16);
+        }
+
+        // Every closed bar that fits is still there, newest last
+        let mut kept = Vec::new();
+        while !queue.is_empty() {
+            kept.push(text(queue.pop().await).parse::<u32>().unwrap());
+        }
+        assert_eq!(kept.len(), 16);
+        assert_eq!(kept.iter().filter(|i| *i % 60 == 59).count(), 16);
+        assert_eq!(*kept.last().unwrap(), 9_959);
+    }
+
+    #[tokio::test]
+    async fn test_queue_is_full_until_half_drained() {
+        let queue = ClientQueue::new(4);
+        for i in 0..3 {
+            queue.push(Message::Text(i.to_string()), None, Priority::Update);
+        }
+        assert!(queue.full_since().is_none());
+
+        queue.push(Message::Text("3".into()), None, Priority::Update);
+        let since = queue.full_since().unwrap();
+        queue.pop().await;
+        queue.push(Message::Text("4".into()), None, Priority::Update);
+        assert_eq!(queue.full_since(), Some(since));
+
+        queue.pop().await;
+        queue.pop().await;
+        assert!(queue.full_since().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pop_waits_for_push_and_ends_on_close() {
+        let queue = Arc::new(ClientQueue::new(4));
+        let consumer = tokio::spawn({
+            let queue = queue.clone();
+            async move { (queue.pop().await, queue.pop().await) }
+        });
+
+        tokio::task::yield_now().await;
+        assert!(queue.push(Message::Text("hello".into()), None, Priority::Keep));
+        tokio::task::yield_now().await;
+        queue.close();
+
+        let (first, second) = timeout(Duration::from_secs(1), consumer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(text(first), "hello");
+        assert!(second.is_none());
+        assert!(!queue.push(Message::Text("late".into()), None, Priority::Keep));
+    }
+
+    #[tokio::test]
+    async fn test_nothing_follows_close_frame() {
+        let queue = ClientQueue::new(4);
+        assert!(queue.push(Message::Close(None), None, Priority::Keep));
+        assert!(queue.is_closing());
+        assert!(!queue.push(Message::Text("late".into()), None, Priority::Keep));
+        assert_eq!(queue.pop().await, Some(Message::Close(None)));
+        assert!(queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stalled_queues_stay_within_memory_budget() {
+        const BUDGET: usize = 64 * 1024;
+        let budget = Arc::new(MemoryBudget::new(Some(BUDGET)));
+        // Capacity alone would let them hold megabytes
+        let queues: Vec<ClientQueue> = (0..20)
+            .map(|_| {
+                ClientQueue::new(100_000).with_memory(MemoryAccount::new(&budget, "client queue"))
+            })
+            .collect();
+        let update = "u".repeat(100);
+        let largest_message = std::mem::size_of::<Queued>() + update.len() + 1;
+        for i in 0..50_000 {
+            // One stalled client gets nearly all the traffic, the others a
+            // few messages each
+            let queue = match i % 250 {
+                0 => &queues[1 + i / 250 % 19],
+                _ => &queues[0],
+            };
+            let (message, priority) = match i % 50 {
+                49 => (Message::Text(format!("closed {}", i)), Priority::Keep),
+                _ => (Message::Text(update.clone()), Priority::Update),
+            };
+            queue.push(message, Some("s"), priority);
+            assert!(budget.used() <= BUDGET + queues.len() * largest_message);
+        }
+
+        assert!(budget.stats().shed > 0);
+        // The largest consumer shed; the small ones kept everything
+        assert!(queues[0].dropped_updates() > 0);
+        assert!(queues[1..].iter().all(|queue| queue.dropped_updates() == 0));
+
+        // Draining releases what was charged
+        for queue in &queues[1..] {
+            queue.close();
+        }
+        while !queues[0].is_empty() {
+            queues[0].pop().await;
+        }
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,120 @@
+use crate::candle::Candle;
+
+/// Bases of the legs of a `rebase` subscription, each the close of the
+/// leg's first closed bar. Leg prices are taken as a percentage of their
+/// base's magnitude, so every leg starts out at 100, or at -100 if its base
+/// is negative, and a rise always reads as one. Legs are addressed by their
+/// index, as in `LegBook`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bases {
+    bases: Vec<Option<f64>>,
+}
+
+impl Bases {
+    pub fn new(legs: usize) -> Bases {
+        Bases {
+            bases: vec![None; legs],
+        }
+    }
+
+    /// Takes the close of `candle` as the base of leg `index` if it's the
+    /// leg's first closed bar; whether it did. A zero close can't be a base.
+    pub fn record(&mut self, index: usize, candle: &Candle) -> bool {
+        match self.bases.get_mut(index) {
+            Some(base @ None) if candle.closed && candle.c != 0.0 => {
+                *base = Some(candle.c);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// `candle` of leg `index` with its prices over the leg's base times
+    /// 100; volumes stay as they are. `None` until the leg has a base.
+    pub fn apply(&self, index: usize, candle: Candle) -> Option<Candle> {
+        let base = self.bases.get(index).copied().flatten()?;
+        let scale = |price: f64| price * 100.0 / base.abs();
+        Some(Candle {
+            o: scale(candle.o),
+            c: scale(candle.c),
+            h: scale(candle.h),
+            l: scale(candle.l),
+            ..candle
+        })
+    }
+
+    /// Base of each leg, `None` for those without one yet.
+    pub fn get(&self) -> &[Option<f64>] {
+        &self.bases
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bases.iter().all(Option::is_none)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bases;
+    use crate::candle::Candle;
+
+    #[test]
+    fn test_first_closed_bar_is_the_base() {
+        let mut bases = Bases::new(2);
+        let open = Candle::new(0, 40.0, 50.0, 55.0, 40.0);
+        assert!(!bases.record(0, &open));
+        assert!(bases.apply(0, open).is_none());
+
+        let closed = open.with_closed(true);
+        assert!(bases.record(0, &closed));
+        // Only the first one
+        assert!(!bases.record(
+            0,
+            &Candle::new(60_000, 1.0, 1.0, 1.0, 1.0).with_closed(true)
+        ));
+        assert_eq!(bases.get(), &[Some(50.0), None]);
+
+        let rebased = bases.apply(0, closed).unwrap();
+        assert_eq!(
+            (rebased.o, rebased.c, rebased.h, rebased.l),
+            (80.0, 100.0, 110.0, 80.0)
+        );
+        assert!(rebased.closed);
+        assert!(bases.apply(1, closed).is_none());
+    }
+
+    #[test]
+    fn test_volumes_are_not_rebased() {
+        let mut bases = Bases::new(1);
+        let candle = Candle::new(0, 20.0, 20.0, 20.0, 20.0)
+            .with_volume(3.0, 60.0)
+            .with_closed(true);
+        bases.record(0, &candle);
+        let rebased = bases.apply(0, candle).unwrap();
+        assert_eq!((rebased.c, rebased.v, rebased.q), (100.0, 3.0, 60.0));
+    }
+
+    #[test]
+    fn test_negative_base_keeps_the_direction() {
+        let mut bases = Bases::new(1);
+        let base = Candle::new(0, -40.0, -50.0, -40.0, -55.0).with_closed(true);
+        assert!(bases.record(0, &base));
+        let rebased = bases.apply(0, base).unwrap();
+        assert_eq!(
+            (rebased.o, rebased.c, rebased.h, rebased.l),
+            (-80.0, -100.0, -80.0, -110.0)
+        );
+        // A rise toward zero is a rise
+        let risen = bases.apply(0, Candle::new(60_000, -50.0, -45.0, -45.0, -50.0));
+        assert_eq!(risen.unwrap().c, -90.0);
+    }
+
+    #[test]
+    fn test_zero_close_is_no_base() {
+        let mut bases = Bases::new(1);
+        assert!(!bases.record(0, &Candle::new(0, 0.0, 0.0, 0.0, 0.0).with_closed(true)));
+        assert!(bases.is_empty());
+        // Out of range legs are ignored
+        assert!(!bases.record(3, &Candle::new(0, 1.0, 1.0, 1.0, 1.0).with_closed(true)));
+    }
+}
@@ -0,0 +1,366 @@
+use log::{error, info, warn};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration};
+use url::Url;
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::error::ServerError;
+use crate::memory::MemoryAccount;
+use crate::sink::{SinkCounters, SinkHandle, SinkReceiver, SinkRecord, SINK_QUEUE_CAPACITY};
+
+// A Redis command taking longer than this counts as a failed connection
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedisSinkConfig {
+    // `redis://[[user]:password@]host[:port][/db]`
+    pub url: String,
+    // Results are published to this prefix followed by the canonical
+    // expression, e.g. `candles:btcusdt+ethusdt@1m`
+    pub channel_prefix: String,
+    // When set, the latest result is also stored under the channel name,
+    // expiring after this long
+    pub latest_ttl: Option<Duration>,
+}
+
+impl Default for RedisSinkConfig {
+    fn default() -> Self {
+        RedisSinkConfig {
+            url: "redis://127.0.0.1:6379".into(),
+            channel_prefix: "candles:".into(),
+            latest_ttl: None,
+        }
+    }
+}
+
+/// Starts a task publishing every record to Redis, reconnecting with
+/// `backoff` on its own; records arriving while it is down are dropped.
+pub fn spawn(config: RedisSinkConfig, backoff: BackoffConfig, memory: MemoryAccount) -> SinkHandle {
+    let (handle, rx) = SinkHandle::new("redis", SINK_QUEUE_CAPACITY, memory);
+    tokio::spawn(run(config, backoff, rx, handle.counters().clone()));
+    handle
+}
+
+async fn run(
+    config: RedisSinkConfig,
+    backoff: BackoffConfig,
+    mut rx: SinkReceiver,
+    counters: Arc<SinkCounters>,
+) {
+    let mut backoff = Backoff::new(backoff);
+    loop {
+        let mut connection = match RedisConnection::connect(&config.url).await {
+            Ok(connection) => {
+                info!("Connected to Redis at {}", config.url);
+                counters.connected.store(true, Ordering::Relaxed);
+                backoff.reset();
+                connection
+            }
+            Err(e) => {
+                let Some(delay) = backoff.next_delay() else {
+                    error!(
+                        "Giving up on Redis after {} attempts: {}",
+                        backoff.attempt(),
+                        e
+                    );
+                    return;
+                };
+                warn!(
+                    "Redis connection attempt {} failed: {}",
+                    backoff.attempt(),
+                    e
+                );
+                // Whatever arrives meanwhile can't be delivered
+                sleep(delay).await;
+                while rx.try_recv().is_some() {
+                    counters.dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                continue;
+            }
+        };
+
+        loop {
+            let Some(record) = rx.recv().await else {
+                return;
+            };
+            match connection.publish(&config, &record).await {
+                Ok(()) => counters.published.fetch_add(1, Ordering::Relaxed),
+                Err(e) => {
+                    warn!("Error publishing {} to Redis: {}", record.key, e);
+                    counters.failed.fetch_add(1, Ordering::Relaxed);
+                    counters.connected.store(false, Ordering::Relaxed);
+                    break;
+                }
+            };
+        }
+    }
+}
+
+struct RedisConnection {
+    stream: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+    async fn connect(url: &str) -> Result<RedisConnection, ServerError> {
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| ServerError::Redis("URL without a host".into()))?;
+        let stream = timeout(
+            COMMAND_TIMEOUT,
+            TcpStream::connect((host, url.port().unwrap_or(6379))),
+        )
+        .await
+        .map_err(|_| ServerError::Redis("connection timed out".into()))??;
+        let mut connection = RedisConnection {
+            stream: BufReader::new(stream),
+        };
+
+        if let Some(password) = url.password() {
+            match url.username() {
+                "" => connection.command(&[b"AUTH", password.as_bytes()]).await?,
+                user => {
+                    connection
+                        .command(&[b"AUTH", user.as_bytes(), password.as_bytes()])
+                        .await?
+                }
+            }
+        }
+        let db = url.path().trim_start_matches('/');
+        if !db.is_empty() {
+            connection.command(&[b"SELECT", db.as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn publish(
+        &mut self,
+        config: &RedisSinkConfig,
+        record: &SinkRecord,
+    ) -> Result<(), ServerError> {
+        let channel = format!("{}{}", config.channel_prefix, record.key);
+        let payload = serde_json::to_vec(&record.message)?;
+        self.command(&[b"PUBLISH", channel.as_bytes(), &payload])
+            .await?;
+        if let Some(ttl) = config.latest_ttl {
+            let ttl = ttl.as_millis().max(1).to_string();
+            self.command(&[b"SET", channel.as_bytes(), &payload, b"PX", ttl.as_bytes()])
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Sends one command and waits for its reply, failing on an error reply.
+    async fn command(&mut self, args: &[&[u8]]) -> Result<(), ServerError> {
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+
+        timeout(COMMAND_TIMEOUT, async {
+            self.stream.get_mut().write_all(&request).await?;
+            self.read_reply().await
+        })
+        .await
+        .map_err(|_| ServerError::Redis("command timed out".into()))?
+    }
+
+    // Only the replies the commands above get: status, error, integer or
+    // bulk string
+    async fn read_reply(&mut self) -> Result<(), ServerError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(ServerError::Redis("connection closed".into()));
+        }
+        let line = line.trim_end();
+        match line.split_at(line.len().min(1)) {
+            ("+" | ":", _) => Ok(()),
+            ("-", message) => Err(ServerError::Redis(message.to_string())),
+            ("$", len) => {
+                if let Ok(len) = len.parse::<usize>() {
+                    let mut bulk = vec![0; len + 2];
+                    self.stream.read_exact(&mut bulk).await?;
+                }
+                Ok(())
+            }
+            _ => Err(ServerError::Redis(format!("unexpected reply {:?}", line))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{spawn, RedisSinkConfig};
+    use crate::backoff::BackoffConfig;
+    use crate::candle::Candle;
+    use crate::memory::MemoryAccount;
+    use crate::protocol::{ResultData, ResultMessage};
+    use crate::sink::SinkRecord;
+    use serde_json::Value;
+    use std::sync::Arc;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+    use tokio::time::{timeout, Duration};
+
+    // Fake Redis answering every command with `+OK` and reporting it; each
+    // connection hangs up after `commands_per_connection` commands if set
+    async fn fake_redis(
+        commands_per_connection: Option<usize>,
+    ) -> (String, mpsc::UnboundedReceiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, commands_per_connection, tx.clone()));
+            }
+        });
+        (url, rx)
+    }
+
+    async fn serve(
+        socket: TcpStream,
+        limit: Option<usize>,
+        tx: mpsc::UnboundedSender<Vec<String>>,
+    ) {
+        let mut socket = BufReader::new(socket);
+        let mut served = 0;
+        while limit.is_none_or(|limit| served < limit) {
+            let mut line = String::new();
+            if socket.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let count: usize = line.trim_end()[1..].parse().unwrap();
+            let mut command = Vec::new();
+            for _ in 0..count {
+                line.clear();
+                socket.read_line(&mut line).await.unwrap();
+                let len: usize = line.trim_end()[1..].parse().unwrap();
+                let mut arg = vec![0; len + 2];
+                socket.read_exact(&mut arg).await.unwrap();
+                arg.truncate(len);
+                command.push(String::from_utf8(arg).unwrap());
+            }
+            socket.get_mut().write_all(b"+OK\r\n").await.unwrap();
+            let _ = tx.send(command);
+            served += 1;
+        }
+    }
+
+    fn record(t: u64) -> Arc<SinkRecord> {
+        Arc::new(SinkRecord {
+            key: "btcusdt+ethusdt@1m".into(),
+            message: ResultMessage {
+                id: None,
+                stream: "btcusdt+ethusdt@1m".into(),
+                data: ResultData::from(Candle::new(t, 1.0, 2.0, 2.0, 1.0)),
+                closed: false,
+                out_of_order: false,
+                partial: false,
+                missing: Vec::new(),
+                snapshot: false,
+                latency_ms: None,
+                alias: None,
+            },
+        })
+    }
+
+    async fn next_command(rx: &mut mpsc::UnboundedReceiver<Vec<String>>) -> Vec<String> {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no command received")
+            .unwrap()
+    }
+
+    async fn eventually(condition: impl Fn() -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition never held");
+    }
+
+    fn fast_backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            ..BackoffConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_results_are_published_and_stored() {
+        let (url, mut commands) = fake_redis(None).await;
+        let config = RedisSinkConfig {
+            url: format!("{}/2", url),
+            latest_ttl: Some(Duration::from_secs(90)),
+            ..RedisSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+        sink.offer(record(0));
+
+        assert_eq!(next_command(&mut commands).await, ["SELECT", "2"]);
+        let publish = next_command(&mut commands).await;
+        assert_eq!(publish[..2], ["PUBLISH", "candles:btcusdt+ethusdt@1m"]);
+        let payload: Value = serde_json::from_str(&publish[2]).unwrap();
+        assert_eq!(payload["stream"], "btcusdt+ethusdt@1m");
+        assert_eq!(payload["data"]["c"], 2.0);
+
+        let set = next_command(&mut commands).await;
+        assert_eq!(set[0], "SET");
+        assert_eq!(set[1], "candles:btcusdt+ethusdt@1m");
+        assert_eq!(set[2], publish[2]);
+        assert_eq!(set[3..], ["PX", "90000"]);
+    }
+
+    #[tokio::test]
+    async fn test_sink_reconnects_after_redis_drops() {
+        let (url, mut commands) = fake_redis(Some(1)).await;
+        let config = RedisSinkConfig {
+            url,
+            ..RedisSinkConfig::default()
+        };
+        let sink = spawn(config, fast_backoff(), MemoryAccount::default());
+
+        sink.offer(record(0));
+        assert_eq!(next_command(&mut commands).await[0], "PUBLISH");
+        // The next publish finds the connection closed
+        sink.offer(record(60_000));
+        eventually(|| sink.stats().failed == 1).await;
+
+        sink.offer(record(120_000));
+        let publish = next_command(&mut commands).await;
+        let payload: Value = serde_json::from_str(&publish[2]).unwrap();
+        assert_eq!(payload["data"]["t"], 120_000);
+        eventually(|| sink.stats().published == 2).await;
+        assert!(sink.stats().connected);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_redis_drops_records() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let sink = spawn(
+            RedisSinkConfig {
+                url,
+                ..RedisSinkConfig::default()
+            },
+            fast_backoff(),
+            MemoryAccount::default(),
+        );
+
+        sink.offer(record(0));
+        eventually(|| sink.stats().dropped == 1).await;
+        assert_eq!(sink.stats().published, 0);
+        assert!(!sink.stats().connected);
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+
+use crate::error::ServerError;
+use crate::protocol::{ResultData, TakerFlow};
+use crate::utils::*;
+
+// Zones with US daylight saving rules, by standard UTC offset in hours
+const NAMED_ZONES: [(&str, i64); 5] = [
+    ("America/New_York", -5),
+    ("America/Chicago", -6),
+    ("America/Denver", -7),
+    ("America/Los_Angeles", -8),
+    ("US/Eastern", -5),
+];
+
+const HOUR: i64 = MILLIS_PER_HOUR as i64;
+const DAY: i64 = MILLIS_PER_DAY as i64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionZone {
+    // Constant UTC offset in milliseconds
+    Fixed(i64),
+    // Standard offset in milliseconds, one hour ahead from the second Sunday
+    // of March to the first Sunday of November, switching at 02:00 local
+    UsDaylight(i64),
+}
+
+impl SessionZone {
+    /// Parses `+HH:MM`/`-HH:MM`, `UTC`, or one of the supported zone names.
+    pub fn parse(input: &str) -> Result<SessionZone, ServerError> {
+        let invalid = || ServerError::InvalidSession(input.to_string());
+        if input == "UTC" || input == "Z" {
+            return Ok(SessionZone::Fixed(0));
+        }
+        if let Some(&(_, hours)) = NAMED_ZONES.iter().find(|(name, _)| *name == input) {
+            return Ok(SessionZone::UsDaylight(hours * HOUR));
+        }
+
+        let sign = match input.chars().next() {
+            Some('+') => 1,
+            Some('-') => -1,
+            _ => return Err(invalid()),
+        };
+        let offset = parse_clock(&input[1..]).ok_or_else(invalid)?;
+        if offset > 14 * HOUR {
+            return Err(invalid());
+        }
+        Ok(SessionZone::Fixed(sign * offset))
+    }
+
+    /// UTC offset in effect at the instant `utc`.
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        let standard = match *self {
+            SessionZone::Fixed(offset) => return offset,
+            SessionZone::UsDaylight(standard) => standard,
+        };
+
+        let (year, _, _) = civil_from_days((utc + standard).div_euclid(DAY).max(0) as u64);
+        let starts = nth_sunday(year, 3, 2) * DAY + 2 * HOUR - standard;
+        let ends = nth_sunday(year, 11, 1) * DAY + 2 * HOUR - (standard + HOUR);
+        if (starts..ends).contains(&utc) {
+            standard + HOUR
+        } else {
+            standard
+        }
+    }
+
+    /// Instant of a local wall-clock time. Times skipped by spring-forward
+    /// resolve an hour early; repeated times at fall-back take the first.
+    pub fn local_to_utc(&self, local: i64) -> i64 {
+        let standard = match *self {
+            SessionZone::Fixed(offset) => return local - offset,
+            SessionZone::UsDaylight(standard) => standard,
+        };
+
+        let daylight = local - (standard + HOUR);
+        if self.offset_at(daylight) == standard + HOUR {
+            daylight
+        } else {
+            local - standard
+        }
+    }
+}
+
+/// Where daily and weekly buckets begin, relative to local midnight in `zone`.
+/// Sessions starting in the afternoon or evening belong to the next day, the
+/// way a 17:00 New York roll opens the following trading day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub zone: SessionZone,
+    // Within [-12h, 12h) of the midnight that starts the session's day
+    pub start: i64,
+}
+
+impl Session {
+    /// Builds a session from a request's `session_offset` and `session_start`
+    /// (`HH:MM`); both default to UTC midnight.
+    pub fn parse(offset: Option<&str>, start: Option<&str>) -> Result<Session, ServerError> {
+        let zone = offset.map_or(Ok(SessionZone::Fixed(0)), SessionZone::parse)?;
+        let start = match start {
+            Some(start) => parse_clock(start)
+                .filter(|&start| start < DAY)
+                .ok_or_else(|| ServerError::InvalidSession(start.to_string()))?,
+            None => 0,
+        };
+        let start = if start >= DAY / 2 { start - DAY } else { start };
+        Ok(Session { zone, start })
+    }
+
+    /// Start of the `interval` bucket holding `t`. Days are counted from the
+    /// epoch and weeks start on Monday, both in session-local time.
+    pub fn bucket_start(&self, t: u64, interval: &str) -> Result<u64, ServerError> {
+        let (count, weeks) = bucket_length(interval)?;
+        let t = t as i64;
+
+        let local = t + self.zone.offset_at(t) - self.start;
+        let day = local.div_euclid(DAY);
+        let first_day = if weeks {
+            // 1970-01-05 was the first Monday after the epoch
+            let week = (day - 4).div_euclid(7);
+            (week - week.rem_euclid(count)) * 7 + 4
+        } else {
+            day - day.rem_euclid(count)
+        };
+
+        let start = self.zone.local_to_utc(first_day * DAY + self.start);
+        Ok(start.max(0) as u64)
+    }
+}
+
+// `Nd` or `Nw`, as (N, is weeks)
+fn bucket_length(interval: &str) -> Result<(i64, bool), ServerError> {
+    let invalid = || ServerError::InvalidInterval(interval.to_string());
+    let weeks = match interval.chars().last() {
+        Some('d') => false,
+        Some('w') => true,
+        _ => return Err(invalid()),
+    };
+    match interval[..interval.len() - 1].parse() {
+        Ok(count) if count > 0 => Ok((count, weeks)),
+        _ => Err(invalid()),
+    }
+}
+
+// `HH:MM` as milliseconds
+fn parse_clock(input: &str) -> Option<i64> {
+    let (hours, minutes) = input.split_once(':')?;
+    if hours.len() != 2 || minutes.len() != 2 {
+        return None;
+    }
+    let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if minutes >= 60 {
+        return None;
+    }
+    Some(hours * HOUR + minutes * 60_000)
+}
+
+// Day count since the epoch of the `n`th Sunday of a month
+fn nth_sunday(year: u64, month: u64, n: i64) -> i64 {
+    let first = days_from_civil(year, month, 1) as i64;
+    // The epoch was a Thursday
+    let weekday = (first + 4).rem_euclid(7);
+    first + (7 - weekday) % 7 + (n - 1) * 7
+}
+
+/// Builds daily or weekly candles from a subscription's finer results,
+/// bucketed by session rather than by UTC midnight.
+pub struct Resampler {
+    interval: String,
+    session: Session,
+    bucket: Option<u64>,
+    // Latest update of every source bar in the current bucket
+    bars: BTreeMap<u64, ResultData>,
+}
+
+impl Resampler {
+    pub fn new(interval: &str, session: Session) -> Result<Resampler, ServerError> {
+        bucket_length(interval)?;
+        Ok(Resampler {
+            interval: interval.to_string(),
+            session,
+            bucket: None,
+            bars: BTreeMap::new(),
+        })
+    }
+
+    // Each leg's volumes summed over the bucket
+    fn flow(&self) -> BTreeMap<String, TakerFlow> {
+        let mut flow: BTreeMap<String, TakerFlow> = BTreeMap::new();
+        for (leg, bar) in self.bars.values().flat_map(|bar| &bar.flow) {
+            let total = flow.entry(leg.clone()).or_default();
+            total.v += bar.v;
+            total.taker_v += bar.taker_v;
+        }
+        flow
+    }
+
+    /// Folds a source bar into its bucket and returns the bucket's candle so
+    /// far, or `None` for bars belonging to an already finished bucket.
+    pub fn push(&mut self, data: &ResultData) -> Result<Option<ResultData>, ServerError> {
+        let bucket = self.session.bucket_start(data.t, &self.interval)?;
+        match self.bucket {
+            Some(current) if bucket < current => return Ok(None),
+            Some(current) if bucket == current => {}
+            _ => {
+                self.bucket = Some(bucket);
+                self.bars.clear();
+            }
+        }
+        self.bars.insert(data.t, data.clone());
+
+        let first = self.bars.values().next().unwrap();
+        let last = self.bars.values().next_back().unwrap();
+        Ok(Some(ResultData {
+            t: bucket,
+            o: first.o,
+            c: last.c,
+            h: self.bars.values().filter_map(|bar| bar.h).reduce(f64::max),
+            l: self.bars.values().filter_map(|bar| bar.l).reduce(f64::min),
+            v: self.bars.values().map(|bar| bar.v).sum(),
+            q: self.bars.values().map(|bar| bar.q).sum(),
+            n: self.bars.values().map(|bar| bar.n).sum(),
+            taker_v: self.bars.values().map(|bar| bar.taker_v).sum(),
+            taker_q: self.bars.values().map(|bar| bar.taker_q).sum(),
+            flow: self.flow(),
+            // Of the base bars, as of the newest
+            corr: data.corr,
+            indicators: data.indicators.clone(),
+            event_time: data.event_time,
+            format: data.format,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Resampler, Session, SessionZone};
+    use crate::protocol::ResultData;
+    use crate::utils::{days_from_civil, format_rfc3339};
+
+    fn utc(year: u64, month: u64, day: u64, hour: u64, minute: u64) -> u64 {
+        days_from_civil(year, month, day) * 86_400_000 + hour * 3_600_000 + minute * 60_000
+    }
+
+    fn bucket(session: &Session, interval: &str, t: u64) -> String {
+        format_rfc3339(session.bucket_start(t, interval).unwrap())
+    }
+
+    fn new_york(start: &str) -> Session {
+        Session::parse(Some("America/New_York"), Some(start)).unwrap()
+    }
+
+    #[test]
+    fn test_utc_midnight_by_default() {
+        let session = Session::parse(None, None).unwrap();
+        let t = utc(2024, 1, 10, 23, 59);
+        assert_eq!(bucket(&session, "1d", t), "2024-01-10T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_fixed_offset_daily_boundary() {
+        let session = Session::parse(Some("-05:00"), None).unwrap();
+        let before = utc(2024, 1, 10, 4, 59);
+        let after = utc(2024, 1, 10, 5, 0);
+        assert_eq!(bucket(&session, "1d", before), "2024-01-09T05:00:00.000Z");
+        assert_eq!(bucket(&session, "1d", after), "2024-01-10T05:00:00.000Z");
+    }
+
+    #[test]
+    fn test_fixed_offset_ignores_daylight_saving() {
+        let session = Session::parse(Some("-05:00"), None).unwrap();
+        let t = utc(2024, 7, 1, 12, 0);
+        assert_eq!(bucket(&session, "1d", t), "2024-07-01T05:00:00.000Z");
+    }
+
+    #[test]
+    fn test_positive_offset_with_minutes() {
+        let session = Session::parse(Some("+05:30"), None).unwrap();
+        let t = utc(2024, 1, 10, 18, 29);
+        assert_eq!(bucket(&session, "1d", t), "2024-01-09T18:30:00.000Z");
+    }
+
+    #[test]
+    fn test_new_york_session_follows_daylight_saving() {
+        let session = new_york("17:00");
+        let winter = utc(2024, 1, 10, 12, 0);
+        let summer = utc(2024, 7, 10, 12, 0);
+        assert_eq!(bucket(&session, "1d", winter), "2024-01-09T22:00:00.000Z");
+        assert_eq!(bucket(&session, "1d", summer), "2024-07-09T21:00:00.000Z");
+    }
+
+    #[test]
+    fn test_spring_forward_session_is_23_hours() {
+        // DST began 2024-03-10 at 07:00 UTC
+        let session = new_york("17:00");
+        let last = utc(2024, 3, 10, 20, 59);
+        let next = utc(2024, 3, 10, 21, 0);
+        assert_eq!(bucket(&session, "1d", last), "2024-03-09T22:00:00.000Z");
+        assert_eq!(bucket(&session, "1d", next), "2024-03-10T21:00:00.000Z");
+    }
+
+    #[test]
+    fn test_fall_back_session_is_25_hours() {
+        // DST ended 2024-11-03 at 06:00 UTC
+        let session = new_york("17:00");
+        let last = utc(2024, 11, 3, 21, 59);
+        let next = utc(2024, 11, 3, 22, 0);
+        assert_eq!(bucket(&session, "1d", last), "2024-11-02T21:00:00.000Z");
+        assert_eq!(bucket(&session, "1d", next), "2024-11-03T22:00:00.000Z");
+    }
+
+    #[test]
+    fn test_midnight_sessions_across_transitions() {
+        let session = new_york("00:00");
+        // Around the switch itself, at 02:00 local
+        let spring = utc(2024, 3, 10, 7, 30);
+        let fall = utc(2024, 11, 3, 5, 30);
+        assert_eq!(bucket(&session, "1d", spring), "2024-03-10T05:00:00.000Z");
+        assert_eq!(bucket(&session, "1d", fall), "2024-11-03T04:00:00.000Z");
+        // The first day after each switch
+        let after_spring = utc(2024, 3, 11, 4, 0);
+        let after_fall = utc(2024, 11, 4, 5, 0);
+        assert_eq!(
+            bucket(&session, "1d", after_spring),
+            "2024-03-11T04:00:00.000Z"
+        );
+        assert_eq!(
+            bucket(&session, "1d", after_fall),
+            "2024-11-04T05:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_daylight_offsets_at_transition_instants() {
+        let zone = SessionZone::parse("America/New_York").unwrap();
+        let hour = 3_600_000;
+        let spring = utc(2024, 3, 10, 7, 0) as i64;
+        let fall = utc(2024, 11, 3, 6, 0) as i64;
+        assert_eq!(zone.offset_at(spring - 1), -5 * hour);
+        assert_eq!(zone.offset_at(spring), -4 * hour);
+        assert_eq!(zone.offset_at(fall - 1), -4 * hour);
+        assert_eq!(zone.offset_at(fall), -5 * hour);
+    }
+
+    #[test]
+    fn test_weekly_buckets_start_on_monday() {
+        let session = new_york("17:00");
+        // Sunday 2024-03-10 evening in New York is already Monday's session
+        let sunday = utc(2024, 3, 10, 22, 0);
+        let saturday = utc(2024, 3, 9, 12, 0);
+        assert_eq!(bucket(&session, "1w", sunday), "2024-03-10T21:00:00.000Z");
+        assert_eq!(bucket(&session, "1w", saturday), "2024-03-03T22:00:00.000Z");
+    }
+
+    #[test]
+    fn test_multi_day_buckets_align_to_epoch() {
+        let session = Session::parse(None, None).unwrap();
+        let t = utc(1970, 1, 5, 12, 0);
+        assert_eq!(bucket(&session, "3d", t), "1970-01-04T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_invalid_sessions_are_rejected() {
+        assert!(Session::parse(Some("-5"), None).is_err());
+        assert!(Session::parse(Some("+15:00"), None).is_err());
+        assert!(Session::parse(Some("Mars/Olympus_Mons"), None).is_err());
+        assert!(Session::parse(None, Some("24:00")).is_err());
+        assert!(Session::parse(None, Some("17:60")).is_err());
+        assert!(Resampler::new("4h", Session::parse(None, None).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_resampler_aggregates_bucket() {
+        let session = Session::parse(Some("-05:00"), None).unwrap();
+        let mut resampler = Resampler::new("1d", session).unwrap();
+        let bar = |t, o, c, h, l| ResultData {
+            o: Some(o),
+            c: Some(c),
+            h: Some(h),
+            l: Some(l),
+            ..ResultData::missing(t)
+        };
+        let first = utc(2024, 1, 10, 5, 0);
+        let second = utc(2024, 1, 10, 6, 0);
+
+        resampler.push(&bar(first, 10.0, 12.0, 13.0, 9.0)).unwrap();
+        let day = resampler
+            .push(&bar(second, 12.0, 11.0, 15.0, 10.0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(format_rfc3339(day.t), "2024-01-10T05:00:00.000Z");
+        assert_eq!(
+            (day.o, day.c, day.h, day.l),
+            (Some(10.0), Some(11.0), Some(15.0), Some(9.0))
+        );
+
+        // An update to the open bar replaces its previous values
+        let day = resampler
+            .push(&bar(second, 12.0, 14.0, 16.0, 10.0))
+            .unwrap()
+            .unwrap();
+        assert_eq!((day.c, day.h), (Some(14.0), Some(16.0)));
+
+        // Bars from an earlier bucket are ignored once a later one started
+        let next = utc(2024, 1, 11, 5, 0);
+        resampler.push(&bar(next, 1.0, 1.0, 1.0, 1.0)).unwrap();
+        assert!(resampler
+            .push(&bar(first, 1.0, 1.0, 1.0, 1.0))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_resampler_sums_trade_counts() {
+        let mut resampler = Resampler::new("1d", Session::parse(None, None).unwrap()).unwrap();
+        let bar = |t, n| ResultData {
+            n: Some(n),
+            ..ResultData::missing(t)
+        };
+
+        resampler.push(&bar(0, 3)).unwrap();
+        let day = resampler.push(&bar(60_000, 4)).unwrap().unwrap();
+        assert_eq!(day.n, Some(7));
+        let day = resampler.push(&bar(60_000, 6)).unwrap().unwrap();
+        assert_eq!(day.n, Some(9));
+    }
+}
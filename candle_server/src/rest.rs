@@ -0,0 +1,338 @@
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use url::Url;
+
+use crate::candle::Candle;
+use crate::error::ServerError;
+use crate::utils::now_millis;
+
+// A REST call taking longer than this is given up on
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Open bar of a kline stream such as `btcusdt@kline_1m`, from
+/// `GET /fapi/v1/klines` at `base`, the scheme and host of a Binance futures
+/// REST API, or of a continuous contract's `btcusdt_perpetual@continuousKline_1m`
+/// from `GET /fapi/v1/continuousKlines` and a premium index's
+/// `btcusdt@premiumIndexKline_1m` from `GET /fapi/v1/premiumIndexKlines`. Only
+/// plain `http` is spoken, e.g. to a local mirror.
+pub async fn open_kline(base: &str, stream: &str) -> Result<Candle, ServerError> {
+    let mut url = Url::parse(base)?;
+    if let Some((symbol, interval)) = stream.split_once("@premiumIndexKline_") {
+        url.set_path("/fapi/v1/premiumIndexKlines");
+        url.query_pairs_mut()
+            .append_pair("symbol", &symbol.to_uppercase())
+            .append_pair("interval", interval)
+            .append_pair("limit", "1");
+    } else if let Some((name, interval)) = stream.split_once("@continuousKline_") {
+        let (pair, contract) = name
+            .split_once('_')
+            .ok_or_else(|| ServerError::Rest(format!("{} names no contract type", stream)))?;
+        url.set_path("/fapi/v1/continuousKlines");
+        url.query_pairs_mut()
+            .append_pair("pair", &pair.to_uppercase())
+            .append_pair("contractType", &contract.to_uppercase())
+            .append_pair("interval", interval)
+            .append_pair("limit", "1");
+    } else {
+        let (symbol, interval) = stream
+            .split_once("@kline_")
+            .ok_or_else(|| ServerError::Rest(format!("{} is not a kline stream", stream)))?;
+        url.set_path("/fapi/v1/klines");
+        url.query_pairs_mut()
+            .append_pair("symbol", &symbol.to_uppercase())
+            .append_pair("interval", interval)
+            .append_pair("limit", "1");
+    }
+
+    let body = timeout(REQUEST_TIMEOUT, get(&url))
+        .await
+        .map_err(|_| ServerError::Rest("request timed out".into()))??;
+    let rows: Vec<Vec<Value>> = serde_json::from_slice(&body)?;
+    let row = rows
+        .last()
+        .ok_or_else(|| ServerError::Rest(format!("no kline for {}", stream)))?;
+    parse_kline(row)
+}
+
+/// Open interest of `symbol` and when Binance measured it, in unix millis,
+/// from `GET /fapi/v1/openInterest` at `base` as for `open_kline`.
+pub async fn open_interest(base: &str, symbol: &str) -> Result<(u64, f64), ServerError> {
+    let mut url = Url::parse(base)?;
+    url.set_path("/fapi/v1/openInterest");
+    url.query_pairs_mut()
+        .append_pair("symbol", &symbol.to_uppercase());
+
+    let body = timeout(REQUEST_TIMEOUT, get(&url))
+        .await
+        .map_err(|_| ServerError::Rest("request timed out".into()))??;
+    let reply: Value = serde_json::from_slice(&body)?;
+    let malformed = |field: &str| ServerError::Rest(format!("malformed open interest {}", field));
+    let time = reply["time"].as_u64().ok_or_else(|| malformed("time"))?;
+    let value = reply["openInterest"]
+        .as_str()
+        .and_then(|text| text.parse::<f64>().ok())
+        .ok_or_else(|| malformed("openInterest"))?;
+    Ok((time, value))
+}
+
+// One row of `/fapi/v1/klines`: open time, open, high, low, close, volume,
+// close time, quote volume, trades, taker base volume, taker quote volume
+fn parse_kline(row: &[Value]) -> Result<Candle, ServerError> {
+    let malformed = |index: usize| ServerError::Rest(format!("malformed kline field {}", index));
+    let integer = |index: usize| {
+        row.get(index)
+            .and_then(Value::as_u64)
+            .ok_or_else(|| malformed(index))
+    };
+    let decimal = |index: usize| {
+        row.get(index)
+            .and_then(Value::as_str)
+            .and_then(|text| text.parse::<f64>().ok())
+            .ok_or_else(|| malformed(index))
+    };
+    let now = now_millis();
+
+    Ok(Candle::new(
+        integer(0)?,
+        decimal(1)?,
+        decimal(4)?,
+        decimal(2)?,
+        decimal(3)?,
+    )
+    .with_volume(decimal(5)?, decimal(7)?)
+    .with_trades(integer(8)?)
+    .with_taker_volume(decimal(9)?, decimal(10)?)
+    // The last row is the open bar until its close time passes
+    .with_closed(integer(6)? < now))
+}
+
+// Body of a `200` response to a GET of `url`
+async fn get(url: &Url) -> Result<Vec<u8>, ServerError> {
+    if url.scheme() != "http" {
+        return Err(ServerError::Rest(format!(
+            "{} needs TLS, which this build lacks",
+            url.scheme()
+        )));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| ServerError::Rest("URL without a host".into()))?;
+    let port = url.port().unwrap_or(80);
+    let mut stream = TcpStream::connect((host, port)).await?;
+
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}:{}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        target, host, port
+    );
+    stream.write_all(request.as_bytes()).await?;
+    // Read to the end, as the connection closes after one response
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    response_body(&response)
+}
+
+fn response_body(response: &[u8]) -> Result<Vec<u8>, ServerError> {
+    let malformed = || ServerError::Rest("malformed HTTP response".into());
+    let head_end = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .ok_or_else(malformed)?;
+    let head = std::str::from_utf8(&response[..head_end]).map_err(|_| malformed())?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or_else(malformed)?;
+    if status != 200 {
+        return Err(ServerError::Rest(format!("HTTP status {}", status)));
+    }
+
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+    let body = &response[head_end + 4..];
+    if chunked {
+        dechunk(body).ok_or_else(malformed)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+// Joins the chunks of a `Transfer-Encoding: chunked` body
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut joined = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|window| window == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        // Chunk extensions follow a `;`
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(joined);
+        }
+        joined.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_interest, open_kline, parse_kline, response_body};
+    use serde_json::json;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_kline_row() {
+        let row = json!([
+            1_704_067_200_000u64,
+            "42283.50",
+            "42310.00",
+            "42280.20",
+            "42301.10",
+            "12.345",
+            1_704_067_259_999u64,
+            "521987.6543",
+            101,
+            "5.120",
+            "216563.01",
+            "0"
+        ]);
+        let candle = parse_kline(row.as_array().unwrap()).unwrap();
+        assert_eq!(candle.t, 1_704_067_200_000);
+        assert_eq!((candle.o, candle.c), (42283.50, 42301.10));
+        assert_eq!((candle.h, candle.l), (42310.00, 42280.20));
+        assert_eq!((candle.v, candle.q, candle.n), (12.345, 521987.6543, 101));
+        assert_eq!((candle.taker_v, candle.taker_q), (5.120, 216563.01));
+        assert!(candle.closed);
+
+        let malformed = json!([1_704_067_200_000u64, "n/a"]);
+        assert!(parse_kline(malformed.as_array().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_response_body() {
+        let plain = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]";
+        assert_eq!(response_body(plain).unwrap(), b"[]");
+
+        let chunked =
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n[[1\r\n2;x=y\r\n]]\r\n0\r\n\r\n";
+        assert_eq!(response_body(chunked).unwrap(), b"[[1]]");
+
+        let error = response_body(b"HTTP/1.1 429 Too Many Requests\r\n\r\n").unwrap_err();
+        assert_eq!(error.to_string(), "REST error: HTTP status 429");
+        assert!(response_body(b"HTTP/1.1 200 OK\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_open_kline_asks_for_one_bar() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 1024];
+            let read = socket.read(&mut request).await.unwrap();
+            let body = r#"[[60000,"1.5","2","1","1.75","10",4102444800000,"15",3,"4","6","0"]]"#;
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let candle = open_kline(&base, "btcusdt@kline_1m").await.unwrap();
+        assert_eq!((candle.t, candle.c, candle.n), (60_000, 1.75, 3));
+        assert!(!candle.closed);
+        let request = server.await.unwrap();
+        assert!(request.starts_with("GET /fapi/v1/klines?symbol=BTCUSDT&interval=1m&limit=1 "));
+
+        let error = open_kline("https://fapi.binance.com", "btcusdt@kline_1m")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "REST error: https needs TLS, which this build lacks"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_open_kline_of_futures_only_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let read = socket.read(&mut request).await.unwrap();
+                let body = r#"[[0,"1.5","2","1","1.75","10",3599999,"15",3,"4","6","0"]]"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..read]).into_owned());
+            }
+            requests
+        });
+
+        let stream = "btcusdt_current_quarter@continuousKline_1h";
+        assert_eq!(open_kline(&base, stream).await.unwrap().c, 1.75);
+        let stream = "btcusdt@premiumIndexKline_1h";
+        assert_eq!(open_kline(&base, stream).await.unwrap().c, 1.75);
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with(
+            "GET /fapi/v1/continuousKlines?pair=BTCUSDT&contractType=CURRENT_QUARTER&interval=1h&limit=1 "
+        ));
+        assert!(requests[1]
+            .starts_with("GET /fapi/v1/premiumIndexKlines?symbol=BTCUSDT&interval=1h&limit=1 "));
+    }
+
+    #[tokio::test]
+    async fn test_open_interest() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for body in [
+                r#"{"openInterest":"10659.509","symbol":"BTCUSDT","time":1589437530011}"#,
+                r#"{"symbol":"BTCUSDT","time":1589437530011}"#,
+            ] {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let read = socket.read(&mut request).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..read]).into_owned());
+            }
+            requests
+        });
+
+        let (time, value) = open_interest(&base, "btcusdt").await.unwrap();
+        assert_eq!((time, value), (1_589_437_530_011, 10659.509));
+        let error = open_interest(&base, "btcusdt").await.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "REST error: malformed open interest openInterest"
+        );
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /fapi/v1/openInterest?symbol=BTCUSDT "));
+    }
+}
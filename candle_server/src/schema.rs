@@ -0,0 +1,850 @@
+use serde_json::{json, Map, Value};
+
+use crate::emitter::Version;
+use crate::indicators;
+
+// JSON Schema (2020-12) of every frame a client sends or gets, as answered
+// to `GET_SCHEMA`. Written out by hand, as nothing derives them here; the
+// tests check real requests and frames against them with unknown fields
+// rejected, so a field added to the protocol fails them until it's listed.
+
+const DIALECT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+/// Schemas of `request`, `result`, `error` and `status` frames as sent in
+/// `version`, with the version itself.
+pub fn schemas(version: Version) -> Value {
+    let (result, error, status) = match version {
+        Version::V3 => (
+            multiplexed(json!({"const": "candle"}), result(), Some("data")),
+            multiplexed(json!({"const": "error"}), error(), None),
+            multiplexed(
+                json!({"enum": ["ack", "error", "alert", "status"]}),
+                status(),
+                None,
+            ),
+        ),
+        _ => (
+            tagged(version, "result", result()),
+            tagged(version, "status", error()),
+            tagged(version, "status", status()),
+        ),
+    };
+    json!({
+        "version": version.number(),
+        "request": document(request()),
+        "result": document(result),
+        "error": document(error),
+        "status": document(status),
+    })
+}
+
+fn document(mut schema: Value) -> Value {
+    schema["$schema"] = json!(DIALECT);
+    schema
+}
+
+// An object with exactly `properties`, of which `required` must be there
+fn object(properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+fn nullable(kind: &str) -> Value {
+    json!({"type": [kind, "null"]})
+}
+
+// Version 2 frames lead with `v` and their `type`
+fn tagged(version: Version, kind: &str, mut schema: Value) -> Value {
+    if version == Version::V2 {
+        schema["properties"]["v"] = json!({"const": version.number()});
+        schema["properties"]["type"] = json!({"const": kind});
+        let required = schema["required"].as_array_mut().unwrap();
+        required.push(json!("v"));
+        required.push(json!("type"));
+    }
+    schema
+}
+
+// Version 3 frames name their `type`, `stream` and `id`, with the rest of
+// `schema` under `data` and the object in its `lifted` field merged in
+fn multiplexed(kind: Value, schema: Value, lifted: Option<&str>) -> Value {
+    let mut properties = schema["properties"].as_object().unwrap().clone();
+    let mut required = schema["required"].as_array().unwrap().clone();
+    if let Some(lifted) = lifted {
+        let inner = properties.remove(lifted).unwrap();
+        required.retain(|field| field != lifted);
+        properties.extend(inner["properties"].as_object().unwrap().clone());
+        required.extend(inner["required"].as_array().unwrap().clone());
+    }
+    for outer in ["id", "stream"] {
+        properties.remove(outer);
+        required.retain(|field| field != outer);
+    }
+
+    let mut frame = object(
+        json!({
+            "v": {"const": Version::V3.number()},
+            "type": kind,
+            "stream": {"type": "string"},
+            "id": nullable("integer"),
+            "data": {
+                "type": "object",
+                "properties": properties,
+                "required": required,
+                "additionalProperties": false,
+            },
+        }),
+        &["v", "type", "stream", "id", "data"],
+    );
+    if let Some(description) = schema.get("description") {
+        frame["description"] = description.clone();
+    }
+    frame
+}
+
+fn request() -> Value {
+    let indicator = |kind: &str, properties: Value, required: &[&str]| {
+        let mut schema = object(properties, required);
+        schema["properties"]["type"] = json!({"const": kind});
+        schema["required"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!("type"));
+        schema
+    };
+    let count = json!({"type": "integer", "minimum": 1});
+    let indicators = [
+        indicator(
+            "volatility",
+            json!({"window": count, "annualize": {"type": "boolean"}}),
+            &["window"],
+        ),
+        indicator(
+            "bollinger",
+            json!({"period": count, "stddev": {"type": "number"}}),
+            &["period"],
+        ),
+        indicator("rsi", json!({"period": count}), &["period"]),
+        indicator("atr", json!({"period": count}), &["period"]),
+        indicator(
+            "macd",
+            json!({"fast": count, "slow": count, "signal": count}),
+            &["fast", "slow", "signal"],
+        ),
+    ];
+    let webhook = object(
+        json!({
+            "url": {"type": "string"},
+            "headers": {"type": "object", "additionalProperties": {"type": "string"}},
+        }),
+        &["url"],
+    );
+    object(
+        json!({
+            "id": {"type": "integer", "minimum": 0},
+            "method": {
+                "enum": [
+                    "SUBSCRIBE",
+                    "UNSUBSCRIBE",
+                    "GET_LAST",
+                    "DEFINE",
+                    "LIST",
+                    "GET_SCHEMA",
+                    "GET_INFO",
+                    "RENEW",
+                    "EXPORT",
+                    "ALERT",
+                    "DELETE_ALERT",
+                ]
+            },
+            "version": {"enum": [1, 2, 3, null]},
+            "stream": {"type": "string"},
+            "name": nullable("string"),
+            "expr": nullable("string"),
+            "alias": nullable("string"),
+            "align": {"enum": ["resample", "forward_fill"]},
+            "time_format": {"enum": ["epoch_millis", "iso8601"]},
+            "precision": {"type": ["integer", "null"], "minimum": 0},
+            "string_prices": {"type": "boolean"},
+            "resample": nullable("string"),
+            "session_offset": nullable("string"),
+            "session_start": nullable("string"),
+            "batch_ms": {"type": ["integer", "null"], "minimum": 0},
+            "format": {"enum": ["json", "cbor", "protobuf", null]},
+            "quiet": {"type": "boolean"},
+            "closed_only": {"type": "boolean"},
+            "emit": {"enum": ["all", "on_change"]},
+            "mode": {"enum": ["full", "delta"]},
+            "latency": {"type": "boolean"},
+            "rebase": {"type": "boolean"},
+            "composite_semantics": {"enum": ["fieldwise", "bound"]},
+            "align_tolerance_ms": {"type": "integer", "minimum": 0},
+            "ttl_secs": {"type": ["integer", "null"], "minimum": 1},
+            "path": nullable("string"),
+            "from": {"type": ["integer", "null"], "minimum": 0},
+            "to": {"type": ["integer", "null"], "minimum": 0},
+            "row_group_rows": {"type": ["integer", "null"], "minimum": 1},
+            "webhook": {"anyOf": [webhook, {"type": "null"}]},
+            "correlation": {
+                "anyOf": [object(json!({"window": count}), &["window"]), {"type": "null"}]
+            },
+            "rule": {"anyOf": [alert_rule(), {"type": "null"}]},
+            "scope": {"enum": ["connection", "server"]},
+            "indicators": {"type": "array", "items": {"anyOf": indicators}},
+        }),
+        &["id", "method"],
+    )
+}
+
+fn alert_rule() -> Value {
+    object(
+        json!({
+            "field": {"enum": ["o", "h", "l", "c", "v"]},
+            "op": {"enum": [">", "<"]},
+            "threshold": {"type": "number"},
+            "hysteresis": {"type": "number", "minimum": 0},
+            "cooldown_secs": {"type": "integer", "minimum": 0},
+        }),
+        &["op", "threshold"],
+    )
+}
+
+fn result() -> Value {
+    // Numbers, or strings with `string_prices`; null for a missing leg
+    let decimal = json!({"type": ["number", "string", "null"]});
+    let mut data = Map::new();
+    data.insert("t".into(), json!({"type": ["integer", "string"]}));
+    for field in ["o", "c", "h", "l", "v", "q", "V", "Q"] {
+        data.insert(field.into(), decimal.clone());
+    }
+    data.insert(
+        "n".into(),
+        json!({"type": ["integer", "null"], "minimum": 0}),
+    );
+    // The rest come and go; a delta nulls those no longer written
+    data.insert("dir".into(), json!({"enum": ["up", "down", "flat", null]}));
+    data.insert(
+        "buy_ratio".into(),
+        json!({"type": ["object", "null"], "additionalProperties": nullable("number")}),
+    );
+    data.insert("corr".into(), nullable("number"));
+    for field in indicators::FIELDS {
+        data.insert(field.into(), nullable("number"));
+    }
+
+    let mut result = object(
+        json!({
+            "id": {"type": "integer"},
+            "stream": {"type": "string"},
+            "alias": {"type": "string"},
+            // Required unless `delta` is set, when only `t` is
+            "data": object(Value::Object(data), &["t"]),
+            "closed": {"const": true},
+            "out_of_order": {"const": true},
+            "partial": {"const": true},
+            "missing": {"type": "array", "items": {"type": "string"}},
+            "snapshot": {"const": true},
+            "latency_ms": {"type": "integer", "minimum": 0},
+            "delta": {"const": true},
+        }),
+        &["stream", "data"],
+    );
+    result["description"] = json!(
+        "A result of a subscription. Every data field but `dir`, `buy_ratio`, `corr` and the \
+         indicators is there unless `delta` is set."
+    );
+    result
+}
+
+fn status_properties() -> Value {
+    let count = json!({"type": "integer", "minimum": 0});
+    let evaluator = object(
+        json!({
+            "subscribers": count,
+            "klines": {"type": "object", "additionalProperties": count},
+            "results": count,
+            "bytes_sent": count,
+            "dropped": count,
+            "buffered_bytes": count,
+            "last_kline_at": count,
+            "last_result_at": count,
+        }),
+        &[
+            "subscribers",
+            "klines",
+            "results",
+            "bytes_sent",
+            "dropped",
+            "buffered_bytes",
+        ],
+    );
+    let alert = object(
+        json!({
+            "id": {"type": "integer"},
+            "stream": {"type": "string"},
+            "rule": alert_rule(),
+            "scope": {"enum": ["connection", "server"]},
+        }),
+        &["id", "stream", "rule", "scope"],
+    );
+    json!({
+        "id": {"type": "integer"},
+        "stream": {"type": "string"},
+        "alias": {"type": "string"},
+        "event": {"type": "string"},
+        "message": {"type": "string"},
+        "code": {"type": "integer"},
+        "attempt": {"type": "integer", "minimum": 1},
+        "bars": count,
+        "from": count,
+        "to": count,
+        "reason": {
+            "enum": ["unsubscribed", "upstream_failed", "evaluator_failed", "ttl_expired"]
+        },
+        "subscriptions": {"type": "array", "items": {"type": "string"}},
+        "definitions": {"type": "object", "additionalProperties": {"type": "string"}},
+        "evaluators": {"type": "object", "additionalProperties": evaluator},
+        "bases": {"type": "object", "additionalProperties": {"type": "number"}},
+        "schema": {"type": "object"},
+        "info": info(),
+        "deadline": count,
+        "candle": result()["properties"]["data"].clone(),
+        "alerts": {"type": "array", "items": alert},
+    })
+}
+
+fn info() -> Value {
+    let count = json!({"type": "integer", "minimum": 0});
+    let names = json!({"type": "array", "items": {"type": "string"}});
+    let limits = object(
+        json!({
+            "max_message_size": count,
+            "max_frame_size": count,
+            "client_queue_capacity": count,
+            "max_subscriptions_per_client": {"type": ["integer", "null"], "minimum": 1},
+            "memory_budget": {"type": ["integer", "null"], "minimum": 0},
+            "max_expression_depth": count,
+            "max_precision": count,
+            "max_indicator_window": count,
+            "max_correlation_window": count,
+            "compress_above": {"type": ["integer", "null"], "minimum": 0},
+        }),
+        &[
+            "max_message_size",
+            "max_frame_size",
+            "client_queue_capacity",
+            "max_subscriptions_per_client",
+            "memory_budget",
+            "max_expression_depth",
+            "max_precision",
+            "max_indicator_window",
+            "max_correlation_window",
+            "compress_above",
+        ],
+    );
+    let features = object(
+        json!({
+            "upstream": {"type": "string"},
+            "rest": nullable("string"),
+            "backfill": {"type": "boolean"},
+            "open_interest": {"type": "boolean"},
+            "sinks": names,
+            "formats": names,
+            "compression": names,
+            "synthetic_seed": {"type": ["integer", "null"], "minimum": 0},
+        }),
+        &[
+            "upstream",
+            "rest",
+            "backfill",
+            "open_interest",
+            "sinks",
+            "formats",
+            "compression",
+            "synthetic_seed",
+        ],
+    );
+    object(
+        json!({
+            "version": {"type": "string"},
+            "git_hash": {"type": "string"},
+            "uptime_ms": count,
+            "protocol_versions": {"type": "array", "items": {"type": "integer"}},
+            "limits": limits,
+            "features": features,
+            "draining": {"type": "boolean"},
+            "drain_deadline": {"type": ["integer", "null"], "minimum": 0},
+            "clients": count,
+            "subscriptions": count,
+            "evaluators": count,
+            "upstream_streams": count,
+        }),
+        &[
+            "version",
+            "git_hash",
+            "uptime_ms",
+            "protocol_versions",
+            "limits",
+            "features",
+            "draining",
+            "drain_deadline",
+            "clients",
+            "subscriptions",
+            "evaluators",
+            "upstream_streams",
+        ],
+    )
+}
+
+fn error() -> Value {
+    let mut error = object(status_properties(), &["stream", "event", "message", "code"]);
+    error["properties"]["event"] = json!({"const": "error"});
+    error
+}
+
+fn status() -> Value {
+    let mut status = object(status_properties(), &["stream", "event", "message"]);
+    status["properties"]["event"] = json!({"enum": EVENTS});
+    status
+}
+
+// Every status event
+const EVENTS: [&str; 21] = [
+    "connecting",
+    "backfilling",
+    "subscribed",
+    "reconnecting",
+    "closed",
+    "error",
+    "stale",
+    "gap_detected",
+    "lagging",
+    "rebased",
+    "defined",
+    "list",
+    "schema",
+    "info",
+    "draining",
+    "renewed",
+    "exporting",
+    "exported",
+    "alert_set",
+    "alert",
+    "alert_deleted",
+];
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_json::{json, Value};
+
+    use super::schemas;
+    use crate::alert::{AlertField, AlertInfo, AlertRule, AlertScope, Comparison};
+    use crate::candle::{Candle, CompositeSemantics};
+    use crate::delta::Delta;
+    use crate::emitter::{Emitter, Version};
+    use crate::encoding::OutputEncoder;
+    use crate::error::ServerError;
+    use crate::indicators::IndicatorSpec;
+    use crate::lifecycle::{CloseReason, SubscriptionState};
+    use crate::protocol::*;
+
+    // Checks the keywords the schemas use, naming where `value` breaks them
+    pub(crate) fn validate(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+        let fail = |why: String| Err(format!("{}: {}", path, why));
+        if let Some(kinds) = schema.get("type") {
+            let kinds: Vec<&str> = match kinds {
+                Value::String(kind) => vec![kind.as_str()],
+                kinds => kinds
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|kind| kind.as_str().unwrap())
+                    .collect(),
+            };
+            let matches = |kind: &str| match kind {
+                "object" => value.is_object(),
+                "array" => value.is_array(),
+                "string" => value.is_string(),
+                "number" => value.is_number(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "boolean" => value.is_boolean(),
+                "null" => value.is_null(),
+                other => panic!("unknown type {}", other),
+            };
+            if !kinds.into_iter().any(matches) {
+                return fail(format!("{} is not a {}", value, schema["type"]));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if value != expected {
+                return fail(format!("{} is not {}", value, expected));
+            }
+        }
+        if let Some(options) = schema.get("enum") {
+            if !options.as_array().unwrap().contains(value) {
+                return fail(format!("{} is not one of {}", value, options));
+            }
+        }
+        if let (Some(minimum), Some(number)) = (schema.get("minimum"), value.as_f64()) {
+            if number < minimum.as_f64().unwrap() {
+                return fail(format!("{} is below {}", value, minimum));
+            }
+        }
+        if let Some(options) = schema.get("anyOf") {
+            if !options
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|option| validate(option, value, path).is_ok())
+            {
+                return fail(format!("{} matches no option", value));
+            }
+        }
+        if let (Some(items), Some(values)) = (schema.get("items"), value.as_array()) {
+            for (index, item) in values.iter().enumerate() {
+                validate(items, item, &format!("{}[{}]", path, index))?;
+            }
+        }
+        if let Some(object) = value.as_object() {
+            for field in schema
+                .get("required")
+                .into_iter()
+                .flat_map(|r| r.as_array().unwrap())
+            {
+                if !object.contains_key(field.as_str().unwrap()) {
+                    return fail(format!("missing {}", field));
+                }
+            }
+            for (field, value) in object {
+                let path = format!("{}.{}", path, field);
+                match schema.get("properties").and_then(|p| p.get(field)) {
+                    Some(property) => validate(property, value, &path)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => return Err(format!("{}: unknown field", path)),
+                        Some(Value::Bool(true)) | None => {}
+                        Some(additional) => validate(additional, value, &path)?,
+                    },
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn emitted(version: Version, message: &ServerMessage) -> Value {
+        let mut buffer = Vec::new();
+        Emitter::new(OutputEncoder::Json, version)
+            .encode(message, &mut buffer)
+            .unwrap();
+        serde_json::from_slice(&buffer).unwrap()
+    }
+
+    fn result(data: ResultData) -> ResultMessage {
+        ResultMessage {
+            id: None,
+            stream: "btcusdt@1m".into(),
+            alias: None,
+            data,
+            closed: false,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+        }
+    }
+
+    fn results() -> Vec<ResultMessage> {
+        let candle = Candle::new(60_000, 1.5, 2.0, 2.5, 1.0).with_volume(3.0, 6.0);
+        let mut strings = ResultData::from(candle);
+        strings.format = OutputFormat {
+            time_format: TimeFormat::Iso8601,
+            precision: Some(2),
+            string_prices: true,
+            encoder: OutputEncoder::Json,
+        };
+        let mut extras = ResultData::from(candle);
+        extras.corr = Some(-0.5);
+        extras
+            .flow
+            .insert("btcusdt@kline_1m".into(), TakerFlow::default());
+        extras.indicators = vec![("rsi", Some(55.0)), ("macd", None)];
+        vec![
+            ResultMessage {
+                closed: true,
+                ..result(ResultData::from(candle))
+            },
+            result(strings),
+            ResultMessage {
+                id: Some(3),
+                alias: Some("btc".into()),
+                latency_ms: Some(12),
+                snapshot: true,
+                ..result(extras)
+            },
+            ResultMessage {
+                partial: true,
+                out_of_order: true,
+                missing: vec!["ethusdt@1m".into()],
+                ..result(ResultData::missing(0))
+            },
+        ]
+    }
+
+    fn rule() -> AlertRule {
+        AlertRule {
+            field: AlertField::H,
+            op: Comparison::Above,
+            threshold: 50_000.0,
+            hysteresis: 100.0,
+            cooldown_secs: 300,
+        }
+    }
+
+    fn statuses() -> Vec<StatusMessage> {
+        let mut statuses: Vec<StatusMessage> = [
+            SubscriptionState::Connecting,
+            SubscriptionState::Backfilling { bars: 10 },
+            SubscriptionState::Subscribed,
+            SubscriptionState::Reconnecting { attempt: 2 },
+            SubscriptionState::Closed {
+                reason: CloseReason::UpstreamFailed,
+            },
+            SubscriptionState::Closed {
+                reason: CloseReason::TtlExpired,
+            },
+        ]
+        .iter()
+        .map(|state| state.status("btcusdt@1m"))
+        .collect();
+        statuses.extend([
+            StatusMessage {
+                event: "gap_detected".into(),
+                from: Some(0),
+                to: Some(120_000),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                id: Some(4),
+                event: "list".into(),
+                subscriptions: Some(vec!["btcusdt@1m".into()]),
+                definitions: Some(BTreeMap::from([("btc".into(), "btcusdt".into())])),
+                evaluators: Some(BTreeMap::from([(
+                    "btcusdt@1m".into(),
+                    EvaluatorStats {
+                        last_kline_at: Some(1),
+                        ..EvaluatorStats::default()
+                    },
+                )])),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                id: Some(5),
+                event: "list".into(),
+                alerts: Some(vec![AlertInfo {
+                    id: 6,
+                    stream: "btcusdt@1m".into(),
+                    rule: rule(),
+                    scope: AlertScope::Connection,
+                }]),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                id: Some(6),
+                event: "alert".into(),
+                message: "h 50001 crossed above 50000".into(),
+                candle: Some(Box::new(ResultData::from(Candle::new(
+                    0, 49_000.0, 50_001.0, 50_001.0, 48_000.0,
+                )))),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                event: "draining".into(),
+                deadline: Some(1_700_000_000_000),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                event: "rebased".into(),
+                bases: Some(BTreeMap::from([("btcusdt@kline_1m".into(), 7.0)])),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                event: "info".into(),
+                info: Some(Box::new(ServerInfo {
+                    protocol_versions: vec![1, 2, 3],
+                    limits: ServerLimits {
+                        compress_above: Some(1024),
+                        ..ServerLimits::default()
+                    },
+                    features: ServerFeatures {
+                        sinks: vec!["redis".into()],
+                        ..ServerFeatures::default()
+                    },
+                    ..ServerInfo::default()
+                })),
+                ..StatusMessage::default()
+            },
+            StatusMessage {
+                event: "schema".into(),
+                schema: Some(schemas(Version::V2)),
+                ..StatusMessage::default()
+            },
+        ]);
+        statuses
+    }
+
+    #[test]
+    fn test_emitted_results_match() {
+        for version in [Version::V1, Version::V2, Version::V3] {
+            let schema = &schemas(version)["result"];
+            for message in results() {
+                let frame = emitted(version, &ServerMessage::Result(message));
+                validate(schema, &frame, "result").unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_delta_results_match() {
+        for version in [Version::V2, Version::V3] {
+            let emitter = Emitter::new(OutputEncoder::Json, version);
+            let mut delta = Delta::new();
+            for close in [2.0, 2.25] {
+                let candle = Candle::new(0, 1.5, close, 2.5, 1.0);
+                let mut buffer = Vec::new();
+                let message = ServerMessage::Result(result(ResultData::from(candle)));
+                emitter
+                    .encode_edited(&message, |value| delta.apply(value, false), &mut buffer)
+                    .unwrap();
+                let frame: Value = serde_json::from_slice(&buffer).unwrap();
+                validate(&schemas(version)["result"], &frame, "result").unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_emitted_statuses_match() {
+        for version in [Version::V1, Version::V2, Version::V3] {
+            let schemas = schemas(version);
+            for status in statuses() {
+                let frame = emitted(version, &ServerMessage::Status(status));
+                validate(&schemas["status"], &frame, "status").unwrap();
+            }
+            let error =
+                StatusMessage::error("btcusdt@1x".into(), &ServerError::UnsupportedVersion(3));
+            let frame = emitted(version, &ServerMessage::Status(error));
+            validate(&schemas["error"], &frame, "error").unwrap();
+            validate(&schemas["status"], &frame, "status").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_requests_match() {
+        let request = Request {
+            id: 1,
+            method: "SUBSCRIBE".into(),
+            version: Some(2),
+            stream: "btcusdt+ethusdt@1m".into(),
+            name: Some("pair".into()),
+            expr: Some("btcusdt+ethusdt".into()),
+            alias: Some("pair".into()),
+            precision: Some(2),
+            string_prices: true,
+            resample: Some("5m".into()),
+            session_offset: Some("+02:00".into()),
+            session_start: Some("09:30".into()),
+            batch_ms: Some(100),
+            format: Some(OutputEncoder::Cbor),
+            quiet: true,
+            closed_only: true,
+            emit: Emit::OnChange,
+            mode: ResultMode::Delta,
+            latency: true,
+            rebase: true,
+            ttl_secs: Some(3600),
+            path: Some("btcusdt.parquet".into()),
+            from: Some(0),
+            to: Some(60_000),
+            row_group_rows: Some(1000),
+            correlation: Some(CorrelationOptions { window: 50 }),
+            webhook: Some(WebhookTarget {
+                url: "http://127.0.0.1:8080/hook".into(),
+                headers: [("Authorization".into(), "Bearer abc".into())].into(),
+            }),
+            composite_semantics: CompositeSemantics::Bound,
+            align_tolerance_ms: 500,
+            rule: Some(rule()),
+            scope: AlertScope::Server,
+            indicators: vec![
+                IndicatorSpec::Volatility {
+                    window: 30,
+                    annualize: true,
+                },
+                IndicatorSpec::Bollinger {
+                    period: 20,
+                    stddev: 2.0,
+                },
+                IndicatorSpec::Rsi { period: 14 },
+                IndicatorSpec::Atr { period: 14 },
+                IndicatorSpec::Macd {
+                    fast: 12,
+                    slow: 26,
+                    signal: 9,
+                },
+            ],
+            ..Request::default()
+        };
+        let schema = &schemas(Version::V1)["request"];
+        validate(schema, &serde_json::to_value(&request).unwrap(), "request").unwrap();
+        let minimal = json!({"id": 1, "method": "GET_SCHEMA"});
+        validate(schema, &minimal, "request").unwrap();
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let schemas = schemas(Version::V1);
+        let mut frame = emitted(Version::V1, &ServerMessage::Result(results().remove(0)));
+        frame["data"]["x"] = json!(1);
+        assert_eq!(
+            validate(&schemas["result"], &frame, "result").unwrap_err(),
+            "result.data.x: unknown field"
+        );
+
+        let tagged = emitted(Version::V2, &ServerMessage::Result(results().remove(0)));
+        assert!(validate(&schemas["result"], &tagged, "result").is_err());
+
+        let error = emitted(Version::V1, &ServerMessage::Status(statuses().remove(0)));
+        assert!(validate(&schemas["error"], &error, "error").is_err());
+    }
+
+    #[test]
+    fn test_documents() {
+        let schemas = schemas(Version::V2);
+        assert_eq!(schemas["version"], 2);
+        for kind in ["request", "result", "error", "status"] {
+            assert_eq!(schemas[kind]["$schema"], super::DIALECT);
+        }
+        assert_eq!(schemas["result"]["properties"]["type"]["const"], "result");
+        assert!(schemas["request"]["properties"].get("v").is_none());
+
+        let schemas = super::schemas(Version::V3);
+        let result = &schemas["result"];
+        assert_eq!(result["properties"]["type"]["const"], "candle");
+        assert_eq!(
+            result["required"],
+            json!(["v", "type", "stream", "id", "data"])
+        );
+        let data = &result["properties"]["data"];
+        assert_eq!(data["required"], json!(["t"]));
+        for field in crate::emitter::RESULT_FLAGS.into_iter().chain(["c"]) {
+            assert!(data["properties"].get(field).is_some(), "{}", field);
+        }
+        let error = &schemas["error"]["properties"]["data"];
+        assert_eq!(error["required"], json!(["event", "message", "code"]));
+    }
+}
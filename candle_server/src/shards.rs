@@ -0,0 +1,102 @@
+//! A fixed pool of threads that evaluators are pinned to by the hash of
+//! their canonical key. Each thread runs a runtime of its own, so an
+//! expensive expression only holds up the evaluators of its shard, while
+//! every evaluator still runs as one task and emits strictly in order.
+
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Default)]
+pub struct Shards {
+    handles: Vec<Handle>,
+    // Dropping these lets each thread's runtime, and its tasks, go
+    _running: Vec<oneshot::Sender<()>>,
+}
+
+impl Shards {
+    /// Starts `count` shard threads. With none, `spawn` leaves evaluators
+    /// to the runtime it's called from.
+    pub fn new(count: usize) -> Shards {
+        let mut shards = Shards::default();
+        for index in 0..count {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("evaluator shard runtime");
+            let (running, stopped) = oneshot::channel::<()>();
+            shards.handles.push(runtime.handle().clone());
+            shards._running.push(running);
+            std::thread::Builder::new()
+                .name(format!("evaluator-shard-{}", index))
+                .spawn(move || {
+                    let _ = runtime.block_on(stopped);
+                })
+                .expect("evaluator shard thread");
+        }
+        shards
+    }
+
+    pub fn len(&self) -> usize {
+        self.handles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handles.is_empty()
+    }
+
+    /// Shard `key` is evaluated on.
+    pub fn shard(&self, key: &str) -> Option<usize> {
+        if self.handles.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some((hasher.finish() % self.handles.len() as u64) as usize)
+    }
+
+    /// Runs `future` on the shard of `key`, or on the current runtime when
+    /// there are no shards.
+    pub fn spawn<F>(&self, key: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        match self.shard(key) {
+            Some(shard) => self.handles[shard].spawn(future),
+            None => tokio::spawn(future),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shards;
+
+    #[tokio::test]
+    async fn test_keys_stay_on_their_shard() {
+        let shards = Shards::new(3);
+        assert_eq!(shards.len(), 3);
+        let shard = shards.shard("btcusdt+ethusdt@1m").unwrap();
+        assert_eq!(shards.shard("btcusdt+ethusdt@1m"), Some(shard));
+
+        let name = || std::thread::current().name().map(str::to_string);
+        let ran = shards.spawn("btcusdt+ethusdt@1m", async move { name() });
+        assert_eq!(
+            ran.await.unwrap(),
+            Some(format!("evaluator-shard-{}", shard))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_without_shards_tasks_run_here() {
+        let shards = Shards::new(0);
+        assert_eq!(shards.shard("btcusdt@1m"), None);
+        let here = std::thread::current().id();
+        let ran = shards.spawn("btcusdt@1m", async move { std::thread::current().id() });
+        assert_eq!(ran.await.unwrap(), here);
+    }
+}
@@ -0,0 +1,307 @@
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::memory::MemoryAccount;
+use crate::protocol::{OutputFormat, ResultMessage, ServerMessage};
+
+/// Records a sink task may fall behind by before new ones are dropped.
+pub const SINK_QUEUE_CAPACITY: usize = 1024;
+
+/// A result of one expression as handed to sinks.
+#[derive(Debug, Clone)]
+pub struct SinkRecord {
+    // `canonical_key` of the expression, also the result's `stream`
+    pub key: String,
+    pub message: ResultMessage,
+}
+
+impl SinkRecord {
+    // Approximate bytes it holds while queued for a sink
+    fn size(&self) -> usize {
+        std::mem::size_of::<SinkRecord>()
+            + self.key.len()
+            + self.message.stream.len()
+            + self.message.alias.as_ref().map_or(0, String::len)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SinkCounters {
+    pub published: AtomicU64,
+    // Records the sink failed to deliver
+    pub failed: AtomicU64,
+    // Records never handed to the sink because it was behind or gone
+    pub dropped: AtomicU64,
+    // Whether the sink currently holds a working connection
+    pub connected: AtomicBool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SinkStats {
+    pub published: u64,
+    pub failed: u64,
+    pub dropped: u64,
+    pub connected: bool,
+    // Approximate bytes of the records waiting for it
+    pub buffered_bytes: usize,
+}
+
+/// Asks a sink task to deliver what it holds now, answered once it has
+/// tried.
+pub type FlushRequest = oneshot::Sender<()>;
+
+/// Sending side of a sink task, shared by every expression's tap.
+#[derive(Debug, Clone)]
+pub struct SinkHandle {
+    name: &'static str,
+    tx: mpsc::Sender<Arc<SinkRecord>>,
+    counters: Arc<SinkCounters>,
+    memory: MemoryAccount,
+    // Set for sinks holding records back, e.g. to batch them
+    flush: Option<mpsc::Sender<FlushRequest>>,
+}
+
+/// Receiving side of a sink task, releasing what it takes from `memory`.
+#[derive(Debug)]
+pub struct SinkReceiver {
+    rx: mpsc::Receiver<Arc<SinkRecord>>,
+    memory: MemoryAccount,
+}
+
+impl SinkReceiver {
+    pub async fn recv(&mut self) -> Option<Arc<SinkRecord>> {
+        let record = self.rx.recv().await?;
+        self.memory.release(record.size());
+        Some(record)
+    }
+
+    pub fn try_recv(&mut self) -> Option<Arc<SinkRecord>> {
+        let record = self.rx.try_recv().ok()?;
+        self.memory.release(record.size());
+        Some(record)
+    }
+}
+
+impl Drop for SinkReceiver {
+    fn drop(&mut self) {
+        self.rx.close();
+        while self.try_recv().is_some() {}
+    }
+}
+
+impl SinkHandle {
+    /// Records waiting for the sink are charged to `memory`.
+    pub fn new(
+        name: &'static str,
+        capacity: usize,
+        memory: MemoryAccount,
+    ) -> (SinkHandle, SinkReceiver) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let handle = SinkHandle {
+            name,
+            tx,
+            counters: Arc::default(),
+            memory: memory.clone(),
+            flush: None,
+        };
+        (handle, SinkReceiver { rx, memory })
+    }
+
+    /// Lets `flush` reach the sink task, through the receiver returned.
+    pub fn with_flush(mut self) -> (SinkHandle, mpsc::Receiver<FlushRequest>) {
+        let (tx, rx) = mpsc::channel(1);
+        self.flush = Some(tx);
+        (self, rx)
+    }
+
+    /// Waits for the sink to deliver what it holds, e.g. before shutting
+    /// down. Returns right away for a sink that holds nothing back.
+    pub async fn flush(&self) {
+        let Some(flush) = &self.flush else {
+            return;
+        };
+        let (done, flushed) = oneshot::channel();
+        if flush.send(done).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn counters(&self) -> &Arc<SinkCounters> {
+        &self.counters
+    }
+
+    /// Hands `record` to the sink without waiting, so a slow or broken sink
+    /// never holds up clients. Over the memory budget, a sink holding its
+    /// share of it gets no more records until it has caught up.
+    pub fn offer(&self, record: Arc<SinkRecord>) {
+        let size = record.size();
+        if self.memory.must_shed() {
+            self.memory.shed(size);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        // Charged first, as the sink task may take it right away
+        self.memory.charge(size);
+        if self.tx.try_send(record).is_err() {
+            self.memory.release(size);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> SinkStats {
+        SinkStats {
+            published: self.counters.published.load(Ordering::Relaxed),
+            failed: self.counters.failed.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            connected: self.counters.connected.load(Ordering::Relaxed),
+            buffered_bytes: self.memory.bytes(),
+        }
+    }
+}
+
+/// Stats of every sink by name.
+pub fn stats(sinks: &[SinkHandle]) -> BTreeMap<&'static str, SinkStats> {
+    sinks
+        .iter()
+        .map(|sink| (sink.name(), sink.stats()))
+        .collect()
+}
+
+/// Copies every result of one expression to the sinks until its results
+/// channel closes. Results are labelled with `key` and rounded at
+/// `precision`, the way a client with default options sees them.
+pub async fn tap(
+    key: String,
+    precision: Option<u32>,
+    mut rx: broadcast::Receiver<ServerMessage>,
+    sinks: Vec<SinkHandle>,
+) {
+    loop {
+        let mut message = match rx.recv().await {
+            Ok(ServerMessage::Result(message)) => message,
+            Ok(ServerMessage::Status(_) | ServerMessage::Ticker(_)) => continue,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Sinks lagging on {}, skipped {} results", key, skipped);
+                for sink in &sinks {
+                    sink.counters.dropped.fetch_add(skipped, Ordering::Relaxed);
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+
+        message.stream = key.clone();
+        message.data.format = OutputFormat {
+            precision,
+            ..OutputFormat::default()
+        };
+        let record = Arc::new(SinkRecord {
+            key: key.clone(),
+            message,
+        });
+        for sink in &sinks {
+            sink.offer(record.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tap, SinkHandle};
+    use crate::candle::Candle;
+    use crate::memory::{MemoryAccount, MemoryBudget};
+    use crate::protocol::{ResultData, ResultMessage, ServerMessage, StatusMessage};
+    use std::sync::Arc;
+    use tokio::sync::broadcast;
+
+    fn result(price: f64) -> ServerMessage {
+        ServerMessage::Result(ResultMessage {
+            id: None,
+            stream: "ETHUSDT + btcusdt@1m".into(),
+            data: ResultData::from(Candle::new(0, price, price, price, price)),
+            closed: false,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+            alias: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tap_labels_results_with_key() {
+        let (tx, rx) = broadcast::channel(8);
+        let (sink, mut records) = SinkHandle::new("test", 8, MemoryAccount::default());
+        let tapping = tokio::spawn(tap(
+            "btcusdt+ethusdt@1m".into(),
+            Some(2),
+            rx,
+            vec![sink.clone()],
+        ));
+
+        tx.send(ServerMessage::Status(StatusMessage {
+            stream: String::new(),
+            event: "gap".into(),
+            message: String::new(),
+            ..StatusMessage::default()
+        }))
+        .unwrap();
+        tx.send(result(1.005)).unwrap();
+        drop(tx);
+        tapping.await.unwrap();
+
+        let record = records.recv().await.unwrap();
+        assert_eq!(record.key, "btcusdt+ethusdt@1m");
+        let json = serde_json::to_value(&record.message).unwrap();
+        assert_eq!(json["stream"], "btcusdt+ethusdt@1m");
+        assert_eq!(json["data"]["c"], 1.0);
+        assert!(records.try_recv().is_none());
+        assert_eq!(sink.stats().buffered_bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn test_full_sink_drops_instead_of_waiting() {
+        let (tx, rx) = broadcast::channel(8);
+        let (sink, _records) = SinkHandle::new("test", 1, MemoryAccount::default());
+        let tapping = tokio::spawn(tap("btcusdt@1m".into(), None, rx, vec![sink.clone()]));
+
+        for price in [1.0, 2.0, 3.0] {
+            tx.send(result(price)).unwrap();
+        }
+        drop(tx);
+        tapping.await.unwrap();
+
+        assert_eq!(sink.stats().dropped, 2);
+    }
+
+    #[tokio::test]
+    async fn test_sink_holding_the_budget_gets_no_more() {
+        let budget = Arc::new(MemoryBudget::new(None));
+        let (sink, mut records) = SinkHandle::new("test", 8, MemoryAccount::new(&budget, "sink"));
+        let (tx, rx) = broadcast::channel(8);
+        let tapping = tokio::spawn(tap("btcusdt@1m".into(), None, rx, vec![sink.clone()]));
+        tx.send(result(1.0)).unwrap();
+        while sink.stats().buffered_bytes == 0 {
+            tokio::task::yield_now().await;
+        }
+        let held = budget.used();
+
+        budget.set_limit(Some(held - 1));
+        tx.send(result(2.0)).unwrap();
+        drop(tx);
+        tapping.await.unwrap();
+        assert_eq!(sink.stats().dropped, 1);
+        assert_eq!(budget.stats().shed, held as u64);
+
+        records.recv().await.unwrap();
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,181 @@
+use log::{error, info};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{interval_at, sleep, Duration, Instant};
+
+use crate::error::ServerError;
+use crate::protocol::{ServerMessage, StatusMessage};
+use crate::server::Server;
+
+// Route served as Server-Sent Events on the WebSocket listener
+const ROUTE: &[u8] = b"GET /sse";
+// Longest request head accepted, request line and headers together
+const MAX_HEAD: usize = 8 * 1024;
+
+const RESPONSE_HEAD: &str = "HTTP/1.1 200 OK\r\n\
+Content-Type: text/event-stream\r\n\
+Cache-Control: no-cache\r\n\
+Connection: keep-alive\r\n\r\n";
+
+/// Whether the request waiting on `socket` is for the SSE route, without
+/// consuming any of it.
+pub async fn is_sse_request(socket: &TcpStream) -> bool {
+    requests_route(socket, ROUTE).await
+}
+
+// Whether the request waiting on `socket` starts with `route`, e.g.
+// `GET /sse`, followed by its query or the end of the target
+pub(crate) async fn requests_route(socket: &TcpStream, route: &[u8]) -> bool {
+    let mut head = vec![0; route.len() + 1];
+    // The request line may arrive over several segments
+    for _ in 0..50 {
+        match socket.peek(&mut head).await {
+            Ok(n) if n == head.len() => {
+                return head.starts_with(route) && matches!(head[route.len()], b'?' | b' ');
+            }
+            Ok(n) if n > 0 && route.starts_with(&head[..n]) => {
+                sleep(Duration::from_millis(10)).await
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+/// Streams one expression's results to an `EventSource`: each result is a
+/// `data:` line, status events are `event: status`, and a comment goes out
+/// every `heartbeat` so proxies keep the connection open.
+pub async fn serve(
+    server: Server,
+    socket: TcpStream,
+    heartbeat: Duration,
+) -> Result<(), ServerError> {
+    let (read, mut write) = socket.into_split();
+    let mut read = BufReader::new(read);
+    let target = read_target(&mut read).await?;
+    write.write_all(RESPONSE_HEAD.as_bytes()).await?;
+
+    let stream = stream_param(&target);
+    let subscribed = match &stream {
+        Some(stream) => server.subscribe(stream).await,
+        None => Err(ServerError::InvalidMessage(
+            "missing stream parameter".into(),
+        )),
+    };
+    let mut subscription = match subscribed {
+        Ok(subscription) => subscription,
+        Err(e) => {
+            info!("Rejected SSE subscription {:?}: {}", stream, e);
+            let status = StatusMessage::error(stream.unwrap_or_default(), &e);
+            let event = format!(
+                "event: error\ndata: {}\n\n",
+                serde_json::to_string(&status)?
+            );
+            write.write_all(event.as_bytes()).await?;
+            return Ok(());
+        }
+    };
+
+    let mut heartbeats = interval_at(Instant::now() + heartbeat, heartbeat);
+    let mut discard = String::new();
+    let result = loop {
+        let event = tokio::select! {
+            // Errors break rather than return, so the subscription is
+            // released below
+            received = subscription.recv() => match received.as_ref().map(event) {
+                Some(Ok(event)) => event,
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            _ = heartbeats.tick() => ": heartbeat\n\n".to_string(),
+            // `EventSource` never sends after its request, so anything
+            // here is the connection closing
+            _ = read.read_line(&mut discard) => break Ok(()),
+        };
+        if let Err(e) = write.write_all(event.as_bytes()).await {
+            break Err(ServerError::from(e));
+        }
+    };
+
+    if let Err(e) = subscription.unsubscribe().await {
+        error!("Error releasing SSE subscription: {}", e);
+    }
+    result
+}
+
+// `message` as an event; results are the unnamed ones
+fn event(message: &ServerMessage) -> Result<String, ServerError> {
+    Ok(match message {
+        ServerMessage::Result(result) => format!("data: {}\n\n", serde_json::to_string(result)?),
+        ServerMessage::Status(status) => {
+            format!(
+                "event: status\ndata: {}\n\n",
+                serde_json::to_string(status)?
+            )
+        }
+        ServerMessage::Ticker(ticker) => {
+            format!(
+                "event: ticker\ndata: {}\n\n",
+                serde_json::to_string(ticker)?
+            )
+        }
+    })
+}
+
+// Reads the request head and returns its target, e.g. `/sse?stream=...`
+pub(crate) async fn read_target<R>(read: &mut R) -> Result<String, ServerError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let too_large = || ServerError::InvalidMessage("request head incomplete or too large".into());
+    let mut head = read.take(MAX_HEAD as u64);
+    let mut request_line = String::new();
+    head.read_line(&mut request_line).await?;
+    if !request_line.ends_with('\n') {
+        return Err(too_large());
+    }
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or_else(|| ServerError::InvalidMessage("malformed request line".into()))?
+        .to_string();
+
+    // Headers carry nothing the route needs
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if head.read_line(&mut line).await? == 0 {
+            return Err(too_large());
+        }
+        if line.trim_end().is_empty() {
+            return Ok(target);
+        }
+    }
+}
+
+// Decoded `stream` query parameter of `target`
+fn stream_param(target: &str) -> Option<String> {
+    let (_, query) = target.split_once('?')?;
+    url::form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "stream")
+        .map(|(_, stream)| stream.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stream_param;
+
+    #[test]
+    fn test_stream_param_is_decoded() {
+        assert_eq!(
+            stream_param("/sse?stream=btcusdt%2Bethusdt%401m").as_deref(),
+            Some("btcusdt+ethusdt@1m")
+        );
+        assert_eq!(
+            stream_param("/sse?x=1&stream=btcusdt-ethusdt@1m").as_deref(),
+            Some("btcusdt-ethusdt@1m")
+        );
+        assert_eq!(stream_param("/sse"), None);
+        assert_eq!(stream_param("/sse?streams=btcusdt@1m"), None);
+    }
+}
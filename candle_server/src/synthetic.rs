@@ -0,0 +1,338 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use tokio::time::Duration;
+
+use crate::align::bar_start;
+use crate::candle::Candle;
+use crate::expr::{is_kline_stream, stream_interval, TICKER_INTERVAL, TICKER_STATS_INTERVAL};
+use crate::protocol::TickerStats;
+use crate::upstream::UpstreamEvent;
+use crate::utils::{interval_to_millis, MILLIS_PER_DAY};
+
+/// How `--synthetic` mode makes up its market, in place of Binance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticConfig {
+    // Seeds every symbol's walk; unset, one is picked at startup, and
+    // `GET_INFO` reports it either way
+    pub seed: Option<u64>,
+    // How often every stream gets an update
+    pub tick: Duration,
+    // Standard deviation of the relative price change of one tick
+    pub volatility: f64,
+}
+
+impl Default for SyntheticConfig {
+    fn default() -> Self {
+        SyntheticConfig {
+            seed: None,
+            tick: Duration::from_secs(1),
+            volatility: 0.001,
+        }
+    }
+}
+
+// One symbol's price, walking on from its own RNG so it's the same whatever
+// else is subscribed
+struct Walk {
+    rng: StdRng,
+    price: f64,
+    // Totals of the day, for mini tickers and tickers
+    day: u64,
+    open: f64,
+    high: f64,
+    low: f64,
+    volume: f64,
+    quote_volume: f64,
+    trades: u64,
+}
+
+// What one tick of a walk traded
+#[derive(Clone, Copy)]
+struct Trade {
+    price: f64,
+    volume: f64,
+    taker_volume: f64,
+    trades: u64,
+}
+
+/// Random-walk market for any symbol, stepped one tick at a time. Kline
+/// streams get an update of their open bar every tick and the bar closed
+/// once a tick lands in the next one, as Binance sends them; tickers and
+/// book tickers get the walk's latest price.
+pub struct Generator {
+    seed: u64,
+    volatility: f64,
+    walks: HashMap<String, Walk>,
+    // Open bar of every kline stream
+    bars: HashMap<String, Candle>,
+}
+
+impl Generator {
+    pub fn new(seed: u64, volatility: f64) -> Generator {
+        Generator {
+            seed,
+            volatility,
+            walks: HashMap::new(),
+            bars: HashMap::new(),
+        }
+    }
+
+    /// Steps every symbol of `streams` once at `time`, in Unix millis, and
+    /// returns what each stream gets, in order. Streams it can't make up,
+    /// e.g. open interest, get nothing.
+    pub fn tick<'a>(
+        &mut self,
+        time: u64,
+        streams: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<(String, UpstreamEvent)> {
+        let streams: Vec<&str> = streams.into_iter().collect();
+        self.bars
+            .retain(|stream, _| streams.contains(&stream.as_str()));
+
+        let mut trades = HashMap::new();
+        let mut events = Vec::new();
+        for stream in streams {
+            let Some((symbol, kind)) = stream.split_once('@') else {
+                continue;
+            };
+            let trade = *trades
+                .entry(symbol)
+                .or_insert_with(|| self.step(symbol, time));
+            let walk = &self.walks[symbol];
+            let event = match (kind, stream_interval(stream)) {
+                ("bookTicker", _) => UpstreamEvent::Mid {
+                    time,
+                    price: trade.price,
+                },
+                (_, TICKER_INTERVAL) => {
+                    let candle = Candle::new(
+                        time - time % 1000,
+                        walk.open,
+                        walk.price,
+                        walk.high,
+                        walk.low,
+                    )
+                    .with_volume(walk.volume, walk.quote_volume);
+                    UpstreamEvent::Kline(Candle {
+                        event_time: time,
+                        ..candle
+                    })
+                }
+                (_, TICKER_STATS_INTERVAL) => UpstreamEvent::Ticker(TickerStats {
+                    t: time,
+                    last_price: walk.price,
+                    price_change: walk.price - walk.open,
+                    price_change_pct: (walk.price - walk.open) / walk.open * 100.0,
+                    weighted_avg_price: walk.quote_volume / walk.volume,
+                    open: walk.open,
+                    high: walk.high,
+                    low: walk.low,
+                    volume: walk.volume,
+                    quote_volume: walk.quote_volume,
+                    trades: walk.trades,
+                }),
+                (_, interval) if is_kline_stream(stream) => {
+                    match interval_to_millis(interval, time) {
+                        Ok(length) => {
+                            if let Some(closed) =
+                                self.trade_bar(stream, interval, length, time, trade)
+                            {
+                                events.push((stream.to_string(), UpstreamEvent::Kline(closed)));
+                            }
+                            UpstreamEvent::Kline(self.bars[stream])
+                        }
+                        Err(_) => continue,
+                    }
+                }
+                _ => continue,
+            };
+            events.push((stream.to_string(), event));
+        }
+        events
+    }
+
+    // Moves `symbol`'s walk one tick on, starting it if it's new
+    fn step(&mut self, symbol: &str, time: u64) -> Trade {
+        let seed = self.seed ^ fnv1a(symbol);
+        let walk = self.walks.entry(symbol.to_string()).or_insert_with(|| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            // Somewhere from cents to tens of thousands, as listed pairs are
+            let price = 10f64.powf(rng.gen_range(-1.0..4.5));
+            Walk {
+                rng,
+                price,
+                day: time / MILLIS_PER_DAY,
+                open: price,
+                high: price,
+                low: price,
+                volume: 0.0,
+                quote_volume: 0.0,
+                trades: 0,
+            }
+        });
+
+        // Box-Muller, for a normally distributed log return
+        let (u, v): (f64, f64) = (walk.rng.gen_range(f64::EPSILON..1.0), walk.rng.gen());
+        let z = (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos();
+        walk.price *= (self.volatility * z).exp();
+        let trade = Trade {
+            price: walk.price,
+            // A notional of about a thousand quote units per tick
+            volume: walk.rng.gen_range(0.0..2000.0) / walk.price,
+            taker_volume: 0.0,
+            trades: walk.rng.gen_range(1..20),
+        };
+        let trade = Trade {
+            taker_volume: trade.volume * walk.rng.gen_range(0.0..1.0),
+            ..trade
+        };
+
+        // The day's totals start over at midnight rather than rolling
+        if time / MILLIS_PER_DAY != walk.day {
+            walk.day = time / MILLIS_PER_DAY;
+            walk.open = walk.price;
+            (walk.high, walk.low) = (walk.price, walk.price);
+            (walk.volume, walk.quote_volume, walk.trades) = (0.0, 0.0, 0);
+        }
+        walk.high = walk.high.max(walk.price);
+        walk.low = walk.low.min(walk.price);
+        walk.volume += trade.volume;
+        walk.quote_volume += trade.volume * trade.price;
+        walk.trades += trade.trades;
+        trade
+    }
+
+    // Folds `trade` into `stream`'s open bar, first closing it when `time`
+    // is past it; returns the bar it closed
+    fn trade_bar(
+        &mut self,
+        stream: &str,
+        interval: &str,
+        length: u64,
+        time: u64,
+        trade: Trade,
+    ) -> Option<Candle> {
+        let start = bar_start(time, interval, length);
+        let mut closed = None;
+        let bar = self.bars.entry(stream.to_string()).or_insert_with(|| {
+            Candle::new(start, trade.price, trade.price, trade.price, trade.price)
+        });
+        if bar.t < start {
+            closed = Some(bar.with_closed(true));
+            *bar = Candle::new(start, bar.c, bar.c, bar.c, bar.c);
+        }
+        bar.c = trade.price;
+        bar.h = bar.h.max(trade.price);
+        bar.l = bar.l.min(trade.price);
+        bar.v += trade.volume;
+        bar.q += trade.volume * trade.price;
+        bar.taker_v += trade.taker_volume;
+        bar.taker_q += trade.taker_volume * trade.price;
+        bar.n += trade.trades;
+        bar.event_time = time;
+        closed
+    }
+}
+
+// Stable across builds and platforms, unlike `DefaultHasher`, so a seed
+// gives the same walks wherever it runs
+fn fnv1a(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Generator;
+    use crate::candle::Candle;
+    use crate::upstream::UpstreamEvent;
+
+    const STREAMS: [&str; 3] = ["btcusdt@kline_1m", "btcusdt@kline_5m", "ethusdt@kline_1m"];
+
+    fn klines(events: Vec<(String, UpstreamEvent)>) -> Vec<(String, Candle)> {
+        events
+            .into_iter()
+            .map(|(stream, event)| match event {
+                UpstreamEvent::Kline(candle) => (stream, candle),
+                other => panic!("expected a kline, got {:?}", other),
+            })
+            .collect()
+    }
+
+    fn run(seed: u64, ticks: u64) -> Vec<(String, Candle)> {
+        let mut generator = Generator::new(seed, 0.01);
+        (0..ticks)
+            .flat_map(|tick| klines(generator.tick(tick * 10_000, STREAMS)))
+            .collect()
+    }
+
+    #[test]
+    fn test_a_seed_reproduces_the_walk() {
+        let first = run(7, 50);
+        let again = run(7, 50);
+        let prices = |run: &[(String, Candle)]| run.iter().map(|(_, c)| c.c).collect::<Vec<_>>();
+        assert_eq!(prices(&first), prices(&again));
+        assert_ne!(prices(&first), prices(&run(8, 50)));
+    }
+
+    #[test]
+    fn test_bars_update_every_tick_and_close_once() {
+        let events = run(1, 13);
+        let one_minute = |t| {
+            events
+                .iter()
+                .filter(move |(stream, c)| stream == "btcusdt@kline_1m" && c.t == t)
+                .map(|(_, c)| *c)
+                .collect::<Vec<_>>()
+        };
+
+        // Six updates at 10s ticks, then the closed bar as the next opens
+        let first = one_minute(0);
+        assert_eq!(first.len(), 7);
+        assert!(first[..6].iter().all(|c| !c.closed));
+        let closed = first[6];
+        assert!(closed.closed);
+        assert_eq!(closed.c, first[5].c);
+        assert!(closed.h >= closed.o.max(closed.c) && closed.l <= closed.o.min(closed.c));
+        assert!(closed.v > 0.0 && closed.n > 0 && closed.taker_v <= closed.v);
+        let next = one_minute(60_000);
+        assert_eq!(next[0].o, closed.c);
+
+        // Intervals of one symbol follow the same walk
+        let (_, five) = events
+            .iter()
+            .rfind(|(stream, _)| stream == "btcusdt@kline_5m")
+            .unwrap();
+        let (_, one) = events
+            .iter()
+            .rfind(|(stream, _)| stream == "btcusdt@kline_1m")
+            .unwrap();
+        assert_eq!(five.c, one.c);
+        assert_eq!(five.o, first[0].o);
+    }
+
+    #[test]
+    fn test_tickers_and_book_tickers() {
+        let mut generator = Generator::new(3, 0.01);
+        let streams = ["btcusdt@bookTicker", "btcusdt@ticker", "btcusdt@miniTicker"];
+        generator.tick(1_000, streams);
+        let events = generator.tick(2_000, streams);
+        let [(_, UpstreamEvent::Mid { time, price }), (_, UpstreamEvent::Ticker(stats)), (_, UpstreamEvent::Kline(mini))] =
+            &events[..]
+        else {
+            panic!("unexpected events {:?}", events);
+        };
+        assert_eq!(*time, 2_000);
+        assert_eq!(stats.last_price, *price);
+        assert_eq!(mini.c, *price);
+        assert_eq!((stats.high, stats.volume), (mini.h, mini.v));
+        assert!(stats.high >= stats.low && stats.trades > 0);
+
+        // Nothing is made up for open interest
+        assert!(generator
+            .tick(3_000, ["btcusdt@openInterest_5m"])
+            .is_empty());
+    }
+}
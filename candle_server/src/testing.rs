@@ -0,0 +1,747 @@
+//! End-to-end test harness: a fake Binance playing scripted klines, the
+//! server started against it on an ephemeral port, and a client collecting
+//! what it's sent. Like Binance, the fake compresses its frames with
+//! permessage-deflate when the server offers it. Built for the crate's own
+//! tests and with the `test-util` feature; everything panics with a
+//! description instead of returning errors, as tests want. Failures beyond
+//! what a scenario scripts are injected through `TestServer::faults`.
+
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout, Duration};
+use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{accept_hdr_async, connect_async, MaybeTlsStream, WebSocketStream};
+
+use crate::backoff::BackoffConfig;
+use crate::candle::Candle;
+use crate::emitter::Version;
+use crate::expr::{self, canonical_key, stream_interval, Expr};
+use crate::fault::FaultInjector;
+use crate::grpc::{self, Frame};
+use crate::hpack;
+use crate::permessage::{self, Deflating, EXTENSIONS_HEADER, PERMESSAGE_DEFLATE};
+use crate::protobuf::{self, FieldValue};
+use crate::server::{Server, ServerConfig};
+use crate::upstream::decode_frame;
+use crate::utils::interval_to_millis;
+
+/// Longest any helper waits for a frame or for the server to settle.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reconnects within milliseconds, giving up after `max_attempts`.
+pub fn fast_backoff(max_attempts: Option<u32>) -> BackoffConfig {
+    BackoffConfig {
+        initial_delay: Duration::from_millis(10),
+        max_delay: Duration::from_millis(50),
+        max_attempts,
+        ..BackoffConfig::default()
+    }
+}
+
+/// Fuzzing entry point for client expressions: `data` through both
+/// parsers, the RPN conversion and evaluation, and the intervals of its
+/// legs, which must give errors rather than panic or hang.
+pub fn fuzz_expression(data: &[u8]) {
+    let Ok(input) = std::str::from_utf8(data) else {
+        return;
+    };
+    let candle = Candle::new(0, 1.0, 1.0, 1.0, 1.0);
+    if let Ok(rpn) = expr::parse(input).and_then(|tokens| expr::to_rpn(&tokens)) {
+        let candles = rpn
+            .symbols
+            .iter()
+            .map(|symbol| (symbol.to_string(), candle))
+            .collect();
+        let _ = expr::evaluate_rpn(&rpn, &candles);
+    }
+    if let Ok((expr, interval)) = Expr::parse(input) {
+        for stream in expr.streams(&interval) {
+            let _ = interval_to_millis(stream_interval(&stream), 0);
+        }
+        let _ = interval_to_millis(interval.as_str(), 0);
+        let _ = expr.eval(&|_| Some(candle));
+        let _ = expr.simplify();
+    }
+    let _ = canonical_key(input);
+}
+
+/// Fuzzing entry point for upstream frames, which must decode or give an
+/// error rather than panic.
+pub fn fuzz_upstream_frame(data: &[u8]) {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = decode_frame(text);
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    Kline(Candle),
+    Frame(String),
+    Wait(Duration),
+    Disconnect,
+}
+
+/// What the fake Binance sends on one stream, played from where it left off
+/// each time the stream is subscribed, so a scenario runs on across
+/// reconnects.
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn new() -> Scenario {
+        Scenario::default()
+    }
+
+    /// An update of the open bar at `t`, flat at `price`.
+    pub fn bar(self, t: u64, price: f64) -> Scenario {
+        self.kline(Candle::new(t, price, price, price, price).with_volume(1.0, price))
+    }
+
+    /// The closed bar at `t`, flat at `price`.
+    pub fn closed_bar(self, t: u64, price: f64) -> Scenario {
+        self.kline(
+            Candle::new(t, price, price, price, price)
+                .with_volume(1.0, price)
+                .with_closed(true),
+        )
+    }
+
+    /// `candle` as a Binance kline, closed when it is. Bars older than the
+    /// ones sent before make the stream out of order.
+    pub fn kline(mut self, candle: Candle) -> Scenario {
+        self.steps.push(Step::Kline(candle));
+        self
+    }
+
+    /// `text` sent as is, e.g. a malformed payload.
+    pub fn frame(mut self, text: impl Into<String>) -> Scenario {
+        self.steps.push(Step::Frame(text.into()));
+        self
+    }
+
+    /// An error reply as Binance sends one for a bad request.
+    pub fn error(self, code: i64, message: &str) -> Scenario {
+        self.frame(json!({"error": {"code": code, "msg": message}, "id": null}).to_string())
+    }
+
+    pub fn wait(mut self, duration: Duration) -> Scenario {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Hangs up the connection; the rest plays once the server resubscribes.
+    pub fn disconnect(mut self) -> Scenario {
+        self.steps.push(Step::Disconnect);
+        self
+    }
+}
+
+/// Binance kline frame of `stream`, e.g. `btcusdt@kline_1m`, carrying
+/// `candle`.
+pub fn kline_frame(stream: &str, candle: &Candle) -> String {
+    let symbol = stream.split('@').next().unwrap_or_default();
+    let interval = stream_interval(stream);
+    let length = interval_to_millis(interval, candle.t).unwrap_or(60_000);
+    let event_time = match candle.event_time {
+        0 => candle.t + 1,
+        time => time,
+    };
+    json!({
+        "stream": stream,
+        "data": {
+            "e": "kline", "E": event_time, "s": symbol.to_uppercase(),
+            "k": {
+                "t": candle.t, "T": candle.t + length - 1, "s": symbol.to_uppercase(),
+                "i": interval, "f": 1, "L": 1 + candle.n,
+                "o": candle.o.to_string(), "c": candle.c.to_string(),
+                "h": candle.h.to_string(), "l": candle.l.to_string(),
+                "v": candle.v.to_string(), "n": candle.n, "x": candle.closed,
+                "q": candle.q.to_string(), "V": candle.taker_v.to_string(),
+                "Q": candle.taker_q.to_string(), "B": "0"
+            }
+        }
+    })
+    .to_string()
+}
+
+/// A Binance stream endpoint playing a `Scenario` per stream. Streams
+/// without one are acknowledged and stay silent.
+#[derive(Debug)]
+pub struct FakeBinance {
+    scenarios: HashMap<String, Scenario>,
+    compression: bool,
+}
+
+impl Default for FakeBinance {
+    fn default() -> Self {
+        FakeBinance {
+            scenarios: HashMap::new(),
+            compression: true,
+        }
+    }
+}
+
+impl FakeBinance {
+    pub fn new() -> FakeBinance {
+        FakeBinance::default()
+    }
+
+    /// Declines permessage-deflate, sending every frame uncompressed.
+    pub fn without_compression(mut self) -> FakeBinance {
+        self.compression = false;
+        self
+    }
+
+    pub fn stream(mut self, stream: &str, scenario: Scenario) -> FakeBinance {
+        self.scenarios.insert(stream.to_string(), scenario);
+        self
+    }
+
+    pub async fn start(self) -> RunningFakeBinance {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let fake = Arc::new(Fake {
+            scenarios: self.scenarios,
+            played: Mutex::default(),
+            requests: Mutex::default(),
+            connections: AtomicUsize::new(0),
+            compressed: AtomicUsize::new(0),
+            open: AtomicUsize::new(0),
+        });
+        let serving = fake.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let mut compressed = false;
+                // The callback signature is tungstenite's
+                #[allow(clippy::result_large_err)]
+                let accept = |request: &Request, mut response: Response| {
+                    compressed = self.compression
+                        && request
+                            .headers()
+                            .get_all(EXTENSIONS_HEADER)
+                            .iter()
+                            .filter_map(|value| value.to_str().ok())
+                            .any(|value| permessage::accepted(value).is_some());
+                    if compressed {
+                        response.headers_mut().insert(
+                            EXTENSIONS_HEADER,
+                            HeaderValue::from_static(PERMESSAGE_DEFLATE),
+                        );
+                    }
+                    Ok(response)
+                };
+                let Ok(ws) = accept_hdr_async(Deflating::new(socket), accept).await else {
+                    continue;
+                };
+                serving.connections.fetch_add(1, Ordering::SeqCst);
+                if compressed {
+                    serving.compressed.fetch_add(1, Ordering::SeqCst);
+                }
+                tokio::spawn(serving.clone().serve(ws));
+            }
+        });
+        RunningFakeBinance { url, fake }
+    }
+}
+
+#[derive(Debug)]
+struct Fake {
+    scenarios: HashMap<String, Scenario>,
+    // Steps of each scenario played so far, across connections
+    played: Mutex<HashMap<String, usize>>,
+    requests: Mutex<Vec<Value>>,
+    connections: AtomicUsize,
+    // Those of `connections` that negotiated permessage-deflate
+    compressed: AtomicUsize,
+    open: AtomicUsize,
+}
+
+impl Fake {
+    async fn serve(self: Arc<Self>, mut ws: WebSocketStream<Deflating<TcpStream>>) {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        'connection: while let Some(Ok(frame)) = ws.next().await {
+            let Message::Text(text) = frame else {
+                continue;
+            };
+            let Ok(request) = serde_json::from_str::<Value>(&text) else {
+                continue;
+            };
+            self.requests.lock().unwrap().push(request.clone());
+            let ack = json!({"result": null, "id": request["id"]});
+            if ws.send(Message::Text(ack.to_string())).await.is_err() {
+                break;
+            }
+            if request["method"] != "SUBSCRIBE" {
+                continue;
+            }
+            let streams = request["params"].as_array().cloned().unwrap_or_default();
+            for stream in streams.iter().filter_map(Value::as_str) {
+                while let Some(step) = self.next_step(stream) {
+                    let frame = match step {
+                        Step::Kline(candle) => kline_frame(stream, &candle),
+                        Step::Frame(text) => text,
+                        Step::Wait(duration) => {
+                            sleep(duration).await;
+                            continue;
+                        }
+                        Step::Disconnect => break 'connection,
+                    };
+                    if ws.send(Message::Text(frame)).await.is_err() {
+                        break 'connection;
+                    }
+                }
+            }
+        }
+        let _ = ws.close(None).await;
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn next_step(&self, stream: &str) -> Option<Step> {
+        let scenario = self.scenarios.get(stream)?;
+        let mut played = self.played.lock().unwrap();
+        let played = played.entry(stream.to_string()).or_default();
+        let step = scenario.steps.get(*played)?.clone();
+        *played += 1;
+        Some(step)
+    }
+}
+
+/// Handle on a started `FakeBinance`.
+#[derive(Debug, Clone)]
+pub struct RunningFakeBinance {
+    pub url: String,
+    fake: Arc<Fake>,
+}
+
+impl RunningFakeBinance {
+    /// Every request received, across connections.
+    pub fn requests(&self) -> Vec<Value> {
+        self.fake.requests.lock().unwrap().clone()
+    }
+
+    /// Params of every SUBSCRIBE received, in order.
+    pub fn subscribed(&self) -> Vec<String> {
+        self.requests()
+            .iter()
+            .filter(|request| request["method"] == "SUBSCRIBE")
+            .flat_map(|request| request["params"].as_array().cloned().unwrap_or_default())
+            .filter_map(|param| param.as_str().map(str::to_string))
+            .collect()
+    }
+
+    /// Connections accepted since it started.
+    pub fn connections(&self) -> usize {
+        self.fake.connections.load(Ordering::SeqCst)
+    }
+
+    /// Connections accepted that negotiated permessage-deflate.
+    pub fn compressed_connections(&self) -> usize {
+        self.fake.compressed.load(Ordering::SeqCst)
+    }
+
+    pub fn open_connections(&self) -> usize {
+        self.fake.open.load(Ordering::SeqCst)
+    }
+}
+
+/// A `Server` listening on an ephemeral port.
+pub struct TestServer {
+    pub server: Server,
+    pub url: String,
+    // Where the same server speaks gRPC
+    pub grpc_addr: SocketAddr,
+    // Those of its config, armed while it runs
+    pub faults: FaultInjector,
+}
+
+impl TestServer {
+    pub async fn start(config: ServerConfig) -> TestServer {
+        let faults = config.faults.clone();
+        let server = Server::from_config(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve_listener(listener).await });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let grpc_addr = listener.local_addr().unwrap();
+        let serving = server.clone();
+        tokio::spawn(async move { serving.serve_grpc_listener(listener).await });
+        TestServer {
+            server,
+            url,
+            grpc_addr,
+            faults,
+        }
+    }
+
+    /// Starts the server reading its upstream from `fake`.
+    pub async fn against(fake: &RunningFakeBinance, config: ServerConfig) -> TestServer {
+        TestServer::start(ServerConfig {
+            upstream_url: fake.url.clone(),
+            ..config
+        })
+        .await
+    }
+
+    pub async fn client(&self) -> TestClient {
+        TestClient::connect(&self.url).await
+    }
+
+    pub async fn grpc_client(&self) -> GrpcClient {
+        GrpcClient::connect(self.grpc_addr).await
+    }
+
+    /// Waits for every subscription, evaluator and upstream stream to be
+    /// gone, e.g. after the last client left.
+    pub async fn wait_until_idle(&self) {
+        timeout(TIMEOUT, async {
+            loop {
+                let info = self.server.info().await;
+                if info.subscriptions == 0 && info.evaluators == 0 && info.upstream_streams == 0 {
+                    return;
+                }
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("server never became idle");
+    }
+}
+
+/// WebSocket client of a `TestServer`, reading frames as JSON. Requests
+/// naming no `version` ask for version 3 frames.
+pub struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    next_id: u64,
+}
+
+impl TestClient {
+    pub async fn connect(url: &str) -> TestClient {
+        let (ws, _) = connect_async(url).await.expect("can not connect");
+        TestClient { ws, next_id: 1 }
+    }
+
+    pub async fn send(&mut self, mut request: Value) {
+        if let Some(request) = request.as_object_mut() {
+            request
+                .entry("version")
+                .or_insert(json!(Version::V3.number()));
+        }
+        self.ws
+            .send(Message::Text(request.to_string()))
+            .await
+            .expect("can not send request");
+    }
+
+    /// Sends `method` for `stream` with the next id, which it returns.
+    pub async fn request(&mut self, method: &str, stream: &str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(json!({"id": id, "method": method, "stream": stream}))
+            .await;
+        id
+    }
+
+    pub async fn subscribe(&mut self, stream: &str) -> u64 {
+        self.request("SUBSCRIBE", stream).await
+    }
+
+    pub async fn unsubscribe(&mut self, stream: &str) -> u64 {
+        self.request("UNSUBSCRIBE", stream).await
+    }
+
+    /// Next text frame, results and statuses alike.
+    pub async fn next(&mut self) -> Value {
+        self.try_next(TIMEOUT)
+            .await
+            .expect("no frame received in time")
+    }
+
+    /// Next text frame within `wait`, `None` when there's none.
+    pub async fn try_next(&mut self, wait: Duration) -> Option<Value> {
+        timeout(wait, async {
+            loop {
+                match self.ws.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        return serde_json::from_str(&text).expect("frame is not JSON")
+                    }
+                    Some(Ok(_)) => continue,
+                    other => panic!("connection ended: {:?}", other),
+                }
+            }
+        })
+        .await
+        .ok()
+    }
+
+    /// Next candle on `stream`, skipping statuses and other streams.
+    pub async fn next_result(&mut self, stream: &str) -> Value {
+        loop {
+            let frame = self.next().await;
+            if frame["stream"] == stream && frame["type"] == "candle" {
+                return frame;
+            }
+        }
+    }
+
+    /// Next status of `event`, on any stream, whatever its `type`.
+    pub async fn next_status(&mut self, event: &str) -> Value {
+        loop {
+            let frame = self.next().await;
+            if frame["data"]["event"] == event {
+                return frame;
+            }
+        }
+    }
+
+    /// Every frame until none arrives for `quiet`.
+    pub async fn collect(&mut self, quiet: Duration) -> Vec<Value> {
+        let mut frames = Vec::new();
+        while let Some(frame) = self.try_next(quiet).await {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    pub async fn close(mut self) {
+        let _ = self.ws.close(None).await;
+    }
+}
+
+/// What a `GrpcClient` reads off one call.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GrpcEvent {
+    /// A `CandleUpdate` as JSON shaped like a WebSocket candle frame:
+    /// `stream`, then `t`, the prices, `v` and `closed`, when set, under
+    /// `data`.
+    Update { call: u32, update: Value },
+    /// The end of a call, with its `grpc-status` and `grpc-message`.
+    Ended {
+        call: u32,
+        status: u32,
+        message: String,
+    },
+}
+
+/// gRPC client of a `TestServer`'s `Candles` service, speaking just enough
+/// HTTP/2 to make calls and read what they return. Calls are told apart by
+/// their HTTP/2 stream.
+pub struct GrpcClient {
+    socket: TcpStream,
+    decoder: hpack::Decoder,
+    next_call: u32,
+    // Bytes of each call's messages not yet read whole
+    buffers: HashMap<u32, Vec<u8>>,
+}
+
+impl GrpcClient {
+    pub async fn connect(addr: SocketAddr) -> GrpcClient {
+        let mut socket = TcpStream::connect(addr).await.expect("can not connect");
+        let mut hello = grpc::PREFACE.to_vec();
+        grpc::write_frame(&mut hello, grpc::SETTINGS, 0, 0, &[]);
+        socket
+            .write_all(&hello)
+            .await
+            .expect("can not send preface");
+        GrpcClient {
+            socket,
+            decoder: hpack::Decoder::new(),
+            next_call: 1,
+            buffers: HashMap::new(),
+        }
+    }
+
+    /// Calls `method` of `Candles` with an `ExpressionRequest` for
+    /// `stream`, returning the call.
+    pub async fn call(&mut self, method: &str, stream: &str) -> u32 {
+        let call = self.next_call;
+        self.next_call += 2;
+        let path = format!("/candles.Candles/{}", method);
+        let mut block = Vec::new();
+        hpack::encode(
+            &[
+                (":method", "POST"),
+                (":scheme", "http"),
+                (":path", &path),
+                (":authority", "localhost"),
+                ("content-type", "application/grpc"),
+                ("te", "trailers"),
+            ],
+            &mut block,
+        );
+        let mut request = vec![0x0a];
+        let mut len = stream.len();
+        while len >= 0x80 {
+            request.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        request.push(len as u8);
+        request.extend_from_slice(stream.as_bytes());
+
+        let mut out = Vec::new();
+        grpc::write_frame(&mut out, grpc::HEADERS, grpc::END_HEADERS, call, &block);
+        let data = grpc::length_prefixed(&request);
+        grpc::write_frame(&mut out, grpc::DATA, grpc::END_STREAM, call, &data);
+        self.socket
+            .write_all(&out)
+            .await
+            .expect("can not send call");
+        call
+    }
+
+    pub async fn subscribe(&mut self, stream: &str) -> u32 {
+        self.call("Subscribe", stream).await
+    }
+
+    pub async fn unsubscribe(&mut self, stream: &str) -> u32 {
+        self.call("Unsubscribe", stream).await
+    }
+
+    /// Next update or end of any call.
+    pub async fn next(&mut self) -> GrpcEvent {
+        self.try_next(TIMEOUT)
+            .await
+            .expect("no gRPC event received in time")
+    }
+
+    /// Next update or end of any call within `wait`, `None` when there's
+    /// none.
+    pub async fn try_next(&mut self, wait: Duration) -> Option<GrpcEvent> {
+        timeout(wait, async {
+            loop {
+                let frame = grpc::read_frame(&mut self.socket, grpc::MAX_FRAME)
+                    .await
+                    .expect("gRPC connection ended");
+                if let Some(event) = self.on_frame(frame).await {
+                    return event;
+                }
+            }
+        })
+        .await
+        .ok()
+    }
+
+    /// Next update of `call`, skipping those of other calls.
+    pub async fn next_update(&mut self, call: u32) -> Value {
+        loop {
+            match self.next().await {
+                GrpcEvent::Update { call: of, update } if of == call => return update,
+                GrpcEvent::Ended {
+                    call: of,
+                    status,
+                    message,
+                } if of == call => panic!("call ended with {}: {}", status, message),
+                _ => continue,
+            }
+        }
+    }
+
+    /// `grpc-status` and `grpc-message` `call` ends with, skipping whatever
+    /// comes before.
+    pub async fn next_end(&mut self, call: u32) -> (u32, String) {
+        loop {
+            if let GrpcEvent::Ended {
+                call: of,
+                status,
+                message,
+            } = self.next().await
+            {
+                if of == call {
+                    return (status, message);
+                }
+            }
+        }
+    }
+
+    async fn on_frame(&mut self, frame: Frame) -> Option<GrpcEvent> {
+        match frame.kind {
+            grpc::SETTINGS if frame.flags & grpc::ACK == 0 => {
+                self.send(grpc::SETTINGS, grpc::ACK, 0, &[]).await
+            }
+            grpc::PING if frame.flags & grpc::ACK == 0 => {
+                self.send(grpc::PING, grpc::ACK, 0, &frame.payload).await
+            }
+            grpc::GOAWAY => panic!("server went away: {:?}", frame.payload),
+            grpc::RST_STREAM => panic!("call {} reset", frame.stream),
+            grpc::HEADERS => {
+                let headers = self
+                    .decoder
+                    .decode(&frame.payload)
+                    .expect("invalid header block");
+                let header = |name: &str| {
+                    headers
+                        .iter()
+                        .find(|(known, _)| known == name)
+                        .map(|(_, value)| value.clone())
+                };
+                let status = header("grpc-status")?;
+                self.buffers.remove(&frame.stream);
+                return Some(GrpcEvent::Ended {
+                    call: frame.stream,
+                    status: status.parse().expect("grpc-status is not a number"),
+                    message: header("grpc-message").unwrap_or_default(),
+                });
+            }
+            grpc::DATA => {
+                // Given straight back, as updates are read as they come
+                let increment = (frame.payload.len() as u32).to_be_bytes();
+                self.send(grpc::WINDOW_UPDATE, 0, 0, &increment).await;
+                self.send(grpc::WINDOW_UPDATE, 0, frame.stream, &increment)
+                    .await;
+                let buffer = self.buffers.entry(frame.stream).or_default();
+                buffer.extend_from_slice(&frame.payload);
+                let len = u32::from_be_bytes(buffer.get(1..5)?.try_into().unwrap()) as usize;
+                if buffer.len() < 5 + len {
+                    return None;
+                }
+                let message: Vec<u8> = buffer.drain(..5 + len).skip(5).collect();
+                return Some(GrpcEvent::Update {
+                    call: frame.stream,
+                    update: candle_update(&message),
+                });
+            }
+            _ => {}
+        }
+        None
+    }
+
+    async fn send(&mut self, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+        let mut out = Vec::new();
+        grpc::write_frame(&mut out, kind, flags, stream, payload);
+        self.socket
+            .write_all(&out)
+            .await
+            .expect("can not send frame");
+    }
+}
+
+// A `CandleUpdate` as the JSON of a WebSocket candle frame
+fn candle_update(message: &[u8]) -> Value {
+    let mut update = json!({"stream": "", "data": {"t": 0}});
+    let names = ["o", "c", "h", "l", "v"];
+    for (field, value) in protobuf::read_fields(message).expect("invalid CandleUpdate") {
+        match (field, value) {
+            (1, FieldValue::Bytes(stream)) => {
+                update["stream"] = json!(String::from_utf8_lossy(stream))
+            }
+            (2, FieldValue::Varint(t)) => update["data"]["t"] = json!(t),
+            (3..=7, FieldValue::Fixed64(bits)) => {
+                update["data"][names[field as usize - 3]] = json!(f64::from_bits(bits))
+            }
+            // Left out unless set, as in WebSocket frames
+            (13, FieldValue::Varint(1)) => update["data"]["closed"] = json!(true),
+            _ => {}
+        }
+    }
+    update
+}
@@ -0,0 +1,1251 @@
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use log::{debug, error, info, warn};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::{interval_at, sleep, timeout, Duration, Instant};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{client_async, WebSocketStream};
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::candle::Candle;
+use crate::error::ServerError;
+use crate::fault::FaultInjector;
+use crate::mid::{self, MidBars};
+use crate::open_interest::{self, OpenInterestBars};
+use crate::permessage::{Inflating, WireCounters, EXTENSIONS_HEADER, PERMESSAGE_DEFLATE};
+use crate::protocol::*;
+use crate::rest;
+use crate::server::ServerConfig;
+use crate::synthetic::{Generator, SyntheticConfig};
+use crate::utils::{interval_to_millis, now_millis};
+
+// Plain `ws` only, as tokio-tungstenite is built without TLS
+type UpstreamSocket = WebSocketStream<Inflating<TcpStream>>;
+
+const KLINE_CHANNEL_CAPACITY: usize = 64;
+// Wait before retrying a failed proactive rotation
+const ROTATION_RETRY: Duration = Duration::from_secs(60);
+// Binance takes at most this many streams per SUBSCRIBE or UNSUBSCRIBE
+const MAX_PARAMS_PER_MESSAGE: usize = 200;
+
+/// What an evaluator receives for each of its upstream streams.
+#[derive(Debug, Clone)]
+pub enum UpstreamEvent {
+    Kline(Candle),
+    // The 24 hour statistics of a `@ticker` stream, which has no bars
+    Ticker(TickerStats),
+    // Midpoint of the best bid and ask of a `@bookTicker` stream, only
+    // seen by the tasks building mid-price bars from it
+    Mid { time: u64, price: f64 },
+    // No kline arrived within the staleness threshold; recovery is underway
+    Stale,
+    // Bars opening in `from..to` were never received
+    Gap { from: u64, to: u64 },
+    // The connection dropped; retry `attempt`, counting from 1, is next
+    Reconnecting { attempt: u32 },
+    // Klines flow again after `Reconnecting`
+    Reconnected,
+    // Older than the stream's newest bar, or a repeat of a closed bar; only
+    // sent under `OrderingPolicy::EmitWithFlag`
+    Late(Candle),
+}
+
+/// What happens to klines that arrive behind a stream's newest bar.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderingPolicy {
+    #[default]
+    Drop,
+    // Forward them as `UpstreamEvent::Late`, for consumers replaying history
+    EmitWithFlag,
+}
+
+/// Traffic of the Binance connection since startup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UpstreamStats {
+    // The newest connection negotiated permessage-deflate
+    pub compressed: bool,
+    // Bytes read off the socket, and the same once inflated
+    pub bytes_received: u64,
+    pub bytes_decoded: u64,
+}
+
+impl UpstreamStats {
+    /// Bytes decoded per byte received, `None` before the first.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.bytes_received > 0).then(|| self.bytes_decoded as f64 / self.bytes_received as f64)
+    }
+}
+
+struct UpstreamStream {
+    refcount: usize,
+    tx: broadcast::Sender<UpstreamEvent>,
+    // Newest in-order kline, handed to evaluators that subscribe later
+    latest: Option<Candle>,
+}
+
+impl UpstreamStream {
+    fn new() -> UpstreamStream {
+        UpstreamStream {
+            refcount: 0,
+            tx: broadcast::channel(KLINE_CHANNEL_CAPACITY).0,
+            latest: None,
+        }
+    }
+}
+
+// A stream produced by its own task instead of subscribed on the
+// connection as is: open interest polled over REST, or mid-price bars built
+// from a book ticker, which is
+struct PolledStream {
+    stream: UpstreamStream,
+    task: JoinHandle<()>,
+}
+
+/// An evaluator's view of one upstream stream.
+pub struct UpstreamLeg {
+    pub rx: broadcast::Receiver<UpstreamEvent>,
+    // Last known kline when the leg was subscribed. It may belong to a bar
+    // that's still open, so it only seeds pairing and is never final.
+    pub latest: Option<Candle>,
+}
+
+// Per-stream progress, kept across reconnects
+struct StreamActivity {
+    // High-water mark: start time of the newest kline forwarded
+    last_t: Option<u64>,
+    // Whether the bar at `last_t` has closed
+    closed: bool,
+    last_seen: Instant,
+}
+
+impl Default for StreamActivity {
+    fn default() -> Self {
+        StreamActivity {
+            last_t: None,
+            closed: false,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum KlineOrder {
+    // A newer bar, or the first one seen on the stream
+    Next { previous: Option<u64> },
+    // Another update to the newest bar
+    Current,
+    // Repeat of the newest bar after it closed
+    ClosedRepeat,
+    // Behind the high-water mark
+    Older { last_t: u64 },
+}
+
+impl StreamActivity {
+    // Places a kline relative to the high-water mark, which only `Next` and
+    // `Current` move
+    fn order(&mut self, t: u64, closed: bool) -> KlineOrder {
+        match self.last_t {
+            Some(last_t) if t < last_t => KlineOrder::Older { last_t },
+            Some(last_t) if t == last_t && self.closed => KlineOrder::ClosedRepeat,
+            Some(last_t) if t == last_t => {
+                self.closed = closed;
+                KlineOrder::Current
+            }
+            previous => {
+                self.last_t = Some(t);
+                self.closed = closed;
+                KlineOrder::Next { previous }
+            }
+        }
+    }
+}
+
+struct UpstreamLink {
+    // `None` while the connection task is reconnecting
+    write: Option<SplitSink<UpstreamSocket, Message>>,
+    task: JoinHandle<()>,
+}
+
+/// Single Binance connection shared by every subscription. Each kline stream
+/// is subscribed upstream once and refcounted by the evaluators consuming it.
+pub struct Upstream {
+    url: String,
+    backoff: BackoffConfig,
+    // Connections are replaced after this long, ahead of Binance's 24h cutoff
+    max_connection_age: Duration,
+    connection_stale_after: Duration,
+    stream_stale_after: Duration,
+    ordering: OrderingPolicy,
+    rest_url: Option<String>,
+    open_interest_poll: Duration,
+    // Keyed by Binance stream name, e.g. `btcusdt@kline_1m`
+    streams: RwLock<HashMap<String, UpstreamStream>>,
+    // Keyed like `streams`, e.g. `btcusdt@openInterest_5m`; never sent on
+    // the connection. A `btcusdt@midKline_1m` holds a reference to its
+    // `btcusdt@bookTicker` in `streams`.
+    polled: RwLock<HashMap<String, PolledStream>>,
+    // With its seed always set; the link is then a generator task instead
+    // of a connection
+    synthetic: Option<SyntheticConfig>,
+    faults: FaultInjector,
+    // permessage-deflate is offered on every connection
+    compression: bool,
+    // Whether the newest connection has it, and bytes over all of them
+    compressed: AtomicBool,
+    wire: Arc<WireCounters>,
+    link: Mutex<Option<UpstreamLink>>,
+    next_id: AtomicU32,
+    // Anomalies detected across all streams since startup
+    gaps: AtomicU64,
+    closed_repeats: AtomicU64,
+    out_of_order: AtomicU64,
+}
+
+impl Upstream {
+    pub fn new(config: &ServerConfig) -> Upstream {
+        Upstream {
+            url: config.upstream_url.clone(),
+            backoff: config.backoff.clone(),
+            max_connection_age: config.max_connection_age,
+            connection_stale_after: config.connection_stale_after,
+            stream_stale_after: config.stream_stale_after,
+            ordering: config.ordering,
+            rest_url: config.rest_url.clone(),
+            open_interest_poll: config.open_interest_poll,
+            streams: RwLock::default(),
+            polled: RwLock::default(),
+            // Random seeds fit in 53 bits, so one read off GET_INFO goes back
+            // into a config file or a JavaScript number unchanged
+            synthetic: config.synthetic.clone().map(|synthetic| SyntheticConfig {
+                seed: Some(
+                    synthetic
+                        .seed
+                        .unwrap_or_else(|| rand::random::<u64>() >> 11),
+                ),
+                ..synthetic
+            }),
+            faults: config.faults.clone(),
+            compression: config.upstream_compression,
+            compressed: AtomicBool::new(false),
+            wire: Arc::default(),
+            link: Mutex::default(),
+            next_id: AtomicU32::new(1),
+            gaps: AtomicU64::new(0),
+            closed_repeats: AtomicU64::new(0),
+            out_of_order: AtomicU64::new(0),
+        }
+    }
+
+    /// Seed of the synthetic streams, when they're made up rather than
+    /// read from Binance.
+    pub fn synthetic_seed(&self) -> Option<u64> {
+        self.synthetic.as_ref().and_then(|synthetic| synthetic.seed)
+    }
+
+    async fn connect_websocket(&self) -> Result<UpstreamSocket, ServerError> {
+        if self.faults.fail_connect() {
+            return Err(ServerError::WebSocketConnect);
+        }
+        let ws = timeout(Duration::from_secs(5), self.handshake())
+            .await
+            .map_err(|_| ServerError::WebSocketTimeout)??;
+        let compressed = ws.get_ref().compressed();
+        if self.compression && !compressed {
+            info!("Binance declined permessage-deflate, reading uncompressed");
+        }
+        self.compressed.store(compressed, Ordering::Relaxed);
+        Ok(ws)
+    }
+
+    async fn handshake(&self) -> Result<UpstreamSocket, ServerError> {
+        let mut request = self
+            .url
+            .as_str()
+            .into_client_request()
+            .map_err(|_| ServerError::WebSocketConnect)?;
+        if self.compression {
+            request.headers_mut().insert(
+                EXTENSIONS_HEADER,
+                HeaderValue::from_static(PERMESSAGE_DEFLATE),
+            );
+        }
+        let uri = request.uri();
+        let (Some("ws"), Some(host)) = (uri.scheme_str(), uri.host()) else {
+            return Err(ServerError::WebSocketConnect);
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let socket = TcpStream::connect((host, uri.port_u16().unwrap_or(80)))
+            .await
+            .map_err(|_| ServerError::WebSocketConnect)?;
+        let stream = Inflating::new(socket, self.compression, self.wire.clone());
+        client_async(request, stream)
+            .await
+            .map_err(|_| ServerError::WebSocketConnect)
+            .map(|(ws, _)| ws)
+    }
+
+    /// Bytes received from Binance and what they came to once inflated.
+    pub fn stats(&self) -> UpstreamStats {
+        UpstreamStats {
+            compressed: self.compressed.load(Ordering::Relaxed),
+            bytes_received: self.wire.received(),
+            bytes_decoded: self.wire.decoded(),
+        }
+    }
+
+    /// Sends `params` in as few frames as Binance accepts, each with its
+    /// own id.
+    async fn send_subscription(
+        &self,
+        write: &mut SplitSink<UpstreamSocket, Message>,
+        method: &str,
+        params: Vec<String>,
+    ) -> Result<(), ServerError> {
+        for chunk in params.chunks(MAX_PARAMS_PER_MESSAGE) {
+            let subscription = BinanceSubscription {
+                id: self.next_id.fetch_add(1, Ordering::Relaxed),
+                method: method.into(),
+                params: chunk.to_vec(),
+            };
+            let subscribe_message = Message::text(serde_json::to_string(&subscription)?);
+            write
+                .send(subscribe_message)
+                .await
+                .map_err(|_| ServerError::WebSocketWrite {
+                    streams: format!("{} {}", method, subscription.params.join(", ")),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Returns one leg per requested stream, in the same order.
+    pub async fn subscribe(
+        self: &Arc<Self>,
+        streams: &[String],
+    ) -> Result<Vec<UpstreamLeg>, ServerError> {
+        let mut link = self.link.lock().await;
+        let mut legs = Vec::with_capacity(streams.len());
+        let mut added = Vec::new();
+
+        {
+            let mut streams_lock = self.streams.write().await;
+            let mut polled_lock = self.polled.write().await;
+            for stream in streams {
+                let entry = if open_interest::is_stream(stream) {
+                    let polled = polled_lock.entry(stream.clone()).or_insert_with(|| {
+                        // Waits for the lock to see its entry
+                        let task = tokio::spawn(self.clone().poll_open_interest(stream.clone()));
+                        PolledStream {
+                            stream: UpstreamStream::new(),
+                            task,
+                        }
+                    });
+                    &mut polled.stream
+                } else if let Some((symbol, _)) = mid::parse_stream(stream) {
+                    let polled = polled_lock.entry(stream.clone()).or_insert_with(|| {
+                        let book = mid::book_ticker_stream(symbol);
+                        let book = streams_lock.entry(book.clone()).or_insert_with(|| {
+                            added.push(book);
+                            UpstreamStream::new()
+                        });
+                        book.refcount += 1;
+                        let task = tokio::spawn(
+                            self.clone()
+                                .build_mid_bars(stream.clone(), book.tx.subscribe()),
+                        );
+                        PolledStream {
+                            stream: UpstreamStream::new(),
+                            task,
+                        }
+                    });
+                    &mut polled.stream
+                } else {
+                    streams_lock.entry(stream.clone()).or_insert_with(|| {
+                        added.push(stream.clone());
+                        UpstreamStream::new()
+                    })
+                };
+                entry.refcount += 1;
+                legs.push(UpstreamLeg {
+                    rx: entry.tx.subscribe(),
+                    latest: entry.latest,
+                });
+            }
+        }
+
+        if added.is_empty() {
+            return Ok(legs);
+        }
+
+        info!("Subscribing to upstream streams: {:?}", added);
+        if let Err(e) = self.send_to_link(&mut link, added).await {
+            drop(link);
+            self.unsubscribe(streams).await;
+            return Err(e);
+        }
+
+        Ok(legs)
+    }
+
+    async fn send_to_link(
+        self: &Arc<Self>,
+        link: &mut Option<UpstreamLink>,
+        params: Vec<String>,
+    ) -> Result<(), ServerError> {
+        match link.as_mut() {
+            Some(active) if !active.task.is_finished() => match active.write.as_mut() {
+                Some(write) => self.send_subscription(write, "SUBSCRIBE", params).await,
+                // The connection task resubscribes every stream once it's back
+                None => Ok(()),
+            },
+            // The generator picks up new streams on its next tick
+            _ if self.synthetic.is_some() => {
+                let task = tokio::spawn(self.clone().generate());
+                *link = Some(UpstreamLink { write: None, task });
+                Ok(())
+            }
+            _ => {
+                let (mut write, read) = self.connect_websocket().await?.split();
+                self.send_subscription(&mut write, "SUBSCRIBE", params)
+                    .await?;
+                let task = tokio::spawn(self.clone().maintain_connection(read));
+                *link = Some(UpstreamLink {
+                    write: Some(write),
+                    task,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Releases one reference to each stream, unsubscribing upstream from the
+    /// ones nobody consumes anymore and closing the connection once idle.
+    pub async fn unsubscribe(&self, streams: &[String]) {
+        let mut link = self.link.lock().await;
+        let mut removed = Vec::new();
+
+        let remaining = {
+            let mut streams_lock = self.streams.write().await;
+            let mut polled_lock = self.polled.write().await;
+            for stream in streams {
+                if let Some(polled) = polled_lock.get_mut(stream) {
+                    polled.stream.refcount -= 1;
+                    if polled.stream.refcount > 0 {
+                        continue;
+                    }
+                    info!("No longer polling {}", stream);
+                    polled.task.abort();
+                    polled_lock.remove(stream);
+                    let Some((symbol, _)) = mid::parse_stream(stream) else {
+                        continue;
+                    };
+                    let book = mid::book_ticker_stream(symbol);
+                    if let Some(entry) = streams_lock.get_mut(&book) {
+                        entry.refcount -= 1;
+                        if entry.refcount == 0 {
+                            streams_lock.remove(&book);
+                            removed.push(book);
+                        }
+                    }
+                    continue;
+                }
+                if let Some(entry) = streams_lock.get_mut(stream) {
+                    entry.refcount -= 1;
+                    if entry.refcount == 0 {
+                        streams_lock.remove(stream);
+                        removed.push(stream.clone());
+                    }
+                }
+            }
+            streams_lock.len()
+        };
+
+        if removed.is_empty() {
+            return;
+        }
+
+        if remaining == 0 {
+            if let Some(idle) = link.take() {
+                info!("No upstream streams left, closing Binance connection");
+                idle.task.abort();
+                if let Some(mut write) = idle.write {
+                    let _ = write.close().await;
+                }
+            }
+            return;
+        }
+
+        info!("Unsubscribing from upstream streams: {:?}", removed);
+        if let Some(write) = link.as_mut().and_then(|active| active.write.as_mut()) {
+            if let Err(e) = self.send_subscription(write, "UNSUBSCRIBE", removed).await {
+                error!("Error unsubscribing upstream streams: {}", e);
+            }
+        }
+    }
+
+    pub async fn stream_count(&self) -> usize {
+        self.streams.read().await.len() + self.polled.read().await.len()
+    }
+
+    /// Fetches the open interest of an `oi:` operand's stream every
+    /// `open_interest_poll` and sends it on as step-function bars. A failed
+    /// fetch is retried on the next poll; the first of a run marks the
+    /// stream stale.
+    async fn poll_open_interest(self: Arc<Self>, stream: String) {
+        let (Some(base), Some((symbol, interval))) = (
+            self.rest_url.as_deref(),
+            open_interest::parse_stream(&stream),
+        ) else {
+            error!("Can not poll {} without rest_url", stream);
+            return;
+        };
+        let mut bars = match OpenInterestBars::new(interval) {
+            Ok(bars) => bars,
+            Err(e) => {
+                error!("Can not poll {}: {}", stream, e);
+                return;
+            }
+        };
+        info!("Polling {} every {:?}", stream, self.open_interest_poll);
+
+        let mut poll = tokio::time::interval(self.open_interest_poll);
+        let mut failing = false;
+        loop {
+            poll.tick().await;
+            let (time, value) = match rest::open_interest(base, symbol).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    warn!("Error polling {}: {}", stream, e);
+                    if !std::mem::replace(&mut failing, true) {
+                        self.notify_polled_stale(&stream).await;
+                    }
+                    continue;
+                }
+            };
+            failing = false;
+
+            let mut polled = self.polled.write().await;
+            let Some(entry) = polled.get_mut(&stream) else {
+                return;
+            };
+            for bar in bars.observe(time, value) {
+                entry.stream.latest = Some(bar);
+                let _ = entry.stream.tx.send(UpstreamEvent::Kline(bar));
+            }
+        }
+    }
+
+    /// Builds the bars of a `mid:` operand's stream from the midpoints on
+    /// `book`, its book ticker, passing on everything else it reports. Ends
+    /// the stream's consumers once the book ticker ends.
+    async fn build_mid_bars(
+        self: Arc<Self>,
+        stream: String,
+        mut book: broadcast::Receiver<UpstreamEvent>,
+    ) {
+        let mut bars = match mid::parse_stream(&stream).map(|(_, interval)| MidBars::new(interval))
+        {
+            Some(Ok(bars)) => bars,
+            Some(Err(e)) => {
+                error!("Can not build {}: {}", stream, e);
+                return;
+            }
+            None => return,
+        };
+        loop {
+            let event = match book.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    debug!("{} skipped {} book updates", stream, skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    self.polled.write().await.remove(&stream);
+                    return;
+                }
+            };
+
+            let mut polled = self.polled.write().await;
+            let Some(entry) = polled.get_mut(&stream) else {
+                return;
+            };
+            let UpstreamEvent::Mid { time, price } = event else {
+                let _ = entry.stream.tx.send(event);
+                continue;
+            };
+            let Some((closed, open)) = bars.observe(time, price) else {
+                continue;
+            };
+            if let Some(closed) = closed {
+                let _ = entry.stream.tx.send(UpstreamEvent::Kline(closed));
+            }
+            entry.stream.latest = Some(open);
+            let _ = entry.stream.tx.send(UpstreamEvent::Kline(open));
+        }
+    }
+
+    /// Steps the synthetic market every tick, sending each stream in use
+    /// what Binance would have.
+    async fn generate(self: Arc<Self>) {
+        let Some(synthetic) = self.synthetic.clone() else {
+            return;
+        };
+        let seed = synthetic.seed.unwrap_or_default();
+        info!(
+            "Generating synthetic streams every {:?} from seed {}",
+            synthetic.tick, seed
+        );
+        let mut generator = Generator::new(seed, synthetic.volatility);
+        let mut tick = tokio::time::interval(synthetic.tick);
+        loop {
+            tick.tick().await;
+            // Written under the lock, as klines read from Binance are
+            let mut streams = self.streams.write().await;
+            let events = generator.tick(now_millis(), streams.keys().map(String::as_str));
+            for (stream, event) in events {
+                let Some(entry) = streams.get_mut(&stream) else {
+                    continue;
+                };
+                if let UpstreamEvent::Kline(candle) = event {
+                    entry.latest = Some(candle);
+                }
+                let _ = entry.tx.send(event);
+            }
+        }
+    }
+
+    async fn notify_polled_stale(&self, stream: &str) {
+        if let Some(entry) = self.polled.read().await.get(stream) {
+            let _ = entry.stream.tx.send(UpstreamEvent::Stale);
+        }
+    }
+
+    /// Reads the connection until it drops, then reconnects with backoff and
+    /// resubscribes every stream still in use.
+    async fn maintain_connection(self: Arc<Self>, mut read: SplitStream<UpstreamSocket>) {
+        let mut backoff = Backoff::new(self.backoff.clone());
+        let mut activity: HashMap<String, StreamActivity> = HashMap::new();
+
+        loop {
+            let connected_at = Instant::now();
+            // Streams get a fresh grace period on every new connection
+            for stream in activity.values_mut() {
+                stream.last_seen = Instant::now();
+            }
+            if let Some(replacement) = self.read_upstream(read, &mut activity).await {
+                read = replacement;
+                continue;
+            }
+
+            if let Some(active) = self.link.lock().await.as_mut() {
+                active.write = None;
+            }
+            if connected_at.elapsed() >= backoff.healthy_after() {
+                backoff.reset();
+            }
+
+            read = match self.reconnect(&mut backoff).await {
+                Some(read) => read,
+                None => return,
+            };
+        }
+    }
+
+    /// Connects and subscribes to every stream currently in use, returning
+    /// the socket and the streams it was subscribed to.
+    async fn open_subscribed(&self) -> Result<Option<UpstreamConnection>, ServerError> {
+        let (mut write, read) = self.connect_websocket().await?.split();
+
+        let _link = self.link.lock().await;
+        let streams = self
+            .streams
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        if streams.is_empty() {
+            return Ok(None);
+        }
+        self.send_subscription(&mut write, "SUBSCRIBE", streams.clone())
+            .await?;
+
+        Ok(Some(UpstreamConnection {
+            write,
+            read,
+            streams,
+        }))
+    }
+
+    async fn reconnect(&self, backoff: &mut Backoff) -> Option<SplitStream<UpstreamSocket>> {
+        loop {
+            let delay = match backoff.next_delay() {
+                Some(delay) => delay,
+                None => {
+                    error!(
+                        "Giving up on Binance after {} reconnect attempts",
+                        backoff.attempt()
+                    );
+                    // Dropping the senders ends every evaluator fed by them
+                    self.streams.write().await.clear();
+                    self.link.lock().await.take();
+                    return None;
+                }
+            };
+
+            warn!(
+                "Binance connection lost, reconnect attempt {} in {:?}",
+                backoff.attempt(),
+                delay
+            );
+            self.broadcast(UpstreamEvent::Reconnecting {
+                attempt: backoff.attempt(),
+            })
+            .await;
+            sleep(delay).await;
+
+            match self.open_subscribed().await {
+                Ok(Some(connection)) => {
+                    info!(
+                        "Reconnected to Binance after {} attempts",
+                        backoff.attempt()
+                    );
+                    let read = self.switch_to(connection).await;
+                    self.broadcast(UpstreamEvent::Reconnected).await;
+                    return Some(read);
+                }
+                Ok(None) => return None,
+                Err(e) => warn!("Reconnect attempt {} failed: {}", backoff.attempt(), e),
+            }
+        }
+    }
+
+    /// Routes writes through `connection`, first catching its subscriptions
+    /// up with streams added or removed since it was opened.
+    async fn switch_to(&self, connection: UpstreamConnection) -> SplitStream<UpstreamSocket> {
+        let UpstreamConnection {
+            mut write,
+            read,
+            streams,
+        } = connection;
+
+        let mut link = self.link.lock().await;
+        let current = self
+            .streams
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        let added = current
+            .iter()
+            .filter(|stream| !streams.contains(stream))
+            .cloned()
+            .collect::<Vec<_>>();
+        let removed = streams
+            .into_iter()
+            .filter(|stream| !current.contains(stream))
+            .collect::<Vec<_>>();
+
+        for (method, params) in [("SUBSCRIBE", added), ("UNSUBSCRIBE", removed)] {
+            if params.is_empty() {
+                continue;
+            }
+            if let Err(e) = self.send_subscription(&mut write, method, params).await {
+                error!("Error syncing streams on new Binance connection: {}", e);
+            }
+        }
+
+        if let Some(active) = link.as_mut() {
+            if let Some(mut old) = active.write.replace(write) {
+                let _ = old.close().await;
+            }
+        }
+        read
+    }
+
+    /// Forwards klines until the connection drops or goes stale (`None`) or
+    /// has been replaced ahead of Binance's 24 hour limit (`Some` with the
+    /// new reader).
+    async fn read_upstream(
+        &self,
+        mut read: SplitStream<UpstreamSocket>,
+        activity: &mut HashMap<String, StreamActivity>,
+    ) -> Option<SplitStream<UpstreamSocket>> {
+        let rotate_at = sleep(self.max_connection_age);
+        tokio::pin!(rotate_at);
+        let mut replacement: Option<UpstreamConnection> = None;
+        let check_period = self.connection_stale_after.min(self.stream_stale_after) / 2;
+        let mut stale_check = interval_at(Instant::now() + check_period, check_period);
+        let mut last_message = Instant::now();
+
+        loop {
+            tokio::select! {
+                _ = stale_check.tick() => {
+                    if last_message.elapsed() > self.connection_stale_after {
+                        warn!(
+                            "No data from Binance for {:?}, reconnecting",
+                            last_message.elapsed()
+                        );
+                        let streams = self.streams.read().await.keys().cloned().collect::<Vec<_>>();
+                        self.notify_stale(&streams).await;
+                        return None;
+                    }
+                    self.resubscribe_stale(activity).await;
+                }
+                _ = &mut rotate_at, if replacement.is_none() => {
+                    match self.open_subscribed().await {
+                        Ok(connection) => {
+                            debug!("Opened replacement Binance connection");
+                            replacement = connection;
+                        }
+                        Err(e) => warn!("Error opening replacement Binance connection: {}", e),
+                    }
+                    if replacement.is_none() {
+                        rotate_at.as_mut().reset(Instant::now() + ROTATION_RETRY);
+                    }
+                }
+                message = read.next() => {
+                    last_message = Instant::now();
+                    let message = self.faults.upstream_frame(message).await;
+                    if !self.forward_frame(message, activity).await.is_open() || self.faults.hang_up() {
+                        break;
+                    }
+                }
+                message = next_frame(&mut replacement) => {
+                    match self.forward_frame(message, activity).await {
+                        Frame::Kline => {
+                            debug!("Switching to replacement Binance connection");
+                            let connection = replacement.take()?;
+                            return Some(self.switch_to(connection).await);
+                        }
+                        Frame::Other => {}
+                        Frame::Closed => {
+                            warn!("Replacement Binance connection closed before use");
+                            replacement = None;
+                            rotate_at.as_mut().reset(Instant::now() + ROTATION_RETRY);
+                        }
+                    }
+                }
+            }
+        }
+
+        info!("Binance connection closed");
+        // A replacement that is already subscribed saves a reconnect
+        match replacement {
+            Some(connection) => Some(self.switch_to(connection).await),
+            None => None,
+        }
+    }
+
+    /// Resends SUBSCRIBE for streams that went quiet while the connection
+    /// itself keeps delivering.
+    async fn resubscribe_stale(&self, activity: &mut HashMap<String, StreamActivity>) {
+        let streams = self
+            .streams
+            .read()
+            .await
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        activity.retain(|stream, _| streams.contains(stream));
+
+        let mut stale = Vec::new();
+        for stream in streams {
+            let entry = activity.entry(stream.clone()).or_default();
+            if entry.last_seen.elapsed() > self.stream_stale_after {
+                entry.last_seen = Instant::now();
+                stale.push(stream);
+            }
+        }
+        if stale.is_empty() {
+            return;
+        }
+
+        warn!("No klines on {:?}, resubscribing", stale);
+        self.notify_stale(&stale).await;
+        let mut link = self.link.lock().await;
+        if let Some(write) = link.as_mut().and_then(|active| active.write.as_mut()) {
+            if let Err(e) = self.send_subscription(write, "SUBSCRIBE", stale).await {
+                error!("Error resubscribing stale streams: {}", e);
+            }
+        }
+    }
+
+    // Sends `event` to every stream in use
+    async fn broadcast(&self, event: UpstreamEvent) {
+        for entry in self.streams.read().await.values() {
+            let _ = entry.tx.send(event.clone());
+        }
+    }
+
+    async fn notify_stale(&self, streams: &[String]) {
+        let streams_lock = self.streams.read().await;
+        for stream in streams {
+            if let Some(entry) = streams_lock.get(stream) {
+                let _ = entry.tx.send(UpstreamEvent::Stale);
+            }
+        }
+    }
+
+    async fn forward_frame(
+        &self,
+        message: Option<Result<Message, tungstenite::Error>>,
+        activity: &mut HashMap<String, StreamActivity>,
+    ) -> Frame {
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => return Frame::Closed,
+            Some(Ok(_)) => return Frame::Other,
+            Some(Err(e)) => {
+                error!("Error reading from Binance: {}", e);
+                return Frame::Closed;
+            }
+        };
+
+        let (name, candle, closed) = match decode_frame(&text) {
+            Ok(UpstreamFrame::Kline {
+                stream,
+                candle,
+                closed,
+            }) => (stream, candle, closed),
+            Ok(UpstreamFrame::Ticker { stream, stats }) => {
+                return self.forward_ticker(&stream, stats, activity).await;
+            }
+            Ok(UpstreamFrame::Book {
+                stream,
+                time,
+                price,
+            }) => {
+                return self
+                    .forward_book_ticker(&stream, time, price, activity)
+                    .await
+            }
+            Ok(UpstreamFrame::Other) => {
+                debug!("Ignoring upstream message: {}", text);
+                return Frame::Other;
+            }
+            Err(e) => {
+                error!("Dropped upstream frame: {}", e);
+                return Frame::Other;
+            }
+        };
+
+        // Reconnects, and overlapping connections during rotation, deliver
+        // bars we've already seen
+        let name: &str = &name;
+        if !activity.contains_key(name) {
+            activity.insert(name.to_string(), StreamActivity::default());
+        }
+        let stream = activity.get_mut(name).unwrap();
+        stream.last_seen = Instant::now();
+        let mut gap = None;
+        let event = match stream.order(candle.t, closed) {
+            KlineOrder::Next { previous } => {
+                gap = previous
+                    .and_then(|last_t| next_open_time(name, last_t))
+                    .filter(|&expected| candle.t > expected)
+                    .map(|expected| (expected, candle.t));
+                UpstreamEvent::Kline(candle)
+            }
+            KlineOrder::Current => UpstreamEvent::Kline(candle),
+            KlineOrder::ClosedRepeat => {
+                let count = self.closed_repeats.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Repeat #{} of closed bar {} on {}", count, candle.t, name);
+                UpstreamEvent::Late(candle)
+            }
+            KlineOrder::Older { last_t } => {
+                let count = self.out_of_order.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Out-of-order kline #{} on {}: {} is older than {}",
+                    count, name, candle.t, last_t
+                );
+                UpstreamEvent::Late(candle)
+            }
+        };
+        if matches!(event, UpstreamEvent::Late(_)) && self.ordering == OrderingPolicy::Drop {
+            return Frame::Kline;
+        }
+
+        // Written under the lock so a subscriber sees each kline either in
+        // `latest` or on its receiver
+        if let Some(entry) = self.streams.write().await.get_mut(name) {
+            if let UpstreamEvent::Kline(candle) = event {
+                entry.latest = Some(candle);
+            }
+            if let Some((from, to)) = gap {
+                let count = self.gaps.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Gap #{} on {}: missing bars from {} to {}",
+                    count, name, from, to
+                );
+                let _ = entry.tx.send(UpstreamEvent::Gap { from, to });
+            }
+            let _ = entry.tx.send(event);
+        }
+        Frame::Kline
+    }
+
+    // Passes on the midpoint of a book update to the mid-price bars built
+    // from it; doesn't allocate once the stream has been seen
+    async fn forward_book_ticker(
+        &self,
+        name: &str,
+        time: u64,
+        price: f64,
+        activity: &mut HashMap<String, StreamActivity>,
+    ) -> Frame {
+        match activity.get_mut(name) {
+            Some(stream) => stream.last_seen = Instant::now(),
+            None => {
+                activity.insert(name.to_string(), StreamActivity::default());
+            }
+        }
+        // Spot's book updates carry no time
+        let time = match time {
+            0 => now_millis(),
+            time => time,
+        };
+        if let Some(entry) = self.streams.read().await.get(name) {
+            let _ = entry.tx.send(UpstreamEvent::Mid { time, price });
+        }
+        Frame::Kline
+    }
+
+    async fn forward_ticker(
+        &self,
+        name: &str,
+        stats: TickerStats,
+        activity: &mut HashMap<String, StreamActivity>,
+    ) -> Frame {
+        activity.entry(name.to_string()).or_default().last_seen = Instant::now();
+        if let Some(entry) = self.streams.read().await.get(name) {
+            let _ = entry.tx.send(UpstreamEvent::Ticker(stats));
+        }
+        Frame::Kline
+    }
+}
+
+/// What one upstream text frame carries.
+#[derive(Debug)]
+pub enum UpstreamFrame<'a> {
+    // A kline, or a mini ticker as a bar of the second it was sent in
+    Kline {
+        stream: Cow<'a, str>,
+        candle: Candle,
+        closed: bool,
+    },
+    Ticker {
+        stream: Cow<'a, str>,
+        stats: TickerStats,
+    },
+    // Midpoint of a book update; `time` is 0 for spot's, which carry none
+    Book {
+        stream: Cow<'a, str>,
+        time: u64,
+        price: f64,
+    },
+    // Replies to SUBSCRIBE and UNSUBSCRIBE, and anything unrecognized
+    Other,
+}
+
+/// Reads an upstream text frame, which is whatever Binance, or anything
+/// posing as it, sends: frames of no known kind are `Other`, and known
+/// ones with unreadable values an error.
+pub fn decode_frame(text: &str) -> Result<UpstreamFrame<'_>, ServerError> {
+    // Book tickers come far more often than anything else, so they're
+    // told apart by name instead of trying every other payload first
+    if text.contains("@bookTicker\"") {
+        let Ok(message) = serde_json::from_str::<BinanceBookTickerMessage>(text) else {
+            return Ok(UpstreamFrame::Other);
+        };
+        let (Ok(bid), Ok(ask)) = (message.data.b.parse::<f64>(), message.data.a.parse::<f64>())
+        else {
+            return Err(ServerError::MalformedKline {
+                symbol: message.data.s.to_string(),
+                fields: format!("b {:?}, a {:?}", message.data.b, message.data.a),
+            });
+        };
+        return Ok(UpstreamFrame::Book {
+            stream: message.stream,
+            time: message.data.E,
+            price: (bid + ask) / 2.0,
+        });
+    }
+    // Replies to SUBSCRIBE/UNSUBSCRIBE don't carry kline data. Mini
+    // tickers are rarer than klines, so they're tried second.
+    if let Ok(message) = serde_json::from_str::<BinanceMessage>(text) {
+        let candle = Candle::try_from(&message.data.k)?;
+        return Ok(UpstreamFrame::Kline {
+            stream: message.stream,
+            candle: Candle {
+                event_time: message.data.E,
+                ..candle
+            },
+            closed: message.data.k.x,
+        });
+    }
+    // Checked ahead of mini tickers, whose fields it has too
+    if let Ok(message) = serde_json::from_str::<BinanceTickerMessage>(text) {
+        return Ok(UpstreamFrame::Ticker {
+            stats: TickerStats::try_from(&message.data)?,
+            stream: message.stream,
+        });
+    }
+    if let Ok(message) = serde_json::from_str::<BinanceMiniTickerMessage>(text) {
+        return Ok(UpstreamFrame::Kline {
+            candle: Candle::try_from(&message.data)?,
+            stream: message.stream,
+            closed: false,
+        });
+    }
+    Ok(UpstreamFrame::Other)
+}
+
+// Start time of the bar following `last_t` on a `<symbol>@<kind>_<interval>`
+// stream, or `None` when the interval can't be read from the name
+fn next_open_time(stream: &str, last_t: u64) -> Option<u64> {
+    let (_, kind) = stream.rsplit_once('@')?;
+    let (_, interval) = kind.split_once('_')?;
+    let length = interval_to_millis(interval, last_t).ok()?;
+    last_t.checked_add(length)
+}
+
+struct UpstreamConnection {
+    write: SplitSink<UpstreamSocket, Message>,
+    read: SplitStream<UpstreamSocket>,
+    streams: Vec<String>,
+}
+
+enum Frame {
+    Kline,
+    Other,
+    Closed,
+}
+
+impl Frame {
+    fn is_open(&self) -> bool {
+        !matches!(self, Frame::Closed)
+    }
+}
+
+async fn next_frame(
+    connection: &mut Option<UpstreamConnection>,
+) -> Option<Result<Message, tungstenite::Error>> {
+    match connection {
+        Some(connection) => connection.read.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_frame, next_open_time, KlineOrder, StreamActivity, UpstreamFrame};
+
+    const KLINE: &str = r#"{"stream":"btcusdt@kline_1m","data":{"e":"kline","E":7,"s":"BTCUSDT",
+        "k":{"t":0,"T":59999,"s":"BTCUSDT","i":"1m","f":1,"L":2,"o":"1","c":"2","h":"3","l":"0.5",
+        "v":"10","n":2,"x":true,"q":"15","V":"5","Q":"7","B":"0"}}}"#;
+
+    #[test]
+    fn test_frames_decode_by_kind() {
+        let Ok(UpstreamFrame::Kline {
+            stream,
+            candle,
+            closed,
+        }) = decode_frame(KLINE)
+        else {
+            panic!("not a kline");
+        };
+        assert_eq!(stream, "btcusdt@kline_1m");
+        assert_eq!((candle.c, candle.event_time, closed), (2.0, 7, true));
+
+        let book = r#"{"stream":"btcusdt@bookTicker","data":{"u":1,"s":"BTCUSDT",
+            "b":"1","B":"1","a":"2","A":"1"}}"#;
+        assert!(matches!(
+            decode_frame(book),
+            Ok(UpstreamFrame::Book { time: 0, price, .. }) if price == 1.5
+        ));
+        assert!(matches!(
+            decode_frame(r#"{"result":null,"id":1}"#),
+            Ok(UpstreamFrame::Other)
+        ));
+    }
+
+    #[test]
+    fn test_malformed_frames_are_errors_or_ignored() {
+        assert!(decode_frame(&KLINE.replace(r#""c":"2""#, r#""c":"two""#)).is_err());
+        let book = r#"{"stream":"btcusdt@bookTicker","data":{"u":1,"s":"BTCUSDT",
+            "b":"","B":"1","a":"2","A":"1"}}"#;
+        assert!(decode_frame(book).is_err());
+        for text in ["", "{", "null", &KLINE[..KLINE.len() / 2], "@bookTicker\""] {
+            assert!(matches!(decode_frame(text), Ok(UpstreamFrame::Other)));
+        }
+    }
+
+    #[test]
+    fn test_next_open_time_does_not_overflow() {
+        assert_eq!(next_open_time("btcusdt@kline_1m", 0), Some(60_000));
+        assert_eq!(next_open_time("btcusdt@kline_1m", u64::MAX - 1), None);
+        assert_eq!(next_open_time("btcusdt@kline_1é", 0), None);
+    }
+
+    #[test]
+    fn test_first_kline_is_next() {
+        let mut stream = StreamActivity::default();
+        assert_eq!(
+            stream.order(60_000, false),
+            KlineOrder::Next { previous: None }
+        );
+        assert_eq!(stream.last_t, Some(60_000));
+    }
+
+    #[test]
+    fn test_newer_kline_advances_high_water_mark() {
+        let mut stream = StreamActivity::default();
+        stream.order(0, true);
+        assert_eq!(
+            stream.order(60_000, false),
+            KlineOrder::Next { previous: Some(0) }
+        );
+        assert_eq!(stream.last_t, Some(60_000));
+        assert!(!stream.closed);
+    }
+
+    #[test]
+    fn test_open_bar_updates_in_place_until_closed() {
+        let mut stream = StreamActivity::default();
+        stream.order(0, false);
+        assert_eq!(stream.order(0, false), KlineOrder::Current);
+        assert_eq!(stream.order(0, true), KlineOrder::Current);
+        assert!(stream.closed);
+    }
+
+    #[test]
+    fn test_repeat_of_closed_bar_is_flagged() {
+        let mut stream = StreamActivity::default();
+        stream.order(0, true);
+        assert_eq!(stream.order(0, true), KlineOrder::ClosedRepeat);
+        assert_eq!(stream.order(0, false), KlineOrder::ClosedRepeat);
+        assert!(stream.closed);
+    }
+
+    #[test]
+    fn test_older_kline_leaves_high_water_mark() {
+        let mut stream = StreamActivity::default();
+        stream.order(120_000, false);
+        assert_eq!(
+            stream.order(60_000, true),
+            KlineOrder::Older { last_t: 120_000 }
+        );
+        assert_eq!(stream.last_t, Some(120_000));
+        assert!(!stream.closed);
+    }
+}
@@ -1,559 +1,176 @@
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, sync::Arc};
-use thiserror::Error;
-use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite;
-
-#[derive(Debug, Error)]
-pub enum ServerError {
-    #[error(transparent)]
-    Io(#[from] std::io::Error),
-
-    #[error(transparent)]
-    WebSocket(#[from] tungstenite::Error),
-
-    #[error(transparent)]
-    Serde(#[from] serde_json::Error),
-
-    #[error(transparent)]
-    UrlParse(#[from] url::ParseError),
-
-    #[error(transparent)]
-    ParseFloatError(#[from] std::num::ParseFloatError),
-
-    #[error("Key not found")]
-    KeyNotFound,
-
-    #[error("Can not connect to WebSocket")]
-    WebSocketConnect,
-
-    #[error("Can not accept WebSocket connection")]
-    WebSocketAccept,
-
-    #[error("Сonnection time expired")]
-    WebSocketTimeout,
-
-    #[error("Can not write to WebSocket")]
-    WebSocketWrite,
-
-    #[error("Division by zero")]
-    DivisionByZero,
-
-    #[error("Websocket error")] //
-    WebsocketError(tokio_tungstenite::tungstenite::Error),
-
-    #[error("Can not serialize")]
-    Serialization,
-
-    #[error("Operation on mismatched timestamps")]
-    MismatchedTimestamps,
-
-    #[error("Wrong stream")]
-    ParsingStream,
-
-    #[error("Invalid message")]
-    InvalidMessage(String),
-}
-
-#[derive(Debug)]
-pub enum Operation {
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-}
-
-#[derive(Serialize)]
-pub struct Response {
-    stream: String,
-    data: Candle,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct BinanceMessage {
-    pub data: BinanceData,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct BinanceData {
-    pub e: String,
-    pub E: u64,
-    pub s: String,
-    pub k: BinanceKlineData,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct BinanceKlineData {
-    pub t: u64,
-    pub T: u64,
-    pub s: String,
-    pub i: String,
-    pub f: u64,
-    pub L: u64,
-    pub o: String, // Open price
-    pub c: String, // Close price
-    pub h: String, // High price
-    pub l: String, // Low price
-    pub v: String,
-    pub n: u64,
-    pub x: bool,
-    pub q: String,
-    pub V: String,
-    pub Q: String,
-    pub B: String,
-}
-
-#[derive(Debug, Serialize)]
-pub struct ResultMessage {
-    pub stream: String,
-    pub data: ResultData,
-}
-
-#[derive(Debug, Serialize)]
-pub struct ResultData {
-    pub t: u64, // kline start time
-    pub o: f64, // open price: 26884.70 + 1806.09
-    pub c: f64, // close price: 26886.20 + 1806.14
-    pub h: f64, // high price: 26892.50 + 1806.33
-    pub l: f64, // low price: 26877.80 + 1805.67
-}
-
-#[derive(Debug, Clone, Copy, Serialize)]
-pub struct Candle {
-    pub t: u64, // start time
-    pub o: f64, // open price
-    pub c: f64, // close price
-    pub h: f64, // high price
-    pub l: f64, // low price
-}
-
-impl Candle {
-    pub fn new(t: u64, o: f64, c: f64, h: f64, l: f64) -> Arc<Mutex<Self>> {
-        Arc::new(Mutex::new(Self { t, o, c, h, l }))
-    }
-
-    fn assert_timestamps(&self, other: Self) -> Result<(), ServerError> {
-        if self.t != other.t {
-            return Err(ServerError::MismatchedTimestamps);
-        }
-
-        Ok(())
-    }
-
-    pub fn add(&self, other: Self) -> Result<Self, ServerError> {
-        self.assert_timestamps(other)?;
-
-        Ok(Self {
-            t: self.t,
-            o: self.o + other.o,
-            c: self.c + other.c,
-            h: self.h + other.h,
-            l: self.l + other.l,
-        })
-    }
-
-    pub fn sub(&self, other: Self) -> Result<Self, ServerError> {
-        self.assert_timestamps(other)?;
-
-        Ok(Self {
-            t: self.t,
-            o: self.o - other.o,
-            c: self.c - other.c,
-            h: self.h - other.h,
-            l: self.l - other.l,
-        })
-    }
-
-    pub fn mul(&self, other: Self) -> Result<Self, ServerError> {
-        self.assert_timestamps(other)?;
-
-        Ok(Self {
-            t: self.t,
-            o: self.o * other.o,
-            c: self.c * other.c,
-            h: self.h * other.h,
-            l: self.l * other.l,
-        })
-    }
-
-    pub fn div(&self, other: Self) -> Result<Self, ServerError> {
-        if other.o == 0.0 || other.c == 0.0 || other.h == 0.0 || other.l == 0.0 {
-            return Err(ServerError::DivisionByZero);
-        }
-
-        self.assert_timestamps(other)?;
-
-        Ok(Self {
-            t: self.t,
-            o: self.o / other.o,
-            c: self.c / other.c,
-            h: self.h / other.h,
-            l: self.l / other.l,
-        })
-    }
-}
-
-pub fn perform_operation(a: &Candle, b: &Candle, op: &Operation) -> Result<Candle, ServerError> {
-    if a.t != b.t {
-        return Err(ServerError::MismatchedTimestamps);
-    }
-
-    match op {
-        Operation::Add => a.add(*b),
-        Operation::Subtract => a.sub(*b),
-        Operation::Multiply => a.mul(*b),
-        Operation::Divide => a.div(*b),
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct Request {
-    pub id: u32,
-    pub method: String,
-    pub stream: String,
-}
-
-#[derive(Serialize)]
-pub struct BinanceSubscription {
-    pub id: u32,
-    pub method: String,
-    pub params: Vec<String>,
-}
-
-pub fn parse_price(s: &str) -> f64 {
-    s.parse().unwrap_or(0.0)
-}
-
-pub fn parse_streams(input: &str) -> Vec<String> {
-    // Looking for @
-    let divider_index = input.rfind('@').unwrap();
-
-    // Getting postfix
-    let postfix = format!("@kline_{}", &input[(divider_index + 1)..]);
-
-    // Getting all tokens
-    let re = Regex::new(r"([()+*/-])").unwrap();
-    let tokens = re.split(&input[..divider_index]).collect::<Vec<&str>>();
-
-    tokens
-        .iter()
-        .filter(|&&token| !token.trim().is_empty())
-        .map(|&token| format!("{}{}", token, postfix))
-        .collect()
-}
-
-const OPERATOR_PRECEDENCES: [(char, usize); 5] = [
-    ('+', 1),
-    ('-', 1),
-    ('*', 2),
-    ('/', 2),
-    ('(', 0), // lower than any other operator
-];
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum Token {
-    Operator(Operator),
-    Operand(String),
-    LeftParenthesis,
-    RightParenthesis,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum Operator {
-    Plus,
-    Minus,
-    Multiply,
-    Divide,
-    NotOperator,
-}
-
-impl From<char> for Operator {
-    fn from(value: char) -> Self {
-        match value {
-            '+' => Operator::Plus,
-            '-' => Operator::Minus,
-            '*' => Operator::Multiply,
-            '/' => Operator::Divide,
-            _ => Operator::NotOperator,
-        }
-    }
-}
-
-impl std::fmt::Display for Operator {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        let symbol = match self {
-            Operator::Plus => '+',
-            Operator::Minus => '-',
-            Operator::Multiply => '*',
-            Operator::Divide => '/',
-            _ => ' ',
-        };
-        write!(f, "{}", symbol)
-    }
-}
-
-impl std::fmt::Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Token::Operator(op) => write!(f, "{}", op),
-            Token::Operand(op) => write!(f, "{}", op),
-            Token::LeftParenthesis => write!(f, "("),
-            Token::RightParenthesis => write!(f, ")"),
-        }
-    }
-}
-
-pub fn parse(input: &str) -> Result<Vec<Token>, ServerError> {
-    let mut tokens = Vec::new();
-    let mut current_operand = String::new();
-    let divider_index = input.rfind('@').unwrap();
-    let postfix = format!("@kline_{}", &input[(divider_index + 1)..]);
-
-    for c in input[..divider_index].chars() {
-        match c {
-            '+' | '-' | '*' | '/' => {
-                if !current_operand.is_empty() {
-                    tokens.push(Token::Operand(current_operand.clone() + &postfix));
-                    current_operand.clear();
-                }
-                tokens.push(Token::Operator(c.into()));
-            }
-            '(' => tokens.push(Token::LeftParenthesis),
-            ')' => {
-                if !current_operand.is_empty() {
-                    tokens.push(Token::Operand(current_operand.clone() + &postfix));
-                    current_operand.clear();
-                }
-                tokens.push(Token::RightParenthesis);
-            }
-            _ => {
-                if c.is_alphanumeric() {
-                    current_operand.push(c);
-                } else {
-                    return Err(ServerError::ParsingStream);
-                }
-            }
-        }
-    }
-
-    if !current_operand.is_empty() {
-        tokens.push(Token::Operand(current_operand + &postfix));
-    }
-
-    Ok(tokens)
-}
-
-pub fn to_rpn(tokens: &[Token]) -> Result<Vec<Token>, ServerError> {
-    let mut rpn = Vec::<Token>::new();
-    let mut stack: Vec<&Token> = Vec::new();
-    let mut open_brackets = 0;
-
-    let precedence = |t: &Token| match t {
-        Token::Operator(op) => match op {
-            Operator::Plus | Operator::Minus => 1,
-            Operator::Multiply | Operator::Divide => 2,
-            _ => 0,
-        },
-        Token::LeftParenthesis => 0,
-        _ => usize::MAX,
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ServerError;
+
+pub const MILLIS_PER_HOUR: u64 = 3_600_000;
+pub const MILLIS_PER_DAY: u64 = 86_400_000;
+
+/// Current unix time in milliseconds, 0 if the clock is before the epoch.
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Length in milliseconds of the `interval` bar opening at `open_time`, e.g.
+/// `1m` -> 60000. Monthly bars follow the calendar, so their length depends
+/// on which month `open_time` falls in.
+pub fn interval_to_millis(interval: &str, open_time: u64) -> Result<u64, ServerError> {
+    let invalid = || ServerError::InvalidInterval(interval.to_string());
+    let unit = interval.chars().last().ok_or_else(invalid)?;
+    let count: u64 = interval[..interval.len() - unit.len_utf8()]
+        .parse()
+        .map_err(|_| invalid())?;
+    if count == 0 {
+        return Err(invalid());
+    }
+
+    let unit_millis = match unit {
+        's' => 1_000,
+        'm' => 60_000,
+        'h' => MILLIS_PER_HOUR,
+        'd' => MILLIS_PER_DAY,
+        'w' => 7 * MILLIS_PER_DAY,
+        'M' => return months_to_millis(count, open_time).ok_or_else(invalid),
+        _ => return Err(invalid()),
     };
-
-    for token in tokens {
-        match token {
-            Token::Operator(_) => {
-                while let Some(&last) = stack.last() {
-                    if precedence(token) <= precedence(last) {
-                        rpn.push((*stack.pop().unwrap()).clone());
-                    } else {
-                        break;
-                    }
-                }
-                stack.push(token);
-            }
-            Token::LeftParenthesis => {
-                stack.push(token);
-                open_brackets += 1;
-            }
-            Token::RightParenthesis => {
-                if open_brackets == 0 {
-                    return Err(ServerError::ParsingStream);
-                }
-                while let Some(top) = stack.pop() {
-                    if matches!(top, Token::LeftParenthesis) {
-                        break;
-                    }
-                    rpn.push((*top).clone());
-                }
-                open_brackets -= 1;
-            }
-            _ => rpn.push((*token).clone()),
-        }
-    }
-
-    if open_brackets != 0 {
-        return Err(ServerError::ParsingStream);
-    }
-
-    while let Some(op) = stack.pop() {
-        rpn.push((*op).clone());
-    }
-
-    Ok(rpn)
-}
-
-#[cfg(test)]
-mod tests_parse {
-    use super::parse_streams;
-
-    #[test]
-    fn test_parse_streams_single_token() {
-        let input = "btcusdt@1m";
-        let expected = vec!["btcusdt@kline_1m"];
-        assert_eq!(parse_streams(input), expected);
-    }
-
-    #[test]
-    fn test_parse_streams_multiple_tokens() {
-        let input = "btcusdt+ethusdt@1h";
-        let expected = vec!["btcusdt@kline_1h", "ethusdt@kline_1h"];
-        assert_eq!(parse_streams(input), expected);
-    }
-
-    #[test]
-    fn test_parse_streams_with_operations() {
-        let input = "(btcusdt-ethusdt)*bnbusdt@1d";
-        let expected = vec!["btcusdt@kline_1d", "ethusdt@kline_1d", "bnbusdt@kline_1d"];
-        assert_eq!(parse_streams(input), expected);
-    }
-
-    // should panic?
-    #[test]
-    fn test_parse_streams_with_empty_tokens() {
-        let input = "btcusdt++ethusdt@1m";
-        let expected = vec!["btcusdt@kline_1m", "ethusdt@kline_1m"];
-        assert_eq!(parse_streams(input), expected);
-    }
+    count.checked_mul(unit_millis).ok_or_else(invalid)
+}
+
+// `None` past any year a date can be computed for, rather than counting
+// months one by one, which an interval like `99999999999M` would make
+// take forever
+fn months_to_millis(count: u64, open_time: u64) -> Option<u64> {
+    let (year, month, _) = civil_from_days(open_time / MILLIS_PER_DAY);
+    let months = count.checked_add(month - 1)?;
+    let end_year = year
+        .checked_add(months / 12)
+        .filter(|&year| year < 1 << 32)?;
+    let days = days_from_civil(end_year, months % 12 + 1, 1) - days_from_civil(year, month, 1);
+    days.checked_mul(MILLIS_PER_DAY)
+}
+
+/// Civil (year, month, day) of a day count since the Unix epoch, after
+/// Howard Hinnant's algorithm of the same name.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Day count since the Unix epoch of a civil date; inverse of
+/// `civil_from_days`.
+pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_index = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Formats epoch milliseconds as RFC 3339 in UTC, e.g.
+/// `2024-01-01T00:00:00.000Z`.
+pub fn format_rfc3339(millis: u64) -> String {
+    let (year, month, day) = civil_from_days(millis / MILLIS_PER_DAY);
+    let millis_of_day = millis % MILLIS_PER_DAY;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis_of_day / MILLIS_PER_HOUR,
+        millis_of_day / 60_000 % 60,
+        millis_of_day / 1_000 % 60,
+        millis_of_day % 1_000
+    )
+}
+
+/// Epoch milliseconds of a timestamp written by `format_rfc3339`.
+pub fn parse_rfc3339(text: &str) -> Option<u64> {
+    let bytes = text.as_bytes();
+    if bytes.len() != 24 || !text.is_ascii() || bytes[23] != b'Z' {
+        return None;
+    }
+    let number = |range: std::ops::Range<usize>| text[range].parse::<u64>().ok();
+    let (month, day) = (number(5..7)?, number(8..10)?);
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    let days = days_from_civil(number(0..4)?, month, day);
+    Some(
+        days * MILLIS_PER_DAY
+            + number(11..13)? * MILLIS_PER_HOUR
+            + number(14..16)? * 60_000
+            + number(17..19)? * 1_000
+            + number(20..23)?,
+    )
 }
 
 #[cfg(test)]
-mod tests_rpn {
-    use super::{parse, to_rpn, Operator, Token};
+mod tests_interval {
+    use super::interval_to_millis;
 
-    #[test]
-    fn test_to_rpn_simple_expression() {
-        let tokens = parse("btcusdt+ethusdt@1m").unwrap();
-        let result = to_rpn(&tokens).unwrap();
-        assert_eq!(
-            result,
-            vec![
-                Token::Operand("btcusdt@kline_1m".into()),
-                Token::Operand("ethusdt@kline_1m".into()),
-                Token::Operator(Operator::Plus)
-            ]
-        );
-    }
-
-    #[test]
-    fn test_to_rpn_expression_with_parentheses() {
-        let tokens = parse("(btcusdt+ethusdt)*adausdt@1m").unwrap();
-        let result = to_rpn(&tokens).unwrap();
-        assert_eq!(
-            result,
-            vec![
-                Token::Operand("btcusdt@kline_1m".into()),
-                Token::Operand("ethusdt@kline_1m".into()),
-                Token::Operator(Operator::Plus),
-                Token::Operand("adausdt@kline_1m".into()),
-                Token::Operator(Operator::Multiply)
-            ]
-        );
-    }
-
-    #[test]
-    fn test_to_rpn_mismatched_parentheses() {
-        let tokens = parse("(btcusdt+ethusdt*adausdt@1m").unwrap();
-        let result = to_rpn(&tokens);
-        assert!(result.is_err());
-    }
+    // 2024-01-01T00:00:00Z
+    const JAN_2024: u64 = 1_704_067_200_000;
+    const DAY: u64 = 86_400_000;
 
     #[test]
-    fn test_to_rpn_operator_precedence() {
-        let tokens = parse("btcusdt+ethusdt*adausdt@1h").unwrap();
-        let result = to_rpn(&tokens).unwrap();
-        assert_eq!(
-            result,
-            vec![
-                Token::Operand("btcusdt@kline_1h".into()),
-                Token::Operand("ethusdt@kline_1h".into()),
-                Token::Operand("adausdt@kline_1h".into()),
-                Token::Operator(Operator::Multiply),
-                Token::Operator(Operator::Plus)
-            ]
-        );
+    fn test_interval_to_millis_fixed_units() {
+        assert_eq!(interval_to_millis("1m", 0).unwrap(), 60_000);
+        assert_eq!(interval_to_millis("15m", 0).unwrap(), 900_000);
+        assert_eq!(interval_to_millis("1h", 0).unwrap(), 3_600_000);
+        assert_eq!(interval_to_millis("12h", 0).unwrap(), 43_200_000);
+        assert_eq!(interval_to_millis("3d", 0).unwrap(), 3 * DAY);
+        assert_eq!(interval_to_millis("1w", 0).unwrap(), 7 * DAY);
     }
 
     #[test]
-    fn test_to_rpn_with_complex_expression() {
-        let tokens = parse("btcusdt+ethusdt*(bnbusdt-trxusdt)@1h").unwrap();
-        let expected = vec![
-            Token::Operand("btcusdt@kline_1h".into()),
-            Token::Operand("ethusdt@kline_1h".into()),
-            Token::Operand("bnbusdt@kline_1h".into()),
-            Token::Operand("trxusdt@kline_1h".into()),
-            Token::Operator(Operator::Minus),
-            Token::Operator(Operator::Multiply),
-            Token::Operator(Operator::Plus),
-        ];
-        assert_eq!(to_rpn(&tokens).unwrap(), expected);
+    fn test_interval_to_millis_month_follows_calendar() {
+        assert_eq!(interval_to_millis("1M", JAN_2024).unwrap(), 31 * DAY);
+        // 2024 is a leap year
+        let feb_2024 = JAN_2024 + 31 * DAY;
+        assert_eq!(interval_to_millis("1M", feb_2024).unwrap(), 29 * DAY);
+        let feb_2023 = JAN_2024 - 334 * DAY;
+        assert_eq!(interval_to_millis("1M", feb_2023).unwrap(), 28 * DAY);
+        let apr_2024 = feb_2024 + 60 * DAY;
+        assert_eq!(interval_to_millis("1M", apr_2024).unwrap(), 30 * DAY);
     }
 
     #[test]
-    fn test_to_rpn_with_no_parentheses() {
-        let tokens = parse("btcusdt+ethusdt*bnbusdt/trxusdt@1M").unwrap();
-        let expected = vec![
-            Token::Operand("btcusdt@kline_1M".into()),
-            Token::Operand("ethusdt@kline_1M".into()),
-            Token::Operand("bnbusdt@kline_1M".into()),
-            Token::Operator(Operator::Multiply),
-            Token::Operand("trxusdt@kline_1M".into()),
-            Token::Operator(Operator::Divide),
-            Token::Operator(Operator::Plus),
-        ];
-        assert_eq!(to_rpn(&tokens).unwrap(), expected);
+    fn test_interval_to_millis_months_span_year_end() {
+        let dec_2023 = JAN_2024 - 31 * DAY;
+        assert_eq!(interval_to_millis("2M", dec_2023).unwrap(), 62 * DAY);
     }
 
     #[test]
-    fn test_to_rpn_with_all_operators() {
-        let tokens = parse("btcusdt+ethusdt-bnbusdt*trxusdt/bchusdt@1M").unwrap();
-        let expected = vec![
-            Token::Operand("btcusdt@kline_1M".into()),
-            Token::Operand("ethusdt@kline_1M".into()),
-            Token::Operator(Operator::Plus),
-            Token::Operand("bnbusdt@kline_1M".into()),
-            Token::Operand("trxusdt@kline_1M".into()),
-            Token::Operator(Operator::Multiply),
-            Token::Operand("bchusdt@kline_1M".into()),
-            Token::Operator(Operator::Divide),
-            Token::Operator(Operator::Minus),
-        ];
-        assert_eq!(to_rpn(&tokens).unwrap(), expected);
+    fn test_interval_to_millis_rejects_invalid() {
+        assert!(interval_to_millis("", 0).is_err());
+        assert!(interval_to_millis("m", 0).is_err());
+        assert!(interval_to_millis("0m", 0).is_err());
+        assert!(interval_to_millis("1y", 0).is_err());
+        assert!(interval_to_millis("1.5h", 0).is_err());
     }
 
     #[test]
-    fn test_to_rpn_with_multiple_parentheses() {
-        let tokens = parse("(btcusdt+(ethusdt-(bnbusdt*(trxusdt/bchusdt))))@1M").unwrap();
-        let expected = vec![
-            Token::Operand("btcusdt@kline_1M".into()),
-            Token::Operand("ethusdt@kline_1M".into()),
-            Token::Operand("bnbusdt@kline_1M".into()),
-            Token::Operand("trxusdt@kline_1M".into()),
-            Token::Operand("bchusdt@kline_1M".into()),
-            Token::Operator(Operator::Divide),
-            Token::Operator(Operator::Multiply),
-            Token::Operator(Operator::Minus),
-            Token::Operator(Operator::Plus),
-        ];
-        assert_eq!(to_rpn(&tokens).unwrap(), expected);
+    fn test_interval_to_millis_rejects_overflow() {
+        assert_eq!(interval_to_millis("12M", JAN_2024).unwrap(), 366 * DAY);
+        assert_eq!(interval_to_millis("1200M", 0).unwrap(), 36_525 * DAY);
+        // Once counted month by month, which never finished
+        assert!(interval_to_millis("18446744073709551615M", JAN_2024).is_err());
+        assert!(interval_to_millis("99999999999999999w", 0).is_err());
     }
 }
@@ -0,0 +1,597 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use url::Url;
+
+use crate::backoff::{Backoff, BackoffConfig};
+use crate::error::ServerError;
+use crate::memory::MemoryAccount;
+use crate::protocol::WebhookTarget;
+use crate::scram::hmac;
+
+/// Header carrying `sha256=` and the hex HMAC-SHA256 of the body under
+/// `WebhookConfig::secret`.
+pub const SIGNATURE_HEADER: &str = "X-Candle-Signature";
+
+// A URL's worker with nothing to deliver for this long goes away
+const IDLE_AFTER: Duration = Duration::from_secs(300);
+
+// Set by the server on every POST
+const RESERVED_HEADERS: [&str; 6] = [
+    "host",
+    "content-type",
+    "content-length",
+    "connection",
+    "transfer-encoding",
+    "x-candle-signature",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    // Key bodies are signed with in `SIGNATURE_HEADER`; unsigned when unset
+    pub secret: Option<String>,
+    // Tries of one POST, apart by the server's backoff, before it's given up
+    pub max_attempts: u32,
+    // Deliveries to a URL given up on in a row that open its circuit, and
+    // how long nothing is sent to it then; one delivery is tried after
+    pub failure_threshold: u32,
+    pub open_for: Duration,
+    // Deliveries waiting per URL; beyond this new ones are dropped
+    pub queue_capacity: usize,
+    // A POST taking longer than this counts as a failed one
+    pub timeout: Duration,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            secret: None,
+            max_attempts: 3,
+            failure_threshold: 5,
+            open_for: Duration::from_secs(60),
+            queue_capacity: 256,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WebhookStats {
+    pub delivered: u64,
+    // Given up on after `max_attempts`, or refused by the endpoint
+    pub failed: u64,
+    // Attempts after the first of a delivery
+    pub retried: u64,
+    // Never queued as their URL's queue was full
+    pub dropped: u64,
+    // Skipped while their URL's circuit was open
+    pub short_circuited: u64,
+    pub open_circuits: u64,
+    // Approximate bytes of the bodies waiting
+    pub buffered_bytes: usize,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    retried: AtomicU64,
+    dropped: AtomicU64,
+    short_circuited: AtomicU64,
+    open_circuits: AtomicU64,
+}
+
+// One closed bar or alert for one endpoint
+struct Delivery {
+    headers: BTreeMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// Posts closed bars and alerts to the webhooks clients gave, one worker per
+/// URL so a slow or dead endpoint holds up only its own deliveries.
+#[derive(Clone)]
+pub struct Webhooks {
+    config: Arc<WebhookConfig>,
+    backoff: BackoffConfig,
+    // Queue of each URL's worker
+    workers: Arc<Mutex<HashMap<String, mpsc::Sender<Delivery>>>>,
+    counters: Arc<Counters>,
+    memory: MemoryAccount,
+}
+
+/// Checks a SUBSCRIBE's webhook can be posted to: a plain `http` URL and
+/// headers that fit in a request and leave the server's own alone.
+pub fn check(target: &WebhookTarget) -> Result<(), ServerError> {
+    let invalid = |why: String| Err(ServerError::InvalidMessage(format!("webhook {}", why)));
+    let url = Url::parse(&target.url)?;
+    if url.scheme() != "http" {
+        return invalid(format!(
+            "{} needs TLS, which this build lacks",
+            url.scheme()
+        ));
+    }
+    if url.host_str().is_none() {
+        return invalid("URL without a host".into());
+    }
+    for (name, value) in &target.headers {
+        let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(token) {
+            return invalid(format!("header name {:?}", name));
+        }
+        if RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return invalid(format!("header {} is set by the server", name));
+        }
+        if value.contains(['\r', '\n']) {
+            return invalid(format!("header {} has a line break", name));
+        }
+    }
+    Ok(())
+}
+
+/// `SIGNATURE_HEADER` value of `body` under `secret`.
+pub fn signature(secret: &str, body: &[u8]) -> String {
+    let mac = hmac(secret.as_bytes(), body);
+    let hex: String = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!("sha256={}", hex)
+}
+
+impl Webhooks {
+    /// POSTs are retried with `backoff`'s delays, bodies waiting charged to
+    /// `memory`.
+    pub fn new(config: WebhookConfig, backoff: BackoffConfig, memory: MemoryAccount) -> Webhooks {
+        Webhooks {
+            config: Arc::new(config),
+            backoff,
+            workers: Arc::default(),
+            counters: Arc::default(),
+            memory,
+        }
+    }
+
+    /// Queues `message` as JSON for `target` without waiting. Starts the
+    /// URL's worker if it has none, so this must run inside a Tokio runtime.
+    pub fn offer<T: Serialize>(&self, target: &WebhookTarget, message: &T) {
+        let body = match serde_json::to_vec(message) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Error serializing webhook body: {}", e);
+                return;
+            }
+        };
+        let size = body.len();
+        if self.memory.must_shed() {
+            self.memory.shed(size);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let delivery = Delivery {
+            headers: target.headers.clone(),
+            body,
+        };
+
+        // Sent under the lock, so an idle worker can't leave in between
+        let mut workers = self.workers.lock().unwrap();
+        let worker = workers
+            .entry(target.url.clone())
+            .or_insert_with(|| self.spawn_worker(&target.url));
+        self.memory.charge(size);
+        if worker.try_send(delivery).is_err() {
+            self.memory.release(size);
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> WebhookStats {
+        let counters = &self.counters;
+        WebhookStats {
+            delivered: counters.delivered.load(Ordering::Relaxed),
+            failed: counters.failed.load(Ordering::Relaxed),
+            retried: counters.retried.load(Ordering::Relaxed),
+            dropped: counters.dropped.load(Ordering::Relaxed),
+            short_circuited: counters.short_circuited.load(Ordering::Relaxed),
+            open_circuits: counters.open_circuits.load(Ordering::Relaxed),
+            buffered_bytes: self.memory.bytes(),
+        }
+    }
+
+    fn spawn_worker(&self, url: &str) -> mpsc::Sender<Delivery> {
+        let (tx, rx) = mpsc::channel(self.config.queue_capacity.max(1));
+        tokio::spawn(self.clone().work(url.to_string(), rx));
+        tx
+    }
+
+    // Delivers to `url` in order, keeping its circuit: after
+    // `failure_threshold` deliveries given up on in a row nothing is tried
+    // for `open_for`, then a single attempt decides whether it closes again
+    async fn work(self, url: String, mut rx: mpsc::Receiver<Delivery>) {
+        let mut failures = 0;
+        let mut open_until: Option<Instant> = None;
+        loop {
+            let delivery = match timeout(IDLE_AFTER, rx.recv()).await {
+                Ok(Some(delivery)) => delivery,
+                Ok(None) => return,
+                // An open circuit is kept until it closes
+                Err(_) if open_until.is_none() => {
+                    let mut workers = self.workers.lock().unwrap();
+                    if rx.is_empty() {
+                        workers.remove(&url);
+                        return;
+                    }
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            self.memory.release(delivery.body.len());
+
+            let attempts = match open_until {
+                Some(until) if Instant::now() < until => {
+                    self.counters
+                        .short_circuited
+                        .fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                Some(_) => 1,
+                None => self.config.max_attempts.max(1),
+            };
+            match self.deliver(&url, &delivery, attempts).await {
+                Ok(()) => {
+                    self.counters.delivered.fetch_add(1, Ordering::Relaxed);
+                    failures = 0;
+                    if open_until.take().is_some() {
+                        self.counters.open_circuits.fetch_sub(1, Ordering::Relaxed);
+                        info!("Webhook {} is back, closing its circuit", url);
+                    }
+                }
+                Err(Failure::Refused(e)) => {
+                    // The endpoint is up, just not taking this one
+                    warn!("Webhook {} refused a delivery: {}", url, e);
+                    self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(Failure::Unreachable(e)) => {
+                    self.counters.failed.fetch_add(1, Ordering::Relaxed);
+                    failures += 1;
+                    if failures >= self.config.failure_threshold || open_until.is_some() {
+                        if open_until.is_none() {
+                            self.counters.open_circuits.fetch_add(1, Ordering::Relaxed);
+                        }
+                        warn!(
+                            "Webhook {} failed {} deliveries in a row, opening its circuit \
+                             for {:?}: {}",
+                            url, failures, self.config.open_for, e
+                        );
+                        open_until = Some(Instant::now() + self.config.open_for);
+                    } else {
+                        warn!("Giving up on a webhook delivery to {}: {}", url, e);
+                    }
+                }
+            }
+        }
+    }
+
+    // POSTs `delivery` up to `attempts` times while it fails transiently
+    async fn deliver(&self, url: &str, delivery: &Delivery, attempts: u32) -> Result<(), Failure> {
+        let mut backoff = Backoff::new(BackoffConfig {
+            max_attempts: Some(attempts - 1),
+            ..self.backoff.clone()
+        });
+        loop {
+            let error = match timeout(self.config.timeout, self.post(url, delivery)).await {
+                Ok(Err(Failure::Unreachable(e))) => e,
+                Ok(result) => return result,
+                Err(_) => ServerError::Webhook("POST timed out".into()),
+            };
+            match backoff.next_delay() {
+                Some(delay) => {
+                    self.counters.retried.fetch_add(1, Ordering::Relaxed);
+                    sleep(delay).await;
+                }
+                None => return Err(Failure::Unreachable(error)),
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, delivery: &Delivery) -> Result<(), Failure> {
+        let url = Url::parse(url).map_err(|e| Failure::Refused(e.into()))?;
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port().unwrap_or(80);
+        let mut target = url.path().to_string();
+        if let Some(query) = url.query() {
+            target.push('?');
+            target.push_str(query);
+        }
+        let mut headers = String::new();
+        for (name, value) in &delivery.headers {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if let Some(secret) = &self.config.secret {
+            headers.push_str(&format!(
+                "{}: {}\r\n",
+                SIGNATURE_HEADER,
+                signature(secret, &delivery.body)
+            ));
+        }
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\n{}Content-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            target,
+            host,
+            port,
+            headers,
+            delivery.body.len()
+        );
+
+        let unreachable = |e: std::io::Error| Failure::Unreachable(e.into());
+        let mut stream = TcpStream::connect((host, port))
+            .await
+            .map_err(unreachable)?;
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(unreachable)?;
+        stream
+            .write_all(&delivery.body)
+            .await
+            .map_err(unreachable)?;
+        // Only the status line matters
+        let mut response = Vec::new();
+        stream
+            .read_to_end(&mut response)
+            .await
+            .map_err(unreachable)?;
+        let status = std::str::from_utf8(&response)
+            .ok()
+            .and_then(|response| response.split_whitespace().nth(1))
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| {
+                Failure::Unreachable(ServerError::Webhook("malformed HTTP response".into()))
+            })?;
+        let error = || ServerError::Webhook(format!("HTTP status {}", status));
+        match status {
+            200..=299 => Ok(()),
+            408 | 429 | 500.. => Err(Failure::Unreachable(error())),
+            _ => Err(Failure::Refused(error())),
+        }
+    }
+}
+
+// Why a delivery didn't go through
+enum Failure {
+    // No answer, a timeout, or a status saying to come back later
+    Unreachable(ServerError),
+    // Any other status, which retrying won't change
+    Refused(ServerError),
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{check, signature, WebhookConfig, Webhooks, SIGNATURE_HEADER};
+    use crate::backoff::BackoffConfig;
+    use crate::candle::Candle;
+    use crate::memory::MemoryAccount;
+    use crate::protocol::{ResultData, ResultMessage, WebhookTarget};
+    use serde_json::Value;
+    use std::collections::{BTreeMap, HashMap};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+    use tokio::time::{sleep, timeout, Duration};
+
+    #[derive(Debug)]
+    pub(crate) struct Post {
+        pub(crate) target: String,
+        // Lowercase names
+        pub(crate) headers: HashMap<String, String>,
+        pub(crate) body: Vec<u8>,
+    }
+
+    /// HTTP server answering the statuses of `script` in turn, then 200,
+    /// and reporting every POST it gets.
+    pub(crate) async fn fake_endpoint(
+        script: &'static [u16],
+    ) -> (String, mpsc::UnboundedReceiver<Post>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks/candles", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        let statuses = Arc::new(Mutex::new(script.iter()));
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let head_end = loop {
+                    let mut chunk = [0; 1024];
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..read]);
+                    if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break end + 4;
+                    }
+                };
+                let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+                let headers: HashMap<String, String> = head
+                    .lines()
+                    .skip(1)
+                    .filter_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        Some((name.to_ascii_lowercase(), value.trim().to_string()))
+                    })
+                    .collect();
+                let length: usize = headers["content-length"].parse().unwrap();
+                while request.len() < head_end + length {
+                    let mut chunk = [0; 1024];
+                    let read = socket.read(&mut chunk).await.unwrap();
+                    request.extend_from_slice(&chunk[..read]);
+                }
+
+                let status = statuses.lock().unwrap().next().copied().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    status
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+                let _ = tx.send(Post {
+                    target: head.split_whitespace().nth(1).unwrap().to_string(),
+                    headers,
+                    body: request[head_end..].to_vec(),
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    pub(crate) async fn next_post(rx: &mut mpsc::UnboundedReceiver<Post>) -> Post {
+        timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("nothing posted")
+            .unwrap()
+    }
+
+    fn closed_bar(t: u64) -> ResultMessage {
+        ResultMessage {
+            id: None,
+            stream: "btcusdt+ethusdt@1m".into(),
+            data: ResultData::from(Candle::new(t, 1.0, 2.0, 3.0, 0.5)),
+            closed: true,
+            out_of_order: false,
+            partial: false,
+            missing: Vec::new(),
+            snapshot: false,
+            latency_ms: None,
+            alias: None,
+        }
+    }
+
+    fn target(url: &str) -> WebhookTarget {
+        WebhookTarget {
+            url: url.into(),
+            headers: BTreeMap::from([("Authorization".into(), "Bearer abc".into())]),
+        }
+    }
+
+    fn fast_backoff() -> BackoffConfig {
+        BackoffConfig {
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            ..BackoffConfig::default()
+        }
+    }
+
+    async fn wait_for(webhooks: &Webhooks, done: impl Fn(&super::WebhookStats) -> bool) {
+        timeout(Duration::from_secs(5), async {
+            while !done(&webhooks.stats()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("stats never got there");
+    }
+
+    #[test]
+    fn test_check_rejects_unusable_targets() {
+        check(&target("http://127.0.0.1:8080/hook?x=1")).unwrap();
+        assert!(check(&target("https://example.com/hook")).is_err());
+        assert!(check(&target("not a url")).is_err());
+        for (name, value) in [
+            ("Content-Length", "1"),
+            ("x-candle-signature", "sha256=0"),
+            ("Bad Name", "1"),
+            ("X-Trace", "1\r\nHost: evil"),
+        ] {
+            let mut target = target("http://127.0.0.1/hook");
+            target.headers.insert(name.into(), value.into());
+            assert!(check(&target).is_err(), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closed_bars_are_posted_signed() {
+        let (url, mut posts) = fake_endpoint(&[]).await;
+        let config = WebhookConfig {
+            secret: Some("key".into()),
+            ..WebhookConfig::default()
+        };
+        let webhooks = Webhooks::new(config, fast_backoff(), MemoryAccount::default());
+
+        for t in [0, 60_000] {
+            webhooks.offer(&target(&url), &closed_bar(t));
+        }
+        for t in [0, 60_000] {
+            let post = next_post(&mut posts).await;
+            assert_eq!(post.target, "/hooks/candles");
+            assert_eq!(post.headers["authorization"], "Bearer abc");
+            assert_eq!(post.headers["content-type"], "application/json");
+            assert_eq!(
+                post.headers[&SIGNATURE_HEADER.to_ascii_lowercase()],
+                signature("key", &post.body)
+            );
+            let body: Value = serde_json::from_slice(&post.body).unwrap();
+            assert_eq!(body["stream"], "btcusdt+ethusdt@1m");
+            assert_eq!(body["closed"], true);
+            assert_eq!(body["data"]["t"], t);
+        }
+        wait_for(&webhooks, |stats| stats.delivered == 2).await;
+        assert_eq!(webhooks.stats().buffered_bytes, 0);
+    }
+
+    #[test]
+    fn test_signature_is_hex_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failures_are_retried_within_bounds() {
+        let (url, mut posts) = fake_endpoint(&[503, 503, 503, 404]).await;
+        let webhooks = Webhooks::new(
+            WebhookConfig::default(),
+            fast_backoff(),
+            MemoryAccount::default(),
+        );
+
+        // Three attempts, all unavailable, then a refusal tried once
+        webhooks.offer(&target(&url), &closed_bar(0));
+        webhooks.offer(&target(&url), &closed_bar(60_000));
+        webhooks.offer(&target(&url), &closed_bar(120_000));
+        let mut times = Vec::new();
+        for _ in 0..5 {
+            let post = next_post(&mut posts).await;
+            let body: Value = serde_json::from_slice(&post.body).unwrap();
+            times.push(body["data"]["t"].as_u64().unwrap());
+        }
+        assert_eq!(times, [0, 0, 0, 60_000, 120_000]);
+        wait_for(&webhooks, |stats| stats.delivered == 1).await;
+        let stats = webhooks.stats();
+        assert_eq!((stats.failed, stats.retried), (2, 2));
+        assert_eq!(stats.open_circuits, 0);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_opens_on_a_dead_endpoint() {
+        // Nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        drop(listener);
+        let config = WebhookConfig {
+            max_attempts: 1,
+            failure_threshold: 2,
+            open_for: Duration::from_secs(60),
+            ..WebhookConfig::default()
+        };
+        let webhooks = Webhooks::new(config, fast_backoff(), MemoryAccount::default());
+
+        for t in 0..5 {
+            webhooks.offer(&target(&url), &closed_bar(t * 60_000));
+        }
+        wait_for(&webhooks, |stats| stats.short_circuited == 3).await;
+        let stats = webhooks.stats();
+        assert_eq!((stats.failed, stats.open_circuits), (2, 1));
+        assert_eq!(stats.delivered, 0);
+    }
+}
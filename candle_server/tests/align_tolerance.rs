@@ -0,0 +1,107 @@
+//! Legs whose bars open a few hundred milliseconds apart, paired under an
+//! `align_tolerance_ms`, with one leg missing a bar.
+
+use candle_server::error::ErrorCode;
+use candle_server::server::{ServerConfig, TimestampPolicy};
+use candle_server::testing::{FakeBinance, Scenario, TestServer};
+use serde_json::{json, Value};
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(400);
+
+// Every frame of a `btcusdt+ethusdt@1m` subscription asking for `tolerance`
+async fn frames(tolerance: u64, timestamp_policy: TimestampPolicy) -> Vec<Value> {
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(0, 1.0)
+                .closed_bar(60_000, 1.0)
+                .closed_bar(120_000, 1.0),
+        )
+        // Opening 300ms later, and without the bar around 60s
+        .stream(
+            "ethusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(300, 2.0)
+                .closed_bar(120_300, 2.0),
+        )
+        .start()
+        .await;
+    let config = ServerConfig {
+        timestamp_policy,
+        ..ServerConfig::default()
+    };
+    let server = TestServer::against(&fake, config).await;
+    let mut client = server.client().await;
+    client
+        .send(json!({
+            "id": 1, "method": "SUBSCRIBE", "stream": "btcusdt+ethusdt@1m",
+            "align_tolerance_ms": tolerance
+        }))
+        .await;
+    client.collect(QUIET).await
+}
+
+fn candles(frames: &[Value]) -> Vec<(u64, Option<f64>, bool)> {
+    frames
+        .iter()
+        .filter(|frame| frame["type"] == "candle")
+        .map(|frame| {
+            let data = &frame["data"];
+            (
+                data["t"].as_u64().unwrap(),
+                data["c"].as_f64(),
+                data["partial"] == true,
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_legs_pair_within_the_tolerance() {
+    let frames = frames(
+        500,
+        TimestampPolicy::Partial {
+            window: Duration::from_millis(200),
+        },
+    )
+    .await;
+    // Stamped with the earlier leg's start; the bar ethusdt missed is
+    // still partial rather than paired with a neighbour
+    assert_eq!(
+        candles(&frames),
+        [
+            (0, Some(3.0), false),
+            (120_000, Some(3.0), false),
+            (60_000, None, true)
+        ]
+    );
+    let gap = frames
+        .iter()
+        .find(|frame| frame["data"]["event"] == "gap_detected")
+        .expect("no gap_detected");
+    assert_eq!(
+        (gap["data"]["from"].as_u64(), gap["data"]["to"].as_u64()),
+        (Some(60_300), Some(120_300))
+    );
+}
+
+#[tokio::test]
+async fn test_zero_tolerance_stays_strict() {
+    let frames = frames(0, TimestampPolicy::Skip).await;
+    assert!(candles(&frames).is_empty(), "{:?}", frames);
+}
+
+#[tokio::test]
+async fn test_tolerance_stays_under_half_a_bar() {
+    let frames = frames(30_000, TimestampPolicy::Skip).await;
+    let error = &frames[0];
+    assert_eq!(error["type"], "error");
+    assert_eq!(error["data"]["code"], ErrorCode::ParseError.value());
+    assert_eq!(
+        error["data"]["message"],
+        "Invalid message: align_tolerance_ms must be under half a 1m bar"
+    );
+    assert!(candles(&frames).is_empty());
+}
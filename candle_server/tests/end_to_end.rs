@@ -0,0 +1,218 @@
+//! The server end to end, from a scripted fake Binance to WebSocket clients.
+
+use candle_server::error::ErrorCode;
+use candle_server::server::ServerConfig;
+use candle_server::testing::{fast_backoff, FakeBinance, Scenario, TestServer};
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test]
+async fn test_subscribe_receive_unsubscribe() {
+    let fake = FakeBinance::new()
+        .stream("btcusdt@kline_1m", Scenario::new().bar(0, 20.0))
+        .stream("ethusdt@kline_1m", Scenario::new().bar(0, 2.0))
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+
+    let id = client.subscribe("btcusdt/ethusdt@1m").await;
+    let subscribed = client.next_status("subscribed").await;
+    assert_eq!(subscribed["id"], id);
+    let result = client.next_result("btcusdt/ethusdt@1m").await;
+    assert_eq!(result["data"]["t"], 0);
+    assert_eq!(result["data"]["c"], 10.0);
+    let mut subscribed = fake.subscribed();
+    subscribed.sort();
+    assert_eq!(subscribed, ["btcusdt@kline_1m", "ethusdt@kline_1m"]);
+
+    client.unsubscribe("btcusdt/ethusdt@1m").await;
+    let closed = client.next_status("closed").await;
+    assert_eq!(closed["data"]["reason"], "unsubscribed");
+    server.wait_until_idle().await;
+    assert!(client.collect(QUIET).await.is_empty());
+}
+
+#[tokio::test]
+async fn test_reconnect_resubscribes_and_resumes() {
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(0, 10.0)
+                .disconnect()
+                .bar(60_000, 11.0),
+        )
+        .start()
+        .await;
+    let server = TestServer::against(
+        &fake,
+        ServerConfig {
+            backoff: fast_backoff(None),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt@1m").await;
+    let result = client.next_result("btcusdt@1m").await;
+    assert_eq!(
+        (
+            result["data"]["t"].as_u64(),
+            result["data"]["closed"].as_bool()
+        ),
+        (Some(0), Some(true))
+    );
+    let reconnecting = client.next_status("reconnecting").await;
+    assert_eq!(reconnecting["data"]["attempt"], 1);
+    client.next_status("subscribed").await;
+    let result = client.next_result("btcusdt@1m").await;
+    assert_eq!(result["data"]["t"], 60_000);
+    assert_eq!(result["data"]["c"], 11.0);
+
+    assert_eq!(fake.connections(), 2);
+    assert_eq!(fake.subscribed(), ["btcusdt@kline_1m", "btcusdt@kline_1m"]);
+}
+
+#[tokio::test]
+async fn test_legs_are_paired_by_open_time() {
+    // The second bar of one leg arrives well after the other's
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new().closed_bar(0, 20.0).closed_bar(60_000, 30.0),
+        )
+        .stream(
+            "ethusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(0, 2.0)
+                .wait(Duration::from_millis(100))
+                .closed_bar(60_000, 5.0),
+        )
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt/ethusdt@1m").await;
+    let results: Vec<_> = client
+        .collect(QUIET)
+        .await
+        .into_iter()
+        .filter(|frame| frame["type"] == "candle")
+        .map(|frame| {
+            (
+                frame["data"]["t"].as_u64().unwrap(),
+                frame["data"]["c"].as_f64().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(results, [(0, 10.0), (60_000, 6.0)]);
+}
+
+#[tokio::test]
+async fn test_bad_frames_and_late_bars_leave_the_stream_running() {
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(60_000, 10.0)
+                .frame("not json")
+                .error(2, "Invalid request")
+                .closed_bar(0, 99.0)
+                .bar(120_000, 12.0),
+        )
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt@1m").await;
+    let results: Vec<_> = client
+        .collect(QUIET)
+        .await
+        .into_iter()
+        .filter(|frame| frame["type"] == "candle")
+        .map(|frame| frame["data"]["t"].as_u64().unwrap())
+        .collect();
+    assert_eq!(results, [60_000, 120_000]);
+    assert_eq!(fake.connections(), 1);
+}
+
+#[tokio::test]
+async fn test_errors_reach_the_client() {
+    let fake = FakeBinance::new().start().await;
+    let server = TestServer::against(
+        &fake,
+        ServerConfig {
+            backoff: fast_backoff(Some(1)),
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+    server.faults.fail_connects(usize::MAX);
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt@@1m").await;
+    let error = client.next_status("error").await;
+    assert_eq!(error["stream"], "btcusdt@@1m");
+    assert_eq!(error["data"]["code"], ErrorCode::InvalidCharacter.value());
+
+    client.subscribe("btcusdt@1m").await;
+    let closed = client.next_status("closed").await;
+    assert_eq!(closed["data"]["reason"], "upstream_failed");
+    assert_eq!(
+        closed["data"]["code"],
+        ErrorCode::UpstreamUnavailable.value()
+    );
+    client.close().await;
+    server.wait_until_idle().await;
+}
+
+#[tokio::test]
+async fn test_corrupt_frame_is_reported_as_gap() {
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new()
+                .closed_bar(0, 10.0)
+                .wait(Duration::from_millis(200))
+                .closed_bar(60_000, 11.0)
+                .closed_bar(120_000, 12.0),
+        )
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt@1m").await;
+    assert_eq!(client.next_result("btcusdt@1m").await["data"]["t"], 0);
+    server.faults.corrupt_frames(1);
+    let gap = client.next_status("gap_detected").await;
+    assert_eq!(
+        (gap["data"]["from"].as_u64(), gap["data"]["to"].as_u64()),
+        (Some(60_000), Some(120_000))
+    );
+    assert_eq!(client.next_result("btcusdt@1m").await["data"]["t"], 120_000);
+    assert_eq!(fake.connections(), 1);
+}
+
+#[tokio::test]
+async fn test_failed_write_drops_the_client() {
+    let fake = FakeBinance::new()
+        .stream("btcusdt@kline_1m", Scenario::new().bar(0, 10.0))
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+
+    client.subscribe("btcusdt@1m").await;
+    client.next_result("btcusdt@1m").await;
+    server.faults.fail_client_writes(1);
+    client
+        .send(serde_json::json!({"id": 9, "method": "GET_INFO"}))
+        .await;
+    server.wait_until_idle().await;
+}
@@ -0,0 +1,45 @@
+//! Replays the inputs kept from fuzzing, under `fuzz/regressions/<target>/`,
+//! through the same checks as the fuzz targets.
+
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use candle_server::testing::{fuzz_expression, fuzz_upstream_frame};
+
+// Some of the inputs used to hang rather than panic
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn replay(target: &str, check: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/regressions")
+        .join(target);
+    let mut replayed = 0;
+    for entry in std::fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let data = std::fs::read(&path).unwrap();
+        let (done, finished) = mpsc::channel();
+        thread::spawn(move || {
+            check(&data);
+            let _ = done.send(());
+        });
+        match finished.recv_timeout(TIMEOUT) {
+            Ok(()) => {}
+            Err(RecvTimeoutError::Disconnected) => panic!("{} panicked", path.display()),
+            Err(RecvTimeoutError::Timeout) => panic!("{} hung", path.display()),
+        }
+        replayed += 1;
+    }
+    assert!(replayed > 0, "nothing to replay in {}", dir.display());
+}
+
+#[test]
+fn test_expression_regressions() {
+    replay("expression", fuzz_expression);
+}
+
+#[test]
+fn test_upstream_frame_regressions() {
+    replay("upstream_frame", fuzz_upstream_frame);
+}
@@ -0,0 +1,84 @@
+//! The gRPC `Candles` service, read next to a WebSocket client of the same
+//! server.
+
+use candle_server::grpc::status;
+use candle_server::server::ServerConfig;
+use candle_server::testing::{FakeBinance, GrpcEvent, Scenario, TestServer};
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(200);
+
+#[tokio::test]
+async fn test_grpc_and_websocket_read_the_same_candles() {
+    let fake = FakeBinance::new()
+        .stream(
+            "btcusdt@kline_1m",
+            Scenario::new()
+                .wait(Duration::from_millis(200))
+                .closed_bar(0, 20.0)
+                .bar(60_000, 22.0),
+        )
+        .start()
+        .await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut ws = server.client().await;
+    let mut grpc = server.grpc_client().await;
+
+    ws.subscribe("btcusdt*2@1m").await;
+    // Spelled differently, it still shares the WebSocket client's evaluator,
+    // with results named as it spelled them
+    let call = grpc.subscribe("btcusdt * 2@1m").await;
+    for (t, c) in [(0, 40.0), (60_000, 44.0)] {
+        let over_ws = ws.next_result("btcusdt*2@1m").await;
+        let over_grpc = grpc.next_update(call).await;
+        assert_eq!(over_grpc["stream"], "btcusdt * 2@1m");
+        for (field, value) in over_grpc["data"].as_object().unwrap() {
+            assert_eq!(*value, over_ws["data"][field], "{}", field);
+        }
+        assert_eq!(
+            (
+                over_grpc["data"]["t"].as_u64(),
+                over_grpc["data"]["c"].as_f64()
+            ),
+            (Some(t), Some(c))
+        );
+    }
+    assert_eq!(server.server.info().await.evaluators, 1);
+
+    // Unsubscribing ends the call but leaves the WebSocket client's
+    let unsubscribe = grpc.unsubscribe("btcusdt*2@1m").await;
+    assert_eq!(grpc.next_end(call).await, (status::OK, String::new()));
+    assert_eq!(grpc.next_end(unsubscribe).await.0, status::OK);
+    assert_eq!(server.server.info().await.subscriptions, 1);
+    ws.close().await;
+    server.wait_until_idle().await;
+    assert_eq!(grpc.try_next(QUIET).await, None);
+}
+
+#[tokio::test]
+async fn test_grpc_calls_fail_with_status() {
+    let fake = FakeBinance::new().start().await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut grpc = server.grpc_client().await;
+
+    let call = grpc.subscribe("btcusdt@@1m").await;
+    let (code, message) = grpc.next_end(call).await;
+    assert_eq!(code, status::INVALID_ARGUMENT);
+    assert!(!message.is_empty());
+
+    let call = grpc.unsubscribe("btcusdt@1m").await;
+    assert_eq!(
+        grpc.next_end(call).await,
+        (status::NOT_FOUND, "Not subscribed to btcusdt@1m".into())
+    );
+
+    let call = grpc.call("Backfill", "btcusdt@1m").await;
+    assert_eq!(
+        grpc.next().await,
+        GrpcEvent::Ended {
+            call,
+            status: status::UNIMPLEMENTED,
+            message: "Unknown method /candles.Candles/Backfill".into(),
+        }
+    );
+}
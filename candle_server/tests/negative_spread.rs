@@ -0,0 +1,206 @@
+//! A spread that stays below zero, through every output mode and indicator.
+//! `ethusdt-btcusdt` closes at `SPREAD`; its mirror `btcusdt-ethusdt` is the
+//! same series above zero, which the indicators are checked against.
+
+use candle_server::candle::Candle;
+use candle_server::emitter::RESULT_FLAGS;
+use candle_server::server::ServerConfig;
+use candle_server::testing::{FakeBinance, Scenario, TestServer};
+use serde_json::{json, Value};
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(200);
+const MINUTE: u64 = 60_000;
+
+const SPREAD: [f64; 8] = [-10.0, -10.5, -10.2, -10.8, -11.0, -10.6, -10.9, -11.4];
+// Updates of the open bar after the last closed one
+const OPEN: [f64; 2] = [-11.0, -11.5];
+
+const STREAM: &str = "ethusdt-btcusdt@1m";
+const MIRROR: &str = "btcusdt-ethusdt@1m";
+
+// Bitcoin flat at 30 with a wide range, ether 30 above the spread with a
+// narrow one, so the highs and lows taken field by field come out inverted
+fn legs(t: u64, spread: f64, closed: bool) -> (Candle, Candle) {
+    let btc = Candle::new(t, 30.0, 30.0, 40.0, 29.0).with_volume(1.0, 30.0);
+    let eth = Candle::new(
+        t,
+        30.0 + spread,
+        30.0 + spread,
+        31.0 + spread,
+        29.5 + spread,
+    )
+    .with_volume(1.0, 30.0);
+    (btc.with_closed(closed), eth.with_closed(closed))
+}
+
+async fn fake() -> candle_server::testing::RunningFakeBinance {
+    let (mut btc, mut eth) = (Scenario::new(), Scenario::new());
+    let closed = SPREAD.iter().map(|&spread| (spread, true));
+    let open = OPEN.iter().map(|&spread| (spread, false));
+    for (i, (spread, closed)) in closed.chain(open).enumerate() {
+        let t = MINUTE * (i as u64).min(SPREAD.len() as u64);
+        let (btc_bar, eth_bar) = legs(t, spread, closed);
+        btc = btc.kline(btc_bar);
+        eth = eth.kline(eth_bar);
+    }
+    FakeBinance::new()
+        .stream("btcusdt@kline_1m", btc)
+        .stream("ethusdt@kline_1m", eth)
+        .start()
+        .await
+}
+
+// Results of a SUBSCRIBE with `options` to `stream`, on a server of its own
+async fn results(stream: &str, options: Value) -> Vec<Value> {
+    let fake = fake().await;
+    let server = TestServer::against(&fake, ServerConfig::default()).await;
+    let mut client = server.client().await;
+    let mut request = json!({"id": 1, "method": "SUBSCRIBE", "stream": stream});
+    for (key, value) in options.as_object().unwrap() {
+        request[key] = value.clone();
+    }
+    client.send(request).await;
+    client
+        .collect(QUIET)
+        .await
+        .into_iter()
+        .filter(|frame| frame["type"] == "candle")
+        .collect()
+}
+
+// The candle of a result, without the flags sharing its `data`
+fn candle(result: &Value) -> Value {
+    let mut data = result["data"].clone();
+    for flag in RESULT_FLAGS {
+        data.as_object_mut().unwrap().remove(flag);
+    }
+    data
+}
+
+fn price(data: &Value, field: &str) -> f64 {
+    data[field].as_f64().unwrap()
+}
+
+#[tokio::test]
+async fn test_closed_bars_keep_their_shape() {
+    let fieldwise = results(STREAM, json!({"closed_only": true})).await;
+    let bound = results(
+        STREAM,
+        json!({"closed_only": true, "composite_semantics": "bound"}),
+    )
+    .await;
+    assert_eq!(fieldwise.len(), SPREAD.len());
+    assert_eq!(bound.len(), SPREAD.len());
+
+    for ((fieldwise, bound), spread) in fieldwise.iter().zip(&bound).zip(SPREAD) {
+        for data in [&fieldwise["data"], &bound["data"]] {
+            let (o, c, h, l) = (
+                price(data, "o"),
+                price(data, "c"),
+                price(data, "h"),
+                price(data, "l"),
+            );
+            assert_eq!((o, c), (spread, spread));
+            assert!(l <= o.min(c) && o.max(c) <= h, "{}", data);
+            assert!(h < 0.0, "{}", data);
+        }
+        // `eth.h - btc.h` is below the close, `eth.l - btc.l` above it
+        let data = &fieldwise["data"];
+        assert_eq!(
+            (price(data, "h"), price(data, "l")),
+            (spread + 0.5, spread - 9.0)
+        );
+        let data = &bound["data"];
+        assert_eq!(
+            (price(data, "h"), price(data, "l")),
+            (spread + 2.0, spread - 10.5)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_written_prices_keep_their_sign() {
+    let text = results(
+        STREAM,
+        json!({"closed_only": true, "precision": 2, "string_prices": true}),
+    )
+    .await;
+    let closes: Vec<&str> = text
+        .iter()
+        .map(|result| result["data"]["c"].as_str().unwrap())
+        .collect();
+    assert_eq!(closes[..3], ["-10.00", "-10.50", "-10.20"]);
+
+    let rounded = results(STREAM, json!({"closed_only": true, "precision": 0})).await;
+    assert_eq!(rounded[1]["data"]["c"], -10.0);
+    assert_eq!(rounded[1]["data"]["l"], -20.0);
+}
+
+#[tokio::test]
+async fn test_delta_and_on_change_results_merge_into_full_ones() {
+    // The open bar as the full results leave it
+    let full = results(STREAM, json!({})).await;
+    let last = candle(full.last().unwrap());
+    assert_eq!(
+        (last["t"].as_u64(), last["c"].as_f64()),
+        (Some(8 * MINUTE), Some(-11.5))
+    );
+
+    for options in [json!({"mode": "delta"}), json!({"emit": "on_change"})] {
+        let mut merged = Value::Null;
+        for result in results(STREAM, options.clone()).await {
+            if result["data"]["delta"] == true {
+                for (field, value) in candle(&result).as_object().unwrap() {
+                    merged[field] = value.clone();
+                }
+            } else {
+                merged = candle(&result);
+            }
+        }
+        assert_eq!(merged, last, "{}", options);
+    }
+}
+
+#[tokio::test]
+async fn test_indicators_mirror_the_spread_above_zero() {
+    let options = json!({
+        "closed_only": true,
+        "indicators": [
+            {"type": "volatility", "window": 3},
+            {"type": "bollinger", "period": 3},
+            {"type": "rsi", "period": 2},
+            {"type": "atr", "period": 2},
+            {"type": "macd", "fast": 2, "slow": 3, "signal": 2}
+        ]
+    });
+    let below = results(STREAM, options.clone()).await;
+    let above = results(MIRROR, options).await;
+    assert_eq!(below.len(), SPREAD.len());
+    assert_eq!(above.len(), SPREAD.len());
+
+    let close = |left: f64, right: f64| (left - right).abs() < 1e-9;
+    let (below, above) = (
+        &below.last().unwrap()["data"],
+        &above.last().unwrap()["data"],
+    );
+    assert!(price(below, "c") < 0.0 && price(above, "c") > 0.0);
+    // No return is left out, as the spread never crosses zero
+    assert!(below.get("volatility_skipped").is_none());
+    assert!(close(
+        price(below, "volatility"),
+        price(above, "volatility")
+    ));
+    assert!(close(price(below, "bb_mid"), -price(above, "bb_mid")));
+    assert!(close(price(below, "bb_upper"), -price(above, "bb_lower")));
+    assert!(close(price(below, "bb_lower"), -price(above, "bb_upper")));
+    assert!(close(price(below, "rsi"), 100.0 - price(above, "rsi")));
+    assert!(close(price(below, "atr"), price(above, "atr")));
+    for field in ["macd", "macd_signal", "macd_hist"] {
+        assert!(
+            close(price(below, field), -price(above, field)),
+            "{}",
+            field
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Expressions sharing a leg, evaluated on several workers at once, or on
+//! evaluator shards: each one's results arrive strictly in order, whatever
+//! the order across them.
+
+use candle_server::server::ServerConfig;
+use candle_server::testing::{FakeBinance, Scenario, TestServer};
+use serde_json::json;
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(300);
+const EXPRESSIONS: u64 = 16;
+// Updates and closes within what a leg buffers, so a burst drops none
+const BARS: u64 = 25;
+
+fn price(bar: u64) -> f64 {
+    100.0 + bar as f64
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_each_expression_stays_in_order() {
+    check_order(0).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_each_expression_stays_in_order_on_shards() {
+    check_order(4).await;
+}
+
+async fn check_order(evaluator_shards: usize) {
+    // Every client subscribes before the first kline, with time to spare
+    let mut scenario = Scenario::new().wait(Duration::from_secs(1));
+    for bar in 0..BARS {
+        scenario = scenario
+            .bar(bar * 60_000, price(bar) - 0.5)
+            .closed_bar(bar * 60_000, price(bar));
+    }
+    let fake = FakeBinance::new()
+        .stream("btcusdt@kline_1m", scenario)
+        .start()
+        .await;
+    let server = TestServer::against(
+        &fake,
+        ServerConfig {
+            evaluator_shards,
+            ..ServerConfig::default()
+        },
+    )
+    .await;
+
+    let mut clients = Vec::new();
+    for factor in 1..=EXPRESSIONS {
+        let mut client = server.client().await;
+        let stream = format!("btcusdt*{}@1m", factor);
+        client
+            .send(json!({"id": 1, "method": "SUBSCRIBE", "stream": stream, "closed_only": true}))
+            .await;
+        clients.push((client, stream));
+    }
+    // Quiet only counts once results flow, not while the fake still waits
+    let collected =
+        futures::future::join_all(clients.iter_mut().map(|(client, stream)| async move {
+            let mut frames = vec![client.next_result(stream).await];
+            frames.extend(client.collect(QUIET).await);
+            frames
+        }))
+        .await;
+
+    for (factor, frames) in (1..=EXPRESSIONS).zip(collected) {
+        let results: Vec<(u64, f64)> = frames
+            .iter()
+            .filter(|frame| frame["type"] == "candle")
+            .map(|frame| {
+                let data = &frame["data"];
+                (data["t"].as_u64().unwrap(), data["c"].as_f64().unwrap())
+            })
+            .collect();
+        let expected: Vec<(u64, f64)> = (0..BARS)
+            .map(|bar| (bar * 60_000, price(bar) * factor as f64))
+            .collect();
+        assert_eq!(results, expected, "btcusdt*{}", factor);
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use candle_server::candle::Candle;
+use candle_server::expr::{canonical_key, evaluate_rpn, parse, to_rpn};
+use candle_server::protocol::{ResultData, ResultMessage, ServerMessage};
+use serde_json::{json, Value};
+
+#[test]
+fn test_expression_result_serializes_for_clients() {
+    let stream = canonical_key("ethusdt/btcusdt@1m").unwrap();
+    let rpn = to_rpn(&parse(&stream).unwrap()).unwrap();
+    let candles = HashMap::from([
+        (
+            "btcusdt@kline_1m".to_string(),
+            Candle::new(60_000, 20.0, 40.0, 50.0, 10.0),
+        ),
+        (
+            "ethusdt@kline_1m".to_string(),
+            Candle::new(60_000, 2.0, 2.0, 5.0, 1.0),
+        ),
+    ]);
+
+    let message = ServerMessage::Result(ResultMessage {
+        id: None,
+        stream: stream.clone(),
+        data: ResultData::from(evaluate_rpn(&rpn, &candles).unwrap()),
+        closed: false,
+        out_of_order: false,
+        partial: false,
+        missing: Vec::new(),
+        snapshot: false,
+        latency_ms: None,
+        alias: None,
+    });
+    let value: Value = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+
+    assert_eq!(value["stream"], json!(stream));
+    assert_eq!(value["data"]["t"], json!(60_000));
+    assert_eq!(value["data"]["o"], json!(0.1));
+    assert_eq!(value["data"]["c"], json!(0.05));
+    assert_eq!(value["data"]["h"], json!(0.1));
+    assert_eq!(value["data"]["l"], json!(0.1));
+}
+
+#[test]
+fn test_canonical_key_rejects_missing_interval() {
+    assert!(canonical_key("btcusdt+ethusdt").is_err());
+}
@@ -0,0 +1,112 @@
+//! Counts heap allocations on the per-bar evaluation path. Kept in its own
+//! test binary so the counting allocator doesn't see other tests' work.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use candle_server::candle::Candle;
+use candle_server::expr::Expr;
+use candle_server::mid::MidBars;
+use candle_server::pairing::LegBook;
+use tokio::time::Duration;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // Only allocations made by the measuring thread are counted
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.with(Cell::get) {
+            ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations_during(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    COUNTING.with(|counting| counting.set(true));
+    f();
+    COUNTING.with(|counting| counting.set(false));
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn bar(t: u64, price: f64) -> Candle {
+    Candle::new(t, price, price + 1.0, price + 2.0, price - 1.0).with_volume(10.0, 10.0 * price)
+}
+
+#[test]
+fn test_pairing_and_evaluation_do_not_allocate() {
+    let (expr, interval) = Expr::parse("(btcusdt-ethusdt*2)/-bnbusdt@1m").unwrap();
+    let expr = expr.simplify().unwrap();
+    let streams = ["bnbusdt", "btcusdt", "ethusdt"]
+        .map(|symbol| interval.stream(symbol))
+        .to_vec();
+    let mut book = LegBook::new(&expr, &interval, streams);
+    let window = Some(Duration::from_secs(60));
+
+    // Each bar is held as pending by the first legs and completed by the
+    // last, then a leg repeats an older bar late
+    let mut run = |bars: std::ops::Range<u64>| {
+        let mut checksum = 0.0;
+        for t in bars.map(|i| i * 60_000) {
+            for leg in 0..3 {
+                let candle = bar(t, 100.0 + leg as f64);
+                if book.record(leg, candle, false, window) {
+                    checksum += book.eval(&expr, leg, candle).unwrap().c;
+                }
+            }
+            let late = bar(t.saturating_sub(60_000), 50.0);
+            if book.record(0, late, true, window) {
+                checksum += book.eval(&expr, 0, late).unwrap().c;
+            }
+        }
+        checksum
+    };
+
+    // Warms up the pending-bar buffers
+    run(0..10);
+    let mut checksum = 0.0;
+    let allocations = allocations_during(|| checksum = run(10..1_010));
+
+    assert!(checksum != 0.0);
+    assert_eq!(allocations, 0);
+}
+
+#[test]
+fn test_mid_price_bars_do_not_allocate() {
+    let mut bars = MidBars::new("1m").unwrap();
+    // Book updates every 100ms over many bars, including their boundaries
+    let mut checksum = 0.0;
+    let allocations = allocations_during(|| {
+        for i in 0..100_000u64 {
+            let price = 100.0 + (i % 7) as f64;
+            if let Some((closed, open)) = bars.observe(i * 100, price) {
+                checksum += open.c + closed.map_or(0.0, |bar| bar.c);
+            }
+        }
+    });
+
+    assert!(checksum != 0.0);
+    assert_eq!(allocations, 0);
+}
@@ -0,0 +1,78 @@
+//! The same klines read from a fake Binance with permessage-deflate
+//! negotiated, declined by the fake, and turned off on the server.
+
+use candle_server::server::ServerConfig;
+use candle_server::testing::{FakeBinance, Scenario, TestServer};
+use candle_server::upstream::UpstreamStats;
+use serde_json::Value;
+use tokio::time::Duration;
+
+const QUIET: Duration = Duration::from_millis(200);
+// Within what a leg buffers, so a burst drops none
+const BARS: u64 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Setup {
+    Negotiated,
+    Declined,
+    Disabled,
+}
+
+// Results of `btcusdt*2@1m` and the upstream traffic behind them
+async fn run(setup: Setup) -> (Vec<Value>, UpstreamStats) {
+    let mut btc = Scenario::new();
+    for i in 0..BARS {
+        btc = btc.closed_bar(i * 60_000, 30_000.0 + i as f64);
+    }
+    let mut fake = FakeBinance::new().stream("btcusdt@kline_1m", btc);
+    if setup == Setup::Declined {
+        fake = fake.without_compression();
+    }
+    let fake = fake.start().await;
+    let config = ServerConfig {
+        upstream_compression: setup != Setup::Disabled,
+        ..ServerConfig::default()
+    };
+    let server = TestServer::against(&fake, config).await;
+    let mut client = server.client().await;
+    client.subscribe("btcusdt*2@1m").await;
+    let results = client
+        .collect(QUIET)
+        .await
+        .into_iter()
+        .filter(|frame| frame["type"] == "candle")
+        .collect();
+
+    let negotiated = setup == Setup::Negotiated;
+    assert_eq!(fake.connections(), 1);
+    assert_eq!(fake.compressed_connections(), negotiated as usize);
+    let stats = server.server.stats().await.upstream;
+    assert_eq!(stats.compressed, negotiated);
+    (results, stats)
+}
+
+#[tokio::test]
+async fn test_results_are_the_same_compressed_or_not() {
+    let (negotiated, _) = run(Setup::Negotiated).await;
+    assert_eq!(negotiated.len(), BARS as usize);
+    assert_eq!(negotiated, run(Setup::Declined).await.0);
+    assert_eq!(negotiated, run(Setup::Disabled).await.0);
+}
+
+#[tokio::test]
+async fn test_bytes_received_show_the_saving() {
+    let (_, compressed) = run(Setup::Negotiated).await;
+    let (_, plain) = run(Setup::Declined).await;
+    assert!(
+        compressed.bytes_received * 3 < plain.bytes_received,
+        "{:?} {:?}",
+        compressed,
+        plain
+    );
+    assert!(compressed.ratio().unwrap() > 3.0, "{:?}", compressed);
+    assert_eq!(plain.bytes_received, plain.bytes_decoded);
+    assert_eq!(plain.ratio(), Some(1.0));
+    // The same klines once inflated, but for the handshake's extra header
+    let extra = compressed.bytes_decoded.abs_diff(plain.bytes_decoded);
+    assert!(extra < 100, "{:?} {:?}", compressed, plain);
+}